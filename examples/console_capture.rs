@@ -0,0 +1,63 @@
+// SPDX-License-Identifier: Apache-2.0
+
+// Boots a kernel, and collects the first 4 KiB of its console output in memory, besides
+// printing it on stdout.
+//
+// This needs KVM, and an uncompressed kernel with an optional initramfs:
+//   cargo run --example console_capture -- vmlinux [initramfs.cpio]
+
+use std::env;
+use std::error::Error;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use vmm::config::VMMConfigBuilder;
+use vmm::VMM;
+
+const CAPTURE_SIZE: usize = 4096;
+
+// Keeps the first bytes of the output, and discards the rest.
+struct Capture(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Capture {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut captured = self.0.lock().unwrap();
+        let len = buf.len().min(CAPTURE_SIZE - captured.len());
+        captured.extend_from_slice(&buf[..len]);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let mut args = env::args_os().skip(1);
+    let kernel = args
+        .next()
+        .ok_or("usage: console_capture <kernel> [initramfs]")?;
+    let initramfs = args.next();
+
+    let config = VMMConfigBuilder::default()
+        .kernel(PathBuf::from(kernel))
+        .initramfs(initramfs.map(|path| PathBuf::from(path).into()))
+        .timeout(Some(Duration::from_secs(30)))
+        .build()?;
+
+    let captured = Arc::new(Mutex::new(Vec::with_capacity(CAPTURE_SIZE)));
+    let mut vmm = VMM::from_config_with_sink(&config, Box::new(Capture(captured.clone())))?;
+    let reason = vmm.run()?;
+
+    let captured = captured.lock().unwrap();
+    eprintln!(
+        "\nThe VM stopped ({:?}), {} bytes captured:\n{}",
+        reason,
+        captured.len(),
+        String::from_utf8_lossy(&captured)
+    );
+
+    Ok(())
+}
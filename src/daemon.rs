@@ -0,0 +1,122 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
+
+/// Where [`daemonize`] returns.
+pub enum Fork {
+    /// In the original process, with the exit code reported by the daemon. `None` when
+    /// the daemon died before reporting anything.
+    Parent(Option<i32>),
+    /// In the daemon.
+    Daemon(Daemon),
+}
+
+/// Handle of the daemon process, to report its status to the original process.
+pub struct Daemon {
+    // Write end of the status pipe, until the original process got a status.
+    status: Option<File>,
+    // Removed when the daemon exits.
+    pidfile: Option<PathBuf>,
+    // Replaces the standard streams once ready, opened early as the VMM may then be jailed.
+    null: File,
+}
+
+fn check(ret: libc::c_int) -> io::Result<libc::c_int> {
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+/// Detach from the calling process and its terminal, with a double fork.
+///
+/// The original process waits for the daemon to be ready, or to fail, so that setup
+/// errors still reach its stderr and exit code. This must be called before creating
+/// any thread, since only the calling thread survives a fork.
+pub fn daemonize() -> io::Result<Fork> {
+    let null = File::options().read(true).write(true).open("/dev/null")?;
+    let mut fds = [0; 2];
+    // Safe because the array holds two file descriptors.
+    check(unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) })?;
+    // Safe because we own the file descriptors the pipe just created.
+    let (mut read_end, write_end) =
+        unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+
+    // Safe because there is no other thread.
+    let child = check(unsafe { libc::fork() })?;
+    if child > 0 {
+        drop(write_end);
+
+        // The intermediate process exits right away.
+        let mut wstatus = 0;
+        // Safe because the child is ours and wstatus is valid.
+        unsafe { libc::waitpid(child, &mut wstatus, 0) };
+
+        let mut code = [0u8; 4];
+        return Ok(Fork::Parent(
+            read_end
+                .read_exact(&mut code)
+                .ok()
+                .map(|_| i32::from_ne_bytes(code)),
+        ));
+    }
+    drop(read_end);
+
+    // Leave the session, and its controlling terminal, then fork again so that the daemon
+    // is not a session leader and never gets a controlling terminal back.
+    // On failure, the original process sees the pipe closed without a status.
+    // Safe because there is no other thread.
+    unsafe {
+        if libc::setsid() < 0 || libc::fork() != 0 {
+            libc::_exit(0);
+        }
+    }
+
+    Ok(Fork::Daemon(Daemon {
+        status: Some(write_end),
+        pidfile: None,
+        null,
+    }))
+}
+
+impl Daemon {
+    /// Write the daemon PID to `pidfile`, which is removed when the daemon exits.
+    pub fn write_pidfile(&mut self, pidfile: &Path) -> io::Result<()> {
+        fs::write(pidfile, format!("{}\n", std::process::id()))?;
+        self.pidfile = Some(pidfile.to_path_buf());
+        Ok(())
+    }
+
+    /// The daemon is set up: close the standard streams, and let the original process exit
+    /// successfully.
+    pub fn ready(&mut self) -> io::Result<()> {
+        for fd in [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+            // Safe because both file descriptors are valid.
+            check(unsafe { libc::dup2(self.null.as_raw_fd(), fd) })?;
+        }
+
+        self.report(0);
+        Ok(())
+    }
+
+    fn report(&mut self, code: i32) {
+        if let Some(mut status) = self.status.take() {
+            let _ = status.write_all(&code.to_ne_bytes());
+        }
+    }
+
+    /// Exit the daemon. The original process exits with the same `code` if it is
+    /// still waiting, i.e. the daemon failed before being ready.
+    pub fn exit(mut self, code: i32) -> ! {
+        self.report(code);
+        if let Some(pidfile) = self.pidfile.take() {
+            let _ = fs::remove_file(pidfile);
+        }
+
+        std::process::exit(code)
+    }
+}
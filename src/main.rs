@@ -1,72 +1,906 @@
+use std::io::Write;
+use std::path::PathBuf;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
 use std::u32;
 
-use clap::Parser;
-use vmm::VMM;
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser, ValueEnum};
+use serde::Serialize;
+use vmm::agent::{self, Agent, ExecEvent, ExecRequest};
+use vmm::cgroup::Cgroup;
+use vmm::config::{
+    CgroupConfig, ConsoleErrorPolicy, ConsoleMode, CpuTemplate, CpuTopology, FirmwareConfig,
+    GdbConfig, ImageSource, IrqchipMode, JailConfig, KsmMode, MemoryBackend, MemoryShare,
+    MemorySize, NetConfig, PmemConfig, PvFeatures, ReplayConfig, SharedDirConfig, SmbiosConfig,
+    ThpMode, Uuid, VMMConfig, VMMConfigBuilder, WatchdogConfig,
+};
+use vmm::inspect::BootCheck;
+use vmm::quardle::Quardle;
+use vmm::{BootImages, ExitReason, PanicReport, PauseTrigger, PvpanicEvent, VMM};
+
+mod daemon;
+use daemon::{Daemon, Fork};
+
+// Process exit codes, see ExitReason::exit_code() for those of the VM stopping:
+// * 0: the guest shut down
+// * 1: the guest asked for a reset, or the watchdog expired with action=reset
+// * 2: internal VMM or vCPU error
+// * 3: guest kernel panic
+// * 4: the guest ran for longer than --timeout
+// * 5: the watchdog expired
+// * 64: invalid command line or configuration, including with --dry-run
+// * 128 + n: the VMM received signal n
+// With --exec, the exit code of the command once it exits.
+const EXIT_GUEST_SHUTDOWN: i32 = 0;
+const EXIT_INTERNAL_ERROR: i32 = 2;
+const EXIT_USAGE: i32 = 64;
+
+// How long --exec waits for the agent to start, guest boot included.
+const AGENT_READY_TIMEOUT: Duration = Duration::from_secs(60);
 
 #[derive(Parser)]
 #[clap(version = "0.1", author = "Polytech Montpellier - DevOps")]
 struct VMMOpts {
-    /// Linux kernel path
+    /// Linux kernel path, or fd:<number> to read it from an inherited file descriptor, e.g.
+    /// a sealed memfd
+    #[clap(short, long, required_unless_present_any = ["quardle", "capabilities"])]
+    kernel: Option<ImageSource>,
+
+    /// Initramfs path, or fd:<number>
     #[clap(short, long)]
-    kernel: String,
+    initramfs: Option<ImageSource>,
 
-    /// Initramfs path
+    /// Decompress a gzip initramfs on the host while loading it, so that the guest kernel
+    /// does not inflate it at boot
+    #[clap(long)]
+    initrd_in_memory: bool,
+
+    /// Kernel command line parameters, added to those of the VMM and the bundle, and replacing
+    /// their console, root, init and panic. The arguments after a -- go to init
+    #[clap(long, allow_hyphen_values = true)]
+    append: Option<String>,
+
+    /// Number of virtual CPUs assigned to the guest [default: 1]
     #[clap(short, long)]
-    initramfs: Option<String>,
+    cpus: Option<u8>,
 
-    /// Number of virtual CPUs assigned to the guest
-    #[clap(short, long, default_value = "1")]
-    cpus: u8,
+    /// Maximum number of virtual CPUs, the ones past --cpus being plugged through the API
+    /// socket [default: --cpus]
+    #[clap(long)]
+    max_cpus: Option<u8>,
+
+    /// Guest CPU topology, as <sockets>:<cores per socket>:<threads per core>, of the
+    /// --max-cpus vCPUs. Defaults to all the vCPUs as cores of a single socket.
+    #[clap(long)]
+    topology: Option<CpuTopology>,
+
+    /// CPU template, restricting the CPU features and MSRs seen by the guest: passthrough,
+    /// t2 or c3
+    #[clap(long)]
+    cpu_template: Option<CpuTemplate>,
+
+    /// KVM paravirtual features offered to the guest, when the host has them: all, none, or a
+    /// list of async-pf, steal-time, pv-eoi and pv-unhalt [default: all]
+    #[clap(long)]
+    pv_features: Option<PvFeatures>,
+
+    /// Interrupt controllers KVM emulates: full, or split to emulate the IOAPIC in the VMM
+    /// [default: full]
+    #[clap(long)]
+    irqchip: Option<IrqchipMode>,
+
+    /// Guest TSC frequency, in kHz. Defaults to the host one
+    #[clap(long)]
+    tsc_khz: Option<u32>,
 
-    /// Memory amount (in MBytes) assigned to the guest
-    #[clap(short, long, default_value = "512")]
-    memory: u32,
+    /// Memory assigned to the guest, with a K, M, G or T unit, e.g. 2G, or in MiB without
+    /// one [default: 512M]
+    #[clap(short, long)]
+    memory: Option<MemorySize>,
+
+    /// Fail instead of warning when the guest memory exceeds the memory available on the
+    /// host, or the memory limit of the VMM cgroup, when the host does not take the
+    /// --memory-ksm and --memory-thp hints, and when the boot images may not boot
+    #[clap(long)]
+    strict: bool,
 
-    /// A level of verbosity, and can be used multiple times
+    /// Do not check that the kernel, the initramfs and the command line go together before
+    /// starting the VM: the architectures, the serial driver of the console, the init of the
+    /// initramfs
+    #[clap(long)]
+    no_validate: bool,
+
+    /// Guest memory backing: file=<path>. The file must be the size of the memory, it is
+    /// mapped copy-on-write: VMs started from the same template share its untouched pages,
+    /// and never modify it
+    #[clap(long)]
+    memory_backend: Option<MemoryBackend>,
+
+    /// Share the guest memory with other processes, read-only: memfd puts it in a memfd,
+    /// whose descriptor the get-memory-fd API request sends. The guest keeps running while
+    /// they read it, they may see it half updated
+    #[clap(long)]
+    memory_share: Option<MemoryShare>,
+
+    /// Also put the shared guest memory descriptor at this descriptor number, for the child
+    /// processes to inherit, and the others to open through /proc/<pid>/fd/<n>
+    #[clap(long, requires = "memory_share")]
+    memory_share_fd: Option<i32>,
+
+    /// Let KSM merge the identical guest memory pages with other processes, or not: on or
+    /// off [default: as the host decides]
+    #[clap(long)]
+    memory_ksm: Option<KsmMode>,
+
+    /// Transparent huge pages for the guest memory: always, never or default, as the host
+    /// decides [default: default]
+    #[clap(long)]
+    memory_thp: Option<ThpMode>,
+
+    /// quark bundle: a .qrk tarball or the directory it extracts to, with a quark.json
+    /// manifest naming the kernel, initramfs and command line. The other options override its
+    /// memory, vCPUs and network hints
+    #[clap(long)]
+    quardle: Option<PathBuf>,
+
+    /// A level of verbosity, and can be used multiple times. -vv also logs every vCPU exit.
+    /// The level changes at runtime with SIGUSR1 or the API socket
     #[clap(short, long, action=clap::ArgAction::Count )]
     verbose: u8,
 
-    /// Stdout console file path
+    /// Console (ttyS0) output: stdout, file:<path>, unix:<path>, none or a file path. Files
+    /// take [,maxsize=<size>][,rotate=<count>][,timestamps=on|off]: rotate to <path>.1 to
+    /// <path>.<count> (1 by default) past maxsize, and prefix the lines with the uptime and the
+    /// UTC time. none discards the output, leaves stdin alone, and drops console=ttyS0 from
+    /// the default kernel command line
+    #[clap(long)]
+    console: Option<ConsoleMode>,
+
+    /// What becomes of the console output once writing it failed, e.g. on a full disk:
+    /// discard it, stop the VM, or write it to stdout instead (stdout-fallback). The guest
+    /// keeps running otherwise [default: discard]
+    #[clap(long)]
+    console_error_policy: Option<ConsoleErrorPolicy>,
+
+    /// Console (ttyS0) input: a file or a pipe, instead of stdin. The end of a regular file
+    /// stops the input, not the VM
+    #[clap(long)]
+    console_input: Option<PathBuf>,
+
+    /// Record the console session to this file, as JSON lines: the input sent to the guest
+    /// and its output, timed from the start of the VM
+    #[clap(long)]
+    console_record: Option<PathBuf>,
+
+    /// Replay a session recorded with --console-record, instead of stdin: send its input at
+    /// the recorded times, then fail if the output differs from the recorded one
+    #[clap(long)]
+    console_replay: Option<PathBuf>,
+
+    /// Send each replayed input once the output it followed shows up, usually a prompt,
+    /// rather than at its recorded time
+    #[clap(long, requires = "console_replay")]
+    replay_fast: bool,
+
+    /// How many output lines may differ from the recorded ones in their numbers only, e.g.
+    /// durations. The kernel timestamps are left out
+    #[clap(long, default_value_t = 0, requires = "console_replay")]
+    replay_tolerance: usize,
+
+    /// Second serial port (ttyS1), used by the agent: stdout, file:<path>, unix:<path> or agent
+    #[clap(long)]
+    serial2: Option<ConsoleMode>,
+
+    /// Run this command in the guest through the agent, once it is ready, mirroring its
+    /// output to stdout and stderr, then stop the VM and exit with the command exit code.
+    /// --serial2 defaults to agent. The console should not go to stdout as well
+    #[clap(long, conflicts_with = "daemonize")]
+    exec: Option<String>,
+
+    /// Network interface, with optional rate limits:
+    /// <tap>|user[,hostfwd=tcp:[<address>]:<port>-[<address>]:<port>][,dhcp-server=...]
+    /// [,rx_rate=<rate>][,tx_rate=<rate>][,rx_ops=<ops>][,tx_ops=<ops>][,burst=<size>][,vhost=on|off]
+    /// [,mac=<address>][,attach-timeout=<duration>][,rx_queue=<size>][,tx_queue=<size>]
+    /// [,bridge=<bridge>[,create-bridge]].
+    /// `user` is a userspace network stack needing no TAP, with DHCP and DNS, and hostfwd
+    /// forwarding host TCP ports to the guest (e.g. hostfwd=tcp::8080-:80). On a TAP,
+    /// dhcp-server=<address>/<prefix>[,range=<address>-<address>][,dns=<address>] answers the
+    /// guest DHCP requests, the TAP host side carrying the server address. Rates are in bits
+    /// per second (e.g. 10mbps), sizes in bytes (e.g. 1mb). vhost=on moves the datapath to
+    /// the host kernel, without rate limits. mac sets the guest Ethernet address, e.g.
+    /// mac=52:54:00:12:34:56. attach-timeout retries attaching to a TAP another process
+    /// still holds for that long, e.g. 2s or 500ms. rx_queue and tx_queue set the entries of
+    /// the virtio queues, a power of two from 64 to 32768, 256 by default. bridge attaches
+    /// the TAP to a host bridge and brings it up, which needs CAP_NET_ADMIN, create-bridge
+    /// creating the bridge if missing, and deleting it once the VM stops
+    #[clap(long)]
+    net: Option<NetConfig>,
+
+    /// Reserve slots for network devices plugged in once the VM runs, through the add-net
+    /// API request. Requires --api-socket
+    #[clap(long, default_value_t = 0)]
+    net_slots: u8,
+
+    /// virtio-pmem device, mapping an image into the guest memory: file=<path>[,ro|,rw].
+    /// Read-only by default
+    #[clap(long)]
+    pmem: Option<PmemConfig>,
+
+    /// Firmware blob mapped read-only below 4 GiB, out of the RAM and the device registers:
+    /// path=<file>,addr=<address>[,entry]. With entry, the boot vCPU starts in the blob
+    /// instead of the kernel, whose entry is in rbx. x86_64 only
+    #[clap(long)]
+    firmware: Option<FirmwareConfig>,
+
+    /// Host directory shared over virtio-9p: tag=<tag>,path=<path>[,ro|,rw]. The guest
+    /// mounts it with `mount -t 9p -o trans=virtio <tag> <dir>`
+    #[clap(long)]
+    shared_dir: Option<SharedDirConfig>,
+
+    /// Do not stop the VM when a kernel panic shows up on the console
+    #[clap(long)]
+    no_panic_detect: bool,
+
+    /// Do not lock the console files and the taps. Unless set, lumper fails to start on
+    /// those another instance uses, rather than mixing its output or stealing its frames
+    #[clap(long)]
+    no_lock: bool,
+
+    /// Stop the VM after this many seconds
+    #[clap(long)]
+    timeout: Option<u64>,
+
+    /// Restart the guest in place when it reboots, instead of stopping the VM. The VMM
+    /// keeps its console and taps. x86_64 only
+    #[clap(long)]
+    restart_on_reboot: bool,
+
+    /// Stop the VM on the reboot past this many restarts
+    #[clap(long, requires = "restart_on_reboot")]
+    max_reboots: Option<u32>,
+
+    /// Watchdog device, stopping the VM when the guest stops petting it:
+    /// timeout=<seconds>[,action=poweroff|reset]. With --restart-on-reboot, reset restarts
+    /// the guest in place
+    #[clap(long)]
+    watchdog: Option<WatchdogConfig>,
+
+    /// Only describe the vCPUs with ACPI tables, without the legacy MP table. Required
+    /// above 64 vCPUs
+    #[clap(long)]
+    no_mptable: bool,
+
+    /// UUID of the VM, in the SMBIOS tables where the guest reads its product_uuid, and its
+    /// serial number unless --smbios sets one. Random by default. x86_64 only
+    #[clap(long)]
+    uuid: Option<Uuid>,
+
+    /// Strings of the SMBIOS tables: manufacturer=, product=, version=, serial=, sku= and
+    /// family=, comma-separated. x86_64 only
+    #[clap(long)]
+    smbios: Option<SmbiosConfig>,
+
+    /// Log the pages the guest writes to, see the dirty-stats API request
+    #[clap(long)]
+    dirty_tracking: bool,
+
+    /// Record the interrupt latency of the virtio devices, see the irq-latency API request
+    #[clap(long)]
+    trace_irq: bool,
+
+    /// Wait for a GDB client on tcp:[<address>]:<port>, the loopback address by default,
+    /// before booting. The guest then stops on its breakpoints; the kernel must be an
+    /// uncompressed vmlinux, without KASLR. x86_64 only
+    #[clap(long)]
+    gdb: Option<GdbConfig>,
+
+    /// Unix socket serving the API requests, one JSON object per line
     #[clap(long)]
-    console: Option<String>,
+    api_socket: Option<PathBuf>,
 
-    /// Interface name
+    /// FIFO the lifecycle events are written to, one JSON object per line: configured,
+    /// vcpus-started, guest-console-active, agent-ready and shutdown. The events that do not
+    /// fit in the FIFO are dropped
     #[clap(long)]
-    net: Option<String>,
+    event_fifo: Option<PathBuf>,
+
+    /// Run in the background once the VM is configured. Requires a console that is not stdout
+    #[clap(long)]
+    daemonize: bool,
+
+    /// File to write the daemon PID to
+    #[clap(long, requires = "daemonize")]
+    pidfile: Option<PathBuf>,
+
+    /// Drop the privileges once the VM is configured, before running the guest:
+    /// uid=<uid>,gid=<gid>,chroot=<directory>[,unshare=on|off]. The VMM chroots to the
+    /// directory, which should be empty, and switches to the uid and gid without
+    /// supplementary groups. unshare=on also moves it to new mount, UTS and IPC namespaces.
+    /// The console files cannot be rotated, and the API socket and pidfile are left behind
+    #[clap(long)]
+    jail: Option<JailConfig>,
+
+    /// Confine the VM resources to a cgroup v2, created before the VM:
+    /// path=<directory>[,cpu-max=<quota>|max [<period>]][,mem-max=<size>][,io-weight=<weight>].
+    /// The parent directory must be delegated to the VMM user, with the controllers of the
+    /// limits enabled. The cgroup is removed when the VM stops cleanly
+    #[clap(long)]
+    cgroup: Option<CgroupConfig>,
+
+    /// Only validate the configuration, and check that the kernel and the initramfs fit in
+    /// the guest memory and go together, without creating the VM. Prints a summary and
+    /// exits with 0 if the VM could start, 64 otherwise
+    #[clap(long)]
+    dry_run: bool,
+
+    /// Print what this lumper supports, and what the host KVM provides, as a JSON document,
+    /// and exit
+    #[clap(long)]
+    capabilities: bool,
+
+    /// Format of the --dry-run summary
+    #[clap(long, value_enum, default_value_t = Output::Text, requires = "dry_run")]
+    output: Output,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Output {
+    Text,
+    Json,
+}
+
+// The --dry-run summary.
+#[derive(Serialize)]
+struct DryRun {
+    valid: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    config: Option<DryRunConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    images: Option<BootImages>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    check: Option<BootCheck>,
+}
+
+#[derive(Serialize)]
+struct DryRunConfig {
+    kernel: String,
+    initramfs: Option<String>,
+    cpus: u8,
+    memory_mib: u64,
 }
 
-#[derive(Debug)]
+#[derive(Debug, thiserror::Error)]
 pub enum Error {
-    VmmNew(vmm::Error),
+    #[error("failed to configure VMM")]
+    VmmConfigure(#[source] vmm::Error),
 
-    VmmConfigure(vmm::Error),
+    #[error("failed to run VMM")]
+    VmmRun(#[source] vmm::Error),
 
-    VmmRun(vmm::Error),
+    #[error("failed to daemonize")]
+    Daemonize(#[source] std::io::Error),
+
+    #[error("failed to open the quardle")]
+    Quardle(#[source] vmm::quardle::Error),
+
+    #[error("failed to enter the jail")]
+    Jail(#[source] vmm::jail::Error),
+
+    #[error("failed to set up the cgroup")]
+    Cgroup(#[source] vmm::cgroup::Error),
+
+    #[error("invalid configuration")]
+    Config(#[source] vmm::config::Error),
+
+    #[error("the boot images do not fit")]
+    Images(#[source] vmm::Error),
+
+    #[error("the boot images may not boot")]
+    BootCheck,
+
+    #[error("failed to run the command in the guest")]
+    Exec(#[source] agent::Error),
+}
+
+// How running the VM ended.
+enum Outcome {
+    // The VM stopped.
+    Stopped(ExitReason),
+    // The --exec command exited with this code.
+    Command(i32),
 }
 
-fn main() -> Result<(), Error> {
-    let opts: VMMOpts = VMMOpts::parse();
+// Log records to stderr, the level is set with -v, then changes at runtime.
+struct StderrLogger;
+
+impl log::Log for StderrLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        vmm::logging::enabled(metadata.level())
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!("[{}] {}", record.level(), record.args());
+        }
+    }
 
-    // Create a new VMM
-    let mut vmm = VMM::new().map_err(Error::VmmNew)?;
+    fn flush(&self) {}
+}
+
+static LOGGER: StderrLogger = StderrLogger;
+
+fn init_logger(verbose: u8) {
+    let level = match verbose {
+        0 => log::LevelFilter::Warn,
+        1 => log::LevelFilter::Info,
+        2 => log::LevelFilter::Debug,
+        _ => log::LevelFilter::Trace,
+    };
+
+    // This only fails if a logger is already set.
+    let _ = log::set_logger(&LOGGER);
+    vmm::logging::set_level(level);
+    vmm::logging::set_trace_exits(verbose >= 2);
+}
+
+// An error along with all its causes, on a single line.
+fn error_message(e: &dyn std::error::Error) -> String {
+    let mut message = e.to_string();
+    let mut source = e.source();
+    while let Some(e) = source {
+        message.push_str(": ");
+        message.push_str(&e.to_string());
+        source = e.source();
+    }
+
+    message
+}
+
+fn print_error(e: &dyn std::error::Error) {
+    eprintln!("Error: {}", error_message(e));
+}
 
-    // Configure the VMM:
+// Check that the boot images go together, warning about what may keep the guest from
+// booting, or failing with --strict.
+fn check_images(config: &VMMConfig, strict: bool) {
+    let check = match vmm::check_images(config) {
+        Ok(check) => check,
+        // Starting the VM reports it.
+        Err(e) => {
+            log::debug!("Not checking the boot images: {}", error_message(&e));
+            return;
+        }
+    };
+    log::info!("Booting {}", check);
+
+    if strict && !check.problems.is_empty() {
+        let problems: Vec<String> = check
+            .problems
+            .iter()
+            .map(|problem| problem.to_string())
+            .collect();
+        let message = format!("the boot images may not boot: {}", problems.join("; "));
+        let _ = VMMOpts::command()
+            .error(ErrorKind::ValueValidation, message)
+            .print();
+        std::process::exit(EXIT_USAGE);
+    }
+    for problem in check.problems.iter() {
+        log::warn!("{}", problem);
+    }
+}
+
+// Validate the configuration and inspect the boot images, print the summary, and return
+// the exit code. The images are checked to go together unless `validate` is false, their
+// problems make the configuration invalid with `strict`.
+fn dry_run(
+    config: Result<VMMConfig, vmm::config::Error>,
+    output: Output,
+    validate: bool,
+    strict: bool,
+) -> i32 {
+    let (config, images) = match config {
+        Ok(config) => {
+            let images = vmm::inspect_images(&config).map_err(Error::Images);
+            (Some(config), images)
+        }
+        Err(e) => (None, Err(Error::Config(e))),
+    };
+    let (images, mut error) = match images {
+        Ok(images) => (Some(images), None),
+        Err(e) => (None, Some(error_message(&e))),
+    };
+
+    let mut check = None;
+    if let (Some(config), None, true) = (config.as_ref(), error.as_ref(), validate) {
+        match vmm::check_images(config) {
+            Ok(found) => check = Some(found),
+            Err(e) => error = Some(error_message(&Error::Images(e))),
+        }
+    }
+    let problems = check
+        .as_ref()
+        .is_some_and(|check| !check.problems.is_empty());
+    if strict && problems {
+        error = Some(Error::BootCheck.to_string());
+    }
+
+    let summary = DryRun {
+        valid: error.is_none(),
+        error,
+        config: config.as_ref().map(|config| DryRunConfig {
+            kernel: config.kernel.to_string(),
+            initramfs: config.initramfs.as_ref().map(|image| image.to_string()),
+            cpus: config.cpus,
+            memory_mib: config.memory >> 20,
+        }),
+        images,
+        check,
+    };
+
+    match output {
+        // Serializing plain structs cannot fail.
+        Output::Json => println!("{}", serde_json::to_string_pretty(&summary).unwrap()),
+        Output::Text => print_summary(&summary),
+    }
+
+    if summary.valid {
+        EXIT_GUEST_SHUTDOWN
+    } else {
+        EXIT_USAGE
+    }
+}
+
+fn print_summary(summary: &DryRun) {
+    if let Some(config) = summary.config.as_ref() {
+        println!("vCPUs: {}, memory: {} MiB", config.cpus, config.memory_mib);
+        println!("Kernel: {}", config.kernel);
+        if let Some(initramfs) = config.initramfs.as_ref() {
+            println!("Initramfs: {}", initramfs);
+        }
+    }
+
+    if let Some(images) = summary.images.as_ref() {
+        let kernel = &images.kernel;
+        println!(
+            "Kernel {} image, entry {:#x}, loaded at {:#x}..{:#x}",
+            kernel.format, kernel.entry, kernel.start, kernel.end
+        );
+        if let Some(initramfs) = images.initramfs.as_ref() {
+            let decompressed = if initramfs.decompressed {
+                " once decompressed"
+            } else {
+                ""
+            };
+            println!(
+                "Initramfs of {} bytes{}, loaded at {:#x}",
+                initramfs.size, decompressed, initramfs.address
+            );
+        }
+    }
+
+    if let Some(check) = summary.check.as_ref() {
+        println!("Booting {}", check);
+        for problem in check.problems.iter() {
+            eprintln!("Warning: {}", problem);
+        }
+    }
+
+    match summary.error.as_deref() {
+        Some(error) => eprintln!("Error: {}", error),
+        None => println!("The configuration is valid"),
+    }
+}
+
+fn main() {
+    let opts = match VMMOpts::try_parse() {
+        Ok(opts) => opts,
+        Err(e) => match e.kind() {
+            ErrorKind::DisplayHelp | ErrorKind::DisplayVersion => e.exit(),
+            _ => {
+                let _ = e.print();
+                std::process::exit(EXIT_USAGE);
+            }
+        },
+    };
+
+    init_logger(opts.verbose);
+
+    if opts.capabilities {
+        // Serializing plain structs cannot fail.
+        let capabilities = vmm::capabilities::capabilities();
+        println!("{}", serde_json::to_string_pretty(&capabilities).unwrap());
+        std::process::exit(EXIT_GUEST_SHUTDOWN);
+    }
+
+    // The bundle files must outlive the VMM configuration.
+    let quardle = match opts.quardle.as_deref().map(Quardle::open).transpose() {
+        Ok(quardle) => quardle,
+        Err(e) => {
+            print_error(&Error::Quardle(e));
+            std::process::exit(EXIT_USAGE);
+        }
+    };
+
+    // Build the VMM configuration:
     // * Number of virtual CPUs
     // * Memory size (in MB)
     // * Path to a Linux kernel
-    // * Optional path to console file
-    vmm.configure(
-        opts.cpus,
-        opts.memory,
-        &opts.kernel,
-        opts.console,
-        opts.initramfs,
-        opts.net,
-    )
-    .map_err(Error::VmmConfigure)?;
+    // * Optional console and second serial port sinks, and console input
+    // The command line overrides the bundle.
+    let mut builder = VMMConfigBuilder::default();
+    if let Some(quardle) = quardle.as_ref() {
+        builder = builder.quardle(quardle);
+    }
+    if let Some(cpus) = opts.cpus {
+        builder = builder.cpus(cpus);
+    }
+    if let Some(memory) = opts.memory {
+        builder = builder.memory(memory.bytes());
+    }
+    if let Some(kernel) = opts.kernel {
+        builder = builder.kernel(kernel);
+    }
+    if let Some(initramfs) = opts.initramfs {
+        builder = builder.initramfs(Some(initramfs));
+    }
+    if let Some(append) = opts.append.as_deref() {
+        builder = builder.append(append);
+    }
+    if let Some(net) = opts.net {
+        builder = builder.net(Some(net));
+    }
+    // --exec talks to the agent on the second serial port.
+    let serial2 = match opts.serial2 {
+        None if opts.exec.is_some() => Some(ConsoleMode::Agent),
+        Some(mode) if opts.exec.is_some() && mode != ConsoleMode::Agent => {
+            let _ = VMMOpts::command()
+                .error(ErrorKind::ArgumentConflict, "--exec needs --serial2 agent")
+                .print();
+            std::process::exit(EXIT_USAGE);
+        }
+        serial2 => serial2,
+    };
+    let config = builder
+        .max_cpus(opts.max_cpus)
+        .topology(opts.topology)
+        .cpu_template(opts.cpu_template)
+        .pv_features(opts.pv_features)
+        .irqchip(opts.irqchip)
+        .tsc_khz(opts.tsc_khz)
+        .memory_backend(opts.memory_backend)
+        .memory_share(opts.memory_share)
+        .memory_share_fd(opts.memory_share_fd)
+        .memory_ksm(opts.memory_ksm)
+        .memory_thp(opts.memory_thp)
+        .strict(opts.strict)
+        .initrd_in_memory(opts.initrd_in_memory)
+        .console(opts.console)
+        .console_error_policy(opts.console_error_policy)
+        .console_input(opts.console_input)
+        .console_record(opts.console_record)
+        .console_replay(opts.console_replay.map(|path| ReplayConfig {
+            path,
+            fast: opts.replay_fast,
+            tolerance: opts.replay_tolerance,
+        }))
+        .serial2(serial2)
+        .net_slots(opts.net_slots)
+        .pmem(opts.pmem)
+        .firmware(opts.firmware)
+        .shared_dir(opts.shared_dir)
+        .panic_detect(!opts.no_panic_detect)
+        .lock_resources(!opts.no_lock)
+        .timeout(opts.timeout.map(Duration::from_secs))
+        .restart_on_reboot(opts.restart_on_reboot)
+        .max_reboots(opts.max_reboots)
+        .mptable(!opts.no_mptable)
+        .uuid(opts.uuid)
+        .smbios(opts.smbios)
+        .watchdog(opts.watchdog)
+        .dirty_tracking(opts.dirty_tracking)
+        .trace_irq(opts.trace_irq)
+        .gdb(opts.gdb)
+        .api_socket(opts.api_socket)
+        .event_fifo(opts.event_fifo)
+        .jail(opts.jail)
+        .cgroup(opts.cgroup)
+        .build();
+    if opts.dry_run {
+        std::process::exit(dry_run(config, opts.output, !opts.no_validate, opts.strict));
+    }
+    let config = match config {
+        Ok(config) => config,
+        Err(e) => {
+            let _ = VMMOpts::command()
+                .error(ErrorKind::ValueValidation, e)
+                .print();
+            std::process::exit(EXIT_USAGE);
+        }
+    };
+
+    // The guest only takes the memory it touches, it may never reach the limit.
+    if let Some(limit) = vmm::host::memory_limit().filter(|limit| config.memory > limit.bytes()) {
+        let message = format!(
+            "the {} MiB of guest memory exceed {}",
+            config.memory >> 20,
+            limit
+        );
+        if opts.strict {
+            let _ = VMMOpts::command()
+                .error(ErrorKind::ValueValidation, message)
+                .print();
+            std::process::exit(EXIT_USAGE);
+        }
+        log::warn!("{}", message);
+    }
+    if !opts.no_validate {
+        check_images(&config, opts.strict);
+    }
+
+    // Configuration errors must still reach the caller, the daemon only detaches once
+    // the VMM is configured.
+    let mut daemon = None;
+    if opts.daemonize {
+        let stdout = [Some(&config.console), config.serial2.as_ref()]
+            .contains(&Some(&ConsoleMode::Stdout))
+            || config.console_error_policy == ConsoleErrorPolicy::StdoutFallback;
+        if stdout {
+            let _ = VMMOpts::command()
+                .error(
+                    ErrorKind::ArgumentConflict,
+                    "--daemonize closes stdout, use --console file:<path> or unix:<path>",
+                )
+                .print();
+            std::process::exit(EXIT_USAGE);
+        }
+
+        match daemon::daemonize() {
+            Ok(Fork::Parent(code)) => std::process::exit(code.unwrap_or(EXIT_INTERNAL_ERROR)),
+            Ok(Fork::Daemon(d)) => daemon = Some(d),
+            Err(e) => {
+                print_error(&Error::Daemonize(e));
+                std::process::exit(EXIT_INTERNAL_ERROR);
+            }
+        }
+    }
+
+    let code = match run(&config, daemon.as_mut(), opts.pidfile, opts.exec) {
+        Ok(Outcome::Stopped(reason)) => exit_code(reason),
+        Ok(Outcome::Command(code)) => code,
+        Err(e) => {
+            print_error(&e);
+            EXIT_INTERNAL_ERROR
+        }
+    };
+
+    match daemon {
+        Some(daemon) => daemon.exit(code),
+        None => std::process::exit(code),
+    }
+}
+
+// Report why the VM stopped, and return the process exit code for it.
+fn exit_code(reason: ExitReason) -> i32 {
+    match &reason {
+        ExitReason::GuestShutdown | ExitReason::GuestReset | ExitReason::Signal(_) => {}
+        ExitReason::GuestPanic(PanicReport::Console(line)) => {
+            eprintln!("Guest kernel panic: {}", line)
+        }
+        ExitReason::GuestPanic(PanicReport::Pvpanic(PvpanicEvent::Panicked)) => {
+            eprintln!("Guest kernel panic")
+        }
+        ExitReason::GuestPanic(PanicReport::Pvpanic(PvpanicEvent::CrashLoaded)) => {
+            eprintln!("Guest kernel panic, crash kernel loaded")
+        }
+        ExitReason::GuestPanic(PanicReport::SystemEvent) => eprintln!("Guest crash"),
+        ExitReason::Timeout => eprintln!("Guest timed out"),
+        ExitReason::WatchdogExpired(_) => eprintln!("Guest watchdog expired"),
+        ExitReason::VcpuError(message) => eprintln!("Error: {}", message),
+        ExitReason::ConsoleError(message) => {
+            eprintln!("Error: failed to write the console output: {}", message)
+        }
+        // Only --exec pauses the VM from the command line, run() returns its exit code
+        // instead.
+        ExitReason::Paused => eprintln!("Error: the VM paused"),
+    }
+
+    reason.exit_code()
+}
+
+fn run(
+    config: &VMMConfig,
+    mut daemon: Option<&mut Daemon>,
+    pidfile: Option<PathBuf>,
+    exec: Option<String>,
+) -> Result<Outcome, Error> {
+    // Move to the cgroup first, so that the guest memory is charged to it
+    let cgroup = match config.cgroup.as_ref() {
+        Some(cgroup) => Some(Cgroup::enter(cgroup).map_err(Error::Cgroup)?),
+        None => None,
+    };
+
+    // Create and configure the VMM
+    let mut vmm = VMM::from_config(config).map_err(Error::VmmConfigure)?;
+
+    // Detach from the caller. The pidfile is written before entering the jail, which
+    // hides it, but the caller waits for the jail to be entered.
+    if let Some(daemon) = daemon.as_mut() {
+        vmm.detach_stdin().map_err(Error::VmmConfigure)?;
+        if let Some(pidfile) = pidfile.as_deref() {
+            daemon.write_pidfile(pidfile).map_err(Error::Daemonize)?;
+        }
+    }
+
+    // Drop the privileges, before any guest code runs
+    if let Some(jail) = config.jail.as_ref() {
+        vmm::jail::enter(jail).map_err(Error::Jail)?;
+    }
+
+    if let Some(daemon) = daemon {
+        daemon.ready().map_err(Error::Daemonize)?;
+    }
+
+    // The command runs along with the VM, and pauses it once it exited.
+    let exec = exec.map(|cmd| {
+        // The second serial port is in agent mode along with --exec.
+        let agent = vmm.agent().unwrap();
+        let pause = vmm.pause_trigger();
+        thread::spawn(move || exec_command(agent, cmd, pause))
+    });
 
     // Run the VMM
-    vmm.run().map_err(Error::VmmRun)?;
+    let reason = vmm.run().map_err(Error::VmmRun)?;
+    if let Some(cgroup) = cgroup {
+        if let Err(e) = cgroup.remove() {
+            log::warn!("failed to remove the cgroup: {}", error_message(&e));
+        }
+    }
+
+    match (reason, exec) {
+        (ExitReason::Paused, Some(exec)) => join_command(exec),
+        (reason, _) => Ok(Outcome::Stopped(reason)),
+    }
+}
+
+// Run `cmd` through the guest agent, mirroring its output, then pause the VM. Returns
+// the exit code of the command.
+fn exec_command(mut agent: Agent, cmd: String, pause: PauseTrigger) -> agent::Result<i32> {
+    let result = mirror_command(&mut agent, cmd);
+    pause.pause();
+    result
+}
+
+fn mirror_command(agent: &mut Agent, cmd: String) -> agent::Result<i32> {
+    agent.wait_ready(AGENT_READY_TIMEOUT)?;
+
+    let request = ExecRequest {
+        cmd,
+        ..Default::default()
+    };
+    for event in agent.exec(&request)? {
+        // Nobody reading the output does not stop the command.
+        match event? {
+            ExecEvent::Stdout(data) => {
+                let mut stdout = std::io::stdout().lock();
+                let _ = stdout.write_all(&data).and_then(|_| stdout.flush());
+            }
+            ExecEvent::Stderr(data) => {
+                let _ = std::io::stderr().write_all(&data);
+            }
+            ExecEvent::Exit(code) => return Ok(code),
+        }
+    }
+
+    unreachable!("the command events end with its exit code or an error")
+}
 
-    Ok(())
+fn join_command(exec: JoinHandle<agent::Result<i32>>) -> Result<Outcome, Error> {
+    match exec.join() {
+        Ok(result) => result.map(Outcome::Command).map_err(Error::Exec),
+        Err(panic) => std::panic::resume_unwind(panic),
+    }
 }
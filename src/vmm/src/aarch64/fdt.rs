@@ -0,0 +1,454 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Flattened device tree, describing the VM to an aarch64 guest.
+//!
+//! See the Devicetree Specification, chapter 5, for the format.
+
+use std::collections::HashMap;
+use std::result;
+
+use super::layout::{
+    FDT_MAX_SIZE, GIC_DIST_SIZE, GIC_DIST_START, GIC_REDIST_SIZE, GIC_REDIST_START,
+};
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_VERSION: u32 = 17;
+const FDT_LAST_COMP_VERSION: u32 = 16;
+
+// Structure block tokens.
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_END: u32 = 0x9;
+
+const HEADER_SIZE: usize = 40;
+// The memory reservation block only holds its terminating entry.
+const MEM_RSVMAP_SIZE: usize = 16;
+
+// Interrupt specifiers of the GIC: type, number and flags.
+// See Documentation/devicetree/bindings/interrupt-controller/arm,gic-v3.yaml.
+const GIC_SPI: u32 = 0;
+const GIC_PPI: u32 = 1;
+const IRQ_TYPE_LEVEL_HIGH: u32 = 4;
+const GIC_PHANDLE: u32 = 1;
+
+// Architected timer PPIs: secure, non-secure, virtual and hypervisor physical timers.
+const TIMER_PPIS: [u32; 4] = [13, 14, 11, 10];
+
+// Input clock of the 16550 UART, which only matters to compute divisors.
+const UART_CLOCK_HZ: u32 = 1_843_200;
+
+/// Device tree errors.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// A node or property name, or a string value, holds a NUL byte.
+    #[error("invalid device tree string {0:?}")]
+    InvalidString(String),
+    /// A property is outside of any node, or the nodes are not all closed.
+    #[error("unbalanced device tree nodes")]
+    UnbalancedNodes,
+    /// The device tree does not fit in the space reserved for it.
+    #[error(
+        "the device tree is {0} bytes, more than the {} reserved for it",
+        FDT_MAX_SIZE
+    )]
+    TooBig(usize),
+}
+
+/// Dedicated Result type.
+pub type Result<T> = result::Result<T, Error>;
+
+/// Builds a flattened device tree, node by node.
+pub(crate) struct FdtWriter {
+    structure: Vec<u8>,
+    strings: Vec<u8>,
+    // Offsets of the property names in the strings block.
+    string_offsets: HashMap<String, u32>,
+    // Number of open nodes.
+    depth: usize,
+}
+
+fn check_string(s: &str) -> Result<()> {
+    if s.contains('\0') {
+        return Err(Error::InvalidString(s.to_string()));
+    }
+    Ok(())
+}
+
+impl FdtWriter {
+    pub fn new() -> Self {
+        FdtWriter {
+            structure: Vec::new(),
+            strings: Vec::new(),
+            string_offsets: HashMap::new(),
+            depth: 0,
+        }
+    }
+
+    fn push_u32(&mut self, value: u32) {
+        self.structure.extend_from_slice(&value.to_be_bytes());
+    }
+
+    // Tokens are 32-bit aligned.
+    fn align(&mut self) {
+        let len = (self.structure.len() + 3) & !3;
+        self.structure.resize(len, 0);
+    }
+
+    fn string_offset(&mut self, name: &str) -> Result<u32> {
+        check_string(name)?;
+        if let Some(offset) = self.string_offsets.get(name) {
+            return Ok(*offset);
+        }
+
+        let offset = self.strings.len() as u32;
+        self.strings.extend_from_slice(name.as_bytes());
+        self.strings.push(0);
+        self.string_offsets.insert(name.to_string(), offset);
+
+        Ok(offset)
+    }
+
+    /// Open a node. The root node has an empty name.
+    pub fn begin_node(&mut self, name: &str) -> Result<()> {
+        check_string(name)?;
+        self.push_u32(FDT_BEGIN_NODE);
+        self.structure.extend_from_slice(name.as_bytes());
+        self.structure.push(0);
+        self.align();
+        self.depth += 1;
+
+        Ok(())
+    }
+
+    /// Close the last open node.
+    pub fn end_node(&mut self) -> Result<()> {
+        if self.depth == 0 {
+            return Err(Error::UnbalancedNodes);
+        }
+        self.push_u32(FDT_END_NODE);
+        self.depth -= 1;
+
+        Ok(())
+    }
+
+    /// Add a property to the last open node.
+    pub fn property(&mut self, name: &str, value: &[u8]) -> Result<()> {
+        if self.depth == 0 {
+            return Err(Error::UnbalancedNodes);
+        }
+        let name_offset = self.string_offset(name)?;
+
+        self.push_u32(FDT_PROP);
+        self.push_u32(value.len() as u32);
+        self.push_u32(name_offset);
+        self.structure.extend_from_slice(value);
+        self.align();
+
+        Ok(())
+    }
+
+    /// Add a property without a value, i.e. a flag.
+    pub fn property_null(&mut self, name: &str) -> Result<()> {
+        self.property(name, &[])
+    }
+
+    pub fn property_string(&mut self, name: &str, value: &str) -> Result<()> {
+        check_string(value)?;
+        let mut bytes = value.as_bytes().to_vec();
+        bytes.push(0);
+        self.property(name, &bytes)
+    }
+
+    pub fn property_u32(&mut self, name: &str, value: u32) -> Result<()> {
+        self.property(name, &value.to_be_bytes())
+    }
+
+    pub fn property_u64(&mut self, name: &str, value: u64) -> Result<()> {
+        self.property(name, &value.to_be_bytes())
+    }
+
+    pub fn property_array_u32(&mut self, name: &str, values: &[u32]) -> Result<()> {
+        let bytes: Vec<u8> = values.iter().flat_map(|v| v.to_be_bytes()).collect();
+        self.property(name, &bytes)
+    }
+
+    pub fn property_array_u64(&mut self, name: &str, values: &[u64]) -> Result<()> {
+        let bytes: Vec<u8> = values.iter().flat_map(|v| v.to_be_bytes()).collect();
+        self.property(name, &bytes)
+    }
+
+    /// Lay the header and the blocks out, once all the nodes are closed.
+    pub fn finish(mut self) -> Result<Vec<u8>> {
+        if self.depth != 0 {
+            return Err(Error::UnbalancedNodes);
+        }
+        self.push_u32(FDT_END);
+
+        let off_mem_rsvmap = HEADER_SIZE;
+        let off_dt_struct = off_mem_rsvmap + MEM_RSVMAP_SIZE;
+        let off_dt_strings = off_dt_struct + self.structure.len();
+        let total_size = off_dt_strings + self.strings.len();
+        if total_size as u64 > FDT_MAX_SIZE {
+            return Err(Error::TooBig(total_size));
+        }
+
+        let mut fdt = Vec::with_capacity(total_size);
+        for field in [
+            FDT_MAGIC,
+            total_size as u32,
+            off_dt_struct as u32,
+            off_dt_strings as u32,
+            off_mem_rsvmap as u32,
+            FDT_VERSION,
+            FDT_LAST_COMP_VERSION,
+            // boot_cpuid_phys
+            0,
+            self.strings.len() as u32,
+            self.structure.len() as u32,
+        ] {
+            fdt.extend_from_slice(&field.to_be_bytes());
+        }
+        fdt.resize(off_dt_struct, 0);
+        fdt.extend_from_slice(&self.structure);
+        fdt.extend_from_slice(&self.strings);
+
+        Ok(fdt)
+    }
+}
+
+/// A memory mapped device: its register window, and its SPI.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct MmioDevice {
+    pub base: u64,
+    pub size: u64,
+    pub irq: u32,
+}
+
+/// What the guest is told about the VM.
+pub(crate) struct FdtConfig<'a> {
+    pub vcpu_count: u32,
+    /// RAM regions, as (address, size).
+    pub memory: &'a [(u64, u64)],
+    pub cmdline: &'a str,
+    /// Initramfs, as (address, size).
+    pub initrd: Option<(u64, u64)>,
+    /// Serial ports, the first one being the console.
+    pub serial_ports: &'a [MmioDevice],
+    pub virtio_devices: &'a [MmioDevice],
+}
+
+// Open the node of a memory mapped device, with its register window and its interrupt.
+// The caller adds its other properties, and closes it.
+fn mmio_device_node(
+    fdt: &mut FdtWriter,
+    name: &str,
+    compatible: &str,
+    device: &MmioDevice,
+) -> Result<()> {
+    fdt.begin_node(&format!("{}@{:x}", name, device.base))?;
+    fdt.property_string("compatible", compatible)?;
+    fdt.property_array_u64("reg", &[device.base, device.size])?;
+    fdt.property_array_u32("interrupts", &[GIC_SPI, device.irq, IRQ_TYPE_LEVEL_HIGH])?;
+    Ok(())
+}
+
+/// Build the device tree: the vCPUs, the RAM, the GIC, the timer, the devices, and the
+/// kernel command line.
+pub(crate) fn create_fdt(config: &FdtConfig) -> Result<Vec<u8>> {
+    let mut fdt = FdtWriter::new();
+
+    fdt.begin_node("")?;
+    fdt.property_string("compatible", "linux,dummy-virt")?;
+    fdt.property_u32("#address-cells", 2)?;
+    fdt.property_u32("#size-cells", 2)?;
+    fdt.property_u32("interrupt-parent", GIC_PHANDLE)?;
+
+    fdt.begin_node("cpus")?;
+    fdt.property_u32("#address-cells", 1)?;
+    fdt.property_u32("#size-cells", 0)?;
+    for index in 0..config.vcpu_count {
+        fdt.begin_node(&format!("cpu@{:x}", index))?;
+        fdt.property_string("device_type", "cpu")?;
+        fdt.property_string("compatible", "arm,arm-v8")?;
+        fdt.property_string("enable-method", "psci")?;
+        fdt.property_u32("reg", index)?;
+        fdt.end_node()?;
+    }
+    fdt.end_node()?;
+
+    for (address, size) in config.memory {
+        fdt.begin_node(&format!("memory@{:x}", address))?;
+        fdt.property_string("device_type", "memory")?;
+        fdt.property_array_u64("reg", &[*address, *size])?;
+        fdt.end_node()?;
+    }
+
+    fdt.begin_node("chosen")?;
+    fdt.property_string("bootargs", config.cmdline)?;
+    if let Some(console) = config.serial_ports.first() {
+        fdt.property_string("stdout-path", &format!("/uart@{:x}", console.base))?;
+    }
+    if let Some((address, size)) = config.initrd {
+        fdt.property_u64("linux,initrd-start", address)?;
+        fdt.property_u64("linux,initrd-end", address + size)?;
+    }
+    fdt.end_node()?;
+
+    fdt.begin_node(&format!("intc@{:x}", GIC_DIST_START))?;
+    fdt.property_string("compatible", "arm,gic-v3")?;
+    fdt.property_null("interrupt-controller")?;
+    fdt.property_u32("#interrupt-cells", 3)?;
+    fdt.property_array_u64(
+        "reg",
+        &[
+            GIC_DIST_START,
+            GIC_DIST_SIZE,
+            GIC_REDIST_START,
+            u64::from(config.vcpu_count) * GIC_REDIST_SIZE,
+        ],
+    )?;
+    fdt.property_u32("phandle", GIC_PHANDLE)?;
+    fdt.end_node()?;
+
+    fdt.begin_node("psci")?;
+    fdt.property_string("compatible", "arm,psci-0.2")?;
+    fdt.property_string("method", "hvc")?;
+    fdt.end_node()?;
+
+    fdt.begin_node("timer")?;
+    fdt.property_string("compatible", "arm,armv8-timer")?;
+    fdt.property_null("always-on")?;
+    let interrupts: Vec<u32> = TIMER_PPIS
+        .iter()
+        .flat_map(|ppi| [GIC_PPI, *ppi, IRQ_TYPE_LEVEL_HIGH])
+        .collect();
+    fdt.property_array_u32("interrupts", &interrupts)?;
+    fdt.end_node()?;
+
+    // The serial ports are the same 16550 UARTs as on x86_64, their registers are just
+    // memory mapped.
+    for port in config.serial_ports {
+        mmio_device_node(&mut fdt, "uart", "ns16550a", port)?;
+        fdt.property_u32("clock-frequency", UART_CLOCK_HZ)?;
+        fdt.end_node()?;
+    }
+
+    for device in config.virtio_devices {
+        mmio_device_node(&mut fdt, "virtio_mmio", "virtio,mmio", device)?;
+        fdt.property_null("dma-coherent")?;
+        fdt.end_node()?;
+    }
+
+    fdt.end_node()?;
+
+    fdt.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn be32(fdt: &[u8], offset: usize) -> u32 {
+        u32::from_be_bytes(fdt[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn layout() {
+        let mut fdt = FdtWriter::new();
+        fdt.begin_node("").unwrap();
+        fdt.property_u32("#size-cells", 2).unwrap();
+        fdt.begin_node("chosen").unwrap();
+        fdt.property_string("bootargs", "panic=1").unwrap();
+        // Property names are only stored once.
+        fdt.property_u32("#size-cells", 1).unwrap();
+        fdt.end_node().unwrap();
+        fdt.end_node().unwrap();
+        let fdt = fdt.finish().unwrap();
+
+        assert_eq!(be32(&fdt, 0), FDT_MAGIC);
+        assert_eq!(be32(&fdt, 4) as usize, fdt.len());
+        assert_eq!(be32(&fdt, 20), FDT_VERSION);
+
+        let off_struct = be32(&fdt, 8) as usize;
+        let off_strings = be32(&fdt, 12) as usize;
+        let size_strings = be32(&fdt, 32) as usize;
+        let size_struct = be32(&fdt, 36) as usize;
+        assert_eq!(off_struct, HEADER_SIZE + MEM_RSVMAP_SIZE);
+        assert_eq!(off_struct + size_struct, off_strings);
+        assert_eq!(off_strings + size_strings, fdt.len());
+        assert_eq!(&fdt[off_strings..], b"#size-cells\0bootargs\0");
+
+        let structure: Vec<u32> = (off_struct..off_strings)
+            .step_by(4)
+            .map(|offset| be32(&fdt, offset))
+            .collect();
+        let chosen = u32::from_be_bytes(*b"chos");
+        let en = u32::from_be_bytes(*b"en\0\0");
+        let panic = u32::from_be_bytes(*b"pani");
+        let c1 = u32::from_be_bytes(*b"c=1\0");
+        let expected = [
+            &[FDT_BEGIN_NODE, 0][..],
+            &[FDT_PROP, 4, 0, 2],
+            &[FDT_BEGIN_NODE, chosen, en],
+            &[FDT_PROP, 8, 12, panic, c1],
+            &[FDT_PROP, 4, 0, 1],
+            &[FDT_END_NODE, FDT_END_NODE, FDT_END],
+        ]
+        .concat();
+        assert_eq!(structure, expected);
+    }
+
+    #[test]
+    fn unbalanced() {
+        let mut fdt = FdtWriter::new();
+        assert!(matches!(fdt.end_node(), Err(Error::UnbalancedNodes)));
+        assert!(matches!(
+            fdt.property_u32("reg", 0),
+            Err(Error::UnbalancedNodes)
+        ));
+
+        fdt.begin_node("").unwrap();
+        assert!(matches!(
+            fdt.property_string("bootargs", "a\0b"),
+            Err(Error::InvalidString(_))
+        ));
+        assert!(matches!(fdt.finish(), Err(Error::UnbalancedNodes)));
+    }
+
+    #[test]
+    fn vm_tree() {
+        let serial = MmioDevice {
+            base: 0x0a00_0000,
+            size: 0x8,
+            irq: 1,
+        };
+        let net = MmioDevice {
+            base: 0x0b00_0000,
+            size: 0x1000,
+            irq: 3,
+        };
+        let fdt = create_fdt(&FdtConfig {
+            vcpu_count: 4,
+            memory: &[(0x8000_0000, 512 << 20)],
+            cmdline: "console=ttyS0",
+            initrd: Some((0x8400_0000, 0x1000)),
+            serial_ports: &[serial],
+            virtio_devices: &[net],
+        })
+        .unwrap();
+
+        assert_eq!(be32(&fdt, 0), FDT_MAGIC);
+        let contains = |needle: &[u8]| fdt.windows(needle.len()).any(|w| w == needle);
+        for needle in [
+            &b"cpu@3\0"[..],
+            b"memory@80000000\0",
+            b"stdout-path\0",
+            b"/uart@a000000\0",
+            b"virtio_mmio@b000000\0",
+            b"linux,initrd-end\0",
+        ] {
+            assert!(contains(needle), "{:?}", String::from_utf8_lossy(needle));
+        }
+    }
+}
@@ -0,0 +1,69 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use kvm_bindings::{
+    kvm_create_device, kvm_device_attr, kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_V3,
+    KVM_DEV_ARM_VGIC_CTRL_INIT, KVM_DEV_ARM_VGIC_GRP_ADDR, KVM_DEV_ARM_VGIC_GRP_CTRL,
+    KVM_DEV_ARM_VGIC_GRP_NR_IRQS, KVM_VGIC_V3_ADDR_TYPE_DIST, KVM_VGIC_V3_ADDR_TYPE_REDIST,
+};
+use kvm_ioctls::{DeviceFd, VmFd};
+
+use super::layout::{GIC_DIST_START, GIC_REDIST_START};
+
+/// Number of private interrupts of each vCPU: SGIs and PPIs.
+const GIC_PRIVATE_IRQS: u32 = 32;
+/// Number of SPIs, the interrupts wired to devices. With the default KVM routing, GSI n
+/// signals SPI n.
+pub(crate) const GIC_SPI_COUNT: u32 = 64;
+
+fn set_attr(gic: &DeviceFd, group: u32, attr: u32, addr: u64) -> kvm_ioctls::Result<()> {
+    gic.set_device_attr(&kvm_device_attr {
+        flags: 0,
+        group,
+        attr: attr.into(),
+        addr,
+    })
+}
+
+/// Create the in-kernel GICv3.
+///
+/// All the vCPUs must exist: the GIC gets one redistributor per vCPU when initialized.
+/// The irqfds are registered once it is.
+pub(crate) fn create_gic(vm_fd: &VmFd) -> kvm_ioctls::Result<DeviceFd> {
+    let mut device = kvm_create_device {
+        type_: kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_V3,
+        fd: 0,
+        flags: 0,
+    };
+    let gic = vm_fd.create_device(&mut device)?;
+
+    // KVM reads the attribute values from the addresses we pass.
+    let dist: u64 = GIC_DIST_START;
+    let redist: u64 = GIC_REDIST_START;
+    let irqs: u32 = GIC_PRIVATE_IRQS + GIC_SPI_COUNT;
+    set_attr(
+        &gic,
+        KVM_DEV_ARM_VGIC_GRP_ADDR,
+        KVM_VGIC_V3_ADDR_TYPE_DIST,
+        &dist as *const u64 as u64,
+    )?;
+    set_attr(
+        &gic,
+        KVM_DEV_ARM_VGIC_GRP_ADDR,
+        KVM_VGIC_V3_ADDR_TYPE_REDIST,
+        &redist as *const u64 as u64,
+    )?;
+    set_attr(
+        &gic,
+        KVM_DEV_ARM_VGIC_GRP_NR_IRQS,
+        0,
+        &irqs as *const u32 as u64,
+    )?;
+    set_attr(
+        &gic,
+        KVM_DEV_ARM_VGIC_GRP_CTRL,
+        KVM_DEV_ARM_VGIC_CTRL_INIT,
+        0,
+    )?;
+
+    Ok(gic)
+}
@@ -0,0 +1,164 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use std::io::{self, Read, Seek};
+
+use linux_loader::loader::{pe::PE, KernelLoader, KernelLoaderResult};
+use serde::Serialize;
+use vm_memory::{Address, GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
+
+use super::layout::{fdt_address, DRAM_START};
+use crate::config::ImageFile;
+use crate::{initramfs, Error, Result};
+
+// Default command line
+pub const DEFAULT_CMDLINE: &str = "console=ttyS0 panic=1";
+
+// The initramfs starts on a page boundary.
+const PAGE_SIZE: u64 = 0x1000;
+
+// arm64 Image header. See Documentation/arch/arm64/booting.rst.
+const IMAGE_HEADER_SIZE: usize = 64;
+const IMAGE_MAGIC: u32 = 0x644d_5241;
+// Offset of the kernels which predate the text_offset field.
+const IMAGE_DEFAULT_TEXT_OFFSET: u64 = 0x8_0000;
+
+/// The kernel image, as read from its header.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct KernelImage {
+    pub format: &'static str,
+    /// Entry point address.
+    pub entry: u64,
+    /// Guest physical range the image loads to.
+    pub start: u64,
+    pub end: u64,
+}
+
+fn le_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+/// Read the Image header of the kernel, and check that it loads in the `ram` regions, as
+/// (address, size), below the device tree, without loading it.
+pub fn inspect(ram: &[(GuestAddress, usize)], file: &mut ImageFile) -> Result<KernelImage> {
+    let image = file.source().clone();
+    let open_error = |source: io::Error| Error::KernelOpen {
+        image: image.clone(),
+        source,
+    };
+    let file_size = file.size().map_err(open_error)?;
+    file.rewind().map_err(open_error)?;
+
+    let mut header = [0u8; IMAGE_HEADER_SIZE];
+    file.read_exact(&mut header)
+        .map_err(|_| Error::KernelHeader {
+            image: image.clone(),
+            reason: "truncated Image header",
+        })?;
+    if u32::from_le_bytes(header[56..60].try_into().unwrap()) != IMAGE_MAGIC {
+        return Err(Error::KernelHeader {
+            image,
+            reason: "no arm64 Image magic",
+        });
+    }
+
+    let (text_offset, size) = match le_u64(&header, 16) {
+        0 => (IMAGE_DEFAULT_TEXT_OFFSET, file_size),
+        size => (le_u64(&header, 8), size),
+    };
+    // The RAM start is 2 MiB aligned, the kernel base.
+    let start = DRAM_START + text_offset;
+    let end = start + size;
+
+    let ram_end = ram
+        .iter()
+        .map(|(addr, size)| addr.raw_value() + *size as u64)
+        .max()
+        .unwrap_or(DRAM_START);
+    let limit = fdt_address(ram_end).raw_value();
+    if end > limit {
+        return Err(Error::KernelPastRam {
+            image,
+            end,
+            ram_end: limit,
+        });
+    }
+
+    Ok(KernelImage {
+        format: "image",
+        entry: start,
+        start,
+        end,
+    })
+}
+
+/// Where the initramfs goes, past the kernel ending at `kernel_end`, as an address and the
+/// limit it must stay below: the device tree.
+pub fn initramfs_range(ram: &[(GuestAddress, usize)], kernel_end: u64) -> Result<(u64, u64)> {
+    let ram_end = ram
+        .iter()
+        .map(|(addr, size)| addr.raw_value() + *size as u64)
+        .max()
+        .ok_or(Error::ImagesTooLarge)?;
+
+    Ok((
+        (kernel_end + PAGE_SIZE - 1) & !(PAGE_SIZE - 1),
+        fdt_address(ram_end).raw_value(),
+    ))
+}
+
+/// Where the kernel and the initramfs were loaded.
+pub struct LoadedImages {
+    pub kernel: KernelLoaderResult,
+    /// Initramfs, as (address, size).
+    pub initrd: Option<(u64, u64)>,
+}
+
+/// Load the kernel `Image` at the start of the RAM, and the initramfs right after it.
+///
+/// Both must fit below the device tree, at the end of the RAM. With `decompress_initramfs`,
+/// a gzip initramfs is inflated on the host.
+pub fn kernel_setup(
+    guest_memory: &GuestMemoryMmap,
+    kernel_image: &mut ImageFile,
+    initramfs: Option<&mut ImageFile>,
+    decompress_initramfs: bool,
+) -> Result<LoadedImages> {
+    let ram: Vec<_> = guest_memory
+        .iter()
+        .map(|region| (region.start_addr(), region.len() as usize))
+        .collect();
+    // Check the header first, for a clearer error than the loader's.
+    inspect(&ram, kernel_image)?;
+
+    // The Image header tells where it goes, relative to a 2 MiB aligned base.
+    let kernel = PE::load(
+        guest_memory,
+        Some(GuestAddress(DRAM_START)),
+        kernel_image,
+        None,
+    )
+    .map_err(Error::KernelLoad)?;
+
+    let limit = fdt_address(guest_memory.last_addr().raw_value() + 1).raw_value();
+    if kernel.kernel_end > limit {
+        return Err(Error::ImagesTooLarge);
+    }
+
+    let initrd = match initramfs {
+        Some(initramfs) => {
+            let (initramfs_address, limit) = initramfs_range(&ram, kernel.kernel_end)?;
+            let initramfs_size = initramfs::load(
+                guest_memory,
+                initramfs,
+                initramfs_address,
+                limit,
+                decompress_initramfs,
+            )?;
+
+            Some((initramfs_address, initramfs_size))
+        }
+        None => None,
+    };
+
+    Ok(LoadedImages { kernel, initrd })
+}
@@ -0,0 +1,105 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! aarch64 guest physical memory map.
+//!
+//! ```text
+//!   0x0800_0000  GICv3 distributor, then one redistributor per vCPU
+//!   0x0a00_0000  serial ports
+//!   0x0b00_0000  device registers, e.g. the virtio-mmio ones
+//!   0x8000_0000  RAM: the kernel, the initramfs, then the device tree at the end
+//!                device memory, past the RAM
+//! ```
+
+use vm_memory::GuestAddress;
+
+use crate::cpu::MAX_SUPPORTED_CPUS;
+
+/// GICv3 distributor registers.
+pub(crate) const GIC_DIST_START: u64 = 0x0800_0000;
+pub(crate) const GIC_DIST_SIZE: u64 = 0x1_0000;
+/// GICv3 redistributors, one per vCPU.
+pub(crate) const GIC_REDIST_START: u64 = GIC_DIST_START + GIC_DIST_SIZE;
+/// Size of the redistributor of one vCPU: its RD and SGI frames.
+pub(crate) const GIC_REDIST_SIZE: u64 = 0x2_0000;
+
+/// Serial port registers, one page per port.
+pub(crate) const SERIAL_MMIO_START: u64 = 0x0a00_0000;
+pub(crate) const SERIAL_MMIO_SIZE: u64 = 0x1000;
+
+/// Range where the device registers, e.g. the virtio-mmio ones, are allocated.
+pub(crate) const DEVICE_MMIO_START: u64 = 0x0b00_0000;
+pub(crate) const DEVICE_MMIO_SIZE: u64 = 0x1000_0000;
+
+/// Start of the RAM, where the kernel is loaded.
+pub(crate) const DRAM_START: u64 = 0x8000_0000;
+
+/// Maximum size of the kernel command line, including the terminating NUL.
+/// Source: COMMAND_LINE_SIZE in linux/arch/arm64/include/uapi/asm/setup.h.
+pub(crate) const CMDLINE_MAX_SIZE: usize = 2048;
+
+/// Maximum size of the device tree. It must not cross a 2 MiB boundary.
+pub(crate) const FDT_MAX_SIZE: u64 = 0x20_0000;
+
+/// Size of the range where device memory (pmem) is allocated, past the RAM. The guest
+/// physical address space is 40 bits wide by default.
+pub(crate) const DEVICE_MEMORY_SIZE: u64 = 1 << 38;
+
+/// RAM regions for `size` bytes of guest memory. There is no hole to go around.
+pub(crate) fn ram_regions(size: u64) -> Vec<(GuestAddress, usize)> {
+    vec![(GuestAddress(DRAM_START), size as usize)]
+}
+
+/// Where device memory can start, given the end of the RAM.
+pub(crate) fn device_memory_start(ram_end: u64) -> u64 {
+    ram_end
+}
+
+/// Where the device tree is written: in the last 2 MiB block of the RAM.
+pub(crate) fn fdt_address(ram_end: u64) -> GuestAddress {
+    GuestAddress((ram_end - FDT_MAX_SIZE) & !(FDT_MAX_SIZE - 1))
+}
+
+// Fixed guest physical ranges, as (start, size), sorted by address.
+const REGIONS: [(u64, u64); 5] = [
+    (GIC_DIST_START, GIC_DIST_SIZE),
+    (
+        GIC_REDIST_START,
+        MAX_SUPPORTED_CPUS as u64 * GIC_REDIST_SIZE,
+    ),
+    // COM1 and COM2.
+    (SERIAL_MMIO_START, 2 * SERIAL_MMIO_SIZE),
+    (DEVICE_MMIO_START, DEVICE_MMIO_SIZE),
+    // The RAM, past all the device registers.
+    (DRAM_START, 0),
+];
+
+const fn disjoint(regions: &[(u64, u64)]) -> bool {
+    let mut i = 1;
+    while i < regions.len() {
+        if regions[i - 1].0 + regions[i - 1].1 > regions[i].0 {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+const _: () = assert!(disjoint(&REGIONS), "guest memory regions overlap");
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vm_memory::Address;
+
+    #[test]
+    fn fdt_in_ram() {
+        for size in [64u64 << 20, (512 << 20) + 0x1000, 8 << 30] {
+            let ram_end = DRAM_START + size;
+            let fdt = fdt_address(ram_end).raw_value();
+
+            assert_eq!(fdt % FDT_MAX_SIZE, 0);
+            assert!(fdt >= DRAM_START);
+            assert!(fdt + FDT_MAX_SIZE <= ram_end);
+        }
+    }
+}
@@ -0,0 +1,119 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! aarch64 boot: the boot vCPU enters the kernel `Image` directly, with a device tree
+//! describing the VM. The interrupt controller is an in-kernel GICv3.
+
+#![cfg(target_arch = "aarch64")]
+
+use std::time::Instant;
+
+use vm_memory::{Address, Bytes, GuestAddress, GuestMemory, GuestMemoryRegion};
+
+use crate::config::CpuTopology;
+use crate::cpu::Vcpu;
+use crate::devices::serial::{SerialPort, COM1, COM2, SERIAL_PORT_SIZE};
+use crate::{Error, Result, VmmState, VMM};
+
+pub(crate) mod fdt;
+use fdt::{FdtConfig, MmioDevice};
+pub(crate) mod gic;
+pub(crate) mod kernel;
+use kernel::LoadedImages;
+pub(crate) mod layout;
+
+impl VMM {
+    /// Create the vCPUs. The boot vCPU enters the kernel with the address of the device
+    /// tree, the others wait for the guest to start them.
+    pub(crate) fn configure_vcpus(
+        &mut self,
+        topology: &CpuTopology,
+        images: &LoadedImages,
+    ) -> Result<()> {
+        self.check_state(&[VmmState::KernelLoaded], "configure the vCPUs of")?;
+        self.check_vcpu_count(topology)?;
+        let start = Instant::now();
+
+        let fdt_address = self.fdt_address();
+        for index in 0..topology.vcpu_count() as u8 {
+            let vcpu = Vcpu::new(
+                &self.vm_fd,
+                index.into(),
+                index.into(),
+                self.io_manager.clone(),
+                self.unknown_ports.clone(),
+            )
+            .map_err(Error::Vcpu)?;
+
+            vcpu.init(&self.vm_fd).map_err(Error::Vcpu)?;
+            vcpu.configure_regs(images.kernel.kernel_load, fdt_address)
+                .map_err(Error::Vcpu)?;
+
+            self.vcpus.push(vcpu);
+        }
+
+        self.vcpu_configure_time = start.elapsed();
+        Ok(())
+    }
+
+    /// Create the GIC, and wire the device interrupts to it.
+    ///
+    /// This must be called once all the devices and the vCPUs are configured.
+    pub(crate) fn configure_io(&mut self) -> Result<()> {
+        self.check_state(&[VmmState::KernelLoaded], "configure the irqchip of")?;
+        // The GIC lives as long as the VM, its fd is only needed to set it up.
+        gic::create_gic(&self.vm_fd).map_err(Error::KvmIoctl)?;
+
+        for (gsi, irqfd) in self.irqfds.iter() {
+            self.vm_fd
+                .register_irqfd(irqfd, *gsi)
+                .map_err(Error::KvmIoctl)?;
+        }
+
+        Ok(())
+    }
+
+    /// Describe the vCPUs, the RAM, the devices and the kernel command line to the guest
+    /// with a device tree, at the end of the RAM.
+    pub(crate) fn configure_fdt(
+        &mut self,
+        topology: &CpuTopology,
+        images: &LoadedImages,
+    ) -> Result<()> {
+        self.check_state(&[VmmState::KernelLoaded], "write the device tree of")?;
+        let memory: Vec<(u64, u64)> = self
+            .guest_memory
+            .iter()
+            .map(|region| (region.start_addr().raw_value(), region.len()))
+            .collect();
+        let cmdline = self.cmdline.build().map_err(Error::CmdlineCompose)?;
+
+        let serial_port = |port: &SerialPort| MmioDevice {
+            base: port.base,
+            size: u64::from(SERIAL_PORT_SIZE),
+            irq: port.irq,
+        };
+        let mut serial_ports = vec![serial_port(&COM1)];
+        if self.serial2.is_some() {
+            serial_ports.push(serial_port(&COM2));
+        }
+
+        let fdt = fdt::create_fdt(&FdtConfig {
+            vcpu_count: topology.vcpu_count(),
+            memory: &memory,
+            cmdline: &cmdline,
+            initrd: images.initrd,
+            serial_ports: &serial_ports,
+            virtio_devices: &self.virtio_devices,
+        })?;
+        self.guest_memory
+            .write_slice(&fdt, self.fdt_address())
+            .map_err(Error::GuestMemory)?;
+        self.state = VmmState::Ready;
+
+        Ok(())
+    }
+
+    fn fdt_address(&self) -> GuestAddress {
+        layout::fdt_address(self.guest_memory.last_addr().raw_value() + 1)
+    }
+}
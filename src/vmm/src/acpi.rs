@@ -0,0 +1,730 @@
+// SPDX-License-Identifier: Apache-2.0
+
+#![cfg(target_arch = "x86_64")]
+
+//! Minimal ACPI tables, describing the vCPUs and the power management registers.
+//!
+//! The guest finds the RSDP by scanning the BIOS read-only area, which is not RAM in the
+//! E820 map. The RSDP points to the XSDT, listing the FADT and MADT. The FADT points to
+//! the FACS, and to a DSDT that defines the `\_S5_` sleep state for poweroff, and the
+//! pvpanic device.
+//!
+//! With vCPU hotplug, the MADT also lists the hot-pluggable vCPUs, disabled. The DSDT then
+//! has a processor device for each vCPU, present according to the
+//! [`CpuHotplug`](crate::devices::cpu_hotplug::CpuHotplug) register, and a Generic Event
+//! Device whose interrupt makes the guest check them again.
+
+use std::mem;
+use std::result;
+
+use vm_memory::{Address, Bytes, GuestAddress, GuestMemoryError, GuestMemoryMmap};
+
+use crate::config::CpuTopology;
+use crate::cpu::mptable::MAX_SUPPORTED_CPUS;
+use crate::devices::acpi_pm::{
+    PM1A_CNT_BLK, PM1A_EVT_BLK, PM1_CNT_LEN, PM1_EVT_LEN, S5_SLP_TYP, SCI_IRQ,
+};
+use crate::devices::cpu_hotplug::CPU_HOTPLUG_PORT;
+use crate::devices::i8042::{I8042_COMMAND_PORT, I8042_RESET};
+use crate::devices::rtc::CENTURY;
+use crate::layout::{ACPI_TABLES_END, ACPI_TABLES_START, APIC_START, IOAPIC_START};
+
+const OEM_ID: [u8; 6] = *b"LUMPER";
+const OEM_TABLE_ID: [u8; 8] = *b"LUMPERVM";
+const OEM_REVISION: u32 = 1;
+const CREATOR_ID: [u8; 4] = *b"LMPR";
+const CREATOR_REVISION: u32 = 1;
+
+// Size of the header shared by all the system description tables.
+const SDT_HEADER_SIZE: usize = 36;
+// Offsets of the header length and checksum fields.
+const SDT_LENGTH_OFFSET: usize = 4;
+const SDT_CHECKSUM_OFFSET: usize = 9;
+
+// ACPI 2.0 RSDP, with the XSDT address.
+const RSDP_REVISION: u8 = 2;
+const RSDP_SIZE: usize = 36;
+// The first checksum only covers the ACPI 1.0 fields.
+const RSDP_V1_SIZE: usize = 20;
+
+const FADT_REVISION: u8 = 6;
+const FADT_SIZE: usize = 276;
+// FADT fields, as offsets in the table.
+const FADT_FIRMWARE_CTRL: usize = 36;
+const FADT_DSDT: usize = 40;
+const FADT_SCI_INT: usize = 46;
+const FADT_PM1A_EVT_BLK: usize = 56;
+const FADT_PM1A_CNT_BLK: usize = 64;
+const FADT_PM1_EVT_LEN: usize = 88;
+const FADT_PM1_CNT_LEN: usize = 89;
+const FADT_CENTURY: usize = 108;
+const FADT_IAPC_BOOT_ARCH: usize = 109;
+const FADT_FLAGS: usize = 112;
+const FADT_RESET_REG: usize = 116;
+const FADT_RESET_VALUE: usize = 128;
+// IA-PC boot architecture flags: no VGA and no MSI.
+const IAPC_BOOT_ARCH: u16 = (1 << 2) | (1 << 3);
+// WBINVD works, there are no fixed power or sleep buttons, and the reset register is
+// supported.
+const FADT_FLAGS_VALUE: u32 = 1 | (1 << 4) | (1 << 5) | (1 << 10);
+// Generic address structure of a byte register in the system I/O space, before its
+// address.
+const GAS_SYSTEM_IO_BYTE: [u8; 4] = [1, 8, 0, 1];
+
+const FACS_SIZE: usize = 64;
+// The FACS must be 64 bytes aligned.
+const FACS_ALIGNMENT: u64 = 64;
+
+const MADT_REVISION: u8 = 4;
+// The guest also has the legacy 8259 PICs.
+const MADT_PCAT_COMPAT: u32 = 1;
+const MADT_LOCAL_APIC: u8 = 0;
+const MADT_IO_APIC: u8 = 1;
+const MADT_LOCAL_APIC_NMI: u8 = 4;
+const MADT_LOCAL_APIC_ENABLED: u32 = 1;
+// The NMI is wired to LINT1 of all the processors.
+const MADT_ALL_PROCESSORS: u8 = 0xff;
+const MADT_NMI_LINT: u8 = 1;
+
+const DSDT_REVISION: u8 = 2;
+// AML for `Name (_S5_, Package () { S5_SLP_TYP, 0, 0, 0 })`.
+const DSDT_S5: [u8; 13] = [
+    0x08, b'_', b'S', b'5', b'_', // NameOp, name
+    0x12, 0x07, 0x04, // PackageOp, length, 4 elements
+    0x0a, S5_SLP_TYP, // BytePrefix, SLP_TYPa
+    0x00, 0x00, 0x00, // SLP_TYPb and reserved
+];
+// AML for the pvpanic device, as QEMU describes it:
+// Scope (\_SB) {
+//     Device (PEVT) {
+//         Name (_HID, "QEMU0001")
+//         Name (_CRS, ResourceTemplate () {
+//             IO (Decode16, PVPANIC_PORT, PVPANIC_PORT, 1, 1)
+//         })
+//     }
+// }
+const DSDT_PVPANIC: [u8; 47] = [
+    0x10, 0x2e, b'_', b'S', b'B', b'_', // ScopeOp, length, name
+    0x5b, 0x82, 0x27, b'P', b'E', b'V', b'T', // DeviceOp, length, name
+    0x08, b'_', b'H', b'I', b'D', // NameOp, name
+    0x0d, b'Q', b'E', b'M', b'U', b'0', b'0', b'0', b'1', 0x00, // StringPrefix, string
+    0x08, b'_', b'C', b'R', b'S', // NameOp, name
+    0x11, 0x0d, 0x0a, 0x0a, // BufferOp, length, BytePrefix, buffer size
+    0x47, 0x01, // I/O port descriptor, 16 bits decoding
+    0x05, 0x05, // minimum base, PVPANIC_PORT
+    0x05, 0x05, // maximum base
+    0x01, 0x01, // alignment, length
+    0x79, 0x00, // end tag, no checksum
+];
+
+// AML opcodes of the generated objects.
+const AML_ZERO: u8 = 0x00;
+const AML_ONE: u8 = 0x01;
+const AML_NAME: u8 = 0x08;
+const AML_BYTE_PREFIX: u8 = 0x0a;
+const AML_DWORD_PREFIX: u8 = 0x0c;
+const AML_STRING_PREFIX: u8 = 0x0d;
+const AML_SCOPE: u8 = 0x10;
+const AML_BUFFER: u8 = 0x11;
+const AML_METHOD: u8 = 0x14;
+const AML_OPERATION_REGION: [u8; 2] = [0x5b, 0x80];
+const AML_FIELD: [u8; 2] = [0x5b, 0x81];
+const AML_DEVICE: [u8; 2] = [0x5b, 0x82];
+const AML_NOTIFY: u8 = 0x86;
+const AML_LGREATER: u8 = 0x94;
+const AML_IF: u8 = 0xa0;
+const AML_RETURN: u8 = 0xa4;
+// Operation region space of the I/O ports.
+const AML_SYSTEM_IO: u8 = 0x01;
+// Field flags: ByteAcc, NoLock, Preserve.
+const AML_FIELD_BYTE_ACC: u8 = 0x01;
+// _STA of a present, enabled and functioning device, shown in the UI.
+const AML_STA_PRESENT: u32 = 0x0f;
+// Device Check notification, for a device that may have appeared.
+const AML_DEVICE_CHECK: u32 = 1;
+// Extended interrupt descriptor of a consumer, edge triggered and active high, with one
+// interrupt.
+const AML_EXTENDED_IRQ: [u8; 5] = [0x89, 0x06, 0x00, 0x03, 0x01];
+const AML_END_TAG: [u8; 2] = [0x79, 0x00];
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// Too many vCPUs for the MADT.
+    #[error("too many vCPUs for the ACPI tables")]
+    TooManyCpus,
+    /// The tables do not fit in the BIOS area.
+    #[error("the ACPI tables do not fit in the BIOS area")]
+    TooLarge,
+    /// Failed to write a table to the guest memory.
+    #[error("failed to write the ACPI {0} table")]
+    Write(&'static str, #[source] GuestMemoryError),
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+// Value making the bytes of `data` sum to zero, once stored in `data`.
+fn checksum(data: &[u8]) -> u8 {
+    let sum = data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+    0u8.wrapping_sub(sum)
+}
+
+// Encode the length of an AML object with `length` bytes after it. The encoding counts
+// itself, it takes 1 to 4 bytes.
+fn aml_pkg_length(length: usize) -> Vec<u8> {
+    if length + 1 < 1 << 6 {
+        return vec![(length + 1) as u8];
+    }
+
+    // The lead byte has 4 bits of the length, each following byte 8 more.
+    let size = (2..=4)
+        .find(|size| length + size < 1 << (4 + 8 * (size - 1)))
+        .expect("AML object too large");
+    let length = length + size;
+    let mut bytes = vec![(((size - 1) << 6) | (length & 0xf)) as u8];
+    bytes.extend((1..size).map(|i| (length >> (4 + 8 * (i - 1))) as u8));
+    bytes
+}
+
+// An AML object with a length: `op`, the length, then `body`.
+fn aml_package(op: &[u8], body: &[u8]) -> Vec<u8> {
+    [op, &aml_pkg_length(body.len()), body].concat()
+}
+
+fn aml_integer(value: u32) -> Vec<u8> {
+    match value {
+        0 => vec![AML_ZERO],
+        1 => vec![AML_ONE],
+        2..=0xff => vec![AML_BYTE_PREFIX, value as u8],
+        _ => [&[AML_DWORD_PREFIX][..], &value.to_le_bytes()].concat(),
+    }
+}
+
+fn aml_name(name: &[u8; 4], value: &[u8]) -> Vec<u8> {
+    [&[AML_NAME][..], name, value].concat()
+}
+
+fn aml_string(value: &str) -> Vec<u8> {
+    [&[AML_STRING_PREFIX][..], value.as_bytes(), &[0]].concat()
+}
+
+fn aml_buffer(data: &[u8]) -> Vec<u8> {
+    aml_package(
+        &[AML_BUFFER],
+        &[&aml_integer(data.len() as u32), data].concat(),
+    )
+}
+
+// Name of the processor device of the vCPU `index`.
+fn cpu_device_name(index: u8) -> [u8; 4] {
+    let mut name = [0; 4];
+    name.copy_from_slice(format!("C{:03X}", index).as_bytes());
+    name
+}
+
+// AML for the processor devices of the vCPUs, and the Generic Event Device telling the
+// guest to check the hot-pluggable ones on `ged_irq`:
+// Scope (\_SB) {
+//     OperationRegion (PRST, SystemIO, CPU_HOTPLUG_PORT, 1)
+//     Field (PRST, ByteAcc, NoLock, Preserve) { PCNT, 8 }
+//     Device (C000) {
+//         Name (_HID, "ACPI0007")
+//         Name (_UID, 0)
+//         Name (_MAT, Buffer () { <enabled MADT local APIC entry> })
+//         Method (_STA) { If (LGreater (PCNT, 0)) { Return (0x0f) } Return (Zero) }
+//     }
+//     ...
+//     Device (GED0) {
+//         Name (_HID, "ACPI0013")
+//         Name (_CRS, ResourceTemplate () {
+//             Interrupt (ResourceConsumer, Edge, ActiveHigh, Exclusive) { ged_irq }
+//         })
+//         Method (_EVT, 1) { Notify (C<boot_cpus>, 1) ... }
+//     }
+// }
+fn cpu_hotplug_aml(topology: &CpuTopology, boot_cpus: u8, ged_irq: u32) -> Vec<u8> {
+    let mut body = b"_SB_".to_vec();
+    body.extend(
+        [
+            &AML_OPERATION_REGION[..],
+            b"PRST",
+            &[AML_SYSTEM_IO],
+            &aml_integer(CPU_HOTPLUG_PORT.into()),
+            &aml_integer(1),
+        ]
+        .concat(),
+    );
+    // A field of 8 bits.
+    body.extend(aml_package(
+        &AML_FIELD,
+        &[&b"PRST"[..], &[AML_FIELD_BYTE_ACC], b"PCNT", &[8]].concat(),
+    ));
+
+    for index in 0..topology.vcpu_count() as u8 {
+        // The guest gets the APIC ID of a plugged vCPU from there, its MADT entry stays
+        // disabled.
+        let mat = madt_local_apic(index, topology.apic_id(index) as u8, true);
+        let status = aml_package(
+            &[AML_IF],
+            &[
+                &[AML_LGREATER][..],
+                b"PCNT",
+                &aml_integer(index.into()),
+                &[AML_RETURN],
+                &aml_integer(AML_STA_PRESENT),
+            ]
+            .concat(),
+        );
+        let status = aml_package(
+            &[AML_METHOD],
+            &[&b"_STA"[..], &[0], &status, &[AML_RETURN, AML_ZERO]].concat(),
+        );
+
+        let device = [
+            &cpu_device_name(index)[..],
+            &aml_name(b"_HID", &aml_string("ACPI0007")),
+            &aml_name(b"_UID", &aml_integer(index.into())),
+            &aml_name(b"_MAT", &aml_buffer(&mat)),
+            &status,
+        ]
+        .concat();
+        body.extend(aml_package(&AML_DEVICE, &device));
+    }
+
+    let interrupt = [&AML_EXTENDED_IRQ[..], &ged_irq.to_le_bytes(), &AML_END_TAG].concat();
+    let mut event = b"_EVT".to_vec();
+    // One argument, the interrupt.
+    event.push(1);
+    for index in boot_cpus..topology.vcpu_count() as u8 {
+        event.push(AML_NOTIFY);
+        event.extend(cpu_device_name(index));
+        event.extend(aml_integer(AML_DEVICE_CHECK));
+    }
+    let ged = [
+        &b"GED0"[..],
+        &aml_name(b"_HID", &aml_string("ACPI0013")),
+        &aml_name(b"_CRS", &aml_buffer(&interrupt)),
+        &aml_package(&[AML_METHOD], &event),
+    ]
+    .concat();
+    body.extend(aml_package(&AML_DEVICE, &ged));
+
+    aml_package(&[AML_SCOPE], &body)
+}
+
+// A system description table being built: the header, then the table fields.
+struct Sdt {
+    bytes: Vec<u8>,
+}
+
+impl Sdt {
+    fn new(signature: &[u8; 4], revision: u8) -> Self {
+        let mut bytes = Vec::with_capacity(SDT_HEADER_SIZE);
+        bytes.extend_from_slice(signature);
+        // Length, filled once the table is complete.
+        bytes.extend_from_slice(&0u32.to_le_bytes());
+        bytes.push(revision);
+        // Checksum, filled once the table is complete.
+        bytes.push(0);
+        bytes.extend_from_slice(&OEM_ID);
+        bytes.extend_from_slice(&OEM_TABLE_ID);
+        bytes.extend_from_slice(&OEM_REVISION.to_le_bytes());
+        bytes.extend_from_slice(&CREATOR_ID);
+        bytes.extend_from_slice(&CREATOR_REVISION.to_le_bytes());
+
+        Sdt { bytes }
+    }
+
+    fn append(&mut self, data: &[u8]) {
+        self.bytes.extend_from_slice(data);
+    }
+
+    // Set the field at `offset`, which must already be part of the table.
+    fn set(&mut self, offset: usize, data: &[u8]) {
+        self.bytes[offset..offset + data.len()].copy_from_slice(data);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        let length = self.bytes.len() as u32;
+        self.set(SDT_LENGTH_OFFSET, &length.to_le_bytes());
+        self.bytes[SDT_CHECKSUM_OFFSET] = checksum(&self.bytes);
+        self.bytes
+    }
+}
+
+fn rsdp(xsdt: GuestAddress) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(RSDP_SIZE);
+    bytes.extend_from_slice(b"RSD PTR ");
+    // Checksum of the ACPI 1.0 fields.
+    bytes.push(0);
+    bytes.extend_from_slice(&OEM_ID);
+    bytes.push(RSDP_REVISION);
+    // No RSDT, the XSDT supersedes it.
+    bytes.extend_from_slice(&0u32.to_le_bytes());
+    bytes.extend_from_slice(&(RSDP_SIZE as u32).to_le_bytes());
+    bytes.extend_from_slice(&xsdt.raw_value().to_le_bytes());
+    // Extended checksum, and reserved bytes.
+    bytes.extend_from_slice(&[0; 4]);
+
+    bytes[8] = checksum(&bytes[..RSDP_V1_SIZE]);
+    bytes[32] = checksum(&bytes);
+    bytes
+}
+
+fn xsdt(tables: &[GuestAddress]) -> Vec<u8> {
+    let mut xsdt = Sdt::new(b"XSDT", 1);
+    for table in tables {
+        xsdt.append(&table.raw_value().to_le_bytes());
+    }
+    xsdt.finish()
+}
+
+fn fadt(facs: GuestAddress, dsdt: GuestAddress) -> Vec<u8> {
+    let mut fadt = Sdt::new(b"FACP", FADT_REVISION);
+    fadt.append(&[0; FADT_SIZE - SDT_HEADER_SIZE]);
+
+    // All the tables are below 4 GiB, the 32 bits fields are enough. There is no SMI
+    // command port, the guest then knows it is already in ACPI mode.
+    fadt.set(FADT_FIRMWARE_CTRL, &(facs.raw_value() as u32).to_le_bytes());
+    fadt.set(FADT_DSDT, &(dsdt.raw_value() as u32).to_le_bytes());
+    fadt.set(FADT_SCI_INT, &u16::from(SCI_IRQ).to_le_bytes());
+    fadt.set(FADT_PM1A_EVT_BLK, &u32::from(PM1A_EVT_BLK).to_le_bytes());
+    fadt.set(FADT_PM1A_CNT_BLK, &u32::from(PM1A_CNT_BLK).to_le_bytes());
+    fadt.set(FADT_PM1_EVT_LEN, &[PM1_EVT_LEN]);
+    fadt.set(FADT_PM1_CNT_LEN, &[PM1_CNT_LEN]);
+    fadt.set(FADT_CENTURY, &[CENTURY]);
+    fadt.set(FADT_IAPC_BOOT_ARCH, &IAPC_BOOT_ARCH.to_le_bytes());
+    fadt.set(FADT_FLAGS, &FADT_FLAGS_VALUE.to_le_bytes());
+    // The guest resets through the i8042 reset line, with reboot=a too.
+    fadt.set(FADT_RESET_REG, &GAS_SYSTEM_IO_BYTE);
+    fadt.set(
+        FADT_RESET_REG + GAS_SYSTEM_IO_BYTE.len(),
+        &u64::from(I8042_COMMAND_PORT).to_le_bytes(),
+    );
+    fadt.set(FADT_RESET_VALUE, &[I8042_RESET]);
+
+    fadt.finish()
+}
+
+fn facs() -> Vec<u8> {
+    // The FACS has no checksum nor revision, only a signature and a length.
+    let mut bytes = vec![0; FACS_SIZE];
+    bytes[..4].copy_from_slice(b"FACS");
+    bytes[4..8].copy_from_slice(&(FACS_SIZE as u32).to_le_bytes());
+    bytes
+}
+
+fn dsdt(topology: &CpuTopology, boot_cpus: u8, ged_irq: Option<u32>) -> Vec<u8> {
+    let mut dsdt = Sdt::new(b"DSDT", DSDT_REVISION);
+    dsdt.append(&DSDT_S5);
+    dsdt.append(&DSDT_PVPANIC);
+    if let Some(ged_irq) = ged_irq {
+        dsdt.append(&cpu_hotplug_aml(topology, boot_cpus, ged_irq));
+    }
+    dsdt.finish()
+}
+
+fn madt_local_apic(index: u8, apic_id: u8, enabled: bool) -> Vec<u8> {
+    let flags = if enabled { MADT_LOCAL_APIC_ENABLED } else { 0 };
+    [
+        &[MADT_LOCAL_APIC, 8, index, apic_id][..],
+        &flags.to_le_bytes(),
+    ]
+    .concat()
+}
+
+// The vCPUs past `boot_cpus` are disabled, the guest counts them as hot-pluggable.
+fn madt(topology: &CpuTopology, boot_cpus: u8) -> Result<Vec<u8>> {
+    if topology.vcpu_count() > MAX_SUPPORTED_CPUS {
+        return Err(Error::TooManyCpus);
+    }
+    let ioapic_id = u8::try_from(topology.ioapic_id()).map_err(|_| Error::TooManyCpus)?;
+
+    let mut madt = Sdt::new(b"APIC", MADT_REVISION);
+    madt.append(&(APIC_START as u32).to_le_bytes());
+    madt.append(&MADT_PCAT_COMPAT.to_le_bytes());
+
+    for index in 0..topology.vcpu_count() as u8 {
+        let apic_id = topology.apic_id(index) as u8;
+        madt.append(&madt_local_apic(index, apic_id, index < boot_cpus));
+    }
+
+    madt.append(&[MADT_IO_APIC, 12, ioapic_id, 0]);
+    madt.append(&(IOAPIC_START as u32).to_le_bytes());
+    // The IOAPIC pins are GSIs 0 to 23, ISA IRQs are identity mapped.
+    madt.append(&0u32.to_le_bytes());
+
+    madt.append(&[MADT_LOCAL_APIC_NMI, 6, MADT_ALL_PROCESSORS]);
+    madt.append(&0u16.to_le_bytes());
+    madt.append(&[MADT_NMI_LINT]);
+
+    Ok(madt.finish())
+}
+
+// Next free address, for a table of `size` bytes, aligned to `alignment`.
+fn place(next: &mut u64, size: usize, alignment: u64) -> Result<GuestAddress> {
+    let start = next.next_multiple_of(alignment);
+    let end = start + size as u64;
+    if end > ACPI_TABLES_END {
+        return Err(Error::TooLarge);
+    }
+
+    *next = end;
+    Ok(GuestAddress(start))
+}
+
+/// Write the ACPI tables for the given CPU `topology` to the guest memory.
+///
+/// The guest boots with the first `boot_cpus` vCPUs. With a `ged_irq`, it is told to
+/// look for the others when that interrupt is raised.
+pub fn setup_acpi(
+    mem: &GuestMemoryMmap,
+    topology: &CpuTopology,
+    boot_cpus: u8,
+    ged_irq: Option<u32>,
+) -> Result<()> {
+    let facs = facs();
+    let madt = madt(topology, boot_cpus)?;
+    let dsdt = dsdt(topology, boot_cpus, ged_irq);
+
+    // The XSDT length only depends on the number of tables it lists.
+    let xsdt_size = SDT_HEADER_SIZE + 2 * mem::size_of::<u64>();
+
+    let mut next = ACPI_TABLES_START;
+    let rsdp_addr = place(&mut next, RSDP_SIZE, 16)?;
+    let xsdt_addr = place(&mut next, xsdt_size, 8)?;
+    let fadt_addr = place(&mut next, FADT_SIZE, 8)?;
+    let facs_addr = place(&mut next, facs.len(), FACS_ALIGNMENT)?;
+    let dsdt_addr = place(&mut next, dsdt.len(), 8)?;
+    let madt_addr = place(&mut next, madt.len(), 8)?;
+
+    let tables = [
+        ("RSDP", rsdp_addr, rsdp(xsdt_addr)),
+        ("XSDT", xsdt_addr, xsdt(&[fadt_addr, madt_addr])),
+        ("FADT", fadt_addr, fadt(facs_addr, dsdt_addr)),
+        ("FACS", facs_addr, facs),
+        ("DSDT", dsdt_addr, dsdt),
+        ("MADT", madt_addr, madt),
+    ];
+    for (name, addr, table) in tables {
+        mem.write_slice(&table, addr)
+            .map_err(|e| Error::Write(name, e))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::devices::pvpanic::PVPANIC_PORT;
+
+    fn read_u32(mem: &GuestMemoryMmap, addr: u64) -> u32 {
+        mem.read_obj(GuestAddress(addr)).unwrap()
+    }
+
+    fn read_u64(mem: &GuestMemoryMmap, addr: u64) -> u64 {
+        mem.read_obj(GuestAddress(addr)).unwrap()
+    }
+
+    // Read a whole table, checking its signature and checksum.
+    fn read_table(mem: &GuestMemoryMmap, addr: u64, signature: &[u8; 4]) -> Vec<u8> {
+        let length = read_u32(mem, addr + SDT_LENGTH_OFFSET as u64) as usize;
+        let mut table = vec![0; length];
+        mem.read_slice(&mut table, GuestAddress(addr)).unwrap();
+
+        assert_eq!(&table[..4], signature);
+        assert_eq!(checksum(&table), 0);
+        table
+    }
+
+    fn setup(topology: &CpuTopology) -> GuestMemoryMmap {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 1 << 20)]).unwrap();
+        setup_acpi(&mem, topology, topology.vcpu_count() as u8, None).unwrap();
+        mem
+    }
+
+    #[test]
+    fn tables() {
+        let mem = setup(&CpuTopology::flat(2));
+
+        let mut rsdp = [0u8; RSDP_SIZE];
+        mem.read_slice(&mut rsdp, GuestAddress(ACPI_TABLES_START))
+            .unwrap();
+        assert_eq!(&rsdp[..8], b"RSD PTR ");
+        assert_eq!(checksum(&rsdp[..RSDP_V1_SIZE]), 0);
+        assert_eq!(checksum(&rsdp), 0);
+
+        let xsdt_addr = read_u64(&mem, ACPI_TABLES_START + 24);
+        let xsdt = read_table(&mem, xsdt_addr, b"XSDT");
+        let entries: Vec<u64> = xsdt[SDT_HEADER_SIZE..]
+            .chunks(8)
+            .map(|entry| u64::from_le_bytes(entry.try_into().unwrap()))
+            .collect();
+        assert_eq!(entries.len(), 2);
+
+        let fadt = read_table(&mem, entries[0], b"FACP");
+        let field =
+            |offset: usize| u32::from_le_bytes(fadt[offset..offset + 4].try_into().unwrap());
+        assert_eq!(field(FADT_PM1A_CNT_BLK), u32::from(PM1A_CNT_BLK));
+        assert_eq!(fadt[FADT_PM1_CNT_LEN], PM1_CNT_LEN);
+        assert_eq!(fadt[FADT_CENTURY], CENTURY);
+        assert_eq!(
+            fadt[FADT_RESET_REG + 4..FADT_RESET_REG + 12],
+            u64::from(I8042_COMMAND_PORT).to_le_bytes()
+        );
+        assert_eq!(fadt[FADT_RESET_VALUE], I8042_RESET);
+
+        let facs = u64::from(field(FADT_FIRMWARE_CTRL));
+        assert_eq!(facs % FACS_ALIGNMENT, 0);
+        assert_eq!(read_u32(&mem, facs), u32::from_le_bytes(*b"FACS"));
+
+        // The guest needs the S5 sleep type to power off.
+        let dsdt = read_table(&mem, u64::from(field(FADT_DSDT)), b"DSDT");
+        assert_eq!(
+            &dsdt[SDT_HEADER_SIZE..],
+            [&DSDT_S5[..], &DSDT_PVPANIC[..]].concat()
+        );
+
+        read_table(&mem, entries[1], b"APIC");
+    }
+
+    #[test]
+    fn pvpanic_aml() {
+        let port = PVPANIC_PORT.to_le_bytes();
+        assert_eq!(DSDT_PVPANIC[39..41], port);
+        assert_eq!(DSDT_PVPANIC[41..43], port);
+
+        // Each length covers the rest of its object, from the length itself.
+        let remaining = |offset: usize| (DSDT_PVPANIC.len() - offset) as u8;
+        assert_eq!(DSDT_PVPANIC[1], remaining(1));
+        assert_eq!(DSDT_PVPANIC[8], remaining(8));
+        assert_eq!(DSDT_PVPANIC[34], remaining(34));
+        assert_eq!(DSDT_PVPANIC[36], remaining(37));
+    }
+
+    #[test]
+    fn madt_entries() {
+        let topology = CpuTopology {
+            sockets: 2,
+            cores_per_socket: 3,
+            threads_per_core: 1,
+        };
+        let madt = madt(&topology, 4).unwrap();
+
+        // Skip the local APIC address and flags.
+        let mut entries = &madt[SDT_HEADER_SIZE + 8..];
+        let mut apic_ids = Vec::new();
+        let mut enabled = Vec::new();
+        let mut ioapic_id = None;
+        while !entries.is_empty() {
+            let (entry_type, length) = (entries[0], entries[1] as usize);
+            match entry_type {
+                MADT_LOCAL_APIC => {
+                    apic_ids.push(entries[3]);
+                    enabled.push(entries[4] == MADT_LOCAL_APIC_ENABLED as u8);
+                }
+                MADT_IO_APIC => ioapic_id = Some(entries[2]),
+                _ => {}
+            }
+            entries = &entries[length..];
+        }
+
+        // Same IDs as in the MP table.
+        assert_eq!(apic_ids, vec![0, 1, 2, 4, 5, 6]);
+        assert_eq!(enabled, vec![true, true, true, true, false, false]);
+        assert_eq!(ioapic_id, Some(8));
+    }
+
+    #[test]
+    fn pkg_length() {
+        assert_eq!(aml_pkg_length(0), [0x01]);
+        assert_eq!(aml_pkg_length(0x3e), [0x3f]);
+        // 0x3f + 2 bytes of length.
+        assert_eq!(aml_pkg_length(0x3f), [0x41, 0x04]);
+        assert_eq!(aml_pkg_length(0xffd), [0x4f, 0xff]);
+        assert_eq!(aml_pkg_length(0xffe), [0x81, 0x00, 0x01]);
+    }
+
+    // Decode the length at the start of `bytes`, and the size of its encoding.
+    fn decode_pkg_length(bytes: &[u8]) -> (usize, usize) {
+        let size = usize::from(bytes[0] >> 6) + 1;
+        if size == 1 {
+            return (usize::from(bytes[0] & 0x3f), 1);
+        }
+        let length = bytes[1..size]
+            .iter()
+            .rev()
+            .fold(0, |length, byte| (length << 8) | usize::from(*byte));
+        ((length << 4) | usize::from(bytes[0] & 0xf), size)
+    }
+
+    fn contains(bytes: &[u8], needle: &[u8]) -> bool {
+        bytes.windows(needle.len()).any(|window| window == needle)
+    }
+
+    #[test]
+    fn cpu_hotplug() {
+        let topology = CpuTopology::flat(64);
+        let aml = cpu_hotplug_aml(&topology, 62, 7);
+
+        // The scope length covers the whole AML, from the length itself.
+        assert_eq!(aml[0], AML_SCOPE);
+        let (length, size) = decode_pkg_length(&aml[1..]);
+        assert_eq!(length, aml.len() - 1);
+        let mut offset = 1 + size;
+        assert_eq!(&aml[offset..offset + 4], b"_SB_");
+
+        // Skip the register, then walk the devices.
+        offset += 4;
+        assert_eq!(aml[offset..offset + 2], AML_OPERATION_REGION);
+        offset += 2 + 4 + 1 + 5 + 1;
+        assert_eq!(aml[offset..offset + 2], AML_FIELD);
+        offset += 2 + decode_pkg_length(&aml[offset + 2..]).0;
+        let mut devices = Vec::new();
+        while offset < aml.len() {
+            assert_eq!(aml[offset..offset + 2], AML_DEVICE);
+            let (length, size) = decode_pkg_length(&aml[offset + 2..]);
+            let name = &aml[offset + 2 + size..offset + 2 + size + 4];
+            devices.push(String::from_utf8(name.to_vec()).unwrap());
+            offset += 2 + length;
+        }
+        assert_eq!(offset, aml.len());
+        assert_eq!(devices.len(), 65);
+        assert_eq!((&devices[0][..], &devices[63][..]), ("C000", "C03F"));
+        assert_eq!(devices[64], "GED0");
+
+        // The processor devices have their enabled MADT entries.
+        for index in [0u8, 1, 63] {
+            assert!(contains(
+                &aml,
+                &[0, 8, index, index, MADT_LOCAL_APIC_ENABLED as u8, 0, 0, 0]
+            ));
+        }
+
+        // Only the hot-pluggable vCPUs are checked on the GED interrupt.
+        let notify = |index: u8| [&[AML_NOTIFY][..], &cpu_device_name(index), &[AML_ONE]].concat();
+        assert!(!contains(&aml, &notify(61)));
+        assert!(contains(&aml, &notify(62)));
+        assert!(contains(&aml, &notify(63)));
+        assert!(contains(
+            &aml,
+            &[&AML_EXTENDED_IRQ[..], &7u32.to_le_bytes(), &AML_END_TAG].concat()
+        ));
+    }
+
+    #[test]
+    fn too_many_cpus() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 1 << 20)]).unwrap();
+        let topology = CpuTopology {
+            sockets: 2,
+            cores_per_socket: 128,
+            threads_per_core: 1,
+        };
+
+        assert!(matches!(
+            setup_acpi(&mem, &topology, 1, None),
+            Err(Error::TooManyCpus)
+        ));
+    }
+}
@@ -0,0 +1,652 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Channel with the guest agent on the second serial port, and the protocol running
+//! commands through it.
+//!
+//! Each SLIP frame carries a [`Message`]: the host sends [`Message::Exec`], the agent
+//! answers with the output of the command and its exit code, and sends heartbeats all
+//! along.
+
+use std::io::{self, Write};
+use std::sync::mpsc::{Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::devices::serial::{LumperSerial, INPUT_BACKLOG_HIGH};
+use crate::events::{Event, EventSink};
+use crate::slip;
+
+/// How long [`Agent`] waits for a frame of a running command, heartbeats included, by
+/// default.
+pub const DEFAULT_HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How often a frame waiting for room in the serial input backlog is sent again.
+const SEND_RETRY_DELAY: Duration = Duration::from_millis(10);
+
+// Message tags, the first byte of each frame.
+const TAG_EXEC: u8 = 1;
+const TAG_STDOUT: u8 = 2;
+const TAG_STDERR: u8 = 3;
+const TAG_EXIT: u8 = 4;
+const TAG_HEARTBEAT: u8 = 5;
+
+/// Agent channel errors.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// The serial input backlog cannot hold the encoded frame until the guest reads more.
+    #[error("the serial input backlog cannot hold a {0} bytes frame right now")]
+    FifoFull(usize),
+    /// Failed to push the frame into the serial device.
+    #[error("failed to write to the agent serial port: {0:?}")]
+    Serial(vm_superio::serial::Error<io::Error>),
+    /// The encoded frame is larger than the serial input backlog.
+    #[error("a {0} bytes frame does not fit in the serial input backlog")]
+    FrameTooLarge(usize),
+    /// A frame does not hold a valid message.
+    #[error("invalid agent message: {0}")]
+    InvalidMessage(&'static str),
+    /// The agent did not send its first heartbeat in time.
+    #[error("the agent is not ready")]
+    NotReady,
+    /// The agent did not report the exit of the previous command yet.
+    #[error("the agent is still running a command")]
+    Busy,
+    /// The agent sent nothing, not even a heartbeat, for that long.
+    #[error("the agent sent nothing for {0:?}")]
+    Timeout(Duration),
+}
+
+/// Dedicated [`Result`](https://doc.rust-lang.org/std/result/) type.
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Output sink of the agent serial port: decodes the SLIP frames written by the
+/// guest and hands them over to the [`AgentChannel`].
+pub(crate) struct AgentWriter {
+    decoder: slip::Decoder,
+    frames: Sender<Vec<u8>>,
+    // Gets Event::AgentReady on the first heartbeat.
+    events: Option<EventSink>,
+}
+
+impl AgentWriter {
+    pub fn new(frames: Sender<Vec<u8>>, events: Option<EventSink>) -> Self {
+        AgentWriter {
+            decoder: slip::Decoder::default(),
+            frames,
+            events,
+        }
+    }
+}
+
+impl Write for AgentWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for frame in self.decoder.decode(buf) {
+            if self.events.is_some() && matches!(Message::decode(&frame), Ok(Message::Heartbeat(_)))
+            {
+                if let Some(events) = self.events.take() {
+                    events.emit(Event::AgentReady);
+                }
+            }
+            // Nobody listening is not an error for the guest, the frame is just lost.
+            let _ = self.frames.send(frame);
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Frame based channel with the guest agent, over the second serial port (ttyS1).
+pub struct AgentChannel {
+    serial: Arc<Mutex<LumperSerial>>,
+    frames: Receiver<Vec<u8>>,
+}
+
+impl AgentChannel {
+    pub(crate) fn new(serial: Arc<Mutex<LumperSerial>>, frames: Receiver<Vec<u8>>) -> Self {
+        AgentChannel { serial, frames }
+    }
+
+    /// Send `frame` to the guest.
+    ///
+    /// The frame waits in the serial input backlog until the guest reads it, this fails
+    /// with [`Error::FifoFull`] when the backlog has no room left for it.
+    pub fn send(&self, frame: &[u8]) -> Result<()> {
+        let packet = slip::encode(frame);
+        if packet.len() > INPUT_BACKLOG_HIGH {
+            return Err(Error::FrameTooLarge(packet.len()));
+        }
+        let mut serial = self.serial.lock().unwrap();
+
+        // Never queue a partial frame.
+        if serial.pending_input() + packet.len() > INPUT_BACKLOG_HIGH {
+            return Err(Error::FifoFull(packet.len()));
+        }
+
+        serial.enqueue_input(&packet).map_err(Error::Serial)?;
+
+        Ok(())
+    }
+
+    /// Get the next frame sent by the guest, if any.
+    pub fn recv(&self) -> Option<Vec<u8>> {
+        self.frames.try_recv().ok()
+    }
+
+    /// Wait up to `timeout` for the next frame sent by the guest.
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<Vec<u8>> {
+        self.frames.recv_timeout(timeout).ok()
+    }
+}
+
+/// Command for the agent to run.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ExecRequest {
+    /// Command line, run by the guest shell.
+    pub cmd: String,
+    /// Environment variables set for the command, on top of the agent ones.
+    pub env: Vec<(String, String)>,
+    /// Standard input of the command, closed once read.
+    pub stdin: Vec<u8>,
+}
+
+/// Messages of the agent protocol, one per frame.
+///
+/// A message is a tag byte followed by its fields. Integers are little-endian, and the
+/// strings and byte strings are prefixed with their length, as a `u32`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Message {
+    /// Run a command, from the host: the command line, the number of environment
+    /// variables followed by their names and values, and the standard input.
+    Exec(ExecRequest),
+    /// Standard output of the command, from the agent.
+    Stdout(Vec<u8>),
+    /// Standard error of the command, from the agent.
+    Stderr(Vec<u8>),
+    /// The command exited with this code, from the agent. That is 128 plus the signal
+    /// number if a signal killed it.
+    Exit(i32),
+    /// The agent is alive, from the agent. The sequence number increases with each
+    /// heartbeat, wrapping around.
+    Heartbeat(u32),
+}
+
+impl Message {
+    /// Encode the message as a frame.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut frame = Vec::new();
+        match self {
+            Message::Exec(request) => {
+                frame.push(TAG_EXEC);
+                put_bytes(&mut frame, request.cmd.as_bytes());
+                frame.extend_from_slice(&(request.env.len() as u32).to_le_bytes());
+                for (name, value) in request.env.iter() {
+                    put_bytes(&mut frame, name.as_bytes());
+                    put_bytes(&mut frame, value.as_bytes());
+                }
+                put_bytes(&mut frame, &request.stdin);
+            }
+            Message::Stdout(data) => {
+                frame.push(TAG_STDOUT);
+                put_bytes(&mut frame, data);
+            }
+            Message::Stderr(data) => {
+                frame.push(TAG_STDERR);
+                put_bytes(&mut frame, data);
+            }
+            Message::Exit(code) => {
+                frame.push(TAG_EXIT);
+                frame.extend_from_slice(&code.to_le_bytes());
+            }
+            Message::Heartbeat(sequence) => {
+                frame.push(TAG_HEARTBEAT);
+                frame.extend_from_slice(&sequence.to_le_bytes());
+            }
+        }
+
+        frame
+    }
+
+    /// Decode the message in `frame`, which must hold nothing else.
+    pub fn decode(frame: &[u8]) -> Result<Self> {
+        let (tag, mut fields) = match frame.split_first() {
+            Some((tag, fields)) => (*tag, Fields(fields)),
+            None => return Err(Error::InvalidMessage("empty frame")),
+        };
+
+        let message = match tag {
+            TAG_EXEC => {
+                let cmd = fields.string()?;
+                let mut env = Vec::new();
+                for _ in 0..fields.u32()? {
+                    env.push((fields.string()?, fields.string()?));
+                }
+                let stdin = fields.bytes()?;
+                Message::Exec(ExecRequest { cmd, env, stdin })
+            }
+            TAG_STDOUT => Message::Stdout(fields.bytes()?),
+            TAG_STDERR => Message::Stderr(fields.bytes()?),
+            TAG_EXIT => Message::Exit(fields.u32()? as i32),
+            TAG_HEARTBEAT => Message::Heartbeat(fields.u32()?),
+            _ => return Err(Error::InvalidMessage("unknown tag")),
+        };
+        if !fields.0.is_empty() {
+            return Err(Error::InvalidMessage("trailing bytes"));
+        }
+
+        Ok(message)
+    }
+}
+
+// Append `bytes` to `frame`, after their length.
+fn put_bytes(frame: &mut Vec<u8>, bytes: &[u8]) {
+    frame.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    frame.extend_from_slice(bytes);
+}
+
+// The fields of a message left to decode.
+struct Fields<'a>(&'a [u8]);
+
+impl<'a> Fields<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.0.len() < len {
+            return Err(Error::InvalidMessage("truncated frame"));
+        }
+        let (field, rest) = self.0.split_at(len);
+        self.0 = rest;
+
+        Ok(field)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        // The slice is 4 bytes long.
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>> {
+        let len = self.u32()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    fn string(&mut self) -> Result<String> {
+        String::from_utf8(self.bytes()?).map_err(|_| Error::InvalidMessage("invalid UTF-8"))
+    }
+}
+
+/// Output and outcome of a command run by the agent.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ExecEvent {
+    /// Part of its standard output.
+    Stdout(Vec<u8>),
+    /// Part of its standard error.
+    Stderr(Vec<u8>),
+    /// Its exit code, the last event.
+    Exit(i32),
+}
+
+// Where the agent stands, from the frames it sent.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    // No heartbeat yet, the agent may not run at all.
+    Starting,
+    // Waiting for a command.
+    Ready,
+    // Running a command, until it exits.
+    Running,
+}
+
+// Protocol state machine. The output of a command is only expected while it runs, the
+// other frames the guest sends are dropped.
+struct Session {
+    state: State,
+    // Sequence number of the latest heartbeat.
+    heartbeat: Option<u32>,
+    dropped: u64,
+}
+
+impl Session {
+    fn new() -> Self {
+        Session {
+            state: State::Starting,
+            heartbeat: None,
+            dropped: 0,
+        }
+    }
+
+    // Handle a frame from the guest, returning the event of the running command it holds,
+    // if any.
+    fn handle(&mut self, frame: &[u8]) -> Option<ExecEvent> {
+        let message = match Message::decode(frame) {
+            Ok(message) => message,
+            Err(e) => {
+                log::warn!("Dropping an agent frame: {}", e);
+                self.dropped += 1;
+                return None;
+            }
+        };
+
+        match (self.state, message) {
+            (_, Message::Heartbeat(sequence)) => {
+                // Heartbeats may cross the other messages, only a newer one counts.
+                match self.heartbeat {
+                    Some(latest) if sequence.wrapping_sub(latest) as i32 <= 0 => {
+                        log::debug!("Ignoring the stale agent heartbeat {}", sequence)
+                    }
+                    _ => {
+                        self.heartbeat = Some(sequence);
+                        if self.state == State::Starting {
+                            self.state = State::Ready;
+                        }
+                    }
+                }
+                None
+            }
+            (State::Running, Message::Stdout(data)) => Some(ExecEvent::Stdout(data)),
+            (State::Running, Message::Stderr(data)) => Some(ExecEvent::Stderr(data)),
+            (State::Running, Message::Exit(code)) => {
+                self.state = State::Ready;
+                Some(ExecEvent::Exit(code))
+            }
+            (state, message) => {
+                log::warn!(
+                    "Dropping the agent message {:?} in state {:?}",
+                    message,
+                    state
+                );
+                self.dropped += 1;
+                None
+            }
+        }
+    }
+}
+
+/// Runs commands through the guest agent. See [`VMM::agent`](crate::VMM::agent).
+///
+/// The agent tells it is ready with its first heartbeat, and keeps sending them while
+/// it runs a command: it must send some frame at least every heartbeat timeout.
+pub struct Agent {
+    channel: AgentChannel,
+    session: Session,
+    heartbeat_timeout: Duration,
+}
+
+impl Agent {
+    pub fn new(channel: AgentChannel) -> Self {
+        Agent {
+            channel,
+            session: Session::new(),
+            heartbeat_timeout: DEFAULT_HEARTBEAT_TIMEOUT,
+        }
+    }
+
+    /// How long to wait for a frame of a running command before giving up on the agent,
+    /// [`DEFAULT_HEARTBEAT_TIMEOUT`] by default.
+    pub fn set_heartbeat_timeout(&mut self, timeout: Duration) {
+        self.heartbeat_timeout = timeout;
+    }
+
+    /// Number of invalid or unexpected frames dropped so far.
+    pub fn dropped(&self) -> u64 {
+        self.session.dropped
+    }
+
+    /// Wait up to `timeout` for the agent first heartbeat.
+    pub fn wait_ready(&mut self, timeout: Duration) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        while self.session.state == State::Starting {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match self.channel.recv_timeout(remaining) {
+                Some(frame) => {
+                    self.session.handle(&frame);
+                }
+                None => return Err(Error::NotReady),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Run a command, and get its output and exit code as they come.
+    ///
+    /// The events end with [`ExecEvent::Exit`], or with an error if the agent goes
+    /// silent. A command whose events were not all read must still exit before the
+    /// next one runs.
+    pub fn exec(&mut self, request: &ExecRequest) -> Result<Exec<'_>> {
+        // The previous command may have exited since.
+        while let Some(frame) = self.channel.recv() {
+            self.session.handle(&frame);
+        }
+        match self.session.state {
+            State::Starting => return Err(Error::NotReady),
+            State::Running => return Err(Error::Busy),
+            State::Ready => (),
+        }
+
+        // The previous command input may still wait for the guest.
+        let frame = Message::Exec(request.clone()).encode();
+        let deadline = Instant::now() + self.heartbeat_timeout;
+        loop {
+            match self.channel.send(&frame) {
+                Err(Error::FifoFull(_)) if Instant::now() < deadline => {
+                    thread::sleep(SEND_RETRY_DELAY)
+                }
+                result => break result?,
+            }
+        }
+        self.session.state = State::Running;
+
+        Ok(Exec {
+            agent: self,
+            done: false,
+        })
+    }
+}
+
+/// Events of a command run by [`Agent::exec`].
+pub struct Exec<'a> {
+    agent: &'a mut Agent,
+    done: bool,
+}
+
+impl Iterator for Exec<'_> {
+    type Item = Result<ExecEvent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let timeout = self.agent.heartbeat_timeout;
+        loop {
+            let frame = match self.agent.channel.recv_timeout(timeout) {
+                Some(frame) => frame,
+                None => {
+                    self.done = true;
+                    return Some(Err(Error::Timeout(timeout)));
+                }
+            };
+            if let Some(event) = self.agent.session.handle(&frame) {
+                self.done = matches!(event, ExecEvent::Exit(_));
+                return Some(Ok(event));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::mpsc;
+
+    // Serial registers the guest reads its input from.
+    const DATA_OFFSET: u8 = 0;
+    const LSR_OFFSET: u8 = 5;
+    const LSR_DATA_READY: u8 = 0x01;
+
+    // Guest side of the protocol: reads the frames from the serial port, as the guest
+    // driver would, and answers through the serial output.
+    struct FakeAgent {
+        serial: Arc<Mutex<LumperSerial>>,
+        decoder: slip::Decoder,
+        output: AgentWriter,
+    }
+
+    impl FakeAgent {
+        // The agent and the host channel, on the same serial port.
+        fn new() -> (Self, AgentChannel) {
+            let (sender, receiver) = mpsc::channel();
+            let serial = Arc::new(Mutex::new(LumperSerial::new(Box::new(io::sink())).unwrap()));
+            let agent = FakeAgent {
+                serial: serial.clone(),
+                decoder: slip::Decoder::default(),
+                output: AgentWriter::new(sender, None),
+            };
+
+            (agent, AgentChannel::new(serial, receiver))
+        }
+
+        fn recv(&mut self) -> Message {
+            loop {
+                let mut serial = self.serial.lock().unwrap();
+                while serial.read(LSR_OFFSET) & LSR_DATA_READY != 0 {
+                    let byte = serial.read(DATA_OFFSET);
+                    if let Some(frame) = self.decoder.decode_byte(byte) {
+                        return Message::decode(&frame).unwrap();
+                    }
+                }
+                drop(serial);
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+
+        // Write the frame a few bytes at a time, as the guest driver may.
+        fn send_raw(&mut self, frame: &[u8]) {
+            for chunk in slip::encode(frame).chunks(3) {
+                self.output.write_all(chunk).unwrap();
+            }
+        }
+
+        fn send(&mut self, message: Message) {
+            self.send_raw(&message.encode());
+        }
+    }
+
+    #[test]
+    fn message_encoding() {
+        let messages = [
+            Message::Exec(ExecRequest {
+                cmd: "python3 main.py".to_string(),
+                env: vec![("LANG".to_string(), "C.UTF-8".to_string())],
+                stdin: vec![slip::END, slip::ESC, 0],
+            }),
+            Message::Stdout(b"hello\n".to_vec()),
+            Message::Stderr(Vec::new()),
+            Message::Exit(-1),
+            Message::Heartbeat(u32::MAX),
+        ];
+        for message in messages.iter() {
+            assert_eq!(&Message::decode(&message.encode()).unwrap(), message);
+        }
+
+        let frame = Message::Stdout(b"hello".to_vec()).encode();
+        assert_eq!(
+            frame,
+            [TAG_STDOUT, 5, 0, 0, 0, b'h', b'e', b'l', b'l', b'o']
+        );
+        let trailing = [frame.as_slice(), &[0]].concat();
+        let invalid: [&[u8]; 3] = [&frame[..frame.len() - 1], &trailing, &[]];
+        for invalid in invalid {
+            assert!(matches!(
+                Message::decode(invalid),
+                Err(Error::InvalidMessage(_))
+            ));
+        }
+        assert!(matches!(
+            Message::decode(&[42]),
+            Err(Error::InvalidMessage("unknown tag"))
+        ));
+    }
+
+    #[test]
+    fn exec() {
+        let (mut guest, channel) = FakeAgent::new();
+        let mut agent = Agent::new(channel);
+        assert!(matches!(
+            agent.exec(&ExecRequest::default()),
+            Err(Error::NotReady)
+        ));
+
+        let request = ExecRequest {
+            cmd: "cat; echo oops >&2; exit 3".to_string(),
+            env: Vec::new(),
+            // Larger than the receive FIFO.
+            stdin: vec![b'x'; 1000],
+        };
+        let expected = request.clone();
+        let guest = thread::spawn(move || {
+            guest.send(Message::Heartbeat(1));
+            assert_eq!(guest.recv(), Message::Exec(expected));
+
+            guest.send(Message::Stdout(vec![b'x'; 1000]));
+            guest.send(Message::Heartbeat(3));
+            // Overtaken by the previous one.
+            guest.send(Message::Heartbeat(2));
+            guest.send_raw(&[TAG_STDERR, 42]);
+            guest.send(Message::Stderr(b"oops\n".to_vec()));
+            guest.send(Message::Exit(3));
+            // Late output of a command that exited, and commands only go to the guest.
+            guest.send(Message::Stdout(b"late".to_vec()));
+            guest.send(Message::Exec(ExecRequest::default()));
+            guest.send(Message::Heartbeat(4));
+        });
+
+        agent.wait_ready(Duration::from_secs(5)).unwrap();
+        let events: Vec<_> = agent.exec(&request).unwrap().map(Result::unwrap).collect();
+        assert_eq!(
+            events,
+            [
+                ExecEvent::Stdout(vec![b'x'; 1000]),
+                ExecEvent::Stderr(b"oops\n".to_vec()),
+                ExecEvent::Exit(3),
+            ]
+        );
+        guest.join().unwrap();
+
+        // The truncated frame is dropped, then the frames following the exit.
+        assert_eq!(agent.dropped(), 1);
+        while agent.session.heartbeat != Some(4) {
+            let frame = agent.channel.recv_timeout(Duration::from_secs(5)).unwrap();
+            agent.session.handle(&frame);
+        }
+        assert_eq!(agent.dropped(), 3);
+        assert_eq!(agent.session.state, State::Ready);
+    }
+
+    #[test]
+    fn silent_agent() {
+        let (mut guest, channel) = FakeAgent::new();
+        let mut agent = Agent::new(channel);
+        agent.set_heartbeat_timeout(Duration::from_millis(100));
+        assert!(matches!(
+            agent.wait_ready(Duration::from_millis(100)),
+            Err(Error::NotReady)
+        ));
+
+        guest.send(Message::Heartbeat(0));
+        agent.wait_ready(Duration::from_secs(5)).unwrap();
+        let mut exec = agent.exec(&ExecRequest::default()).unwrap();
+        assert!(matches!(exec.next(), Some(Err(Error::Timeout(_)))));
+        assert!(exec.next().is_none());
+
+        // The command may still be running.
+        assert!(matches!(
+            agent.exec(&ExecRequest::default()),
+            Err(Error::Busy)
+        ));
+    }
+}
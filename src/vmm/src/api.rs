@@ -0,0 +1,581 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Control socket of a running VMM.
+//!
+//! Clients connect to a Unix socket, send one JSON request on a line, and read one JSON
+//! response back before the VMM closes the connection, e.g.:
+//!
+//! ```text
+//! $ echo '{"action":"dirty-stats"}' | socat - UNIX-CONNECT:/run/lumper.sock
+//! {"dirty_pages":1234,"page_size":4096}
+//! ```
+//!
+//! `stats` returns the device and vCPU counters, counted since the VM started. New
+//! counters may be added, the existing ones keep their name and meaning:
+//!
+//! ```text
+//! $ echo '{"action":"stats"}' | socat - UNIX-CONNECT:/run/lumper.sock
+//! {"net":{"rx_bytes":3072,"rx_packets":3,"rx_dropped_no_buffer":0,"rx_dropped_oversize":0,
+//! "rx_dropped_fault":0,"rx_dropped_malformed":0,"rx_budget_exhausted":0,"tx_bytes":2048,
+//! "tx_packets":2,"tx_errors":0,"tx_dropped_offload":0,"tx_dropped_fault":0,
+//! "tx_dropped_malformed":0},
+//! "serial":{"rx_bytes":12,"tx_bytes":4096,"tx_errors":0},"serial2":null,
+//! "vcpus":[{"exits":52311,"exit_kinds":{"io_in":1204,"io_out":48771,"mmio_read":310,
+//! "mmio_write":2026,"hlt":0,"other":0},"last_exit":"io_out","handling_ns":61220351,
+//! "stray_mmio":0}]}
+//! ```
+//!
+//! `net` and `serial2` are null without the device. See [`Stats`] for the counters.
+//!
+//! `add-net` plugs a virtio-net device on a tap into one of the slots reserved at boot,
+//! with an optional MAC address. It returns the guest platform device of the slot, which
+//! the guest binds the virtio-mmio driver to:
+//!
+//! ```text
+//! $ echo '{"action":"add-net","tap":"tap3","mac":"52:54:00:12:34:56"}' \
+//!     | socat - UNIX-CONNECT:/run/lumper.sock
+//! {"device":"virtio-mmio.1"}
+//! (guest) # echo virtio-mmio.1 > /sys/bus/platform/drivers/virtio-mmio/bind
+//! ```
+//!
+//! `remove-net` unplugs it, once the guest unbound the driver:
+//!
+//! ```text
+//! (guest) # echo virtio-mmio.1 > /sys/bus/platform/drivers/virtio-mmio/unbind
+//! $ echo '{"action":"remove-net","tap":"tap3"}' | socat - UNIX-CONNECT:/run/lumper.sock
+//! {"device":"virtio-mmio.1"}
+//! ```
+//!
+//! The hot-plugged devices use the userspace datapath, without rate limits nor DHCP
+//! server, and their counters are not part of `stats`.
+//!
+//! `irq-latency` returns the interrupt latency histograms of the virtio devices, for all of
+//! them then for each. They stay empty unless the VMM runs with `--trace-irq`, see
+//! [`IrqLatencyReport`]. `latency` goes from the first irqfd
+//! signal to the driver acknowledging it, `wakeup` from the net worker waking up to it
+//! processing the interface. The buckets only list the non-empty ones, by their largest
+//! value:
+//!
+//! ```text
+//! $ echo '{"action":"irq-latency"}' | socat - UNIX-CONNECT:/run/lumper.sock
+//! {"enabled":true,"devices":[{"device":"all","latency":{"count":2,"mean_ns":18432,
+//! "max_ns":20480,"p50_ns":16383,"p90_ns":20479,"p99_ns":20479,"buckets":[{"max_ns":16383,
+//! "count":1},{"max_ns":20479,"count":1}]},"wakeup":{...}},{"device":"net",...}]}
+//! ```
+//!
+//! The percentiles are the bounds of their buckets, within 12.5% of the actual values.
+//!
+//! `add-vcpu` plugs `count` vCPUs into the guest, when it runs with fewer than its maximum,
+//! and returns its vCPU count. The guest brings them up once onlined, if it does not on
+//! its own:
+//!
+//! ```text
+//! $ echo '{"action":"add-vcpu","count":1}' | socat - UNIX-CONNECT:/run/lumper.sock
+//! {"cpus":2}
+//! (guest) # echo 1 > /sys/devices/system/cpu/cpu1/online
+//! ```
+//!
+//! The plugged vCPUs are not part of `stats`, and cannot be unplugged.
+//!
+//! `get-memory-fd` sends a read-only descriptor of the guest RAM along with the response,
+//! when it is shared with `--memory-share memfd`. The response tells where each RAM region
+//! is in it. The guest keeps running while the client reads its memory, see
+//! [`VMM::read_guest`](crate::VMM::read_guest) for what it may see:
+//!
+//! ```text
+//! {"action":"get-memory-fd"}
+//! {"regions":[{"guest_addr":0,"size":3221225472,"offset":0},
+//! {"guest_addr":4294967296,"size":1073741824,"offset":3221225472}]}
+//! ```
+//!
+//! `set-log-level` changes the level of the VMM logs while the VM runs, to `off`, `error`,
+//! `warn`, `info`, `debug` or `trace`, and turns the vCPU exit trace on or off with
+//! `trace_exits`. Each is optional. It returns the new state, as `get-log-level` does. The
+//! exits are logged at the debug level:
+//!
+//! ```text
+//! $ echo '{"action":"set-log-level","level":"debug","trace_exits":true}' \
+//!     | socat - UNIX-CONNECT:/run/lumper.sock
+//! {"level":"debug","trace_exits":true}
+//! ```
+//!
+//! SIGUSR1 also raises the level, one step at a time, from `warn` up to `trace` and back.
+//!
+//! `pause` stops the VM until `resume`: the vCPUs wait out of guest mode, the virtio-net
+//! devices leave their interface alone, the frames for the guest waiting in the host
+//! queue, and the serial output so far is written. The guest time goes on. Both return
+//! the state of the VM, as `info` does, and do nothing when it is in it already:
+//!
+//! ```text
+//! $ echo '{"action":"pause"}' | socat - UNIX-CONNECT:/run/lumper.sock
+//! {"state":"paused"}
+//! $ echo '{"action":"info"}' | socat - UNIX-CONNECT:/run/lumper.sock
+//! {"state":"paused"}
+//! ```
+//!
+//! No vCPU can be plugged while the VM is paused.
+
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use vmm_sys_util::sock_ctrl_msg::ScmSocket;
+
+// Longest request line.
+const MAX_REQUEST_LEN: u64 = 4096;
+// How long a client may take to send its request. The VMM event loop waits meanwhile.
+const REQUEST_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Requests, tagged by their `action`.
+#[derive(Debug, Deserialize, PartialEq, Eq)]
+#[serde(tag = "action", rename_all = "kebab-case")]
+pub enum ApiRequest {
+    /// Guest pages written since the previous `dirty-stats`, or since the VM started.
+    DirtyStats,
+    /// Device and vCPU counters.
+    Stats,
+    /// Plug a virtio-net device on `tap` into a free network slot.
+    AddNet { tap: String, mac: Option<String> },
+    /// Unplug the virtio-net device on `tap`, which the guest no longer uses.
+    RemoveNet { tap: String },
+    /// Interrupt latency histograms of the virtio devices.
+    IrqLatency,
+    /// Plug `count` vCPUs into the guest.
+    AddVcpu { count: u8 },
+    /// A read-only descriptor of the shared guest RAM.
+    GetMemoryFd,
+    /// Change the log level, and turn the vCPU exit trace on or off.
+    SetLogLevel {
+        level: Option<String>,
+        trace_exits: Option<bool>,
+    },
+    /// The log level, and whether the vCPU exits are traced.
+    GetLogLevel,
+    /// Stop the vCPUs and the devices, until resumed.
+    Pause,
+    /// Run the paused VM again.
+    Resume,
+    /// The state of the VM, `running` or `paused`.
+    Info,
+}
+
+/// Responses, one per request.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum ApiResponse {
+    DirtyStats { dirty_pages: u64, page_size: u64 },
+    Stats(Stats),
+    NetDevice { device: String },
+    IrqLatency(IrqLatencyReport),
+    VcpuCount { cpus: u8 },
+    MemoryFd(MemoryFd),
+    LogLevel { level: String, trace_exits: bool },
+    Info { state: String },
+    Error { error: String },
+}
+
+/// The `get-memory-fd` response.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct MemoryFd {
+    /// The RAM regions in the descriptor.
+    pub regions: Vec<MemoryRegion>,
+    /// The descriptor, sent along with the response.
+    #[serde(skip)]
+    pub fd: RawFd,
+}
+
+/// A guest RAM region, in the descriptor of the `get-memory-fd` response.
+#[derive(Clone, Debug, Serialize, PartialEq, Eq)]
+pub struct MemoryRegion {
+    /// Guest physical address of the region.
+    pub guest_addr: u64,
+    pub size: u64,
+    /// Offset of the region in the descriptor.
+    pub offset: u64,
+}
+
+/// Counters of the `stats` response.
+#[derive(Debug, Default, Serialize, PartialEq, Eq)]
+pub struct Stats {
+    /// The virtio-net device, if any.
+    pub net: Option<NetCounters>,
+    /// The console serial port.
+    pub serial: SerialCounters,
+    /// The second serial port, if any.
+    pub serial2: Option<SerialCounters>,
+    /// Each vCPU, by index.
+    pub vcpus: Vec<VcpuCounters>,
+}
+
+/// virtio-net counters. The receive ones are about the frames going to the guest, and the
+/// transmit ones about the frames it sends. The bytes are those of the Ethernet frames.
+#[derive(Debug, Default, Serialize, PartialEq, Eq)]
+pub struct NetCounters {
+    pub rx_bytes: u64,
+    pub rx_packets: u64,
+    /// Frames dropped as the guest had no receive buffer, or had not set the device up yet.
+    pub rx_dropped_no_buffer: u64,
+    /// Frames larger than the receive buffer of the guest.
+    pub rx_dropped_oversize: u64,
+    /// Frames dropped as a receive buffer of the guest was outside its memory.
+    pub rx_dropped_fault: u64,
+    /// Frames dropped as a receive chain of the guest was too long, or looped.
+    pub rx_dropped_malformed: u64,
+    /// Times the device stopped receiving after its budget of frames, to let the other
+    /// events through.
+    pub rx_budget_exhausted: u64,
+    pub tx_bytes: u64,
+    pub tx_packets: u64,
+    /// Frames the device could not send.
+    pub tx_errors: u64,
+    /// Frames needing an offload the interface refused, which the device does not do in
+    /// software, e.g. segmentation.
+    pub tx_dropped_offload: u64,
+    /// Frames dropped as one of their buffers was outside the guest memory.
+    pub tx_dropped_fault: u64,
+    /// Frames dropped as their chain was too long, or looped.
+    pub tx_dropped_malformed: u64,
+}
+
+/// Serial port counters.
+#[derive(Debug, Default, Serialize, PartialEq, Eq)]
+pub struct SerialCounters {
+    /// Input bytes given to the guest.
+    pub rx_bytes: u64,
+    /// Bytes the guest wrote.
+    pub tx_bytes: u64,
+    /// Writes to the output sink that failed, e.g. on a full disk. Their output is lost.
+    pub tx_errors: u64,
+}
+
+/// vCPU counters.
+#[derive(Debug, Default, Serialize, PartialEq, Eq)]
+pub struct VcpuCounters {
+    /// Times the vCPU left guest mode for the VMM.
+    pub exits: u64,
+    /// The exits, by kind.
+    pub exit_kinds: VcpuExits,
+    /// The kind of the last exit, as in [`VcpuExits`], or null before the first one.
+    pub last_exit: Option<&'static str>,
+    /// Nanoseconds the VMM spent handling the exits, out of guest mode. Always 0 when
+    /// built without the `exit-timing` feature, which takes two timestamps per exit.
+    pub handling_ns: u64,
+    /// MMIO accesses to addresses no device claims: the reads got all ones, the writes
+    /// were ignored.
+    pub stray_mmio: u64,
+}
+
+/// vCPU exits by kind, the ones the VMM handles on its own, or `other`.
+#[derive(Debug, Default, Serialize, PartialEq, Eq)]
+pub struct VcpuExits {
+    /// Port reads.
+    pub io_in: u64,
+    /// Port writes.
+    pub io_out: u64,
+    pub mmio_read: u64,
+    pub mmio_write: u64,
+    pub hlt: u64,
+    pub other: u64,
+}
+
+/// The `irq-latency` response.
+#[derive(Debug, Default, Serialize, PartialEq, Eq)]
+pub struct IrqLatencyReport {
+    /// Whether the VMM runs with `--trace-irq`. Without it, the histograms stay empty.
+    pub enabled: bool,
+    /// All the devices, then each of them.
+    pub devices: Vec<IrqLatency>,
+}
+
+/// Interrupt latency histograms of a device, or of all of them.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct IrqLatency {
+    /// `net`, `pmem` or `shared-dir`, `all` for the sum of the devices.
+    pub device: String,
+    /// From the irqfd signal to the InterruptACK write.
+    pub latency: HistogramSnapshot,
+    /// From the worker wakeup to the start of the event processing, only for `net`.
+    pub wakeup: HistogramSnapshot,
+}
+
+/// Durations in nanoseconds. The percentiles are the largest value of their bucket.
+#[derive(Debug, Default, Serialize, PartialEq, Eq)]
+pub struct HistogramSnapshot {
+    pub count: u64,
+    pub mean_ns: u64,
+    pub max_ns: u64,
+    pub p50_ns: u64,
+    pub p90_ns: u64,
+    pub p99_ns: u64,
+    /// The non-empty buckets, in order.
+    pub buckets: Vec<HistogramBucket>,
+}
+
+/// Count of the durations up to `max_ns`, and above the previous bucket.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct HistogramBucket {
+    pub max_ns: u64,
+    pub count: u64,
+}
+
+/// Listening control socket. The socket file is removed when dropped.
+pub(crate) struct ApiSocket {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl ApiSocket {
+    /// Listen on `path`, replacing a stale socket left by a previous VMM.
+    pub fn bind(path: &Path) -> io::Result<Self> {
+        match fs::remove_file(path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+
+        let listener = UnixListener::bind(path)?;
+        listener.set_nonblocking(true)?;
+
+        Ok(ApiSocket {
+            listener,
+            path: path.into(),
+        })
+    }
+
+    /// Serve the pending connections, one request each.
+    pub fn handle_connections<F>(&self, mut handler: F) -> io::Result<()>
+    where
+        F: FnMut(ApiRequest) -> ApiResponse,
+    {
+        loop {
+            let stream = match self.listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+
+            // A misbehaving client only loses its own connection.
+            if let Err(e) = Self::handle_connection(stream, &mut handler) {
+                log::warn!("API connection failed: {}", e);
+            }
+        }
+    }
+
+    fn handle_connection<F>(stream: UnixStream, handler: &mut F) -> io::Result<()>
+    where
+        F: FnMut(ApiRequest) -> ApiResponse,
+    {
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+        stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+
+        let mut line = String::new();
+        BufReader::new(&stream)
+            .take(MAX_REQUEST_LEN)
+            .read_line(&mut line)?;
+
+        let response = match serde_json::from_str(&line) {
+            Ok(request) => handler(request),
+            Err(e) => ApiResponse::Error {
+                error: format!("invalid request: {}", e),
+            },
+        };
+
+        let fd = match response {
+            ApiResponse::MemoryFd(MemoryFd { fd, .. }) => Some(fd),
+            _ => None,
+        };
+        let mut response = serde_json::to_vec(&response)?;
+        response.push(b'\n');
+        // The descriptor goes along with the first bytes.
+        let sent = match fd {
+            Some(fd) => stream.send_with_fd(&response[..], fd)?,
+            None => 0,
+        };
+        (&stream).write_all(&response[sent..])
+    }
+}
+
+impl AsRawFd for ApiSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.listener.as_raw_fd()
+    }
+}
+
+impl Drop for ApiSocket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(path: &Path, request: &str) -> String {
+        let mut stream = UnixStream::connect(path).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    // Send `request`, returning the response and the descriptor along with it.
+    fn request_fd(path: &Path, request: &str) -> (String, Option<fs::File>) {
+        let mut stream = UnixStream::connect(path).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = vec![0u8; 256];
+        let (count, file) = stream.recv_with_fd(&mut response).unwrap();
+        response.truncate(count);
+        stream.read_to_end(&mut response).unwrap();
+        (String::from_utf8(response).unwrap(), file)
+    }
+
+    #[test]
+    fn requests() {
+        let path = std::env::temp_dir().join(format!("lumper-api-{}.sock", std::process::id()));
+        let socket = ApiSocket::bind(&path).unwrap();
+        let memory_path = path.with_extension("mem");
+        fs::write(&memory_path, b"guest RAM").unwrap();
+        let memory = fs::File::open(&memory_path).unwrap();
+
+        let handler = |request| match request {
+            ApiRequest::DirtyStats => ApiResponse::DirtyStats {
+                dirty_pages: 3,
+                page_size: 4096,
+            },
+            ApiRequest::Stats => ApiResponse::Stats(Stats {
+                net: Some(NetCounters {
+                    rx_packets: 1,
+                    ..Default::default()
+                }),
+                vcpus: vec![VcpuCounters {
+                    exits: 2,
+                    exit_kinds: VcpuExits {
+                        io_out: 2,
+                        ..Default::default()
+                    },
+                    last_exit: Some("io_out"),
+                    handling_ns: 1500,
+                    stray_mmio: 0,
+                }],
+                ..Default::default()
+            }),
+            ApiRequest::AddNet { tap, mac } => {
+                assert_eq!(mac.as_deref(), Some("52:54:00:12:34:56"));
+                ApiResponse::NetDevice { device: tap }
+            }
+            ApiRequest::RemoveNet { tap } => ApiResponse::Error {
+                error: format!("the guest driver still uses {}", tap),
+            },
+            ApiRequest::IrqLatency => ApiResponse::IrqLatency(IrqLatencyReport::default()),
+            ApiRequest::AddVcpu { count } => ApiResponse::VcpuCount { cpus: 1 + count },
+            ApiRequest::GetMemoryFd => ApiResponse::MemoryFd(MemoryFd {
+                regions: vec![MemoryRegion {
+                    guest_addr: 0x1000,
+                    size: 9,
+                    offset: 0,
+                }],
+                fd: memory.as_raw_fd(),
+            }),
+            ApiRequest::SetLogLevel { level, trace_exits } => {
+                assert_eq!(trace_exits, Some(true));
+                ApiResponse::LogLevel {
+                    level: level.unwrap_or_else(|| "warn".into()),
+                    trace_exits: true,
+                }
+            }
+            ApiRequest::GetLogLevel => ApiResponse::LogLevel {
+                level: "warn".into(),
+                trace_exits: false,
+            },
+            ApiRequest::Pause | ApiRequest::Info => ApiResponse::Info {
+                state: "paused".into(),
+            },
+            ApiRequest::Resume => ApiResponse::Info {
+                state: "running".into(),
+            },
+        };
+
+        // The requests wait in the socket backlog, until the VMM serves them.
+        let client = {
+            let path = path.clone();
+            std::thread::spawn(move || {
+                let responses = [
+                    request(&path, "{\"action\":\"dirty-stats\"}\n"),
+                    request(&path, "{\"action\":\"reboot\"}\n"),
+                    request(&path, "dirty-stats\n"),
+                    request(&path, "{\"action\":\"stats\"}\n"),
+                    request(
+                        &path,
+                        "{\"action\":\"add-net\",\"tap\":\"tap3\",\"mac\":\"52:54:00:12:34:56\"}\n",
+                    ),
+                    request(&path, "{\"action\":\"remove-net\",\"tap\":\"tap3\"}\n"),
+                    request(&path, "{\"action\":\"add-net\"}\n"),
+                    request(&path, "{\"action\":\"irq-latency\"}\n"),
+                    request(&path, "{\"action\":\"add-vcpu\",\"count\":2}\n"),
+                    request(
+                        &path,
+                        "{\"action\":\"set-log-level\",\"level\":\"debug\",\"trace_exits\":true}\n",
+                    ),
+                    request(&path, "{\"action\":\"get-log-level\"}\n"),
+                    request(&path, "{\"action\":\"pause\"}\n"),
+                    request(&path, "{\"action\":\"resume\"}\n"),
+                ];
+                let memory_fd = request_fd(&path, "{\"action\":\"get-memory-fd\"}\n");
+                (responses, memory_fd)
+            })
+        };
+        while !client.is_finished() {
+            socket.handle_connections(handler).unwrap();
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        let (responses, (memory_response, memory_fd)) = client.join().unwrap();
+
+        assert_eq!(responses[0], "{\"dirty_pages\":3,\"page_size\":4096}\n");
+        assert!(responses[1].starts_with("{\"error\":\"invalid request: unknown variant"));
+        assert!(responses[2].starts_with("{\"error\":\"invalid request:"));
+        assert_eq!(
+            responses[3],
+            concat!(
+                "{\"net\":{\"rx_bytes\":0,\"rx_packets\":1,\"rx_dropped_no_buffer\":0,",
+                "\"rx_dropped_oversize\":0,\"rx_dropped_fault\":0,\"rx_dropped_malformed\":0,",
+                "\"rx_budget_exhausted\":0,\"tx_bytes\":0,\"tx_packets\":0,\"tx_errors\":0,",
+                "\"tx_dropped_offload\":0,\"tx_dropped_fault\":0,\"tx_dropped_malformed\":0},",
+                "\"serial\":{\"rx_bytes\":0,\"tx_bytes\":0,\"tx_errors\":0},\"serial2\":null,\"vcpus\":[{\"exits\":2,\"exit_kinds\":{\"io_in\":0,\"io_out\":2,\"mmio_read\":0,",
+                "\"mmio_write\":0,\"hlt\":0,\"other\":0},\"last_exit\":\"io_out\",",
+                "\"handling_ns\":1500,\"stray_mmio\":0}]}\n"
+            )
+        );
+        assert_eq!(responses[4], "{\"device\":\"tap3\"}\n");
+        assert_eq!(
+            responses[5],
+            "{\"error\":\"the guest driver still uses tap3\"}\n"
+        );
+        assert!(responses[6].starts_with("{\"error\":\"invalid request: missing field `tap`"));
+        assert_eq!(responses[7], "{\"enabled\":false,\"devices\":[]}\n");
+        assert_eq!(responses[8], "{\"cpus\":3}\n");
+        assert_eq!(responses[9], "{\"level\":\"debug\",\"trace_exits\":true}\n");
+        assert_eq!(
+            responses[10],
+            "{\"level\":\"warn\",\"trace_exits\":false}\n"
+        );
+        assert_eq!(responses[11], "{\"state\":\"paused\"}\n");
+        assert_eq!(responses[12], "{\"state\":\"running\"}\n");
+        assert_eq!(
+            memory_response,
+            "{\"regions\":[{\"guest_addr\":4096,\"size\":9,\"offset\":0}]}\n"
+        );
+        let mut content = String::new();
+        memory_fd.unwrap().read_to_string(&mut content).unwrap();
+        assert_eq!(content, "guest RAM");
+        fs::remove_file(&memory_path).unwrap();
+
+        drop(socket);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,501 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! What this VMM supports, and what the host KVM provides.
+//!
+//! [`capabilities`] describes it in a document callers serialize to JSON, e.g. to find out
+//! whether a lumper binary has the devices they need before starting it:
+//!
+//! ```text
+//! $ lumper --capabilities
+//! {
+//!   "schema_version": 1,
+//!   "version": "0.1.1",
+//!   "arch": "x86_64",
+//!   "max_cpus": 254,
+//!   ...
+//!   "host": {
+//!     "status": "available",
+//!     "kvm_api_version": 12,
+//!     ...
+//!   }
+//! }
+//! ```
+//!
+//! The document only gets new fields within a `schema_version`.
+
+use std::collections::BTreeMap;
+
+use kvm_ioctls::{Cap, Kvm};
+use serde::Serialize;
+
+use crate::config::{ConsoleMode, CpuTemplate, IrqchipMode, PvFeature, MAX_NET_SLOTS};
+use crate::cpu::MAX_SUPPORTED_CPUS;
+use crate::devices::net::bindings::VIRTIO_NET_DEVICE_ID;
+use crate::devices::p9::VIRTIO_9P_DEVICE_ID;
+use crate::devices::pmem::VIRTIO_PMEM_DEVICE_ID;
+use crate::{quardle, Error, Result};
+
+/// Version of the [`Capabilities`] document, bumped when a field changes or goes away.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// The only KVM API version there has ever been.
+/// See https://www.kernel.org/doc/html/latest/virt/kvm/api.html#kvm-get-api-version
+pub(crate) const KVM_API_VERSION: i32 = 12;
+
+/// KVM capabilities the VMM cannot run without.
+#[cfg(target_arch = "x86_64")]
+pub(crate) const REQUIRED_CAPABILITIES: [(Cap, &str); 7] = [
+    (Cap::Irqchip, "KVM_CAP_IRQCHIP"),
+    (Cap::UserMemory, "KVM_CAP_USER_MEMORY"),
+    (Cap::SetTssAddr, "KVM_CAP_SET_TSS_ADDR"),
+    (Cap::SetIdentityMapAddr, "KVM_CAP_SET_IDENTITY_MAP_ADDR"),
+    (Cap::ExtCpuid, "KVM_CAP_EXT_CPUID"),
+    (Cap::Irqfd, "KVM_CAP_IRQFD"),
+    (Cap::Ioeventfd, "KVM_CAP_IOEVENTFD"),
+];
+
+/// KVM capabilities the VMM cannot run without.
+#[cfg(target_arch = "aarch64")]
+pub(crate) const REQUIRED_CAPABILITIES: [(Cap, &str); 6] = [
+    (Cap::Irqchip, "KVM_CAP_IRQCHIP"),
+    (Cap::UserMemory, "KVM_CAP_USER_MEMORY"),
+    (Cap::OneReg, "KVM_CAP_ONE_REG"),
+    (Cap::ArmPsci02, "KVM_CAP_ARM_PSCI_0_2"),
+    (Cap::Irqfd, "KVM_CAP_IRQFD"),
+    (Cap::Ioeventfd, "KVM_CAP_IOEVENTFD"),
+];
+
+/// KVM capabilities some options need, checked when they are used.
+#[cfg(target_arch = "x86_64")]
+pub(crate) const OPTIONAL_CAPABILITIES: [(Cap, &str); 3] = [
+    (Cap::ReadonlyMem, "KVM_CAP_READONLY_MEM"),
+    (Cap::TscControl, "KVM_CAP_TSC_CONTROL"),
+    (Cap::TscDeadlineTimer, "KVM_CAP_TSC_DEADLINE_TIMER"),
+];
+
+/// KVM capabilities some options need, checked when they are used.
+#[cfg(target_arch = "aarch64")]
+pub(crate) const OPTIONAL_CAPABILITIES: [(Cap, &str); 1] =
+    [(Cap::ReadonlyMem, "KVM_CAP_READONLY_MEM")];
+
+// The options of the VMM beyond booting a kernel, on every architecture.
+const FEATURES: [&str; 19] = [
+    "initramfs",
+    "initrd-in-memory",
+    "quardle",
+    "memory-backend",
+    "memory-share",
+    "net-slots",
+    "user-net",
+    "vhost-net",
+    "net-bridge",
+    "pmem",
+    "shared-dir",
+    "watchdog",
+    "agent",
+    "api-socket",
+    "event-fifo",
+    "dirty-tracking",
+    "irq-trace",
+    "jail",
+    "cgroup",
+];
+
+// The options the configuration only takes on x86_64.
+#[cfg(target_arch = "x86_64")]
+const ARCH_FEATURES: [&str; 7] = [
+    "clone",
+    "cpu-hotplug",
+    "firmware",
+    "gdb",
+    "mptable",
+    "restart-on-reboot",
+    "smbios",
+];
+#[cfg(target_arch = "aarch64")]
+const ARCH_FEATURES: [&str; 0] = [];
+
+/// What the VMM needs to know about the host KVM.
+pub(crate) trait KvmCapabilities {
+    fn api_version(&self) -> i32;
+    fn has_capability(&self, cap: Cap) -> bool;
+    fn max_vcpus(&self) -> usize;
+}
+
+impl KvmCapabilities for Kvm {
+    fn api_version(&self) -> i32 {
+        self.get_api_version()
+    }
+
+    fn has_capability(&self, cap: Cap) -> bool {
+        self.check_extension(cap)
+    }
+
+    fn max_vcpus(&self) -> usize {
+        self.get_max_vcpus()
+    }
+}
+
+/// Open /dev/kvm.
+pub(crate) fn open_kvm() -> Result<Kvm> {
+    Kvm::new().map_err(|e| match e.errno() {
+        libc::ENOENT => Error::KvmNotFound,
+        libc::EACCES => Error::KvmPermissionDenied,
+        _ => Error::KvmIoctl(e),
+    })
+}
+
+/// Check that the host KVM provides everything the VMM relies on.
+pub(crate) fn check_kvm_capabilities<K: KvmCapabilities>(kvm: &K) -> Result<()> {
+    let version = kvm.api_version();
+    if version != KVM_API_VERSION {
+        return Err(Error::KvmApiVersion(version));
+    }
+
+    let missing: Vec<&'static str> = REQUIRED_CAPABILITIES
+        .iter()
+        .filter(|(cap, _)| !kvm.has_capability(*cap))
+        .map(|(_, name)| *name)
+        .collect();
+
+    if !missing.is_empty() {
+        return Err(Error::MissingKvmCapability(missing));
+    }
+
+    Ok(())
+}
+
+/// What this VMM build supports, and what the host provides, see the [module](self)
+/// documentation.
+#[derive(Debug, Serialize)]
+pub struct Capabilities {
+    /// See [`SCHEMA_VERSION`].
+    pub schema_version: u32,
+    /// Version of the VMM.
+    pub version: &'static str,
+    pub arch: &'static str,
+    /// Maximum number of vCPUs of a guest.
+    pub max_cpus: u32,
+    /// Maximum number of network device slots.
+    pub max_net_slots: u8,
+    /// Version of the quardle manifests the VMM reads.
+    pub quardle_manifest_version: u64,
+    /// Devices the guest may get.
+    pub devices: Vec<Device>,
+    /// How the device interrupts reach the guest, on x86_64.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device_irqs: Option<DeviceIrqs>,
+    /// Kinds of serial port sinks.
+    pub console_modes: Vec<&'static str>,
+    pub cpu_templates: Vec<String>,
+    pub irqchip_modes: Vec<String>,
+    pub pv_features: Vec<&'static str>,
+    /// Options of the VMM beyond booting a kernel.
+    pub features: Vec<&'static str>,
+    pub host: HostCapabilities,
+}
+
+/// A device the guest may get.
+#[derive(Debug, Serialize)]
+pub struct Device {
+    pub name: &'static str,
+    /// The device ID of the virtio devices.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub virtio_id: Option<u32>,
+}
+
+/// The GSIs the devices signal their interrupts on, and the IOAPIC pins they are routed
+/// to. The pins are the guest IRQs, and may be shared with ISA devices.
+#[derive(Debug, Serialize)]
+pub struct DeviceIrqs {
+    /// First and last device GSIs.
+    pub gsis: (u32, u32),
+    /// First and last IOAPIC pins the device GSIs are routed to.
+    pub ioapic_pins: (u32, u32),
+    /// Whether the device pins are legacy IRQs shared with ISA devices.
+    pub shared_with_isa: bool,
+}
+
+/// What the host KVM provides, when it can be opened.
+#[derive(Debug, Serialize)]
+#[serde(tag = "status", rename_all = "lowercase")]
+pub enum HostCapabilities {
+    Available {
+        kvm_api_version: i32,
+        /// Maximum number of vCPUs of a VM.
+        max_vcpus: usize,
+        /// Whether KVM has each capability the VMM checks for, the required ones and those
+        /// some options need.
+        capabilities: BTreeMap<&'static str, bool>,
+        /// Whether the VMM can run guests on this host.
+        usable: bool,
+    },
+    Unavailable {
+        error: String,
+    },
+}
+
+impl HostCapabilities {
+    fn probe<K: KvmCapabilities>(kvm: &K) -> Self {
+        let capabilities = REQUIRED_CAPABILITIES
+            .iter()
+            .chain(OPTIONAL_CAPABILITIES.iter())
+            .map(|(cap, name)| (*name, kvm.has_capability(*cap)))
+            .collect();
+
+        HostCapabilities::Available {
+            kvm_api_version: kvm.api_version(),
+            max_vcpus: kvm.max_vcpus(),
+            capabilities,
+            usable: check_kvm_capabilities(kvm).is_ok(),
+        }
+    }
+}
+
+fn devices() -> Vec<Device> {
+    let virtio = [
+        ("virtio-net", VIRTIO_NET_DEVICE_ID),
+        ("virtio-pmem", VIRTIO_PMEM_DEVICE_ID),
+        ("virtio-9p", VIRTIO_9P_DEVICE_ID),
+    ];
+    let mut legacy = vec!["serial", "watchdog"];
+    if cfg!(target_arch = "x86_64") {
+        legacy.extend([
+            "rtc",
+            "acpi-pm",
+            "pvpanic",
+            "cpu-hotplug",
+            "ioapic",
+            "i8042",
+        ]);
+    }
+
+    virtio
+        .into_iter()
+        .map(|(name, id)| Device {
+            name,
+            virtio_id: Some(id),
+        })
+        .chain(legacy.into_iter().map(|name| Device {
+            name,
+            virtio_id: None,
+        }))
+        .collect()
+}
+
+// The device GSIs are distinct, but routed back onto the legacy IOAPIC pins.
+#[cfg(target_arch = "x86_64")]
+fn device_irqs() -> Option<DeviceIrqs> {
+    use crate::irq::{DEVICE_GSI_BASE, DEVICE_GSI_MAX};
+
+    Some(DeviceIrqs {
+        gsis: (DEVICE_GSI_BASE, DEVICE_GSI_MAX),
+        ioapic_pins: (
+            crate::X86_IRQ_BASE,
+            crate::X86_IRQ_BASE + DEVICE_GSI_MAX - DEVICE_GSI_BASE,
+        ),
+        shared_with_isa: true,
+    })
+}
+
+// The devices signal the GIC SPIs directly.
+#[cfg(target_arch = "aarch64")]
+fn device_irqs() -> Option<DeviceIrqs> {
+    None
+}
+
+// The document, with the host part from `host`.
+fn describe(host: HostCapabilities) -> Capabilities {
+    // The VMM only emulates the IOAPIC on x86_64.
+    let irqchip_modes = IrqchipMode::ALL
+        .iter()
+        .filter(|mode| cfg!(target_arch = "x86_64") || **mode == IrqchipMode::Full)
+        .map(IrqchipMode::to_string)
+        .collect();
+
+    Capabilities {
+        schema_version: SCHEMA_VERSION,
+        version: env!("CARGO_PKG_VERSION"),
+        arch: std::env::consts::ARCH,
+        max_cpus: MAX_SUPPORTED_CPUS,
+        max_net_slots: MAX_NET_SLOTS,
+        quardle_manifest_version: quardle::VERSION,
+        devices: devices(),
+        device_irqs: device_irqs(),
+        console_modes: ConsoleMode::KINDS.to_vec(),
+        cpu_templates: CpuTemplate::ALL
+            .iter()
+            .map(CpuTemplate::to_string)
+            .collect(),
+        irqchip_modes,
+        pv_features: PvFeature::ALL
+            .iter()
+            .map(|feature| feature.name())
+            .collect(),
+        features: FEATURES
+            .iter()
+            .chain(ARCH_FEATURES.iter())
+            .copied()
+            .collect(),
+        host,
+    }
+}
+
+/// Describe what this VMM supports, and probe the host KVM, if /dev/kvm can be opened.
+pub fn capabilities() -> Capabilities {
+    let host = match open_kvm() {
+        Ok(kvm) => HostCapabilities::probe(&kvm),
+        Err(e) => HostCapabilities::Unavailable {
+            error: e.to_string(),
+        },
+    };
+
+    describe(host)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct MockKvm {
+        api_version: i32,
+        missing: Vec<Cap>,
+    }
+
+    impl KvmCapabilities for MockKvm {
+        fn api_version(&self) -> i32 {
+            self.api_version
+        }
+
+        fn has_capability(&self, cap: Cap) -> bool {
+            !self
+                .missing
+                .iter()
+                .any(|missing| *missing as i32 == cap as i32)
+        }
+
+        fn max_vcpus(&self) -> usize {
+            1024
+        }
+    }
+
+    #[test]
+    fn all_capabilities() {
+        let kvm = MockKvm {
+            api_version: KVM_API_VERSION,
+            missing: vec![],
+        };
+
+        assert!(check_kvm_capabilities(&kvm).is_ok());
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn missing_capabilities() {
+        let kvm = MockKvm {
+            api_version: KVM_API_VERSION,
+            missing: vec![Cap::SetTssAddr, Cap::Ioeventfd],
+        };
+
+        match check_kvm_capabilities(&kvm) {
+            Err(Error::MissingKvmCapability(missing)) => {
+                assert_eq!(missing, vec!["KVM_CAP_SET_TSS_ADDR", "KVM_CAP_IOEVENTFD"])
+            }
+            r => panic!("unexpected result: {:?}", r.err()),
+        }
+    }
+
+    #[test]
+    fn bad_api_version() {
+        let kvm = MockKvm {
+            api_version: 11,
+            missing: vec![],
+        };
+
+        assert!(matches!(
+            check_kvm_capabilities(&kvm),
+            Err(Error::KvmApiVersion(11))
+        ));
+    }
+
+    #[test]
+    fn schema() {
+        let kvm = MockKvm {
+            api_version: KVM_API_VERSION,
+            missing: vec![Cap::ReadonlyMem],
+        };
+        let document = serde_json::to_value(describe(HostCapabilities::probe(&kvm))).unwrap();
+
+        // The fields callers rely on, with their types.
+        assert_eq!(document["schema_version"], SCHEMA_VERSION);
+        assert_eq!(document["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(document["arch"], std::env::consts::ARCH);
+        assert_eq!(document["max_cpus"], MAX_SUPPORTED_CPUS);
+        assert_eq!(document["max_net_slots"], MAX_NET_SLOTS);
+        assert_eq!(document["quardle_manifest_version"], quardle::VERSION);
+        assert_eq!(
+            document["console_modes"],
+            serde_json::json!(["stdout", "file", "unix", "agent"])
+        );
+        assert_eq!(
+            document["devices"][0],
+            serde_json::json!({"name": "virtio-net", "virtio_id": 1})
+        );
+        assert_eq!(
+            document["devices"][3],
+            serde_json::json!({"name": "serial"})
+        );
+        #[cfg(target_arch = "x86_64")]
+        assert_eq!(
+            document["device_irqs"],
+            serde_json::json!({
+                "gsis": [24, 31],
+                "ioapic_pins": [5, 12],
+                "shared_with_isa": true,
+            })
+        );
+        for list in ["cpu_templates", "irqchip_modes", "pv_features", "features"] {
+            let names = document[list].as_array().unwrap();
+            assert!(!names.is_empty());
+            assert!(names.iter().all(serde_json::Value::is_string), "{}", list);
+        }
+        assert_eq!(document["irqchip_modes"][0], "full");
+
+        let host = &document["host"];
+        assert_eq!(host["status"], "available");
+        assert_eq!(host["kvm_api_version"], KVM_API_VERSION);
+        assert_eq!(host["max_vcpus"], 1024);
+        assert_eq!(host["capabilities"]["KVM_CAP_IRQFD"], true);
+        assert_eq!(host["capabilities"]["KVM_CAP_READONLY_MEM"], false);
+        // The missing capability is only needed by some options.
+        assert_eq!(host["usable"], true);
+
+        let document = serde_json::to_value(describe(HostCapabilities::Unavailable {
+            error: Error::KvmNotFound.to_string(),
+        }))
+        .unwrap();
+        assert_eq!(
+            document["host"],
+            serde_json::json!({
+                "status": "unavailable",
+                "error": Error::KvmNotFound.to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn unique_names() {
+        let document = describe(HostCapabilities::Unavailable {
+            error: String::new(),
+        });
+        let mut features = document.features.clone();
+        features.sort_unstable();
+        features.dedup();
+        assert_eq!(features.len(), document.features.len());
+
+        let mut devices: Vec<_> = document.devices.iter().map(|device| device.name).collect();
+        devices.sort_unstable();
+        devices.dedup();
+        assert_eq!(devices.len(), document.devices.len());
+    }
+}
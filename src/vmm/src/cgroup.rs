@@ -0,0 +1,269 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Confining the VMM to a cgroup v2, which limits the resources of the whole VM.
+//!
+//! [`Cgroup::enter`] creates the cgroup, writes its limits, and moves the VMM process into
+//! it. Outside of threaded subtrees, all the threads of a process share its cgroup: the
+//! threads already running move along, and the vCPU and device threads
+//! [`VMM::run`](crate::VMM::run) starts are created in the cgroup. Entering it before
+//! [`VMM::from_config`](crate::VMM::from_config) also charges the guest memory to it, as
+//! a page is charged to the cgroup of the thread touching it first.
+//!
+//! The parent directory must be a cgroup v2 the VMM user can write to, e.g. delegated by
+//! systemd, with the controllers of the limits enabled in its `cgroup.subtree_control`:
+//! `cpu` for `cpu.max`, `memory` for `memory.max` and `io` for `io.weight`.
+//!
+//! [`Cgroup::remove`] moves the VMM back to the cgroup it came from, then removes the
+//! cgroup. It only uses files [`Cgroup::enter`] opened, and works from the
+//! [`jail`](crate::jail) too.
+
+use std::ffi::CString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use crate::config::CgroupConfig;
+
+const CGROUP2_SUPER_MAGIC: u64 = 0x6367_7270;
+
+/// Errors entering or removing the cgroup.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("{0:?} is not on a cgroup v2 filesystem")]
+    NotMounted(PathBuf),
+    #[error("permission denied on {0:?}, the cgroup tree must be delegated to the VMM user")]
+    PermissionDenied(PathBuf),
+    #[error(
+        "the {controller} controller is not enabled in {parent:?}, add it to its cgroup.subtree_control"
+    )]
+    ControllerNotEnabled {
+        controller: &'static str,
+        parent: PathBuf,
+    },
+    #[error("failed to access {path:?}")]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    #[error("failed to move the VMM back to its original cgroup")]
+    Leave(#[source] io::Error),
+}
+
+// The error accessing `path`, telling the missing permissions apart.
+fn access_error(path: &Path) -> impl Fn(io::Error) -> Error + '_ {
+    move |source| match source.raw_os_error() {
+        Some(libc::EACCES) | Some(libc::EPERM) => Error::PermissionDenied(path.to_path_buf()),
+        _ => Error::Io {
+            path: path.to_path_buf(),
+            source,
+        },
+    }
+}
+
+fn is_cgroup2(path: &Path) -> io::Result<bool> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    // Safe because the path is a valid C string, and the kernel fills the structure we own.
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statfs(path.as_ptr(), &mut stat) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(stat.f_type as u64 == CGROUP2_SUPER_MAGIC)
+}
+
+// The cgroup the VMM is in, from the root of the hierarchy holding `dir`.
+fn current_cgroup(dir: &Path) -> io::Result<PathBuf> {
+    // The cgroup v2 line is the one of hierarchy 0, e.g. `0::/user.slice/vm.scope`.
+    let cgroups = fs::read_to_string("/proc/self/cgroup")?;
+    let current = cgroups
+        .lines()
+        .find_map(|line| line.strip_prefix("0::"))
+        .ok_or_else(|| io::Error::from_raw_os_error(libc::ENOENT))?;
+
+    // The root is the last ancestor on the cgroup2 filesystem.
+    let mut root = dir;
+    while let Some(parent) = root.parent() {
+        if !is_cgroup2(parent)? {
+            break;
+        }
+        root = parent;
+    }
+
+    Ok(root.join(current.trim_start_matches('/')))
+}
+
+/// A cgroup the VMM moved to.
+#[derive(Debug)]
+pub struct Cgroup {
+    path: PathBuf,
+    // Kept open, to remove the cgroup without resolving its path.
+    parent: File,
+    // `cgroup.procs` of the cgroup the VMM came from, if it could be opened.
+    origin: io::Result<File>,
+}
+
+impl Cgroup {
+    /// Create the cgroup `config` describes, and move the VMM process into it.
+    ///
+    /// This must be called before [`VMM::run`](crate::VMM::run), and preferably before
+    /// [`VMM::from_config`](crate::VMM::from_config). An existing cgroup is reused, and its
+    /// limits overwritten.
+    pub fn enter(config: &CgroupConfig) -> Result<Self, Error> {
+        let path = config.path.as_path();
+        let parent = path
+            .parent()
+            .ok_or_else(|| Error::NotMounted(path.to_path_buf()))?;
+        if !is_cgroup2(parent).map_err(access_error(parent))? {
+            return Err(Error::NotMounted(parent.to_path_buf()));
+        }
+
+        // Without its controller enabled in the parent, a limit file does not exist.
+        let subtree_control = parent.join("cgroup.subtree_control");
+        let controllers =
+            fs::read_to_string(&subtree_control).map_err(access_error(&subtree_control))?;
+        let cpu_max = config.cpu_max.map(|cpu_max| {
+            let quota = cpu_max.quota.map_or("max".to_string(), |q| q.to_string());
+            format!("{} {}", quota, cpu_max.period)
+        });
+        let memory_max = config.memory_max.map(|max| max.to_string());
+        let io_weight = config.io_weight.map(|weight| format!("default {}", weight));
+        let limits = [
+            ("cpu", "cpu.max", cpu_max),
+            ("memory", "memory.max", memory_max),
+            ("io", "io.weight", io_weight),
+        ];
+        for &(controller, _, ref value) in limits.iter() {
+            if value.is_some() && !controllers.split_whitespace().any(|c| c == controller) {
+                return Err(Error::ControllerNotEnabled {
+                    controller,
+                    parent: parent.to_path_buf(),
+                });
+            }
+        }
+
+        match fs::create_dir(path) {
+            Err(e) if e.kind() != io::ErrorKind::AlreadyExists => {
+                return Err(access_error(path)(e))
+            }
+            _ => {}
+        }
+        for (_, file, value) in limits.iter() {
+            if let Some(value) = value {
+                let file = path.join(file);
+                fs::write(&file, value).map_err(access_error(&file))?;
+            }
+        }
+
+        // Opened before leaving it, the original cgroup could be out of reach afterwards.
+        let origin = current_cgroup(parent).and_then(|origin| {
+            OpenOptions::new()
+                .write(true)
+                .open(origin.join("cgroup.procs"))
+        });
+        let cgroup = Cgroup {
+            path: path.to_path_buf(),
+            parent: File::open(parent).map_err(access_error(parent))?,
+            origin,
+        };
+
+        // Writing a pid moves its whole process.
+        let procs = path.join("cgroup.procs");
+        fs::write(&procs, std::process::id().to_string()).map_err(access_error(&procs))?;
+
+        Ok(cgroup)
+    }
+
+    /// Move the VMM back to its original cgroup, and remove this one. This is only done on
+    /// a clean shutdown: after an error, the cgroup is left behind for inspection.
+    pub fn remove(self) -> Result<(), Error> {
+        let mut origin = self.origin.map_err(Error::Leave)?;
+        origin
+            .write_all(std::process::id().to_string().as_bytes())
+            .map_err(Error::Leave)?;
+
+        // The cgroup path has a parent, thus a file name.
+        let name = CString::new(self.path.file_name().unwrap().as_bytes())
+            .map_err(|e| access_error(&self.path)(e.into()))?;
+        // Safe because the name is a valid C string, and we check the result.
+        let ret =
+            unsafe { libc::unlinkat(self.parent.as_raw_fd(), name.as_ptr(), libc::AT_REMOVEDIR) };
+        if ret < 0 {
+            return Err(access_error(&self.path)(io::Error::last_os_error()));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process::Command;
+    use std::thread;
+
+    // A delegated cgroup v2 directory the tests create their cgroups in.
+    const CGROUP_ENV: &str = "LUMPER_TEST_CGROUP";
+    const CHILD_ENV: &str = "LUMPER_CGROUP_TEST_CHILD";
+
+    fn config(path: PathBuf) -> CgroupConfig {
+        CgroupConfig {
+            path,
+            cpu_max: None,
+            memory_max: None,
+            io_weight: None,
+        }
+    }
+
+    // The cgroup of the calling thread, from the root of the hierarchy.
+    fn thread_cgroup() -> String {
+        let cgroups = fs::read_to_string("/proc/thread-self/cgroup").unwrap();
+        cgroups.trim().strip_prefix("0::").unwrap().to_string()
+    }
+
+    #[test]
+    fn not_mounted() {
+        assert!(matches!(
+            Cgroup::enter(&config(env::temp_dir().join("lumper-cgroup"))),
+            Err(Error::NotMounted(_))
+        ));
+    }
+
+    // The cgroup applies to the whole process, the test enters it from a child process.
+    // Without a delegated cgroup tree, there is nothing to test.
+    #[test]
+    fn enter_and_remove() {
+        let parent = match env::var_os(CGROUP_ENV) {
+            Some(parent) => PathBuf::from(parent),
+            None => {
+                eprintln!("{} is not set, skipping", CGROUP_ENV);
+                return;
+            }
+        };
+        let path = parent.join(format!("lumper-test-{}", std::process::id()));
+
+        if env::var_os(CHILD_ENV).is_none() {
+            let status = Command::new(env::current_exe().unwrap())
+                .args(["--exact", "cgroup::tests::enter_and_remove"])
+                .env(CHILD_ENV, "1")
+                .status()
+                .unwrap();
+            assert!(status.success());
+            return;
+        }
+
+        let origin = thread_cgroup();
+        let cgroup = Cgroup::enter(&config(path.clone())).unwrap();
+        let name = path.file_name().unwrap().to_str().unwrap();
+        assert!(thread_cgroup().ends_with(name));
+        // As the vCPUs, a thread started afterwards is in the cgroup.
+        let spawned = thread::spawn(thread_cgroup).join().unwrap();
+        assert!(spawned.ends_with(name));
+
+        cgroup.remove().unwrap();
+        assert_eq!(thread_cgroup(), origin);
+        assert!(!path.exists());
+    }
+}
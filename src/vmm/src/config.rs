@@ -0,0 +1,3870 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+#[cfg(target_arch = "x86_64")]
+use crate::cpu::mptable::MAX_MPTABLE_CPUS;
+use crate::cpu::MAX_SUPPORTED_CPUS;
+use crate::layout::CMDLINE_MAX_SIZE;
+use crate::quardle::Quardle;
+
+/// Guest memory needed to boot Linux, in bytes.
+pub const MIN_MEMORY: u64 = 64 << 20;
+
+/// Most network devices that can be hot-plugged.
+pub const MAX_NET_SLOTS: u8 = 8;
+
+/// Configuration errors.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// The console/serial specification could not be parsed.
+    #[error(
+        "invalid console specification `{0}` (expected stdout, file:<path>[,maxsize=<size>][,rotate=<count>][,timestamps=on|off], unix:<path> or agent)"
+    )]
+    InvalidConsole(String),
+    /// Unknown console error policy.
+    #[error("unknown console error policy `{0}` (expected discard, stop or stdout-fallback)")]
+    InvalidConsoleErrorPolicy(String),
+    /// The agent channel was requested for the console.
+    #[error("the agent channel is only available on the second serial port")]
+    AgentConsole,
+    /// No kernel was given to the builder.
+    #[error("a kernel path is required")]
+    MissingKernel,
+    /// The CPU topology specification could not be parsed.
+    #[error("invalid CPU topology `{0}` (expected <sockets>:<cores>:<threads>)")]
+    InvalidTopology(String),
+    /// Unknown CPU template name.
+    #[error("unknown CPU template `{0}` (expected passthrough, t2 or c3)")]
+    InvalidCpuTemplate(String),
+    /// Unknown irqchip mode.
+    #[error("unknown irqchip mode `{0}` (expected full or split)")]
+    InvalidIrqchip(String),
+    /// The split irqchip is only supported on x86_64.
+    #[cfg(target_arch = "aarch64")]
+    #[error("the split irqchip is not supported on aarch64")]
+    SplitIrqchipUnsupported,
+    /// The paravirtual features could not be parsed.
+    #[error(
+        "invalid paravirtual features `{0}` (expected all, none, or a list of async-pf, steal-time, pv-eoi and pv-unhalt)"
+    )]
+    InvalidPvFeatures(String),
+    /// The CPU topology does not match the number of vCPUs.
+    #[error("CPU topology {topology} describes {} vCPUs, but {cpus} were requested", .topology.vcpu_count())]
+    TopologyMismatch { topology: CpuTopology, cpus: u8 },
+    /// The pmem device specification could not be parsed.
+    #[error("invalid pmem specification `{0}` (expected file=<path>[,ro|,rw])")]
+    InvalidPmem(String),
+    /// The firmware specification could not be parsed.
+    #[error(
+        "invalid firmware specification `{0}` (expected path=<file>,addr=<address>[,entry], with a page aligned address)"
+    )]
+    InvalidFirmware(String),
+    /// Firmware blobs are only loaded on x86_64.
+    #[cfg(target_arch = "aarch64")]
+    #[error("firmware blobs are not supported on aarch64")]
+    FirmwareUnsupported,
+    /// The GDB stub address could not be parsed.
+    #[error("invalid GDB address `{0}` (expected tcp:[<address>]:<port>)")]
+    InvalidGdb(String),
+    /// The GDB stub only debugs x86_64 guests.
+    #[cfg(target_arch = "aarch64")]
+    #[error("the GDB stub is not supported on aarch64")]
+    GdbUnsupported,
+    /// The shared directory specification could not be parsed.
+    #[error(
+        "invalid shared directory specification `{0}` (expected tag=<tag>,path=<path>[,ro|,rw])"
+    )]
+    InvalidSharedDir(String),
+    /// The network specification could not be parsed.
+    #[error("invalid network specification `{0}` (expected <tap>|user[,hostfwd=tcp:[<address>]:<port>-[<address>]:<port>][,dhcp-server=<address>/<prefix>[,range=<address>-<address>][,dns=<address>]][,rx_rate=<rate>][,tx_rate=<rate>][,rx_ops=<ops>][,tx_ops=<ops>][,burst=<size>][,vhost=on|off][,mac=<address>][,attach-timeout=<duration>][,rx_queue=<size>][,tx_queue=<size>][,bridge=<bridge>[,create-bridge]])")]
+    InvalidNet(String),
+    /// The watchdog specification could not be parsed.
+    #[error(
+        "invalid watchdog specification `{0}` (expected timeout=<seconds>[,action=poweroff|reset])"
+    )]
+    InvalidWatchdog(String),
+    /// The number of vCPUs is zero, or above what the VMM can describe to the guest.
+    #[error("invalid number of vCPUs {0} (expected 1 to {})", MAX_SUPPORTED_CPUS)]
+    InvalidCpus(u8),
+    /// The maximum number of vCPUs is below the boot ones, or above what the VMM can
+    /// describe to the guest.
+    #[error(
+        "invalid maximum number of vCPUs {max_cpus} (expected {cpus} to {})",
+        MAX_SUPPORTED_CPUS
+    )]
+    InvalidMaxCpus { max_cpus: u8, cpus: u8 },
+    /// vCPUs are only hot-plugged on x86_64.
+    #[cfg(target_arch = "aarch64")]
+    #[error("vCPU hotplug is not supported on aarch64")]
+    CpuHotplugUnsupported,
+    /// The MP table cannot describe that many vCPUs.
+    #[cfg(target_arch = "x86_64")]
+    #[error(
+        "the MP table describes at most {} vCPUs, {0} were requested: disable it",
+        MAX_MPTABLE_CPUS
+    )]
+    MptableCpus(u8),
+    /// The guest memory size could not be parsed.
+    #[error(
+        "invalid memory size `{size}`: {reason} (expected <number>[K|M|G|T][iB], in MiB without a unit)"
+    )]
+    InvalidMemorySize { size: String, reason: &'static str },
+    /// The guest memory is too small to boot Linux.
+    #[error(
+        "{} MiB of guest memory is not enough to boot Linux (expected at least {} MiB)",
+        .0 >> 20,
+        MIN_MEMORY >> 20
+    )]
+    MemoryTooSmall(u64),
+    /// The initramfs does not exist.
+    #[error("initramfs {0:?} does not exist")]
+    MissingInitramfs(PathBuf),
+    /// The boot image file descriptor specification could not be parsed.
+    #[error("invalid boot image `{0}` (expected <path>, fd:<number> or /proc/self/fd/<number>)")]
+    InvalidImage(String),
+    /// A boot image given as a file descriptor cannot be read from, or seeked.
+    #[error("boot image {image} is not a readable and seekable file")]
+    UnusableImage {
+        image: ImageSource,
+        #[source]
+        source: io::Error,
+    },
+    /// The memory backend specification could not be parsed.
+    #[error("invalid memory backend `{0}` (expected file=<path>)")]
+    InvalidMemoryBackend(String),
+    /// Unknown KSM mode.
+    #[error("unknown KSM mode `{0}` (expected on or off)")]
+    InvalidKsmMode(String),
+    /// Unknown transparent huge pages mode.
+    #[error("unknown THP mode `{0}` (expected always, never or default)")]
+    InvalidThpMode(String),
+    /// The memory backend file does not exist.
+    #[error("memory backend {0:?} does not exist")]
+    MissingMemoryBackend(PathBuf),
+    /// Unknown memory share mode.
+    #[error("unknown memory share mode `{0}` (expected memfd)")]
+    InvalidMemoryShare(String),
+    /// The guest memory is shared, but comes from a memory backend.
+    #[error("the guest memory cannot be shared along with a memory backend")]
+    SharedMemoryBackend,
+    /// The shared guest memory descriptor would replace a standard stream.
+    #[error("invalid memory share descriptor {0} (expected at least 3)")]
+    InvalidMemoryShareFd(RawFd),
+    /// A shared guest memory descriptor is requested, but the guest memory is not shared.
+    #[error("the memory share descriptor needs a shared guest memory")]
+    MemoryShareFdWithoutShare,
+    /// The memory backend file is not the size of the guest memory.
+    #[error("memory backend {path:?} holds {size} bytes, but the guest memory is {memory} bytes")]
+    MemoryBackendSize {
+        path: PathBuf,
+        size: u64,
+        memory: u64,
+    },
+    /// The directory of a serial output file does not exist.
+    #[error("directory of the serial output file {0:?} does not exist")]
+    MissingConsoleDirectory(PathBuf),
+    /// The event FIFO does not exist.
+    #[error("event FIFO {0:?} does not exist, create it with mkfifo")]
+    MissingEventFifo(PathBuf),
+    /// The UUID could not be parsed.
+    #[error("invalid UUID `{0}` (expected xxxxxxxx-xxxx-xxxx-xxxx-xxxxxxxxxxxx, in hexadecimal)")]
+    InvalidUuid(String),
+    /// The SMBIOS strings could not be parsed.
+    #[error(
+        "invalid SMBIOS specification `{0}` (expected a list of manufacturer=, product=, version=, serial=, sku= and family=, with non-empty values)"
+    )]
+    InvalidSmbios(String),
+    /// The jail specification could not be parsed.
+    #[error(
+        "invalid jail specification `{0}` (expected uid=<uid>,gid=<gid>,chroot=<directory>[,unshare=on|off], with a uid other than 0)"
+    )]
+    InvalidJail(String),
+    /// The cgroup specification could not be parsed.
+    #[error(
+        "invalid cgroup specification `{0}` (expected path=<directory>[,cpu-max=<quota>|max [<period>]][,mem-max=<size>][,io-weight=<weight>])"
+    )]
+    InvalidCgroup(String),
+    /// The jail directory does not exist.
+    #[error("jail directory {0:?} does not exist")]
+    MissingJailDirectory(PathBuf),
+    /// A serial output file is rotated, which the jail prevents.
+    #[error("serial output file {0:?} cannot be rotated from the jail, remove its maxsize")]
+    JailedRotation(PathBuf),
+    /// More hot-plug network slots than the VMM reserves IRQs for.
+    #[error(
+        "invalid number of network slots {0} (expected at most {})",
+        MAX_NET_SLOTS
+    )]
+    InvalidNetSlots(u8),
+    /// A console session is replayed along with a console input.
+    #[error("the console input cannot be replayed along with --console-input")]
+    ReplayWithConsoleInput,
+    /// The replayed console session does not exist.
+    #[error("console session {0:?} does not exist")]
+    MissingReplay(PathBuf),
+    /// Network slots are reserved, but there is no API socket to plug devices in with.
+    #[error("network slots need an API socket")]
+    NetSlotsWithoutApi,
+    /// The kernel command line has an unterminated quote.
+    #[error("invalid kernel command line `{0}` (expected balanced double quotes)")]
+    InvalidCmdline(String),
+    /// A kernel command line parameter the guest takes once is given twice.
+    #[error("conflicting kernel command line parameters `{first}` and `{second}`")]
+    ConflictingCmdline { first: String, second: String },
+    /// The kernel command line does not fit where the kernel reads it from.
+    #[error("the kernel command line is {len} bytes long (expected at most {})", .max_size - 1)]
+    CmdlineTooLong { len: usize, max_size: usize },
+    /// Network slots are reserved, but the jail prevents opening the taps later on.
+    #[error("network devices cannot be hot-plugged from the jail, remove the network slots")]
+    JailedNetSlots,
+    /// The number of reboots is bounded, but the guest does not restart on reboot.
+    #[error("--max-reboots needs --restart-on-reboot")]
+    MaxRebootsWithoutRestart,
+    /// The vCPUs plugged into the guest could not be unplugged when it restarts.
+    #[error("--restart-on-reboot does not support hot-pluggable vCPUs")]
+    RestartWithHotplug,
+    /// The guest only restarts in place on x86_64.
+    #[cfg(target_arch = "aarch64")]
+    #[error("restarting on reboot is not supported on aarch64")]
+    RestartUnsupported,
+}
+
+/// Dedicated [`Result`](https://doc.rust-lang.org/std/result/) type.
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Where a serial port sends the guest output, and reads its input from.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum ConsoleMode {
+    /// Output goes to the VMM stdout, input comes from the VMM stdin.
+    #[default]
+    Stdout,
+    /// Output is written to a file, there is no input.
+    File(ConsoleFile),
+    /// Output and input go through a connected Unix socket.
+    Unix(PathBuf),
+    /// Output and input are SLIP frames exchanged with an in-process
+    /// [`AgentChannel`](crate::agent::AgentChannel).
+    Agent,
+    /// Output is discarded, there is no input. The output still goes through the counters
+    /// and the panic detection.
+    None,
+}
+
+impl ConsoleMode {
+    /// The kinds of serial port sinks, as the command line names them.
+    pub const KINDS: [&'static str; 5] = ["stdout", "file", "unix", "agent", "none"];
+
+    /// The kind of sink, one of [`ConsoleMode::KINDS`].
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ConsoleMode::Stdout => "stdout",
+            ConsoleMode::File(_) => "file",
+            ConsoleMode::Unix(_) => "unix",
+            ConsoleMode::Agent => "agent",
+            ConsoleMode::None => "none",
+        }
+    }
+}
+
+impl FromStr for ConsoleMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once(':') {
+            _ if s == "stdout" => Ok(ConsoleMode::Stdout),
+            _ if s == "agent" => Ok(ConsoleMode::Agent),
+            _ if s == "none" => Ok(ConsoleMode::None),
+            Some(("file", file)) => file.parse().map(ConsoleMode::File),
+            Some(("unix", path)) if !path.is_empty() => Ok(ConsoleMode::Unix(path.into())),
+            Some(_) => Err(Error::InvalidConsole(s.to_string())),
+            // A bare path is a file, for backward compatibility with `--console <path>`.
+            None if !s.is_empty() => Ok(ConsoleMode::File(s.into())),
+            None => Err(Error::InvalidConsole(s.to_string())),
+        }
+    }
+}
+
+/// File the guest output is written to.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ConsoleFile {
+    pub path: PathBuf,
+    /// Size the file is rotated at, in bytes. The file grows unbounded without it.
+    pub max_size: Option<u64>,
+    /// Number of rotated files kept, as `<path>.1` (the most recent) to `<path>.<rotate>`.
+    pub rotate: u32,
+    /// Prefix each line with the time since the VMM started, and the wall clock time.
+    pub timestamps: bool,
+}
+
+impl From<PathBuf> for ConsoleFile {
+    fn from(path: PathBuf) -> Self {
+        ConsoleFile {
+            path,
+            ..Default::default()
+        }
+    }
+}
+
+impl From<&str> for ConsoleFile {
+    fn from(path: &str) -> Self {
+        PathBuf::from(path).into()
+    }
+}
+
+impl FromStr for ConsoleFile {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::InvalidConsole(format!("file:{}", s));
+        let mut options = s.split(',');
+
+        let mut file = ConsoleFile::from(
+            options
+                .next()
+                .filter(|path| !path.is_empty())
+                .ok_or_else(invalid)?,
+        );
+        let mut rotate = None;
+
+        for option in options {
+            match option.split_once('=').ok_or_else(invalid)? {
+                ("maxsize", size) => file.max_size = Some(parse_size(size).ok_or_else(invalid)?),
+                ("rotate", count) => rotate = Some(count.parse().map_err(|_| invalid())?),
+                ("timestamps", "on") => file.timestamps = true,
+                ("timestamps", "off") => file.timestamps = false,
+                _ => return Err(invalid()),
+            }
+        }
+
+        // One rotated file is kept by default. Rotating needs a size to rotate at.
+        match (file.max_size, rotate) {
+            (Some(_), rotate) => file.rotate = rotate.unwrap_or(1),
+            (None, Some(_)) => return Err(invalid()),
+            (None, None) => {}
+        }
+
+        Ok(file)
+    }
+}
+
+/// What becomes of the console output once writing it failed, e.g. on a full disk.
+///
+/// The guest keeps running whichever the policy, and the failed writes are counted.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ConsoleErrorPolicy {
+    /// Drop the output, and try the sink again with the next one.
+    #[default]
+    Discard,
+    /// Stop the VM.
+    Stop,
+    /// Write the output to the VMM stdout from then on. Discards it when the console
+    /// already is stdout.
+    StdoutFallback,
+}
+
+impl std::fmt::Display for ConsoleErrorPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            ConsoleErrorPolicy::Discard => "discard",
+            ConsoleErrorPolicy::Stop => "stop",
+            ConsoleErrorPolicy::StdoutFallback => "stdout-fallback",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for ConsoleErrorPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "discard" => Ok(ConsoleErrorPolicy::Discard),
+            "stop" => Ok(ConsoleErrorPolicy::Stop),
+            "stdout-fallback" => Ok(ConsoleErrorPolicy::StdoutFallback),
+            _ => Err(Error::InvalidConsoleErrorPolicy(s.to_string())),
+        }
+    }
+}
+
+/// A recorded console session, replayed against the VM.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReplayConfig {
+    /// The session, as `--console-record` writes it.
+    pub path: PathBuf,
+    /// Send each input once the output it followed shows up, rather than at its time.
+    pub fast: bool,
+    /// How many output lines may differ from the recorded ones in their numbers only, e.g.
+    /// durations. The kernel timestamps are left out.
+    pub tolerance: usize,
+}
+
+/// File backing a virtio-pmem device.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PmemConfig {
+    /// Image mapped into the guest physical memory.
+    pub path: PathBuf,
+    /// Map the image read-only. This is the default.
+    pub read_only: bool,
+}
+
+impl FromStr for PmemConfig {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut path = None;
+        let mut read_only = true;
+
+        for option in s.split(',') {
+            match option.split_once('=') {
+                Some(("file", file)) if !file.is_empty() => path = Some(PathBuf::from(file)),
+                None if option == "ro" => read_only = true,
+                None if option == "rw" => read_only = false,
+                _ => return Err(Error::InvalidPmem(s.to_string())),
+            }
+        }
+
+        Ok(PmemConfig {
+            path: path.ok_or_else(|| Error::InvalidPmem(s.to_string()))?,
+            read_only,
+        })
+    }
+}
+
+/// Firmware blob mapped read-only into the guest, e.g. a verification stub.
+///
+/// It goes into the MMIO gap below 4 GiB, out of the RAM and the device registers, e.g.
+/// at `0xffff0000`. With `entry`, the boot vCPU starts executing it instead of the kernel,
+/// in the same 64-bit mode, with the kernel entry in `rbx`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FirmwareConfig {
+    pub path: PathBuf,
+    /// Guest physical address, page aligned.
+    pub address: u64,
+    /// Start the boot vCPU at `address`.
+    pub entry: bool,
+}
+
+impl FromStr for FirmwareConfig {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::InvalidFirmware(s.to_string());
+        let mut path = None;
+        let mut address = None;
+        let mut entry = false;
+
+        for option in s.split(',') {
+            match option.split_once('=') {
+                Some(("path", value)) if !value.is_empty() => path = Some(PathBuf::from(value)),
+                Some(("addr", value)) => {
+                    let parsed = match value.strip_prefix("0x") {
+                        Some(hex) => u64::from_str_radix(hex, 16),
+                        None => value.parse(),
+                    };
+                    address = Some(
+                        parsed
+                            .ok()
+                            .filter(|address| address % 0x1000 == 0)
+                            .ok_or_else(invalid)?,
+                    );
+                }
+                None if option == "entry" => entry = true,
+                _ => return Err(invalid()),
+            }
+        }
+
+        Ok(FirmwareConfig {
+            path: path.ok_or_else(invalid)?,
+            address: address.ok_or_else(invalid)?,
+            entry,
+        })
+    }
+}
+
+/// Host directory shared with the guest over virtio-9p.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SharedDirConfig {
+    /// Mount tag the guest finds the share by.
+    pub tag: String,
+    /// Shared directory, which the guest cannot see out of.
+    pub path: PathBuf,
+    /// Reject the guest writes. The share is writable by default.
+    pub read_only: bool,
+}
+
+impl FromStr for SharedDirConfig {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut tag = None;
+        let mut path = None;
+        let mut read_only = false;
+
+        for option in s.split(',') {
+            match option.split_once('=') {
+                Some(("tag", value)) if !value.is_empty() => tag = Some(value.to_string()),
+                Some(("path", value)) if !value.is_empty() => path = Some(PathBuf::from(value)),
+                None if option == "ro" => read_only = true,
+                None if option == "rw" => read_only = false,
+                _ => return Err(Error::InvalidSharedDir(s.to_string())),
+            }
+        }
+
+        Ok(SharedDirConfig {
+            tag: tag.ok_or_else(|| Error::InvalidSharedDir(s.to_string()))?,
+            path: path.ok_or_else(|| Error::InvalidSharedDir(s.to_string()))?,
+            read_only,
+        })
+    }
+}
+
+/// Where a boot image, the kernel or the initramfs, is read from.
+///
+/// Given as file descriptors, e.g. sealed memfds, the images need no filesystem access: the
+/// VMM can run in an empty chroot.
+#[derive(Clone, Debug)]
+pub enum ImageSource {
+    /// A file the VMM opens.
+    Path(PathBuf),
+    /// A file descriptor the VMM inherited, and does not own: `fd:<number>`, or
+    /// `/proc/self/fd/<number>`.
+    Fd(RawFd),
+    /// An open file, from the library API.
+    File(Arc<File>),
+}
+
+impl ImageSource {
+    /// Open the image, from its start. The file descriptors are duplicated: the copies
+    /// share their offset with the original.
+    pub fn open(&self) -> io::Result<ImageFile> {
+        let mut file = match self {
+            ImageSource::Path(path) => File::open(path)?,
+            ImageSource::Fd(fd) => {
+                // Safe because it only duplicates the descriptor, and we check the result.
+                let fd = unsafe { libc::fcntl(*fd, libc::F_DUPFD_CLOEXEC, 0) };
+                if fd < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                // Safe because the duplicate is ours.
+                unsafe { File::from_raw_fd(fd) }
+            }
+            ImageSource::File(file) => file.try_clone()?,
+        };
+        file.rewind()?;
+
+        Ok(ImageFile {
+            source: self.clone(),
+            file,
+        })
+    }
+
+    // Check that the image opens, seeks, and was not opened write-only.
+    fn check(&self) -> io::Result<()> {
+        let file = self.open()?;
+        // Safe because it only reads the status flags, and we check the result.
+        let flags = unsafe { libc::fcntl(file.file.as_raw_fd(), libc::F_GETFL) };
+        if flags < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if flags & libc::O_ACCMODE == libc::O_WRONLY {
+            return Err(io::Error::from_raw_os_error(libc::EBADF));
+        }
+
+        Ok(())
+    }
+}
+
+impl PartialEq for ImageSource {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (ImageSource::Path(a), ImageSource::Path(b)) => a == b,
+            (ImageSource::Fd(a), ImageSource::Fd(b)) => a == b,
+            (ImageSource::File(a), ImageSource::File(b)) => Arc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for ImageSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ImageSource::Path(path) => write!(f, "{}", path.display()),
+            ImageSource::Fd(fd) => write!(f, "fd:{}", fd),
+            ImageSource::File(file) => write!(f, "fd:{}", file.as_raw_fd()),
+        }
+    }
+}
+
+impl From<PathBuf> for ImageSource {
+    fn from(path: PathBuf) -> Self {
+        ImageSource::Path(path)
+    }
+}
+
+// A path, without the file descriptor syntax, as for the other configuration paths.
+impl From<&str> for ImageSource {
+    fn from(path: &str) -> Self {
+        ImageSource::Path(path.into())
+    }
+}
+
+impl From<File> for ImageSource {
+    fn from(file: File) -> Self {
+        ImageSource::File(Arc::new(file))
+    }
+}
+
+impl FromStr for ImageSource {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        // The VMM may not see /proc, e.g. from a chroot: the descriptor is used directly.
+        match s
+            .strip_prefix("fd:")
+            .or_else(|| s.strip_prefix("/proc/self/fd/"))
+        {
+            Some(fd) => match fd.parse::<RawFd>() {
+                Ok(fd) if fd >= 0 => Ok(ImageSource::Fd(fd)),
+                _ => Err(Error::InvalidImage(s.to_string())),
+            },
+            None if !s.is_empty() => Ok(ImageSource::Path(s.into())),
+            None => Err(Error::InvalidImage(s.to_string())),
+        }
+    }
+}
+
+/// An open boot image, which the load errors name after its source.
+pub struct ImageFile {
+    source: ImageSource,
+    file: File,
+}
+
+impl ImageFile {
+    pub fn source(&self) -> &ImageSource {
+        &self.source
+    }
+
+    /// Size of the image, in bytes.
+    pub fn size(&self) -> io::Result<u64> {
+        Ok(self.file.metadata()?.len())
+    }
+}
+
+impl Read for ImageFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl Seek for ImageFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.file.seek(pos)
+    }
+}
+
+/// Where the guest RAM comes from, instead of zeroed anonymous memory.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MemoryBackend {
+    /// Private mapping of a template file, the size of the RAM. The guest sees its content,
+    /// but its writes only go to copies of the pages: the VMs started from a template share
+    /// the untouched pages in the page cache.
+    File(PathBuf),
+}
+
+impl FromStr for MemoryBackend {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once('=') {
+            Some(("file", path)) if !path.is_empty() => Ok(MemoryBackend::File(path.into())),
+            _ => Err(Error::InvalidMemoryBackend(s.to_string())),
+        }
+    }
+}
+
+/// How the guest RAM is shared with other processes, which map it read-only to look into
+/// the running guest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MemoryShare {
+    /// The RAM is a memfd, whose descriptor the API sends.
+    Memfd,
+}
+
+impl std::fmt::Display for MemoryShare {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            MemoryShare::Memfd => write!(f, "memfd"),
+        }
+    }
+}
+
+impl FromStr for MemoryShare {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "memfd" => Ok(MemoryShare::Memfd),
+            _ => Err(Error::InvalidMemoryShare(s.to_string())),
+        }
+    }
+}
+
+/// Whether KSM may merge the guest memory pages with identical ones, e.g. those of other
+/// VMs running the same guest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KsmMode {
+    On,
+    Off,
+}
+
+impl std::fmt::Display for KsmMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            KsmMode::On => "on",
+            KsmMode::Off => "off",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for KsmMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "on" => Ok(KsmMode::On),
+            "off" => Ok(KsmMode::Off),
+            _ => Err(Error::InvalidKsmMode(s.to_string())),
+        }
+    }
+}
+
+/// Whether the guest memory goes in transparent huge pages.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ThpMode {
+    /// Whenever possible, whatever the host default is.
+    Always,
+    /// Never, which avoids the latency spikes of the page compaction.
+    Never,
+    /// As the host decides, from /sys/kernel/mm/transparent_hugepage/enabled.
+    #[default]
+    Default,
+}
+
+impl std::fmt::Display for ThpMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            ThpMode::Always => "always",
+            ThpMode::Never => "never",
+            ThpMode::Default => "default",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for ThpMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "always" => Ok(ThpMode::Always),
+            "never" => Ok(ThpMode::Never),
+            "default" => Ok(ThpMode::Default),
+            _ => Err(Error::InvalidThpMode(s.to_string())),
+        }
+    }
+}
+
+/// Guest memory size: a number with a binary unit, K, M, G or T, optionally followed by B
+/// or iB, e.g. 512M or 2GiB. A bare number is in MiB.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemorySize(u64);
+
+impl MemorySize {
+    /// The size, in bytes.
+    pub fn bytes(self) -> u64 {
+        self.0
+    }
+}
+
+impl FromStr for MemorySize {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = |reason| Error::InvalidMemorySize {
+            size: s.to_string(),
+            reason,
+        };
+
+        let size = s.trim().to_ascii_lowercase();
+        let digits = size
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(size.len());
+        let (value, unit) = size.split_at(digits);
+        if value.is_empty() {
+            return Err(invalid("missing number"));
+        }
+        let shift = match unit.trim_start() {
+            // MiB, as before the units.
+            "" => 20,
+            "b" => 0,
+            "k" | "kb" | "kib" => 10,
+            "m" | "mb" | "mib" => 20,
+            "g" | "gb" | "gib" => 30,
+            "t" | "tb" | "tib" => 40,
+            unit if unit.starts_with('.') => {
+                return Err(invalid("use a smaller unit for fractions"))
+            }
+            _ => return Err(invalid("unknown unit")),
+        };
+
+        let bytes = value
+            .parse::<u64>()
+            .ok()
+            .and_then(|value| value.checked_mul(1 << shift))
+            .ok_or_else(|| invalid("too large"))?;
+        // The RAM regions are laid out in MiB.
+        if bytes % (1 << 20) != 0 {
+            return Err(invalid("not a whole number of MiB"));
+        }
+
+        Ok(MemorySize(bytes))
+    }
+}
+
+/// What the VMM does when the guest stops petting the watchdog.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum WatchdogAction {
+    /// Stop the VM.
+    #[default]
+    Poweroff,
+    /// Restart the guest in place, as on a reboot, when the VM restarts on reboot and may
+    /// reboot again. Otherwise, stop the VM, reporting a guest reset for the caller to
+    /// restart it.
+    Reset,
+}
+
+/// Watchdog device, detecting hung guests.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WatchdogConfig {
+    /// How long the guest may go without petting the watchdog, once it enabled it.
+    pub timeout: Duration,
+    pub action: WatchdogAction,
+}
+
+impl FromStr for WatchdogConfig {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::InvalidWatchdog(s.to_string());
+        let mut timeout = None;
+        let mut action = WatchdogAction::default();
+
+        for option in s.split(',') {
+            match option.split_once('=') {
+                Some(("timeout", seconds)) => {
+                    let seconds = seconds.parse::<u64>().map_err(|_| invalid())?;
+                    if seconds == 0 {
+                        return Err(invalid());
+                    }
+                    timeout = Some(Duration::from_secs(seconds));
+                }
+                Some(("action", "poweroff")) => action = WatchdogAction::Poweroff,
+                Some(("action", "reset")) => action = WatchdogAction::Reset,
+                _ => return Err(invalid()),
+            }
+        }
+
+        Ok(WatchdogConfig {
+            timeout: timeout.ok_or_else(invalid)?,
+            action,
+        })
+    }
+}
+
+/// GDB stub, which a GDB client debugs the guest kernel through.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GdbConfig {
+    /// Address and port to listen on, the loopback address unless given one.
+    pub addr: SocketAddrV4,
+}
+
+impl FromStr for GdbConfig {
+    type Err = Error;
+
+    // tcp:[<address>]:<port>, as with QEMU.
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::InvalidGdb(s.to_string());
+        let (address, port) = s
+            .strip_prefix("tcp:")
+            .and_then(|s| s.split_once(':'))
+            .ok_or_else(invalid)?;
+        let address = match address {
+            "" => Ipv4Addr::LOCALHOST,
+            address => address.parse().map_err(|_| invalid())?,
+        };
+        let port = port.parse().map_err(|_| invalid())?;
+
+        Ok(GdbConfig {
+            addr: SocketAddrV4::new(address, port),
+        })
+    }
+}
+
+/// The UUID of the VM, as the guest reads it from the SMBIOS tables.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Uuid(pub [u8; 16]);
+
+impl Uuid {
+    /// A random (version 4) UUID.
+    pub fn random() -> io::Result<Self> {
+        let mut bytes = [0u8; 16];
+        // Safe because the buffer is valid for its length, and we check the result.
+        let ret = unsafe { libc::getrandom(bytes.as_mut_ptr().cast(), bytes.len(), 0) };
+        if ret != bytes.len() as isize {
+            return Err(io::Error::last_os_error());
+        }
+        bytes[6] = (bytes[6] & 0x0f) | 0x40;
+        bytes[8] = (bytes[8] & 0x3f) | 0x80;
+
+        Ok(Uuid(bytes))
+    }
+}
+
+impl FromStr for Uuid {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::InvalidUuid(s.to_string());
+        let groups: Vec<&str> = s.split('-').collect();
+        let digits = groups.concat();
+        if groups.iter().map(|group| group.len()).ne([8, 4, 4, 4, 12])
+            || !digits.chars().all(|c| c.is_ascii_hexdigit())
+        {
+            return Err(invalid());
+        }
+
+        let mut bytes = [0u8; 16];
+        for (index, byte) in bytes.iter_mut().enumerate() {
+            // The digits are ASCII, two of them make each byte.
+            *byte = u8::from_str_radix(&digits[2 * index..2 * index + 2], 16).unwrap();
+        }
+
+        Ok(Uuid(bytes))
+    }
+}
+
+impl std::fmt::Display for Uuid {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for (index, byte) in self.0.iter().enumerate() {
+            if [4, 6, 8, 10].contains(&index) {
+                write!(f, "-")?;
+            }
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+/// Strings of the SMBIOS tables, overriding the defaults: `lumper` as the product, and the
+/// UUID as the serial number.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SmbiosConfig {
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub version: Option<String>,
+    pub serial: Option<String>,
+    pub sku: Option<String>,
+    pub family: Option<String>,
+}
+
+impl FromStr for SmbiosConfig {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::InvalidSmbios(s.to_string());
+        let mut config = SmbiosConfig::default();
+
+        for option in s.split(',') {
+            let (key, value) = option.split_once('=').ok_or_else(invalid)?;
+            // The tables end their strings with a NUL, and have no empty ones.
+            if value.is_empty() || value.contains('\0') {
+                return Err(invalid());
+            }
+            let field = match key {
+                "manufacturer" => &mut config.manufacturer,
+                "product" => &mut config.product,
+                "version" => &mut config.version,
+                "serial" => &mut config.serial,
+                "sku" => &mut config.sku,
+                "family" => &mut config.family,
+                _ => return Err(invalid()),
+            };
+            *field = Some(value.to_string());
+        }
+
+        Ok(config)
+    }
+}
+
+/// Unprivileged identity and empty root the VMM switches to before running the guest, see
+/// [`jail`](crate::jail).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct JailConfig {
+    pub uid: u32,
+    pub gid: u32,
+    /// Directory the VMM is chrooted to, preferably empty.
+    pub chroot: PathBuf,
+    /// Also move to new mount, UTS and IPC namespaces.
+    pub unshare: bool,
+}
+
+impl FromStr for JailConfig {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::InvalidJail(s.to_string());
+        let mut uid = None;
+        let mut gid = None;
+        let mut chroot = None;
+        let mut unshare = false;
+
+        for option in s.split(',') {
+            match option.split_once('=') {
+                Some(("uid", value)) => uid = Some(value.parse::<u32>().map_err(|_| invalid())?),
+                Some(("gid", value)) => gid = Some(value.parse::<u32>().map_err(|_| invalid())?),
+                Some(("chroot", path)) if !path.is_empty() => chroot = Some(PathBuf::from(path)),
+                Some(("unshare", "on")) => unshare = true,
+                Some(("unshare", "off")) => unshare = false,
+                _ => return Err(invalid()),
+            }
+        }
+
+        // Staying root in the jail would defeat it.
+        let uid = uid.filter(|uid| *uid != 0).ok_or_else(invalid)?;
+        Ok(JailConfig {
+            uid,
+            gid: gid.ok_or_else(invalid)?,
+            chroot: chroot.ok_or_else(invalid)?,
+            unshare,
+        })
+    }
+}
+
+/// CPU bandwidth of a cgroup: it runs for `quota` microseconds every `period`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CpuMax {
+    /// Runtime per period, in microseconds, unlimited if `None`.
+    pub quota: Option<u64>,
+    /// Period, in microseconds.
+    pub period: u64,
+}
+
+impl CpuMax {
+    // The kernel limits, in microseconds.
+    const MIN_QUOTA: u64 = 1000;
+    const MIN_PERIOD: u64 = 1000;
+    const MAX_PERIOD: u64 = 1_000_000;
+    const DEFAULT_PERIOD: u64 = 100_000;
+}
+
+/// cgroup v2 the VMM moves to, with the limits of the whole VM, see
+/// [`cgroup`](crate::cgroup).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CgroupConfig {
+    /// cgroup directory, created in a delegated cgroup v2 tree.
+    pub path: PathBuf,
+    /// Written to `cpu.max`.
+    pub cpu_max: Option<CpuMax>,
+    /// Written to `memory.max`, in bytes.
+    pub memory_max: Option<u64>,
+    /// Written to `io.weight`, from 1 to 10000.
+    pub io_weight: Option<u16>,
+}
+
+impl FromStr for CgroupConfig {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::InvalidCgroup(s.to_string());
+        let mut path = None;
+        let mut cpu_max = None;
+        let mut memory_max = None;
+        let mut io_weight = None;
+
+        for option in s.split(',') {
+            match option.split_once('=') {
+                Some(("path", value))
+                    if value.starts_with('/') && Path::new(value).file_name().is_some() =>
+                {
+                    path = Some(value.into())
+                }
+                Some(("cpu-max", value)) => {
+                    let mut fields = value.split_whitespace();
+                    let quota = match fields.next() {
+                        Some("max") => None,
+                        Some(quota) => Some(
+                            quota
+                                .parse::<u64>()
+                                .ok()
+                                .filter(|quota| *quota >= CpuMax::MIN_QUOTA)
+                                .ok_or_else(invalid)?,
+                        ),
+                        None => return Err(invalid()),
+                    };
+                    let period = match fields.next() {
+                        Some(period) => period
+                            .parse::<u64>()
+                            .ok()
+                            .filter(|period| {
+                                (CpuMax::MIN_PERIOD..=CpuMax::MAX_PERIOD).contains(period)
+                            })
+                            .ok_or_else(invalid)?,
+                        None => CpuMax::DEFAULT_PERIOD,
+                    };
+                    if fields.next().is_some() {
+                        return Err(invalid());
+                    }
+                    cpu_max = Some(CpuMax { quota, period });
+                }
+                Some(("mem-max", value)) => {
+                    memory_max = Some(value.parse::<MemorySize>().map_err(|_| invalid())?.bytes())
+                }
+                Some(("io-weight", value)) => {
+                    io_weight = Some(
+                        value
+                            .parse::<u16>()
+                            .ok()
+                            .filter(|weight| (1..=10000).contains(weight))
+                            .ok_or_else(invalid)?,
+                    )
+                }
+                _ => return Err(invalid()),
+            }
+        }
+
+        Ok(CgroupConfig {
+            path: path.ok_or_else(invalid)?,
+            cpu_max,
+            memory_max,
+            io_weight,
+        })
+    }
+}
+
+// Parse a bandwidth, in bits per second with an optional k, m or g decimal prefix,
+// to bytes per second.
+fn parse_rate(rate: &str) -> Option<u64> {
+    let bits = rate.to_ascii_lowercase();
+    let bits = bits.strip_suffix("bps")?;
+    let (value, multiplier) = match bits.char_indices().last()? {
+        (i, 'k') => (&bits[..i], 1_000),
+        (i, 'm') => (&bits[..i], 1_000_000),
+        (i, 'g') => (&bits[..i], 1_000_000_000),
+        _ => (bits, 1),
+    };
+
+    let bytes = value.parse::<u64>().ok()?.checked_mul(multiplier)? / 8;
+    (bytes > 0).then_some(bytes)
+}
+
+// Parse a size, in bytes with an optional k, m or g binary prefix.
+fn parse_size(size: &str) -> Option<u64> {
+    let size = size.to_ascii_lowercase();
+    let size = size.strip_suffix('b').unwrap_or(&size);
+    let (value, shift) = match size.char_indices().last()? {
+        (i, 'k') => (&size[..i], 10),
+        (i, 'm') => (&size[..i], 20),
+        (i, 'g') => (&size[..i], 30),
+        _ => (size, 0),
+    };
+
+    let bytes = value.parse::<u64>().ok()?.checked_mul(1 << shift)?;
+    (bytes > 0).then_some(bytes)
+}
+
+// Parse a duration, in seconds or in milliseconds with the ms suffix.
+fn parse_duration(duration: &str) -> Option<Duration> {
+    let duration = match duration.strip_suffix("ms") {
+        Some(millis) => Duration::from_millis(millis.parse().ok()?),
+        None => Duration::from_secs(duration.strip_suffix('s')?.parse().ok()?),
+    };
+    (!duration.is_zero()).then_some(duration)
+}
+
+// Smallest and largest virtio queue sizes a device may offer.
+const MIN_QUEUE_SIZE: u16 = 64;
+const MAX_QUEUE_SIZE: u16 = 32768;
+
+// Parse the size of a virtio queue: a power of two the driver can negotiate, which is not
+// too small for the device to keep up.
+fn parse_queue_size(size: &str) -> Option<u16> {
+    let size: u16 = size.parse().ok()?;
+    (size.is_power_of_two() && (MIN_QUEUE_SIZE..=MAX_QUEUE_SIZE).contains(&size)).then_some(size)
+}
+
+/// Where the guest network traffic goes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum NetBackend {
+    /// TAP interface, by name.
+    Tap(String),
+    /// Userspace network stack, relaying the guest connections to host sockets. It needs
+    /// no privileges.
+    User(UserNetConfig),
+}
+
+impl Default for NetBackend {
+    fn default() -> Self {
+        NetBackend::Tap(String::new())
+    }
+}
+
+/// Userspace network stack. The guest gets 10.0.2.15 through DHCP, on a network where the
+/// VMM is the gateway (10.0.2.2) and the DNS server (10.0.2.3).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct UserNetConfig {
+    /// Host ports forwarded to the guest.
+    pub hostfwd: Vec<HostForward>,
+}
+
+/// TCP port forwarded from the host to the guest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HostForward {
+    /// Host address and port to listen on. The unspecified address listens on all the host
+    /// interfaces.
+    pub host: SocketAddrV4,
+    /// Guest address, the one it gets through DHCP by default.
+    pub guest_addr: Option<Ipv4Addr>,
+    /// Guest port.
+    pub guest_port: u16,
+}
+
+impl FromStr for HostForward {
+    type Err = Error;
+
+    // tcp:[<host address>]:<host port>-[<guest address>]:<guest port>, as with QEMU.
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::InvalidNet(s.to_string());
+        let endpoint = |endpoint: &str| -> Result<(Option<Ipv4Addr>, u16)> {
+            let (address, port) = endpoint.split_once(':').ok_or_else(invalid)?;
+            let address = match address {
+                "" => None,
+                address => Some(address.parse().map_err(|_| invalid())?),
+            };
+            let port = port.parse().ok().filter(|port| *port > 0);
+            Ok((address, port.ok_or_else(invalid)?))
+        };
+
+        // Only TCP is forwarded, which is also the default.
+        let s = s.strip_prefix("tcp").unwrap_or(s);
+        let (host, guest) = s
+            .strip_prefix(':')
+            .and_then(|s| s.split_once('-'))
+            .ok_or_else(invalid)?;
+        let (host_addr, host_port) = endpoint(host)?;
+        let (guest_addr, guest_port) = endpoint(guest)?;
+
+        Ok(HostForward {
+            host: SocketAddrV4::new(host_addr.unwrap_or(Ipv4Addr::UNSPECIFIED), host_port),
+            guest_addr,
+            guest_port,
+        })
+    }
+}
+
+/// DHCP server answering the guest on the host side of a TAP interface, which must carry
+/// the server address.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DhcpServerConfig {
+    /// Server address, handed out as the gateway.
+    pub address: Ipv4Addr,
+    /// Length of the network prefix.
+    pub prefix_len: u8,
+    /// First and last addresses handed out. Defaults to the rest of the network, past the
+    /// server address.
+    pub range: (Ipv4Addr, Ipv4Addr),
+    /// DNS server handed out. Defaults to the host nameserver, unless it is a loopback
+    /// address.
+    pub dns: Option<Ipv4Addr>,
+}
+
+impl DhcpServerConfig {
+    /// The network mask.
+    pub fn netmask(&self) -> Ipv4Addr {
+        Ipv4Addr::from(
+            u32::MAX
+                .checked_shl(32 - u32::from(self.prefix_len))
+                .unwrap_or(0),
+        )
+    }
+
+    /// Whether the address is on the server network.
+    pub fn contains(&self, address: Ipv4Addr) -> bool {
+        let netmask = u32::from(self.netmask());
+        u32::from(address) & netmask == u32::from(self.address) & netmask
+    }
+}
+
+impl FromStr for DhcpServerConfig {
+    type Err = Error;
+
+    // <address>/<prefix>, with the range past the server address.
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::InvalidNet(s.to_string());
+        let (address, prefix_len) = s.split_once('/').ok_or_else(invalid)?;
+        let address: Ipv4Addr = address.parse().map_err(|_| invalid())?;
+        let prefix_len = prefix_len
+            .parse()
+            .ok()
+            .filter(|len| (1..=30).contains(len))
+            .ok_or_else(invalid)?;
+
+        let mut dhcp = DhcpServerConfig {
+            address,
+            prefix_len,
+            range: (address, address),
+            dns: None,
+        };
+        let broadcast = u32::from(address) | !u32::from(dhcp.netmask());
+        dhcp.range = (
+            Ipv4Addr::from(u32::from(address).wrapping_add(1)),
+            Ipv4Addr::from(broadcast - 1),
+        );
+
+        Ok(dhcp)
+    }
+}
+
+// <first address>-<last address>
+fn parse_range(range: &str) -> Option<(Ipv4Addr, Ipv4Addr)> {
+    let (first, last) = range.split_once('-')?;
+    Some((first.parse().ok()?, last.parse().ok()?))
+}
+
+/// Ethernet address.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MacAddress(pub [u8; 6]);
+
+impl FromStr for MacAddress {
+    type Err = Error;
+
+    // Six hexadecimal bytes separated by colons, e.g. 52:54:00:12:34:56. Group addresses
+    // cannot be assigned to an interface.
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::InvalidNet(s.to_string());
+        let mut mac = [0u8; 6];
+        let mut bytes = s.split(':');
+        for byte in mac.iter_mut() {
+            *byte = bytes
+                .next()
+                .filter(|byte| byte.len() == 2 && byte.bytes().all(|b| b.is_ascii_hexdigit()))
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                .ok_or_else(invalid)?;
+        }
+        if bytes.next().is_some() || mac[0] & 1 != 0 {
+            return Err(invalid());
+        }
+
+        Ok(MacAddress(mac))
+    }
+}
+
+impl std::fmt::Display for MacAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(
+            f,
+            "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+            a, b, c, d, e, g
+        )
+    }
+}
+
+/// Guest network interface.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct NetConfig {
+    /// Where the traffic goes.
+    pub backend: NetBackend,
+    /// DHCP server for the guest, on the TAP interface.
+    pub dhcp: Option<DhcpServerConfig>,
+    /// Guest receive bandwidth limit, in bytes per second.
+    pub rx_rate: Option<u64>,
+    /// Guest transmit bandwidth limit, in bytes per second.
+    pub tx_rate: Option<u64>,
+    /// Guest receive limit, in frames per second.
+    pub rx_ops: Option<u64>,
+    /// Guest transmit limit, in frames per second.
+    pub tx_ops: Option<u64>,
+    /// Bytes that can go through at once, above the bandwidth limits. Defaults to one
+    /// second worth of traffic.
+    pub burst: Option<u64>,
+    /// Move the datapath to the host kernel with vhost-net. It cannot be rate limited, and
+    /// needs a TAP interface.
+    pub vhost: bool,
+    /// Guest Ethernet address. The guest picks a random one without it.
+    pub mac: Option<MacAddress>,
+    /// How long to keep trying to attach to a busy TAP interface, e.g. one the previous
+    /// VMM did not release yet. It fails at once without.
+    pub attach_timeout: Option<Duration>,
+    /// Entries of the receive queue the device offers. Defaults to 256.
+    pub rx_queue_size: Option<u16>,
+    /// Entries of the transmit queue the device offers. Defaults to 256.
+    pub tx_queue_size: Option<u16>,
+    /// Host bridge the TAP interface is attached to.
+    pub bridge: Option<BridgeConfig>,
+}
+
+/// Host bridge a TAP interface is attached to, see [`NetConfig::bridge`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BridgeConfig {
+    /// Name of the bridge.
+    pub name: String,
+    /// Create the bridge when it does not exist. The VMM deletes the bridges it created
+    /// once it stops.
+    pub create: bool,
+}
+
+impl FromStr for NetConfig {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::InvalidNet(s.to_string());
+        let mut options = s.split(',');
+
+        // A TAP interface cannot be named `user`.
+        let backend = match options.next() {
+            Some("user") => NetBackend::User(UserNetConfig::default()),
+            Some(name) if !name.is_empty() && !name.contains('=') => {
+                NetBackend::Tap(name.to_string())
+            }
+            _ => return Err(invalid()),
+        };
+        let mut net = NetConfig {
+            backend,
+            ..Default::default()
+        };
+        // The range and DNS server may come before the DHCP server itself.
+        let mut range = None;
+        let mut dns = None;
+        let mut create_bridge = false;
+
+        for option in options {
+            if option == "create-bridge" {
+                create_bridge = true;
+                continue;
+            }
+            let (key, value) = option.split_once('=').ok_or_else(invalid)?;
+            if key == "vhost" {
+                net.vhost = match value {
+                    "on" => true,
+                    "off" => false,
+                    _ => return Err(invalid()),
+                };
+                continue;
+            }
+            if key == "hostfwd" {
+                match &mut net.backend {
+                    NetBackend::User(user) => user.hostfwd.push(value.parse()?),
+                    NetBackend::Tap(_) => return Err(invalid()),
+                }
+                continue;
+            }
+            match key {
+                "mac" => {
+                    net.mac = Some(value.parse().map_err(|_| invalid())?);
+                    continue;
+                }
+                "dhcp-server" => {
+                    net.dhcp = Some(value.parse()?);
+                    continue;
+                }
+                "range" => {
+                    range = Some(parse_range(value).ok_or_else(invalid)?);
+                    continue;
+                }
+                "dns" => {
+                    dns = Some(value.parse().map_err(|_| invalid())?);
+                    continue;
+                }
+                "attach-timeout" if matches!(net.backend, NetBackend::Tap(_)) => {
+                    net.attach_timeout = Some(parse_duration(value).ok_or_else(invalid)?);
+                    continue;
+                }
+                // As long as an interface name may be.
+                "bridge"
+                    if matches!(net.backend, NetBackend::Tap(_))
+                        && (1..16).contains(&value.len())
+                        && !value.contains(['/', ' ']) =>
+                {
+                    net.bridge = Some(BridgeConfig {
+                        name: value.to_string(),
+                        create: false,
+                    });
+                    continue;
+                }
+                "rx_queue" => {
+                    net.rx_queue_size = Some(parse_queue_size(value).ok_or_else(invalid)?);
+                    continue;
+                }
+                "tx_queue" => {
+                    net.tx_queue_size = Some(parse_queue_size(value).ok_or_else(invalid)?);
+                    continue;
+                }
+                _ => {}
+            }
+
+            let value = match key {
+                "rx_rate" | "tx_rate" => parse_rate(value),
+                "rx_ops" | "tx_ops" => value.parse().ok().filter(|ops| *ops > 0),
+                "burst" => parse_size(value),
+                _ => None,
+            };
+            let value = Some(value.ok_or_else(invalid)?);
+
+            match key {
+                "rx_rate" => net.rx_rate = value,
+                "tx_rate" => net.tx_rate = value,
+                "rx_ops" => net.rx_ops = value,
+                "tx_ops" => net.tx_ops = value,
+                _ => net.burst = value,
+            }
+        }
+
+        match net.bridge.as_mut() {
+            Some(bridge) => bridge.create = create_bridge,
+            None if create_bridge => return Err(invalid()),
+            None => {}
+        }
+
+        // The host kernel moves the frames, out of reach of the rate limiters, and between
+        // the virtqueues and a tap.
+        let limited = [net.rx_rate, net.tx_rate, net.rx_ops, net.tx_ops, net.burst]
+            .iter()
+            .any(Option::is_some);
+        if net.vhost && (limited || matches!(net.backend, NetBackend::User(_))) {
+            return Err(invalid());
+        }
+
+        // The user backend has its own DHCP server.
+        match net.dhcp.as_mut() {
+            Some(_) if matches!(net.backend, NetBackend::User(_)) => return Err(invalid()),
+            Some(dhcp) => {
+                if let Some(range) = range {
+                    dhcp.range = range;
+                }
+                dhcp.dns = dns;
+
+                // The range is on the server network, without the server address.
+                let (first, last) = dhcp.range;
+                if first > last
+                    || !dhcp.contains(first)
+                    || !dhcp.contains(last)
+                    || (first..=last).contains(&dhcp.address)
+                {
+                    return Err(invalid());
+                }
+            }
+            None if range.is_some() || dns.is_some() => return Err(invalid()),
+            None => {}
+        }
+
+        Ok(net)
+    }
+}
+
+/// Guest visible CPU topology.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CpuTopology {
+    pub sockets: u8,
+    pub cores_per_socket: u8,
+    pub threads_per_core: u8,
+}
+
+// Number of bits needed to encode `count` different IDs.
+fn id_bits(count: u8) -> u32 {
+    u8::BITS - count.saturating_sub(1).leading_zeros()
+}
+
+impl CpuTopology {
+    /// All `cpus` cores in a single socket, with one thread per core.
+    pub fn flat(cpus: u8) -> Self {
+        CpuTopology {
+            sockets: 1,
+            cores_per_socket: cpus,
+            threads_per_core: 1,
+        }
+    }
+
+    /// Total number of vCPUs.
+    pub fn vcpu_count(&self) -> u32 {
+        u32::from(self.sockets)
+            * u32::from(self.cores_per_socket)
+            * u32::from(self.threads_per_core)
+    }
+
+    /// Number of logical processors in a socket.
+    pub fn threads_per_socket(&self) -> u32 {
+        u32::from(self.cores_per_socket) * u32::from(self.threads_per_core)
+    }
+
+    /// Width of the thread ID field of the APIC IDs.
+    pub(crate) fn thread_bits(&self) -> u32 {
+        id_bits(self.threads_per_core)
+    }
+
+    /// Width of the core and thread ID fields of the APIC IDs.
+    pub(crate) fn core_bits(&self) -> u32 {
+        self.thread_bits() + id_bits(self.cores_per_socket)
+    }
+
+    /// Initial APIC ID of the vCPU `index`, encoding its socket, core and thread IDs.
+    pub(crate) fn apic_id(&self, index: u8) -> u32 {
+        let index = u32::from(index);
+        let threads = u32::from(self.threads_per_core);
+        let thread = index % threads;
+        let core = (index / threads) % u32::from(self.cores_per_socket);
+        let socket = index / self.threads_per_socket();
+
+        (socket << self.core_bits()) | (core << self.thread_bits()) | thread
+    }
+
+    /// APIC ID of the IOAPIC. One ID is left free after the last vCPU one.
+    pub(crate) fn ioapic_id(&self) -> u32 {
+        match self.vcpu_count().checked_sub(1) {
+            Some(last) => self.apic_id(last as u8) + 2,
+            None => 1,
+        }
+    }
+}
+
+impl std::fmt::Display for CpuTopology {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}",
+            self.sockets, self.cores_per_socket, self.threads_per_core
+        )
+    }
+}
+
+impl FromStr for CpuTopology {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let values = s
+            .split(':')
+            .map(|v| v.parse::<u8>().ok().filter(|v| *v > 0))
+            .collect::<Option<Vec<u8>>>();
+
+        match values.as_deref() {
+            Some([sockets, cores_per_socket, threads_per_core]) => Ok(CpuTopology {
+                sockets: *sockets,
+                cores_per_socket: *cores_per_socket,
+                threads_per_core: *threads_per_core,
+            }),
+            _ => Err(Error::InvalidTopology(s.to_string())),
+        }
+    }
+}
+
+/// CPU template exposed to the guest: its CPUID features and MSRs.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CpuTemplate {
+    /// Everything KVM supports on the host.
+    #[default]
+    Passthrough,
+    /// Haswell class CPU features (AVX2).
+    T2,
+    /// Cascade Lake class CPU features (AVX-512).
+    C3,
+}
+
+impl CpuTemplate {
+    /// All the templates.
+    pub const ALL: [CpuTemplate; 3] = [CpuTemplate::Passthrough, CpuTemplate::T2, CpuTemplate::C3];
+}
+
+impl std::fmt::Display for CpuTemplate {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            CpuTemplate::Passthrough => "passthrough",
+            CpuTemplate::T2 => "t2",
+            CpuTemplate::C3 => "c3",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for CpuTemplate {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "passthrough" => Ok(CpuTemplate::Passthrough),
+            "t2" => Ok(CpuTemplate::T2),
+            "c3" => Ok(CpuTemplate::C3),
+            _ => Err(Error::InvalidCpuTemplate(s.to_string())),
+        }
+    }
+}
+
+/// Which interrupt controllers KVM emulates.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IrqchipMode {
+    /// The PICs, the IOAPIC and the local APICs.
+    #[default]
+    Full,
+    /// Only the local APICs, the VMM emulates the IOAPIC, without PICs.
+    Split,
+}
+
+impl IrqchipMode {
+    /// All the modes, not all of them supported on every architecture.
+    pub const ALL: [IrqchipMode; 2] = [IrqchipMode::Full, IrqchipMode::Split];
+}
+
+impl std::fmt::Display for IrqchipMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            IrqchipMode::Full => "full",
+            IrqchipMode::Split => "split",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for IrqchipMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "full" => Ok(IrqchipMode::Full),
+            "split" => Ok(IrqchipMode::Split),
+            _ => Err(Error::InvalidIrqchip(s.to_string())),
+        }
+    }
+}
+
+/// KVM paravirtual feature, which the guest uses when both KVM and its kernel support it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PvFeature {
+    /// Asynchronous page faults: the guest schedules another task while the host swaps a
+    /// page in.
+    AsyncPf,
+    /// Steal time accounting: the time the vCPU waited for a host CPU.
+    StealTime,
+    /// End of interrupts without VM exits.
+    PvEoi,
+    /// Paravirtual spinlocks, which halt instead of spinning on a preempted vCPU.
+    PvUnhalt,
+}
+
+impl PvFeature {
+    /// All the features.
+    pub const ALL: [PvFeature; 4] = [
+        PvFeature::AsyncPf,
+        PvFeature::StealTime,
+        PvFeature::PvEoi,
+        PvFeature::PvUnhalt,
+    ];
+
+    /// The name of the feature, in [`PvFeatures`] lists.
+    pub fn name(self) -> &'static str {
+        match self {
+            PvFeature::AsyncPf => "async-pf",
+            PvFeature::StealTime => "steal-time",
+            PvFeature::PvEoi => "pv-eoi",
+            PvFeature::PvUnhalt => "pv-unhalt",
+        }
+    }
+
+    fn bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+/// The KVM paravirtual features offered to the guest, all of them by default: `all`,
+/// `none` or a comma separated list of `async-pf`, `steal-time`, `pv-eoi` and `pv-unhalt`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PvFeatures(u8);
+
+impl PvFeatures {
+    pub fn all() -> Self {
+        PvFeature::ALL
+            .iter()
+            .fold(PvFeatures::none(), |features, feature| {
+                features.with(*feature)
+            })
+    }
+
+    pub fn none() -> Self {
+        PvFeatures(0)
+    }
+
+    pub fn with(self, feature: PvFeature) -> Self {
+        PvFeatures(self.0 | feature.bit())
+    }
+
+    pub fn contains(self, feature: PvFeature) -> bool {
+        self.0 & feature.bit() != 0
+    }
+}
+
+impl Default for PvFeatures {
+    fn default() -> Self {
+        PvFeatures::all()
+    }
+}
+
+impl std::fmt::Display for PvFeatures {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if *self == PvFeatures::none() {
+            return write!(f, "none");
+        }
+        let names: Vec<_> = PvFeature::ALL
+            .iter()
+            .filter(|feature| self.contains(**feature))
+            .map(|feature| feature.name())
+            .collect();
+        write!(f, "{}", names.join(","))
+    }
+}
+
+impl FromStr for PvFeatures {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "all" => return Ok(PvFeatures::all()),
+            "none" => return Ok(PvFeatures::none()),
+            _ => {}
+        }
+
+        s.to_lowercase()
+            .split(',')
+            .try_fold(PvFeatures::none(), |features, name| {
+                PvFeature::ALL
+                    .iter()
+                    .find(|feature| feature.name() == name.trim())
+                    .map(|feature| features.with(*feature))
+                    .ok_or_else(|| Error::InvalidPvFeatures(s.to_string()))
+            })
+    }
+}
+
+// The kernel command line parameters the guest only takes once, the last one given.
+const SINGLE_CMDLINE_PARAMS: [&str; 4] = ["console", "root", "init", "panic"];
+
+// A kernel command line parameter, as written.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct CmdlineParam {
+    key: String,
+    value: Option<String>,
+}
+
+impl CmdlineParam {
+    // The key as the kernel compares it, without quotes, with dashes and underscores alike.
+    fn name(&self) -> String {
+        self.key.replace('"', "").replace('-', "_")
+    }
+
+    fn single(&self) -> bool {
+        SINGLE_CMDLINE_PARAMS.contains(&self.name().as_str())
+    }
+}
+
+impl fmt::Display for CmdlineParam {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.value.as_ref() {
+            Some(value) => write!(f, "{}={}", self.key, value),
+            None => write!(f, "{}", self.key),
+        }
+    }
+}
+
+/// A kernel command line, composed from parts.
+///
+/// Each part replaces the `console`, `root`, `init` and `panic` parameters the previous
+/// ones gave, instead of leaving the guest with both, and conflicts with itself when it gives
+/// one twice. The other parameters add up, e.g. `virtio_mmio.device`. The arguments after a
+/// `--` go to init, after those of the previous parts.
+#[derive(Clone, Debug)]
+pub struct CmdlineBuilder {
+    params: Vec<CmdlineParam>,
+    init_args: Vec<String>,
+    max_size: usize,
+}
+
+impl Default for CmdlineBuilder {
+    fn default() -> Self {
+        CmdlineBuilder::new(CMDLINE_MAX_SIZE)
+    }
+}
+
+impl CmdlineBuilder {
+    /// An empty command line, which must fit `max_size` bytes with its terminating NUL.
+    pub fn new(max_size: usize) -> Self {
+        CmdlineBuilder {
+            params: Vec::new(),
+            init_args: Vec::new(),
+            max_size,
+        }
+    }
+
+    /// Add the parameters of `cmdline`, quoted as the kernel reads them.
+    pub fn append(&mut self, cmdline: &str) -> Result<()> {
+        let mut words = split_cmdline(cmdline)?.into_iter();
+        let params: Vec<CmdlineParam> = words
+            .by_ref()
+            .take_while(|word| word != "--")
+            .map(|word| match word.split_once('=') {
+                Some((key, value)) => CmdlineParam {
+                    key: key.to_string(),
+                    value: Some(value.to_string()),
+                },
+                None => CmdlineParam {
+                    key: word,
+                    value: None,
+                },
+            })
+            .collect();
+
+        for (index, param) in params.iter().enumerate() {
+            let name = param.name();
+            if let Some(other) = params[..index]
+                .iter()
+                .find(|other| param.single() && other.name() == name && *other != param)
+            {
+                return Err(Error::ConflictingCmdline {
+                    first: other.to_string(),
+                    second: param.to_string(),
+                });
+            }
+        }
+        for param in params {
+            self.push(param);
+        }
+        self.init_args.extend(words);
+
+        Ok(())
+    }
+
+    /// Add the `key=value` parameter.
+    pub fn insert(&mut self, key: &str, value: &str) -> Result<()> {
+        self.append(&format!("{}={}", key, value))
+    }
+
+    /// The value of the last `key` parameter, the one the kernel takes. None for a flag.
+    pub fn value(&self, key: &str) -> Option<&str> {
+        let key = key.replace('-', "_");
+        self.params
+            .iter()
+            .rev()
+            .find(|param| param.name() == key)
+            .and_then(|param| param.value.as_deref())
+    }
+
+    // Replace the parameter the guest only takes once, or add it.
+    fn push(&mut self, param: CmdlineParam) {
+        if param.single() {
+            let name = param.name();
+            if let Some(existing) = self.params.iter_mut().find(|other| other.name() == name) {
+                *existing = param;
+                return;
+            }
+        } else if self.params.contains(&param) && param.value.is_none() {
+            // Flags only need saying once.
+            return;
+        }
+        self.params.push(param);
+    }
+
+    /// The command line, once checked to fit.
+    pub fn build(&self) -> Result<String> {
+        let mut words: Vec<String> = self.params.iter().map(|param| param.to_string()).collect();
+        if !self.init_args.is_empty() {
+            words.push("--".to_string());
+            words.extend(self.init_args.iter().cloned());
+        }
+        let cmdline = words.join(" ");
+
+        if cmdline.len() >= self.max_size {
+            return Err(Error::CmdlineTooLong {
+                len: cmdline.len(),
+                max_size: self.max_size,
+            });
+        }
+        Ok(cmdline)
+    }
+}
+
+// Split a kernel command line on the spaces out of double quotes, keeping the quotes.
+fn split_cmdline(cmdline: &str) -> Result<Vec<String>> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut quoted = false;
+    for c in cmdline.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                word.push(c);
+            }
+            c if c.is_ascii_whitespace() && !quoted => {
+                if !word.is_empty() {
+                    words.push(std::mem::take(&mut word));
+                }
+            }
+            c => word.push(c),
+        }
+    }
+    if quoted {
+        return Err(Error::InvalidCmdline(cmdline.to_string()));
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+
+    Ok(words)
+}
+
+/// VMM configuration.
+#[derive(Clone, Debug)]
+pub struct VMMConfig {
+    /// Number of virtual CPUs the guest boots with.
+    pub cpus: u8,
+    /// Number of virtual CPUs the guest may have, the ones past `cpus` being hot-plugged
+    /// through the API.
+    pub max_cpus: u8,
+    /// How the `max_cpus` vCPUs are spread across sockets, cores and threads.
+    pub topology: CpuTopology,
+    /// CPUID template applied to all the vCPUs.
+    pub cpu_template: CpuTemplate,
+    /// KVM paravirtual features offered to the guest, when the host has them.
+    pub pv_features: PvFeatures,
+    /// Interrupt controllers emulated by KVM.
+    pub irqchip: IrqchipMode,
+    /// Guest TSC frequency, in kHz. Defaults to the host one.
+    pub tsc_khz: Option<u32>,
+    /// Guest memory size, in bytes.
+    pub memory: u64,
+    /// Optional guest RAM backing, instead of anonymous memory.
+    pub memory_backend: Option<MemoryBackend>,
+    /// Share the guest RAM with other processes, read-only.
+    pub memory_share: Option<MemoryShare>,
+    /// Descriptor the shared guest RAM is also at, for the child processes.
+    pub memory_share_fd: Option<RawFd>,
+    /// Whether KSM may merge the guest memory pages, or as the host decides.
+    pub memory_ksm: Option<KsmMode>,
+    /// Whether the guest memory goes in transparent huge pages.
+    pub memory_thp: ThpMode,
+    /// Fail instead of warning when the host does not take the guest memory hints.
+    pub strict: bool,
+    /// Linux kernel image.
+    pub kernel: ImageSource,
+    /// Optional initramfs image.
+    pub initramfs: Option<ImageSource>,
+    /// Inflate a gzip initramfs on the host, instead of in the guest kernel.
+    pub initrd_in_memory: bool,
+    /// Extra kernel command line parameters, after the VMM ones, whose `console`, `root`,
+    /// `init` and `panic` they replace.
+    pub cmdline: Option<String>,
+    /// UUID of the VM in the SMBIOS tables, random by default.
+    pub uuid: Option<Uuid>,
+    /// Strings of the SMBIOS tables.
+    pub smbios: SmbiosConfig,
+    /// Console (ttyS0) sink.
+    pub console: ConsoleMode,
+    /// What becomes of the console output once the sink failed.
+    pub console_error_policy: ConsoleErrorPolicy,
+    /// Optional file or pipe the console input is read from, instead of stdin.
+    pub console_input: Option<PathBuf>,
+    /// Optional file the console session is recorded to, both directions.
+    pub console_record: Option<PathBuf>,
+    /// Optional recorded session sent to the console, instead of stdin.
+    pub console_replay: Option<ReplayConfig>,
+    /// Optional second serial port (ttyS1) sink, used by the agent.
+    pub serial2: Option<ConsoleMode>,
+    /// Optional TAP interface.
+    pub net: Option<NetConfig>,
+    /// Reserved virtio-net slots, which the API plugs devices into once the VM runs.
+    pub net_slots: u8,
+    /// Optional virtio-pmem device.
+    pub pmem: Option<PmemConfig>,
+    /// Optional firmware blob, loaded before the kernel starts.
+    pub firmware: Option<FirmwareConfig>,
+    /// Optional host directory shared over virtio-9p.
+    pub shared_dir: Option<SharedDirConfig>,
+    /// Stop the VMM when a guest kernel panic shows up on the console.
+    pub panic_detect: bool,
+    /// Lock the console files and the taps, failing when another instance has them.
+    pub lock_resources: bool,
+    /// Stop the VMM once the guest ran for this long.
+    pub timeout: Option<Duration>,
+    /// Restart the guest in place when it reboots, instead of stopping the VMM.
+    pub restart_on_reboot: bool,
+    /// Stop the VMM on the reboot past this many restarts, if set.
+    pub max_reboots: Option<u32>,
+    /// Also describe the vCPUs with the legacy MP table, besides the ACPI tables.
+    pub mptable: bool,
+    /// Optional watchdog device.
+    pub watchdog: Option<WatchdogConfig>,
+    /// Log the pages the guest writes to.
+    pub dirty_tracking: bool,
+    /// Record the interrupt latency of the virtio devices, served by the API.
+    pub trace_irq: bool,
+    /// Optional GDB stub, which the boot vCPU waits for a client of.
+    pub gdb: Option<GdbConfig>,
+    /// Optional Unix socket serving the API requests.
+    pub api_socket: Option<PathBuf>,
+    /// Optional FIFO the lifecycle events are written to.
+    pub event_fifo: Option<PathBuf>,
+    /// Optional jail the VMM enters once configured.
+    pub jail: Option<JailConfig>,
+    /// Optional cgroup the VMM moves to before creating the VM.
+    pub cgroup: Option<CgroupConfig>,
+}
+
+/// Builder for [`VMMConfig`].
+#[derive(Clone)]
+pub struct VMMConfigBuilder {
+    cpus: u8,
+    max_cpus: Option<u8>,
+    topology: Option<CpuTopology>,
+    cpu_template: CpuTemplate,
+    pv_features: PvFeatures,
+    irqchip: IrqchipMode,
+    tsc_khz: Option<u32>,
+    memory: u64,
+    memory_backend: Option<MemoryBackend>,
+    memory_share: Option<MemoryShare>,
+    memory_share_fd: Option<RawFd>,
+    memory_ksm: Option<KsmMode>,
+    memory_thp: ThpMode,
+    strict: bool,
+    kernel: Option<ImageSource>,
+    initramfs: Option<ImageSource>,
+    initrd_in_memory: bool,
+    cmdline: Option<String>,
+    append: Vec<String>,
+    uuid: Option<Uuid>,
+    smbios: SmbiosConfig,
+    console: ConsoleMode,
+    console_error_policy: ConsoleErrorPolicy,
+    console_input: Option<PathBuf>,
+    console_record: Option<PathBuf>,
+    console_replay: Option<ReplayConfig>,
+    serial2: Option<ConsoleMode>,
+    net: Option<NetConfig>,
+    net_slots: u8,
+    pmem: Option<PmemConfig>,
+    firmware: Option<FirmwareConfig>,
+    shared_dir: Option<SharedDirConfig>,
+    panic_detect: bool,
+    lock_resources: bool,
+    timeout: Option<Duration>,
+    restart_on_reboot: bool,
+    max_reboots: Option<u32>,
+    mptable: bool,
+    watchdog: Option<WatchdogConfig>,
+    dirty_tracking: bool,
+    trace_irq: bool,
+    gdb: Option<GdbConfig>,
+    api_socket: Option<PathBuf>,
+    event_fifo: Option<PathBuf>,
+    jail: Option<JailConfig>,
+    cgroup: Option<CgroupConfig>,
+}
+
+impl Default for VMMConfigBuilder {
+    fn default() -> Self {
+        VMMConfigBuilder {
+            cpus: 1,
+            max_cpus: None,
+            topology: None,
+            cpu_template: CpuTemplate::Passthrough,
+            pv_features: PvFeatures::all(),
+            irqchip: IrqchipMode::Full,
+            tsc_khz: None,
+            memory: 512 << 20,
+            memory_backend: None,
+            memory_share: None,
+            memory_share_fd: None,
+            memory_ksm: None,
+            memory_thp: ThpMode::Default,
+            strict: false,
+            kernel: None,
+            initramfs: None,
+            initrd_in_memory: false,
+            cmdline: None,
+            append: Vec::new(),
+            uuid: None,
+            smbios: SmbiosConfig::default(),
+            console: ConsoleMode::Stdout,
+            console_error_policy: ConsoleErrorPolicy::Discard,
+            console_input: None,
+            console_record: None,
+            console_replay: None,
+            serial2: None,
+            net: None,
+            net_slots: 0,
+            pmem: None,
+            firmware: None,
+            shared_dir: None,
+            panic_detect: true,
+            lock_resources: true,
+            timeout: None,
+            restart_on_reboot: false,
+            max_reboots: None,
+            mptable: true,
+            watchdog: None,
+            dirty_tracking: false,
+            trace_irq: false,
+            gdb: None,
+            api_socket: None,
+            event_fifo: None,
+            jail: None,
+            cgroup: None,
+        }
+    }
+}
+
+impl VMMConfigBuilder {
+    pub fn cpus(mut self, cpus: u8) -> Self {
+        self.cpus = cpus;
+        self
+    }
+
+    /// Defaults to the boot vCPUs, without hotplug.
+    pub fn max_cpus(mut self, max_cpus: Option<u8>) -> Self {
+        self.max_cpus = max_cpus;
+        self
+    }
+
+    /// Defaults to all the vCPUs in a single socket, with one thread per core.
+    pub fn topology(mut self, topology: Option<CpuTopology>) -> Self {
+        self.topology = topology;
+        self
+    }
+
+    pub fn cpu_template(mut self, cpu_template: Option<CpuTemplate>) -> Self {
+        self.cpu_template = cpu_template.unwrap_or_default();
+        self
+    }
+
+    /// Defaults to all the features the host has.
+    pub fn pv_features(mut self, pv_features: Option<PvFeatures>) -> Self {
+        self.pv_features = pv_features.unwrap_or_default();
+        self
+    }
+
+    pub fn irqchip(mut self, irqchip: Option<IrqchipMode>) -> Self {
+        self.irqchip = irqchip.unwrap_or_default();
+        self
+    }
+
+    pub fn tsc_khz(mut self, tsc_khz: Option<u32>) -> Self {
+        self.tsc_khz = tsc_khz;
+        self
+    }
+
+    /// Guest memory size, in bytes.
+    pub fn memory(mut self, memory: u64) -> Self {
+        self.memory = memory;
+        self
+    }
+
+    pub fn memory_backend(mut self, memory_backend: Option<MemoryBackend>) -> Self {
+        self.memory_backend = memory_backend;
+        self
+    }
+
+    pub fn memory_share(mut self, memory_share: Option<MemoryShare>) -> Self {
+        self.memory_share = memory_share;
+        self
+    }
+
+    pub fn memory_share_fd(mut self, fd: Option<RawFd>) -> Self {
+        self.memory_share_fd = fd;
+        self
+    }
+
+    pub fn memory_ksm(mut self, ksm: Option<KsmMode>) -> Self {
+        self.memory_ksm = ksm;
+        self
+    }
+
+    pub fn memory_thp(mut self, thp: Option<ThpMode>) -> Self {
+        self.memory_thp = thp.unwrap_or_default();
+        self
+    }
+
+    /// Fail instead of warning when the host does not take the guest memory hints.
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    pub fn kernel<S: Into<ImageSource>>(mut self, kernel: S) -> Self {
+        self.kernel = Some(kernel.into());
+        self
+    }
+
+    pub fn initramfs(mut self, initramfs: Option<ImageSource>) -> Self {
+        self.initramfs = initramfs;
+        self
+    }
+
+    /// Decompress a gzip initramfs while loading it, trading host CPU for guest boot time.
+    pub fn initrd_in_memory(mut self, initrd_in_memory: bool) -> Self {
+        self.initrd_in_memory = initrd_in_memory;
+        self
+    }
+
+    pub fn cmdline(mut self, cmdline: Option<String>) -> Self {
+        self.cmdline = cmdline;
+        self
+    }
+
+    /// Add kernel command line parameters to those set so far, replacing their `console`,
+    /// `root`, `init` and `panic`. See [`CmdlineBuilder`].
+    pub fn append(mut self, params: &str) -> Self {
+        self.append.push(params.to_string());
+        self
+    }
+
+    /// Give the VM `uuid` in the SMBIOS tables, instead of a random one.
+    pub fn uuid(mut self, uuid: Option<Uuid>) -> Self {
+        self.uuid = uuid;
+        self
+    }
+
+    /// Override strings of the SMBIOS tables.
+    pub fn smbios(mut self, smbios: Option<SmbiosConfig>) -> Self {
+        self.smbios = smbios.unwrap_or_default();
+        self
+    }
+
+    /// Boot the kernel and initramfs of a quark bundle, with its command line and hints.
+    /// The settings made after this override the hints.
+    pub fn quardle(mut self, quardle: &Quardle) -> Self {
+        self.kernel = Some(quardle.kernel.clone().into());
+        self.initramfs = quardle.initramfs.clone().map(ImageSource::from);
+        self.cmdline = quardle.cmdline.clone();
+        self.cpus = quardle.cpus.unwrap_or(self.cpus);
+        self.memory = quardle
+            .memory
+            .map_or(self.memory, |memory| u64::from(memory) << 20);
+        if quardle.net.is_some() {
+            self.net = quardle.net.clone();
+        }
+        self
+    }
+
+    pub fn console(mut self, console: Option<ConsoleMode>) -> Self {
+        self.console = console.unwrap_or_default();
+        self
+    }
+
+    pub fn console_error_policy(mut self, policy: Option<ConsoleErrorPolicy>) -> Self {
+        self.console_error_policy = policy.unwrap_or_default();
+        self
+    }
+
+    pub fn console_input(mut self, console_input: Option<PathBuf>) -> Self {
+        self.console_input = console_input;
+        self
+    }
+
+    pub fn console_record(mut self, console_record: Option<PathBuf>) -> Self {
+        self.console_record = console_record;
+        self
+    }
+
+    pub fn console_replay(mut self, console_replay: Option<ReplayConfig>) -> Self {
+        self.console_replay = console_replay;
+        self
+    }
+
+    pub fn serial2(mut self, serial2: Option<ConsoleMode>) -> Self {
+        self.serial2 = serial2;
+        self
+    }
+
+    pub fn net(mut self, net: Option<NetConfig>) -> Self {
+        self.net = net;
+        self
+    }
+
+    /// Reserve slots for network devices plugged in through the API, see
+    /// [`api`](crate::api).
+    pub fn net_slots(mut self, net_slots: u8) -> Self {
+        self.net_slots = net_slots;
+        self
+    }
+
+    pub fn pmem(mut self, pmem: Option<PmemConfig>) -> Self {
+        self.pmem = pmem;
+        self
+    }
+
+    pub fn firmware(mut self, firmware: Option<FirmwareConfig>) -> Self {
+        self.firmware = firmware;
+        self
+    }
+
+    pub fn shared_dir(mut self, shared_dir: Option<SharedDirConfig>) -> Self {
+        self.shared_dir = shared_dir;
+        self
+    }
+
+    pub fn panic_detect(mut self, panic_detect: bool) -> Self {
+        self.panic_detect = panic_detect;
+        self
+    }
+
+    /// Lock the console files and the taps, so that another instance fails to use them.
+    pub fn lock_resources(mut self, lock_resources: bool) -> Self {
+        self.lock_resources = lock_resources;
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Restart the guest in place when it reboots: the VMM keeps its process, its console
+    /// and its taps, and reloads the kernel and the initramfs.
+    pub fn restart_on_reboot(mut self, restart_on_reboot: bool) -> Self {
+        self.restart_on_reboot = restart_on_reboot;
+        self
+    }
+
+    /// Unbounded by default. The VMM stops with
+    /// [`ExitReason::GuestReset`](crate::ExitReason::GuestReset) on the next reboot.
+    pub fn max_reboots(mut self, max_reboots: Option<u32>) -> Self {
+        self.max_reboots = max_reboots;
+        self
+    }
+
+    /// Enabled by default, for guests without ACPI support.
+    pub fn mptable(mut self, mptable: bool) -> Self {
+        self.mptable = mptable;
+        self
+    }
+
+    pub fn watchdog(mut self, watchdog: Option<WatchdogConfig>) -> Self {
+        self.watchdog = watchdog;
+        self
+    }
+
+    pub fn dirty_tracking(mut self, dirty_tracking: bool) -> Self {
+        self.dirty_tracking = dirty_tracking;
+        self
+    }
+
+    pub fn trace_irq(mut self, trace_irq: bool) -> Self {
+        self.trace_irq = trace_irq;
+        self
+    }
+
+    /// Wait for a GDB client on `gdb` before booting, and stop the guest for it.
+    pub fn gdb(mut self, gdb: Option<GdbConfig>) -> Self {
+        self.gdb = gdb;
+        self
+    }
+
+    pub fn api_socket(mut self, api_socket: Option<PathBuf>) -> Self {
+        self.api_socket = api_socket;
+        self
+    }
+
+    /// Write the lifecycle events to an existing FIFO, see [`events`](crate::events).
+    pub fn event_fifo(mut self, event_fifo: Option<PathBuf>) -> Self {
+        self.event_fifo = event_fifo;
+        self
+    }
+
+    pub fn jail(mut self, jail: Option<JailConfig>) -> Self {
+        self.jail = jail;
+        self
+    }
+
+    /// Confine the VM resources to a cgroup, see [`cgroup`](crate::cgroup).
+    pub fn cgroup(mut self, cgroup: Option<CgroupConfig>) -> Self {
+        self.cgroup = cgroup;
+        self
+    }
+
+    // Reject the configurations the VMM would only fail on later, or worse.
+    fn validate(&self) -> Result<()> {
+        if self.console == ConsoleMode::Agent {
+            return Err(Error::AgentConsole);
+        }
+
+        if self.cpus == 0 || u32::from(self.cpus) > MAX_SUPPORTED_CPUS {
+            return Err(Error::InvalidCpus(self.cpus));
+        }
+
+        let max_cpus = self.max_cpus.unwrap_or(self.cpus);
+        if max_cpus < self.cpus || u32::from(max_cpus) > MAX_SUPPORTED_CPUS {
+            return Err(Error::InvalidMaxCpus {
+                max_cpus,
+                cpus: self.cpus,
+            });
+        }
+        #[cfg(target_arch = "aarch64")]
+        if max_cpus != self.cpus {
+            return Err(Error::CpuHotplugUnsupported);
+        }
+
+        // The MP table lists the hot-pluggable vCPUs too.
+        #[cfg(target_arch = "x86_64")]
+        if self.mptable && u32::from(max_cpus) > MAX_MPTABLE_CPUS {
+            return Err(Error::MptableCpus(max_cpus));
+        }
+
+        if self.memory < MIN_MEMORY {
+            return Err(Error::MemoryTooSmall(self.memory));
+        }
+
+        #[cfg(target_arch = "aarch64")]
+        if self.firmware.is_some() {
+            return Err(Error::FirmwareUnsupported);
+        }
+        #[cfg(target_arch = "aarch64")]
+        if self.irqchip == IrqchipMode::Split {
+            return Err(Error::SplitIrqchipUnsupported);
+        }
+        #[cfg(target_arch = "aarch64")]
+        if self.gdb.is_some() {
+            return Err(Error::GdbUnsupported);
+        }
+
+        if let Some(ImageSource::Path(initramfs)) = self.initramfs.as_ref() {
+            if !initramfs.exists() {
+                return Err(Error::MissingInitramfs(initramfs.clone()));
+            }
+        }
+        // The paths are checked as they are opened, the descriptors cannot be reopened.
+        for image in [self.kernel.as_ref(), self.initramfs.as_ref()]
+            .into_iter()
+            .flatten()
+        {
+            if !matches!(image, ImageSource::Path(_)) {
+                image.check().map_err(|source| Error::UnusableImage {
+                    image: image.clone(),
+                    source,
+                })?;
+            }
+        }
+
+        // The template is mapped privately, the guest writes would not show.
+        if self.memory_share.is_some() && self.memory_backend.is_some() {
+            return Err(Error::SharedMemoryBackend);
+        }
+        if let Some(MemoryBackend::File(path)) = self.memory_backend.as_ref() {
+            let size = path
+                .metadata()
+                .map_err(|_| Error::MissingMemoryBackend(path.clone()))?
+                .len();
+            if size != self.memory {
+                return Err(Error::MemoryBackendSize {
+                    path: path.clone(),
+                    size,
+                    memory: self.memory,
+                });
+            }
+        }
+
+        match self.memory_share_fd {
+            Some(_) if self.memory_share.is_none() => return Err(Error::MemoryShareFdWithoutShare),
+            Some(fd) if fd < 3 => return Err(Error::InvalidMemoryShareFd(fd)),
+            _ => {}
+        }
+
+        // The output files are created, but not their directory.
+        for mode in [Some(&self.console), self.serial2.as_ref()]
+            .into_iter()
+            .flatten()
+        {
+            if let ConsoleMode::File(ConsoleFile { path, .. }) = mode {
+                let dir = match path.parent() {
+                    Some(dir) if !dir.as_os_str().is_empty() => dir,
+                    _ => Path::new("."),
+                };
+                if !dir.is_dir() {
+                    return Err(Error::MissingConsoleDirectory(path.clone()));
+                }
+            }
+        }
+
+        if let Some(path) = self.event_fifo.as_ref() {
+            if !path.exists() {
+                return Err(Error::MissingEventFifo(path.clone()));
+            }
+        }
+
+        if let Some(replay) = self.console_replay.as_ref() {
+            if self.console_input.is_some() {
+                return Err(Error::ReplayWithConsoleInput);
+            }
+            if !replay.path.is_file() {
+                return Err(Error::MissingReplay(replay.path.clone()));
+            }
+        }
+
+        if self.max_reboots.is_some() && !self.restart_on_reboot {
+            return Err(Error::MaxRebootsWithoutRestart);
+        }
+        #[cfg(target_arch = "aarch64")]
+        if self.restart_on_reboot {
+            return Err(Error::RestartUnsupported);
+        }
+        if self.restart_on_reboot && self.max_cpus.is_some_and(|max_cpus| max_cpus != self.cpus) {
+            return Err(Error::RestartWithHotplug);
+        }
+
+        if self.net_slots > MAX_NET_SLOTS {
+            return Err(Error::InvalidNetSlots(self.net_slots));
+        }
+        if self.net_slots > 0 && self.api_socket.is_none() {
+            return Err(Error::NetSlotsWithoutApi);
+        }
+
+        if let Some(jail) = self.jail.as_ref() {
+            // The taps are opened by name, through /dev/net/tun.
+            if self.net_slots > 0 {
+                return Err(Error::JailedNetSlots);
+            }
+            if !jail.chroot.is_dir() {
+                return Err(Error::MissingJailDirectory(jail.chroot.clone()));
+            }
+
+            // Rotating renames and creates files by path, outside of the jail.
+            for mode in [Some(&self.console), self.serial2.as_ref()]
+                .into_iter()
+                .flatten()
+            {
+                if let ConsoleMode::File(file) = mode {
+                    if file.max_size.is_some() {
+                        return Err(Error::JailedRotation(file.path.clone()));
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn build(self) -> Result<VMMConfig> {
+        self.validate()?;
+
+        let max_cpus = self.max_cpus.unwrap_or(self.cpus);
+        let topology = self.topology.unwrap_or(CpuTopology::flat(max_cpus));
+        if topology.vcpu_count() != u32::from(max_cpus) {
+            return Err(Error::TopologyMismatch {
+                topology,
+                cpus: max_cpus,
+            });
+        }
+
+        let mut cmdline = CmdlineBuilder::default();
+        for params in self.cmdline.iter().chain(&self.append) {
+            cmdline.append(params)?;
+        }
+        let cmdline = Some(cmdline.build()?).filter(|cmdline| !cmdline.is_empty());
+
+        Ok(VMMConfig {
+            cpus: self.cpus,
+            max_cpus,
+            topology,
+            cpu_template: self.cpu_template,
+            pv_features: self.pv_features,
+            irqchip: self.irqchip,
+            tsc_khz: self.tsc_khz,
+            memory: self.memory,
+            memory_backend: self.memory_backend,
+            memory_share: self.memory_share,
+            memory_share_fd: self.memory_share_fd,
+            memory_ksm: self.memory_ksm,
+            memory_thp: self.memory_thp,
+            strict: self.strict,
+            kernel: self.kernel.ok_or(Error::MissingKernel)?,
+            initramfs: self.initramfs,
+            initrd_in_memory: self.initrd_in_memory,
+            cmdline,
+            uuid: self.uuid,
+            smbios: self.smbios,
+            console: self.console,
+            console_error_policy: self.console_error_policy,
+            console_input: self.console_input,
+            console_record: self.console_record,
+            console_replay: self.console_replay,
+            serial2: self.serial2,
+            net: self.net,
+            net_slots: self.net_slots,
+            pmem: self.pmem,
+            firmware: self.firmware,
+            shared_dir: self.shared_dir,
+            panic_detect: self.panic_detect,
+            lock_resources: self.lock_resources,
+            timeout: self.timeout,
+            restart_on_reboot: self.restart_on_reboot,
+            max_reboots: self.max_reboots,
+            mptable: self.mptable,
+            watchdog: self.watchdog,
+            dirty_tracking: self.dirty_tracking,
+            trace_irq: self.trace_irq,
+            gdb: self.gdb,
+            api_socket: self.api_socket,
+            event_fifo: self.event_fifo,
+            jail: self.jail,
+            cgroup: self.cgroup,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+
+    use vmm_sys_util::tempfile::TempFile;
+
+    #[test]
+    fn console_mode_from_str() {
+        assert_eq!(
+            "stdout".parse::<ConsoleMode>().unwrap(),
+            ConsoleMode::Stdout
+        );
+        assert_eq!(
+            "file:/tmp/out.log".parse::<ConsoleMode>().unwrap(),
+            ConsoleMode::File("/tmp/out.log".into())
+        );
+        assert_eq!(
+            "file:/tmp/out.log,maxsize=10M,rotate=3,timestamps=on"
+                .parse::<ConsoleMode>()
+                .unwrap(),
+            ConsoleMode::File(ConsoleFile {
+                path: "/tmp/out.log".into(),
+                max_size: Some(10 << 20),
+                rotate: 3,
+                timestamps: true,
+            })
+        );
+        assert_eq!(
+            "file:/tmp/out.log,maxsize=1k"
+                .parse::<ConsoleMode>()
+                .unwrap(),
+            ConsoleMode::File(ConsoleFile {
+                path: "/tmp/out.log".into(),
+                max_size: Some(1024),
+                rotate: 1,
+                timestamps: false,
+            })
+        );
+        assert_eq!(
+            "unix:/tmp/agent.sock".parse::<ConsoleMode>().unwrap(),
+            ConsoleMode::Unix("/tmp/agent.sock".into())
+        );
+        assert_eq!(
+            "/tmp/out.log".parse::<ConsoleMode>().unwrap(),
+            ConsoleMode::File("/tmp/out.log".into())
+        );
+        assert_eq!("agent".parse::<ConsoleMode>().unwrap(), ConsoleMode::Agent);
+        // The kinds are those the parser takes.
+        let examples = [
+            "stdout",
+            "file:/tmp/out.log",
+            "unix:/tmp/agent.sock",
+            "agent",
+            "none",
+        ];
+        for (kind, example) in ConsoleMode::KINDS.iter().zip(examples) {
+            assert_eq!(example.parse::<ConsoleMode>().unwrap().kind(), *kind);
+        }
+        assert!("unix:".parse::<ConsoleMode>().is_err());
+        assert!("file:".parse::<ConsoleMode>().is_err());
+        assert!("file:/tmp/out.log,rotate=3".parse::<ConsoleMode>().is_err());
+        assert!("file:/tmp/out.log,maxsize=0"
+            .parse::<ConsoleMode>()
+            .is_err());
+        assert!("file:/tmp/out.log,timestamps=yes"
+            .parse::<ConsoleMode>()
+            .is_err());
+        assert!("tcp:1234".parse::<ConsoleMode>().is_err());
+        assert!("".parse::<ConsoleMode>().is_err());
+    }
+
+    #[test]
+    fn console_error_policy_from_str() {
+        assert_eq!(
+            "stdout-fallback".parse::<ConsoleErrorPolicy>().unwrap(),
+            ConsoleErrorPolicy::StdoutFallback
+        );
+        assert_eq!(
+            "Stop".parse::<ConsoleErrorPolicy>().unwrap(),
+            ConsoleErrorPolicy::Stop
+        );
+        assert_eq!(ConsoleErrorPolicy::default().to_string(), "discard");
+        assert!("stdout".parse::<ConsoleErrorPolicy>().is_err());
+    }
+
+    #[test]
+    fn memory_hints_from_str() {
+        assert_eq!("On".parse::<KsmMode>().unwrap(), KsmMode::On);
+        assert_eq!("off".parse::<KsmMode>().unwrap(), KsmMode::Off);
+        assert!("default".parse::<KsmMode>().is_err());
+
+        assert_eq!("never".parse::<ThpMode>().unwrap(), ThpMode::Never);
+        assert_eq!(ThpMode::default().to_string(), "default");
+        assert!("madvise".parse::<ThpMode>().is_err());
+    }
+
+    #[test]
+    fn topology() {
+        let topology = "2:3:2".parse::<CpuTopology>().unwrap();
+        assert_eq!(topology.vcpu_count(), 12);
+        assert_eq!(topology.to_string(), "2:3:2");
+
+        // 1 bit of thread ID, 2 bits of core ID.
+        let apic_ids: Vec<u32> = (0..12).map(|i| topology.apic_id(i)).collect();
+        assert_eq!(apic_ids, vec![0, 1, 2, 3, 4, 5, 8, 9, 10, 11, 12, 13]);
+
+        assert_eq!(CpuTopology::flat(4).apic_id(3), 3);
+        assert!("1:4".parse::<CpuTopology>().is_err());
+        assert!("1:0:1".parse::<CpuTopology>().is_err());
+
+        let builder = VMMConfigBuilder::default().kernel("vmlinux").cpus(4);
+        assert_eq!(
+            builder.clone().build().unwrap().topology,
+            CpuTopology::flat(4)
+        );
+        assert!(matches!(
+            builder.clone().topology(Some(topology)).build(),
+            Err(Error::TopologyMismatch { cpus: 4, .. })
+        ));
+
+        // The topology describes the hot-pluggable vCPUs too.
+        #[cfg(target_arch = "x86_64")]
+        {
+            let config = builder.clone().max_cpus(Some(12)).build().unwrap();
+            assert_eq!((config.cpus, config.topology), (4, CpuTopology::flat(12)));
+            assert!(builder
+                .max_cpus(Some(12))
+                .topology(Some(topology))
+                .build()
+                .is_ok());
+        }
+    }
+
+    #[test]
+    fn pv_features() {
+        assert_eq!("all".parse::<PvFeatures>().unwrap(), PvFeatures::all());
+        assert_eq!("none".parse::<PvFeatures>().unwrap(), PvFeatures::none());
+
+        let features = "steal-time, PV-EOI".parse::<PvFeatures>().unwrap();
+        assert!(features.contains(PvFeature::StealTime));
+        assert!(features.contains(PvFeature::PvEoi));
+        assert!(!features.contains(PvFeature::AsyncPf));
+        assert!(!features.contains(PvFeature::PvUnhalt));
+        assert_eq!(features.to_string(), "steal-time,pv-eoi");
+        assert_eq!(
+            PvFeatures::all().to_string(),
+            "async-pf,steal-time,pv-eoi,pv-unhalt"
+        );
+
+        assert!("steal-time,kvmclock".parse::<PvFeatures>().is_err());
+        assert!("".parse::<PvFeatures>().is_err());
+    }
+
+    #[test]
+    fn cpu_template_from_str() {
+        assert_eq!("T2".parse::<CpuTemplate>().unwrap(), CpuTemplate::T2);
+        for template in CpuTemplate::ALL {
+            assert_eq!(
+                template.to_string().parse::<CpuTemplate>().unwrap(),
+                template
+            );
+        }
+        assert!("skylake".parse::<CpuTemplate>().is_err());
+    }
+
+    #[test]
+    fn irqchip_from_str() {
+        assert_eq!("full".parse::<IrqchipMode>().unwrap(), IrqchipMode::Full);
+        assert_eq!("Split".parse::<IrqchipMode>().unwrap(), IrqchipMode::Split);
+        assert_eq!(IrqchipMode::Split.to_string(), "split");
+        for mode in IrqchipMode::ALL {
+            assert_eq!(mode.to_string().parse::<IrqchipMode>().unwrap(), mode);
+        }
+        assert!("none".parse::<IrqchipMode>().is_err());
+    }
+
+    #[test]
+    fn pmem_from_str() {
+        assert_eq!(
+            "file=/images/rootfs.img".parse::<PmemConfig>().unwrap(),
+            PmemConfig {
+                path: "/images/rootfs.img".into(),
+                read_only: true,
+            }
+        );
+        assert!(
+            !"file=/images/rootfs.img,rw"
+                .parse::<PmemConfig>()
+                .unwrap()
+                .read_only
+        );
+        assert!("ro".parse::<PmemConfig>().is_err());
+        assert!("file=".parse::<PmemConfig>().is_err());
+        assert!("file=/images/rootfs.img,dax".parse::<PmemConfig>().is_err());
+    }
+
+    #[test]
+    fn firmware_from_str() {
+        assert_eq!(
+            "path=blob.bin,addr=0xffff0000,entry"
+                .parse::<FirmwareConfig>()
+                .unwrap(),
+            FirmwareConfig {
+                path: "blob.bin".into(),
+                address: 0xffff_0000,
+                entry: true,
+            }
+        );
+        assert_eq!(
+            "addr=4026531840,path=blob.bin"
+                .parse::<FirmwareConfig>()
+                .unwrap()
+                .address,
+            0xf000_0000
+        );
+        assert!("path=blob.bin".parse::<FirmwareConfig>().is_err());
+        assert!("path=blob.bin,addr=0xffff0010"
+            .parse::<FirmwareConfig>()
+            .is_err());
+        assert!("path=blob.bin,addr=ffff0000"
+            .parse::<FirmwareConfig>()
+            .is_err());
+        assert!("path=blob.bin,addr=0xffff0000,entry=on"
+            .parse::<FirmwareConfig>()
+            .is_err());
+    }
+
+    #[test]
+    fn shared_dir_from_str() {
+        assert_eq!(
+            "tag=src,path=/home/me/project,ro"
+                .parse::<SharedDirConfig>()
+                .unwrap(),
+            SharedDirConfig {
+                tag: "src".to_string(),
+                path: "/home/me/project".into(),
+                read_only: true,
+            }
+        );
+        assert!(
+            !"tag=src,path=/home/me/project"
+                .parse::<SharedDirConfig>()
+                .unwrap()
+                .read_only
+        );
+        assert!("path=/home/me/project".parse::<SharedDirConfig>().is_err());
+        assert!("tag=,path=/home/me/project"
+            .parse::<SharedDirConfig>()
+            .is_err());
+        assert!("tag=src,path=/home/me/project,dax"
+            .parse::<SharedDirConfig>()
+            .is_err());
+    }
+
+    #[test]
+    fn image_from_str() {
+        assert_eq!(
+            "/boot/vmlinux".parse::<ImageSource>().unwrap(),
+            ImageSource::Path("/boot/vmlinux".into())
+        );
+        assert_eq!("fd:3".parse::<ImageSource>().unwrap(), ImageSource::Fd(3));
+        assert_eq!(
+            "/proc/self/fd/4".parse::<ImageSource>().unwrap(),
+            ImageSource::Fd(4)
+        );
+        assert!("fd:".parse::<ImageSource>().is_err());
+        assert!("fd:-1".parse::<ImageSource>().is_err());
+        assert!("/proc/self/fd/3/vmlinux".parse::<ImageSource>().is_err());
+        assert!("".parse::<ImageSource>().is_err());
+    }
+
+    #[test]
+    fn fd_images() {
+        // Safe because the name is a valid C string, and we check the result.
+        let fd = unsafe { libc::memfd_create(c"vmlinux".as_ptr(), libc::MFD_CLOEXEC) };
+        assert!(fd >= 0);
+        // Safe because the descriptor is ours.
+        let mut memfd = unsafe { File::from_raw_fd(fd) };
+        memfd.write_all(b"kernel").unwrap();
+
+        // The image reads from its start, whatever the offset of the descriptor.
+        let builder = VMMConfigBuilder::default().kernel(ImageSource::Fd(fd));
+        let config = builder.clone().build().unwrap();
+        let mut content = Vec::new();
+        config
+            .kernel
+            .open()
+            .unwrap()
+            .read_to_end(&mut content)
+            .unwrap();
+        assert_eq!(content, b"kernel");
+
+        let initramfs = TempFile::new().unwrap();
+        let initramfs = File::open(initramfs.as_path()).unwrap();
+        assert!(builder
+            .clone()
+            .initramfs(Some(initramfs.into()))
+            .build()
+            .is_ok());
+
+        let write_only = TempFile::new().unwrap();
+        let write_only = std::fs::OpenOptions::new()
+            .write(true)
+            .open(write_only.as_path())
+            .unwrap();
+        assert!(matches!(
+            builder.clone().initramfs(Some(write_only.into())).build(),
+            Err(Error::UnusableImage { .. })
+        ));
+
+        // A pipe does not seek.
+        let mut pipe = [0; 2];
+        // Safe because the array holds both descriptors, and we check the result.
+        assert_eq!(
+            unsafe { libc::pipe2(pipe.as_mut_ptr(), libc::O_CLOEXEC) },
+            0
+        );
+        let result = builder
+            .clone()
+            .initramfs(Some(ImageSource::Fd(pipe[0])))
+            .build();
+        // Safe because the descriptors are ours, and no longer used.
+        unsafe {
+            libc::close(pipe[0]);
+            libc::close(pipe[1]);
+        }
+        assert!(matches!(result, Err(Error::UnusableImage { .. })));
+
+        assert!(matches!(
+            builder.kernel(ImageSource::Fd(RawFd::MAX)).build(),
+            Err(Error::UnusableImage { .. })
+        ));
+    }
+
+    #[test]
+    fn validate() {
+        let builder = VMMConfigBuilder::default().kernel("vmlinux");
+        assert!(builder.clone().build().is_ok());
+
+        assert!(matches!(
+            builder.clone().cpus(0).build(),
+            Err(Error::InvalidCpus(0))
+        ));
+        assert!(matches!(
+            builder.clone().cpus(255).build(),
+            Err(Error::InvalidCpus(255))
+        ));
+        assert!(builder.clone().cpus(254).mptable(false).build().is_ok());
+        #[cfg(target_arch = "x86_64")]
+        {
+            assert!(builder.clone().cpus(64).build().is_ok());
+            assert!(matches!(
+                builder.clone().cpus(65).build(),
+                Err(Error::MptableCpus(65))
+            ));
+            assert!(matches!(
+                builder.clone().max_cpus(Some(65)).build(),
+                Err(Error::MptableCpus(65))
+            ));
+        }
+        assert!(matches!(
+            builder.clone().cpus(2).max_cpus(Some(1)).build(),
+            Err(Error::InvalidMaxCpus {
+                max_cpus: 1,
+                cpus: 2
+            })
+        ));
+
+        assert!(matches!(
+            builder.clone().memory(0).build(),
+            Err(Error::MemoryTooSmall(0))
+        ));
+        assert!(matches!(
+            builder.clone().memory(MIN_MEMORY - (1 << 20)).build(),
+            Err(Error::MemoryTooSmall(_))
+        ));
+        assert!(builder.clone().memory(MIN_MEMORY).build().is_ok());
+
+        assert!(matches!(
+            builder
+                .clone()
+                .initramfs(Some("/nonexistent/initramfs.cpio".into()))
+                .build(),
+            Err(Error::MissingInitramfs(_))
+        ));
+        assert!(builder
+            .clone()
+            .initramfs(Some(std::env::temp_dir().into()))
+            .build()
+            .is_ok());
+
+        let missing_dir = ConsoleMode::File("/nonexistent/console.log".into());
+        assert!(matches!(
+            builder.clone().console(Some(missing_dir.clone())).build(),
+            Err(Error::MissingConsoleDirectory(_))
+        ));
+        assert!(matches!(
+            builder.clone().serial2(Some(missing_dir)).build(),
+            Err(Error::MissingConsoleDirectory(_))
+        ));
+        assert!(matches!(
+            builder
+                .clone()
+                .event_fifo(Some("/nonexistent/events".into()))
+                .build(),
+            Err(Error::MissingEventFifo(_))
+        ));
+        let replay = |path: &str| {
+            Some(ReplayConfig {
+                path: path.into(),
+                fast: false,
+                tolerance: 0,
+            })
+        };
+        assert!(matches!(
+            builder
+                .clone()
+                .console_replay(replay("/nonexistent/session.json"))
+                .build(),
+            Err(Error::MissingReplay(_))
+        ));
+        assert!(matches!(
+            builder
+                .clone()
+                .console_input(Some("/dev/null".into()))
+                .console_replay(replay("/dev/null"))
+                .build(),
+            Err(Error::ReplayWithConsoleInput)
+        ));
+        // A bare file name lives in the current directory.
+        assert!(builder
+            .clone()
+            .console(Some(ConsoleMode::File("console.log".into())))
+            .build()
+            .is_ok());
+
+        let jail = |chroot: &str| {
+            Some(JailConfig {
+                uid: 1000,
+                gid: 1000,
+                chroot: chroot.into(),
+                unshare: false,
+            })
+        };
+        assert!(matches!(
+            builder.clone().jail(jail("/nonexistent")).build(),
+            Err(Error::MissingJailDirectory(_))
+        ));
+        let rotated = "file:console.log,maxsize=1mb".parse().unwrap();
+        assert!(matches!(
+            builder
+                .clone()
+                .console(Some(rotated))
+                .jail(jail("/"))
+                .build(),
+            Err(Error::JailedRotation(_))
+        ));
+        assert!(matches!(
+            builder
+                .clone()
+                .api_socket(Some("lumper.sock".into()))
+                .net_slots(1)
+                .jail(jail("/"))
+                .build(),
+            Err(Error::JailedNetSlots)
+        ));
+        assert!(builder.jail(jail("/")).build().is_ok());
+    }
+
+    #[test]
+    fn net_slots() {
+        let builder = VMMConfigBuilder::default().kernel("vmlinux").net_slots(2);
+        assert!(matches!(
+            builder.clone().build(),
+            Err(Error::NetSlotsWithoutApi)
+        ));
+
+        let builder = builder.api_socket(Some("lumper.sock".into()));
+        assert_eq!(builder.clone().build().unwrap().net_slots, 2);
+        assert!(matches!(
+            builder.net_slots(MAX_NET_SLOTS + 1).build(),
+            Err(Error::InvalidNetSlots(_))
+        ));
+    }
+
+    #[test]
+    fn memory_size() {
+        let bytes = |s: &str| s.parse::<MemorySize>().unwrap().bytes();
+        assert_eq!(bytes("1024"), 1 << 30);
+        assert_eq!(bytes("1048576k"), 1 << 30);
+        assert_eq!(bytes("512M"), 512 << 20);
+        assert_eq!(bytes("512MB"), 512 << 20);
+        assert_eq!(bytes("2G"), 2 << 30);
+        assert_eq!(bytes("2 GiB"), 2 << 30);
+        assert_eq!(bytes("2gib"), 2 << 30);
+        assert_eq!(bytes("8T"), 8 << 40);
+        assert_eq!(bytes("16TiB"), 16 << 40);
+        assert_eq!(bytes("1073741824B"), 1 << 30);
+        // Too small, but that is for the builder to tell.
+        assert_eq!(bytes("0"), 0);
+
+        for (size, reason) in [
+            ("", "missing number"),
+            ("G", "missing number"),
+            ("-1G", "missing number"),
+            ("1.5G", "use a smaller unit for fractions"),
+            ("2GG", "unknown unit"),
+            ("2Gi", "unknown unit"),
+            ("2P", "unknown unit"),
+            ("2 G B", "unknown unit"),
+            ("16777216T", "too large"),
+            ("99999999999999999999", "too large"),
+            ("1536K", "not a whole number of MiB"),
+            ("4097b", "not a whole number of MiB"),
+        ] {
+            match size.parse::<MemorySize>() {
+                Err(Error::InvalidMemorySize { reason: r, .. }) => {
+                    assert_eq!(r, reason, "{}", size)
+                }
+                r => panic!("unexpected result for `{}`: {:?}", size, r),
+            }
+        }
+    }
+
+    #[test]
+    fn memory_backend() {
+        assert_eq!(
+            "file=/tmp/template.mem".parse::<MemoryBackend>().unwrap(),
+            MemoryBackend::File("/tmp/template.mem".into())
+        );
+        assert!("file=".parse::<MemoryBackend>().is_err());
+        assert!("/tmp/template.mem".parse::<MemoryBackend>().is_err());
+        assert!("anon".parse::<MemoryBackend>().is_err());
+
+        let template = vmm_sys_util::tempfile::TempFile::new().unwrap();
+        template.as_file().set_len(128 << 20).unwrap();
+        let backend = Some(MemoryBackend::File(template.as_path().into()));
+        let builder = VMMConfigBuilder::default()
+            .kernel("vmlinux")
+            .memory_backend(backend);
+
+        assert!(builder.clone().memory(128 << 20).build().is_ok());
+        assert!(matches!(
+            builder.memory(256 << 20).build(),
+            Err(Error::MemoryBackendSize {
+                size: 134_217_728,
+                memory: 268_435_456,
+                ..
+            })
+        ));
+        assert!(matches!(
+            VMMConfigBuilder::default()
+                .kernel("vmlinux")
+                .memory_backend(Some(MemoryBackend::File("/nonexistent".into())))
+                .build(),
+            Err(Error::MissingMemoryBackend(_))
+        ));
+    }
+
+    #[test]
+    fn memory_share() {
+        assert_eq!("memfd".parse::<MemoryShare>().unwrap(), MemoryShare::Memfd);
+        assert!("shm".parse::<MemoryShare>().is_err());
+
+        let builder = VMMConfigBuilder::default()
+            .kernel("vmlinux")
+            .memory_share(Some(MemoryShare::Memfd));
+        assert!(builder.clone().memory_share_fd(Some(100)).build().is_ok());
+        assert!(matches!(
+            builder.clone().memory_share_fd(Some(1)).build(),
+            Err(Error::InvalidMemoryShareFd(1))
+        ));
+        assert!(matches!(
+            builder
+                .memory_backend(Some(MemoryBackend::File("/dev/null".into())))
+                .build(),
+            Err(Error::SharedMemoryBackend)
+        ));
+        assert!(matches!(
+            VMMConfigBuilder::default()
+                .kernel("vmlinux")
+                .memory_share_fd(Some(100))
+                .build(),
+            Err(Error::MemoryShareFdWithoutShare)
+        ));
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn max_reboots() {
+        let builder = VMMConfigBuilder::default()
+            .kernel("vmlinux")
+            .max_reboots(Some(3));
+        assert!(matches!(
+            builder.clone().build(),
+            Err(Error::MaxRebootsWithoutRestart)
+        ));
+
+        let builder = builder.restart_on_reboot(true);
+        let config = builder.clone().build().unwrap();
+        assert!(config.restart_on_reboot);
+        assert_eq!(config.max_reboots, Some(3));
+
+        assert!(matches!(
+            builder.max_cpus(Some(4)).build(),
+            Err(Error::RestartWithHotplug)
+        ));
+    }
+
+    #[test]
+    fn quardle() {
+        let quardle = Quardle::open(
+            &Path::new(env!("CARGO_MANIFEST_DIR")).join("src/quardle/fixtures/hello.qrk"),
+        )
+        .unwrap();
+
+        let config = VMMConfigBuilder::default()
+            .quardle(&quardle)
+            .build()
+            .unwrap();
+        assert_eq!(config.kernel, quardle.kernel.clone().into());
+        assert_eq!(config.initramfs, quardle.initramfs.clone().map(Into::into));
+        assert_eq!(config.cmdline.as_deref(), Some("quiet"));
+        assert_eq!((config.cpus, config.memory), (2, 256 << 20));
+        assert_eq!(
+            config.net.unwrap().backend,
+            NetBackend::Tap("tap0".to_string())
+        );
+
+        // The later settings win.
+        let config = VMMConfigBuilder::default()
+            .quardle(&quardle)
+            .memory(1 << 30)
+            .kernel("vmlinux")
+            .build()
+            .unwrap();
+        assert_eq!(config.kernel, "vmlinux".into());
+        assert_eq!((config.cpus, config.memory), (2, 1 << 30));
+
+        // The appended parameters go after those of the bundle.
+        let config = VMMConfigBuilder::default()
+            .quardle(&quardle)
+            .append("quiet root=/dev/pmem0")
+            .build()
+            .unwrap();
+        assert_eq!(config.cmdline.as_deref(), Some("quiet root=/dev/pmem0"));
+    }
+
+    #[test]
+    fn cmdline_builder() {
+        let build = |parts: &[&str]| {
+            let mut cmdline = CmdlineBuilder::new(128);
+            for part in parts {
+                cmdline.append(part)?;
+            }
+            cmdline.build()
+        };
+
+        // Blanks only separate the parameters, the quoted ones included.
+        assert_eq!(build(&[]).unwrap(), "");
+        assert_eq!(build(&["  \t "]).unwrap(), "");
+        assert_eq!(
+            build(&[" quiet\tinit=/sbin/init  lumper.tag=\"a  b\" "]).unwrap(),
+            "quiet init=/sbin/init lumper.tag=\"a  b\""
+        );
+        assert_eq!(
+            build(&["\"dyndbg=file virtio_net.c +p\""]).unwrap(),
+            "\"dyndbg=file virtio_net.c +p\""
+        );
+        assert!(matches!(
+            build(&["lumper.tag=\"a b"]),
+            Err(Error::InvalidCmdline(_))
+        ));
+
+        // The later parts replace the parameters the guest takes once, in place, whether
+        // their names use dashes or underscores.
+        assert_eq!(
+            build(&["console=ttyS0 reboot=k panic=1", "panic=-1 console=hvc0"]).unwrap(),
+            "console=hvc0 reboot=k panic=-1"
+        );
+        assert_eq!(build(&["root=/dev/vda", "root="]).unwrap(), "root=");
+        assert_eq!(
+            build(&[
+                "virtio_mmio.device=4K@0xd0000000:5",
+                "virtio-mmio.device=4K@0xd0001000:6"
+            ])
+            .unwrap(),
+            "virtio_mmio.device=4K@0xd0000000:5 virtio-mmio.device=4K@0xd0001000:6"
+        );
+        assert_eq!(build(&["quiet", "quiet"]).unwrap(), "quiet");
+
+        // A part conflicts with itself, unless it repeats a parameter as it was.
+        assert!(matches!(
+            build(&["console=ttyS0 console=hvc0"]),
+            Err(Error::ConflictingCmdline { first, second })
+                if first == "console=ttyS0" && second == "console=hvc0"
+        ));
+        assert!(matches!(
+            build(&["init=/init \"init=/sbin/init\""]),
+            Err(Error::ConflictingCmdline { .. })
+        ));
+        assert_eq!(build(&["panic=1 panic=1"]).unwrap(), "panic=1");
+
+        let mut cmdline = CmdlineBuilder::new(128);
+        cmdline.append("console=ttyS0 quiet lumper-tag=a").unwrap();
+        cmdline.append("console=hvc0 lumper_tag=b").unwrap();
+        assert_eq!(cmdline.value("console"), Some("hvc0"));
+        assert_eq!(cmdline.value("lumper-tag"), Some("b"));
+        assert_eq!(cmdline.value("quiet"), None);
+        assert_eq!(cmdline.value("root"), None);
+
+        // The arguments after the first `--` go to init, after those of the previous parts.
+        assert_eq!(
+            build(&["quiet -- -s", "panic=1 -- --verbose -- x"]).unwrap(),
+            "quiet panic=1 -- -s --verbose -- x"
+        );
+        assert_eq!(build(&["--"]).unwrap(), "");
+        assert_eq!(build(&["-- console=hvc0"]).unwrap(), "-- console=hvc0");
+
+        // With its NUL, the command line fits the 128 bytes.
+        let param = format!("lumper.tag={}", "x".repeat(116));
+        assert_eq!(build(&[&param]).unwrap().len(), 127);
+        assert!(matches!(
+            build(&[&param, "a"]),
+            Err(Error::CmdlineTooLong {
+                len: 129,
+                max_size: 128
+            })
+        ));
+    }
+
+    #[test]
+    fn uuid_from_str() {
+        let uuid: Uuid = "4C4C4544-0051-3010-8052-b2c04f564433".parse().unwrap();
+        assert_eq!(
+            uuid.0,
+            [
+                0x4c, 0x4c, 0x45, 0x44, 0x00, 0x51, 0x30, 0x10, 0x80, 0x52, 0xb2, 0xc0, 0x4f, 0x56,
+                0x44, 0x33
+            ]
+        );
+        assert_eq!(uuid.to_string(), "4c4c4544-0051-3010-8052-b2c04f564433");
+
+        for invalid in [
+            "",
+            "4c4c4544005130108052b2c04f564433",
+            "4c4c4544-0051-3010-8052-b2c04f56443",
+            "4c4c4544-0051-3010-8052-b2c04f5644330",
+            "4c4c454-40051-3010-8052-b2c04f564433",
+            "4c4c4544-0051-3010-8052-b2c04f56443g",
+            "+c4c4544-0051-3010-8052-b2c04f564433",
+        ] {
+            assert!(matches!(
+                invalid.parse::<Uuid>(),
+                Err(Error::InvalidUuid(_))
+            ));
+        }
+
+        // Random UUIDs are version 4, of the RFC 4122 variant.
+        let uuid = Uuid::random().unwrap();
+        assert_eq!(uuid.0[6] >> 4, 4);
+        assert_eq!(uuid.0[8] >> 6, 0b10);
+        assert_ne!(uuid, Uuid::random().unwrap());
+        assert_eq!(uuid.to_string().parse::<Uuid>().unwrap(), uuid);
+    }
+
+    #[test]
+    fn smbios_from_str() {
+        assert_eq!(
+            "product=ci-runner,serial=42"
+                .parse::<SmbiosConfig>()
+                .unwrap(),
+            SmbiosConfig {
+                product: Some("ci-runner".to_string()),
+                serial: Some("42".to_string()),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            "manufacturer=ACME,version=2,sku=S,family=F,product=a b"
+                .parse::<SmbiosConfig>()
+                .unwrap(),
+            SmbiosConfig {
+                manufacturer: Some("ACME".to_string()),
+                product: Some("a b".to_string()),
+                version: Some("2".to_string()),
+                serial: None,
+                sku: Some("S".to_string()),
+                family: Some("F".to_string()),
+            }
+        );
+
+        for invalid in ["", "product", "product=", "uuid=x", "product=a,,serial=b"] {
+            assert!(matches!(
+                invalid.parse::<SmbiosConfig>(),
+                Err(Error::InvalidSmbios(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn watchdog_from_str() {
+        assert_eq!(
+            "timeout=30".parse::<WatchdogConfig>().unwrap(),
+            WatchdogConfig {
+                timeout: Duration::from_secs(30),
+                action: WatchdogAction::Poweroff,
+            }
+        );
+        assert_eq!(
+            "action=reset,timeout=5"
+                .parse::<WatchdogConfig>()
+                .unwrap()
+                .action,
+            WatchdogAction::Reset
+        );
+        assert!("action=reset".parse::<WatchdogConfig>().is_err());
+        assert!("timeout=0".parse::<WatchdogConfig>().is_err());
+        assert!("timeout=30,action=halt".parse::<WatchdogConfig>().is_err());
+    }
+
+    #[test]
+    fn gdb_from_str() {
+        assert_eq!(
+            "tcp::1234".parse::<GdbConfig>().unwrap().addr,
+            SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1234)
+        );
+        assert_eq!(
+            "tcp:0.0.0.0:1234".parse::<GdbConfig>().unwrap().addr,
+            SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 1234)
+        );
+        for invalid in [
+            "",
+            ":1234",
+            "tcp:1234",
+            "udp::1234",
+            "tcp:host:1234",
+            "tcp::",
+        ] {
+            assert!(matches!(
+                invalid.parse::<GdbConfig>(),
+                Err(Error::InvalidGdb(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn jail_from_str() {
+        assert_eq!(
+            "uid=1000,gid=100,chroot=/var/lib/lumper/vm1"
+                .parse::<JailConfig>()
+                .unwrap(),
+            JailConfig {
+                uid: 1000,
+                gid: 100,
+                chroot: "/var/lib/lumper/vm1".into(),
+                unshare: false,
+            }
+        );
+        assert!(
+            "chroot=/var/empty,unshare=on,gid=0,uid=65534"
+                .parse::<JailConfig>()
+                .unwrap()
+                .unshare
+        );
+        assert!("uid=1000,gid=100".parse::<JailConfig>().is_err());
+        assert!("uid=0,gid=0,chroot=/var/empty"
+            .parse::<JailConfig>()
+            .is_err());
+        assert!("uid=nobody,gid=100,chroot=/var/empty"
+            .parse::<JailConfig>()
+            .is_err());
+        assert!("uid=1000,gid=100,chroot=".parse::<JailConfig>().is_err());
+        assert!("uid=1000,gid=100,chroot=/,unshare=yes"
+            .parse::<JailConfig>()
+            .is_err());
+    }
+
+    #[test]
+    fn cgroup_from_str() {
+        assert_eq!(
+            "path=/sys/fs/cgroup/lumper/vm1,cpu-max=200000 100000,mem-max=1G,io-weight=50"
+                .parse::<CgroupConfig>()
+                .unwrap(),
+            CgroupConfig {
+                path: "/sys/fs/cgroup/lumper/vm1".into(),
+                cpu_max: Some(CpuMax {
+                    quota: Some(200000),
+                    period: 100000,
+                }),
+                memory_max: Some(1 << 30),
+                io_weight: Some(50),
+            }
+        );
+        assert_eq!(
+            "path=/sys/fs/cgroup/vm1,cpu-max=max"
+                .parse::<CgroupConfig>()
+                .unwrap()
+                .cpu_max,
+            Some(CpuMax {
+                quota: None,
+                period: 100000,
+            })
+        );
+        assert!("cpu-max=max".parse::<CgroupConfig>().is_err());
+        assert!("path=lumper/vm1".parse::<CgroupConfig>().is_err());
+        assert!("path=/sys/fs/cgroup/vm1,cpu-max=100"
+            .parse::<CgroupConfig>()
+            .is_err());
+        assert!("path=/sys/fs/cgroup/vm1,cpu-max=max 100 100"
+            .parse::<CgroupConfig>()
+            .is_err());
+        assert!("path=/sys/fs/cgroup/vm1,io-weight=0"
+            .parse::<CgroupConfig>()
+            .is_err());
+        assert!("path=/sys/fs/cgroup/vm1,mem-max=1Q"
+            .parse::<CgroupConfig>()
+            .is_err());
+    }
+
+    #[test]
+    fn net_from_str() {
+        assert_eq!(
+            "tap0".parse::<NetConfig>().unwrap(),
+            NetConfig {
+                backend: NetBackend::Tap("tap0".to_string()),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            "tap0,rx_rate=10mbps,tx_rate=512Kbps,tx_ops=1000,burst=1mb"
+                .parse::<NetConfig>()
+                .unwrap(),
+            NetConfig {
+                backend: NetBackend::Tap("tap0".to_string()),
+                dhcp: None,
+                rx_rate: Some(1_250_000),
+                tx_rate: Some(64_000),
+                rx_ops: None,
+                tx_ops: Some(1000),
+                burst: Some(1 << 20),
+                vhost: false,
+                mac: None,
+                attach_timeout: None,
+                rx_queue_size: None,
+                tx_queue_size: None,
+                bridge: None,
+            }
+        );
+        assert_eq!(
+            "tap0,vhost=on".parse::<NetConfig>().unwrap(),
+            NetConfig {
+                backend: NetBackend::Tap("tap0".to_string()),
+                vhost: true,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            "tap0,mac=52:54:00:AB:cd:01".parse::<NetConfig>().unwrap(),
+            NetConfig {
+                backend: NetBackend::Tap("tap0".to_string()),
+                mac: Some(MacAddress([0x52, 0x54, 0x00, 0xab, 0xcd, 0x01])),
+                ..Default::default()
+            }
+        );
+        assert!("".parse::<NetConfig>().is_err());
+        assert!("rx_rate=10mbps".parse::<NetConfig>().is_err());
+        assert!("tap0,rx_rate=10mb".parse::<NetConfig>().is_err());
+        assert!("tap0,rx_rate=0bps".parse::<NetConfig>().is_err());
+        assert!("tap0,burst=lots".parse::<NetConfig>().is_err());
+        assert!("tap0,mtu=1500".parse::<NetConfig>().is_err());
+        assert!("tap0,vhost=yes".parse::<NetConfig>().is_err());
+        assert!("tap0,mac=52:54:00:ab:cd".parse::<NetConfig>().is_err());
+        assert!("tap0,mac=52:54:00:ab:cd:01:02"
+            .parse::<NetConfig>()
+            .is_err());
+        assert!("tap0,mac=52:54:00:ab:cd:1".parse::<NetConfig>().is_err());
+        assert_eq!(
+            "tap0,attach-timeout=2s"
+                .parse::<NetConfig>()
+                .unwrap()
+                .attach_timeout,
+            Some(Duration::from_secs(2))
+        );
+        assert_eq!(
+            "tap0,attach-timeout=500ms"
+                .parse::<NetConfig>()
+                .unwrap()
+                .attach_timeout,
+            Some(Duration::from_millis(500))
+        );
+        assert!("tap0,attach-timeout=2".parse::<NetConfig>().is_err());
+        assert!("tap0,attach-timeout=0s".parse::<NetConfig>().is_err());
+        assert!("user,attach-timeout=2s".parse::<NetConfig>().is_err());
+        assert_eq!(
+            "tap0,rx_queue=1024,tx_queue=64"
+                .parse::<NetConfig>()
+                .unwrap(),
+            NetConfig {
+                backend: NetBackend::Tap("tap0".to_string()),
+                rx_queue_size: Some(1024),
+                tx_queue_size: Some(64),
+                ..Default::default()
+            }
+        );
+        assert!("tap0,rx_queue=32".parse::<NetConfig>().is_err());
+        assert!("tap0,rx_queue=1000".parse::<NetConfig>().is_err());
+        assert!("tap0,tx_queue=65536".parse::<NetConfig>().is_err());
+        assert_eq!(
+            "tap0,bridge=br0".parse::<NetConfig>().unwrap().bridge,
+            Some(BridgeConfig {
+                name: "br0".to_string(),
+                create: false,
+            })
+        );
+        assert_eq!(
+            "tap0,create-bridge,bridge=br0"
+                .parse::<NetConfig>()
+                .unwrap()
+                .bridge,
+            Some(BridgeConfig {
+                name: "br0".to_string(),
+                create: true,
+            })
+        );
+        assert!("tap0,create-bridge".parse::<NetConfig>().is_err());
+        assert!("tap0,bridge=".parse::<NetConfig>().is_err());
+        assert!("tap0,bridge=a-much-too-long-bridge"
+            .parse::<NetConfig>()
+            .is_err());
+        assert!("user,bridge=br0".parse::<NetConfig>().is_err());
+        // Multicast.
+        assert!("tap0,mac=01:00:5e:00:00:01".parse::<NetConfig>().is_err());
+        assert!("tap0,vhost=on,rx_rate=10mbps".parse::<NetConfig>().is_err());
+    }
+
+    #[test]
+    fn user_net_from_str() {
+        assert_eq!(
+            "user".parse::<NetConfig>().unwrap(),
+            NetConfig {
+                backend: NetBackend::User(UserNetConfig::default()),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            "user,hostfwd=tcp::8080-:80,hostfwd=tcp:127.0.0.1:2222-10.0.2.16:22,rx_rate=1mbps"
+                .parse::<NetConfig>()
+                .unwrap(),
+            NetConfig {
+                backend: NetBackend::User(UserNetConfig {
+                    hostfwd: vec![
+                        HostForward {
+                            host: "0.0.0.0:8080".parse().unwrap(),
+                            guest_addr: None,
+                            guest_port: 80,
+                        },
+                        HostForward {
+                            host: "127.0.0.1:2222".parse().unwrap(),
+                            guest_addr: Some(Ipv4Addr::new(10, 0, 2, 16)),
+                            guest_port: 22,
+                        },
+                    ],
+                }),
+                rx_rate: Some(125_000),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            "::8080-:80".parse::<HostForward>().unwrap(),
+            "tcp::8080-:80".parse::<HostForward>().unwrap()
+        );
+        assert!("user,hostfwd=udp::5353-:53".parse::<NetConfig>().is_err());
+        assert!("user,hostfwd=tcp::8080".parse::<NetConfig>().is_err());
+        assert!("user,hostfwd=tcp::0-:80".parse::<NetConfig>().is_err());
+        assert!("user,hostfwd=tcp:host:8080-:80"
+            .parse::<NetConfig>()
+            .is_err());
+        assert!("user,vhost=on".parse::<NetConfig>().is_err());
+        assert!("tap0,hostfwd=tcp::8080-:80".parse::<NetConfig>().is_err());
+    }
+
+    #[test]
+    fn dhcp_server_from_str() {
+        let address = Ipv4Addr::new(172, 16, 0, 1);
+        assert_eq!(
+            "tap0,dhcp-server=172.16.0.1/24,range=172.16.0.10-172.16.0.20"
+                .parse::<NetConfig>()
+                .unwrap(),
+            NetConfig {
+                backend: NetBackend::Tap("tap0".to_string()),
+                dhcp: Some(DhcpServerConfig {
+                    address,
+                    prefix_len: 24,
+                    range: (Ipv4Addr::new(172, 16, 0, 10), Ipv4Addr::new(172, 16, 0, 20)),
+                    dns: None,
+                }),
+                ..Default::default()
+            }
+        );
+
+        // The range defaults to the rest of the network.
+        let net = "tap0,dns=1.1.1.1,dhcp-server=172.16.0.1/30"
+            .parse::<NetConfig>()
+            .unwrap();
+        assert_eq!(
+            net.dhcp,
+            Some(DhcpServerConfig {
+                address,
+                prefix_len: 30,
+                range: (Ipv4Addr::new(172, 16, 0, 2), Ipv4Addr::new(172, 16, 0, 2)),
+                dns: Some(Ipv4Addr::new(1, 1, 1, 1)),
+            })
+        );
+        assert_eq!(
+            net.dhcp.unwrap().netmask(),
+            Ipv4Addr::new(255, 255, 255, 252)
+        );
+
+        for invalid in [
+            "tap0,dhcp-server=172.16.0.1",
+            "tap0,dhcp-server=172.16.0.1/31",
+            "tap0,dhcp-server=172.16.0.1/24,range=172.16.0.20-172.16.0.10",
+            "tap0,dhcp-server=172.16.0.1/24,range=172.16.1.10-172.16.1.20",
+            "tap0,dhcp-server=172.16.0.1/24,range=172.16.0.1-172.16.0.20",
+            "tap0,range=172.16.0.10-172.16.0.20",
+            "user,dhcp-server=10.0.2.2/24",
+        ] {
+            assert!(invalid.parse::<NetConfig>().is_err(), "{}", invalid);
+        }
+    }
+}
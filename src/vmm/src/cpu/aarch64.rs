@@ -0,0 +1,83 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use kvm_bindings::{
+    kvm_vcpu_init, KVM_ARM_VCPU_POWER_OFF, KVM_ARM_VCPU_PSCI_0_2, KVM_REG_ARM64, KVM_REG_ARM_CORE,
+    KVM_REG_SIZE_U64,
+};
+use kvm_ioctls::VmFd;
+use vm_memory::{Address, GuestAddress};
+
+use super::{Error, Result, Vcpu};
+
+/// Maximum number of vCPUs. The GIC redistributor range is sized for them.
+pub(crate) const MAX_SUPPORTED_CPUS: u32 = 254;
+
+// Offsets of the core registers in struct kvm_regs, which starts with the user_pt_regs:
+// regs[31], sp, pc and pstate. See arch/arm64/include/uapi/asm/ptrace.h.
+const X0_OFFSET: u64 = 0;
+const PC_OFFSET: u64 = 32 * 8;
+const PSTATE_OFFSET: u64 = 33 * 8;
+
+// PSTATE bits. The kernel is entered in EL1h, with all the exceptions masked.
+// See Documentation/arm64/booting.rst.
+const PSR_MODE_EL1H: u64 = 0x5;
+const PSR_F_BIT: u64 = 0x40;
+const PSR_I_BIT: u64 = 0x80;
+const PSR_A_BIT: u64 = 0x100;
+const PSR_D_BIT: u64 = 0x200;
+const BOOT_PSTATE: u64 = PSR_MODE_EL1H | PSR_F_BIT | PSR_I_BIT | PSR_A_BIT | PSR_D_BIT;
+
+// The ids of the core registers hold their offset in struct kvm_regs, in 32-bit words.
+fn core_reg_id(offset: u64) -> u64 {
+    u64::from(KVM_REG_ARM64)
+        | u64::from(KVM_REG_SIZE_U64)
+        | u64::from(KVM_REG_ARM_CORE)
+        | (offset / 4)
+}
+
+impl Vcpu {
+    /// Initialize the vCPU as the CPU model KVM prefers on this host, with PSCI 0.2.
+    ///
+    /// Only the boot vCPU starts running. The guest brings the others up with PSCI CPU_ON.
+    pub fn init(&self, vm_fd: &VmFd) -> Result<()> {
+        let mut kvi = kvm_vcpu_init::default();
+        vm_fd
+            .get_preferred_target(&mut kvi)
+            .map_err(Error::KvmIoctl)?;
+
+        kvi.features[0] |= 1 << KVM_ARM_VCPU_PSCI_0_2;
+        if self.index > 0 {
+            kvi.features[0] |= 1 << KVM_ARM_VCPU_POWER_OFF;
+        }
+
+        self.vcpu_fd.vcpu_init(&kvi).map_err(Error::KvmIoctl)
+    }
+
+    /// Configure regs, following the arm64 Linux boot protocol: the boot vCPU enters the
+    /// kernel with the device tree address in X0.
+    pub fn configure_regs(
+        &self,
+        kernel_load: GuestAddress,
+        fdt_address: GuestAddress,
+    ) -> Result<()> {
+        self.vcpu_fd
+            .set_one_reg(core_reg_id(PSTATE_OFFSET), BOOT_PSTATE.into())
+            .map_err(Error::KvmIoctl)?;
+
+        // PSCI CPU_ON gives the secondary vCPUs their entry point.
+        if self.index > 0 {
+            return Ok(());
+        }
+
+        for (offset, value) in [
+            (PC_OFFSET, kernel_load.raw_value()),
+            (X0_OFFSET, fdt_address.raw_value()),
+        ] {
+            self.vcpu_fd
+                .set_one_reg(core_reg_id(offset), value.into())
+                .map_err(Error::KvmIoctl)?;
+        }
+
+        Ok(())
+    }
+}
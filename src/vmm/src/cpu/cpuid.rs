@@ -2,8 +2,11 @@
 // Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
 
+use std::arch::x86_64::__cpuid;
+
 use kvm_bindings::CpuId;
-use kvm_ioctls::{Cap::TscDeadlineTimer, Kvm};
+
+use crate::config::{CpuTopology, PvFeature, PvFeatures};
 
 // CPUID bits in ebx, ecx, and edx.
 const EBX_CLFLUSH_CACHELINE: u32 = 8; // Flush a cache line size.
@@ -11,11 +14,45 @@ const EBX_CLFLUSH_SIZE_SHIFT: u32 = 8; // Bytes flushed when executing CLFLUSH.
 const EBX_CPU_COUNT_SHIFT: u32 = 16; // Index of this CPU.
 const EBX_CPUID_SHIFT: u32 = 24; // Index of this CPU.
 const ECX_EPB_SHIFT: u32 = 3; // "Energy Performance Bias" bit.
+const ECX_X2APIC_SHIFT: u32 = 21; // x2APIC mode, which the guest switches to.
 const ECX_TSC_DEADLINE_TIMER_SHIFT: u32 = 24; // TSC deadline mode of APIC timer
 const ECX_HYPERVISOR_SHIFT: u32 = 31; // Flag to be set when the cpu is running on a hypervisor.
 const EDX_HTT_SHIFT: u32 = 28; // Hyper Threading Enabled.
+const EDX_INVARIANT_TSC_SHIFT: u32 = 8; // TSC runs at a constant rate in all ACPI states.
+
+// Extended topology enumeration (leaves 0xb and 0x1f) level types.
+const LEVEL_TYPE_INVALID: u32 = 0;
+const LEVEL_TYPE_SMT: u32 = 1;
+const LEVEL_TYPE_CORE: u32 = 2;
+const ECX_LEVEL_TYPE_SHIFT: u32 = 8;
+// Leaf 0x8000_0008 ECX: APIC ID size of the core and thread IDs.
+const ECX_APIC_ID_CORE_ID_SIZE_SHIFT: u32 = 12;
+
+// KVM paravirtual features, in EAX.
+pub(crate) const KVM_CPUID_FEATURES: u32 = 0x4000_0001;
+pub(crate) const KVM_FEATURE_ASYNC_PF: u32 = 4;
+pub(crate) const KVM_FEATURE_STEAL_TIME: u32 = 5;
+pub(crate) const KVM_FEATURE_PV_EOI: u32 = 6;
+const KVM_FEATURE_PV_UNHALT: u32 = 7;
+const KVM_FEATURE_ASYNC_PF_VMEXIT: u32 = 10;
+pub(crate) const KVM_FEATURE_ASYNC_PF_INT: u32 = 14;
+
+// Whether the host TSC is invariant, leaf 0x8000_0007 EDX.
+fn host_has_invariant_tsc() -> bool {
+    // Safe because CPUID is always available on x86_64, and the leaf is checked first.
+    // Recent toolchains consider `__cpuid` safe.
+    #[allow(unused_unsafe)]
+    unsafe {
+        __cpuid(0x8000_0000).eax >= 0x8000_0007
+            && __cpuid(0x8000_0007).edx & (1 << EDX_INVARIANT_TSC_SHIFT) != 0
+    }
+}
+
+/// Set up the CPUID entries shared by all the vCPUs. [`set_apic_id`] then sets those of
+/// each one.
+pub(crate) fn filter_cpuid(tsc_deadline_timer: bool, topology: &CpuTopology, cpuid: &mut CpuId) {
+    let threads_per_socket = topology.threads_per_socket();
 
-pub(crate) fn filter_cpuid(kvm: &Kvm, vcpu_id: usize, cpu_count: usize, cpuid: &mut CpuId) {
     for entry in cpuid.as_mut_slice().iter_mut() {
         match entry.function {
             1 => {
@@ -23,13 +60,14 @@ pub(crate) fn filter_cpuid(kvm: &Kvm, vcpu_id: usize, cpu_count: usize, cpuid: &
                 if entry.index == 0 {
                     entry.ecx |= 1 << ECX_HYPERVISOR_SHIFT;
                 }
-                if kvm.check_extension(TscDeadlineTimer) {
+                // KVM emulates the x2APIC, whatever the host has.
+                entry.ecx |= 1 << ECX_X2APIC_SHIFT;
+                if tsc_deadline_timer {
                     entry.ecx |= 1 << ECX_TSC_DEADLINE_TIMER_SHIFT;
                 }
-                entry.ebx = (vcpu_id << EBX_CPUID_SHIFT) as u32
-                    | (EBX_CLFLUSH_CACHELINE << EBX_CLFLUSH_SIZE_SHIFT);
-                if cpu_count > 1 {
-                    entry.ebx |= (cpu_count as u32) << EBX_CPU_COUNT_SHIFT;
+                entry.ebx = EBX_CLFLUSH_CACHELINE << EBX_CLFLUSH_SIZE_SHIFT;
+                if threads_per_socket > 1 {
+                    entry.ebx |= threads_per_socket << EBX_CPU_COUNT_SHIFT;
                     entry.edx |= 1 << EDX_HTT_SHIFT;
                 }
             }
@@ -37,7 +75,183 @@ pub(crate) fn filter_cpuid(kvm: &Kvm, vcpu_id: usize, cpu_count: usize, cpuid: &
                 // Clear X86 EPB feature. No frequency selection in the hypervisor.
                 entry.ecx &= !(1 << ECX_EPB_SHIFT);
             }
+            0xb | 0x1f => {
+                // Extended topology: the thread level, then the core level.
+                let (shift, count, level_type) = match entry.index {
+                    0 => (
+                        topology.thread_bits(),
+                        u32::from(topology.threads_per_core),
+                        LEVEL_TYPE_SMT,
+                    ),
+                    1 => (topology.core_bits(), threads_per_socket, LEVEL_TYPE_CORE),
+                    _ => (0, 0, LEVEL_TYPE_INVALID),
+                };
+                entry.eax = shift;
+                entry.ebx = count;
+                entry.ecx = (level_type << ECX_LEVEL_TYPE_SHIFT) | entry.index;
+            }
+            0x8000_0007 => {
+                if host_has_invariant_tsc() {
+                    entry.edx |= 1 << EDX_INVARIANT_TSC_SHIFT;
+                }
+            }
+            0x8000_0008 => {
+                // Number of threads per socket, minus one.
+                entry.ecx = (topology.core_bits() << ECX_APIC_ID_CORE_ID_SIZE_SHIFT)
+                    | (threads_per_socket - 1);
+            }
+            _ => (),
+        }
+    }
+}
+
+/// Set the APIC ID of a vCPU in the CPUID entries [`filter_cpuid`] set up.
+pub(crate) fn set_apic_id(apic_id: u32, cpuid: &mut CpuId) {
+    for entry in cpuid.as_mut_slice().iter_mut() {
+        match entry.function {
+            1 => {
+                entry.ebx = (entry.ebx & !(0xff << EBX_CPUID_SHIFT)) | (apic_id << EBX_CPUID_SHIFT)
+            }
+            0xb | 0x1f => entry.edx = apic_id,
             _ => (),
         }
     }
 }
+
+// The KVM feature bits of a paravirtual feature.
+fn kvm_feature_bits(feature: PvFeature) -> u32 {
+    match feature {
+        PvFeature::AsyncPf => {
+            (1 << KVM_FEATURE_ASYNC_PF)
+                | (1 << KVM_FEATURE_ASYNC_PF_VMEXIT)
+                | (1 << KVM_FEATURE_ASYNC_PF_INT)
+        }
+        PvFeature::StealTime => 1 << KVM_FEATURE_STEAL_TIME,
+        PvFeature::PvEoi => 1 << KVM_FEATURE_PV_EOI,
+        PvFeature::PvUnhalt => 1 << KVM_FEATURE_PV_UNHALT,
+    }
+}
+
+/// Hide the KVM paravirtual features not in `pv_features`. The others stay as KVM
+/// reports them: only offered if the host supports them.
+pub(crate) fn filter_pv_features(pv_features: PvFeatures, cpuid: &mut CpuId) {
+    for entry in cpuid.as_mut_slice().iter_mut() {
+        if entry.function == KVM_CPUID_FEATURES {
+            for feature in PvFeature::ALL {
+                if !pv_features.contains(feature) {
+                    entry.eax &= !kvm_feature_bits(feature);
+                }
+            }
+        }
+    }
+}
+
+/// The KVM paravirtual feature bits in `cpuid`.
+pub(crate) fn kvm_features(cpuid: &CpuId) -> u32 {
+    cpuid
+        .as_slice()
+        .iter()
+        .find(|entry| entry.function == KVM_CPUID_FEATURES)
+        .map_or(0, |entry| entry.eax)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::config::CpuTemplate;
+    use crate::cpu::msrs::{
+        pv_msrs, MSR_KVM_ASYNC_PF_EN, MSR_KVM_ASYNC_PF_INT, MSR_KVM_PV_EOI_EN, MSR_KVM_STEAL_TIME,
+    };
+    use crate::cpu::templates::{apply_template, parse_fixture};
+
+    fn host_with(pv_features: &str) -> CpuId {
+        let mut cpuid = parse_fixture(include_str!("templates/fixtures/host.txt"));
+        filter_pv_features(pv_features.parse().unwrap(), &mut cpuid);
+        cpuid
+    }
+
+    #[test]
+    fn pv_features() {
+        // All the features the host has stay, so do the ones lumper does not know about.
+        let cpuid = host_with("all");
+        assert_eq!(kvm_features(&cpuid), 0x0103_fefb);
+        assert_eq!(
+            pv_msrs(&cpuid),
+            [
+                MSR_KVM_ASYNC_PF_INT,
+                MSR_KVM_ASYNC_PF_EN,
+                MSR_KVM_STEAL_TIME,
+                MSR_KVM_PV_EOI_EN
+            ]
+        );
+
+        // The kvmclock and the other features are not affected.
+        let cpuid = host_with("none");
+        assert_eq!(kvm_features(&cpuid), 0x0103_ba0b);
+        assert!(pv_msrs(&cpuid).is_empty());
+
+        // Async page faults go with all their delivery modes.
+        let cpuid = host_with("steal-time,pv-eoi");
+        assert_eq!(kvm_features(&cpuid), 0x0103_ba6b);
+        assert_eq!(pv_msrs(&cpuid), [MSR_KVM_STEAL_TIME, MSR_KVM_PV_EOI_EN]);
+
+        // Only the features of the host are offered.
+        let mut cpuid = parse_fixture(include_str!("templates/fixtures/host-no-avx512.txt"));
+        filter_pv_features(PvFeatures::all(), &mut cpuid);
+        assert_eq!(kvm_features(&cpuid), 0);
+        assert!(pv_msrs(&cpuid).is_empty());
+    }
+
+    // The host fixture, with the extended topology leaves.
+    fn host_with_topology() -> CpuId {
+        parse_fixture(&format!(
+            "{}0x0000000b 0x0 0x0 0x0 0x0 0x0\n0x0000000b 0x1 0x0 0x0 0x0 0x0\n",
+            include_str!("templates/fixtures/host.txt")
+        ))
+    }
+
+    fn dump(cpuid: &CpuId) -> Vec<(u32, u32, u32, u32, u32, u32)> {
+        cpuid
+            .as_slice()
+            .iter()
+            .map(|e| (e.function, e.index, e.eax, e.ebx, e.ecx, e.edx))
+            .collect()
+    }
+
+    #[test]
+    fn vcpu_cpuid() {
+        let topology = CpuTopology {
+            sockets: 2,
+            cores_per_socket: 4,
+            threads_per_core: 2,
+        };
+        let mut base = host_with_topology();
+        filter_cpuid(true, &topology, &mut base);
+        filter_pv_features(PvFeatures::all(), &mut base);
+        apply_template(CpuTemplate::T2, &mut base).unwrap();
+
+        for index in 0..topology.vcpu_count() as u8 {
+            let apic_id = topology.apic_id(index);
+            let mut cpuid = base.clone();
+            set_apic_id(apic_id, &mut cpuid);
+
+            // The same as when each vCPU got its CPUID set up from scratch, its APIC ID
+            // first.
+            let mut expected = host_with_topology();
+            filter_cpuid(true, &topology, &mut expected);
+            set_apic_id(apic_id, &mut expected);
+            filter_pv_features(PvFeatures::all(), &mut expected);
+            apply_template(CpuTemplate::T2, &mut expected).unwrap();
+            assert_eq!(dump(&cpuid), dump(&expected));
+
+            for entry in cpuid.as_slice() {
+                match entry.function {
+                    1 => assert_eq!(entry.ebx, (apic_id << 24) | (8 << 16) | (8 << 8)),
+                    0xb => assert_eq!(entry.edx, apic_id),
+                    _ => {}
+                }
+            }
+        }
+    }
+}
@@ -12,10 +12,12 @@ use vm_memory::{Bytes, GuestAddress, GuestMemory, GuestMemoryMmap};
 
 use std::mem;
 
-pub const BOOT_GDT_OFFSET: u64 = 0x500;
-pub const BOOT_IDT_OFFSET: u64 = 0x520;
+use crate::layout::{BOOT_GDT_SIZE, BOOT_GDT_START, BOOT_IDT_START};
+
 pub const BOOT_GDT_MAX: usize = 4;
 
+const _: () = assert!(BOOT_GDT_MAX * mem::size_of::<u64>() <= BOOT_GDT_SIZE as usize);
+
 pub fn gdt_entry(flags: u16, base: u32, limit: u32) -> u64 {
     ((u64::from(base) & 0xff00_0000u64) << (56 - 24))
         | ((u64::from(flags) & 0x0000_f0ffu64) << 40)
@@ -97,7 +99,7 @@ pub fn write_gdt_table(
     table: &[u64],
     guest_mem: &GuestMemoryMmap,
 ) -> std::result::Result<(), vm_memory::GuestMemoryError> {
-    let boot_gdt_addr = GuestAddress(BOOT_GDT_OFFSET);
+    let boot_gdt_addr = GuestAddress(BOOT_GDT_START);
     for (index, entry) in table.iter().enumerate() {
         let addr = guest_mem
             .checked_offset(boot_gdt_addr, index * mem::size_of::<u64>())
@@ -113,7 +115,7 @@ pub fn write_idt_value(
     val: u64,
     guest_mem: &GuestMemoryMmap,
 ) -> std::result::Result<(), vm_memory::GuestMemoryError> {
-    let boot_idt_addr = GuestAddress(BOOT_IDT_OFFSET);
+    let boot_idt_addr = GuestAddress(BOOT_IDT_START);
     guest_mem.write_obj(val, boot_idt_addr)
 }
 
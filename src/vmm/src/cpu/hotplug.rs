@@ -0,0 +1,188 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! vCPUs plugged into the running guest, up to the ones the ACPI tables describe.
+//!
+//! The guest boots with the first vCPUs of the topology, the MADT lists the others
+//! disabled. Plugging one creates it, set up like the boot vCPUs, starts its thread, then
+//! raises the interrupt of the ACPI Generic Event Device: the guest finds the vCPU present
+//! through the [`CpuHotplug`](crate::devices::cpu_hotplug::CpuHotplug) register, and brings
+//! it up like any secondary processor once it is onlined.
+
+use std::io;
+use std::mem;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use kvm_bindings::{CpuId, Msrs};
+use kvm_ioctls::VmFd;
+use vm_device::device_manager::IoManager;
+use vmm_sys_util::eventfd::EventFd;
+
+use super::{cpuid, Error, Result, Vcpu, VcpuHandle};
+use crate::config::CpuTopology;
+use crate::devices::pio::UnknownPorts;
+use crate::ExitNotifier;
+
+/// The thread of a plugged vCPU, and the handle controlling it.
+pub(crate) type PluggedVcpu = (Arc<VcpuHandle>, JoinHandle<Vcpu>);
+
+/// Plugs the vCPUs past the boot ones.
+pub(crate) struct VcpuHotplug {
+    vm_fd: Arc<VmFd>,
+    topology: CpuTopology,
+    io_manager: Arc<Mutex<IoManager>>,
+    unknown_ports: Arc<UnknownPorts>,
+    exit: Arc<ExitNotifier>,
+    // Number of vCPUs of the guest, which reads it from the CpuHotplug register.
+    present: Arc<AtomicU8>,
+    // Guest IRQ of the Generic Event Device, and the eventfd raising it.
+    ged_irq: u32,
+    ged: EventFd,
+    // The CPUID of the boot vCPUs before their APIC ID, and their MSRs.
+    setup: Option<(CpuId, Msrs)>,
+    tsc_khz: Option<u32>,
+    // The vCPUs started since the VMM last took them.
+    started: Vec<PluggedVcpu>,
+}
+
+impl VcpuHotplug {
+    /// Plug the vCPUs of `topology` past the `boot_cpus` ones, raising `ged_irq` for the
+    /// guest to find them, see [`VcpuHotplug::ged_fd`].
+    pub fn new(
+        vm_fd: Arc<VmFd>,
+        topology: CpuTopology,
+        boot_cpus: u8,
+        ged_irq: u32,
+        io_manager: Arc<Mutex<IoManager>>,
+        unknown_ports: Arc<UnknownPorts>,
+        exit: Arc<ExitNotifier>,
+    ) -> Result<Self> {
+        Ok(VcpuHotplug {
+            vm_fd,
+            topology,
+            io_manager,
+            unknown_ports,
+            exit,
+            present: Arc::new(AtomicU8::new(boot_cpus)),
+            ged_irq,
+            ged: EventFd::new(libc::EFD_NONBLOCK)?,
+            setup: None,
+            tsc_khz: None,
+            started: Vec::new(),
+        })
+    }
+
+    /// The vCPU count, for the [`CpuHotplug`](crate::devices::cpu_hotplug::CpuHotplug)
+    /// register.
+    pub fn present(&self) -> Arc<AtomicU8> {
+        self.present.clone()
+    }
+
+    /// Number of vCPUs of the guest.
+    pub fn cpus(&self) -> u8 {
+        self.present.load(Ordering::Acquire)
+    }
+
+    /// Number of vCPUs the guest may have.
+    pub fn max_cpus(&self) -> u32 {
+        self.topology.vcpu_count()
+    }
+
+    /// Guest IRQ of the Generic Event Device.
+    pub fn ged_irq(&self) -> u32 {
+        self.ged_irq
+    }
+
+    /// The eventfd raising the interrupt of the Generic Event Device, to connect to the
+    /// irqchip.
+    pub fn ged_fd(&self) -> io::Result<EventFd> {
+        self.ged.try_clone()
+    }
+
+    /// Set the plugged vCPUs up like the boot ones: with the `cpuid` they got before their
+    /// APIC ID, and the `msrs`.
+    pub fn set_vcpu_setup(&mut self, cpuid: CpuId, msrs: Msrs) {
+        self.setup = Some((cpuid, msrs));
+    }
+
+    /// Run the plugged vCPUs at the TSC frequency of the boot ones.
+    pub fn set_tsc_khz(&mut self, tsc_khz: u32) {
+        self.tsc_khz = Some(tsc_khz);
+    }
+
+    /// Plug `count` vCPUs into the running guest, which must fit in the topology. Returns
+    /// the vCPU count of the guest.
+    ///
+    /// The guest is told about the vCPUs started before a failure.
+    pub fn add(&mut self, count: u8) -> Result<u8> {
+        let present = self.cpus();
+        debug_assert!(u32::from(present) + u32::from(count) <= self.max_cpus());
+
+        let mut result = Ok(());
+        for index in present..present + count {
+            if let Err(e) = self.start(index) {
+                result = Err(e);
+                break;
+            }
+            // In order, see CpuHotplug.
+            self.present.store(index + 1, Ordering::Release);
+        }
+        if self.cpus() > present {
+            self.ged.write(1)?;
+        }
+
+        result.map(|()| self.cpus())
+    }
+
+    // Create the vCPU `index`, set up like the boot ones, and start its thread.
+    fn start(&mut self, index: u8) -> Result<()> {
+        let (base_cpuid, msrs) = self.setup.as_ref().ok_or(Error::NotConfigured)?;
+        let apic_id = self.topology.apic_id(index);
+        let mut vcpu = Vcpu::new(
+            &self.vm_fd,
+            index.into(),
+            apic_id.into(),
+            self.io_manager.clone(),
+            self.unknown_ports.clone(),
+        )?;
+
+        let mut vcpu_cpuid = base_cpuid.clone();
+        cpuid::set_apic_id(apic_id, &mut vcpu_cpuid);
+        vcpu.configure_cpuid(&vcpu_cpuid)?;
+        vcpu.configure_msrs(msrs)?;
+        // The registers are set when the guest starts it, like the other secondary vCPUs.
+        vcpu.configure_fpu()?;
+        vcpu.configure_lapic()?;
+        if let Some(tsc_khz) = self.tsc_khz {
+            vcpu.configure_tsc_khz(tsc_khz)?;
+        }
+
+        let handle = vcpu.handle();
+        let exit = self.exit.clone();
+        let thread = thread::Builder::new().spawn(move || {
+            if let Some(reason) = vcpu.run_until_exit() {
+                exit.notify(reason);
+            }
+            vcpu
+        })?;
+        self.started.push((handle, thread));
+
+        println!("Starting vCPU {}", index);
+        Ok(())
+    }
+
+    /// The handles of the vCPUs started since the VMM last took them.
+    pub fn started_handles(&self) -> Vec<Arc<VcpuHandle>> {
+        self.started
+            .iter()
+            .map(|(handle, _)| handle.clone())
+            .collect()
+    }
+
+    /// Take the vCPUs started since the last call, for the VMM to stop them along with
+    /// the others.
+    pub fn take_started(&mut self) -> Vec<PluggedVcpu> {
+        mem::take(&mut self.started)
+    }
+}
@@ -1,62 +1,415 @@
 // Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
 
-use std::convert::TryInto;
-use std::io;
-use std::sync::{Arc, Mutex};
-use std::{result, u64};
-
-use kvm_bindings::{kvm_fpu, kvm_regs, CpuId};
+use std::any::Any;
+use std::cell::Cell;
+use std::os::raw::{c_int, c_void};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::result;
+use std::sync::atomic::{fence, AtomicU64, AtomicU8, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use kvm_bindings::{KVM_SYSTEM_EVENT_CRASH, KVM_SYSTEM_EVENT_RESET, KVM_SYSTEM_EVENT_SHUTDOWN};
 use kvm_ioctls::{VcpuExit, VcpuFd, VmFd};
-use vm_device::bus::MmioAddress;
-use vm_device::device_manager::{IoManager, MmioManager};
-use vm_memory::{Address, Bytes, GuestAddress, GuestMemoryError, GuestMemoryMmap};
-use vmm_sys_util::terminal::Terminal;
-
-use crate::devices::serial::{LumperSerial, SERIAL_PORT_BASE, SERIAL_PORT_LAST_REGISTER};
-
+use libc::siginfo_t;
+use vm_device::bus::{self, MmioAddress, PioAddress};
+use vm_device::device_manager::{IoManager, MmioManager, PioManager};
+use vm_memory::GuestMemoryError;
+use vmm_sys_util::signal::{register_signal_handler, SIGRTMIN};
+
+use crate::api::{VcpuCounters, VcpuExits};
+#[cfg(target_arch = "x86_64")]
+use crate::devices::ioapic::IOAPIC_EOI;
+use crate::devices::pio::UnknownPorts;
+#[cfg(target_arch = "x86_64")]
+use crate::gdb::{set_guest_debug, GdbStub};
+#[cfg(target_arch = "x86_64")]
+use crate::layout::IOAPIC_START;
+use crate::{logging, ExitReason, PanicReport};
+
+#[cfg(target_arch = "aarch64")]
+mod aarch64;
+#[cfg(target_arch = "aarch64")]
+pub(crate) use aarch64::MAX_SUPPORTED_CPUS;
+
+#[cfg(target_arch = "x86_64")]
 pub(crate) mod cpuid;
+#[cfg(target_arch = "x86_64")]
 mod gdt;
-use gdt::*;
+#[cfg(target_arch = "x86_64")]
+pub(crate) mod hotplug;
+#[cfg(target_arch = "x86_64")]
 mod interrupts;
-use interrupts::*;
+#[cfg(target_arch = "x86_64")]
 pub(crate) mod mpspec;
+#[cfg(target_arch = "x86_64")]
 pub(crate) mod mptable;
+#[cfg(target_arch = "x86_64")]
 pub(crate) mod msr_index;
+#[cfg(target_arch = "x86_64")]
 pub(crate) mod msrs;
-
-/// Initial stack for the boot CPU.
-const BOOT_STACK_POINTER: u64 = 0x8ff0;
-
-// Initial pagetables.
-const PML4_START: u64 = 0x9000;
-const PDPTE_START: u64 = 0xa000;
-const PDE_START: u64 = 0xb000;
-
-const X86_CR0_PE: u64 = 0x1;
-const X86_CR0_PG: u64 = 0x8000_0000;
-const X86_CR4_PAE: u64 = 0x20;
+#[cfg(target_arch = "x86_64")]
+pub(crate) mod templates;
+#[cfg(target_arch = "x86_64")]
+mod x86_64;
+#[cfg(target_arch = "x86_64")]
+pub(crate) use mptable::MAX_SUPPORTED_CPUS;
+#[cfg(target_arch = "x86_64")]
+pub(crate) use x86_64::VcpuState;
 
 /// Errors encountered during vCPU operation.
-#[derive(Debug)]
+#[derive(Debug, thiserror::Error)]
 pub enum Error {
     /// Failed to operate on guest memory.
-    GuestMemory(GuestMemoryError),
+    #[error("failed to access guest memory")]
+    GuestMemory(#[from] GuestMemoryError),
     /// I/O Error.
-    IO(std::io::Error),
+    #[error("I/O error")]
+    IO(#[from] std::io::Error),
     /// Error issuing an ioctl to KVM.
-    KvmIoctl(kvm_ioctls::Error),
+    #[error("KVM ioctl failed")]
+    KvmIoctl(#[from] kvm_ioctls::Error),
     /// Failed to configure mptables.
-    Mptable(mptable::Error),
+    #[cfg(target_arch = "x86_64")]
+    #[error("failed to configure the MP table")]
+    Mptable(#[from] mptable::Error),
     /// Failed to configure MSRs.
+    #[cfg(target_arch = "x86_64")]
+    #[error("KVM did not set all the MSRs")]
     SetModelSpecificRegistersCount,
     /// Failed to configure MSRs.
-    CreateMsr(msrs::Error),
+    #[cfg(target_arch = "x86_64")]
+    #[error("failed to create the boot MSRs")]
+    CreateMsr(#[from] msrs::Error),
+    /// Failed to apply the CPU template.
+    #[cfg(target_arch = "x86_64")]
+    #[error("failed to apply the CPU template")]
+    CpuTemplate(#[from] templates::Error),
+    /// A vCPU was plugged before the boot ones were configured.
+    #[cfg(target_arch = "x86_64")]
+    #[error("the boot vCPUs are not configured")]
+    NotConfigured,
+    /// Failed to install the vCPU kick signal handler.
+    #[error("failed to install the vCPU kick signal handler")]
+    KickSignal(#[source] vmm_sys_util::errno::Error),
 }
 
 /// Dedicated Result type.
 pub type Result<T> = result::Result<T, Error>;
 
+// The exits counted apart, by kind. The others are counted as `Other`.
+#[derive(Clone, Copy)]
+enum ExitKind {
+    IoIn,
+    IoOut,
+    MmioRead,
+    MmioWrite,
+    Hlt,
+    Other,
+}
+
+// The names of the exit kinds, in their order.
+const EXIT_KINDS: [&str; 6] = ["io_in", "io_out", "mmio_read", "mmio_write", "hlt", "other"];
+
+impl ExitKind {
+    fn of(exit: &VcpuExit) -> Self {
+        match exit {
+            VcpuExit::IoIn(..) => ExitKind::IoIn,
+            VcpuExit::IoOut(..) => ExitKind::IoOut,
+            VcpuExit::MmioRead(..) => ExitKind::MmioRead,
+            VcpuExit::MmioWrite(..) => ExitKind::MmioWrite,
+            VcpuExit::Hlt => ExitKind::Hlt,
+            _ => ExitKind::Other,
+        }
+    }
+}
+
+/// vCPU counters, read by the API while the vCPU thread updates them.
+#[derive(Default)]
+pub(crate) struct VcpuStats {
+    exits: AtomicU64,
+    // By exit kind.
+    kind_exits: [AtomicU64; EXIT_KINDS.len()],
+    // The kind of the last exit plus one, 0 before the first exit.
+    last_exit: AtomicU8,
+    // Nanoseconds spent handling the exits.
+    handling_ns: AtomicU64,
+    stray_mmio: AtomicU64,
+}
+
+impl VcpuStats {
+    pub fn counters(&self) -> VcpuCounters {
+        let kind_exits = |kind: ExitKind| self.kind_exits[kind as usize].load(Ordering::Relaxed);
+        let last_exit = self.last_exit.load(Ordering::Relaxed);
+        VcpuCounters {
+            exits: self.exits.load(Ordering::Relaxed),
+            exit_kinds: VcpuExits {
+                io_in: kind_exits(ExitKind::IoIn),
+                io_out: kind_exits(ExitKind::IoOut),
+                mmio_read: kind_exits(ExitKind::MmioRead),
+                mmio_write: kind_exits(ExitKind::MmioWrite),
+                hlt: kind_exits(ExitKind::Hlt),
+                other: kind_exits(ExitKind::Other),
+            },
+            last_exit: last_exit
+                .checked_sub(1)
+                .map(|kind| EXIT_KINDS[usize::from(kind)]),
+            handling_ns: self.handling_ns.load(Ordering::Relaxed),
+            stray_mmio: self.stray_mmio.load(Ordering::Relaxed),
+        }
+    }
+
+    fn record_exit(&self, exit: &VcpuExit) {
+        let kind = ExitKind::of(exit);
+        self.exits.fetch_add(1, Ordering::Relaxed);
+        self.kind_exits[kind as usize].fetch_add(1, Ordering::Relaxed);
+        self.last_exit.store(kind as u8 + 1, Ordering::Relaxed);
+    }
+}
+
+// Adds the time from its start until it is dropped to the exit handling time of a vCPU.
+#[cfg(feature = "exit-timing")]
+struct ExitTimer<'a> {
+    stats: &'a VcpuStats,
+    start: Instant,
+}
+
+#[cfg(feature = "exit-timing")]
+impl<'a> ExitTimer<'a> {
+    fn start(stats: &'a VcpuStats) -> Self {
+        ExitTimer {
+            stats,
+            start: Instant::now(),
+        }
+    }
+}
+
+#[cfg(feature = "exit-timing")]
+impl Drop for ExitTimer<'_> {
+    fn drop(&mut self) {
+        let elapsed = u64::try_from(self.start.elapsed().as_nanos()).unwrap_or(u64::MAX);
+        self.stats.handling_ns.fetch_add(elapsed, Ordering::Relaxed);
+    }
+}
+
+// Unclaimed MMIO addresses logged per vCPU, the accesses to the next ones are only counted.
+const STRAY_MMIO_LOGGED: usize = 8;
+
+/// Accounts for the accesses of a vCPU to MMIO addresses no device claims.
+///
+/// As on real hardware, those reads return all ones and the writes go nowhere. A guest
+/// probing for devices makes a few, the first addresses are logged once each.
+struct StrayMmio {
+    index: u64,
+    stats: Arc<VcpuStats>,
+    // The addresses logged so far.
+    logged: Vec<u64>,
+}
+
+impl StrayMmio {
+    fn new(index: u64, stats: Arc<VcpuStats>) -> Self {
+        StrayMmio {
+            index,
+            stats,
+            logged: Vec::with_capacity(STRAY_MMIO_LOGGED),
+        }
+    }
+
+    // Check the result of an MMIO `access`, "read" or "write", at `addr`. Returns whether
+    // it reached a device.
+    fn check(&mut self, addr: u64, access: &str, result: result::Result<(), bus::Error>) -> bool {
+        match result {
+            Ok(()) => true,
+            Err(bus::Error::DeviceNotFound) => {
+                self.stats.stray_mmio.fetch_add(1, Ordering::Relaxed);
+                if self.logged.len() < STRAY_MMIO_LOGGED && !self.logged.contains(&addr) {
+                    self.logged.push(addr);
+                    log::debug!(
+                        "vCPU {} MMIO {} at {:#x}, where no device is, ignoring it{}",
+                        self.index,
+                        access,
+                        addr,
+                        match self.logged.len() {
+                            STRAY_MMIO_LOGGED => " and the next addresses",
+                            _ => "",
+                        }
+                    );
+                }
+                false
+            }
+            // A device claims the address, but not the whole access.
+            Err(e) => {
+                eprintln!(
+                    "vCPU {} failed MMIO {} at {:#x}: {:?}",
+                    self.index, access, addr, e
+                );
+                false
+            }
+        }
+    }
+}
+
+// Write `data` to the device at `addr`, if any.
+fn mmio_write(io_manager: &Mutex<IoManager>, stray: &mut StrayMmio, addr: u64, data: &[u8]) {
+    let result = io_manager
+        .lock()
+        .unwrap()
+        .mmio_write(MmioAddress(addr), data);
+    stray.check(addr, "write", result);
+}
+
+// Read `data` from the device at `addr`, all ones if none.
+fn mmio_read(io_manager: &Mutex<IoManager>, stray: &mut StrayMmio, addr: u64, data: &mut [u8]) {
+    let result = io_manager
+        .lock()
+        .unwrap()
+        .mmio_read(MmioAddress(addr), data);
+    if !stray.check(addr, "read", result) {
+        data.fill(0xff);
+    }
+}
+
+// Real-time signal kicking the vCPUs out of guest mode, after SIGRTMIN.
+const KICK_SIGNAL_OFFSET: c_int = 0;
+
+thread_local! {
+    // The vCPU running on this thread, if any, for the kick signal handler.
+    static THREAD_VCPU: Cell<*const VcpuFd> = const { Cell::new(ptr::null()) };
+}
+
+// Make the next, or the current, KVM_RUN of the vCPU on this thread return EINTR. Setting
+// immediate_exit closes the race with a signal coming right before KVM_RUN, which would
+// otherwise go unnoticed until the next exit.
+extern "C" fn handle_kick(_num: c_int, _info: *mut siginfo_t, _unused: *mut c_void) {
+    THREAD_VCPU.with(|vcpu| {
+        // Safe because the pointer is only set while the vCPU runs on this thread.
+        if let Some(vcpu) = unsafe { vcpu.get().as_ref() } {
+            vcpu.set_kvm_immediate_exit(1);
+        }
+    });
+    fence(Ordering::Release);
+}
+
+/// Install the handler of the signal kicking the vCPUs, see [`VcpuHandle::kick`]. This must
+/// be called before the vCPU threads start.
+pub(crate) fn register_kick_handler() -> Result<()> {
+    register_signal_handler(SIGRTMIN() + KICK_SIGNAL_OFFSET, handle_kick).map_err(Error::KickSignal)
+}
+
+/// What the VMM wants a vCPU to do, checked each time it leaves guest mode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum VcpuRunState {
+    /// Run the guest.
+    Running,
+    /// Wait out of guest mode, until told otherwise.
+    Paused,
+    /// Stop the vCPU thread.
+    Exiting,
+}
+
+/// Shared between a vCPU thread and the VMM, which changes its run state and kicks it.
+pub(crate) struct VcpuHandle {
+    state: Mutex<VcpuRunState>,
+    changed: Condvar,
+    // Whether the vCPU thread waits in the pause, out of guest mode.
+    parked: Mutex<bool>,
+    parked_changed: Condvar,
+    // The thread running the vCPU loop, to send the kick signal to.
+    thread: Mutex<Option<libc::pthread_t>>,
+}
+
+impl VcpuHandle {
+    fn new() -> Self {
+        VcpuHandle {
+            state: Mutex::new(VcpuRunState::Running),
+            changed: Condvar::new(),
+            parked: Mutex::new(false),
+            parked_changed: Condvar::new(),
+            thread: Mutex::new(None),
+        }
+    }
+
+    pub fn state(&self) -> VcpuRunState {
+        *self.state.lock().unwrap()
+    }
+
+    /// Ask the vCPU to switch to `state`, and kick it for it to notice. An exiting vCPU
+    /// stays so.
+    pub fn set_state(&self, state: VcpuRunState) {
+        {
+            let mut current = self.state.lock().unwrap();
+            if *current == VcpuRunState::Exiting {
+                return;
+            }
+            *current = state;
+        }
+        self.changed.notify_all();
+        self.kick();
+    }
+
+    /// Get the vCPU out of guest mode, or out of the next KVM_RUN when it is in the VMM.
+    /// It then checks its run state, and gets back to the guest unless told otherwise.
+    ///
+    /// Nothing happens when the vCPU loop does not run.
+    pub fn kick(&self) {
+        let thread = self.thread.lock().unwrap();
+        if let Some(thread) = *thread {
+            // Safe because the thread is running the vCPU loop while we hold the lock, and
+            // the signal has a handler.
+            let ret = unsafe { libc::pthread_kill(thread, SIGRTMIN() + KICK_SIGNAL_OFFSET) };
+            if ret != 0 {
+                eprintln!(
+                    "Failed to kick a vCPU: {}",
+                    std::io::Error::from_raw_os_error(ret)
+                );
+            }
+        }
+    }
+
+    /// Wait up to `timeout` for the paused vCPU to be out of guest mode, with its exit
+    /// handled. Returns `false` on timeout. A vCPU whose loop does not run is.
+    pub fn wait_parked(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut parked = self.parked.lock().unwrap();
+        while !*parked && self.thread.lock().unwrap().is_some() {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            parked = self
+                .parked_changed
+                .wait_timeout(parked, deadline - now)
+                .unwrap()
+                .0;
+        }
+
+        true
+    }
+
+    // Wait while the vCPU is paused. Returns the state it leaves the pause in.
+    fn wait_while_paused(&self) -> VcpuRunState {
+        let state = self.state.lock().unwrap();
+        if *state != VcpuRunState::Paused {
+            return *state;
+        }
+
+        self.set_parked(true);
+        let state = self
+            .changed
+            .wait_while(state, |state| *state == VcpuRunState::Paused)
+            .unwrap();
+        self.set_parked(false);
+        *state
+    }
+
+    fn set_parked(&self, parked: bool) {
+        *self.parked.lock().unwrap() = parked;
+        self.parked_changed.notify_all();
+    }
+}
+
 /// Struct for interacting with vCPUs.
 ///
 /// This struct is a temporary (and quite terrible) placeholder until the
@@ -67,241 +420,387 @@ pub(crate) struct Vcpu {
     /// KVM file descriptor for a vCPU.
     pub vcpu_fd: VcpuFd,
 
-    serial: Arc<Mutex<LumperSerial>>,
-    virtio_manager: Arc<Mutex<IoManager>>,
+    io_manager: Arc<Mutex<IoManager>>,
+    unknown_ports: Arc<UnknownPorts>,
+    stray_mmio: StrayMmio,
+    stats: Arc<VcpuStats>,
+    handle: Arc<VcpuHandle>,
+    // Stops the vCPU for the GDB client, when attached.
+    #[cfg(target_arch = "x86_64")]
+    gdb: Option<Arc<GdbStub>>,
 }
 
 impl Vcpu {
     /// Create a new vCPU.
+    ///
+    /// On x86_64, KVM uses the vCPU ID as its initial APIC ID.
     pub fn new(
         vm_fd: &VmFd,
         index: u64,
-        serial: Arc<Mutex<LumperSerial>>,
-        virtio_manager: Arc<Mutex<IoManager>>,
+        vcpu_id: u64,
+        io_manager: Arc<Mutex<IoManager>>,
+        unknown_ports: Arc<UnknownPorts>,
     ) -> Result<Self> {
+        let stats = Arc::new(VcpuStats::default());
         Ok(Vcpu {
             index,
-            vcpu_fd: vm_fd.create_vcpu(index).map_err(Error::KvmIoctl)?,
-            serial,
-            virtio_manager,
+            vcpu_fd: vm_fd.create_vcpu(vcpu_id).map_err(Error::KvmIoctl)?,
+            io_manager,
+            unknown_ports,
+            stray_mmio: StrayMmio::new(index, stats.clone()),
+            stats,
+            handle: Arc::new(VcpuHandle::new()),
+            #[cfg(target_arch = "x86_64")]
+            gdb: None,
         })
     }
 
-    /// Set CPUID.
-    pub fn configure_cpuid(&self, cpuid: &CpuId) -> Result<()> {
-        self.vcpu_fd.set_cpuid2(cpuid).map_err(Error::KvmIoctl)
+    pub fn stats(&self) -> Arc<VcpuStats> {
+        self.stats.clone()
     }
 
-    /// Configure MSRs.
-    pub fn configure_msrs(&self) -> Result<()> {
-        let msrs = msrs::create_boot_msr_entries().map_err(Error::CreateMsr)?;
-        self.vcpu_fd
-            .set_msrs(&msrs)
-            .map_err(Error::KvmIoctl)
-            .and_then(|msrs_written| {
-                if msrs_written as u32 != msrs.as_fam_struct_ref().nmsrs {
-                    Err(Error::SetModelSpecificRegistersCount)
-                } else {
-                    Ok(())
-                }
-            })
+    /// The handle the VMM controls the vCPU thread with.
+    pub fn handle(&self) -> Arc<VcpuHandle> {
+        self.handle.clone()
     }
 
-    /// Configure regs.
-    pub fn configure_regs(&self, kernel_load: GuestAddress) -> Result<()> {
-        let regs = kvm_regs {
-            rflags: 0x0000_0000_0000_0002u64,
-            rip: kernel_load.raw_value(),
-            // Frame pointer. It gets a snapshot of the stack pointer (rsp) so that when adjustments are
-            // made to rsp (i.e. reserving space for local variables or pushing values on to the stack),
-            // local variables and function parameters are still accessible from a constant offset from rbp.
-            rsp: BOOT_STACK_POINTER,
-            // Starting stack pointer.
-            rbp: BOOT_STACK_POINTER,
-            // Must point to zero page address per Linux ABI. This is x86_64 specific.
-            rsi: crate::kernel::ZEROPG_START,
-            ..Default::default()
-        };
-        self.vcpu_fd.set_regs(&regs).map_err(Error::KvmIoctl)
+    /// Give the vCPU a new handle, for its next thread. The one of the previous thread
+    /// stays exiting.
+    pub fn reset_handle(&mut self) {
+        self.handle = Arc::new(VcpuHandle::new());
     }
 
-    /// Configure sregs.
-    pub fn configure_sregs(&self, guest_memory: &GuestMemoryMmap) -> Result<()> {
-        let mut sregs = self.vcpu_fd.get_sregs().map_err(Error::KvmIoctl)?;
-
-        // Global descriptor tables.
-        let gdt_table: [u64; BOOT_GDT_MAX as usize] = [
-            gdt_entry(0, 0, 0),            // NULL
-            gdt_entry(0xa09b, 0, 0xfffff), // CODE
-            gdt_entry(0xc093, 0, 0xfffff), // DATA
-            gdt_entry(0x808b, 0, 0xfffff), // TSS
-        ];
-
-        let code_seg = kvm_segment_from_gdt(gdt_table[1], 1);
-        let data_seg = kvm_segment_from_gdt(gdt_table[2], 2);
-        let tss_seg = kvm_segment_from_gdt(gdt_table[3], 3);
-
-        // Write segments to guest memory.
-        write_gdt_table(&gdt_table[..], guest_memory).map_err(Error::GuestMemory)?;
-        sregs.gdt.base = BOOT_GDT_OFFSET as u64;
-        sregs.gdt.limit = std::mem::size_of_val(&gdt_table) as u16 - 1;
-
-        write_idt_value(0, guest_memory).map_err(Error::GuestMemory)?;
-        sregs.idt.base = BOOT_IDT_OFFSET as u64;
-        sregs.idt.limit = std::mem::size_of::<u64>() as u16 - 1;
-
-        sregs.cs = code_seg;
-        sregs.ds = data_seg;
-        sregs.es = data_seg;
-        sregs.fs = data_seg;
-        sregs.gs = data_seg;
-        sregs.ss = data_seg;
-        sregs.tr = tss_seg;
-
-        // 64-bit protected mode.
-        sregs.cr0 |= X86_CR0_PE;
-        sregs.efer |= (msr_index::EFER_LME | msr_index::EFER_LMA) as u64;
-
-        // Start page table configuration.
-        // Puts PML4 right after zero page but aligned to 4k.
-        let boot_pml4_addr = GuestAddress(PML4_START);
-        let boot_pdpte_addr = GuestAddress(PDPTE_START);
-        let boot_pde_addr = GuestAddress(PDE_START);
-
-        // Entry covering VA [0..512GB).
-        guest_memory
-            .write_obj(boot_pdpte_addr.raw_value() as u64 | 0x03, boot_pml4_addr)
-            .map_err(Error::GuestMemory)?;
-
-        // Entry covering VA [0..1GB).
-        guest_memory
-            .write_obj(boot_pde_addr.raw_value() as u64 | 0x03, boot_pdpte_addr)
-            .map_err(Error::GuestMemory)?;
-
-        // 512 2MB entries together covering VA [0..1GB).
-        // This assumes that the CPU supports 2MB pages (/proc/cpuinfo has 'pse').
-        for i in 0..512 {
-            guest_memory
-                .write_obj((i << 21) + 0x83u64, boot_pde_addr.unchecked_add(i * 8))
-                .map_err(Error::GuestMemory)?;
-        }
-
-        sregs.cr3 = boot_pml4_addr.raw_value() as u64;
-        sregs.cr4 |= X86_CR4_PAE;
-        sregs.cr0 |= X86_CR0_PG;
-
-        self.vcpu_fd.set_sregs(&sregs).map_err(Error::KvmIoctl)
+    /// Stop the vCPU for the client of `gdb` on its breakpoints, see [`GdbStub`].
+    #[cfg(target_arch = "x86_64")]
+    pub fn set_gdb(&mut self, gdb: Arc<GdbStub>) -> Result<()> {
+        set_guest_debug(&self.vcpu_fd, false).map_err(Error::KvmIoctl)?;
+        self.gdb = Some(gdb);
+        Ok(())
     }
 
-    /// Configure FPU.
-    pub fn configure_fpu(&self) -> Result<()> {
-        let fpu = kvm_fpu {
-            fcw: 0x37f,
-            mxcsr: 0x1f80,
-            ..Default::default()
-        };
-        self.vcpu_fd.set_fpu(&fpu).map_err(Error::KvmIoctl)
+    /// Kick the vCPU out of guest mode, see [`VcpuHandle::kick`].
+    #[allow(dead_code)]
+    pub fn kick(&self) {
+        self.handle.kick();
     }
 
-    /// Configures LAPICs. LAPIC0 is set for external interrupts, LAPIC1 is set for NMI.
-    pub fn configure_lapic(&self) -> Result<()> {
-        let mut klapic = self.vcpu_fd.get_lapic().map_err(Error::KvmIoctl)?;
-
-        let lvt_lint0 = get_klapic_reg(&klapic, APIC_LVT0);
-        set_klapic_reg(
-            &mut klapic,
-            APIC_LVT0,
-            set_apic_delivery_mode(lvt_lint0, APIC_MODE_EXTINT),
-        );
-        let lvt_lint1 = get_klapic_reg(&klapic, APIC_LVT1);
-        set_klapic_reg(
-            &mut klapic,
-            APIC_LVT1,
-            set_apic_delivery_mode(lvt_lint1, APIC_MODE_NMI),
-        );
+    /// Run the vCPU on the calling thread until it stops the VM, or the VMM tells it to
+    /// exit. Returns why the VM must stop, if the vCPU stopped it.
+    ///
+    /// When told to exit, the vCPU completes its pending I/O first: its state can then be
+    /// saved, and it can run again from another thread.
+    ///
+    /// A panic while handling an exit, e.g. in a device, stops the VM with a vCPU error:
+    /// it must not go on with a CPU less.
+    pub fn run_until_exit(&mut self) -> Option<ExitReason> {
+        let index = self.index;
+        THREAD_VCPU.with(|vcpu| vcpu.set(&self.vcpu_fd));
+        // Safe because it only returns the calling thread.
+        *self.handle.thread.lock().unwrap() = Some(unsafe { libc::pthread_self() });
+
+        let handle = self.handle.clone();
+        let reason = panic::catch_unwind(AssertUnwindSafe(|| {
+            // The boot vCPU waits for the GDB client before its first instruction.
+            #[cfg(target_arch = "x86_64")]
+            if let Some(gdb) = self.gdb.as_ref() {
+                if let Some(reason) = gdb.attach(self.index, &self.vcpu_fd) {
+                    return Some(reason);
+                }
+            }
+            loop {
+                if handle.wait_while_paused() == VcpuRunState::Exiting {
+                    self.complete_io();
+                    return None;
+                }
+                if let Some(reason) = self.run() {
+                    return Some(reason);
+                }
+            }
+        }))
+        .unwrap_or_else(|payload| {
+            Some(ExitReason::VcpuError(format!(
+                "vCPU {} panicked: {}",
+                index,
+                panic_message(&*payload)
+            )))
+        });
+
+        // No kick reaches the thread past this point, nor does it park.
+        *self.handle.thread.lock().unwrap() = None;
+        self.handle.set_parked(false);
+        THREAD_VCPU.with(|vcpu| vcpu.set(ptr::null()));
+        reason
+    }
 
-        self.vcpu_fd.set_lapic(&klapic).map_err(Error::KvmIoctl)
+    // Finish the I/O instruction of the last exit, if any, without running the guest
+    // further: KVM only completes it on the next KVM_RUN, and its state is not saved until
+    // then.
+    fn complete_io(&mut self) {
+        self.vcpu_fd.set_kvm_immediate_exit(1);
+        match self.vcpu_fd.run() {
+            Err(e) if e.errno() == libc::EINTR => {}
+            Err(e) => eprintln!("vCPU {} failed to complete its I/O: {}", self.index, e),
+            Ok(exit) => eprintln!(
+                "vCPU {} exited while completing its I/O: {:?}",
+                self.index, exit
+            ),
+        }
+        self.vcpu_fd.set_kvm_immediate_exit(0);
     }
 
     /// vCPU emulation loop.
-    pub fn run(&mut self) {
+    ///
+    /// Returns why the VM must stop, if this exit ends it.
+    pub fn run(&mut self) -> Option<ExitReason> {
         // Call into KVM to launch (VMLAUNCH) or resume (VMRESUME) the virtual CPU.
         // This is a blocking function, it only returns for either an error or a
         // VM-Exit. In the latter case, we can inspect the exit reason.
-        match self.vcpu_fd.run() {
-            Ok(exit_reason) => match exit_reason {
-                // The VM stopped (Shutdown ot HLT).
-                VcpuExit::Shutdown | VcpuExit::Hlt => {
-                    println!("Guest shutdown: {:?}. Bye!", exit_reason);
-                    let stdin = io::stdin();
-                    let stdin_lock = stdin.lock();
-                    stdin_lock.set_canon_mode().unwrap();
-
-                    unsafe { libc::exit(0) };
-                }
+        let exit = self.vcpu_fd.run();
+        // Back in the VMM, until this returns to get into the guest again.
+        #[cfg(feature = "exit-timing")]
+        let _timer = ExitTimer::start(&self.stats);
+        if let Ok(exit) = exit.as_ref() {
+            self.stats.record_exit(exit);
+            if logging::trace_exits() {
+                log::debug!("vCPU {} exit: {:?}", self.index, exit);
+            }
+        }
 
+        match exit {
+            Ok(exit_reason) => match exit_reason {
                 // This is a PIO write, i.e. the guest is trying to write
                 // something to an I/O port.
-                VcpuExit::IoOut(addr, data) => match addr {
-                    SERIAL_PORT_BASE..=SERIAL_PORT_LAST_REGISTER => {
-                        self.serial
-                            .lock()
-                            .unwrap()
-                            .serial
-                            .write(
-                                (addr - SERIAL_PORT_BASE)
-                                    .try_into()
-                                    .expect("Invalid serial register offset"),
-                                data[0],
-                            )
-                            .unwrap();
-                    }
-                    _ => {
-                        println!("Unsupported device write at {:x?}", addr);
+                VcpuExit::IoOut(addr, data) => {
+                    let result = self
+                        .io_manager
+                        .lock()
+                        .unwrap()
+                        .pio_write(PioAddress(addr), data);
+                    if result.is_err() {
+                        self.unknown_ports.access(addr, "write");
                     }
-                },
+                }
 
                 // This is a PIO read, i.e. the guest is trying to read
                 // from an I/O port.
-                VcpuExit::IoIn(addr, data) => match addr {
-                    SERIAL_PORT_BASE..=SERIAL_PORT_LAST_REGISTER => {
-                        data[0] = self.serial.lock().unwrap().serial.read(
-                            (addr - SERIAL_PORT_BASE)
-                                .try_into()
-                                .expect("Invalid serial register offset"),
-                        );
-                    }
-                    _ => {
-                        println!("Unsupported device read at {:x?}", addr);
+                VcpuExit::IoIn(addr, data) => {
+                    let result = self
+                        .io_manager
+                        .lock()
+                        .unwrap()
+                        .pio_read(PioAddress(addr), data);
+                    if result.is_err() {
+                        data.fill(0xff);
+                        self.unknown_ports.access(addr, "read");
                     }
-                },
+                }
 
                 // This is a MMIO write, i.e. the guest is trying to write
                 // something to a memory-mapped I/O region.
                 VcpuExit::MmioWrite(addr, data) => {
-                    self.virtio_manager
-                        .lock()
-                        .unwrap()
-                        .mmio_write(MmioAddress(addr), data)
-                        .unwrap();
+                    mmio_write(&self.io_manager, &mut self.stray_mmio, addr, data);
                 }
 
                 // This is a MMIO read, i.e. the guest is trying to read
                 // from a memory-mapped I/O region.
                 VcpuExit::MmioRead(addr, data) => {
-                    self.virtio_manager
+                    mmio_read(&self.io_manager, &mut self.stray_mmio, addr, data);
+                }
+
+                // With the split irqchip, the local APIC got the EOI of a level-triggered
+                // interrupt from the userspace IOAPIC, which clears its remote IRR.
+                #[cfg(target_arch = "x86_64")]
+                VcpuExit::IoapicEoi(vector) => {
+                    let result = self
+                        .io_manager
                         .lock()
                         .unwrap()
-                        .mmio_read(MmioAddress(addr), data)
-                        .unwrap();
+                        .mmio_write(MmioAddress(IOAPIC_START + IOAPIC_EOI), &[vector, 0, 0, 0]);
+                    if let Err(e) = result {
+                        log::debug!("Ignoring the EOI of vector {:#x}: {}", vector, e);
+                    }
+                }
+
+                // A breakpoint or a single step, with the guest debug set up for GDB.
+                #[cfg(target_arch = "x86_64")]
+                VcpuExit::Debug(debug) => {
+                    if let Some(gdb) = self.gdb.as_ref() {
+                        return gdb.debug_exit(self.index, &self.vcpu_fd, debug);
+                    }
                 }
 
-                _ => {
-                    eprintln!("Unhandled VM-Exit: {:?}", exit_reason);
+                exit_reason => {
+                    if let VcpuAction::Stop(reason) = handle_exit(self.index, exit_reason) {
+                        return Some(reason);
+                    }
                 }
             },
 
-            Err(e) => eprintln!("Emulation error: {}", e),
+            // Kicked, or interrupted by another signal: the caller checks its run state
+            // before getting back to the guest.
+            Err(e) if e.errno() == libc::EINTR || e.errno() == libc::EAGAIN => {
+                self.vcpu_fd.set_kvm_immediate_exit(0);
+            }
+
+            Err(e) => {
+                return Some(ExitReason::VcpuError(format!(
+                    "vCPU {} emulation error: {}",
+                    self.index, e
+                )))
+            }
+        }
+
+        None
+    }
+}
+
+/// What the vCPU loop does after an exit.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum VcpuAction {
+    /// Get back to the guest.
+    Resume,
+    /// Stop the VM.
+    Stop(ExitReason),
+}
+
+// Handle the exits of the vCPU `index` which involve no device: the ones stopping the VM,
+// and the unexpected ones.
+fn handle_exit(index: u64, exit: VcpuExit) -> VcpuAction {
+    match exit {
+        // The VM stopped.
+        VcpuExit::Hlt => VcpuAction::Stop(ExitReason::GuestShutdown),
+        // A triple fault resets the machine, e.g. with reboot=t.
+        VcpuExit::Shutdown => VcpuAction::Stop(ExitReason::GuestReset),
+
+        // PSCI SYSTEM_OFF and SYSTEM_RESET on aarch64, or a crash the guest reported through
+        // the Hyper-V crash MSRs.
+        VcpuExit::SystemEvent(event_type, _) => VcpuAction::Stop(match event_type {
+            KVM_SYSTEM_EVENT_SHUTDOWN => ExitReason::GuestShutdown,
+            KVM_SYSTEM_EVENT_RESET => ExitReason::GuestReset,
+            KVM_SYSTEM_EVENT_CRASH => ExitReason::GuestPanic(PanicReport::SystemEvent),
+            event_type => ExitReason::VcpuError(format!(
+                "vCPU {} unknown system event {}",
+                index, event_type
+            )),
+        }),
+
+        exit => {
+            eprintln!("Unhandled VM-Exit: {:?}", exit);
+            VcpuAction::Resume
+        }
+    }
+}
+
+// The message a panic was raised with, as `panic!` formats it.
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown panic payload"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use vm_device::bus::{MmioAddressOffset, MmioRange};
+    use vm_device::MutDeviceMmio;
+
+    use super::*;
+
+    struct Register(u8);
+
+    impl MutDeviceMmio for Register {
+        fn mmio_read(&mut self, _base: MmioAddress, _offset: MmioAddressOffset, data: &mut [u8]) {
+            data.fill(self.0);
         }
+
+        fn mmio_write(&mut self, _base: MmioAddress, _offset: MmioAddressOffset, data: &[u8]) {
+            self.0 = data[0];
+        }
+    }
+
+    #[test]
+    fn stray_mmio() {
+        let mut io_manager = IoManager::new();
+        io_manager
+            .register_mmio(
+                MmioRange::new(MmioAddress(0x1000), 0x10).unwrap(),
+                Arc::new(Mutex::new(Register(0))),
+            )
+            .unwrap();
+        let io_manager = Mutex::new(io_manager);
+        let stats = Arc::new(VcpuStats::default());
+        let mut stray = StrayMmio::new(0, stats.clone());
+
+        let mut data = [0u8; 4];
+        mmio_write(&io_manager, &mut stray, 0x1000, &[0x42]);
+        mmio_read(&io_manager, &mut stray, 0x1004, &mut data);
+        assert_eq!(data, [0x42; 4]);
+        assert_eq!(stats.counters().stray_mmio, 0);
+
+        // Nothing at 0x2000, the read gets all ones.
+        mmio_write(&io_manager, &mut stray, 0x2000, &[0x42]);
+        mmio_read(&io_manager, &mut stray, 0x2000, &mut data);
+        assert_eq!(data, [0xff; 4]);
+        assert_eq!(stats.counters().stray_mmio, 2);
+
+        // Only the first addresses are logged, all are counted.
+        for addr in 0..2 * STRAY_MMIO_LOGGED as u64 {
+            mmio_read(&io_manager, &mut stray, 0x3000 + addr, &mut data);
+        }
+        assert_eq!(stray.logged.len(), STRAY_MMIO_LOGGED);
+        assert_eq!(
+            stats.counters().stray_mmio,
+            2 + 2 * STRAY_MMIO_LOGGED as u64
+        );
+
+        // An access running past the end of the device is not stray, but still fails.
+        data = [0; 4];
+        mmio_read(&io_manager, &mut stray, 0x100e, &mut data);
+        assert_eq!(data, [0xff; 4]);
+        assert_eq!(
+            stats.counters().stray_mmio,
+            2 + 2 * STRAY_MMIO_LOGGED as u64
+        );
+    }
+
+    #[test]
+    fn stopping_exits() {
+        assert_eq!(
+            handle_exit(0, VcpuExit::Hlt),
+            VcpuAction::Stop(ExitReason::GuestShutdown)
+        );
+        assert_eq!(
+            handle_exit(0, VcpuExit::Shutdown),
+            VcpuAction::Stop(ExitReason::GuestReset)
+        );
+        assert_eq!(handle_exit(0, VcpuExit::IrqWindowOpen), VcpuAction::Resume);
+    }
+
+    #[test]
+    fn system_events() {
+        let event =
+            |event_type| handle_exit(1, VcpuExit::SystemEvent(event_type, Default::default()));
+
+        assert_eq!(
+            event(KVM_SYSTEM_EVENT_SHUTDOWN),
+            VcpuAction::Stop(ExitReason::GuestShutdown)
+        );
+        assert_eq!(
+            event(KVM_SYSTEM_EVENT_RESET),
+            VcpuAction::Stop(ExitReason::GuestReset)
+        );
+        assert_eq!(
+            event(KVM_SYSTEM_EVENT_CRASH),
+            VcpuAction::Stop(ExitReason::GuestPanic(PanicReport::SystemEvent))
+        );
+        assert_eq!(
+            event(42),
+            VcpuAction::Stop(ExitReason::VcpuError(
+                "vCPU 1 unknown system event 42".to_string()
+            ))
+        );
     }
 }
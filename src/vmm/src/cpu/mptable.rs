@@ -14,7 +14,9 @@ use libc::c_char;
 
 use vm_memory::{Address, ByteValued, Bytes, GuestAddress, GuestMemory, GuestMemoryMmap};
 
+use crate::config::CpuTopology;
 use crate::cpu::mpspec;
+use crate::layout::{APIC_START, IOAPIC_START, MPTABLE_START};
 
 // This is a workaround to the Rust enforcement specifying that any implementation of a foreign
 // trait (in this case `ByteValued`) where:
@@ -45,32 +47,43 @@ unsafe impl ByteValued for MpcTableWrapper {}
 unsafe impl ByteValued for MpcLintsrcWrapper {}
 unsafe impl ByteValued for MpfIntelWrapper {}
 
-// MPTABLE, describing VCPUS.
-const MPTABLE_START: u64 = 0x9fc00;
-
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, thiserror::Error)]
 pub enum Error {
     /// There was too little guest memory to store the entire MP table.
+    #[error("not enough guest memory to store the MP table")]
     NotEnoughMemory,
     /// The MP table has too little address space to be stored.
+    #[error("the MP table has too little address space to be stored")]
     AddressOverflow,
     /// Failure while zeroing out the memory for the MP table.
+    #[error("failed to zero out the MP table memory")]
     Clear,
     /// Number of CPUs exceeds the maximum supported CPUs
+    #[error("too many vCPUs for the MP table (at most {})", MAX_MPTABLE_CPUS)]
     TooManyCpus,
+    /// An APIC ID does not fit in the 8 bits of the MP table entries.
+    #[error("APIC ID {0} does not fit in the MP table")]
+    ApicIdOverflow(u32),
     /// Failure to write the MP floating pointer.
+    #[error("failed to write the MP floating pointer")]
     WriteMpfIntel,
     /// Failure to write MP CPU entry.
+    #[error("failed to write an MP CPU entry")]
     WriteMpcCpu,
     /// Failure to write MP ioapic entry.
+    #[error("failed to write the MP IOAPIC entry")]
     WriteMpcIoapic,
     /// Failure to write MP bus entry.
+    #[error("failed to write an MP bus entry")]
     WriteMpcBus,
     /// Failure to write MP interrupt source entry.
+    #[error("failed to write an MP interrupt source entry")]
     WriteMpcIntsrc,
     /// Failure to write MP local interrupt source entry.
+    #[error("failed to write an MP local interrupt source entry")]
     WriteMpcLintsrc,
     /// Failure to write MP table header.
+    #[error("failed to write the MP table header")]
     WriteMpcTable,
 }
 
@@ -81,6 +94,13 @@ pub type Result<T> = result::Result<T, Error>;
 // a large number for FC usecases.
 pub const MAX_SUPPORTED_CPUS: u32 = 254;
 
+/// vCPUs the MP table describes at most. It only serves guests without ACPI, the larger VMs
+/// are described by the ACPI tables alone.
+pub const MAX_MPTABLE_CPUS: u32 = 64;
+
+// Destination of the MP table entries sent to all the local APICs.
+const APIC_ID_BROADCAST: u32 = 0xff;
+
 // Convenience macro for making arrays of diverse character types.
 macro_rules! char_array {
     ($t:ty; $( $c:expr ),*) => ( [ $( $c as $t ),* ] )
@@ -93,8 +113,6 @@ const MPC_SPEC: i8 = 4;
 const MPC_OEM: [c_char; 8] = char_array!(c_char; 'F', 'C', ' ', ' ', ' ', ' ', ' ', ' ');
 const MPC_PRODUCT_ID: [c_char; 12] = ['0' as c_char; 12];
 const BUS_TYPE_ISA: [u8; 6] = char_array!(u8; 'I', 'S', 'A', ' ', ' ', ' ');
-const IO_APIC_DEFAULT_PHYS_BASE: u32 = 0xfec0_0000; // source: linux/arch/x86/include/asm/apicdef.h
-const APIC_DEFAULT_PHYS_BASE: u32 = 0xfee0_0000; // source: linux/arch/x86/include/asm/apicdef.h
 const APIC_VERSION: u8 = 0x14;
 const CPU_STEPPING: u32 = 0x600;
 const CPU_FEATURE_APIC: u32 = 0x200;
@@ -115,6 +133,14 @@ fn mpf_intel_compute_checksum(v: &mpspec::mpf_intel) -> u8 {
     (!checksum).wrapping_add(1)
 }
 
+// The 8 bits APIC ID of an entry, which must not be the broadcast one.
+fn mp_apic_id(apic_id: u32) -> Result<u8> {
+    u8::try_from(apic_id)
+        .ok()
+        .filter(|id| u32::from(*id) < APIC_ID_BROADCAST)
+        .ok_or(Error::ApicIdOverflow(apic_id))
+}
+
 fn compute_mp_size(num_cpus: u8) -> usize {
     mem::size_of::<MpfIntelWrapper>()
         + mem::size_of::<MpcTableWrapper>()
@@ -125,11 +151,17 @@ fn compute_mp_size(num_cpus: u8) -> usize {
         + mem::size_of::<MpcLintsrcWrapper>() * 2
 }
 
-/// Performs setup of the MP table for the given `num_cpus`.
-pub fn setup_mptable(mem: &GuestMemoryMmap, num_cpus: u8) -> Result<()> {
-    if u32::from(num_cpus) > MAX_SUPPORTED_CPUS {
+/// Performs setup of the MP table for the given CPU `topology`.
+///
+/// Only the first `boot_cpus` vCPUs are enabled, the guest counts the others as
+/// hot-pluggable.
+pub fn setup_mptable(mem: &GuestMemoryMmap, topology: &CpuTopology, boot_cpus: u8) -> Result<()> {
+    if topology.vcpu_count() > MAX_MPTABLE_CPUS {
         return Err(Error::TooManyCpus);
     }
+    let num_cpus = topology.vcpu_count() as u8;
+
+    let ioapicid = mp_apic_id(topology.ioapic_id())?;
 
     // Used to keep track of the next base pointer into the MP table.
     let mut base_mp = GuestAddress(MPTABLE_START);
@@ -137,7 +169,6 @@ pub fn setup_mptable(mem: &GuestMemoryMmap, num_cpus: u8) -> Result<()> {
     let mp_size = compute_mp_size(num_cpus);
 
     let mut checksum: u8 = 0;
-    let ioapicid: u8 = num_cpus + 1;
 
     // The checked_add here ensures the all of the following base_mp.unchecked_add's will be without
     // overflow.
@@ -175,14 +206,13 @@ pub fn setup_mptable(mem: &GuestMemoryMmap, num_cpus: u8) -> Result<()> {
         for cpu_id in 0..num_cpus {
             let mut mpc_cpu = MpcCpuWrapper(mpspec::mpc_cpu::default());
             mpc_cpu.0.type_ = mpspec::MP_PROCESSOR as u8;
-            mpc_cpu.0.apicid = cpu_id;
+            mpc_cpu.0.apicid = mp_apic_id(topology.apic_id(cpu_id))?;
             mpc_cpu.0.apicver = APIC_VERSION;
-            mpc_cpu.0.cpuflag = mpspec::CPU_ENABLED as u8
-                | if cpu_id == 0 {
-                    mpspec::CPU_BOOTPROCESSOR as u8
-                } else {
-                    0
-                };
+            mpc_cpu.0.cpuflag = match cpu_id {
+                0 => mpspec::CPU_ENABLED as u8 | mpspec::CPU_BOOTPROCESSOR as u8,
+                id if id < boot_cpus => mpspec::CPU_ENABLED as u8,
+                _ => 0,
+            };
             mpc_cpu.0.cpufeature = CPU_STEPPING;
             mpc_cpu.0.featureflag = CPU_FEATURE_APIC | CPU_FEATURE_FPU;
             mem.write_obj(mpc_cpu, base_mp)
@@ -209,7 +239,7 @@ pub fn setup_mptable(mem: &GuestMemoryMmap, num_cpus: u8) -> Result<()> {
         mpc_ioapic.0.apicid = ioapicid;
         mpc_ioapic.0.apicver = APIC_VERSION;
         mpc_ioapic.0.flags = mpspec::MPC_APIC_USABLE as u8;
-        mpc_ioapic.0.apicaddr = IO_APIC_DEFAULT_PHYS_BASE;
+        mpc_ioapic.0.apicaddr = IOAPIC_START as u32;
         mem.write_obj(mpc_ioapic, base_mp)
             .map_err(|_| Error::WriteMpcIoapic)?;
         base_mp = base_mp.unchecked_add(size);
@@ -254,7 +284,7 @@ pub fn setup_mptable(mem: &GuestMemoryMmap, num_cpus: u8) -> Result<()> {
         mpc_lintsrc.0.irqflag = mpspec::MP_IRQDIR_DEFAULT as u16;
         mpc_lintsrc.0.srcbusid = 0;
         mpc_lintsrc.0.srcbusirq = 0;
-        mpc_lintsrc.0.destapic = 0xFF; /* to all local APICs */
+        mpc_lintsrc.0.destapic = APIC_ID_BROADCAST as u8;
         mpc_lintsrc.0.destapiclint = 1;
         mem.write_obj(mpc_lintsrc, base_mp)
             .map_err(|_| Error::WriteMpcLintsrc)?;
@@ -274,7 +304,7 @@ pub fn setup_mptable(mem: &GuestMemoryMmap, num_cpus: u8) -> Result<()> {
         mpc_table.0.spec = MPC_SPEC;
         mpc_table.0.oem = MPC_OEM;
         mpc_table.0.productid = MPC_PRODUCT_ID;
-        mpc_table.0.lapic = APIC_DEFAULT_PHYS_BASE;
+        mpc_table.0.lapic = APIC_START as u32;
         checksum = checksum.wrapping_add(compute_checksum(&mpc_table.0));
         mpc_table.0.checksum = (!checksum).wrapping_add(1) as i8;
         mem.write_obj(mpc_table, table_base)
@@ -309,7 +339,7 @@ mod tests {
         )])
         .unwrap();
 
-        setup_mptable(&mem, num_cpus).unwrap();
+        setup_mptable(&mem, &CpuTopology::flat(num_cpus), num_cpus).unwrap();
     }
 
     #[test]
@@ -321,7 +351,7 @@ mod tests {
         )])
         .unwrap();
 
-        assert!(setup_mptable(&mem, num_cpus).is_err());
+        assert!(setup_mptable(&mem, &CpuTopology::flat(num_cpus), num_cpus).is_err());
     }
 
     #[test]
@@ -333,7 +363,7 @@ mod tests {
         )])
         .unwrap();
 
-        setup_mptable(&mem, num_cpus).unwrap();
+        setup_mptable(&mem, &CpuTopology::flat(num_cpus), num_cpus).unwrap();
 
         let mpf_intel: MpfIntelWrapper = mem.read_obj(GuestAddress(MPTABLE_START)).unwrap();
 
@@ -352,7 +382,7 @@ mod tests {
         )])
         .unwrap();
 
-        setup_mptable(&mem, num_cpus).unwrap();
+        setup_mptable(&mem, &CpuTopology::flat(num_cpus), num_cpus).unwrap();
 
         let mpf_intel: MpfIntelWrapper = mem.read_obj(GuestAddress(MPTABLE_START)).unwrap();
         let mpc_offset = GuestAddress(u64::from(mpf_intel.0.physptr));
@@ -381,12 +411,12 @@ mod tests {
     fn cpu_entry_count() {
         let mem = GuestMemoryMmap::from_ranges(&[(
             GuestAddress(MPTABLE_START),
-            compute_mp_size(MAX_SUPPORTED_CPUS as u8),
+            compute_mp_size(MAX_MPTABLE_CPUS as u8),
         )])
         .unwrap();
 
-        for i in 0..MAX_SUPPORTED_CPUS as u8 {
-            setup_mptable(&mem, i).unwrap();
+        for i in 0..=MAX_MPTABLE_CPUS as u8 {
+            setup_mptable(&mem, &CpuTopology::flat(i), i).unwrap();
 
             let mpf_intel: MpfIntelWrapper = mem.read_obj(GuestAddress(MPTABLE_START)).unwrap();
             let mpc_offset = GuestAddress(u64::from(mpf_intel.0.physptr));
@@ -413,16 +443,60 @@ mod tests {
         }
     }
 
+    #[test]
+    fn topology_apic_ids() {
+        let topology = CpuTopology {
+            sockets: 2,
+            cores_per_socket: 3,
+            threads_per_core: 1,
+        };
+        let mem = GuestMemoryMmap::from_ranges(&[(
+            GuestAddress(MPTABLE_START),
+            compute_mp_size(topology.vcpu_count() as u8),
+        )])
+        .unwrap();
+
+        setup_mptable(&mem, &topology, 4).unwrap();
+
+        let mpf_intel: MpfIntelWrapper = mem.read_obj(GuestAddress(MPTABLE_START)).unwrap();
+        let mut entry_offset = GuestAddress(u64::from(mpf_intel.0.physptr))
+            .checked_add(mem::size_of::<MpcTableWrapper>() as u64)
+            .unwrap();
+        let mut apic_ids = Vec::new();
+        let mut enabled = Vec::new();
+        for _ in 0..topology.vcpu_count() {
+            let mpc_cpu: MpcCpuWrapper = mem.read_obj(entry_offset).unwrap();
+            apic_ids.push(mpc_cpu.0.apicid);
+            enabled.push(mpc_cpu.0.cpuflag & mpspec::CPU_ENABLED as u8 != 0);
+            entry_offset = entry_offset.unchecked_add(mem::size_of::<MpcCpuWrapper>() as u64);
+        }
+        // Core IDs take 2 bits, the second socket starts at APIC ID 4.
+        assert_eq!(apic_ids, vec![0, 1, 2, 4, 5, 6]);
+        // The last ones are left for hotplug.
+        assert_eq!(enabled, vec![true, true, true, true, false, false]);
+
+        let mpc_ioapic: MpcIoapicWrapper = mem.read_obj(entry_offset).unwrap();
+        assert_eq!(mpc_ioapic.0.apicid, 8);
+    }
+
     #[test]
     fn cpu_entry_count_max() {
-        let cpus = MAX_SUPPORTED_CPUS + 1;
+        let cpus = MAX_MPTABLE_CPUS + 1;
         let mem = GuestMemoryMmap::from_ranges(&[(
             GuestAddress(MPTABLE_START),
             compute_mp_size(cpus as u8),
         )])
         .unwrap();
 
-        let result = setup_mptable(&mem, cpus as u8).unwrap_err();
+        let result = setup_mptable(&mem, &CpuTopology::flat(cpus as u8), cpus as u8).unwrap_err();
         assert_eq!(result, Error::TooManyCpus);
     }
+
+    #[test]
+    fn apic_id_range() {
+        assert_eq!(mp_apic_id(0), Ok(0));
+        assert_eq!(mp_apic_id(0xfe), Ok(0xfe));
+        assert_eq!(mp_apic_id(0xff), Err(Error::ApicIdOverflow(0xff)));
+        assert_eq!(mp_apic_id(0x100), Err(Error::ApicIdOverflow(0x100)));
+    }
 }
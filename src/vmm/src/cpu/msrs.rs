@@ -3,31 +3,63 @@
 
 use std::default::Default;
 
-use kvm_bindings::{kvm_msr_entry, Msrs};
+use kvm_bindings::{kvm_msr_entry, CpuId, Msrs};
 
+use crate::cpu::cpuid::{
+    kvm_features, KVM_FEATURE_ASYNC_PF, KVM_FEATURE_ASYNC_PF_INT, KVM_FEATURE_PV_EOI,
+    KVM_FEATURE_STEAL_TIME,
+};
 use crate::cpu::msr_index::{
     MSR_CSTAR, MSR_IA32_MISC_ENABLE, MSR_IA32_MISC_ENABLE_FAST_STRING, MSR_IA32_SYSENTER_CS,
     MSR_IA32_SYSENTER_EIP, MSR_IA32_SYSENTER_ESP, MSR_IA32_TSC, MSR_KERNEL_GS_BASE, MSR_LSTAR,
     MSR_STAR, MSR_SYSCALL_MASK,
 };
 
+// KVM paravirtual MSRs.
+pub const MSR_KVM_ASYNC_PF_EN: u32 = 0x4b56_4d02;
+pub const MSR_KVM_STEAL_TIME: u32 = 0x4b56_4d03;
+pub const MSR_KVM_PV_EOI_EN: u32 = 0x4b56_4d04;
+pub const MSR_KVM_ASYNC_PF_INT: u32 = 0x4b56_4d06;
+
 // Errors associated with operations on MSRs.
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, thiserror::Error)]
 pub enum Error {
     /// Failed to initialize MSRS.
+    #[error("failed to initialize the MSR entries")]
     CreateMsrs,
 }
 /// Specialized result type for operations on MSRs.
 pub type Result<T> = std::result::Result<T, Error>;
 
-pub fn create_boot_msr_entries() -> Result<Msrs> {
+/// The KVM paravirtual features with an MSR, and their MSR, in the order they are set: the
+/// async page fault interrupt vector goes before the MSR enabling them with it.
+pub const PV_MSRS: [(u32, u32); 4] = [
+    (KVM_FEATURE_ASYNC_PF_INT, MSR_KVM_ASYNC_PF_INT),
+    (KVM_FEATURE_ASYNC_PF, MSR_KVM_ASYNC_PF_EN),
+    (KVM_FEATURE_STEAL_TIME, MSR_KVM_STEAL_TIME),
+    (KVM_FEATURE_PV_EOI, MSR_KVM_PV_EOI_EN),
+];
+
+/// The MSRs of the KVM paravirtual features in `cpuid`, in the order they are set.
+pub fn pv_msrs(cpuid: &CpuId) -> Vec<u32> {
+    let features = kvm_features(cpuid);
+    PV_MSRS
+        .into_iter()
+        .filter(|(feature, _)| features & (1 << feature) != 0)
+        .map(|(_, msr)| msr)
+        .collect()
+}
+
+/// The MSRs set at boot, along with the `pv_msrs`, which start disabled until the guest
+/// enables them. With an `allowlist`, e.g. the one of a CPU template, only its MSRs are.
+pub fn create_boot_msr_entries(pv_msrs: &[u32], allowlist: Option<&[u32]>) -> Result<Msrs> {
     let msr_entry_default = |msr| kvm_msr_entry {
         index: msr,
         data: 0x0,
         ..Default::default()
     };
 
-    let raw_msrs = vec![
+    let mut raw_msrs = vec![
         msr_entry_default(MSR_IA32_SYSENTER_CS),
         msr_entry_default(MSR_IA32_SYSENTER_ESP),
         msr_entry_default(MSR_IA32_SYSENTER_EIP),
@@ -45,6 +77,10 @@ pub fn create_boot_msr_entries() -> Result<Msrs> {
             ..Default::default()
         },
     ];
+    raw_msrs.extend(pv_msrs.iter().map(|msr| msr_entry_default(*msr)));
+    if let Some(allowlist) = allowlist {
+        raw_msrs.retain(|entry| allowlist.contains(&entry.index));
+    }
 
     Msrs::from_entries(&raw_msrs).map_err(|_| Error::CreateMsrs)
 }
@@ -0,0 +1,82 @@
+// SPDX-License-Identifier: Apache-2.0
+
+// C3: a Cascade Lake class CPU, with AVX-512.
+
+use super::{feature, mask, CpuidFeature, CpuidMask, Register::*, Template};
+use crate::cpu::msr_index::{
+    MSR_CSTAR, MSR_IA32_MISC_ENABLE, MSR_IA32_SYSENTER_CS, MSR_IA32_SYSENTER_EIP,
+    MSR_IA32_SYSENTER_ESP, MSR_IA32_TSC, MSR_KERNEL_GS_BASE, MSR_LSTAR, MSR_STAR, MSR_SYSCALL_MASK,
+};
+use crate::cpu::msrs::{MSR_KVM_ASYNC_PF_EN, MSR_KVM_PV_EOI_EN, MSR_KVM_STEAL_TIME};
+
+const MASKS: [CpuidMask; 9] = [
+    // SSE3, PCLMULQDQ, SSSE3, FMA, CX16, SSE4.1, SSE4.2, x2APIC, MOVBE, POPCNT,
+    // TSC deadline, AES, XSAVE, OSXSAVE, AVX, F16C, RDRAND, hypervisor.
+    mask(0x1, 0, Ecx, 0xfff8_3203),
+    // Everything but PSN, DS, ACPI, TM and PBE.
+    mask(0x1, 0, Edx, 0x1f8b_fbff),
+    // FSGSBASE, BMI1, AVX2, SMEP, BMI2, ERMS, INVPCID, AVX512F, AVX512DQ, RDSEED, ADX,
+    // SMAP, CLFLUSHOPT, CLWB, AVX512CD, AVX512BW, AVX512VL.
+    mask(0x7, 0, Ebx, 0xd19f_07a9),
+    // AVX512_VNNI.
+    mask(0x7, 0, Ecx, 0x0000_0800),
+    // MD_CLEAR, SPEC_CTRL, STIBP, SSBD: the guest needs them for its mitigations.
+    mask(0x7, 0, Edx, 0x8c00_0400),
+    // x87, SSE, AVX and AVX-512 states.
+    mask(0xd, 0, Eax, 0x0000_00e7),
+    // XSAVEOPT, XSAVEC, XGETBV with ECX=1.
+    mask(0xd, 1, Eax, 0x0000_0007),
+    // LAHF/SAHF, ABM, PREFETCHW.
+    mask(0x8000_0001, 0, Ecx, 0x0000_0121),
+    // SYSCALL, NX, 1GB pages, RDTSCP, long mode.
+    mask(0x8000_0001, 0, Edx, 0x2c10_0800),
+];
+
+const REQUIRED: [CpuidFeature; 22] = [
+    feature("fma", 0x1, 0, Ecx, 12),
+    feature("sse4_2", 0x1, 0, Ecx, 20),
+    feature("movbe", 0x1, 0, Ecx, 22),
+    feature("popcnt", 0x1, 0, Ecx, 23),
+    feature("aes", 0x1, 0, Ecx, 25),
+    feature("xsave", 0x1, 0, Ecx, 26),
+    feature("avx", 0x1, 0, Ecx, 28),
+    feature("f16c", 0x1, 0, Ecx, 29),
+    feature("rdrand", 0x1, 0, Ecx, 30),
+    feature("bmi1", 0x7, 0, Ebx, 3),
+    feature("avx2", 0x7, 0, Ebx, 5),
+    feature("bmi2", 0x7, 0, Ebx, 8),
+    feature("rdseed", 0x7, 0, Ebx, 18),
+    feature("adx", 0x7, 0, Ebx, 19),
+    feature("clflushopt", 0x7, 0, Ebx, 23),
+    feature("avx512f", 0x7, 0, Ebx, 16),
+    feature("avx512dq", 0x7, 0, Ebx, 17),
+    feature("avx512cd", 0x7, 0, Ebx, 28),
+    feature("avx512bw", 0x7, 0, Ebx, 30),
+    feature("avx512vl", 0x7, 0, Ebx, 31),
+    feature("nx", 0x8000_0001, 0, Edx, 20),
+    feature("lm", 0x8000_0001, 0, Edx, 29),
+];
+
+// The boot MSRs, and the paravirtual ones but the interrupt vector of the async page
+// faults, which older hosts lack.
+const MSRS: [u32; 13] = [
+    MSR_IA32_SYSENTER_CS,
+    MSR_IA32_SYSENTER_ESP,
+    MSR_IA32_SYSENTER_EIP,
+    MSR_STAR,
+    MSR_CSTAR,
+    MSR_KERNEL_GS_BASE,
+    MSR_SYSCALL_MASK,
+    MSR_LSTAR,
+    MSR_IA32_TSC,
+    MSR_IA32_MISC_ENABLE,
+    MSR_KVM_ASYNC_PF_EN,
+    MSR_KVM_STEAL_TIME,
+    MSR_KVM_PV_EOI_EN,
+];
+
+pub(super) const TEMPLATE: Template = Template {
+    masks: &MASKS,
+    required: &REQUIRED,
+    msrs: &MSRS,
+};
@@ -0,0 +1,289 @@
+// SPDX-License-Identifier: Apache-2.0
+
+// CPU templates: a curated set of CPUID feature bits and MSRs exposed to the guest, so
+// that it sees the same CPU on heterogeneous hosts.
+
+use kvm_bindings::{kvm_cpuid_entry2, CpuId};
+
+use crate::config::CpuTemplate;
+use crate::cpu::cpuid::KVM_CPUID_FEATURES;
+use crate::cpu::msrs::PV_MSRS;
+
+mod c3;
+mod t2;
+
+/// CPU template errors.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// The host lacks features required by the template.
+    #[error("CPU template {template} requires features missing on this host: {}", .missing.join(", "))]
+    MissingFeatures {
+        template: CpuTemplate,
+        missing: Vec<&'static str>,
+    },
+}
+
+/// Dedicated [`Result`](https://doc.rust-lang.org/std/result/) type.
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// CPUID output register.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Register {
+    Eax,
+    Ebx,
+    Ecx,
+    Edx,
+}
+
+impl Register {
+    fn get(self, entry: &kvm_cpuid_entry2) -> u32 {
+        match self {
+            Register::Eax => entry.eax,
+            Register::Ebx => entry.ebx,
+            Register::Ecx => entry.ecx,
+            Register::Edx => entry.edx,
+        }
+    }
+
+    fn get_mut(self, entry: &mut kvm_cpuid_entry2) -> &mut u32 {
+        match self {
+            Register::Eax => &mut entry.eax,
+            Register::Ebx => &mut entry.ebx,
+            Register::Ecx => &mut entry.ecx,
+            Register::Edx => &mut entry.edx,
+        }
+    }
+}
+
+/// Bits of a CPUID register the template lets through, all the others are cleared.
+pub(crate) struct CpuidMask {
+    function: u32,
+    index: u32,
+    register: Register,
+    keep: u32,
+}
+
+const fn mask(function: u32, index: u32, register: Register, keep: u32) -> CpuidMask {
+    CpuidMask {
+        function,
+        index,
+        register,
+        keep,
+    }
+}
+
+/// A CPUID feature bit the template cannot do without.
+pub(crate) struct CpuidFeature {
+    name: &'static str,
+    function: u32,
+    index: u32,
+    register: Register,
+    bit: u32,
+}
+
+const fn feature(
+    name: &'static str,
+    function: u32,
+    index: u32,
+    register: Register,
+    bit: u32,
+) -> CpuidFeature {
+    CpuidFeature {
+        name,
+        function,
+        index,
+        register,
+        bit,
+    }
+}
+
+pub(crate) struct Template {
+    masks: &'static [CpuidMask],
+    required: &'static [CpuidFeature],
+    // The MSRs the vCPUs get. The KVM paravirtual features of the MSRs left out are
+    // hidden too, for the guest not to enable them.
+    msrs: &'static [u32],
+}
+
+fn template(cpu_template: CpuTemplate) -> Option<&'static Template> {
+    match cpu_template {
+        CpuTemplate::Passthrough => None,
+        CpuTemplate::T2 => Some(&t2::TEMPLATE),
+        CpuTemplate::C3 => Some(&c3::TEMPLATE),
+    }
+}
+
+fn find_entry(cpuid: &CpuId, function: u32, index: u32) -> Option<&kvm_cpuid_entry2> {
+    cpuid
+        .as_slice()
+        .iter()
+        .find(|entry| entry.function == function && entry.index == index)
+}
+
+fn has_feature(cpuid: &CpuId, feature: &CpuidFeature) -> bool {
+    match find_entry(cpuid, feature.function, feature.index) {
+        Some(entry) => feature.register.get(entry) & (1 << feature.bit) != 0,
+        None => false,
+    }
+}
+
+/// The MSRs `cpu_template` lets the vCPUs have, or `None` for all of them.
+pub(crate) fn msr_allowlist(cpu_template: CpuTemplate) -> Option<&'static [u32]> {
+    template(cpu_template).map(|template| template.msrs)
+}
+
+/// Apply `cpu_template` to the `cpuid` entries of a vCPU.
+///
+/// Fails, listing them, if any feature required by the template is not in `cpuid`.
+pub(crate) fn apply_template(cpu_template: CpuTemplate, cpuid: &mut CpuId) -> Result<()> {
+    let template = match template(cpu_template) {
+        Some(template) => template,
+        None => return Ok(()),
+    };
+
+    let missing: Vec<&'static str> = template
+        .required
+        .iter()
+        .filter(|feature| !has_feature(cpuid, feature))
+        .map(|feature| feature.name)
+        .collect();
+
+    if !missing.is_empty() {
+        return Err(Error::MissingFeatures {
+            template: cpu_template,
+            missing,
+        });
+    }
+
+    let hidden_pv_features = PV_MSRS
+        .iter()
+        .filter(|(_, msr)| !template.msrs.contains(msr))
+        .fold(0, |features, (feature, _)| features | (1 << feature));
+    for entry in cpuid.as_mut_slice().iter_mut() {
+        for mask in template.masks {
+            if entry.function == mask.function && entry.index == mask.index {
+                *mask.register.get_mut(entry) &= mask.keep;
+            }
+        }
+        if entry.function == KVM_CPUID_FEATURES {
+            entry.eax &= !hidden_pv_features;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+/// The entries of a CPUID fixture, which holds one per line:
+/// `<function> <index> <eax> <ebx> <ecx> <edx>`.
+pub(crate) fn parse_fixture(fixture: &str) -> CpuId {
+    let entries: Vec<kvm_cpuid_entry2> = fixture
+        .lines()
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let values: Vec<u32> = line
+                .split_whitespace()
+                .map(|v| u32::from_str_radix(v.trim_start_matches("0x"), 16).unwrap())
+                .collect();
+            kvm_cpuid_entry2 {
+                function: values[0],
+                index: values[1],
+                eax: values[2],
+                ebx: values[3],
+                ecx: values[4],
+                edx: values[5],
+                ..Default::default()
+            }
+        })
+        .collect();
+
+    CpuId::from_entries(&entries).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dump(cpuid: &CpuId) -> String {
+        cpuid
+            .as_slice()
+            .iter()
+            .map(|e| {
+                format!(
+                    "0x{:08x} 0x{:x} 0x{:08x} 0x{:08x} 0x{:08x} 0x{:08x}\n",
+                    e.function, e.index, e.eax, e.ebx, e.ecx, e.edx
+                )
+            })
+            .collect()
+    }
+
+    fn check_fixture(cpu_template: CpuTemplate, expected: &str) {
+        let mut cpuid = parse_fixture(include_str!("fixtures/host.txt"));
+        apply_template(cpu_template, &mut cpuid).unwrap();
+
+        assert_eq!(dump(&cpuid), dump(&parse_fixture(expected)));
+    }
+
+    #[test]
+    fn passthrough() {
+        check_fixture(CpuTemplate::Passthrough, include_str!("fixtures/host.txt"));
+    }
+
+    #[test]
+    fn t2() {
+        check_fixture(CpuTemplate::T2, include_str!("fixtures/t2.txt"));
+    }
+
+    #[test]
+    fn c3() {
+        check_fixture(CpuTemplate::C3, include_str!("fixtures/c3.txt"));
+    }
+
+    #[test]
+    fn msrs() {
+        use crate::cpu::msrs::{create_boot_msr_entries, pv_msrs, MSR_KVM_ASYNC_PF_INT};
+
+        for cpu_template in [CpuTemplate::T2, CpuTemplate::C3] {
+            let mut cpuid = parse_fixture(include_str!("fixtures/host.txt"));
+            apply_template(cpu_template, &mut cpuid).unwrap();
+            let allowlist = msr_allowlist(cpu_template).unwrap();
+
+            // The guest is not told about the paravirtual MSRs left out.
+            let offered = pv_msrs(&cpuid);
+            assert!(!offered.contains(&MSR_KVM_ASYNC_PF_INT));
+
+            // The vCPUs get all the MSRs of the template, and no other, even when asked for.
+            let boot_msrs = create_boot_msr_entries(&offered, Some(allowlist)).unwrap();
+            let mut indexes: Vec<u32> = boot_msrs.as_slice().iter().map(|e| e.index).collect();
+            indexes.sort_unstable();
+            let mut expected = allowlist.to_vec();
+            expected.sort_unstable();
+            assert_eq!(indexes, expected);
+
+            let boot_msrs =
+                create_boot_msr_entries(&[MSR_KVM_ASYNC_PF_INT], Some(allowlist)).unwrap();
+            assert!(boot_msrs
+                .as_slice()
+                .iter()
+                .all(|entry| allowlist.contains(&entry.index)));
+        }
+
+        assert!(msr_allowlist(CpuTemplate::Passthrough).is_none());
+    }
+
+    #[test]
+    fn missing_features() {
+        let mut cpuid = parse_fixture(include_str!("fixtures/host-no-avx512.txt"));
+
+        // T2 is fine without AVX-512.
+        apply_template(CpuTemplate::T2, &mut cpuid.clone()).unwrap();
+
+        match apply_template(CpuTemplate::C3, &mut cpuid) {
+            Err(Error::MissingFeatures { missing, .. }) => assert_eq!(
+                missing,
+                vec!["avx512f", "avx512dq", "avx512cd", "avx512bw", "avx512vl"]
+            ),
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+}
@@ -0,0 +1,309 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+use kvm_bindings::{
+    kvm_debugregs, kvm_fpu, kvm_lapic_state, kvm_mp_state, kvm_msr_entry, kvm_regs, kvm_sregs,
+    kvm_vcpu_events, kvm_xcrs, kvm_xsave, CpuId, Msrs, KVM_MAX_CPUID_ENTRIES, KVM_MAX_MSR_ENTRIES,
+};
+use vm_memory::{Address, Bytes, GuestAddress, GuestMemoryMmap};
+
+use super::gdt::*;
+use super::interrupts::*;
+use super::{msr_index, msrs, Error, Result, Vcpu};
+use crate::layout::{
+    BOOT_GDT_START, BOOT_IDT_START, BOOT_STACK_POINTER, FIRMWARE_PDE_START, PDE_START, PDPTE_START,
+    PML4_START, ZEROPG_START,
+};
+
+/// The state of a stopped vCPU, to start another one from, in another VM.
+pub(crate) struct VcpuState {
+    cpuid: CpuId,
+    mp_state: kvm_mp_state,
+    regs: kvm_regs,
+    sregs: kvm_sregs,
+    xsave: kvm_xsave,
+    xcrs: kvm_xcrs,
+    debug_regs: kvm_debugregs,
+    lapic: kvm_lapic_state,
+    msrs: Vec<kvm_msr_entry>,
+    events: kvm_vcpu_events,
+}
+
+const X86_CR0_PE: u64 = 0x1;
+const X86_CR0_PG: u64 = 0x8000_0000;
+const X86_CR4_PAE: u64 = 0x20;
+
+impl Vcpu {
+    /// Set CPUID.
+    pub fn configure_cpuid(&self, cpuid: &CpuId) -> Result<()> {
+        self.vcpu_fd.set_cpuid2(cpuid).map_err(Error::KvmIoctl)
+    }
+
+    /// Configure MSRs, from the boot entries of [`msrs::create_boot_msr_entries`].
+    pub fn configure_msrs(&self, msrs: &Msrs) -> Result<()> {
+        self.vcpu_fd
+            .set_msrs(msrs)
+            .map_err(Error::KvmIoctl)
+            .and_then(|msrs_written| {
+                if msrs_written as u32 != msrs.as_fam_struct_ref().nmsrs {
+                    Err(Error::SetModelSpecificRegistersCount)
+                } else {
+                    Ok(())
+                }
+            })
+    }
+
+    /// Configure regs.
+    pub fn configure_regs(&self, kernel_load: GuestAddress) -> Result<()> {
+        let regs = kvm_regs {
+            rflags: 0x0000_0000_0000_0002u64,
+            rip: kernel_load.raw_value(),
+            // Frame pointer. It gets a snapshot of the stack pointer (rsp) so that when adjustments are
+            // made to rsp (i.e. reserving space for local variables or pushing values on to the stack),
+            // local variables and function parameters are still accessible from a constant offset from rbp.
+            rsp: BOOT_STACK_POINTER,
+            // Starting stack pointer.
+            rbp: BOOT_STACK_POINTER,
+            // Must point to zero page address per Linux ABI. This is x86_64 specific.
+            rsi: ZEROPG_START,
+            ..Default::default()
+        };
+        self.vcpu_fd.set_regs(&regs).map_err(Error::KvmIoctl)
+    }
+
+    /// Start at the firmware `entry` instead of the kernel entry, which the firmware finds in
+    /// rbx to jump to. The other registers are the ones the kernel expects.
+    ///
+    /// This must be called after [`Vcpu::configure_regs`] and [`Vcpu::configure_sregs`]. The
+    /// boot page tables only map the first GiB: the GiB holding the entry is mapped too.
+    pub fn configure_firmware_entry(
+        &self,
+        guest_memory: &GuestMemoryMmap,
+        entry: GuestAddress,
+    ) -> Result<()> {
+        let gib = entry.raw_value() >> 30;
+        let firmware_pde_addr = GuestAddress(FIRMWARE_PDE_START);
+        guest_memory
+            .write_obj(
+                FIRMWARE_PDE_START | 0x03,
+                GuestAddress(PDPTE_START).unchecked_add(gib * 8),
+            )
+            .map_err(Error::GuestMemory)?;
+        for i in 0..512 {
+            guest_memory
+                .write_obj(
+                    (gib << 30) + (i << 21) + 0x83u64,
+                    firmware_pde_addr.unchecked_add(i * 8),
+                )
+                .map_err(Error::GuestMemory)?;
+        }
+
+        let mut regs = self.vcpu_fd.get_regs().map_err(Error::KvmIoctl)?;
+        regs.rbx = regs.rip;
+        regs.rip = entry.raw_value();
+        self.vcpu_fd.set_regs(&regs).map_err(Error::KvmIoctl)
+    }
+
+    /// Configure sregs.
+    pub fn configure_sregs(&self, guest_memory: &GuestMemoryMmap) -> Result<()> {
+        let mut sregs = self.vcpu_fd.get_sregs().map_err(Error::KvmIoctl)?;
+
+        // Global descriptor tables.
+        let gdt_table: [u64; BOOT_GDT_MAX as usize] = [
+            gdt_entry(0, 0, 0),            // NULL
+            gdt_entry(0xa09b, 0, 0xfffff), // CODE
+            gdt_entry(0xc093, 0, 0xfffff), // DATA
+            gdt_entry(0x808b, 0, 0xfffff), // TSS
+        ];
+
+        let code_seg = kvm_segment_from_gdt(gdt_table[1], 1);
+        let data_seg = kvm_segment_from_gdt(gdt_table[2], 2);
+        let tss_seg = kvm_segment_from_gdt(gdt_table[3], 3);
+
+        // Write segments to guest memory.
+        write_gdt_table(&gdt_table[..], guest_memory).map_err(Error::GuestMemory)?;
+        sregs.gdt.base = BOOT_GDT_START;
+        sregs.gdt.limit = std::mem::size_of_val(&gdt_table) as u16 - 1;
+
+        write_idt_value(0, guest_memory).map_err(Error::GuestMemory)?;
+        sregs.idt.base = BOOT_IDT_START;
+        sregs.idt.limit = std::mem::size_of::<u64>() as u16 - 1;
+
+        sregs.cs = code_seg;
+        sregs.ds = data_seg;
+        sregs.es = data_seg;
+        sregs.fs = data_seg;
+        sregs.gs = data_seg;
+        sregs.ss = data_seg;
+        sregs.tr = tss_seg;
+
+        // 64-bit protected mode.
+        sregs.cr0 |= X86_CR0_PE;
+        sregs.efer |= (msr_index::EFER_LME | msr_index::EFER_LMA) as u64;
+
+        // Start page table configuration.
+        // Puts PML4 right after zero page but aligned to 4k.
+        let boot_pml4_addr = GuestAddress(PML4_START);
+        let boot_pdpte_addr = GuestAddress(PDPTE_START);
+        let boot_pde_addr = GuestAddress(PDE_START);
+
+        // Entry covering VA [0..512GB).
+        guest_memory
+            .write_obj(boot_pdpte_addr.raw_value() as u64 | 0x03, boot_pml4_addr)
+            .map_err(Error::GuestMemory)?;
+
+        // Entry covering VA [0..1GB).
+        guest_memory
+            .write_obj(boot_pde_addr.raw_value() as u64 | 0x03, boot_pdpte_addr)
+            .map_err(Error::GuestMemory)?;
+
+        // 512 2MB entries together covering VA [0..1GB).
+        // This assumes that the CPU supports 2MB pages (/proc/cpuinfo has 'pse').
+        for i in 0..512 {
+            guest_memory
+                .write_obj((i << 21) + 0x83u64, boot_pde_addr.unchecked_add(i * 8))
+                .map_err(Error::GuestMemory)?;
+        }
+
+        sregs.cr3 = boot_pml4_addr.raw_value() as u64;
+        sregs.cr4 |= X86_CR4_PAE;
+        sregs.cr0 |= X86_CR0_PG;
+
+        self.vcpu_fd.set_sregs(&sregs).map_err(Error::KvmIoctl)
+    }
+
+    /// Configure FPU.
+    pub fn configure_fpu(&self) -> Result<()> {
+        let fpu = kvm_fpu {
+            fcw: 0x37f,
+            mxcsr: 0x1f80,
+            ..Default::default()
+        };
+        self.vcpu_fd.set_fpu(&fpu).map_err(Error::KvmIoctl)
+    }
+
+    /// Configures LAPICs. LAPIC0 is set for external interrupts, LAPIC1 is set for NMI.
+    pub fn configure_lapic(&self) -> Result<()> {
+        // The LVT registers are at the same offsets in xAPIC and x2APIC modes, and the
+        // APIC ID register goes back as read, in the format of the current mode.
+        let mut klapic = self.vcpu_fd.get_lapic().map_err(Error::KvmIoctl)?;
+
+        let lvt_lint0 = get_klapic_reg(&klapic, APIC_LVT0);
+        set_klapic_reg(
+            &mut klapic,
+            APIC_LVT0,
+            set_apic_delivery_mode(lvt_lint0, APIC_MODE_EXTINT),
+        );
+        let lvt_lint1 = get_klapic_reg(&klapic, APIC_LVT1);
+        set_klapic_reg(
+            &mut klapic,
+            APIC_LVT1,
+            set_apic_delivery_mode(lvt_lint1, APIC_MODE_NMI),
+        );
+
+        self.vcpu_fd.set_lapic(&klapic).map_err(Error::KvmIoctl)
+    }
+
+    /// Save the state of the vCPU, which must not run, along with the `msr_list` MSRs it
+    /// can read.
+    pub fn save_state(&self, msr_list: &[u32]) -> Result<VcpuState> {
+        // Getting the MP state first flushes the APIC events into the saved state.
+        let mp_state = self.vcpu_fd.get_mp_state().map_err(Error::KvmIoctl)?;
+
+        Ok(VcpuState {
+            cpuid: self
+                .vcpu_fd
+                .get_cpuid2(KVM_MAX_CPUID_ENTRIES)
+                .map_err(Error::KvmIoctl)?,
+            mp_state,
+            regs: self.vcpu_fd.get_regs().map_err(Error::KvmIoctl)?,
+            sregs: self.vcpu_fd.get_sregs().map_err(Error::KvmIoctl)?,
+            xsave: self.vcpu_fd.get_xsave().map_err(Error::KvmIoctl)?,
+            xcrs: self.vcpu_fd.get_xcrs().map_err(Error::KvmIoctl)?,
+            debug_regs: self.vcpu_fd.get_debug_regs().map_err(Error::KvmIoctl)?,
+            lapic: self.vcpu_fd.get_lapic().map_err(Error::KvmIoctl)?,
+            msrs: self.save_msrs(msr_list)?,
+            events: self.vcpu_fd.get_vcpu_events().map_err(Error::KvmIoctl)?,
+        })
+    }
+
+    // Read the MSRs of `msr_list`, skipping the ones KVM lists but the vCPU does not have.
+    fn save_msrs(&self, msr_list: &[u32]) -> Result<Vec<kvm_msr_entry>> {
+        let mut saved = Vec::with_capacity(msr_list.len());
+        let mut pending = msr_list;
+        while !pending.is_empty() {
+            let chunk = &pending[..pending.len().min(KVM_MAX_MSR_ENTRIES)];
+            let entries: Vec<_> = chunk
+                .iter()
+                .map(|index| kvm_msr_entry {
+                    index: *index,
+                    ..Default::default()
+                })
+                .collect();
+            let mut msrs = Msrs::from_entries(&entries).map_err(|_| msrs::Error::CreateMsrs)?;
+            let read = self.vcpu_fd.get_msrs(&mut msrs).map_err(Error::KvmIoctl)?;
+            saved.extend_from_slice(&msrs.as_slice()[..read]);
+
+            // KVM stops at the first MSR it fails to read.
+            let skipped = if read < chunk.len() { read + 1 } else { read };
+            pending = &pending[skipped..];
+        }
+
+        Ok(saved)
+    }
+
+    /// Restore the `state` saved from another vCPU, with the same ID. The TSC frequency
+    /// must be set first, the TSC is restored with the MSRs.
+    pub fn restore_state(&self, state: &VcpuState) -> Result<()> {
+        // The CPUID goes first, it tells KVM which features the other registers may use.
+        self.configure_cpuid(&state.cpuid)?;
+        self.vcpu_fd
+            .set_mp_state(state.mp_state)
+            .map_err(Error::KvmIoctl)?;
+        self.vcpu_fd
+            .set_regs(&state.regs)
+            .map_err(Error::KvmIoctl)?;
+        self.vcpu_fd
+            .set_sregs(&state.sregs)
+            .map_err(Error::KvmIoctl)?;
+        self.vcpu_fd
+            .set_xsave(&state.xsave)
+            .map_err(Error::KvmIoctl)?;
+        self.vcpu_fd
+            .set_xcrs(&state.xcrs)
+            .map_err(Error::KvmIoctl)?;
+        self.vcpu_fd
+            .set_debug_regs(&state.debug_regs)
+            .map_err(Error::KvmIoctl)?;
+        self.vcpu_fd
+            .set_lapic(&state.lapic)
+            .map_err(Error::KvmIoctl)?;
+
+        // The async page fault interrupt vector goes before the MSR enabling them with it,
+        // wherever KVM lists it.
+        let mut saved = state.msrs.clone();
+        saved.sort_by_key(|entry| entry.index != msrs::MSR_KVM_ASYNC_PF_INT);
+        for chunk in saved.chunks(KVM_MAX_MSR_ENTRIES) {
+            let msrs = Msrs::from_entries(chunk).map_err(|_| msrs::Error::CreateMsrs)?;
+            let written = self.vcpu_fd.set_msrs(&msrs).map_err(Error::KvmIoctl)?;
+            if written != chunk.len() {
+                return Err(Error::SetModelSpecificRegistersCount);
+            }
+        }
+
+        // Last, the pending events may depend on the other registers.
+        self.vcpu_fd
+            .set_vcpu_events(&state.events)
+            .map_err(Error::KvmIoctl)
+    }
+
+    /// Get the TSC frequency, in kHz.
+    pub fn tsc_khz(&self) -> Result<u32> {
+        self.vcpu_fd.get_tsc_khz().map_err(Error::KvmIoctl)
+    }
+
+    /// Set the TSC frequency, in kHz.
+    pub fn configure_tsc_khz(&self, tsc_khz: u32) -> Result<()> {
+        self.vcpu_fd.set_tsc_khz(tsc_khz).map_err(Error::KvmIoctl)
+    }
+}
@@ -0,0 +1,149 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use std::sync::Arc;
+
+use vm_device::bus::{PioAddress, PioAddressOffset};
+use vm_device::MutDevicePio;
+
+use crate::{ExitNotifier, ExitReason};
+
+/// PM1a event block port: the PM1 status register, then the PM1 enable register.
+pub const PM1A_EVT_BLK: u16 = 0x600;
+/// Size of the PM1a event block.
+pub const PM1_EVT_LEN: u8 = 4;
+/// PM1a control block port.
+pub const PM1A_CNT_BLK: u16 = 0x604;
+/// Size of the PM1a control block.
+pub const PM1_CNT_LEN: u8 = 2;
+/// Number of ports of the PM1a registers.
+pub const ACPI_PM_PORT_SIZE: u16 = PM1_EVT_LEN as u16 + PM1_CNT_LEN as u16;
+
+/// Legacy IRQ of the System Control Interrupt. It is never raised.
+pub const SCI_IRQ: u8 = 9;
+/// Sleep type of the S5 (soft off) state, as described in the DSDT.
+pub const S5_SLP_TYP: u8 = 5;
+
+// Register offsets in the PM1a block, after the status register.
+const PM1_EN: usize = 2;
+const PM1_CNT: usize = 4;
+
+// PM1 control register fields.
+const SCI_EN: u16 = 1;
+const SLP_TYP_SHIFT: u16 = 10;
+const SLP_TYP_MASK: u16 = 0x7;
+const SLP_EN: u16 = 1 << 13;
+
+/// ACPI PM1a event and control registers.
+///
+/// The guest is always in ACPI mode. Entering the S5 sleep state stops the VMM with
+/// [`ExitReason::GuestShutdown`], other sleep states are ignored.
+pub(crate) struct AcpiPm {
+    // PM1 status, enable and control registers, as laid out in the I/O space.
+    regs: [u8; ACPI_PM_PORT_SIZE as usize],
+    exit: Arc<ExitNotifier>,
+}
+
+impl AcpiPm {
+    pub fn new(exit: Arc<ExitNotifier>) -> Self {
+        let mut regs = [0; ACPI_PM_PORT_SIZE as usize];
+        regs[PM1_CNT..PM1_CNT + 2].copy_from_slice(&SCI_EN.to_le_bytes());
+
+        AcpiPm { regs, exit }
+    }
+
+    fn control(&self) -> u16 {
+        u16::from_le_bytes([self.regs[PM1_CNT], self.regs[PM1_CNT + 1]])
+    }
+
+    fn set_control(&mut self, value: u16) {
+        self.regs[PM1_CNT..PM1_CNT + 2].copy_from_slice(&value.to_le_bytes());
+    }
+
+    // The guest wrote SLP_EN: enter the sleep state set in SLP_TYP.
+    fn sleep(&mut self, control: u16) {
+        let sleep_type = (control >> SLP_TYP_SHIFT) & SLP_TYP_MASK;
+        if sleep_type == u16::from(S5_SLP_TYP) {
+            self.exit.notify(ExitReason::GuestShutdown);
+        } else {
+            log::debug!("Ignoring ACPI sleep type {}", sleep_type);
+        }
+    }
+}
+
+impl MutDevicePio for AcpiPm {
+    fn pio_read(&mut self, _base: PioAddress, offset: PioAddressOffset, data: &mut [u8]) {
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte = self
+                .regs
+                .get(usize::from(offset) + i)
+                .copied()
+                .unwrap_or(0xff);
+        }
+    }
+
+    fn pio_write(&mut self, _base: PioAddress, offset: PioAddressOffset, data: &[u8]) {
+        for (i, byte) in data.iter().enumerate() {
+            let offset = usize::from(offset) + i;
+            if offset < PM1_EN {
+                // Status bits are cleared by writing ones.
+                self.regs[offset] &= !byte;
+            } else if offset < self.regs.len() {
+                self.regs[offset] = *byte;
+            }
+        }
+
+        let control = self.control();
+        // SLP_EN always reads as zero, and the guest cannot leave ACPI mode.
+        self.set_control((control & !SLP_EN) | SCI_EN);
+        if control & SLP_EN != 0 {
+            self.sleep(control);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_control(pm: &mut AcpiPm, value: u16) {
+        pm.pio_write(
+            PioAddress(PM1A_EVT_BLK),
+            PM1_CNT as PioAddressOffset,
+            &value.to_le_bytes(),
+        );
+    }
+
+    #[test]
+    fn poweroff() {
+        let exit = Arc::new(ExitNotifier::new().unwrap());
+        let mut pm = AcpiPm::new(exit.clone());
+
+        // The guest checks it is in ACPI mode.
+        let mut data = [0u8; 2];
+        pm.pio_read(
+            PioAddress(PM1A_EVT_BLK),
+            PM1_CNT as PioAddressOffset,
+            &mut data,
+        );
+        assert_eq!(u16::from_le_bytes(data) & SCI_EN, SCI_EN);
+
+        // Linux first sets the sleep type, then sets SLP_EN.
+        let s5 = u16::from(S5_SLP_TYP) << SLP_TYP_SHIFT;
+        write_control(&mut pm, s5);
+        assert_eq!(exit.take(), None);
+
+        // Other sleep states are ignored.
+        write_control(&mut pm, (3 << SLP_TYP_SHIFT) | SLP_EN);
+        assert_eq!(exit.take(), None);
+
+        write_control(&mut pm, s5 | SLP_EN);
+        assert_eq!(exit.take(), Some(ExitReason::GuestShutdown));
+
+        pm.pio_read(
+            PioAddress(PM1A_EVT_BLK),
+            PM1_CNT as PioAddressOffset,
+            &mut data,
+        );
+        assert_eq!(u16::from_le_bytes(data) & SLP_EN, 0);
+    }
+}
@@ -0,0 +1,370 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use std::collections::VecDeque;
+use std::io::{Result, Write};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::devices::serial::SerialStats;
+use crate::{ExitNotifier, ExitReason};
+
+/// Default amount of output buffered for a slow sink.
+pub(crate) const OUTPUT_QUEUE_SIZE: usize = 64 * 1024;
+
+#[derive(Default)]
+struct Queue {
+    buffer: VecDeque<u8>,
+    // Bytes dropped since the last warning.
+    dropped: u64,
+    // The writer thread is busy with bytes taken from the buffer.
+    writing: bool,
+    // The AsyncWriter is gone, or the VMM closed it: the writer thread exits once the
+    // buffer is empty.
+    closed: bool,
+    // The writer thread dropped the sink, and exited.
+    done: bool,
+}
+
+#[derive(Default)]
+struct Shared {
+    queue: Mutex<Queue>,
+    // Signaled when bytes are queued, or the writer is closed.
+    data_ready: Condvar,
+    // Signaled when the writer thread is done with a batch of bytes, and when it exits.
+    drained: Condvar,
+}
+
+/// What the writer thread does once a write to the sink failed, e.g. on a full disk.
+pub(crate) enum OnSinkError {
+    /// Drop the output, and try the sink again with the next one.
+    Discard,
+    /// Stop the VM, and drop the output.
+    Stop(Arc<ExitNotifier>),
+    /// Write the output to this other sink from then on.
+    Fallback(Box<dyn Write + Send>),
+}
+
+/// Output sink that never blocks its caller.
+///
+/// The bytes are queued and written to the actual sink by a dedicated thread, so that a
+/// slow file or a stuck socket does not stall the vCPU writing to a serial port. When the
+/// queue is full, the output is dropped and a warning reports how much was lost. The sink
+/// failures are counted in the stats of the serial port.
+pub(crate) struct AsyncWriter {
+    shared: Arc<Shared>,
+    capacity: usize,
+}
+
+/// Lets the VMM wait for the output queued in an [`AsyncWriter`] to be written.
+#[derive(Clone)]
+pub(crate) struct FlushHandle {
+    shared: Arc<Shared>,
+}
+
+impl AsyncWriter {
+    pub fn new(
+        output: Box<dyn Write + Send>,
+        capacity: usize,
+        on_error: OnSinkError,
+        stats: Arc<SerialStats>,
+    ) -> Result<Self> {
+        let shared = Arc::new(Shared::default());
+
+        let thread_shared = shared.clone();
+        thread::Builder::new()
+            .name("serial-output".to_string())
+            .spawn(move || {
+                writer_loop(&thread_shared, output, on_error, stats);
+                thread_shared.queue.lock().unwrap().done = true;
+                thread_shared.drained.notify_all();
+            })?;
+
+        Ok(AsyncWriter { shared, capacity })
+    }
+
+    pub fn flush_handle(&self) -> FlushHandle {
+        FlushHandle {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl Write for AsyncWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let mut queue = self.shared.queue.lock().unwrap();
+        // The VM is going away, nothing writes the output anymore.
+        if queue.closed {
+            return Ok(buf.len());
+        }
+
+        let count = std::cmp::min(self.capacity - queue.buffer.len(), buf.len());
+        queue.buffer.extend(&buf[..count]);
+        queue.dropped += (buf.len() - count) as u64;
+        self.shared.data_ready.notify_one();
+
+        // Dropped bytes are still reported as written, the guest cannot do anything about them.
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for AsyncWriter {
+    fn drop(&mut self) {
+        self.shared.queue.lock().unwrap().closed = true;
+        self.shared.data_ready.notify_one();
+    }
+}
+
+impl FlushHandle {
+    /// Wait up to `timeout` for the queued output to be written. Returns `false` on timeout.
+    pub fn flush(&self, timeout: Duration) -> bool {
+        self.wait(timeout, |queue| queue.buffer.is_empty() && !queue.writing)
+    }
+
+    /// Drop the output written from now on, and wait up to `timeout` for the queued one to
+    /// be written, and the sink to be dropped, which closes it. Returns `false` on timeout.
+    pub fn close(&self, timeout: Duration) -> bool {
+        self.shared.queue.lock().unwrap().closed = true;
+        self.shared.data_ready.notify_one();
+
+        self.wait(timeout, |queue| queue.done)
+    }
+
+    // Wait up to `timeout` for the writer thread to get the queue to `done`.
+    fn wait(&self, timeout: Duration, done: impl Fn(&Queue) -> bool) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut queue = self.shared.queue.lock().unwrap();
+
+        while !done(&queue) {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            queue = self
+                .shared
+                .drained
+                .wait_timeout(queue, deadline - now)
+                .unwrap()
+                .0;
+        }
+
+        true
+    }
+}
+
+fn writer_loop(
+    shared: &Shared,
+    mut output: Box<dyn Write + Send>,
+    mut on_error: OnSinkError,
+    stats: Arc<SerialStats>,
+) {
+    loop {
+        let (data, dropped) = {
+            let mut queue = shared.queue.lock().unwrap();
+            while queue.buffer.is_empty() && !queue.closed {
+                queue = shared.data_ready.wait(queue).unwrap();
+            }
+            if queue.buffer.is_empty() {
+                return;
+            }
+
+            queue.writing = true;
+            let data: Vec<u8> = queue.buffer.drain(..).collect();
+            (data, std::mem::take(&mut queue.dropped))
+        };
+
+        if dropped > 0 {
+            eprintln!(
+                "Warning: serial output sink too slow, dropped {} bytes",
+                dropped
+            );
+        }
+
+        if let Err(e) = output.write_all(&data).and_then(|_| output.flush()) {
+            stats.output_error(&e);
+            match std::mem::replace(&mut on_error, OnSinkError::Discard) {
+                OnSinkError::Discard => {}
+                OnSinkError::Stop(exit) => exit.notify(ExitReason::ConsoleError(e.to_string())),
+                // What the failed write did not get out is written again, in full.
+                OnSinkError::Fallback(fallback) => {
+                    log::warn!("Writing the serial output to the fallback sink from now on");
+                    output = fallback;
+                    if let Err(e) = output.write_all(&data).and_then(|_| output.flush()) {
+                        stats.output_error(&e);
+                    }
+                }
+            }
+        }
+
+        shared.queue.lock().unwrap().writing = false;
+        shared.drained.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::os::unix::net::UnixStream;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    #[test]
+    fn blocked_sink() {
+        let (sink, mut peer) = UnixStream::pair().unwrap();
+        let mut writer =
+            AsyncWriter::new(Box::new(sink), 4096, OnSinkError::Discard, Arc::default()).unwrap();
+
+        // Nobody reads the peer, the socket buffer fills up and the writer thread blocks.
+        // Writing one byte at a time, like a vCPU to the serial port, must still go on.
+        let start = Instant::now();
+        for i in 0..(1 << 20) {
+            assert_eq!(writer.write(&[i as u8]).unwrap(), 1);
+        }
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(!writer.flush_handle().flush(Duration::from_millis(10)));
+
+        // Once the peer reads, everything queued is written.
+        peer.set_nonblocking(true).unwrap();
+        let handle = writer.flush_handle();
+        let mut buf = [0u8; 4096];
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !handle.flush(Duration::from_millis(1)) {
+            assert!(Instant::now() < deadline);
+            let _ = peer.read(&mut buf);
+        }
+    }
+
+    #[test]
+    fn ordered_output() {
+        let (sink, mut peer) = UnixStream::pair().unwrap();
+        let mut writer = AsyncWriter::new(
+            Box::new(sink),
+            OUTPUT_QUEUE_SIZE,
+            OnSinkError::Discard,
+            Arc::default(),
+        )
+        .unwrap();
+
+        writer.write_all(b"hello ").unwrap();
+        writer.write_all(b"world").unwrap();
+        // Closing the writer lets the thread exit, closing the sink once everything is written.
+        drop(writer);
+
+        let mut output = String::new();
+        peer.read_to_string(&mut output).unwrap();
+        assert_eq!(output, "hello world");
+    }
+
+    // A sink telling when it is dropped.
+    struct DroppedSink {
+        output: Arc<Mutex<Vec<u8>>>,
+        dropped: Arc<AtomicBool>,
+    }
+
+    impl Write for DroppedSink {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            // Slow enough for the output to queue up.
+            thread::sleep(Duration::from_millis(1));
+            self.output.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Drop for DroppedSink {
+        fn drop(&mut self) {
+            self.dropped.store(true, Ordering::Release);
+        }
+    }
+
+    #[test]
+    fn close() {
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let dropped = Arc::new(AtomicBool::new(false));
+        let sink = DroppedSink {
+            output: output.clone(),
+            dropped: dropped.clone(),
+        };
+        let mut writer = AsyncWriter::new(
+            Box::new(sink),
+            OUTPUT_QUEUE_SIZE,
+            OnSinkError::Discard,
+            Arc::default(),
+        )
+        .unwrap();
+
+        // What was queued before closing gets out, even though the writer is still there.
+        let mut expected = Vec::new();
+        for i in 0..1000 {
+            let line = format!("line {}\n", i);
+            writer.write_all(line.as_bytes()).unwrap();
+            expected.extend_from_slice(line.as_bytes());
+        }
+        assert!(writer.flush_handle().close(Duration::from_secs(5)));
+        assert!(dropped.load(Ordering::Acquire));
+        assert_eq!(*output.lock().unwrap(), expected);
+
+        // The rest is dropped.
+        writer.write_all(b"after").unwrap();
+        assert!(writer.flush_handle().close(Duration::from_secs(5)));
+        assert_eq!(output.lock().unwrap().len(), expected.len());
+    }
+
+    // A sink on a full disk.
+    struct FullDisk;
+
+    impl Write for FullDisk {
+        fn write(&mut self, _buf: &[u8]) -> Result<usize> {
+            Err(std::io::Error::from_raw_os_error(libc::ENOSPC))
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn sink_errors() {
+        let (fallback, mut peer) = UnixStream::pair().unwrap();
+        let stats = Arc::new(SerialStats::default());
+        let mut writer = AsyncWriter::new(
+            Box::new(FullDisk),
+            OUTPUT_QUEUE_SIZE,
+            OnSinkError::Fallback(Box::new(fallback)),
+            stats.clone(),
+        )
+        .unwrap();
+
+        // The output the full disk failed to take goes to the fallback, and so does the
+        // rest.
+        writer.write_all(b"hello ").unwrap();
+        assert!(writer.flush_handle().flush(Duration::from_secs(5)));
+        writer.write_all(b"world").unwrap();
+        drop(writer);
+
+        let mut output = String::new();
+        peer.read_to_string(&mut output).unwrap();
+        assert_eq!(output, "hello world");
+        assert_eq!(stats.counters().tx_errors, 1);
+
+        // Or the VM stops, and the output is lost.
+        let exit = Arc::new(ExitNotifier::new().unwrap());
+        let mut writer = AsyncWriter::new(
+            Box::new(FullDisk),
+            OUTPUT_QUEUE_SIZE,
+            OnSinkError::Stop(exit.clone()),
+            Arc::default(),
+        )
+        .unwrap();
+        writer.write_all(b"hello").unwrap();
+        assert!(writer.flush_handle().flush(Duration::from_secs(5)));
+        assert!(matches!(exit.take(), Some(ExitReason::ConsoleError(_))));
+    }
+}
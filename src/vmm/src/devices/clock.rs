@@ -0,0 +1,84 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// UTC calendar date and time, to the second.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct DateTime {
+    pub year: u64,
+    /// From 1 to 12.
+    pub month: u64,
+    /// From 1 to 31.
+    pub day: u64,
+    /// From 0 (Sunday) to 6.
+    pub weekday: u64,
+    pub hour: u64,
+    pub minute: u64,
+    pub second: u64,
+}
+
+impl DateTime {
+    /// Convert seconds since the Unix epoch.
+    pub fn from_unix(secs: u64) -> Self {
+        let (days, secs) = (secs / 86400, secs % 86400);
+
+        // From the days since the epoch to the civil date, see
+        // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+        let z = days + 719_468;
+        let era = z / 146_097;
+        let doe = z % 146_097;
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + u64::from(month <= 2);
+
+        DateTime {
+            year,
+            month,
+            day,
+            // The epoch was a Thursday.
+            weekday: (days + 4) % 7,
+            hour: secs / 3600,
+            minute: secs / 60 % 60,
+            second: secs % 60,
+        }
+    }
+
+    /// The host wall-clock time.
+    pub fn now() -> Self {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        Self::from_unix(now.as_secs())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_unix() {
+        let date = |year, month, day, weekday, hour, minute, second| DateTime {
+            year,
+            month,
+            day,
+            weekday,
+            hour,
+            minute,
+            second,
+        };
+
+        assert_eq!(DateTime::from_unix(0), date(1970, 1, 1, 4, 0, 0, 0));
+        assert_eq!(
+            DateTime::from_unix(951_868_799),
+            date(2000, 2, 29, 2, 23, 59, 59)
+        );
+        assert_eq!(
+            DateTime::from_unix(1_792_235_045),
+            date(2026, 10, 17, 6, 11, 4, 5)
+        );
+    }
+}
@@ -0,0 +1,151 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::Path;
+
+use crate::epoll_context::{EpollContext, Interest, Token, Wakeup};
+
+/// Console input read from a file or a pipe, instead of the VMM stdin.
+pub(crate) struct ConsoleInput {
+    file: File,
+    // Whether the input is a pipe, a FIFO or a device, rather than a regular file.
+    stream: bool,
+    // Whether the input is registered in the event loop.
+    polled: bool,
+}
+
+impl ConsoleInput {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let stream = !fs::metadata(path)?.is_file();
+
+        // Also opening a FIFO for writing keeps it from reaching EOF when its writers come
+        // and go, it streams for as long as the VM runs.
+        let file = OpenOptions::new()
+            .read(true)
+            .write(stream)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(path)?;
+
+        Ok(ConsoleInput {
+            file,
+            stream,
+            polled: false,
+        })
+    }
+
+    /// Wake the event loop up when the input is readable, under `token`, or stop doing so.
+    ///
+    /// Regular files are always readable, and cannot be polled.
+    pub fn set_polled(
+        &mut self,
+        epoll: &mut EpollContext,
+        token: Token,
+        polled: bool,
+    ) -> io::Result<()> {
+        if !self.stream || polled == self.polled {
+            return Ok(());
+        }
+
+        if polled {
+            epoll.add(
+                self.file.as_raw_fd(),
+                token,
+                Interest::Read,
+                Box::new(Wakeup),
+            )?;
+        } else {
+            epoll.remove(token)?;
+        }
+        self.polled = polled;
+
+        Ok(())
+    }
+
+    /// Read the available input. Returns `Ok(None)` when there is none yet, and
+    /// `Ok(Some(0))` at the end of the input.
+    pub fn read(&mut self, buf: &mut [u8]) -> io::Result<Option<usize>> {
+        loop {
+            match self.file.read(buf) {
+                Ok(count) => return Ok(Some(count)),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl AsRawFd for ConsoleInput {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+    use std::io::Write;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::PathBuf;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "lumper-console-input-{}-{}",
+            std::process::id(),
+            name
+        ));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn regular_file() {
+        let path = temp_path("file");
+        fs::write(&path, b"uname -r\n").unwrap();
+
+        let mut input = ConsoleInput::open(&path).unwrap();
+        let mut epoll = EpollContext::new().unwrap();
+        // Regular files are never registered, epoll rejects them.
+        input.set_polled(&mut epoll, Token(0), true).unwrap();
+        assert!(epoll.is_empty());
+
+        let mut buf = [0u8; 64];
+        assert_eq!(input.read(&mut buf).unwrap(), Some(9));
+        assert_eq!(&buf[..9], b"uname -r\n");
+        assert_eq!(input.read(&mut buf).unwrap(), Some(0));
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn fifo() {
+        let path = temp_path("fifo");
+        let c_path = CString::new(path.as_os_str().as_bytes()).unwrap();
+        // Safe because the path is a valid NUL terminated string.
+        assert_eq!(unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) }, 0);
+
+        let mut input = ConsoleInput::open(&path).unwrap();
+        let mut epoll = EpollContext::new().unwrap();
+        input.set_polled(&mut epoll, Token(0), true).unwrap();
+
+        let mut buf = [0u8; 64];
+        assert_eq!(input.read(&mut buf).unwrap(), None);
+
+        // A writer comes and goes, the input does not end.
+        let mut writer = OpenOptions::new().write(true).open(&path).unwrap();
+        writer.write_all(b"poweroff\n").unwrap();
+        drop(writer);
+
+        assert_eq!(input.read(&mut buf).unwrap(), Some(9));
+        assert_eq!(&buf[..9], b"poweroff\n");
+        assert_eq!(input.read(&mut buf).unwrap(), None);
+
+        input.set_polled(&mut epoll, Token(0), false).unwrap();
+        assert!(epoll.is_empty());
+        fs::remove_file(&path).unwrap();
+    }
+}
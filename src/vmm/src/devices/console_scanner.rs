@@ -0,0 +1,233 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use std::io::{Result, Write};
+use std::sync::{Arc, Mutex};
+
+use crate::{ExitNotifier, ExitReason, PanicReport};
+
+/// Console lines reporting a guest kernel panic.
+const PANIC_MARKERS: [&[u8]; 2] = [b"Kernel panic -", b"Oops:"];
+
+/// Longest console line we keep around. Longer lines are scanned in chunks.
+const MAX_LINE_LEN: usize = 1024;
+
+/// Scans the guest console output for kernel panic reports.
+///
+/// Output is scanned line by line, so markers split across several writes are
+/// still detected.
+pub(crate) struct ConsoleScanner {
+    line: Vec<u8>,
+    panic_line: Option<String>,
+}
+
+impl ConsoleScanner {
+    pub fn new() -> Self {
+        ConsoleScanner {
+            line: Vec::with_capacity(MAX_LINE_LEN),
+            panic_line: None,
+        }
+    }
+
+    fn scan_line(&mut self) -> Option<String> {
+        let found = PANIC_MARKERS
+            .iter()
+            .any(|marker| self.line.windows(marker.len()).any(|w| w == *marker));
+
+        let line = if found {
+            let line = String::from_utf8_lossy(&self.line);
+            Some(line.trim_end_matches('\r').to_string())
+        } else {
+            None
+        };
+        self.line.clear();
+
+        line
+    }
+
+    /// Feed guest output to the scanner.
+    ///
+    /// Returns the first line reporting a panic, once it is complete. Subsequent panic
+    /// lines are ignored.
+    pub fn scan(&mut self, data: &[u8]) -> Option<String> {
+        let mut result = None;
+
+        for byte in data {
+            if self.panic_line.is_some() {
+                break;
+            }
+
+            let line = match *byte {
+                b'\n' => self.scan_line(),
+                b => {
+                    self.line.push(b);
+                    if self.line.len() == MAX_LINE_LEN {
+                        self.scan_line()
+                    } else {
+                        None
+                    }
+                }
+            };
+
+            if let Some(line) = line {
+                self.panic_line = Some(line.clone());
+                result = Some(line);
+            }
+        }
+
+        result
+    }
+}
+
+/// Watches the guest console output for a marker, keeping the output up to it, as
+/// [`VMM::run_until`](crate::VMM::run_until) does. Exported for the tools scraping the
+/// console output themselves, to match the markers the same way.
+///
+/// The marker is found across several writes too.
+pub struct MarkerWatch {
+    marker: Vec<u8>,
+    output: Vec<u8>,
+    seen: bool,
+}
+
+impl MarkerWatch {
+    /// Watch for `marker`, which must not be empty.
+    pub fn new(marker: &[u8]) -> Self {
+        MarkerWatch {
+            marker: marker.to_vec(),
+            output: Vec::new(),
+            seen: false,
+        }
+    }
+
+    /// Feed guest output to the watch. Returns whether it completed the marker. The
+    /// output past the marker is not kept.
+    pub fn scan(&mut self, data: &[u8]) -> bool {
+        if self.seen {
+            return false;
+        }
+
+        // The start of the marker may be in the previous output.
+        let from = self.output.len().saturating_sub(self.marker.len() - 1);
+        self.output.extend_from_slice(data);
+        let position = self.output[from..]
+            .windows(self.marker.len())
+            .position(|window| window == self.marker.as_slice());
+        if let Some(position) = position {
+            self.output.truncate(from + position + self.marker.len());
+            self.seen = true;
+        }
+
+        self.seen
+    }
+
+    pub fn seen(&self) -> bool {
+        self.seen
+    }
+
+    /// The output so far, up to the end of the marker once seen.
+    pub fn into_output(self) -> Vec<u8> {
+        self.output
+    }
+}
+
+/// Console output sink, forwarding everything to the actual sink while
+/// looking for a guest kernel panic, and for the marker of the watch when there is one.
+pub(crate) struct ScanningWriter {
+    output: Box<dyn Write + Send>,
+    // Without the panic detection, only the markers are looked for.
+    scanner: Option<ConsoleScanner>,
+    watch: Arc<Mutex<Option<MarkerWatch>>>,
+    exit: Arc<ExitNotifier>,
+}
+
+impl ScanningWriter {
+    pub fn new(
+        output: Box<dyn Write + Send>,
+        panic_detect: bool,
+        watch: Arc<Mutex<Option<MarkerWatch>>>,
+        exit: Arc<ExitNotifier>,
+    ) -> Self {
+        ScanningWriter {
+            output,
+            scanner: panic_detect.then(ConsoleScanner::new),
+            watch,
+            exit,
+        }
+    }
+}
+
+impl Write for ScanningWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let count = self.output.write(buf)?;
+
+        let panic_line = self
+            .scanner
+            .as_mut()
+            .and_then(|scanner| scanner.scan(&buf[..count]));
+        if let Some(line) = panic_line {
+            self.exit
+                .notify(ExitReason::GuestPanic(PanicReport::Console(line)));
+        }
+        // The VM pauses on the marker, for the watcher to take the output.
+        if let Some(watch) = self.watch.lock().unwrap().as_mut() {
+            if watch.scan(&buf[..count]) {
+                self.exit.notify(ExitReason::Paused);
+            }
+        }
+
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.output.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_marker() {
+        let mut scanner = ConsoleScanner::new();
+
+        assert_eq!(scanner.scan(b"[    1.0] Kernel pa"), None);
+        assert_eq!(scanner.scan(b"nic - not syncing: VFS"), None);
+        assert_eq!(
+            scanner.scan(b": Unable to mount root fs\r\n[    1.1] CPU: 0"),
+            Some("[    1.0] Kernel panic - not syncing: VFS: Unable to mount root fs".to_string())
+        );
+        // Only the first panic line is reported.
+        assert_eq!(
+            scanner.scan(b"---[ end Kernel panic - not syncing ]---\n"),
+            None
+        );
+    }
+
+    #[test]
+    fn oops() {
+        let mut scanner = ConsoleScanner::new();
+
+        assert_eq!(scanner.scan(b"Booting Linux\nLinux version 6.1\n"), None);
+        assert_eq!(
+            scanner.scan(b"Oops: 0002 [#1] SMP\n"),
+            Some("Oops: 0002 [#1] SMP".to_string())
+        );
+    }
+    #[test]
+    fn marker_watch() {
+        let mut watch = MarkerWatch::new(b"login:");
+
+        assert!(!watch.scan(b"Welcome\nbuildroot log"));
+        assert!(watch.scan(b"in: root\n"));
+        assert!(watch.seen());
+        // Only once.
+        assert!(!watch.scan(b"login:"));
+        assert_eq!(watch.into_output(), b"Welcome\nbuildroot login:");
+
+        let mut watch = MarkerWatch::new(b"$ ");
+        assert!(!watch.scan(b"no prompt yet"));
+        assert!(!watch.seen());
+        assert_eq!(watch.into_output(), b"no prompt yet");
+    }
+}
@@ -0,0 +1,519 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Console sessions: the interaction with the guest over the console, recorded to replay it
+//! against another VM.
+//!
+//! A session is a JSON lines stream of records, in order, timed from the start of the VM:
+//!
+//! ```text
+//! {"kind":"output","time_us":1820455,"data":"~ # "}
+//! {"kind":"input","time_us":3104120,"data":"uname -r\r"}
+//! {"kind":"output","time_us":3104980,"data":"uname -r\r\n"}
+//! {"kind":"output","time_us":3106312,"data":"6.1.0\r\n"}
+//! ```
+//!
+//! The output is recorded line by line. The partial line an input follows, usually a
+//! prompt, is recorded before it. The bytes which are not UTF-8 are replaced.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+/// Longest output line recorded at once. Longer lines are recorded in chunks.
+const MAX_LINE_LEN: usize = 1024;
+
+/// Differing lines reported when the replayed output diverges, the count covers them all.
+const MAX_REPORTED_LINES: usize = 20;
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+enum Record {
+    /// Bytes sent to the guest.
+    Input { time_us: u64, data: String },
+    /// Bytes the guest wrote.
+    Output { time_us: u64, data: String },
+}
+
+struct Recording {
+    file: BufWriter<File>,
+    start: Instant,
+    // The output line being written.
+    line: Vec<u8>,
+    // The recording stops on the first failure, the VM keeps running.
+    failed: bool,
+}
+
+impl Recording {
+    fn write(&mut self, record: Record) {
+        if self.failed {
+            return;
+        }
+
+        let mut line = serde_json::to_vec(&record).unwrap();
+        line.push(b'\n');
+        if let Err(e) = self.file.write_all(&line) {
+            log::warn!("Stopped recording the console session: {}", e);
+            self.failed = true;
+        }
+    }
+
+    fn time_us(&self) -> u64 {
+        self.start.elapsed().as_micros() as u64
+    }
+
+    fn record_line(&mut self) {
+        if self.line.is_empty() {
+            return;
+        }
+
+        let data = String::from_utf8_lossy(&self.line).into_owned();
+        self.line.clear();
+        let time_us = self.time_us();
+        self.write(Record::Output { time_us, data });
+    }
+}
+
+/// Records the console input and output to a session file.
+#[derive(Clone)]
+pub(crate) struct SessionRecorder {
+    recording: Arc<Mutex<Recording>>,
+}
+
+impl SessionRecorder {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let recording = Recording {
+            file: BufWriter::new(File::create(path)?),
+            start: Instant::now(),
+            line: Vec::with_capacity(MAX_LINE_LEN),
+            failed: false,
+        };
+
+        Ok(SessionRecorder {
+            recording: Arc::new(Mutex::new(recording)),
+        })
+    }
+
+    /// Time the records from now on, when the VM starts.
+    pub fn start(&self) {
+        self.recording.lock().unwrap().start = Instant::now();
+    }
+
+    /// Record `data`, sent to the guest.
+    pub fn record_input(&self, data: &[u8]) {
+        let mut recording = self.recording.lock().unwrap();
+        recording.record_line();
+        let time_us = recording.time_us();
+        let data = String::from_utf8_lossy(data).into_owned();
+        recording.write(Record::Input { time_us, data });
+    }
+
+    fn record_output(&self, data: &[u8]) {
+        let mut recording = self.recording.lock().unwrap();
+        for byte in data {
+            recording.line.push(*byte);
+            if *byte == b'\n' || recording.line.len() == MAX_LINE_LEN {
+                recording.record_line();
+            }
+        }
+    }
+
+    /// Record the output written to `output`.
+    pub fn output_writer(&self, output: Box<dyn Write + Send>) -> RecordingWriter {
+        RecordingWriter {
+            output,
+            recorder: self.clone(),
+        }
+    }
+
+    /// Record the partial output line, and write the records out.
+    pub fn finish(&self) -> io::Result<()> {
+        let mut recording = self.recording.lock().unwrap();
+        recording.record_line();
+        recording.file.flush()
+    }
+}
+
+/// The console output, recorded.
+pub(crate) struct RecordingWriter {
+    output: Box<dyn Write + Send>,
+    recorder: SessionRecorder,
+}
+
+impl Write for RecordingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // The guest wrote it, whether the sink took it or not.
+        let result = self.output.write(buf);
+        let written = *result.as_ref().unwrap_or(&buf.len());
+        self.recorder.record_output(&buf[..written]);
+
+        result
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+}
+
+// An input of the session, and when to send it.
+struct ReplayInput {
+    time: Duration,
+    // The last output line before the input, without its numbers, which the replay waits
+    // for in fast mode.
+    prompt: String,
+    data: Vec<u8>,
+}
+
+/// Sends the input of a recorded session to the guest, at the recorded times or once the
+/// prompts they followed show up, and compares the output with the recorded one.
+pub(crate) struct SessionReplay {
+    inputs: VecDeque<ReplayInput>,
+    expected: String,
+    // The whole console output of the guest.
+    output: Arc<Mutex<Vec<u8>>>,
+    fast: bool,
+    tolerance: usize,
+    start: Instant,
+    // Where the output following the last input starts.
+    sent_at: usize,
+}
+
+impl SessionReplay {
+    /// Replay the session at `path`, at the recorded times or, when `fast`, on the prompts.
+    /// Up to `tolerance` output lines may differ in their numbers only, e.g. durations.
+    pub fn open(path: &Path, fast: bool, tolerance: usize) -> io::Result<Self> {
+        let mut inputs = VecDeque::new();
+        let mut expected = String::new();
+        // The output since the last input.
+        let mut since_input = String::new();
+
+        for (index, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let record = serde_json::from_str(&line).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("line {}: {}", index + 1, e),
+                )
+            })?;
+
+            match record {
+                Record::Input { time_us, data } => {
+                    inputs.push_back(ReplayInput {
+                        time: Duration::from_micros(time_us),
+                        prompt: without_numbers(last_line(&since_input)),
+                        data: data.into_bytes(),
+                    });
+                    since_input.clear();
+                }
+                Record::Output { data, .. } => {
+                    expected.push_str(&data);
+                    since_input.push_str(&data);
+                }
+            }
+        }
+
+        Ok(SessionReplay {
+            inputs,
+            expected,
+            output: Arc::default(),
+            fast,
+            tolerance,
+            start: Instant::now(),
+            sent_at: 0,
+        })
+    }
+
+    /// Time the input from now on, when the VM starts.
+    pub fn start(&mut self) {
+        self.start = Instant::now();
+    }
+
+    /// Capture the output written to `output`, to compare it with the recorded one.
+    pub fn output_writer(&self, output: Box<dyn Write + Send>) -> CaptureWriter {
+        CaptureWriter {
+            output,
+            captured: self.output.clone(),
+        }
+    }
+
+    /// Take the next input, once it is due.
+    pub fn next_input(&mut self) -> Option<Vec<u8>> {
+        let input = self.inputs.front()?;
+        let due = if self.fast {
+            let output = self.output.lock().unwrap();
+            let since_input = String::from_utf8_lossy(&output[self.sent_at..]);
+            without_numbers(&since_input).contains(&input.prompt)
+        } else {
+            self.start.elapsed() >= input.time
+        };
+        if !due {
+            return None;
+        }
+
+        self.sent_at = self.output.lock().unwrap().len();
+        self.inputs.pop_front().map(|input| input.data)
+    }
+
+    /// Whether the whole input was sent.
+    pub fn is_done(&self) -> bool {
+        self.inputs.is_empty()
+    }
+
+    /// Compare the output of the guest with the recorded one.
+    pub fn check(&self) -> Result<(), Divergence> {
+        let output = self.output.lock().unwrap();
+        compare(
+            &self.expected,
+            &String::from_utf8_lossy(&output),
+            self.tolerance,
+        )
+    }
+}
+
+/// The console output, captured.
+pub(crate) struct CaptureWriter {
+    output: Box<dyn Write + Send>,
+    captured: Arc<Mutex<Vec<u8>>>,
+}
+
+impl Write for CaptureWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let result = self.output.write(buf);
+        let written = *result.as_ref().unwrap_or(&buf.len());
+        self.captured
+            .lock()
+            .unwrap()
+            .extend_from_slice(&buf[..written]);
+
+        result
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+}
+
+/// How the replayed output differs from the recorded one.
+#[derive(Debug)]
+pub(crate) struct Divergence {
+    // The differing lines, numbered from 1, as recorded and as replayed.
+    lines: Vec<(usize, Option<String>, Option<String>)>,
+    differing: usize,
+    timing_only: usize,
+    tolerance: usize,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{} lines differ, {} of them in their numbers only (tolerance {})",
+            self.differing + self.timing_only,
+            self.timing_only,
+            self.tolerance
+        )?;
+        for (number, expected, actual) in self.lines.iter() {
+            writeln!(f, "@@ line {} @@", number)?;
+            if let Some(expected) = expected {
+                writeln!(f, "-{}", expected)?;
+            }
+            if let Some(actual) = actual {
+                writeln!(f, "+{}", actual)?;
+            }
+        }
+        let unreported = self.differing + self.timing_only - self.lines.len();
+        if unreported > 0 {
+            writeln!(f, "... and {} more", unreported)?;
+        }
+
+        Ok(())
+    }
+}
+
+// The last line of `text` with something in it.
+fn last_line(text: &str) -> &str {
+    text.lines()
+        .rev()
+        .map(str::trim)
+        .find(|line| !line.is_empty())
+        .unwrap_or("")
+}
+
+// The lines of the console output, without the kernel timestamps: they never match.
+fn output_lines(output: &str) -> Vec<&str> {
+    output
+        .lines()
+        .map(|line| without_timestamp(line.trim_end_matches('\r')))
+        .collect()
+}
+
+// The line without its `[    1.234567]` kernel timestamp.
+fn without_timestamp(line: &str) -> &str {
+    let (time, rest) = match line.strip_prefix('[').and_then(|l| l.split_once(']')) {
+        Some(split) => split,
+        None => return line,
+    };
+    match time.trim_start().split_once('.') {
+        Some((secs, frac))
+            if !secs.is_empty()
+                && !frac.is_empty()
+                && secs.bytes().chain(frac.bytes()).all(|b| b.is_ascii_digit()) =>
+        {
+            rest
+        }
+        _ => line,
+    }
+}
+
+// The text with its numbers and its spacing left out: the lines which differ in the
+// durations or the counters they show only are then the same.
+fn without_numbers(line: &str) -> String {
+    let mut words = line.split_whitespace().map(|word| {
+        let mut word = word.to_string();
+        word.retain(|c| !c.is_ascii_digit());
+        word
+    });
+    let mut result = words.next().unwrap_or_default();
+    for word in words {
+        result.push(' ');
+        result.push_str(&word);
+    }
+
+    result
+}
+
+// Compare the `actual` output with the `expected` one, line by line. Up to `tolerance`
+// lines may differ in their numbers only.
+fn compare(expected: &str, actual: &str, tolerance: usize) -> Result<(), Divergence> {
+    let expected = output_lines(expected);
+    let actual = output_lines(actual);
+    let mut divergence = Divergence {
+        lines: Vec::new(),
+        differing: 0,
+        timing_only: 0,
+        tolerance,
+    };
+
+    for index in 0..expected.len().max(actual.len()) {
+        let (e, a) = (expected.get(index).copied(), actual.get(index).copied());
+        match (e, a) {
+            (Some(e), Some(a)) if e == a => continue,
+            (Some(e), Some(a)) if without_numbers(e) == without_numbers(a) => {
+                divergence.timing_only += 1
+            }
+            _ => divergence.differing += 1,
+        }
+        if divergence.lines.len() < MAX_REPORTED_LINES {
+            divergence
+                .lines
+                .push((index + 1, e.map(str::to_string), a.map(str::to_string)));
+        }
+    }
+
+    if divergence.differing > 0 || divergence.timing_only > tolerance {
+        return Err(divergence);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "lumper-console-session-{}-{}",
+            std::process::id(),
+            name
+        ))
+    }
+
+    #[test]
+    fn record_replay() {
+        let path = temp_path("session");
+        let recorder = SessionRecorder::create(&path).unwrap();
+        let mut output = recorder.output_writer(Box::new(io::sink()));
+        output.write_all(b"Welcome\r\n~ # ").unwrap();
+        recorder.record_input(b"uname\r");
+        output.write_all(b"uname\r\nLinux\r\n~ # ").unwrap();
+        recorder.finish().unwrap();
+
+        let records: Vec<Record> = fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let data: Vec<_> = records
+            .iter()
+            .map(|record| match record {
+                Record::Input { data, .. } => format!("<{}", data),
+                Record::Output { data, .. } => format!(">{}", data),
+            })
+            .collect();
+        assert_eq!(
+            data,
+            [
+                ">Welcome\r\n",
+                ">~ # ",
+                "<uname\r",
+                ">uname\r\n",
+                ">Linux\r\n",
+                ">~ # "
+            ]
+        );
+
+        // The input waits for the prompt it followed.
+        let mut replay = SessionReplay::open(&path, true, 0).unwrap();
+        let mut output = replay.output_writer(Box::new(io::sink()));
+        assert_eq!(replay.next_input(), None);
+        output.write_all(b"Welcome\r\n~ ").unwrap();
+        assert_eq!(replay.next_input(), None);
+        output.write_all(b"# ").unwrap();
+        assert_eq!(replay.next_input().unwrap(), b"uname\r");
+        assert!(replay.is_done());
+
+        output.write_all(b"uname\r\nLinux\r\n").unwrap();
+        assert!(replay.check().is_err());
+        output.write_all(b"~ # ").unwrap();
+        replay.check().unwrap();
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn divergence() {
+        let expected = "[    0.051234] Booting\r\nready in 12 ms\r\n~ # ";
+        // The kernel timestamps are left out.
+        compare(
+            expected,
+            "[   10.000001] Booting\r\nready in 12 ms\r\n~ # ",
+            0,
+        )
+        .unwrap();
+
+        let actual = "[    0.051234] Booting\r\nready in 9  ms\r\n~ # ";
+        let divergence = compare(expected, actual, 0).unwrap_err();
+        assert_eq!(divergence.timing_only, 1);
+        compare(expected, actual, 1).unwrap();
+
+        let divergence = compare(expected, "[    0.051234] Booting\r\npanic\r\n", 5).unwrap_err();
+        assert_eq!(divergence.differing, 2);
+        assert_eq!(
+            divergence.to_string(),
+            "2 lines differ, 0 of them in their numbers only (tolerance 5)\n\
+             @@ line 2 @@\n-ready in 12 ms\n+panic\n\
+             @@ line 3 @@\n-~ # \n"
+        );
+    }
+}
@@ -0,0 +1,66 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
+
+use vm_device::bus::{PioAddress, PioAddressOffset};
+use vm_device::MutDevicePio;
+
+/// I/O port of the vCPU count register, the one of the QEMU CPU hotplug block.
+pub const CPU_HOTPLUG_PORT: u16 = 0xcd8;
+/// Number of CPU hotplug ports.
+pub const CPU_HOTPLUG_PORT_SIZE: u16 = 1;
+
+/// Read-only register giving the number of vCPUs plugged into the guest.
+///
+/// The vCPUs are plugged in order: the `_STA` method of the ACPI processor device of
+/// vCPU `n` reports it present when the count is above `n`.
+pub(crate) struct CpuHotplug {
+    present: Arc<AtomicU8>,
+}
+
+impl CpuHotplug {
+    pub fn new(present: Arc<AtomicU8>) -> Self {
+        CpuHotplug { present }
+    }
+}
+
+impl MutDevicePio for CpuHotplug {
+    fn pio_read(&mut self, _base: PioAddress, offset: PioAddressOffset, data: &mut [u8]) {
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte = match offset + i as PioAddressOffset {
+                0 => self.present.load(Ordering::Acquire),
+                _ => 0xff,
+            };
+        }
+    }
+
+    fn pio_write(&mut self, _base: PioAddress, offset: PioAddressOffset, data: &[u8]) {
+        log::debug!(
+            "Ignoring write of {:x?} to CPU hotplug port {:#x}",
+            data,
+            CPU_HOTPLUG_PORT + offset
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn present() {
+        let present = Arc::new(AtomicU8::new(2));
+        let mut device = CpuHotplug::new(present.clone());
+
+        let mut data = [0u8; 2];
+        device.pio_read(PioAddress(CPU_HOTPLUG_PORT), 0, &mut data);
+        assert_eq!(data, [2, 0xff]);
+
+        // The guest cannot change it, the VMM does once it plugged a vCPU.
+        device.pio_write(PioAddress(CPU_HOTPLUG_PORT), 0, &[8]);
+        present.store(3, Ordering::Release);
+        device.pio_read(PioAddress(CPU_HOTPLUG_PORT), 0, &mut data[..1]);
+        assert_eq!(data[0], 3);
+    }
+}
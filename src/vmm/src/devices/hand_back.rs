@@ -0,0 +1,59 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use std::io::{Result, Write};
+use std::sync::{Arc, Mutex};
+
+/// Where a [`HandBackWriter`] puts its sink back.
+pub(crate) type SinkSlot = Arc<Mutex<Option<Box<dyn Write + Send>>>>;
+
+/// Sink lent to the VMM, which goes back to its slot once dropped rather than being closed.
+///
+/// The console output wraps the sink of the library user in it: the writer thread drops
+/// it when the VM stops, and the next VMM can take it from the slot.
+pub(crate) struct HandBackWriter {
+    sink: Option<Box<dyn Write + Send>>,
+    slot: SinkSlot,
+}
+
+impl HandBackWriter {
+    pub fn new(sink: Box<dyn Write + Send>, slot: SinkSlot) -> Self {
+        HandBackWriter {
+            sink: Some(sink),
+            slot,
+        }
+    }
+}
+
+impl Write for HandBackWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        // Only gone once dropped.
+        self.sink.as_mut().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.sink.as_mut().unwrap().flush()
+    }
+}
+
+impl Drop for HandBackWriter {
+    fn drop(&mut self) {
+        *self.slot.lock().unwrap() = self.sink.take();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn handed_back() {
+        let slot = SinkSlot::default();
+        let mut writer = HandBackWriter::new(Box::new(Vec::new()), slot.clone());
+        writer.write_all(b"login: ").unwrap();
+        assert!(slot.lock().unwrap().is_none());
+
+        drop(writer);
+        let mut sink = slot.lock().unwrap().take().unwrap();
+        sink.write_all(b"again").unwrap();
+    }
+}
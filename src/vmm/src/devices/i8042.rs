@@ -0,0 +1,67 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use std::sync::Arc;
+
+use vm_device::bus::{PioAddress, PioAddressOffset};
+use vm_device::MutDevicePio;
+
+use crate::{ExitNotifier, ExitReason};
+
+/// Command port of the i8042 keyboard controller.
+pub const I8042_COMMAND_PORT: u16 = 0x64;
+/// Number of i8042 command ports.
+pub const I8042_COMMAND_PORT_SIZE: u16 = 1;
+/// Command pulsing the CPU reset line. The FADT reset register writes it too.
+pub const I8042_RESET: u8 = 0xfe;
+
+/// The reset line of an i8042 keyboard controller, which `reboot=k` pulses.
+///
+/// There is no keyboard behind it: the status reads as all ones, as on a floating bus,
+/// and the guest driver finds no controller. Pulsing the reset line stops the VMM with
+/// [`ExitReason::GuestReset`], the other commands are ignored.
+pub(crate) struct I8042 {
+    exit: Arc<ExitNotifier>,
+}
+
+impl I8042 {
+    pub fn new(exit: Arc<ExitNotifier>) -> Self {
+        I8042 { exit }
+    }
+}
+
+impl MutDevicePio for I8042 {
+    fn pio_read(&mut self, _base: PioAddress, _offset: PioAddressOffset, data: &mut [u8]) {
+        data.fill(0xff);
+    }
+
+    fn pio_write(&mut self, _base: PioAddress, offset: PioAddressOffset, data: &[u8]) {
+        match data.first() {
+            Some(&I8042_RESET) if offset == 0 => self.exit.notify(ExitReason::GuestReset),
+            Some(command) => log::debug!("Ignoring i8042 command {:#x}", command),
+            None => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reset() {
+        let exit = Arc::new(ExitNotifier::new().unwrap());
+        let mut i8042 = I8042::new(exit.clone());
+
+        // No controller: the guest waits for the input buffer to empty, then gives up.
+        let mut data = [0u8];
+        i8042.pio_read(PioAddress(I8042_COMMAND_PORT), 0, &mut data);
+        assert_eq!(data[0], 0xff);
+
+        // Reading the output port.
+        i8042.pio_write(PioAddress(I8042_COMMAND_PORT), 0, &[0xd0]);
+        assert_eq!(exit.take(), None);
+
+        i8042.pio_write(PioAddress(I8042_COMMAND_PORT), 0, &[I8042_RESET]);
+        assert_eq!(exit.take(), Some(ExitReason::GuestReset));
+    }
+}
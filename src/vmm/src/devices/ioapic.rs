@@ -0,0 +1,412 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Userspace IOAPIC, for the split irqchip: KVM only emulates the local APICs.
+//!
+//! The devices keep signaling their interrupts on their eventfds. The IOAPIC worker reads
+//! them, and the IOAPIC turns each into an MSI to the local APICs, as the redirection
+//! entry of its pin tells. The entries are also the MSI routes of the GSIs of the pins:
+//! KVM then exits when a vCPU ends a level-triggered interrupt, and the vCPU writes its
+//! vector to the EOI register.
+//!
+//! The eventfds only tell that an interrupt happened, not when the line went down: each
+//! signal is a pulse. A level-triggered pin keeps it pending until the guest ended the
+//! interrupt it delivered before, as the in-kernel IOAPIC does with the irqfds.
+
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use vm_device::bus::{MmioAddress, MmioAddressOffset};
+use vm_device::MutDeviceMmio;
+use vmm_sys_util::eventfd::EventFd;
+
+use crate::epoll_context::{EpollContext, EventHandler, EventOps, Events, Interest, Token};
+
+/// Number of input pins.
+pub(crate) const IOAPIC_PINS: usize = 24;
+/// Size of the register window.
+pub(crate) const IOAPIC_MMIO_SIZE: u64 = 0x1000;
+/// Offset of the EOI register, where the vCPUs write the vectors they ended.
+pub(crate) const IOAPIC_EOI: MmioAddressOffset = 0x40;
+
+// Register offsets, 32 bits wide.
+const IOREGSEL: MmioAddressOffset = 0x00;
+const IOWIN: MmioAddressOffset = 0x10;
+
+// Indirect registers, selected by IOREGSEL.
+const IOAPICID: u32 = 0x00;
+const IOAPICVER: u32 = 0x01;
+const IOAPICARB: u32 = 0x02;
+// Each redirection entry takes two registers, low then high.
+const IOREDTBL: u32 = 0x10;
+
+// Version 0x20 has the EOI register, and the highest entry is in bits 16-23.
+const VERSION: u32 = 0x20 | ((IOAPIC_PINS as u32 - 1) << 16);
+
+// Redirection entry fields.
+const ENTRY_VECTOR: u64 = 0xff;
+const ENTRY_DELIVERY_MODE_SHIFT: u32 = 8;
+const ENTRY_DEST_MODE_SHIFT: u32 = 11;
+const ENTRY_DELIVERY_STATUS: u64 = 1 << 12;
+const ENTRY_REMOTE_IRR: u64 = 1 << 14;
+const ENTRY_LEVEL: u64 = 1 << 15;
+const ENTRY_MASKED: u64 = 1 << 16;
+const ENTRY_DEST_SHIFT: u32 = 56;
+// Set by the IOAPIC, the guest writes do not change them.
+const ENTRY_READ_ONLY: u64 = ENTRY_DELIVERY_STATUS | ENTRY_REMOTE_IRR;
+
+// MSI address of the local APICs.
+const MSI_ADDRESS: u32 = 0xfee0_0000;
+
+/// An interrupt message to the local APICs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Msi {
+    pub address: u32,
+    pub data: u32,
+}
+
+/// Where the IOAPIC sends its interrupts: the KVM local APICs, outside of the tests.
+pub(crate) trait MsiSink: Send {
+    /// Deliver `msi`.
+    fn send(&self, msi: Msi) -> io::Result<()>;
+
+    /// Route the GSIs of the unmasked pins, as (pin, message).
+    fn set_routes(&self, routes: &[(u32, Msi)]) -> io::Result<()>;
+}
+
+/// IOAPIC, with all its entries masked until the guest sets them up.
+pub(crate) struct Ioapic {
+    id: u32,
+    select: u32,
+    entries: [u64; IOAPIC_PINS],
+    // Pins signaled, and not delivered yet.
+    pending: u32,
+    sink: Box<dyn MsiSink>,
+}
+
+impl Ioapic {
+    pub fn new(sink: Box<dyn MsiSink>) -> Self {
+        Ioapic {
+            id: 0,
+            select: 0,
+            entries: [ENTRY_MASKED; IOAPIC_PINS],
+            pending: 0,
+            sink,
+        }
+    }
+
+    /// Mask all the entries and drop the pending interrupts, as at power on.
+    pub fn reset(&mut self) {
+        self.id = 0;
+        self.select = 0;
+        self.entries = [ENTRY_MASKED; IOAPIC_PINS];
+        self.pending = 0;
+        if let Err(e) = self.sink.set_routes(&[]) {
+            eprintln!("Failed to route the IOAPIC pins: {}", e);
+        }
+    }
+
+    /// Signal an interrupt on `pin`.
+    pub fn trigger(&mut self, pin: usize) {
+        if pin < IOAPIC_PINS {
+            self.pending |= 1 << pin;
+            self.service(pin);
+        }
+    }
+
+    /// A vCPU ended the interrupt `vector`: the level-triggered pins which delivered it
+    /// may deliver again.
+    pub fn end_of_interrupt(&mut self, vector: u8) {
+        for pin in 0..IOAPIC_PINS {
+            let entry = self.entries[pin];
+            if entry & ENTRY_REMOTE_IRR != 0 && entry & ENTRY_VECTOR == u64::from(vector) {
+                self.entries[pin] &= !ENTRY_REMOTE_IRR;
+                self.service(pin);
+            }
+        }
+    }
+
+    // Deliver the pending interrupt of `pin`, unless masked, or the previous one of a
+    // level-triggered pin was not ended yet.
+    fn service(&mut self, pin: usize) {
+        let entry = self.entries[pin];
+        if self.pending & (1 << pin) == 0 || entry & ENTRY_MASKED != 0 {
+            return;
+        }
+        if entry & ENTRY_LEVEL != 0 {
+            if entry & ENTRY_REMOTE_IRR != 0 {
+                return;
+            }
+            self.entries[pin] |= ENTRY_REMOTE_IRR;
+        }
+        self.pending &= !(1 << pin);
+
+        let msi = msi(entry);
+        log::trace!("IOAPIC pin {} delivers vector {}", pin, msi.data & 0xff);
+        if let Err(e) = self.sink.send(msi) {
+            eprintln!("Failed to deliver the IOAPIC pin {}: {}", pin, e);
+        }
+    }
+
+    fn read_register(&self) -> u32 {
+        match self.select {
+            IOAPICID | IOAPICARB => self.id << 24,
+            IOAPICVER => VERSION,
+            index => match self.entry_register(index) {
+                Some((pin, false)) => self.entries[pin] as u32,
+                Some((pin, true)) => (self.entries[pin] >> 32) as u32,
+                None => 0,
+            },
+        }
+    }
+
+    fn write_register(&mut self, value: u32) {
+        let (pin, high) = match self.select {
+            IOAPICID => {
+                self.id = (value >> 24) & 0xf;
+                return;
+            }
+            index => match self.entry_register(index) {
+                Some(register) => register,
+                None => return,
+            },
+        };
+
+        let old = self.entries[pin];
+        let mut entry = if high {
+            (old & 0xffff_ffff) | (u64::from(value) << 32)
+        } else {
+            (old & !0xffff_ffff) | u64::from(value)
+        };
+        entry = (entry & !ENTRY_READ_ONLY) | (old & ENTRY_READ_ONLY);
+        // An edge-triggered pin has nothing left to end.
+        if entry & ENTRY_LEVEL == 0 {
+            entry &= !ENTRY_REMOTE_IRR;
+        }
+        self.entries[pin] = entry;
+
+        if (old ^ entry) & !ENTRY_READ_ONLY != 0 {
+            if let Err(e) = self.sink.set_routes(&self.routes()) {
+                eprintln!("Failed to route the IOAPIC pins: {}", e);
+            }
+        }
+        self.service(pin);
+    }
+
+    // The pin of a redirection entry register, and whether it is the high half.
+    fn entry_register(&self, index: u32) -> Option<(usize, bool)> {
+        let pin = index.checked_sub(IOREDTBL)? as usize / 2;
+        (pin < IOAPIC_PINS).then_some((pin, index % 2 == 1))
+    }
+
+    fn routes(&self) -> Vec<(u32, Msi)> {
+        (0..IOAPIC_PINS)
+            .filter(|pin| self.entries[*pin] & ENTRY_MASKED == 0)
+            .map(|pin| (pin as u32, msi(self.entries[pin])))
+            .collect()
+    }
+}
+
+// The message a redirection entry sends.
+fn msi(entry: u64) -> Msi {
+    let dest = ((entry >> ENTRY_DEST_SHIFT) & 0xff) as u32;
+    let dest_mode = ((entry >> ENTRY_DEST_MODE_SHIFT) & 1) as u32;
+    let delivery_mode = ((entry >> ENTRY_DELIVERY_MODE_SHIFT) & 0x7) as u32;
+    let level = u32::from(entry & ENTRY_LEVEL != 0);
+
+    Msi {
+        address: MSI_ADDRESS | (dest << 12) | (dest_mode << 2),
+        data: (entry & ENTRY_VECTOR) as u32 | (delivery_mode << 8) | (level << 15),
+    }
+}
+
+impl MutDeviceMmio for Ioapic {
+    fn mmio_read(&mut self, _base: MmioAddress, offset: MmioAddressOffset, data: &mut [u8]) {
+        let value = match offset {
+            IOREGSEL => self.select,
+            IOWIN => self.read_register(),
+            _ => 0,
+        }
+        .to_le_bytes();
+        let len = data.len().min(value.len());
+        data[..len].copy_from_slice(&value[..len]);
+    }
+
+    fn mmio_write(&mut self, _base: MmioAddress, offset: MmioAddressOffset, data: &[u8]) {
+        let mut value = [0u8; 4];
+        let len = data.len().min(value.len());
+        value[..len].copy_from_slice(&data[..len]);
+        let value = u32::from_le_bytes(value);
+
+        match offset {
+            IOREGSEL => self.select = value & 0xff,
+            IOWIN => self.write_register(value),
+            IOAPIC_EOI => self.end_of_interrupt(value as u8),
+            _ => {}
+        }
+    }
+}
+
+// Triggers a pin each time its device signals its eventfd.
+struct PinHandler {
+    ioapic: Arc<Mutex<Ioapic>>,
+    eventfd: EventFd,
+    pin: usize,
+}
+
+impl EventHandler for PinHandler {
+    fn process(&mut self, _events: Events, _ops: &mut EventOps) -> crate::Result<()> {
+        // The counter only tells there was an interrupt, the pulses are merged.
+        if self.eventfd.read().is_ok() {
+            self.ioapic.lock().unwrap().trigger(self.pin);
+        }
+        Ok(())
+    }
+}
+
+/// Read the device eventfds on their own thread, and trigger their pins, given along
+/// them.
+///
+/// The thread runs for as long as the VMM.
+pub(crate) fn spawn_worker(
+    ioapic: Arc<Mutex<Ioapic>>,
+    eventfds: Vec<(usize, EventFd)>,
+) -> io::Result<()> {
+    let mut epoll = EpollContext::new()?;
+    for (index, (pin, eventfd)) in eventfds.into_iter().enumerate() {
+        let fd = eventfd.as_raw_fd();
+        let handler = PinHandler {
+            ioapic: ioapic.clone(),
+            eventfd,
+            pin,
+        };
+        epoll.add(fd, Token(index as u64), Interest::Read, Box::new(handler))?;
+    }
+
+    thread::Builder::new()
+        .name("ioapic".to_string())
+        .spawn(move || loop {
+            if let Err(e) = epoll.run_once(None) {
+                eprintln!("IOAPIC worker failed: {}", e);
+                return;
+            }
+        })?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct Recorder {
+        sent: Arc<Mutex<Vec<Msi>>>,
+        routes: Arc<Mutex<Vec<(u32, Msi)>>>,
+    }
+
+    impl MsiSink for Recorder {
+        fn send(&self, msi: Msi) -> io::Result<()> {
+            self.sent.lock().unwrap().push(msi);
+            Ok(())
+        }
+
+        fn set_routes(&self, routes: &[(u32, Msi)]) -> io::Result<()> {
+            *self.routes.lock().unwrap() = routes.to_vec();
+            Ok(())
+        }
+    }
+
+    fn write(ioapic: &mut Ioapic, offset: MmioAddressOffset, value: u32) {
+        ioapic.mmio_write(MmioAddress(0), offset, &value.to_le_bytes());
+    }
+
+    fn read(ioapic: &mut Ioapic, index: u32) -> u32 {
+        write(ioapic, IOREGSEL, index);
+        let mut data = [0u8; 4];
+        ioapic.mmio_read(MmioAddress(0), IOWIN, &mut data);
+        u32::from_le_bytes(data)
+    }
+
+    fn set_entry(ioapic: &mut Ioapic, pin: u32, entry: u64) {
+        write(ioapic, IOREGSEL, IOREDTBL + 2 * pin + 1);
+        write(ioapic, IOWIN, (entry >> 32) as u32);
+        write(ioapic, IOREGSEL, IOREDTBL + 2 * pin);
+        write(ioapic, IOWIN, entry as u32);
+    }
+
+    #[test]
+    fn registers() {
+        let mut ioapic = Ioapic::new(Box::new(Recorder::default()));
+        assert_eq!(read(&mut ioapic, IOAPICVER), 0x0017_0020);
+        assert_eq!(read(&mut ioapic, IOREDTBL + 2 * 23), ENTRY_MASKED as u32);
+
+        write(&mut ioapic, IOREGSEL, IOAPICID);
+        write(&mut ioapic, IOWIN, 8 << 24);
+        assert_eq!(read(&mut ioapic, IOAPICID), 8 << 24);
+
+        // The read-only bits stay.
+        set_entry(&mut ioapic, 5, (3 << 56) | ENTRY_REMOTE_IRR | 0x30);
+        assert_eq!(read(&mut ioapic, IOREDTBL + 10), 0x30);
+        assert_eq!(read(&mut ioapic, IOREDTBL + 11), 3 << 24);
+    }
+
+    #[test]
+    fn edge() {
+        let recorder = Recorder::default();
+        let mut ioapic = Ioapic::new(Box::new(recorder.clone()));
+
+        // Masked, the interrupt waits.
+        ioapic.trigger(4);
+        assert!(recorder.sent.lock().unwrap().is_empty());
+
+        set_entry(&mut ioapic, 4, (1 << 56) | 0x24);
+        let msi = Msi {
+            address: 0xfee0_1000,
+            data: 0x24,
+        };
+        assert_eq!(*recorder.sent.lock().unwrap(), [msi]);
+        assert_eq!(*recorder.routes.lock().unwrap(), [(4, msi)]);
+
+        ioapic.trigger(4);
+        ioapic.trigger(4);
+        assert_eq!(recorder.sent.lock().unwrap().len(), 3);
+
+        // Masked again, without routes.
+        ioapic.reset();
+        assert!(recorder.routes.lock().unwrap().is_empty());
+        ioapic.trigger(4);
+        assert_eq!(recorder.sent.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn level() {
+        let recorder = Recorder::default();
+        let mut ioapic = Ioapic::new(Box::new(recorder.clone()));
+        set_entry(&mut ioapic, 10, ENTRY_LEVEL | 0x31);
+
+        // The second interrupt waits for the first to end.
+        ioapic.trigger(10);
+        ioapic.trigger(10);
+        assert_eq!(recorder.sent.lock().unwrap().len(), 1);
+        assert_eq!(recorder.sent.lock().unwrap()[0].data, 0x8031);
+        assert_ne!(
+            read(&mut ioapic, IOREDTBL + 20) & ENTRY_REMOTE_IRR as u32,
+            0
+        );
+
+        // Another vector changes nothing.
+        write(&mut ioapic, IOAPIC_EOI, 0x32);
+        assert_eq!(recorder.sent.lock().unwrap().len(), 1);
+
+        write(&mut ioapic, IOAPIC_EOI, 0x31);
+        assert_eq!(recorder.sent.lock().unwrap().len(), 2);
+        ioapic.end_of_interrupt(0x31);
+        assert_eq!(
+            read(&mut ioapic, IOREDTBL + 20) & ENTRY_REMOTE_IRR as u32,
+            0
+        );
+        assert_eq!(recorder.sent.lock().unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,317 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Interrupt latency diagnostics of the virtio devices, enabled with `--trace-irq`.
+//!
+//! Each device times its interrupts, from the first irqfd signal the driver did not
+//! acknowledge yet to its write to InterruptACK. The virtio-net worker also times the
+//! delay from its epoll wakeup to the start of the interface processing. The durations go
+//! to histograms with log-linear buckets, as HDR histograms: 8 buckets per power of 2,
+//! within 12.5% of the values they count.
+//!
+//! Tracing is process-wide. Off, recording is a relaxed atomic load.
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::Instant;
+
+use crate::api::{HistogramBucket, HistogramSnapshot, IrqLatency};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+// The timestamps are nanoseconds since then.
+static EPOCH: OnceLock<Instant> = OnceLock::new();
+
+// Buckets per power of 2, as bits.
+const SUB_BUCKET_BITS: u32 = 3;
+const SUB_BUCKETS: usize = 1 << SUB_BUCKET_BITS;
+// The values below SUB_BUCKETS have a bucket each, then each power of 2 from there.
+const BUCKETS: usize = (64 - SUB_BUCKET_BITS as usize + 1) * SUB_BUCKETS;
+
+/// Start tracing the interrupts of all the devices.
+pub(crate) fn enable() {
+    EPOCH.get_or_init(Instant::now);
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+pub(crate) fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Current timestamp, never 0, which stands for no timestamp.
+pub(crate) fn now() -> u64 {
+    let epoch = EPOCH.get_or_init(Instant::now);
+    epoch.elapsed().as_nanos() as u64 + 1
+}
+
+// The bucket counting `value`.
+fn bucket(value: u64) -> usize {
+    if value < SUB_BUCKETS as u64 {
+        return value as usize;
+    }
+    let exponent = 63 - value.leading_zeros();
+    let sub_bucket = (value >> (exponent - SUB_BUCKET_BITS)) as usize & (SUB_BUCKETS - 1);
+    (exponent - SUB_BUCKET_BITS + 1) as usize * SUB_BUCKETS + sub_bucket
+}
+
+// The largest value counted in `bucket`.
+fn bucket_max(bucket: usize) -> u64 {
+    if bucket < SUB_BUCKETS {
+        return bucket as u64;
+    }
+    let exponent = (bucket / SUB_BUCKETS) as u32 + SUB_BUCKET_BITS - 1;
+    let sub_bucket = (bucket % SUB_BUCKETS) as u64;
+    let width = 1u64 << (exponent - SUB_BUCKET_BITS);
+    ((SUB_BUCKETS as u64 + sub_bucket) * width).wrapping_add(width - 1)
+}
+
+/// Durations, in nanoseconds, counted in log-linear buckets.
+pub(crate) struct Histogram {
+    buckets: Vec<AtomicU64>,
+    count: AtomicU64,
+    sum: AtomicU64,
+    max: AtomicU64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram {
+            buckets: (0..BUCKETS).map(|_| AtomicU64::new(0)).collect(),
+            count: AtomicU64::new(0),
+            sum: AtomicU64::new(0),
+            max: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Histogram {
+    pub fn record(&self, nanos: u64) {
+        self.buckets[bucket(nanos)].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(nanos, Ordering::Relaxed);
+        self.max.fetch_max(nanos, Ordering::Relaxed);
+    }
+
+    /// The current counts, with those of `others` added.
+    pub fn snapshot(&self, others: &[&Histogram]) -> HistogramSnapshot {
+        let histograms = || std::iter::once(self).chain(others.iter().copied());
+        let load = |histogram: &Histogram, field: fn(&Histogram) -> &AtomicU64| {
+            field(histogram).load(Ordering::Relaxed)
+        };
+
+        let counts: Vec<u64> = (0..BUCKETS)
+            .map(|index| {
+                histograms()
+                    .map(|histogram| histogram.buckets[index].load(Ordering::Relaxed))
+                    .sum()
+            })
+            .collect();
+        let count: u64 = histograms().map(|h| load(h, |h| &h.count)).sum();
+        let sum: u64 = histograms().map(|h| load(h, |h| &h.sum)).sum();
+
+        // The largest value of the bucket the percentile falls in.
+        let percentile = |percent: u64| {
+            let rank = (count * percent).div_ceil(100).max(1);
+            let mut seen = 0;
+            counts
+                .iter()
+                .position(|bucket_count| {
+                    seen += bucket_count;
+                    seen >= rank
+                })
+                .map_or(0, bucket_max)
+        };
+
+        HistogramSnapshot {
+            count,
+            mean_ns: sum.checked_div(count).unwrap_or(0),
+            max_ns: histograms().map(|h| load(h, |h| &h.max)).max().unwrap_or(0),
+            p50_ns: percentile(50),
+            p90_ns: percentile(90),
+            p99_ns: percentile(99),
+            buckets: counts
+                .iter()
+                .enumerate()
+                .filter(|(_, count)| **count > 0)
+                .map(|(index, count)| HistogramBucket {
+                    max_ns: bucket_max(index),
+                    count: *count,
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Interrupt latency of a device.
+pub(crate) struct IrqTrace {
+    name: String,
+    // Timestamp of the first signal the driver did not acknowledge yet, 0 without one.
+    pending: AtomicU64,
+    // From the signals to their acknowledgments.
+    latency: Histogram,
+    // From the worker wakeups to the event processing.
+    wakeup: Histogram,
+}
+
+impl IrqTrace {
+    pub fn new(name: &str) -> Self {
+        IrqTrace {
+            name: name.to_string(),
+            pending: AtomicU64::new(0),
+            latency: Histogram::default(),
+            wakeup: Histogram::default(),
+        }
+    }
+
+    /// The device signaled its irqfd.
+    pub fn signal(&self) {
+        if enabled() {
+            self.signal_at(now());
+        }
+    }
+
+    /// The driver wrote to InterruptACK.
+    pub fn ack(&self) {
+        if enabled() {
+            self.ack_at(now());
+        }
+    }
+
+    /// The worker starts processing an event it woke up for at `wakeup`, a timestamp from
+    /// [`now`], 0 when tracing is off.
+    pub fn processing(&self, wakeup: u64) {
+        if enabled() && wakeup != 0 {
+            self.wakeup.record(now().saturating_sub(wakeup));
+        }
+    }
+
+    // The signals coming before the acknowledgment share it: the latency is that of the
+    // first one.
+    fn signal_at(&self, timestamp: u64) {
+        let _ = self
+            .pending
+            .compare_exchange(0, timestamp, Ordering::Relaxed, Ordering::Relaxed);
+    }
+
+    // An acknowledgment without a signal, e.g. of a configuration change the trace missed,
+    // is not counted.
+    fn ack_at(&self, timestamp: u64) {
+        let signaled = self.pending.swap(0, Ordering::Relaxed);
+        if signaled != 0 {
+            self.latency.record(timestamp.saturating_sub(signaled));
+        }
+    }
+}
+
+/// The histograms of `traces`, and of all of them together.
+pub(crate) fn report(traces: &[&IrqTrace]) -> Vec<IrqLatency> {
+    let (first, others) = match traces.split_first() {
+        Some(split) => split,
+        None => return Vec::new(),
+    };
+
+    let latencies: Vec<_> = others.iter().map(|trace| &trace.latency).collect();
+    let wakeups: Vec<_> = others.iter().map(|trace| &trace.wakeup).collect();
+    let mut report = vec![IrqLatency {
+        device: "all".to_string(),
+        latency: first.latency.snapshot(&latencies),
+        wakeup: first.wakeup.snapshot(&wakeups),
+    }];
+    report.extend(traces.iter().map(|trace| IrqLatency {
+        device: trace.name.clone(),
+        latency: trace.latency.snapshot(&[]),
+        wakeup: trace.wakeup.snapshot(&[]),
+    }));
+
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    // A duration in nanoseconds, for the synthetic timestamps.
+    fn nanos(duration: Duration) -> u64 {
+        duration.as_nanos() as u64
+    }
+
+    #[test]
+    fn buckets() {
+        let mut previous = None;
+        for value in (0..4096).chain([u32::MAX.into(), u64::MAX - 1, u64::MAX]) {
+            let index = bucket(value);
+            assert!(index < BUCKETS);
+            assert!(value <= bucket_max(index), "{} in bucket {}", value, index);
+            if index > 0 {
+                assert!(value > bucket_max(index - 1), "{} in {}", value, index);
+            }
+            // Within 12.5% of the value.
+            assert!(bucket_max(index) - value <= value / 8);
+            if let Some(previous) = previous {
+                assert!(index >= previous);
+            }
+            previous = Some(index);
+        }
+        assert_eq!(bucket_max(BUCKETS - 1), u64::MAX);
+    }
+
+    #[test]
+    fn latency() {
+        let trace = IrqTrace::new("net");
+        let start = 1;
+
+        // The second signal shares the acknowledgment of the first.
+        trace.signal_at(start);
+        trace.signal_at(start + nanos(Duration::from_micros(5)));
+        trace.ack_at(start + nanos(Duration::from_micros(10)));
+        // Not signaled.
+        trace.ack_at(start + nanos(Duration::from_micros(20)));
+
+        let snapshot = trace.latency.snapshot(&[]);
+        assert_eq!(snapshot.count, 1);
+        assert_eq!(snapshot.max_ns, 10_000);
+    }
+
+    #[test]
+    fn percentiles() {
+        let trace = IrqTrace::new("net");
+        let start = 1;
+        for micros in 1..=100 {
+            trace.signal_at(start);
+            trace.ack_at(start + nanos(Duration::from_micros(micros)));
+        }
+
+        let snapshot = trace.latency.snapshot(&[]);
+        assert_eq!(snapshot.count, 100);
+        assert_eq!(snapshot.max_ns, 100_000);
+        assert_eq!(snapshot.mean_ns, 50_500);
+        // The percentiles are bucket bounds, within 12.5% of the values.
+        for (percentile, value) in [
+            (snapshot.p50_ns, 50_000),
+            (snapshot.p90_ns, 90_000),
+            (snapshot.p99_ns, 99_000),
+        ] {
+            assert!(percentile >= value && percentile - value <= value / 8);
+        }
+        let counted: u64 = snapshot.buckets.iter().map(|bucket| bucket.count).sum();
+        assert_eq!(counted, 100);
+    }
+
+    #[test]
+    fn report_all() {
+        let net = IrqTrace::new("net");
+        let pmem = IrqTrace::new("pmem");
+        net.latency.record(1000);
+        pmem.latency.record(3000);
+        net.wakeup.record(500);
+
+        let devices = report(&[&net, &pmem]);
+        let names: Vec<_> = devices.iter().map(|d| d.device.as_str()).collect();
+        assert_eq!(names, ["all", "net", "pmem"]);
+        assert_eq!(devices[0].latency.count, 2);
+        assert_eq!(devices[0].latency.mean_ns, 2000);
+        assert_eq!(devices[0].latency.max_ns, 3000);
+        assert_eq!(devices[0].wakeup.count, 1);
+        assert_eq!(devices[2].latency.count, 1);
+        assert!(report(&[]).is_empty());
+    }
+}
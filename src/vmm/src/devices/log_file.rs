@@ -0,0 +1,402 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Result, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::config::ConsoleFile;
+use crate::devices::clock::DateTime;
+use crate::resource_lock;
+use crate::Error;
+
+/// Guest output file, rotated when it reaches a size limit, with optionally timestamped
+/// lines.
+///
+/// A timestamp goes in front of the first byte of each line, when it is written: lines
+/// written in several parts get a single one. The file is rotated before a write that does
+/// not fit in it. Writes larger than a whole file are split, without cutting their UTF-8
+/// characters.
+pub(crate) struct LogFile {
+    config: ConsoleFile,
+    file: File,
+    // Bytes written to the current file.
+    size: u64,
+    // The next byte starts a line.
+    line_start: bool,
+    start: Instant,
+    // The file is locked for this instance, the new ones of the rotations too.
+    locked: bool,
+}
+
+impl LogFile {
+    /// Create the file, truncating any previous one. The rotated files are left alone until
+    /// the first rotation.
+    pub fn create(config: ConsoleFile) -> Result<Self> {
+        Ok(LogFile {
+            file: File::create(&config.path)?,
+            config,
+            size: 0,
+            line_start: true,
+            start: Instant::now(),
+            locked: false,
+        })
+    }
+
+    /// Create the file like [`LogFile::create`], locked for this instance first: the file
+    /// of another instance is left alone. A FIFO or a character device is not locked.
+    pub fn create_locked(config: ConsoleFile) -> crate::Result<Self> {
+        let console_error = |source| Error::ConsoleError {
+            path: config.path.clone(),
+            source,
+        };
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            // Not until locked.
+            .truncate(false)
+            .open(&config.path)
+            .map_err(console_error)?;
+        let locked = file.metadata().map_err(console_error)?.is_file();
+        if locked {
+            resource_lock::lock_console(&file, &config.path)?;
+            file.set_len(0).map_err(console_error)?;
+        }
+
+        Ok(LogFile {
+            file,
+            config,
+            size: 0,
+            line_start: true,
+            start: Instant::now(),
+            locked,
+        })
+    }
+
+    fn rotated_path(&self, index: u32) -> PathBuf {
+        let mut path = self.config.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        path.into()
+    }
+
+    // Shift the rotated files, the oldest one is overwritten, and start a new file.
+    fn rotate(&mut self) -> Result<()> {
+        for index in (1..self.config.rotate).rev() {
+            let from = self.rotated_path(index);
+            if Path::exists(&from) {
+                fs::rename(&from, self.rotated_path(index + 1))?;
+            }
+        }
+        if self.config.rotate > 0 {
+            fs::rename(&self.config.path, self.rotated_path(1))?;
+        }
+
+        self.file = File::create(&self.config.path)?;
+        self.size = 0;
+        // The lock went away with the previous file.
+        if self.locked && !resource_lock::try_lock(&self.file)? {
+            log::warn!(
+                "Another instance locked the console file {}",
+                self.config.path.display()
+            );
+        }
+
+        Ok(())
+    }
+
+    // Write to the file, rotating it on the way if needed.
+    fn write_rotated(&mut self, mut data: &[u8]) -> Result<()> {
+        let max_size = match self.config.max_size {
+            Some(max_size) => max_size,
+            None => return self.file.write_all(data),
+        };
+
+        while !data.is_empty() {
+            // Keep whatever fits in a new file in one piece.
+            if self.size > 0 && self.size + data.len() as u64 > max_size {
+                self.rotate()?;
+            }
+
+            let room = (max_size - self.size) as usize;
+            let mut len = data.len().min(room);
+            if len < data.len() {
+                // Do not cut a UTF-8 character in two: back off its continuation bytes.
+                let min_len = len.saturating_sub(3);
+                while len > min_len && data[len] & 0xc0 == 0x80 {
+                    len -= 1;
+                }
+                if len == 0 {
+                    len = room;
+                }
+            }
+
+            // On a full disk, what a short write got out still counts toward the size.
+            let mut chunk = &data[..len];
+            while !chunk.is_empty() {
+                match self.file.write(chunk) {
+                    Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                    Ok(written) => {
+                        self.size += written as u64;
+                        chunk = &chunk[written..];
+                    }
+                    Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            data = &data[len..];
+        }
+
+        Ok(())
+    }
+
+    fn timestamp(&self) -> String {
+        let uptime = self.start.elapsed();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+
+        format!(
+            "[{:>5}.{:06} {}.{:06}Z] ",
+            uptime.as_secs(),
+            uptime.subsec_micros(),
+            format_utc(now.as_secs()),
+            now.subsec_micros()
+        )
+    }
+}
+
+/// Format seconds since the Unix epoch as an ISO 8601 UTC date and time, to the second.
+fn format_utc(secs: u64) -> String {
+    let date = DateTime::from_unix(secs);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+        date.year, date.month, date.day, date.hour, date.minute, date.second
+    )
+}
+
+impl Write for LogFile {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        if !self.config.timestamps {
+            self.write_rotated(buf)?;
+            return Ok(buf.len());
+        }
+
+        // A newline byte is never part of a multi-byte UTF-8 character, splitting the lines
+        // there is safe.
+        for line in buf.split_inclusive(|byte| *byte == b'\n') {
+            let mut chunk = Vec::with_capacity(line.len() + 48);
+            if self.line_start {
+                chunk.extend_from_slice(self.timestamp().as_bytes());
+            }
+            chunk.extend_from_slice(line);
+            self.line_start = line.ends_with(b"\n");
+
+            self.write_rotated(&chunk)?;
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Drop for LogFile {
+    fn drop(&mut self) {
+        // The last lines matter most after a guest crash, get them to the disk. A FIFO or a
+        // character device cannot be synced.
+        if let Err(e) = self.file.sync_data() {
+            if e.raw_os_error() != Some(libc::EINVAL) {
+                log::warn!("Failed to sync {}: {}", self.config.path.display(), e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use vmm_sys_util::tempdir::TempDir;
+
+    fn config(dir: &TempDir, max_size: Option<u64>, rotate: u32, timestamps: bool) -> ConsoleFile {
+        ConsoleFile {
+            path: dir.as_path().join("console.log"),
+            max_size,
+            rotate,
+            timestamps,
+        }
+    }
+
+    // Check a `[uptime wallclock] ` stamp, and return what follows it.
+    fn strip_stamp(line: &str) -> &str {
+        let (stamp, rest) = line.split_once("] ").expect("missing timestamp");
+        let (uptime, wallclock) = stamp
+            .strip_prefix('[')
+            .unwrap()
+            .trim_start()
+            .split_once(' ')
+            .unwrap();
+
+        let (secs, micros) = uptime.split_once('.').unwrap();
+        secs.parse::<u64>().unwrap();
+        assert_eq!(micros.len(), 6);
+        micros.parse::<u32>().unwrap();
+
+        // 2026-10-17T10:00:00.123456Z
+        assert_eq!(wallclock.len(), 27);
+        assert!(wallclock.ends_with('Z'));
+        assert_eq!(&wallclock[4..5], "-");
+        assert_eq!(&wallclock[10..11], "T");
+        assert_eq!(&wallclock[19..20], ".");
+
+        rest
+    }
+
+    #[test]
+    fn utc() {
+        assert_eq!(format_utc(0), "1970-01-01T00:00:00");
+        assert_eq!(format_utc(951_782_400), "2000-02-29T00:00:00");
+        assert_eq!(format_utc(1_792_235_045), "2026-10-17T11:04:05");
+    }
+
+    #[test]
+    fn timestamps() {
+        let dir = TempDir::new().unwrap();
+        let config = config(&dir, None, 0, true);
+        let path = config.path.clone();
+        let mut file = LogFile::create(config).unwrap();
+
+        // Lines in several writes, and a character cut in two.
+        for part in [
+            &b"hel"[..],
+            b"lo\nwor",
+            b"ld \xc3",
+            b"\xa9t\xc3\xa9\n",
+            b"\n",
+            b"end",
+        ] {
+            file.write_all(part).unwrap();
+        }
+
+        let content = String::from_utf8(fs::read(path).unwrap()).unwrap();
+        let lines: Vec<_> = content.split_inclusive('\n').map(strip_stamp).collect();
+        assert_eq!(lines, ["hello\n", "world été\n", "\n", "end"]);
+    }
+
+    #[test]
+    fn rotation() {
+        const MAX_SIZE: u64 = 256 * 1024;
+        const ROTATE: u32 = 3;
+        let dir = TempDir::new().unwrap();
+        let config = config(&dir, Some(MAX_SIZE), ROTATE, true);
+        let mut file = LogFile::create(config.clone()).unwrap();
+
+        // About 4 MiB of numbered lines, in writes of all sizes.
+        let mut output = Vec::new();
+        let mut line = 0;
+        while output.len() < 4 << 20 {
+            output.extend_from_slice(
+                format!("line {} héhé {}\n", line, "x".repeat(line % 300)).as_bytes(),
+            );
+            line += 1;
+        }
+        let mut written = 0;
+        let mut size = 1;
+        while written < output.len() {
+            let end = (written + size).min(output.len());
+            file.write_all(&output[written..end]).unwrap();
+            written = end;
+            size = size * 7 % 5000 + 1;
+        }
+        file.flush().unwrap();
+        drop(file);
+
+        let rotated = |index| {
+            let mut path = config.path.clone().into_os_string();
+            path.push(format!(".{}", index));
+            PathBuf::from(path)
+        };
+        assert!(!rotated(ROTATE + 1).exists());
+
+        // The oldest file first.
+        let mut files: Vec<_> = (1..=ROTATE).rev().map(rotated).collect();
+        files.push(config.path.clone());
+
+        let mut content = Vec::new();
+        for (index, path) in files.iter().enumerate() {
+            let file = fs::read(path).unwrap();
+            assert!(file.len() as u64 <= MAX_SIZE);
+            // Files are only rotated when the next write does not fit.
+            if index < files.len() - 1 {
+                assert!(file.len() as u64 > MAX_SIZE - 8192);
+            }
+            content.extend_from_slice(&file);
+        }
+
+        // The files hold the end of the output, each line once, with its timestamp.
+        let content = String::from_utf8(content).unwrap();
+        let mut lines = content.split_inclusive('\n').peekable();
+        // The oldest file may start in the middle of a line.
+        lines.next_if(|line| !line.starts_with('['));
+
+        let mut next = None;
+        for line in lines {
+            let line = strip_stamp(line);
+            let number: usize = line.split(' ').nth(1).unwrap().parse().unwrap();
+            if let Some(next) = next {
+                assert_eq!(number, next);
+            }
+            assert_eq!(
+                line,
+                format!("line {} héhé {}\n", number, "x".repeat(number % 300))
+            );
+            next = Some(number + 1);
+        }
+        assert_eq!(next, Some(line));
+    }
+
+    #[test]
+    fn locked() {
+        let dir = TempDir::new().unwrap();
+        let config = config(&dir, Some(16), 1, false);
+        fs::write(&config.path, "previous output\n").unwrap();
+        let mut file = LogFile::create_locked(config.clone()).unwrap();
+        assert!(fs::read(&config.path).unwrap().is_empty());
+
+        // Another instance leaves the file alone, before and after a rotation.
+        for _ in 0..2 {
+            file.write_all(b"0123456789abcdef").unwrap();
+            assert!(matches!(
+                LogFile::create_locked(config.clone()),
+                Err(Error::ResourceBusy { .. })
+            ));
+            assert_eq!(fs::read(&config.path).unwrap(), b"0123456789abcdef");
+        }
+        drop(file);
+        LogFile::create_locked(config).unwrap();
+
+        // Nothing to lock on a character device.
+        let null = ConsoleFile::from(PathBuf::from("/dev/null"));
+        let _null = LogFile::create_locked(null.clone()).unwrap();
+        LogFile::create_locked(null).unwrap();
+    }
+
+    #[test]
+    fn long_lines() {
+        let dir = TempDir::new().unwrap();
+        let config = config(&dir, Some(15), 1, false);
+        let mut file = LogFile::create(config.clone()).unwrap();
+
+        // A line longer than a file is spread over several, characters are kept whole.
+        file.write_all("ééééééééééé\n".as_bytes()).unwrap();
+
+        let mut rotated = config.path.clone().into_os_string();
+        rotated.push(".1");
+        let rotated = String::from_utf8(fs::read(rotated).unwrap()).unwrap();
+        let current = String::from_utf8(fs::read(&config.path).unwrap()).unwrap();
+        assert_eq!(rotated, "ééééééé");
+        assert_eq!(current, "éééé\n");
+    }
+}
@@ -1,4 +1,30 @@
 // SPDX-License-Identifier: Apache-2.0
 
+#[cfg(target_arch = "x86_64")]
+pub(crate) mod acpi_pm;
+pub(crate) mod async_writer;
+pub(crate) mod clock;
+pub(crate) mod console_input;
+pub(crate) mod console_scanner;
+pub(crate) mod console_session;
+#[cfg(target_arch = "x86_64")]
+pub(crate) mod cpu_hotplug;
+pub(crate) mod hand_back;
+#[cfg(target_arch = "x86_64")]
+pub(crate) mod i8042;
+#[cfg(target_arch = "x86_64")]
+pub(crate) mod ioapic;
+pub(crate) mod irq_trace;
+pub(crate) mod log_file;
 pub(crate) mod net;
+pub(crate) mod p9;
+pub(crate) mod pio;
+pub(crate) mod pmem;
+#[cfg(target_arch = "x86_64")]
+pub(crate) mod pvpanic;
+#[cfg(target_arch = "x86_64")]
+pub(crate) mod rtc;
 pub(crate) mod serial;
+pub(crate) mod tee;
+pub(crate) mod virtio;
+pub(crate) mod watchdog;
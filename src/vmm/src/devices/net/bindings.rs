@@ -23,6 +23,8 @@ pub const TUN_F_UFO: ::std::os::raw::c_uint = 16;
 
 pub const VIRTIO_F_VERSION_1: u64 = 32;
 pub const VIRTIO_HDR_LEN: usize = ::core::mem::size_of::<virtio_net_hdr_v1>();
+pub const VIRTIO_HDR_NUM_BUFFERS_OFFSET: usize =
+    ::core::mem::offset_of!(virtio_net_hdr_v1, num_buffers);
 pub const VIRTIO_NET_DEVICE_ID: u32 = 1;
 
 #[repr(C)]
@@ -0,0 +1,413 @@
+// SPDX-License-Identifier: Apache-2.0
+
+// Attaching a tap to a host bridge, over rtnetlink, as `ip link set <tap> master <bridge>
+// up` does. The bridge is created when asked to, and deleted again once the tap leaves it:
+// only what the VMM created goes away.
+
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+
+use crate::config::BridgeConfig;
+use crate::{Error, Result};
+
+// As defined in the Linux UAPI:
+// https://elixir.bootlin.com/linux/v6.1/source/include/uapi/linux/netlink.h
+// https://elixir.bootlin.com/linux/v6.1/source/include/uapi/linux/rtnetlink.h
+// https://elixir.bootlin.com/linux/v6.1/source/include/uapi/linux/if_link.h
+const NLMSG_ERROR: u16 = 2;
+const NLM_F_REQUEST: u16 = 0x1;
+const NLM_F_ACK: u16 = 0x4;
+const NLM_F_EXCL: u16 = 0x200;
+const NLM_F_CREATE: u16 = 0x400;
+const RTM_NEWLINK: u16 = 16;
+const RTM_DELLINK: u16 = 17;
+const IFLA_IFNAME: u16 = 3;
+const IFLA_MASTER: u16 = 10;
+const IFLA_LINKINFO: u16 = 18;
+const IFLA_INFO_KIND: u16 = 1;
+const IFF_UP: u32 = 0x1;
+
+// Sizes of struct nlmsghdr and struct ifinfomsg.
+const NLMSG_HDR_LEN: usize = 16;
+const IFINFOMSG_LEN: usize = 16;
+// Largest answer read, an error carrying the request back.
+const RECV_SIZE: usize = 4096;
+
+// Bit of CAP_NET_ADMIN in the capability sets.
+const CAP_NET_ADMIN: u32 = 12;
+
+/// A tap attached to its bridge. The bridge is deleted when dropped, if the VMM created it.
+pub(crate) struct TapBridge {
+    name: String,
+    // The index of the bridge the VMM created.
+    created: Option<u32>,
+}
+
+impl TapBridge {
+    /// Fail without CAP_NET_ADMIN, e.g. before opening the tap, rather than once attaching
+    /// it. When the capabilities are unknown, attaching tells.
+    pub fn check_permission(bridge: &BridgeConfig) -> Result<()> {
+        let status = fs::read_to_string("/proc/self/status").unwrap_or_default();
+        match has_capability(&status, CAP_NET_ADMIN) {
+            Some(false) => Err(Error::BridgePermission(bridge.name.clone())),
+            _ => Ok(()),
+        }
+    }
+
+    /// Attach the tap `if_name` to its `bridge`, and bring both up.
+    pub fn attach(if_name: &str, bridge: &BridgeConfig) -> Result<Self> {
+        let error = |source: io::Error| match source.raw_os_error() {
+            Some(libc::EPERM | libc::EACCES) => Error::BridgePermission(bridge.name.clone()),
+            _ => Error::Bridge {
+                if_name: if_name.to_string(),
+                bridge: bridge.name.clone(),
+                source,
+            },
+        };
+        let mut netlink = Netlink::open().map_err(error)?;
+
+        let mut attached = TapBridge {
+            name: bridge.name.clone(),
+            created: None,
+        };
+        let bridge_index = match if_index(&bridge.name) {
+            Ok(index) => index,
+            Err(e) if e.raw_os_error() == Some(libc::ENODEV) && bridge.create => {
+                netlink
+                    .request(|seq| new_bridge(seq, &bridge.name))
+                    .map_err(error)?;
+                let index = if_index(&bridge.name).map_err(error)?;
+                // Deleted again on the way out from now on.
+                attached.created = Some(index);
+                log::info!("Created bridge {}", bridge.name);
+                netlink
+                    .request(|seq| set_link(seq, index, None))
+                    .map_err(error)?;
+                index
+            }
+            Err(e) if e.raw_os_error() == Some(libc::ENODEV) => {
+                return Err(Error::BridgeMissing(bridge.name.clone()))
+            }
+            Err(e) => return Err(error(e)),
+        };
+
+        let tap_index = if_index(if_name).map_err(error)?;
+        netlink
+            .request(|seq| set_link(seq, tap_index, Some(bridge_index)))
+            .map_err(error)?;
+        log::info!("Attached tap {} to bridge {}", if_name, bridge.name);
+
+        Ok(attached)
+    }
+}
+
+impl Drop for TapBridge {
+    fn drop(&mut self) {
+        let index = match self.created {
+            Some(index) => index,
+            None => return,
+        };
+        let deleted =
+            Netlink::open().and_then(|mut netlink| netlink.request(|seq| del_link(seq, index)));
+        match deleted {
+            Ok(()) => log::info!("Deleted bridge {}", self.name),
+            Err(e) => log::warn!("Failed to delete bridge {}: {}", self.name, e),
+        }
+    }
+}
+
+// Whether the effective set in the /proc/<pid>/status `status` has `cap`.
+fn has_capability(status: &str, cap: u32) -> Option<bool> {
+    let effective = status
+        .lines()
+        .find_map(|line| line.strip_prefix("CapEff:"))?;
+    let effective = u64::from_str_radix(effective.trim(), 16).ok()?;
+    Some(effective & (1 << cap) != 0)
+}
+
+fn if_index(if_name: &str) -> io::Result<u32> {
+    let name = CString::new(if_name).map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))?;
+    // Safe because the name is a valid C string, and we check the result.
+    match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+        0 => Err(io::Error::last_os_error()),
+        index => Ok(index),
+    }
+}
+
+// A route netlink socket, sending requests to the kernel.
+struct Netlink {
+    socket: OwnedFd,
+    seq: u32,
+}
+
+impl Netlink {
+    fn open() -> io::Result<Self> {
+        // Safe because we check the result.
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                libc::NETLINK_ROUTE,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Netlink {
+            // Safe because we own the new descriptor.
+            socket: unsafe { OwnedFd::from_raw_fd(fd) },
+            seq: 0,
+        })
+    }
+
+    // Send the request `build` makes with the next sequence number, and wait for its
+    // acknowledgement.
+    fn request(&mut self, build: impl FnOnce(u32) -> Vec<u8>) -> io::Result<()> {
+        self.seq += 1;
+        let request = build(self.seq);
+        // Safe because the buffer is valid for its length, and we check the result.
+        let sent = unsafe {
+            libc::send(
+                self.socket.as_raw_fd(),
+                request.as_ptr() as *const libc::c_void,
+                request.len(),
+                0,
+            )
+        };
+        if sent < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut answer = vec![0u8; RECV_SIZE];
+        loop {
+            // Safe because the buffer is valid for its length, and we check the result.
+            let len = unsafe {
+                libc::recv(
+                    self.socket.as_raw_fd(),
+                    answer.as_mut_ptr() as *mut libc::c_void,
+                    answer.len(),
+                    0,
+                )
+            };
+            if len < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            if let Some(result) = parse_ack(&answer[..len as usize], self.seq) {
+                return result;
+            }
+        }
+    }
+}
+
+// A netlink message, built in place.
+struct Message(Vec<u8>);
+
+impl Message {
+    // A request of `kind`, with the netlink `flags` besides the request and ack ones, about
+    // the link `index`: its `link_flags` within the `change` mask.
+    fn link(kind: u16, flags: u16, seq: u32, index: u32, link_flags: u32, change: u32) -> Self {
+        let mut buf = Vec::with_capacity(NLMSG_HDR_LEN + IFINFOMSG_LEN);
+        // The length goes in once complete, the port is the kernel.
+        buf.extend_from_slice(&0u32.to_ne_bytes());
+        buf.extend_from_slice(&kind.to_ne_bytes());
+        buf.extend_from_slice(&(NLM_F_REQUEST | NLM_F_ACK | flags).to_ne_bytes());
+        buf.extend_from_slice(&seq.to_ne_bytes());
+        buf.extend_from_slice(&0u32.to_ne_bytes());
+        // AF_UNSPEC, padding and any device type.
+        buf.extend_from_slice(&[0; 4]);
+        buf.extend_from_slice(&index.to_ne_bytes());
+        buf.extend_from_slice(&link_flags.to_ne_bytes());
+        buf.extend_from_slice(&change.to_ne_bytes());
+        Message(buf)
+    }
+
+    fn attr(mut self, kind: u16, data: &[u8]) -> Self {
+        self.0.extend_from_slice(&attr(kind, data));
+        self
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        let len = self.0.len() as u32;
+        self.0[..4].copy_from_slice(&len.to_ne_bytes());
+        self.0
+    }
+}
+
+// A route attribute, padded to 4 bytes.
+fn attr(kind: u16, data: &[u8]) -> Vec<u8> {
+    let len = 4 + data.len();
+    let mut attr = Vec::with_capacity(len.next_multiple_of(4));
+    attr.extend_from_slice(&(len as u16).to_ne_bytes());
+    attr.extend_from_slice(&kind.to_ne_bytes());
+    attr.extend_from_slice(data);
+    attr.resize(len.next_multiple_of(4), 0);
+    attr
+}
+
+// Bring the link `index` up, attaching it to `master` if set.
+fn set_link(seq: u32, index: u32, master: Option<u32>) -> Vec<u8> {
+    let message = Message::link(RTM_NEWLINK, 0, seq, index, IFF_UP, IFF_UP);
+    match master {
+        Some(master) => message.attr(IFLA_MASTER, &master.to_ne_bytes()).finish(),
+        None => message.finish(),
+    }
+}
+
+// Create the bridge `name`, failing if it exists.
+fn new_bridge(seq: u32, name: &str) -> Vec<u8> {
+    let mut if_name = name.as_bytes().to_vec();
+    if_name.push(0);
+    Message::link(RTM_NEWLINK, NLM_F_CREATE | NLM_F_EXCL, seq, 0, 0, 0)
+        .attr(IFLA_IFNAME, &if_name)
+        .attr(IFLA_LINKINFO, &attr(IFLA_INFO_KIND, b"bridge"))
+        .finish()
+}
+
+// Delete the link `index`.
+fn del_link(seq: u32, index: u32) -> Vec<u8> {
+    Message::link(RTM_DELLINK, 0, seq, index, 0, 0).finish()
+}
+
+// The result the acknowledgement of the request `seq` in `answer` carries, if any.
+fn parse_ack(answer: &[u8], seq: u32) -> Option<io::Result<()>> {
+    let mut messages = answer;
+    while messages.len() >= NLMSG_HDR_LEN {
+        let u32_at =
+            |offset: usize| u32::from_ne_bytes(messages[offset..offset + 4].try_into().unwrap());
+        let len = u32_at(0) as usize;
+        let kind = u16::from_ne_bytes([messages[4], messages[5]]);
+        if len < NLMSG_HDR_LEN || len > messages.len() {
+            return Some(Err(io::Error::from_raw_os_error(libc::EBADMSG)));
+        }
+        if kind == NLMSG_ERROR && u32_at(8) == seq && len >= NLMSG_HDR_LEN + 4 {
+            let error = u32_at(NLMSG_HDR_LEN) as i32;
+            return Some(match error {
+                0 => Ok(()),
+                error => Err(io::Error::from_raw_os_error(-error)),
+            });
+        }
+        messages = &messages[len.next_multiple_of(4).min(messages.len())..];
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::Path;
+
+    use crate::devices::net::tap::Tap;
+
+    #[test]
+    fn messages() {
+        #[rustfmt::skip]
+        assert_eq!(
+            set_link(1, 5, Some(3)),
+            [
+                // Length, RTM_NEWLINK, NLM_F_REQUEST | NLM_F_ACK, sequence, port.
+                40, 0, 0, 0, 16, 0, 5, 0, 1, 0, 0, 0, 0, 0, 0, 0,
+                // AF_UNSPEC, index, IFF_UP flag and change.
+                0, 0, 0, 0, 5, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0,
+                // IFLA_MASTER.
+                8, 0, 10, 0, 3, 0, 0, 0,
+            ]
+        );
+        #[rustfmt::skip]
+        assert_eq!(
+            set_link(2, 3, None),
+            [
+                32, 0, 0, 0, 16, 0, 5, 0, 2, 0, 0, 0, 0, 0, 0, 0,
+                0, 0, 0, 0, 3, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0,
+            ]
+        );
+        #[rustfmt::skip]
+        assert_eq!(
+            new_bridge(3, "br0"),
+            [
+                // And NLM_F_CREATE | NLM_F_EXCL.
+                56, 0, 0, 0, 16, 0, 5, 6, 3, 0, 0, 0, 0, 0, 0, 0,
+                0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+                // IFLA_IFNAME.
+                8, 0, 3, 0, b'b', b'r', b'0', 0,
+                // IFLA_LINKINFO, with its padded IFLA_INFO_KIND.
+                16, 0, 18, 0,
+                10, 0, 1, 0, b'b', b'r', b'i', b'd', b'g', b'e', 0, 0,
+            ]
+        );
+        #[rustfmt::skip]
+        assert_eq!(
+            del_link(4, 7),
+            [
+                // RTM_DELLINK.
+                32, 0, 0, 0, 17, 0, 5, 0, 4, 0, 0, 0, 0, 0, 0, 0,
+                0, 0, 0, 0, 7, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+            ]
+        );
+    }
+
+    #[test]
+    fn acks() {
+        // An NLMSG_ERROR answer to `seq`, carrying the request header back.
+        let ack = |seq: u32, error: i32| {
+            let mut ack = Vec::new();
+            ack.extend_from_slice(&36u32.to_ne_bytes());
+            ack.extend_from_slice(&NLMSG_ERROR.to_ne_bytes());
+            ack.extend_from_slice(&0u16.to_ne_bytes());
+            ack.extend_from_slice(&seq.to_ne_bytes());
+            ack.extend_from_slice(&0u32.to_ne_bytes());
+            ack.extend_from_slice(&error.to_ne_bytes());
+            ack.extend_from_slice(&del_link(seq, 7)[..NLMSG_HDR_LEN]);
+            ack
+        };
+
+        assert!(matches!(parse_ack(&ack(1, 0), 1), Some(Ok(()))));
+        let error = parse_ack(&ack(2, -libc::EPERM), 2).unwrap().unwrap_err();
+        assert_eq!(error.raw_os_error(), Some(libc::EPERM));
+        // The answer to another request is skipped.
+        assert!(parse_ack(&ack(1, 0), 2).is_none());
+        let answers = [ack(1, -libc::ENODEV), ack(2, 0)].concat();
+        assert!(matches!(parse_ack(&answers, 2), Some(Ok(()))));
+        // So are the truncated ones.
+        assert!(parse_ack(&ack(1, 0)[..8], 1).is_none());
+        assert!(matches!(parse_ack(&ack(1, 0)[..20], 1), Some(Err(_))));
+    }
+
+    #[test]
+    fn capabilities() {
+        let status = "Name:\tlumper\nCapPrm:\t0000000000001000\nCapEff:\t0000000000001000\n";
+        assert_eq!(has_capability(status, CAP_NET_ADMIN), Some(true));
+        assert_eq!(has_capability(status, 21), Some(false));
+        assert_eq!(has_capability("Name:\tlumper\n", CAP_NET_ADMIN), None);
+    }
+
+    #[test]
+    #[ignore = "needs root"]
+    fn attach() {
+        let bridge = BridgeConfig {
+            name: "lumper-br0".to_string(),
+            create: false,
+        };
+        let _tap = Tap::open_named("lumper-brtap0", None).unwrap();
+        assert!(matches!(
+            TapBridge::attach("lumper-brtap0", &bridge),
+            Err(Error::BridgeMissing(_))
+        ));
+
+        let bridge = BridgeConfig {
+            create: true,
+            ..bridge
+        };
+        let attached = TapBridge::attach("lumper-brtap0", &bridge).unwrap();
+        let master = fs::read_link("/sys/class/net/lumper-brtap0/master").unwrap();
+        assert!(master.ends_with("lumper-br0"));
+
+        // Only what was created goes away.
+        drop(attached);
+        assert!(!Path::new("/sys/class/net/lumper-br0").exists());
+        assert!(Path::new("/sys/class/net/lumper-brtap0").exists());
+    }
+}
@@ -0,0 +1,191 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Software completion of the offloads the driver asks for in the virtio header of the frames
+//! it sends, for the interfaces which did not take them.
+
+use virtio_bindings::bindings::virtio_net::{VIRTIO_NET_HDR_F_NEEDS_CSUM, VIRTIO_NET_HDR_GSO_NONE};
+
+use super::bindings::VIRTIO_HDR_LEN;
+
+// Offsets of the fields of the virtio header.
+const HDR_FLAGS: usize = 0;
+const HDR_GSO_TYPE: usize = 1;
+const HDR_CSUM_START: usize = 6;
+const HDR_CSUM_OFFSET: usize = 8;
+
+/// Complete the checksum the virtio header of `frame` asks for, if any, and clear the
+/// request from the header. The frame then needs no offload.
+///
+/// Returns false when the frame needs segmentation, which is not done here, or when its
+/// header points out of it: the frame cannot be sent as it is.
+pub(crate) fn complete(frame: &mut [u8]) -> bool {
+    if frame.len() < VIRTIO_HDR_LEN || frame[HDR_GSO_TYPE] != VIRTIO_NET_HDR_GSO_NONE as u8 {
+        return false;
+    }
+    if frame[HDR_FLAGS] & VIRTIO_NET_HDR_F_NEEDS_CSUM as u8 == 0 {
+        return true;
+    }
+
+    let field = |offset: usize| usize::from(u16::from_le_bytes([frame[offset], frame[offset + 1]]));
+    let start = VIRTIO_HDR_LEN + field(HDR_CSUM_START);
+    let offset = start + field(HDR_CSUM_OFFSET);
+    if offset + 2 > frame.len() {
+        return false;
+    }
+
+    // The driver left the checksum of the pseudo header in the field, which the sum covers,
+    // so this works the same for TCP and UDP, over IPv4 and IPv6. A zero checksum means
+    // none in UDP, it goes out as its other form.
+    let checksum = match !sum(&frame[start..]) {
+        0 => 0xffff,
+        checksum => checksum,
+    };
+    frame[offset..offset + 2].copy_from_slice(&checksum.to_be_bytes());
+    frame[HDR_FLAGS] &= !(VIRTIO_NET_HDR_F_NEEDS_CSUM as u8);
+
+    true
+}
+
+// The folded ones' complement sum of `data`, as 16-bit big-endian words.
+fn sum(data: &[u8]) -> u16 {
+    let mut chunks = data.chunks_exact(2);
+    let mut sum: u64 = chunks
+        .by_ref()
+        .map(|word| u64::from(u16::from_be_bytes([word[0], word[1]])))
+        .sum();
+    if let [last] = chunks.remainder() {
+        sum += u64::from(*last) << 8;
+    }
+
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use virtio_bindings::bindings::virtio_net::VIRTIO_NET_HDR_GSO_TCPV4;
+
+    const TCP: u8 = 6;
+    const UDP: u8 = 17;
+    // Where the checksum is, in the TCP and UDP headers.
+    const TCP_CSUM_OFFSET: usize = 16;
+    const UDP_CSUM_OFFSET: usize = 6;
+
+    const SRC_V4: [u8; 4] = [10, 0, 0, 2];
+    const DST_V4: [u8; 4] = [10, 0, 0, 1];
+    const SRC_V6: [u8; 16] = [0xfd, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2];
+    const DST_V6: [u8; 16] = [0xfd, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+
+    // The pseudo header of a segment, as the checksums cover it.
+    fn pseudo_header(src: &[u8], dst: &[u8], protocol: u8, len: usize) -> Vec<u8> {
+        let mut header = [src, dst].concat();
+        if src.len() == 4 {
+            header.extend_from_slice(&[0, protocol]);
+            header.extend_from_slice(&(len as u16).to_be_bytes());
+        } else {
+            header.extend_from_slice(&(len as u32).to_be_bytes());
+            header.extend_from_slice(&[0, 0, 0, protocol]);
+        }
+        header
+    }
+
+    // A TCP or UDP segment, with an odd length payload.
+    fn segment(protocol: u8) -> Vec<u8> {
+        let mut segment = match protocol {
+            TCP => {
+                let mut header = vec![0u8; 20];
+                header[..4].copy_from_slice(&[0x30, 0x39, 0x00, 0x50]);
+                header[12] = 5 << 4;
+                header[13] = 0x18;
+                header
+            }
+            _ => vec![0x30, 0x39, 0x00, 0x35, 0, 0, 0, 0],
+        };
+        segment.extend((0..101).map(|i| (i * 7) as u8));
+        if protocol == UDP {
+            let len = (segment.len() as u16).to_be_bytes();
+            segment[4..6].copy_from_slice(&len);
+        }
+        segment
+    }
+
+    // A frame with a partial checksum, as the driver sends it with VIRTIO_NET_F_CSUM.
+    fn partial_frame(src: &[u8], dst: &[u8], protocol: u8) -> Vec<u8> {
+        let (ethertype, ip_header) = if src.len() == 4 {
+            ([0x08, 0x00], vec![0x45; 20])
+        } else {
+            ([0x86, 0xdd], vec![0x60; 40])
+        };
+        let segment = segment(protocol);
+        let csum_start = 14 + ip_header.len();
+        let csum_offset = match protocol {
+            TCP => TCP_CSUM_OFFSET,
+            _ => UDP_CSUM_OFFSET,
+        };
+
+        let mut frame = vec![0u8; VIRTIO_HDR_LEN];
+        frame[HDR_FLAGS] = VIRTIO_NET_HDR_F_NEEDS_CSUM as u8;
+        frame[HDR_CSUM_START..HDR_CSUM_START + 2]
+            .copy_from_slice(&(csum_start as u16).to_le_bytes());
+        frame[HDR_CSUM_OFFSET..HDR_CSUM_OFFSET + 2]
+            .copy_from_slice(&(csum_offset as u16).to_le_bytes());
+        frame.extend_from_slice(&[0xff; 12]);
+        frame.extend_from_slice(&ethertype);
+        frame.extend_from_slice(&ip_header);
+        frame.extend_from_slice(&segment);
+
+        // The driver only sums the pseudo header, uncomplemented.
+        let pseudo = sum(&pseudo_header(src, dst, protocol, segment.len()));
+        let field = VIRTIO_HDR_LEN + csum_start + csum_offset;
+        frame[field..field + 2].copy_from_slice(&pseudo.to_be_bytes());
+        frame
+    }
+
+    // Whether the segment at the end of the frame has a valid checksum.
+    fn valid(frame: &[u8], src: &[u8], dst: &[u8], protocol: u8) -> bool {
+        let start = VIRTIO_HDR_LEN + 14 + if src.len() == 4 { 20 } else { 40 };
+        let segment = &frame[start..];
+        let mut data = pseudo_header(src, dst, protocol, segment.len());
+        data.extend_from_slice(segment);
+        sum(&data) == 0xffff
+    }
+
+    #[test]
+    fn checksums() {
+        for (src, dst) in [(&SRC_V4[..], &DST_V4[..]), (&SRC_V6[..], &DST_V6[..])] {
+            for protocol in [TCP, UDP] {
+                let mut frame = partial_frame(src, dst, protocol);
+                assert!(!valid(&frame, src, dst, protocol));
+
+                assert!(complete(&mut frame));
+                assert!(valid(&frame, src, dst, protocol));
+                assert_eq!(frame[HDR_FLAGS], 0);
+
+                // Once complete, the frame stays as it is.
+                let completed = frame.clone();
+                assert!(complete(&mut frame));
+                assert_eq!(frame, completed);
+            }
+        }
+    }
+
+    #[test]
+    fn unsendable() {
+        // Segmentation is left to the offloads.
+        let mut frame = partial_frame(&SRC_V4, &DST_V4, TCP);
+        frame[HDR_GSO_TYPE] = VIRTIO_NET_HDR_GSO_TCPV4 as u8;
+        assert!(!complete(&mut frame));
+
+        // As are checksums out of the frame.
+        let mut frame = partial_frame(&SRC_V4, &DST_V4, TCP);
+        let len = frame.len() as u16;
+        frame[HDR_CSUM_START..HDR_CSUM_START + 2].copy_from_slice(&len.to_le_bytes());
+        assert!(!complete(&mut frame));
+
+        assert!(!complete(&mut [0u8; 4]));
+    }
+}
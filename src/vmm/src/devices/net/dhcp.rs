@@ -0,0 +1,396 @@
+// SPDX-License-Identifier: Apache-2.0
+
+// DHCP server handing the guest its address, in the user backend and on the host side of a
+// tap. Each guest MAC address gets its own address from the range, and keeps it for as long
+// as the VMM runs: there is no lease expiry, nor persistence.
+
+use std::collections::HashMap;
+use std::io;
+use std::mem;
+use std::net::{Ipv4Addr, SocketAddrV4, UdpSocket};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+
+use smoltcp::wire::{
+    DhcpMessageType, DhcpOption, DhcpPacket, DhcpRepr, EthernetAddress, Ipv4Address,
+    DHCP_CLIENT_PORT, DHCP_SERVER_PORT,
+};
+
+use super::user::host_nameserver;
+use crate::config::DhcpServerConfig;
+use crate::epoll_context::{EventHandler, EventOps, Events};
+use crate::Result;
+
+const LEASE_SECS: u32 = 24 * 3600;
+const OPT_DOMAIN_NAME_SERVER: u8 = 6;
+// Largest message taken from the guest, which sends them in a single frame.
+const MAX_MESSAGE_SIZE: usize = 1500;
+
+/// Answers the DHCP discoveries and requests of the guests.
+pub(crate) struct DhcpServer {
+    config: DhcpServerConfig,
+    leases: HashMap<EthernetAddress, Ipv4Addr>,
+}
+
+impl DhcpServer {
+    pub fn new(config: DhcpServerConfig) -> Self {
+        DhcpServer {
+            config,
+            leases: HashMap::new(),
+        }
+    }
+
+    /// The reply to a client message, and the hardware address of the client. Returns None
+    /// for the messages we do not answer.
+    pub fn reply(&mut self, message: &[u8]) -> Option<(EthernetAddress, Vec<u8>)> {
+        let packet = DhcpPacket::new_checked(message).ok()?;
+        let request = DhcpRepr::parse(&packet).ok()?;
+        let mac = request.client_hardware_address;
+        let server = Ipv4Address::from(self.config.address);
+
+        let mut message_type = match request.message_type {
+            DhcpMessageType::Discover => DhcpMessageType::Offer,
+            // The client picked the offer of another server.
+            DhcpMessageType::Request
+                if request
+                    .server_identifier
+                    .is_some_and(|identifier| identifier != server) =>
+            {
+                return None
+            }
+            DhcpMessageType::Request => DhcpMessageType::Ack,
+            _ => return None,
+        };
+        let address = match self.lease(mac) {
+            Some(address) => address,
+            None => {
+                log::warn!("No DHCP address left for {}", mac);
+                return None;
+            }
+        };
+
+        // A client asking for another address, from an earlier run for example, is told to
+        // start over.
+        let wanted = match request.requested_ip {
+            Some(wanted) => Some(wanted),
+            None => (!request.client_ip.is_unspecified()).then_some(request.client_ip),
+        };
+        if message_type == DhcpMessageType::Ack
+            && wanted.is_some_and(|wanted| wanted != Ipv4Address::from(address))
+        {
+            message_type = DhcpMessageType::Nak;
+        }
+        let nak = message_type == DhcpMessageType::Nak;
+
+        let dns = self.config.dns.map(|dns| dns.octets());
+        let dns_option = dns.as_ref().map(|dns| DhcpOption {
+            kind: OPT_DOMAIN_NAME_SERVER,
+            data: dns,
+        });
+        let reply = DhcpRepr {
+            message_type,
+            transaction_id: request.transaction_id,
+            secs: 0,
+            client_hardware_address: mac,
+            client_ip: Ipv4Address::UNSPECIFIED,
+            your_ip: if nak {
+                Ipv4Address::UNSPECIFIED
+            } else {
+                address.into()
+            },
+            server_ip: server,
+            router: (!nak).then_some(server),
+            subnet_mask: (!nak).then(|| self.config.netmask().into()),
+            relay_agent_ip: Ipv4Address::UNSPECIFIED,
+            broadcast: false,
+            requested_ip: None,
+            client_identifier: None,
+            server_identifier: Some(server),
+            parameter_request_list: None,
+            dns_servers: None,
+            max_size: None,
+            lease_duration: (!nak).then_some(LEASE_SECS),
+            renew_duration: None,
+            rebind_duration: None,
+            additional_options: if nak { &[] } else { dns_option.as_slice() },
+        };
+
+        let mut buffer = vec![0; reply.buffer_len()];
+        reply
+            .emit(&mut DhcpPacket::new_unchecked(&mut buffer[..]))
+            .ok()?;
+
+        Some((mac, buffer))
+    }
+
+    // The address of the client, the first free one of the range for a new client.
+    fn lease(&mut self, mac: EthernetAddress) -> Option<Ipv4Addr> {
+        if let Some(address) = self.leases.get(&mac) {
+            return Some(*address);
+        }
+
+        let (first, last) = self.config.range;
+        let address = (u32::from(first)..=u32::from(last))
+            .map(Ipv4Addr::from)
+            .find(|address| !self.leases.values().any(|leased| leased == address))?;
+        self.leases.insert(mac, address);
+
+        Some(address)
+    }
+}
+
+/// DHCP server on the host side of a tap, polled by the VMM event loop.
+pub(crate) struct TapDhcpServer {
+    socket: UdpSocket,
+    server: DhcpServer,
+}
+
+impl TapDhcpServer {
+    /// Listen on the DHCP server port of the tap. The interface must exist.
+    pub fn bind(if_name: &str, config: &DhcpServerConfig) -> io::Result<Self> {
+        let mut config = *config;
+        if config.dns.is_none() {
+            // The guest cannot reach a nameserver on the host loopback.
+            config.dns = host_nameserver().filter(|dns| !dns.is_loopback());
+        }
+
+        Ok(TapDhcpServer {
+            socket: bind_device(if_name)?,
+            server: DhcpServer::new(config),
+        })
+    }
+}
+
+impl AsRawFd for TapDhcpServer {
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+}
+
+impl EventHandler for TapDhcpServer {
+    fn process(&mut self, _events: Events, _ops: &mut EventOps) -> Result<()> {
+        let mut message = [0u8; MAX_MESSAGE_SIZE];
+
+        loop {
+            let count = match self.socket.recv(&mut message) {
+                Ok(count) => count,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    log::warn!("Failed to receive a DHCP message: {}", e);
+                    return Ok(());
+                }
+            };
+
+            // The guest has no address yet, the replies are broadcast.
+            if let Some((_, reply)) = self.server.reply(&message[..count]) {
+                let client = SocketAddrV4::new(Ipv4Addr::BROADCAST, DHCP_CLIENT_PORT);
+                if let Err(e) = self.socket.send_to(&reply, client) {
+                    log::warn!("Failed to send a DHCP reply: {}", e);
+                }
+            }
+        }
+    }
+}
+
+// A UDP socket on the DHCP server port of the interface only, next to the servers the host
+// may run on its other interfaces.
+fn bind_device(if_name: &str) -> io::Result<UdpSocket> {
+    // Safe because we check the result.
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_INET,
+            libc::SOCK_DGRAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+            0,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Safe because we just created the socket, and nothing else owns it.
+    let socket = unsafe { UdpSocket::from_raw_fd(fd) };
+
+    let enable = 1i32.to_ne_bytes();
+    set_option(&socket, libc::SO_REUSEADDR, &enable)?;
+    set_option(&socket, libc::SO_BROADCAST, &enable)?;
+    set_option(&socket, libc::SO_BINDTODEVICE, if_name.as_bytes())?;
+
+    let sockaddr = libc::sockaddr_in {
+        sin_family: libc::AF_INET as libc::sa_family_t,
+        sin_port: DHCP_SERVER_PORT.to_be(),
+        sin_addr: libc::in_addr {
+            s_addr: libc::INADDR_ANY,
+        },
+        sin_zero: [0; 8],
+    };
+    // Safe because the address is valid for the length we give, and we check the result.
+    let ret = unsafe {
+        libc::bind(
+            fd,
+            &sockaddr as *const libc::sockaddr_in as *const libc::sockaddr,
+            mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(socket)
+}
+
+fn set_option(socket: &UdpSocket, option: libc::c_int, value: &[u8]) -> io::Result<()> {
+    // Safe because the value is valid for the length we give, and we check the result.
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            option,
+            value.as_ptr() as *const libc::c_void,
+            value.len() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GUEST_MAC: EthernetAddress = EthernetAddress([0x52, 0x54, 0x00, 0x12, 0x34, 0x56]);
+    const OTHER_MAC: EthernetAddress = EthernetAddress([0x52, 0x54, 0x00, 0x12, 0x34, 0x57]);
+
+    fn server() -> DhcpServer {
+        DhcpServer::new(DhcpServerConfig {
+            address: Ipv4Addr::new(172, 16, 0, 1),
+            prefix_len: 24,
+            range: (Ipv4Addr::new(172, 16, 0, 10), Ipv4Addr::new(172, 16, 0, 11)),
+            dns: Some(Ipv4Addr::new(1, 1, 1, 1)),
+        })
+    }
+
+    // A client message, as udhcpc sends them.
+    fn message(message_type: DhcpMessageType, mac: EthernetAddress) -> DhcpRepr<'static> {
+        DhcpRepr {
+            message_type,
+            transaction_id: 0x1234,
+            secs: 0,
+            client_hardware_address: mac,
+            client_ip: Ipv4Address::UNSPECIFIED,
+            your_ip: Ipv4Address::UNSPECIFIED,
+            server_ip: Ipv4Address::UNSPECIFIED,
+            router: None,
+            subnet_mask: None,
+            relay_agent_ip: Ipv4Address::UNSPECIFIED,
+            broadcast: false,
+            requested_ip: None,
+            client_identifier: Some(mac),
+            server_identifier: None,
+            parameter_request_list: Some(&[1, 3, 6]),
+            dns_servers: None,
+            max_size: None,
+            lease_duration: None,
+            renew_duration: None,
+            rebind_duration: None,
+            additional_options: &[],
+        }
+    }
+
+    fn emit(message: &DhcpRepr) -> Vec<u8> {
+        let mut buffer = vec![0; message.buffer_len()];
+        message
+            .emit(&mut DhcpPacket::new_unchecked(&mut buffer[..]))
+            .unwrap();
+        buffer
+    }
+
+    fn request(mac: EthernetAddress, address: Ipv4Address) -> Vec<u8> {
+        emit(&DhcpRepr {
+            requested_ip: Some(address),
+            ..message(DhcpMessageType::Request, mac)
+        })
+    }
+
+    // Check the reply, and return its message type and the address it hands out.
+    fn reply(
+        server: &mut DhcpServer,
+        message: &[u8],
+        mac: EthernetAddress,
+    ) -> (DhcpMessageType, Ipv4Address) {
+        let (destination, reply) = server.reply(message).unwrap();
+        assert_eq!(destination, mac);
+
+        let packet = DhcpPacket::new_checked(&reply[..]).unwrap();
+        let reply = DhcpRepr::parse(&packet).unwrap();
+        assert_eq!(reply.transaction_id, 0x1234);
+        assert_eq!(reply.client_hardware_address, mac);
+        assert_eq!(
+            reply.server_identifier,
+            Some(Ipv4Address::new(172, 16, 0, 1))
+        );
+        if reply.message_type != DhcpMessageType::Nak {
+            assert_eq!(reply.router, Some(Ipv4Address::new(172, 16, 0, 1)));
+            assert_eq!(reply.subnet_mask, Some(Ipv4Address::new(255, 255, 255, 0)));
+            assert_eq!(reply.lease_duration, Some(LEASE_SECS));
+            let dns = reply.dns_servers.unwrap();
+            assert_eq!(&dns[..], [Ipv4Address::new(1, 1, 1, 1)]);
+        }
+
+        (reply.message_type, reply.your_ip)
+    }
+
+    #[test]
+    fn leases() {
+        let mut server = server();
+        let first = Ipv4Address::new(172, 16, 0, 10);
+        let second = Ipv4Address::new(172, 16, 0, 11);
+
+        let discover = emit(&message(DhcpMessageType::Discover, GUEST_MAC));
+        assert_eq!(
+            reply(&mut server, &discover, GUEST_MAC),
+            (DhcpMessageType::Offer, first)
+        );
+        assert_eq!(
+            reply(&mut server, &request(GUEST_MAC, first), GUEST_MAC),
+            (DhcpMessageType::Ack, first)
+        );
+        // The address stays the same.
+        assert_eq!(
+            reply(&mut server, &discover, GUEST_MAC),
+            (DhcpMessageType::Offer, first)
+        );
+
+        // Another client gets the next address, and cannot take the first one.
+        assert_eq!(
+            reply(&mut server, &request(OTHER_MAC, first), OTHER_MAC),
+            (DhcpMessageType::Nak, Ipv4Address::UNSPECIFIED)
+        );
+        assert_eq!(
+            reply(&mut server, &request(OTHER_MAC, second), OTHER_MAC),
+            (DhcpMessageType::Ack, second)
+        );
+
+        // The range is exhausted.
+        let mac = EthernetAddress([0x52, 0x54, 0x00, 0x12, 0x34, 0x58]);
+        let discover = emit(&message(DhcpMessageType::Discover, mac));
+        assert!(server.reply(&discover).is_none());
+    }
+
+    #[test]
+    fn ignored_messages() {
+        let mut server = server();
+
+        assert!(server.reply(&[0; 16]).is_none());
+        let release = emit(&message(DhcpMessageType::Release, GUEST_MAC));
+        assert!(server.reply(&release).is_none());
+
+        // Nor do the requests for the offer of another server get a reply.
+        let request = emit(&DhcpRepr {
+            server_identifier: Some(Ipv4Address::new(172, 16, 0, 2)),
+            ..message(DhcpMessageType::Request, GUEST_MAC)
+        });
+        assert!(server.reply(&request).is_none());
+    }
+}
@@ -1,13 +1,101 @@
 use std::{
-    io::{Read, Write},
-    os::fd::AsRawFd,
+    io::{self, Read, Write},
+    os::fd::{AsRawFd, RawFd},
 };
 
+use super::tap::Tap;
+use super::user::UserNet;
 use super::Result;
+use crate::config::{NetBackend, NetConfig};
 
 pub trait Interface: Read + Write + AsRawFd + Send + Sync {
-    fn activate(&self, virtio_flags: u64, virtio_header_size: usize) -> Result<()>;
-    fn open_named(if_name: &str) -> Result<Self>
+    /// Set the interface up for the features the driver negotiated. Returns them, without
+    /// the offloads the interface refused.
+    fn activate(&self, virtio_flags: u64, virtio_header_size: usize) -> Result<u64>;
+    fn open_config(config: &NetConfig) -> Result<Self>
     where
         Self: Sized;
+
+    /// Open the interface again, once it went away. The new one replaces this one.
+    fn reconnect(&self, config: &NetConfig) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        Self::open_config(config)
+    }
+}
+
+/// Whether an I/O error of an interface means it went away, e.g. its tap was deleted.
+pub fn is_disconnected(error: &io::Error) -> bool {
+    matches!(
+        error.raw_os_error(),
+        Some(libc::ENXIO | libc::ENODEV | libc::EBADFD)
+    )
+}
+
+/// The interface of whichever backend the configuration asks for.
+pub enum NetInterface {
+    Tap(Tap),
+    User(Box<UserNet>),
+}
+
+impl Interface for NetInterface {
+    fn activate(&self, virtio_flags: u64, virtio_header_size: usize) -> Result<u64> {
+        match self {
+            NetInterface::Tap(tap) => tap.activate(virtio_flags, virtio_header_size),
+            NetInterface::User(user) => user.activate(virtio_flags, virtio_header_size),
+        }
+    }
+
+    fn open_config(config: &NetConfig) -> Result<Self> {
+        match config.backend {
+            NetBackend::Tap(_) => Tap::open_config(config).map(NetInterface::Tap),
+            NetBackend::User(_) => {
+                UserNet::open_config(config).map(|user| NetInterface::User(Box::new(user)))
+            }
+        }
+    }
+
+    fn reconnect(&self, config: &NetConfig) -> Result<Self> {
+        match self {
+            NetInterface::Tap(tap) => tap.reconnect(config).map(NetInterface::Tap),
+            NetInterface::User(user) => user
+                .reconnect(config)
+                .map(|user| NetInterface::User(Box::new(user))),
+        }
+    }
+}
+
+impl Read for NetInterface {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            NetInterface::Tap(tap) => tap.read(buf),
+            NetInterface::User(user) => user.read(buf),
+        }
+    }
+}
+
+impl Write for NetInterface {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            NetInterface::Tap(tap) => tap.write(buf),
+            NetInterface::User(user) => user.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            NetInterface::Tap(tap) => tap.flush(),
+            NetInterface::User(user) => user.flush(),
+        }
+    }
+}
+
+impl AsRawFd for NetInterface {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            NetInterface::Tap(tap) => tap.as_raw_fd(),
+            NetInterface::User(user) => user.as_raw_fd(),
+        }
+    }
 }
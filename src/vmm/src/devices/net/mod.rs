@@ -1,37 +1,78 @@
 pub mod interface;
 
 pub(crate) mod bindings;
+pub(crate) mod bridge;
+mod csum;
+pub(crate) mod dhcp;
+pub(crate) mod slot;
 pub(crate) mod tap;
+#[cfg(test)]
+pub(crate) mod testing;
+pub(crate) mod user;
+pub(crate) mod vhost;
+mod worker;
+
+pub(crate) use worker::{spawn_worker, WorkerHandle};
 
 use std::{
     borrow::{Borrow, BorrowMut},
     cmp,
     error::Error,
     fmt::{self, Debug, Display},
+    io,
+    ops::Deref,
     os::fd::{AsRawFd, RawFd},
-    sync::atomic::Ordering,
+    sync::atomic::{AtomicU64, Ordering},
+    sync::Arc,
+    time::Duration,
 };
 
 use virtio_device::{VirtioConfig, VirtioDeviceActions, VirtioDeviceType, VirtioMmioDevice};
 
+use virtio_bindings::bindings::virtio_config::{
+    VIRTIO_CONFIG_S_FEATURES_OK, VIRTIO_CONFIG_S_NEEDS_RESET,
+};
 use virtio_bindings::bindings::virtio_net::{
     self, VIRTIO_NET_F_CSUM, VIRTIO_NET_F_GUEST_CSUM, VIRTIO_NET_F_GUEST_TSO4,
     VIRTIO_NET_F_GUEST_TSO6, VIRTIO_NET_F_GUEST_UFO, VIRTIO_NET_F_HOST_TSO4,
-    VIRTIO_NET_F_HOST_TSO6, VIRTIO_NET_F_HOST_UFO,
+    VIRTIO_NET_F_HOST_TSO6, VIRTIO_NET_F_HOST_UFO, VIRTIO_NET_F_MAC, VIRTIO_NET_F_MRG_RXBUF,
+    VIRTIO_NET_F_STATUS, VIRTIO_NET_S_LINK_UP,
 };
-use virtio_queue::{Queue, QueueOwnedT, QueueT};
+use virtio_queue::{DescriptorChain, Queue, QueueOwnedT, QueueT};
 use vm_device::{
     bus::{MmioAddress, MmioAddressOffset},
     MutDeviceMmio,
 };
-use vm_memory::{Bytes, GuestAddressSpace};
+use vm_memory::{Bytes, GuestAddress, GuestAddressSpace, GuestMemory};
 use vmm_sys_util::eventfd::EventFd;
+use vmm_sys_util::timerfd::TimerFd;
+
+use interface::{is_disconnected, Interface};
+use vhost::VhostNet;
 
-use interface::Interface;
+use crate::api::NetCounters;
+use crate::config::NetConfig;
+use crate::devices::irq_trace::IrqTrace;
+use crate::devices::virtio::{
+    self, VIRTIO_MMIO_INTERRUPT_ACK, VIRTIO_MMIO_INT_CONFIG, VIRTIO_MMIO_INT_VRING,
+};
+use crate::rate_limiter::RateLimiter;
 
 // TODO: Make this configurable.
 const VIRTIO_FEATURES: u64 = (1 << bindings::VIRTIO_F_VERSION_1)
     | (1 << VIRTIO_NET_F_CSUM)
+    | (1 << VIRTIO_NET_F_GUEST_CSUM)
+    | (1 << VIRTIO_NET_F_HOST_TSO4)
+    | (1 << VIRTIO_NET_F_HOST_TSO6)
+    | (1 << VIRTIO_NET_F_HOST_UFO)
+    | (1 << VIRTIO_NET_F_GUEST_TSO4)
+    | (1 << VIRTIO_NET_F_GUEST_TSO6)
+    | (1 << VIRTIO_NET_F_GUEST_UFO)
+    | (1 << VIRTIO_NET_F_MRG_RXBUF)
+    | (1 << VIRTIO_NET_F_STATUS);
+
+// The features an interface may refuse to offload, in either direction.
+pub(crate) const OFFLOAD_FEATURES: u64 = (1 << VIRTIO_NET_F_CSUM)
     | (1 << VIRTIO_NET_F_GUEST_CSUM)
     | (1 << VIRTIO_NET_F_HOST_TSO4)
     | (1 << VIRTIO_NET_F_HOST_TSO6)
@@ -42,10 +83,45 @@ const VIRTIO_FEATURES: u64 = (1 << bindings::VIRTIO_F_VERSION_1)
 
 const MAX_BUFFER_SIZE: usize = 65565;
 
+// Entries of the queues the device offers, unless configured otherwise.
+const DEFAULT_QUEUE_SIZE: u16 = 256;
+
+// Descriptors of a chain, past which the device drops it. The iterator of the queue only
+// stops a looping chain after as many descriptors as its table has: up to 65535 with an
+// indirect table.
+const MAX_CHAIN_DESCRIPTORS: usize = 256;
+
+// Frames received in one go. The worker then handles its other events, and the vCPUs get
+// the device lock, before it comes back for the next frames.
+const RX_BUDGET: usize = 256;
+
+// Offsets of the driver features and device status registers.
+const VIRTIO_MMIO_DRIVER_FEATURES: MmioAddressOffset = 0x20;
+const VIRTIO_MMIO_STATUS: MmioAddressOffset = 0x70;
+
+// Offset of the link status in the configuration space, after the MAC address.
+const CONFIG_STATUS_OFFSET: usize = 6;
+
+// Chains in a row with a buffer outside the guest memory, or malformed, on a queue, after
+// which the device fails and waits for the driver to reset it.
+const MAX_CONSECUTIVE_FAULTS: u32 = 64;
+
+// How often the device tries to open its interface again, once it went away.
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
 #[derive(Debug)]
 
 pub enum VirtioNetError {
     InvalidIfname,
+    InvalidBackend,
+    /// A chain of the guest is too long, or loops.
+    InvalidChain,
+    /// Another process still holds the queue of the tap.
+    TapBusy(String),
+    /// The VMM may not attach to the tap.
+    TapPermissionDenied(String),
+    /// There is no TUN/TAP driver, or no such tap.
+    TapUnavailable(String),
     VirtioQueueError(virtio_queue::Error),
     IoCtlError(std::io::Error),
     IoError(std::io::Error),
@@ -55,36 +131,199 @@ pub enum VirtioNetError {
 impl Error for VirtioNetError {}
 impl Display for VirtioNetError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "virtio net error")
+        match self {
+            VirtioNetError::TapBusy(if_name) => write!(f, "tap {} is busy", if_name),
+            VirtioNetError::TapPermissionDenied(if_name) => {
+                write!(f, "no permission to attach to tap {}", if_name)
+            }
+            VirtioNetError::TapUnavailable(if_name) => write!(f, "tap {} is unavailable", if_name),
+            _ => write!(f, "virtio net error"),
+        }
     }
 }
 
 pub type Result<T> = std::result::Result<T, VirtioNetError>;
 
+/// Device counters. They are only updated with relaxed atomics, and may lag behind each
+/// other. The frames vhost-net moves are not counted.
+#[derive(Debug, Default)]
+pub struct NetStats {
+    /// Bytes of the frames given to the guest, without their virtio header.
+    pub rx_bytes: AtomicU64,
+    /// Frames given to the guest.
+    pub rx_packets: AtomicU64,
+    /// Frames dropped as the guest had no receive buffer for them.
+    pub rx_dropped_no_buffer: AtomicU64,
+    /// Frames truncated to fit the receive buffer of the guest, which then drops them.
+    pub rx_dropped_oversize: AtomicU64,
+    /// Frames dropped as a receive buffer of the guest was outside its memory.
+    pub rx_dropped_fault: AtomicU64,
+    /// Frames dropped as a receive chain of the guest was too long, or looped.
+    pub rx_dropped_malformed: AtomicU64,
+    /// Times the RX processing stopped after its budget of frames, to let the other events
+    /// through.
+    pub rx_budget_exhausted: AtomicU64,
+    /// Bytes of the frames sent by the guest, without their virtio header.
+    pub tx_bytes: AtomicU64,
+    /// Frames sent by the guest.
+    pub tx_packets: AtomicU64,
+    /// Frames of the guest which could not be sent.
+    pub tx_errors: AtomicU64,
+    /// Frames of the guest needing an offload the interface refused, and not done in
+    /// software.
+    pub tx_dropped_offload: AtomicU64,
+    /// Frames of the guest dropped as one of their buffers was outside its memory.
+    pub tx_dropped_fault: AtomicU64,
+    /// Frames of the guest dropped as their chain was too long, or looped.
+    pub tx_dropped_malformed: AtomicU64,
+}
+
+impl NetStats {
+    /// The current values of the counters.
+    pub fn counters(&self) -> NetCounters {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        NetCounters {
+            rx_bytes: load(&self.rx_bytes),
+            rx_packets: load(&self.rx_packets),
+            rx_dropped_no_buffer: load(&self.rx_dropped_no_buffer),
+            rx_dropped_oversize: load(&self.rx_dropped_oversize),
+            rx_dropped_fault: load(&self.rx_dropped_fault),
+            rx_dropped_malformed: load(&self.rx_dropped_malformed),
+            rx_budget_exhausted: load(&self.rx_budget_exhausted),
+            tx_bytes: load(&self.tx_bytes),
+            tx_packets: load(&self.tx_packets),
+            tx_errors: load(&self.tx_errors),
+            tx_dropped_offload: load(&self.tx_dropped_offload),
+            tx_dropped_fault: load(&self.tx_dropped_fault),
+            tx_dropped_malformed: load(&self.tx_dropped_malformed),
+        }
+    }
+}
+
+// Count one more frame, and its bytes past the virtio header.
+fn count_frame(frames: &AtomicU64, bytes: &AtomicU64, size: usize) {
+    frames.fetch_add(1, Ordering::Relaxed);
+    bytes.fetch_add(
+        size.saturating_sub(bindings::VIRTIO_HDR_LEN) as u64,
+        Ordering::Relaxed,
+    );
+}
+
 pub struct VirtioNet<M: GuestAddressSpace + Clone + Send, I: Interface> {
     pub device_config: VirtioConfig<Queue>,
     pub guest_irq_fd: EventFd,
     pub address_space: M,
     pub interface: I,
+    pub rx_limiter: RateLimiter,
+    pub tx_limiter: RateLimiter,
+    // Frame read from the interface, held back by the RX rate limiter.
+    pending_rx: Option<Vec<u8>>,
+    // Head index and content of a chain, held back by the TX rate limiter.
+    pending_tx: Option<(u16, Vec<u8>)>,
+    // Written when the driver adds RX buffers, to wake the worker up.
+    rx_kick: EventFd,
+    // Whether the worker leaves the device alone, while the VM is paused.
+    paused: bool,
+    // Written when the device is paused, resumed, fails or is reset, for the worker to
+    // notice.
+    pause_kick: EventFd,
+    // Runs the datapath in the host kernel instead, when set.
+    vhost: Option<VhostNet>,
+    // Features the driver wrote, unsupported ones included.
+    acked_features: u64,
+    // Features both sides agreed on, once the driver set FEATURES_OK.
+    negotiated_features: u64,
+    // The negotiated features the interface took the offloads of, once activated. The
+    // device does the checksums of the others.
+    offloads: u64,
+    // Chains in a row with a buffer outside the guest memory, or malformed, on the RX and
+    // TX queues.
+    rx_faults: u32,
+    tx_faults: u32,
+    stats: Arc<NetStats>,
+    irq_trace: Arc<IrqTrace>,
+    // What the interface was opened from, to open it again.
+    config: NetConfig,
+    // Whether the interface is there. When it is not, the timer fires at each reconnection
+    // attempt.
+    link_up: bool,
+    reconnect_timer: TimerFd,
 }
 
 impl<M: GuestAddressSpace + Clone + Send, I: Interface> VirtioNet<M, I> {
-    pub fn new(memory: M, irq_fd: EventFd, if_name: &str) -> Result<Self> {
+    pub fn new(
+        memory: M,
+        irq_fd: EventFd,
+        config: &NetConfig,
+        rx_limiter: RateLimiter,
+        tx_limiter: RateLimiter,
+        vhost: Option<VhostNet>,
+    ) -> Result<Self> {
+        let interface = I::open_config(config)?;
+        Self::with_interface(
+            memory, irq_fd, config, interface, rx_limiter, tx_limiter, vhost,
+        )
+    }
+
+    /// Create the device over `interface`, already open, e.g. the one a previous device
+    /// handed back with [`VirtioNet::into_interface`]. The offloads and the virtio header
+    /// size are set again, whatever the previous device left them at.
+    pub fn with_interface(
+        memory: M,
+        irq_fd: EventFd,
+        config: &NetConfig,
+        interface: I,
+        rx_limiter: RateLimiter,
+        tx_limiter: RateLimiter,
+        vhost: Option<VhostNet>,
+    ) -> Result<Self> {
+        // Only offer what vhost-net can handle, when it runs the datapath.
+        let mut features = match vhost.as_ref() {
+            Some(vhost) => VIRTIO_FEATURES & vhost.features(),
+            None => VIRTIO_FEATURES,
+        };
+        // The address is only in the configuration space, it does not involve vhost-net.
+        if config.mac.is_some() {
+            features |= 1 << VIRTIO_NET_F_MAC;
+        }
+        let features = supported_features(&interface, features)?;
+
         Ok(Self {
             device_config: VirtioConfig::new(
-                VIRTIO_FEATURES,
+                features,
                 vec![
-                    Queue::new(256).map_err(VirtioNetError::QueueError)?,
-                    Queue::new(256).map_err(VirtioNetError::QueueError)?,
+                    Queue::new(config.rx_queue_size.unwrap_or(DEFAULT_QUEUE_SIZE))
+                        .map_err(VirtioNetError::QueueError)?,
+                    Queue::new(config.tx_queue_size.unwrap_or(DEFAULT_QUEUE_SIZE))
+                        .map_err(VirtioNetError::QueueError)?,
                 ],
-                // Not used in the current implementation.
                 Self::config_vec(virtio_net::virtio_net_config {
+                    mac: config.mac.unwrap_or_default().0,
+                    status: VIRTIO_NET_S_LINK_UP as u16,
                     ..Default::default()
                 }),
             ),
             address_space: memory,
             guest_irq_fd: irq_fd,
-            interface: I::open_named(if_name)?,
+            interface,
+            rx_limiter,
+            tx_limiter,
+            pending_rx: None,
+            pending_tx: None,
+            rx_kick: EventFd::new(libc::EFD_NONBLOCK).map_err(VirtioNetError::IoError)?,
+            paused: false,
+            pause_kick: EventFd::new(libc::EFD_NONBLOCK).map_err(VirtioNetError::IoError)?,
+            vhost,
+            acked_features: 0,
+            negotiated_features: 0,
+            offloads: 0,
+            rx_faults: 0,
+            tx_faults: 0,
+            stats: Arc::new(NetStats::default()),
+            irq_trace: Arc::new(IrqTrace::new("net")),
+            config: config.clone(),
+            link_up: true,
+            reconnect_timer: TimerFd::new().map_err(VirtioNetError::IoError)?,
         })
     }
 
@@ -107,120 +346,294 @@ impl<M: GuestAddressSpace + Clone + Send, I: Interface> VirtioNet<M, I> {
         }
     }
 
+    // Copy a frame, with its virtio header, to the RX queue. Without mergeable buffers, it
+    // must fit in a single chain, or it is truncated and the guest drops it. With them, it
+    // spans as many chains as needed, their count going to the num_buffers field of the
+    // header. Returns false when the guest has no buffer for the frame.
+    //
+    // A buffer outside the guest memory, or a malformed chain, drops the frame: the chains
+    // taken go back empty.
     fn write_frame_to_guest(
         &mut self,
         original_buffer: &mut [u8; MAX_BUFFER_SIZE],
         size: usize,
     ) -> Result<bool> {
-        let mem = self.address_space.memory();
-        let mut chain = match &mut self.device_config.queues[0]
-            .iter(&*mem)
-            .map_err(VirtioNetError::QueueError)?
-            .next()
-        {
-            Some(c) => c.to_owned(),
-            _ => return Ok(false),
-        };
+        let mergeable = self.negotiated_features & (1 << VIRTIO_NET_F_MRG_RXBUF) != 0;
+        let buffer = &mut original_buffer[..size];
+        // The interfaces do not fill num_buffers in. A frame in a single buffer is all the
+        // driver expects without mergeable buffers.
+        let num_buffers = bindings::VIRTIO_HDR_NUM_BUFFERS_OFFSET..bindings::VIRTIO_HDR_LEN;
+        if let Some(field) = buffer.get_mut(num_buffers.clone()) {
+            field.copy_from_slice(&1u16.to_le_bytes());
+        }
 
+        let mem = self.address_space.memory();
+        let queue = &mut self.device_config.queues[0];
+        let first_avail = queue.next_avail();
+        // Head index and written length of each chain used.
+        let mut used = Vec::new();
+        // Where the virtio header went, in the first chain.
+        let mut header = Vec::<(GuestAddress, usize)>::new();
         let mut count = 0;
-        let buffer = &mut original_buffer[..size];
 
-        while let Some(desc) = chain.next() {
-            let left = buffer.len() - count;
+        // The error of the buffer outside the guest memory, or of the malformed chain, if
+        // any.
+        let mut fault = None;
+
+        let mut chains = queue.iter(&*mem).map_err(VirtioNetError::QueueError)?;
+        'chains: while count < buffer.len() {
+            let mut chain = match chains.next() {
+                Some(chain) => chain,
+                None => break,
+            };
 
-            if left == 0 {
+            let start = count;
+            let mut descriptors = 0;
+            // Whether the last descriptor said the chain goes on.
+            let mut has_next = false;
+            while count < buffer.len() {
+                let desc = match chain.next() {
+                    Some(desc) => desc,
+                    // Cut short by the iterator, the chain loops.
+                    None if has_next => {
+                        used.push((chain.head_index(), 0));
+                        fault = Some(VirtioNetError::InvalidChain);
+                        break 'chains;
+                    }
+                    None => break,
+                };
+                descriptors += 1;
+                has_next = desc.has_next();
+                if descriptors > MAX_CHAIN_DESCRIPTORS {
+                    used.push((chain.head_index(), 0));
+                    fault = Some(VirtioNetError::InvalidChain);
+                    break 'chains;
+                }
+
+                let len = cmp::min(buffer.len() - count, desc.len() as usize);
+                if let Err(e) = chain
+                    .memory()
+                    .write_slice(&buffer[count..count + len], desc.addr())
+                {
+                    used.push((chain.head_index(), 0));
+                    fault = Some(VirtioNetError::MemoryError(e));
+                    break 'chains;
+                }
+                if used.is_empty() && count < bindings::VIRTIO_HDR_LEN {
+                    header.push((desc.addr(), len));
+                }
+
+                count += len;
+            }
+            used.push((chain.head_index(), count - start));
+
+            if !mergeable {
                 break;
             }
+        }
+        drop(chains);
 
-            let len = cmp::min(left, desc.len() as usize);
-            chain
-                .memory()
-                .write_slice(&buffer[count..count + len], desc.addr())
-                .map_err(VirtioNetError::MemoryError)?;
+        if let Some(error) = fault {
+            for (head, _) in used {
+                queue
+                    .add_used(&*mem, head, 0)
+                    .map_err(VirtioNetError::QueueError)?;
+            }
+            let dropped = match error {
+                VirtioNetError::InvalidChain => &self.stats.rx_dropped_malformed,
+                _ => &self.stats.rx_dropped_fault,
+            };
+            dropped.fetch_add(1, Ordering::Relaxed);
+            self.rx_faults += 1;
+            if self.rx_faults == MAX_CONSECUTIVE_FAULTS {
+                self.fail(error);
+            } else {
+                log::debug!("Dropped a virtio-net RX frame: {:?}", error);
+            }
+            return Ok(true);
+        }
 
-            count += len;
+        if used.is_empty() {
+            return Ok(false);
         }
 
         if count != buffer.len() {
+            if mergeable {
+                // The guest ran out of buffers: give back the chains taken, the frame is
+                // dropped.
+                queue.set_next_avail(first_avail);
+                return Ok(false);
+            }
+
             // The frame was too large for the chain.
             println!("rx frame too large");
+            self.stats
+                .rx_dropped_oversize
+                .fetch_add(1, Ordering::Relaxed);
+        } else {
+            count_frame(&self.stats.rx_packets, &self.stats.rx_bytes, count);
         }
 
-        self.device_config.queues[0]
-            .add_used(&*mem, chain.head_index(), count as u32)
-            .map_err(VirtioNetError::QueueError)?;
+        if used.len() > 1 {
+            // The header went out with a single buffer, fix it up.
+            buffer[num_buffers].copy_from_slice(&(used.len() as u16).to_le_bytes());
+            let mut offset = 0;
+            for (addr, len) in header {
+                let len = cmp::min(len, bindings::VIRTIO_HDR_LEN - offset);
+                mem.write_slice(&buffer[offset..offset + len], addr)
+                    .map_err(VirtioNetError::MemoryError)?;
+                offset += len;
+            }
+        }
+
+        for (head, len) in used {
+            queue
+                .add_used(&*mem, head, len as u32)
+                .map_err(VirtioNetError::QueueError)?;
+        }
+        self.rx_faults = 0;
 
         Ok(true)
     }
 
     pub fn process_tap(&mut self) -> Result<()> {
+        // The interface went away, there is nothing to read until it is back.
+        if !self.link_up {
+            return Ok(());
+        }
+        // Until the driver is ready, or resets the failed device, the frames are dropped, as
+        // on a link down.
+        if !self.device_config.device_activated || self.failed() {
+            return self.drain_interface();
+        }
+
+        // Whether the guest got frames, and may need an interrupt.
+        let mut used = false;
         {
             let buffer = &mut [0u8; MAX_BUFFER_SIZE];
+            let mut frames = 0;
 
             loop {
-                let read_size = match self.interface.read(buffer) {
-                    Ok(size) => size,
-                    Err(_) => {
-                        break;
+                if frames == RX_BUDGET {
+                    // The interface is polled level-triggered: the rest of the frames are
+                    // handled on the next round.
+                    self.stats
+                        .rx_budget_exhausted
+                        .fetch_add(1, Ordering::Relaxed);
+                    break;
+                }
+
+                // A frame held back by the rate limiter goes first.
+                let read_size = match self.pending_rx.take() {
+                    Some(frame) => {
+                        buffer[..frame.len()].copy_from_slice(&frame);
+                        frame.len()
                     }
+                    None => match self.interface.read(buffer) {
+                        Ok(size) => size,
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                        Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                        Err(e) if is_disconnected(&e) => {
+                            self.link_down();
+                            break;
+                        }
+                        Err(e) => return Err(VirtioNetError::IoError(e)),
+                    },
                 };
+                frames += 1;
+
+                if !self.rx_limiter.consume(read_size as u64) {
+                    // Keep the frame until the rate limiter timer fires.
+                    self.pending_rx = Some(buffer[..read_size].to_vec());
+                    break;
+                }
 
                 let mem = self.address_space.memory().borrow_mut().clone();
 
-                if !self.write_frame_to_guest(buffer, read_size)?
-                    && !self.device_config.queues[0]
+                if self.write_frame_to_guest(buffer, read_size)? {
+                    used = true;
+                    if self.failed() {
+                        break;
+                    }
+                } else {
+                    self.stats
+                        .rx_dropped_no_buffer
+                        .fetch_add(1, Ordering::Relaxed);
+                    if !self.device_config.queues[0]
                         .enable_notification(&*mem.clone())
                         .map_err(VirtioNetError::QueueError)?
-                {
-                    break;
+                    {
+                        break;
+                    }
                 }
             }
         }
 
-        if self.device_config.queues[0]
-            .needs_notification(&*self.address_space.memory())
-            .map_err(VirtioNetError::QueueError)?
+        if used
+            && self.device_config.queues[0]
+                .needs_notification(&*self.address_space.memory())
+                .map_err(VirtioNetError::QueueError)?
         {
-            // TODO: Figure out why we need to do that
-            self.device_config
-                .interrupt_status
-                .store(1, Ordering::SeqCst);
-
-            // Error should be recoverable as is, so we just log it.
-            self.guest_irq_fd.write(1).unwrap_or_else(|e| {
-                println!("Failed to signal irq: {:?}", e);
-            });
+            virtio::signal(
+                &self.device_config.interrupt_status,
+                &self.guest_irq_fd,
+                &self.irq_trace,
+                VIRTIO_MMIO_INT_VRING,
+            );
         }
 
         Ok(())
     }
-}
 
-impl<M: GuestAddressSpace + Clone + Send, I: Interface> AsRawFd for VirtioNet<M, I> {
-    fn as_raw_fd(&self) -> RawFd {
-        self.interface.as_raw_fd()
-    }
-}
+    fn drain_interface(&mut self) -> Result<()> {
+        let buffer = &mut [0u8; MAX_BUFFER_SIZE];
 
-impl<M: GuestAddressSpace + Clone + Send, I: Interface> VirtioDeviceType for VirtioNet<M, I> {
-    fn device_type(&self) -> u32 {
-        bindings::VIRTIO_NET_DEVICE_ID
+        let mut frames = 0;
+        while frames < RX_BUDGET {
+            match self.interface.read(buffer) {
+                Ok(_) => {
+                    frames += 1;
+                    self.stats
+                        .rx_dropped_no_buffer
+                        .fetch_add(1, Ordering::Relaxed);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) if is_disconnected(&e) => {
+                    self.link_down();
+                    return Ok(());
+                }
+                Err(e) => return Err(VirtioNetError::IoError(e)),
+            }
+        }
+
+        self.stats
+            .rx_budget_exhausted
+            .fetch_add(1, Ordering::Relaxed);
+        Ok(())
     }
-}
 
-impl<M: GuestAddressSpace + Clone + Send, I: Interface> VirtioMmioDevice for VirtioNet<M, I> {
     // Please note that this method can be improved error handling wise.
-    // We are limited in how we can handle errors here, as we are not allowed to return a Result.
-    fn queue_notify(&mut self, val: u32) {
-        if val == 0 {
+    // We are limited in how we can handle errors here, as it runs from queue_notify,
+    // which is not allowed to return a Result.
+    fn process_tx(&mut self) {
+        // The driver resets the failed device before sending anything more.
+        if self.failed() {
             return;
         }
 
         let mem = self.address_space.memory().clone();
-        let irq = &mut self.guest_irq_fd;
+        let irq = &self.guest_irq_fd;
+        let irq_trace = &self.irq_trace;
+        let interrupt_status = &self.device_config.interrupt_status;
         let queue = &mut self.device_config.queues[1];
+        // Whether a write found the interface gone.
+        let mut disconnected = false;
+        // The error failing the device, after too many buffers outside the guest memory, or
+        // malformed chains.
+        let mut failure = None;
 
-        loop {
+        'notifications: loop {
             match queue.disable_notification(&*mem) {
                 Ok(_) => {}
                 Err(e) => {
@@ -229,44 +642,102 @@ impl<M: GuestAddressSpace + Clone + Send, I: Interface> VirtioMmioDevice for Vir
                 }
             }
 
-            // Consume entries from the available ring.
-            // Never fails since we know the memory is valid.
-            while let Some(chain) = queue.iter(&*mem).unwrap().next() {
-                let mut data_buffer: Vec<u8> = Vec::new();
-                chain.clone().for_each(|desc| {
-                    let initial_buffer_len = data_buffer.len();
-
-                    data_buffer.resize(data_buffer.len() + desc.len() as usize, 0);
+            loop {
+                // A frame held back by the rate limiter goes first.
+                let (head_index, mut data_buffer) = match self.pending_tx.take() {
+                    Some(frame) => frame,
+                    // Consume entries from the available ring, whose index the driver may
+                    // have set past the queue size, or outside the guest memory.
+                    None => match queue.iter(&*mem).map(|mut chains| chains.next()) {
+                        Ok(Some(chain)) => {
+                            let head_index = chain.head_index();
+                            let data_buffer = match read_tx_chain(chain) {
+                                Ok(data_buffer) => data_buffer,
+                                Err(error) => {
+                                    // Give the chain back, the next ones may be valid.
+                                    let dropped = match error {
+                                        VirtioNetError::InvalidChain => {
+                                            &self.stats.tx_dropped_malformed
+                                        }
+                                        _ => &self.stats.tx_dropped_fault,
+                                    };
+                                    dropped.fetch_add(1, Ordering::Relaxed);
+                                    queue.add_used(&*mem, head_index, 0).unwrap_or_else(|e| {
+                                        println!("Failed to add used buffer: {:?}", e);
+                                    });
+                                    self.tx_faults += 1;
+                                    if self.tx_faults == MAX_CONSECUTIVE_FAULTS {
+                                        failure = Some(error);
+                                        break 'notifications;
+                                    }
+                                    log::debug!("Dropped a virtio-net TX frame: {:?}", error);
+                                    continue;
+                                }
+                            };
+                            self.tx_faults = 0;
 
-                    // Safe as we just allocated the buffer and mem is valid.
-                    // If it actually fails, it is probably unrecoverable anyway.
-                    mem.read_slice(&mut data_buffer[initial_buffer_len..], desc.addr())
-                        .unwrap();
-                });
+                            (head_index, data_buffer)
+                        }
+                        Ok(None) => break,
+                        Err(e) => {
+                            failure = Some(VirtioNetError::QueueError(e));
+                            break 'notifications;
+                        }
+                    },
+                };
 
-                if (data_buffer.len() as usize) < bindings::VIRTIO_HDR_LEN {
+                if data_buffer.len() < bindings::VIRTIO_HDR_LEN {
+                    // Give the chain back, the next ones may be valid.
                     println!("invalid net packet");
-                    return;
+                    self.stats.tx_errors.fetch_add(1, Ordering::Relaxed);
+                    queue.add_used(&*mem, head_index, 0).unwrap_or_else(|e| {
+                        println!("Failed to add used buffer: {:?}", e);
+                    });
+                    continue;
+                }
+
+                if !self.tx_limiter.consume(data_buffer.len() as u64) {
+                    // Notifications stay disabled, the rate limiter timer resumes the processing.
+                    self.pending_tx = Some((head_index, data_buffer));
+                    break 'notifications;
                 }
 
-                match self.interface.write(&data_buffer) {
-                    Ok(_) => {
-                        queue
-                            .add_used(&*mem, chain.head_index(), 0x100)
-                            // Try continuing even if we failed to add the used buffer.
-                            .unwrap_or_else(|e| {
-                                println!("Failed to add used buffer: {:?}", e);
-                            });
-
-                        if queue.needs_notification(&*mem).unwrap_or_default() {
-                            irq.write(1).unwrap_or_else(|e| {
-                                println!("Failed to signal irq: {:?}", e);
-                            });
+                // With the offloads the interface refused, the device completes the checksums,
+                // but cannot segment.
+                let refused = self.negotiated_features & !self.offloads;
+                if refused != 0 && !csum::complete(&mut data_buffer) {
+                    self.stats
+                        .tx_dropped_offload
+                        .fetch_add(1, Ordering::Relaxed);
+                } else {
+                    match self.interface.write(&data_buffer) {
+                        Ok(_) => count_frame(
+                            &self.stats.tx_packets,
+                            &self.stats.tx_bytes,
+                            data_buffer.len(),
+                        ),
+                        // The frames are lost until the interface is back.
+                        Err(e) if is_disconnected(&e) => {
+                            disconnected = true;
+                            self.stats.tx_errors.fetch_add(1, Ordering::Relaxed);
+                        }
+                        Err(e) => {
+                            println!("Failed to write to tap: {:?}", e);
+                            self.stats.tx_errors.fetch_add(1, Ordering::Relaxed);
                         }
                     }
-                    Err(e) => {
-                        println!("Failed to write to tap: {:?}", e);
-                    }
+                }
+
+                // The driver gets the chain back, whether the frame went through or not.
+                queue
+                    .add_used(&*mem, head_index, 0x100)
+                    // Try continuing even if we failed to add the used buffer.
+                    .unwrap_or_else(|e| {
+                        println!("Failed to add used buffer: {:?}", e);
+                    });
+
+                if queue.needs_notification(&*mem).unwrap_or_default() {
+                    virtio::signal(interrupt_status, irq, irq_trace, VIRTIO_MMIO_INT_VRING);
                 }
             }
 
@@ -274,6 +745,335 @@ impl<M: GuestAddressSpace + Clone + Send, I: Interface> VirtioMmioDevice for Vir
                 break;
             }
         }
+
+        if disconnected {
+            self.link_down();
+        }
+        if let Some(error) = failure {
+            self.fail(error);
+        }
+    }
+
+    /// Whether the interface is there. The driver sees it as the link status.
+    pub fn link_up(&self) -> bool {
+        self.link_up
+    }
+
+    // The interface went away: tell the driver the link is down, and try to open it again
+    // until it is back.
+    fn link_down(&mut self) {
+        if !self.link_up {
+            return;
+        }
+
+        log::warn!(
+            "virtio-net interface went away, reconnecting every {:?}",
+            RECONNECT_INTERVAL
+        );
+        if let Err(e) = self
+            .reconnect_timer
+            .reset(RECONNECT_INTERVAL, Some(RECONNECT_INTERVAL))
+        {
+            log::warn!("Failed to arm the reconnection timer: {:?}", e);
+        }
+        self.set_link(false);
+    }
+
+    // Update the link status, and tell the driver its configuration changed.
+    fn set_link(&mut self, up: bool) {
+        self.link_up = up;
+        let status = if up { VIRTIO_NET_S_LINK_UP as u16 } else { 0 };
+        self.device_config.config_space[CONFIG_STATUS_OFFSET..CONFIG_STATUS_OFFSET + 2]
+            .copy_from_slice(&status.to_le_bytes());
+
+        virtio::signal(
+            &self.device_config.interrupt_status,
+            &self.guest_irq_fd,
+            &self.irq_trace,
+            VIRTIO_MMIO_INT_CONFIG,
+        );
+    }
+
+    // Try to open the interface again. Returns whether it is back, with a new file
+    // descriptor.
+    fn reconnect(&mut self) -> bool {
+        if self.link_up {
+            return false;
+        }
+
+        let interface = match self.interface.reconnect(&self.config) {
+            Ok(interface) => interface,
+            Err(e) => {
+                log::debug!("Failed to reconnect the virtio-net interface: {:?}", e);
+                return false;
+            }
+        };
+        // The new interface starts without the offloads the driver negotiated.
+        let features = if self.device_config.device_activated {
+            self.negotiated_features
+        } else {
+            0
+        };
+        let offloads = match interface.activate(features, bindings::VIRTIO_HDR_LEN) {
+            Ok(offloads) => offloads,
+            Err(e) => {
+                log::debug!("Failed to set up the virtio-net interface: {:?}", e);
+                return false;
+            }
+        };
+
+        log::info!("virtio-net interface reconnected");
+        self.interface = interface;
+        self.offloads = offloads;
+        if let Err(e) = self.reconnect_timer.clear() {
+            log::warn!("Failed to disarm the reconnection timer: {:?}", e);
+        }
+        self.set_link(true);
+        true
+    }
+
+    /// Try to open the interface again, once the reconnection timer fires. Returns whether
+    /// it is back, with a new file descriptor to poll.
+    pub fn reconnect_event(&mut self) -> bool {
+        // The counter only wakes us up, its value does not matter.
+        let _ = self.reconnect_timer.wait();
+        self.reconnect()
+    }
+
+    /// Resume receiving frames once the RX rate limiter timer fires.
+    pub fn rx_limiter_event(&mut self) -> Result<()> {
+        self.rx_limiter.event_handler();
+        self.process_tap()
+    }
+
+    /// Resume sending frames once the TX rate limiter timer fires.
+    pub fn tx_limiter_event(&mut self) {
+        self.tx_limiter.event_handler();
+        self.process_tx();
+    }
+
+    /// Resume receiving frames once the driver added RX buffers.
+    pub fn rx_kick_event(&mut self) -> Result<()> {
+        // The counter only wakes us up, its value does not matter.
+        let _ = self.rx_kick.read();
+        self.process_tap()
+    }
+
+    /// Relay the vhost-net notifications to the driver.
+    ///
+    /// vhost cannot signal the guest IRQ directly: the virtio-mmio driver ignores the
+    /// interrupts without the used ring bit in the interrupt status.
+    pub fn vhost_call_event(&mut self) {
+        if let Some(vhost) = self.vhost.as_ref() {
+            // The counter only wakes us up, its value does not matter.
+            let _ = vhost.call().read();
+        }
+
+        virtio::signal(
+            &self.device_config.interrupt_status,
+            &self.guest_irq_fd,
+            &self.irq_trace,
+            VIRTIO_MMIO_INT_VRING,
+        );
+    }
+
+    /// The device counters.
+    pub fn stats(&self) -> Arc<NetStats> {
+        self.stats.clone()
+    }
+
+    /// The interrupt latency of the device.
+    pub(crate) fn irq_trace(&self) -> Arc<IrqTrace> {
+        self.irq_trace.clone()
+    }
+
+    /// Stop moving the frames, or start again. While paused, the worker leaves the
+    /// interface alone, and the frames for the guest wait in the host queue.
+    ///
+    /// The worker notices on its next wakeup: once this returns, it no longer processes
+    /// anything, as it does with the device locked.
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+        self.kick_worker();
+    }
+
+    // Get the worker to check whether it may process the device.
+    fn kick_worker(&self) {
+        self.pause_kick.write(1).unwrap_or_else(|e| {
+            log::warn!("Failed to wake the virtio-net worker up: {:?}", e);
+        });
+    }
+
+    /// The features the driver negotiated, or 0 until it set FEATURES_OK.
+    pub fn negotiated_features(&self) -> u64 {
+        self.negotiated_features
+    }
+
+    /// The vhost-net instance running the datapath, if any.
+    pub fn vhost(&self) -> Option<&VhostNet> {
+        self.vhost.as_ref()
+    }
+
+    /// Release the interface, for another device to take it. Its offloads are turned off,
+    /// as for a new interface.
+    pub fn into_interface(self) -> I {
+        // vhost-net lets go of the interface along with the rest of the device.
+        if let Err(e) = self.interface.activate(0, bindings::VIRTIO_HDR_LEN) {
+            log::warn!("Failed to turn the offloads of the interface off: {:?}", e);
+        }
+        self.interface
+    }
+
+    /// Stop the device after an unrecoverable error, and ask the driver to reset it. The
+    /// worker leaves the device alone until then.
+    pub fn fail(&mut self, error: VirtioNetError) {
+        log::error!("virtio-net device failed: {:?}", error);
+
+        self.device_config.device_status |= VIRTIO_CONFIG_S_NEEDS_RESET as u8;
+        virtio::signal(
+            &self.device_config.interrupt_status,
+            &self.guest_irq_fd,
+            &self.irq_trace,
+            VIRTIO_MMIO_INT_CONFIG,
+        );
+        self.kick_worker();
+    }
+
+    /// Whether a driver started setting the device up, and did not reset it since.
+    pub fn driver_attached(&self) -> bool {
+        self.device_config.device_status != 0
+    }
+
+    /// Whether the device failed, and waits for a reset.
+    pub fn failed(&self) -> bool {
+        u32::from(self.device_config.device_status) & VIRTIO_CONFIG_S_NEEDS_RESET != 0
+    }
+
+    // Reset the device as the driver writes 0 to the status, whether it got to DRIVER_OK
+    // or not: the virtio device only resets the activated devices, leaving the features
+    // and the queues a failed probe set up to the next one.
+    fn driver_reset(&mut self) {
+        match VirtioDeviceActions::reset(self) {
+            // The worker gets back to a failed device.
+            Ok(()) => self.kick_worker(),
+            Err(e) => self.fail(e),
+        }
+    }
+
+    // Record the half of the features the driver writes as is, whatever the virtio config
+    // keeps of it.
+    fn ack_features(&mut self, data: &[u8]) {
+        let config = &self.device_config;
+        let value = match data.try_into() {
+            Ok(value) => u64::from(u32::from_le_bytes(value)),
+            Err(_) => return,
+        };
+        if u32::from(config.device_status) & VIRTIO_CONFIG_S_FEATURES_OK != 0 {
+            return;
+        }
+
+        let shift = match config.driver_features_select {
+            0 => 0,
+            1 => 32,
+            _ => return,
+        };
+        self.acked_features = (self.acked_features & !(0xffff_ffff << shift)) | (value << shift);
+    }
+
+    // Check the features the driver acked as it sets FEATURES_OK, and keep them. Returns
+    // false when it acked some we did not offer: the status write is then dropped, and the
+    // driver reads FEATURES_OK back as cleared and gives up on the device.
+    fn negotiate(&mut self, data: &[u8]) -> bool {
+        let status = match data.first() {
+            Some(status) => u32::from(*status),
+            None => return true,
+        };
+        let config = &self.device_config;
+        if status & VIRTIO_CONFIG_S_FEATURES_OK == 0
+            || u32::from(config.device_status) & VIRTIO_CONFIG_S_FEATURES_OK != 0
+        {
+            return true;
+        }
+
+        let unsupported = self.acked_features & !config.device_features;
+        if unsupported != 0 {
+            log::warn!(
+                "virtio-net driver acked unsupported features {:#x}",
+                unsupported
+            );
+            return false;
+        }
+
+        self.negotiated_features = self.acked_features;
+        true
+    }
+}
+
+// Read the frame of a TX chain. The chain must not be longer than a frame, nor loop: a
+// buffer is only allocated for what the descriptors actually hold.
+fn read_tx_chain<M>(mut chain: DescriptorChain<M>) -> Result<Vec<u8>>
+where
+    M: Deref,
+    M::Target: GuestMemory,
+{
+    let mut data_buffer = Vec::new();
+    let mut descriptors = 0;
+    let mut has_next = false;
+    while let Some(desc) = chain.next() {
+        descriptors += 1;
+        has_next = desc.has_next();
+        let len = desc.len() as usize;
+        if descriptors > MAX_CHAIN_DESCRIPTORS || data_buffer.len() + len > MAX_BUFFER_SIZE {
+            return Err(VirtioNetError::InvalidChain);
+        }
+
+        let start = data_buffer.len();
+        data_buffer.resize(start + len, 0);
+        chain
+            .memory()
+            .read_slice(&mut data_buffer[start..], desc.addr())
+            .map_err(VirtioNetError::MemoryError)?;
+    }
+    // Cut short by the iterator, the chain loops.
+    if has_next {
+        return Err(VirtioNetError::InvalidChain);
+    }
+
+    Ok(data_buffer)
+}
+
+// The features the device offers on `interface`, out of `features`. Without the offloads
+// the interface refuses, the driver does them itself.
+fn supported_features<I: Interface>(interface: &I, features: u64) -> Result<u64> {
+    let enabled = interface.activate(features, bindings::VIRTIO_HDR_LEN)?;
+    // Until the driver negotiates them.
+    interface.activate(0, bindings::VIRTIO_HDR_LEN)?;
+    Ok(features & (enabled | !OFFLOAD_FEATURES))
+}
+
+impl<M: GuestAddressSpace + Clone + Send, I: Interface> AsRawFd for VirtioNet<M, I> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.interface.as_raw_fd()
+    }
+}
+
+impl<M: GuestAddressSpace + Clone + Send, I: Interface> VirtioDeviceType for VirtioNet<M, I> {
+    fn device_type(&self) -> u32 {
+        bindings::VIRTIO_NET_DEVICE_ID
+    }
+}
+
+impl<M: GuestAddressSpace + Clone + Send, I: Interface> VirtioMmioDevice for VirtioNet<M, I> {
+    fn queue_notify(&mut self, val: u32) {
+        if val == 0 {
+            // The worker thread owns the RX processing.
+            self.rx_kick.write(1).unwrap_or_else(|e| {
+                println!("Failed to kick the RX queue: {:?}", e);
+            });
+            return;
+        }
+
+        self.process_tx();
     }
 }
 
@@ -297,13 +1097,48 @@ impl<M: GuestAddressSpace + Clone + Send, I: Interface> VirtioDeviceActions for
     type E = VirtioNetError;
 
     fn activate(&mut self) -> Result<()> {
-        self.interface
-            .activate(VIRTIO_FEATURES, bindings::VIRTIO_HDR_LEN)?;
+        log::info!(
+            "virtio-net negotiated features {:#x}",
+            self.negotiated_features
+        );
+        self.offloads = self
+            .interface
+            .activate(self.negotiated_features, bindings::VIRTIO_HDR_LEN)?;
+
+        if let Some(vhost) = self.vhost.as_ref() {
+            vhost.activate(
+                &*self.address_space.memory(),
+                self.negotiated_features,
+                &self.device_config.queues,
+                self.interface.as_raw_fd(),
+            )?;
+        }
 
         Ok(())
     }
     fn reset(&mut self) -> std::result::Result<(), Self::E> {
-        println!("virtio net reset");
+        let activated = self.device_config.device_activated;
+
+        // Back to the state of a new device, the next activation starts from scratch. The
+        // worker drops the frames until then.
+        virtio::reset_config(&mut self.device_config);
+        self.acked_features = 0;
+        self.negotiated_features = 0;
+        self.rx_faults = 0;
+        self.tx_faults = 0;
+
+        // The frames held back by the rate limiters belong to the old queues.
+        self.pending_rx = None;
+        self.pending_tx = None;
+
+        if activated {
+            if let Some(vhost) = self.vhost.as_ref() {
+                vhost.deactivate()?;
+            }
+        }
+        // Turn the offloads off, until the driver negotiates them again.
+        self.offloads = self.interface.activate(0, bindings::VIRTIO_HDR_LEN)?;
+
         Ok(())
     }
 }
@@ -316,8 +1151,835 @@ impl<M: GuestAddressSpace + Clone + Send, I: Interface> MutDeviceMmio for Virtio
     }
 
     fn mmio_write(&mut self, _base: MmioAddress, offset: MmioAddressOffset, data: &[u8]) {
-        if self.is_reading_register(&offset) {
-            self.write(u64::from(offset), data);
+        if !self.is_reading_register(&offset) {
+            return;
+        }
+        match offset {
+            VIRTIO_MMIO_DRIVER_FEATURES => self.ack_features(data),
+            VIRTIO_MMIO_STATUS if data.first() == Some(&0) => {
+                self.driver_reset();
+                return;
+            }
+            VIRTIO_MMIO_STATUS if !self.negotiate(data) => return,
+            VIRTIO_MMIO_INTERRUPT_ACK => {
+                virtio::ack(&self.device_config.interrupt_status, &self.irq_trace, data);
+                return;
+            }
+            _ => {}
+        }
+
+        self.write(u64::from(offset), data);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::*;
+    use super::*;
+    use crate::config::MacAddress;
+
+    use virtio_bindings::bindings::virtio_config::VIRTIO_CONFIG_S_DRIVER_OK;
+    use virtio_bindings::bindings::virtio_ring::{VRING_DESC_F_WRITE, VRING_USED_F_NO_NOTIFY};
+    use vm_memory::{GuestAddress, GuestMemoryMmap};
+
+    // Everything the device offers, but mergeable buffers: each frame goes to a single
+    // chain.
+    const SINGLE_BUFFER_FEATURES: u64 = VIRTIO_FEATURES & !(1 << VIRTIO_NET_F_MRG_RXBUF);
+
+    // The content of the buffers of a chain, as the driver reads them.
+    fn read_buffers(mem: &GuestMemoryMmap, buffers: &[(u64, usize)]) -> Vec<u8> {
+        let mut data = Vec::new();
+        for (addr, len) in buffers {
+            let mut buffer = vec![0u8; *len];
+            mem.read_slice(&mut buffer, GuestAddress(*addr)).unwrap();
+            data.extend(buffer);
+        }
+        data
+    }
+
+    fn num_buffers(frame: &[u8]) -> u16 {
+        let offset = bindings::VIRTIO_HDR_NUM_BUFFERS_OFFSET;
+        u16::from_le_bytes([frame[offset], frame[offset + 1]])
+    }
+
+    #[test]
+    fn negotiation() {
+        let mem = guest_memory(0x20000);
+        let mut net = new_net(&mem);
+
+        // The driver takes part of what the device offers, the offloads follow it.
+        let features = (1 << bindings::VIRTIO_F_VERSION_1)
+            | (1 << VIRTIO_NET_F_GUEST_CSUM)
+            | (1 << VIRTIO_NET_F_GUEST_TSO4);
+        driver_init(&mut net, &mem, features);
+        assert_eq!(net.negotiated_features(), features);
+        assert_eq!(*net.interface.offloads.lock().unwrap(), Some(features));
+
+        // It cannot take more after a reset.
+        write_register(&mut net, 0x70, 0);
+        assert_eq!(net.negotiated_features(), 0);
+        let status = ack_features(&mut net, VIRTIO_FEATURES | (1 << 63));
+        assert_eq!(status & VIRTIO_CONFIG_S_FEATURES_OK, 0);
+        assert_eq!(net.negotiated_features(), 0);
+        assert!(!net.device_config.device_activated);
+
+        // Nor start the device without FEATURES_OK.
+        write_register(
+            &mut net,
+            0x70,
+            status | VIRTIO_CONFIG_S_FEATURES_OK | VIRTIO_CONFIG_S_DRIVER_OK,
+        );
+        assert!(!net.device_config.device_activated);
+        assert_eq!(*net.interface.offloads.lock().unwrap(), Some(0));
+    }
+
+    #[test]
+    fn refused_offloads() {
+        let mem = guest_memory(0x20000);
+        let mut net = new_net(&mem);
+
+        // An interface refusing the offloads from the start, they are not offered.
+        net.interface.refuse_offloads = true;
+        assert_eq!(
+            supported_features(&net.interface, VIRTIO_FEATURES).unwrap(),
+            VIRTIO_FEATURES & !OFFLOAD_FEATURES
+        );
+
+        // One refusing them once negotiated, e.g. after a reconnection: the device
+        // completes the checksums.
+        let stats = net.stats();
+        let (_, mut tx) = driver_init(&mut net, &mem, VIRTIO_FEATURES);
+        let mut frame = vec![0u8; bindings::VIRTIO_HDR_LEN];
+        frame[0] = virtio_net::VIRTIO_NET_HDR_F_NEEDS_CSUM as u8;
+        frame[8] = 2;
+        frame.extend_from_slice(&[0x00, 0x01, 0x00, 0x00, 0x00, 0x02]);
+        tx.send(&mut net, &mem, &[&frame]);
+        assert_eq!(net.interface.sent.len(), 1);
+        assert_eq!(net.interface.sent[0][0], 0);
+        assert_eq!(
+            net.interface.sent[0][bindings::VIRTIO_HDR_LEN..],
+            [0x00, 0x01, 0xff, 0xfc, 0x00, 0x02]
+        );
+
+        // But does not segment, the frame is dropped.
+        frame[1] = virtio_net::VIRTIO_NET_HDR_GSO_TCPV4 as u8;
+        tx.send(&mut net, &mem, &[&frame]);
+        assert_eq!(net.interface.sent.len(), 1);
+        assert_eq!(tx.used_index(&mem), 2);
+        assert_eq!(stats.counters().tx_dropped_offload, 1);
+    }
+
+    #[test]
+    fn mac_address() {
+        let mem = guest_memory(0x20000);
+        let mac = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+        let config = NetConfig {
+            mac: Some(MacAddress(mac)),
+            ..Default::default()
+        };
+        let mut net = TestNet::new(
+            mem.clone(),
+            EventFd::new(libc::EFD_NONBLOCK).unwrap(),
+            &config,
+            RateLimiter::new(None, None).unwrap(),
+            RateLimiter::new(None, None).unwrap(),
+            None,
+        )
+        .unwrap();
+
+        // The driver finds the MAC feature, and reads the address from the configuration
+        // space.
+        write_register(&mut net, 0x14, 0);
+        assert_ne!(read_register(&net, 0x10) & (1 << VIRTIO_NET_F_MAC), 0);
+        let mut address = [0u8; 6];
+        net.read(0x100, &mut address);
+        assert_eq!(address, mac);
+        assert!(!net.driver_attached());
+
+        driver_init(&mut net, &mem, VIRTIO_FEATURES | (1 << VIRTIO_NET_F_MAC));
+        assert!(net.driver_attached());
+        write_register(&mut net, 0x70, 0);
+        assert!(!net.driver_attached());
+
+        // Without an address, the driver picks one.
+        let net = new_net(&mem);
+        assert_eq!(read_register(&net, 0x10) & (1 << VIRTIO_NET_F_MAC), 0);
+    }
+
+    #[test]
+    fn queue_sizes() {
+        // The device offers 256 entries by default.
+        let mem = guest_memory(0x60000);
+        let mut net = new_net(&mem);
+        for index in 0..2 {
+            write_register(&mut net, 0x30, index);
+            assert_eq!(read_register(&net, 0x34), 256);
+        }
+
+        for size in [64, 1024] {
+            let config = NetConfig {
+                rx_queue_size: Some(size),
+                tx_queue_size: Some(size),
+                ..Default::default()
+            };
+            let mut net = TestNet::new(
+                mem.clone(),
+                EventFd::new(libc::EFD_NONBLOCK).unwrap(),
+                &config,
+                RateLimiter::new(None, None).unwrap(),
+                RateLimiter::new(None, None).unwrap(),
+                None,
+            )
+            .unwrap();
+            for index in 0..2 {
+                write_register(&mut net, 0x30, index);
+                assert_eq!(read_register(&net, 0x34), u32::from(size));
+            }
+
+            // The driver uses the whole queues, the frames go through as the rings wrap.
+            let queues = [
+                DriverQueue::with_size(&mem, 0x40000, size),
+                DriverQueue::with_size(&mem, 0x50000, size),
+            ];
+            let (mut rx, mut tx) =
+                driver_init_queues(&mut net, &mem, SINGLE_BUFFER_FEATURES, queues);
+            let frame = rx_frame(60);
+            for index in 0..=size {
+                rx.post_buffer(&mem, 0x8000, 2048);
+                net.interface.received.push_back(frame.clone());
+                net.process_tap().unwrap();
+                assert_eq!(rx.used(&mem, index), [(0x8000, frame.len())]);
+
+                tx.send_frame(&mut net, &mem, b"frame");
+            }
+            assert_eq!(tx.used_index(&mem), size + 1);
+            assert_eq!(net.interface.sent.len(), usize::from(size) + 1);
+        }
+    }
+
+    #[test]
+    fn reset() {
+        let mem = guest_memory(0x20000);
+        let mut net = new_net(&mem);
+
+        let (_, mut tx) = driver_init(&mut net, &mem, VIRTIO_FEATURES);
+        assert_eq!(
+            *net.interface.offloads.lock().unwrap(),
+            Some(VIRTIO_FEATURES)
+        );
+        tx.send_frame(&mut net, &mem, b"before");
+        tx.send_frame(&mut net, &mem, b"reset");
+        assert_eq!(tx.used_index(&mem), 2);
+        net.device_config
+            .interrupt_status
+            .store(VIRTIO_MMIO_INT_VRING, Ordering::SeqCst);
+
+        // The driver resets the device.
+        write_register(&mut net, 0x70, 0);
+        assert_eq!(read_register(&net, 0x70), 0);
+        assert_eq!(net.device_config.driver_features, 0);
+        assert_eq!(read_register(&net, 0x60), 0);
+        assert_eq!(*net.interface.offloads.lock().unwrap(), Some(0));
+        for index in 0..2 {
+            write_register(&mut net, 0x30, index);
+            assert_eq!(read_register(&net, 0x44), 0);
+            assert_eq!(net.device_config.queues[index as usize].next_avail(), 0);
+            assert_eq!(net.device_config.queues[index as usize].next_used(), 0);
+        }
+
+        // And starts over, the frames go through again.
+        let (_, mut tx) = driver_init(&mut net, &mem, VIRTIO_FEATURES);
+        assert_eq!(
+            *net.interface.offloads.lock().unwrap(),
+            Some(VIRTIO_FEATURES)
+        );
+        tx.send_frame(&mut net, &mem, b"after");
+        assert_eq!(tx.used_index(&mem), 1);
+
+        let payloads: Vec<_> = net
+            .interface
+            .sent
+            .iter()
+            .map(|frame| &frame[bindings::VIRTIO_HDR_LEN..])
+            .collect();
+        assert_eq!(payloads, [&b"before"[..], b"reset", b"after"]);
+    }
+
+    #[test]
+    fn interface_setup() {
+        let mem = guest_memory(0x20000);
+        let mut net = new_net(&mem);
+        assert_eq!(*net.interface.vnet_hdr_size.lock().unwrap(), None);
+
+        // Activating the device hands the offloads the driver took, and the size of the
+        // header before each frame, to the interface.
+        let features = (1 << bindings::VIRTIO_F_VERSION_1)
+            | (1 << VIRTIO_NET_F_GUEST_CSUM)
+            | (1 << VIRTIO_NET_F_GUEST_UFO);
+        driver_init(&mut net, &mem, features);
+        assert_eq!(*net.interface.offloads.lock().unwrap(), Some(features));
+        assert_eq!(
+            *net.interface.vnet_hdr_size.lock().unwrap(),
+            Some(bindings::VIRTIO_HDR_LEN)
+        );
+
+        // Letting the interface go turns the offloads off, and keeps the header.
+        let interface = net.into_interface();
+        assert_eq!(*interface.offloads.lock().unwrap(), Some(0));
+        assert_eq!(
+            *interface.vnet_hdr_size.lock().unwrap(),
+            Some(bindings::VIRTIO_HDR_LEN)
+        );
+    }
+
+    #[test]
+    fn reused_interface() {
+        // Two VMs in a row, the second one taking the interface of the first.
+        let mem = guest_memory(0x20000);
+        let mut net = new_net(&mem);
+        let (_, mut tx) = driver_init(&mut net, &mem, VIRTIO_FEATURES);
+        tx.send_frame(&mut net, &mem, b"first");
+        let interface = net.into_interface();
+        assert_eq!(*interface.offloads.lock().unwrap(), Some(0));
+
+        let mem = guest_memory(0x20000);
+        let mut net = TestNet::with_interface(
+            mem.clone(),
+            EventFd::new(libc::EFD_NONBLOCK).unwrap(),
+            &NetConfig::default(),
+            interface,
+            RateLimiter::new(None, None).unwrap(),
+            RateLimiter::new(None, None).unwrap(),
+            None,
+        )
+        .unwrap();
+        assert_eq!(net.device_config.device_features, VIRTIO_FEATURES);
+        // The new driver gets the offloads set again.
+        let features = VIRTIO_FEATURES & !(1 << VIRTIO_NET_F_GUEST_TSO6);
+        let (_, mut tx) = driver_init(&mut net, &mem, features);
+        assert_eq!(*net.interface.offloads.lock().unwrap(), Some(features));
+        tx.send_frame(&mut net, &mem, b"second");
+
+        let payloads: Vec<_> = net
+            .interface
+            .sent
+            .iter()
+            .map(|frame| &frame[bindings::VIRTIO_HDR_LEN..])
+            .collect();
+        assert_eq!(payloads, [&b"first"[..], b"second"]);
+    }
+
+    #[test]
+    fn probe_reset() {
+        let mem = guest_memory(0x20000);
+        let mut net = new_net(&mem);
+
+        // The driver negotiates, sets a queue up, then fails its probe before DRIVER_OK and
+        // resets the device.
+        ack_features(&mut net, VIRTIO_FEATURES);
+        assert_eq!(net.negotiated_features(), VIRTIO_FEATURES);
+        write_register(&mut net, 0x30, 0);
+        write_register(&mut net, 0x38, u32::from(QUEUE_SIZE));
+        write_register(&mut net, 0x44, 1);
+        write_register(&mut net, 0x70, 0);
+
+        assert_eq!(read_register(&net, 0x70), 0);
+        assert_eq!(net.device_config.driver_features, 0);
+        assert_eq!(net.negotiated_features(), 0);
+        for index in 0..2 {
+            write_register(&mut net, 0x30, index);
+            assert_eq!(read_register(&net, 0x44), 0);
+        }
+        // Until the driver is back, the frames are dropped.
+        net.interface.received.push_back(rx_frame(60));
+        net.process_tap().unwrap();
+        assert!(net.interface.received.is_empty());
+
+        // The next probe starts from scratch, with other features.
+        let (mut rx, mut tx) = driver_init(&mut net, &mem, SINGLE_BUFFER_FEATURES);
+        assert_eq!(net.negotiated_features(), SINGLE_BUFFER_FEATURES);
+        assert_eq!(
+            *net.interface.offloads.lock().unwrap(),
+            Some(SINGLE_BUFFER_FEATURES)
+        );
+        rx.post_buffer(&mem, 0x8000, 2048);
+        let frame = rx_frame(60);
+        net.interface.received.push_back(frame.clone());
+        net.process_tap().unwrap();
+        assert_eq!(rx.used(&mem, 0), [(0x8000, frame.len())]);
+        tx.send_frame(&mut net, &mem, b"probed");
+        assert_eq!(net.interface.sent.len(), 1);
+    }
+
+    #[test]
+    fn stats() {
+        let mem = guest_memory(0x20000);
+        let mut net = new_net(&mem);
+        let stats = net.stats();
+
+        // Frames received before the driver is ready are dropped.
+        net.interface.received.extend([rx_frame(60), rx_frame(60)]);
+        net.process_tap().unwrap();
+        assert_eq!(stats.counters().rx_dropped_no_buffer, 2);
+
+        // One buffer fits a frame, the next one is too small, and there is no third one.
+        // Without mergeable buffers, the frame is truncated to it.
+        let (mut rx, mut tx) = driver_init(&mut net, &mem, SINGLE_BUFFER_FEATURES);
+        rx.post_buffer(&mem, 0x8000, 2048);
+        rx.post_buffer(&mem, 0x9000, 16);
+        net.interface
+            .received
+            .extend([rx_frame(100), rx_frame(100), rx_frame(100)]);
+        net.process_tap().unwrap();
+
+        for payload in [&b"a"[..], b"frame", b"sent by", b"the guest"] {
+            tx.send_frame(&mut net, &mem, payload);
         }
+
+        assert_eq!(
+            stats.counters(),
+            NetCounters {
+                rx_bytes: 100,
+                rx_packets: 1,
+                rx_dropped_no_buffer: 3,
+                rx_dropped_oversize: 1,
+                rx_dropped_fault: 0,
+                rx_dropped_malformed: 0,
+                rx_budget_exhausted: 0,
+                tx_bytes: 22,
+                tx_packets: 4,
+                tx_errors: 0,
+                tx_dropped_offload: 0,
+                tx_dropped_fault: 0,
+                tx_dropped_malformed: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn memory_faults() {
+        let mem = guest_memory(0x20000);
+        let mut net = new_net(&mem);
+        let stats = net.stats();
+        let (mut rx, mut tx) = driver_init(&mut net, &mem, SINGLE_BUFFER_FEATURES);
+
+        // A receive buffer straddling the end of the guest memory drops its frame, the
+        // next buffer gets the next one.
+        rx.post_buffer(&mem, 0x1ff00, 0x1000);
+        rx.post_buffer(&mem, 0x8000, 2048);
+        let frame = rx_frame(60);
+        net.interface
+            .received
+            .extend([rx_frame(300), frame.clone()]);
+        net.process_tap().unwrap();
+        assert_eq!(rx.used(&mem, 0), [(0x1ff00, 0), (0x8000, frame.len())]);
+
+        // Same for a frame sent from past the end.
+        tx.add_chain(&mem, &[(0x30000, 64)], 0);
+        write_register(&mut net, 0x50, 1);
+        tx.send_frame(&mut net, &mem, b"next");
+        assert_eq!(tx.used(&mem, 0)[0], (0x30000, 0));
+        assert_eq!(tx.used_index(&mem), 2);
+        assert_eq!(net.interface.sent.len(), 1);
+
+        let counters = stats.counters();
+        assert_eq!(counters.rx_dropped_fault, 1);
+        assert_eq!(counters.rx_packets, 1);
+        assert_eq!(counters.tx_dropped_fault, 1);
+        assert_eq!(counters.tx_packets, 1);
+
+        // Too many in a row fail the device, which then drops the frames.
+        for index in 0..MAX_CONSECUTIVE_FAULTS {
+            assert!(!net.failed());
+            rx.post_buffer(&mem, 0x30000 + u64::from(index) * 0x1000, 2048);
+            net.interface.received.push_back(frame.clone());
+            net.process_tap().unwrap();
+        }
+        assert!(net.failed());
+        rx.post_buffer(&mem, 0x8000, 2048);
+        net.interface.received.push_back(frame.clone());
+        net.process_tap().unwrap();
+        assert_eq!(stats.counters().rx_dropped_fault, 65);
+        assert_eq!(stats.counters().rx_dropped_no_buffer, 1);
+
+        // Until the driver resets it.
+        write_register(&mut net, 0x70, 0);
+        assert!(!net.failed());
+        let (mut rx, _) = driver_init(&mut net, &mem, SINGLE_BUFFER_FEATURES);
+        rx.post_buffer(&mem, 0x8000, 2048);
+        net.interface.received.push_back(frame.clone());
+        net.process_tap().unwrap();
+        assert_eq!(rx.used(&mem, 0), [(0x8000, frame.len())]);
+    }
+
+    #[test]
+    fn invalid_avail_index() {
+        let mem = guest_memory(0x20000);
+        let mut net = new_net(&mem);
+        let (_, mut tx) = driver_init(&mut net, &mem, SINGLE_BUFFER_FEATURES);
+
+        // An index more than a queue ahead fails the device, rather than the vCPU
+        // notifying it.
+        tx.set_avail_index(&mem, QUEUE_SIZE + 1);
+        write_register(&mut net, 0x50, 1);
+        assert!(net.failed());
+        assert!(net.interface.sent.is_empty());
+        assert_eq!(tx.used_index(&mem), 0);
+    }
+
+    #[test]
+    fn malformed_chains() {
+        let mem = guest_memory(0x20000);
+        let mut net = new_net(&mem);
+        let stats = net.stats();
+        let (mut rx, mut tx) = driver_init(&mut net, &mem, SINGLE_BUFFER_FEATURES);
+
+        // A receive chain looping on itself drops its frame, the next buffer gets the next
+        // one.
+        let head = rx.post_buffer(&mem, 0x8000, 32);
+        rx.link(&mem, head, head);
+        rx.post_buffer(&mem, 0x9000, 2048);
+        let frame = rx_frame(60);
+        net.interface
+            .received
+            .extend([rx_frame(1000), frame.clone()]);
+        net.process_tap().unwrap();
+        assert_eq!(rx.used(&mem, 0), [(0x8000, 0), (0x9000, frame.len())]);
+
+        // Sent frames are dropped when their chain loops, is larger than a frame, or has too
+        // many descriptors.
+        let head = tx.add_chain(&mem, &[(0x10000, 64)], 0);
+        tx.link(&mem, head, head);
+        tx.add_chain(
+            &mem,
+            &[(0x10000, 0x8000), (0x10000, 0x8000), (0x10000, 0x1000)],
+            0,
+        );
+        let buffers = [(0x10000, 1); MAX_CHAIN_DESCRIPTORS + 1];
+        tx.add_indirect_chain(&mem, 0x18000, &buffers, 0);
+        write_register(&mut net, 0x50, 1);
+        tx.send_frame(&mut net, &mem, b"next");
+        let used: Vec<_> = tx.used(&mem, 0).iter().map(|(_, len)| *len).collect();
+        assert_eq!(used, [0, 0, 0, bindings::VIRTIO_HDR_LEN + 4]);
+        assert_eq!(net.interface.sent.len(), 1);
+
+        let counters = stats.counters();
+        assert_eq!(counters.rx_dropped_malformed, 1);
+        assert_eq!(counters.rx_packets, 1);
+        assert_eq!(counters.tx_dropped_malformed, 3);
+        assert_eq!(counters.tx_packets, 1);
+        assert_eq!(counters.tx_dropped_fault, 0);
+
+        // Too many in a row fail the device.
+        for _ in 0..MAX_CONSECUTIVE_FAULTS {
+            assert!(!net.failed());
+            let head = tx.add_chain(&mem, &[(0x10000, 64)], 0);
+            tx.link(&mem, head, head);
+            write_register(&mut net, 0x50, 1);
+        }
+        assert!(net.failed());
+        assert_eq!(stats.counters().tx_dropped_malformed, 67);
+    }
+
+    #[test]
+    fn rx_chains() {
+        let mem = guest_memory(0x20000);
+        let mut net = new_net(&mem);
+        let (mut rx, _) = driver_init(&mut net, &mem, SINGLE_BUFFER_FEATURES);
+
+        // A buffer of the exact frame size.
+        let frame = rx_frame(64);
+        rx.post_buffer(&mem, 0x8000, frame.len() as u32);
+        // A chain splitting the header, then the payload.
+        let split = [(0x9000, 4), (0x9100, 8), (0x9200, 16), (0x9300, 1500)];
+        rx.add_chain(&mem, &split, VRING_DESC_F_WRITE as u16);
+        // And a buffer too small for the header.
+        rx.post_buffer(&mem, 0xa000, 8);
+
+        let mut frames: Vec<_> = (0..3u8)
+            .map(|index| {
+                let mut frame = frame.clone();
+                frame[bindings::VIRTIO_HDR_LEN..].fill(index);
+                frame
+            })
+            .collect();
+        net.interface.received.extend(frames.clone());
+        net.process_tap().unwrap();
+
+        // The device sets num_buffers, as the interface does not.
+        for frame in frames.iter_mut() {
+            let offset = bindings::VIRTIO_HDR_NUM_BUFFERS_OFFSET;
+            frame[offset..offset + 2].copy_from_slice(&1u16.to_le_bytes());
+        }
+        let used = rx.used(&mem, 0);
+        assert_eq!(used, [(0x8000, 76), (0x9000, 76), (0xa000, 8)]);
+        assert_eq!(read_buffers(&mem, &[(0x8000, 76)]), frames[0]);
+        let split: Vec<_> = split
+            .iter()
+            .map(|(addr, len)| (*addr, *len as usize))
+            .collect();
+        assert_eq!(read_buffers(&mem, &split)[..76], frames[1]);
+        assert_eq!(read_buffers(&mem, &[(0xa000, 8)]), frames[2][..8]);
+
+        let counters = net.stats().counters();
+        assert_eq!(counters.rx_packets, 2);
+        assert_eq!(counters.rx_bytes, 128);
+        assert_eq!(counters.rx_dropped_oversize, 1);
+    }
+
+    #[test]
+    fn rx_notifications() {
+        let mem = guest_memory(0x20000);
+        let mut net = new_net(&mem);
+        let (mut rx, _) = driver_init(&mut net, &mem, VIRTIO_FEATURES);
+
+        // As if the device had turned the kicks off while busy.
+        net.device_config.queues[0]
+            .disable_notification(&*mem)
+            .unwrap();
+        assert_eq!(rx.used_flags(&mem), VRING_USED_F_NO_NOTIFY as u16);
+
+        // Nothing to receive: the guest gets no interrupt.
+        net.process_tap().unwrap();
+        assert_eq!(net.guest_irq_fd.read().ok(), None);
+        assert_eq!(net.device_config.interrupt_status.load(Ordering::SeqCst), 0);
+
+        // The queue is empty: the frame is dropped, and the device wants a kick once there
+        // are buffers again. Still without interrupting the guest.
+        net.interface.received.push_back(rx_frame(60));
+        net.process_tap().unwrap();
+        assert_eq!(rx.used_index(&mem), 0);
+        assert_eq!(rx.used_flags(&mem), 0);
+        assert_eq!(net.guest_irq_fd.read().ok(), None);
+        assert_eq!(net.stats().counters().rx_dropped_no_buffer, 1);
+
+        // Once a frame is received, it does.
+        rx.post_buffer(&mem, 0x8000, 2048);
+        net.interface.received.push_back(rx_frame(60));
+        net.process_tap().unwrap();
+        assert_eq!(rx.used_index(&mem), 1);
+        assert_eq!(net.guest_irq_fd.read().unwrap(), 1);
+        assert_ne!(
+            net.device_config.interrupt_status.load(Ordering::SeqCst) & VIRTIO_MMIO_INT_VRING,
+            0
+        );
+    }
+
+    #[test]
+    fn interrupt_ack() {
+        let mem = guest_memory(0x20000);
+        let mut net = new_net(&mem);
+        let (mut rx, mut tx) = driver_init(&mut net, &mem, SINGLE_BUFFER_FEATURES);
+
+        for round in 0..32u64 {
+            for buffer in 0..2 {
+                rx.post_buffer(&mem, 0x8000 + (round % 4 * 2 + buffer) * 0x1000, 2048);
+            }
+            net.interface.received.push_back(rx_frame(60));
+            net.process_tap().unwrap();
+            assert_eq!(net.guest_irq_fd.read().unwrap(), 1);
+
+            // The interrupt handler of the Linux driver reads the status, and acks it. A
+            // second frame, and every other round a link status update, come in between.
+            let status = read_register(&net, 0x60);
+            assert_eq!(status, u32::from(VIRTIO_MMIO_INT_VRING));
+            net.interface.received.push_back(rx_frame(60));
+            net.process_tap().unwrap();
+            let link_changed = round % 2 == 1;
+            if link_changed {
+                net.set_link(true);
+            }
+            write_register(&mut net, 0x64, status);
+
+            // Then it handles the used ring, where both frames are.
+            assert_eq!(u64::from(rx.used_index(&mem)), (round + 1) * 2);
+
+            // Each event interrupted the guest. The configuration change is still pending,
+            // the second frame was handled along with the first one.
+            let interrupts = if link_changed { 2 } else { 1 };
+            assert_eq!(net.guest_irq_fd.read().unwrap(), interrupts);
+            let status = read_register(&net, 0x60);
+            if link_changed {
+                assert_eq!(status, u32::from(VIRTIO_MMIO_INT_CONFIG));
+            } else {
+                assert_eq!(status, 0);
+            }
+            write_register(&mut net, 0x64, status);
+            assert_eq!(read_register(&net, 0x60), 0);
+        }
+        assert_eq!(net.stats().counters().rx_packets, 64);
+
+        // The sent frames are reported with the same bit.
+        tx.send_frame(&mut net, &mem, b"frame");
+        assert_eq!(net.guest_irq_fd.read().unwrap(), 1);
+        assert_eq!(read_register(&net, 0x60), u32::from(VIRTIO_MMIO_INT_VRING));
+    }
+
+    #[test]
+    fn tx_chains() {
+        let mem = guest_memory(0x20000);
+        let mut net = new_net(&mem);
+        let (_, mut tx) = driver_init(&mut net, &mem, VIRTIO_FEATURES);
+
+        // The header in its own descriptor, and the payload in two.
+        let header = [0u8; bindings::VIRTIO_HDR_LEN];
+        tx.send(&mut net, &mem, &[&header[..], b"split ", b"payload"]);
+        // A chain shorter than the header is rejected, without stopping the queue.
+        tx.send(&mut net, &mem, &[&header[..4]]);
+        tx.send_frame(&mut net, &mem, b"after");
+
+        let payloads: Vec<_> = net
+            .interface
+            .sent
+            .iter()
+            .map(|frame| &frame[bindings::VIRTIO_HDR_LEN..])
+            .collect();
+        assert_eq!(payloads, [&b"split payload"[..], b"after"]);
+        // The driver gets all the chains back.
+        assert_eq!(tx.used_index(&mem), 3);
+
+        let counters = net.stats().counters();
+        assert_eq!(counters.tx_packets, 2);
+        assert_eq!(counters.tx_bytes, 18);
+        assert_eq!(counters.tx_errors, 1);
+    }
+
+    // A GSO frame, as large as the tap gives them, with a recognizable payload.
+    fn gso_frame() -> Vec<u8> {
+        let mut frame = rx_frame(0);
+        frame.extend((0..65536).map(|i| (i % 251) as u8));
+        frame
+    }
+
+    #[test]
+    fn mergeable_rx() {
+        let mem = guest_memory(0x80000);
+        let mut net = new_net(&mem);
+        let (mut rx, _) = driver_init(&mut net, &mem, VIRTIO_FEATURES);
+        assert_ne!(net.negotiated_features() & (1 << VIRTIO_NET_F_MRG_RXBUF), 0);
+
+        // The driver posts page sized buffers, the frame spans 9 of them.
+        const BUFFER: u64 = 0x20000;
+        const BUFFER_SIZE: u32 = 0x2000;
+        for index in 0..u64::from(QUEUE_SIZE) {
+            rx.post_buffer(&mem, BUFFER + index * u64::from(BUFFER_SIZE), BUFFER_SIZE);
+        }
+        let frame = gso_frame();
+        net.interface.received.push_back(frame.clone());
+        net.process_tap().unwrap();
+
+        let used = rx.used(&mem, 0);
+        assert_eq!(used.len(), 9);
+        let received = read_buffers(&mem, &used);
+        assert_eq!(num_buffers(&received), 9);
+        assert_eq!(received.len(), frame.len());
+        assert_eq!(
+            received[bindings::VIRTIO_HDR_LEN..],
+            frame[bindings::VIRTIO_HDR_LEN..]
+        );
+
+        // The 7 buffers left are not enough for the next one, which is dropped without
+        // taking them.
+        net.interface.received.push_back(frame);
+        net.process_tap().unwrap();
+        assert_eq!(rx.used_index(&mem), 9);
+        assert_eq!(net.device_config.queues[0].next_avail(), 9);
+
+        // While a small frame fits in one of them.
+        net.interface.received.push_back(rx_frame(60));
+        net.process_tap().unwrap();
+        let used = rx.used(&mem, 9);
+        assert_eq!(used.len(), 1);
+        assert_eq!(num_buffers(&read_buffers(&mem, &used)), 1);
+
+        let counters = net.stats().counters();
+        assert_eq!(counters.rx_packets, 2);
+        assert_eq!(counters.rx_bytes, 65536 + 60);
+        assert_eq!(counters.rx_dropped_no_buffer, 1);
+        assert_eq!(counters.rx_dropped_oversize, 0);
+    }
+
+    #[test]
+    fn single_buffer_rx() {
+        let mem = guest_memory(0x80000);
+        let mut net = new_net(&mem);
+        let (mut rx, _) = driver_init(&mut net, &mem, SINGLE_BUFFER_FEATURES);
+
+        // Without mergeable buffers, the driver posts buffers for whole GSO frames.
+        rx.post_buffer(&mem, 0x20000, 0x10100);
+        rx.post_buffer(&mem, 0x40000, 0x1000);
+        let frame = gso_frame();
+        net.interface
+            .received
+            .extend([frame.clone(), frame.clone(), rx_frame(60)]);
+        net.process_tap().unwrap();
+
+        // The second frame does not fit.
+        let used = rx.used(&mem, 0);
+        assert_eq!(used, [(0x20000, frame.len()), (0x40000, 0x1000)]);
+        let received = read_buffers(&mem, &used[..1]);
+        assert_eq!(num_buffers(&received), 1);
+        assert_eq!(
+            received[bindings::VIRTIO_HDR_LEN..],
+            frame[bindings::VIRTIO_HDR_LEN..]
+        );
+
+        let counters = net.stats().counters();
+        assert_eq!(counters.rx_packets, 1);
+        assert_eq!(counters.rx_dropped_oversize, 1);
+        assert_eq!(counters.rx_dropped_no_buffer, 1);
+    }
+
+    fn link_status(net: &TestNet) -> u16 {
+        let mut status = [0u8; 2];
+        net.read(0x100 + CONFIG_STATUS_OFFSET as u64, &mut status);
+        u16::from_le_bytes(status)
+    }
+
+    #[test]
+    fn reconnect() {
+        let mem = guest_memory(0x20000);
+        let mut net = new_net(&mem);
+        let (mut rx, mut tx) = driver_init(&mut net, &mem, VIRTIO_FEATURES);
+        assert_eq!(link_status(&net), VIRTIO_NET_S_LINK_UP as u16);
+
+        // The tap is deleted: the driver sees the link go down.
+        net.interface.error = Some(libc::EBADFD);
+        net.process_tap().unwrap();
+        assert!(!net.link_up());
+        assert_eq!(link_status(&net), 0);
+        assert_eq!(net.guest_irq_fd.read().unwrap(), 1);
+        assert_ne!(
+            net.device_config.interrupt_status.load(Ordering::SeqCst) & VIRTIO_MMIO_INT_CONFIG,
+            0
+        );
+        assert!(net.reconnect_timer.is_armed().unwrap());
+
+        // The frames sent meanwhile are lost, the driver gets their buffers back.
+        tx.send_frame(&mut net, &mem, b"lost");
+        assert_eq!(tx.used_index(&mem), 1);
+        assert_eq!(net.stats().counters().tx_errors, 1);
+
+        // It cannot be opened yet.
+        assert!(!net.reconnect());
+        assert!(!net.link_up());
+
+        // Until it is back, with the offloads the driver negotiated.
+        net.interface.reconnectable = true;
+        assert!(net.reconnect());
+        assert!(net.link_up());
+        assert_eq!(link_status(&net), VIRTIO_NET_S_LINK_UP as u16);
+        assert_eq!(
+            *net.interface.offloads.lock().unwrap(),
+            Some(VIRTIO_FEATURES)
+        );
+        assert!(!net.reconnect_timer.is_armed().unwrap());
+
+        rx.post_buffer(&mem, 0x8000, 2048);
+        net.interface.received.push_back(rx_frame(60));
+        net.process_tap().unwrap();
+        assert_eq!(rx.used_index(&mem), 1);
+        tx.send_frame(&mut net, &mem, b"back");
+        assert_eq!(net.interface.sent.len(), 1);
     }
 }
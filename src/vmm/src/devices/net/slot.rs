@@ -0,0 +1,210 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Reserved virtio-mmio windows, which virtio-net devices are plugged into once the VM runs.
+//!
+//! virtio-mmio has no hot-plug notification, and the guest only probes the devices it was
+//! told about at boot. Each slot is announced at boot, with its own IRQ, as a virtio-mmio
+//! device with the placeholder device ID 0: the Linux driver declines it, and leaves its
+//! platform device unbound. Once a device is plugged in, the guest probes it again, e.g.:
+//!
+//! ```text
+//! # echo virtio-mmio.1 > /sys/bus/platform/drivers/virtio-mmio/bind
+//! ```
+//!
+//! And unbinds it before it is unplugged. The driver then resets the device, which tells
+//! the VMM it is no longer in use.
+
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use virtio_device::VirtioDeviceActions;
+use vm_device::bus::{MmioAddress, MmioAddressOffset};
+use vm_device::MutDeviceMmio;
+use vm_memory::GuestAddressSpace;
+use vmm_sys_util::eventfd::EventFd;
+
+use super::interface::Interface;
+use super::worker::{spawn_worker, WorkerHandle};
+use super::{VirtioNet, VirtioNetError};
+
+// Registers of an empty slot. The others read as 0, starting with the device ID.
+const VIRTIO_MMIO_MAGIC_VALUE: MmioAddressOffset = 0x0;
+const VIRTIO_MMIO_VERSION: MmioAddressOffset = 0x4;
+// "virt", in little endian.
+const VIRTIO_MMIO_MAGIC: u32 = 0x7472_6976;
+const VIRTIO_MMIO_MODERN: u32 = 2;
+
+// A device plugged into a slot, and the thread running its I/O.
+struct PluggedNet<M: GuestAddressSpace + Clone + Send, I: Interface> {
+    tap: String,
+    net: Arc<Mutex<VirtioNet<M, I>>>,
+    worker: WorkerHandle,
+}
+
+/// A virtio-mmio window, with its IRQ, holding a virtio-net device or nothing.
+pub(crate) struct NetSlot<M: GuestAddressSpace + Clone + Send, I: Interface> {
+    name: String,
+    irq_fd: EventFd,
+    device: Option<PluggedNet<M, I>>,
+}
+
+impl<M, I> NetSlot<M, I>
+where
+    M: GuestAddressSpace + Clone + Send + 'static,
+    I: Interface + 'static,
+{
+    /// An empty slot, which the guest knows as the platform device `name`, and whose
+    /// devices signal `irq_fd`.
+    pub fn new(name: String, irq_fd: EventFd) -> Self {
+        NetSlot {
+            name,
+            irq_fd,
+            device: None,
+        }
+    }
+
+    /// The name of the guest platform device.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The interrupt eventfd for a new device.
+    pub fn irq_fd(&self) -> io::Result<EventFd> {
+        self.irq_fd.try_clone()
+    }
+
+    /// The tap of the device in the slot, if any.
+    pub fn tap(&self) -> Option<&str> {
+        self.device.as_ref().map(|device| device.tap.as_str())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.device.is_none()
+    }
+
+    /// Whether the guest driver uses the device in the slot.
+    pub fn driver_attached(&self) -> bool {
+        self.device
+            .as_ref()
+            .is_some_and(|device| device.net.lock().unwrap().driver_attached())
+    }
+
+    /// Plug `net`, on `tap`, into the empty slot, and start its I/O thread.
+    pub fn plug(&mut self, tap: String, net: VirtioNet<M, I>) -> io::Result<()> {
+        assert!(
+            self.is_empty(),
+            "plugging into the occupied slot {}",
+            self.name
+        );
+
+        let net = Arc::new(Mutex::new(net));
+        let worker = spawn_worker(net.clone())?;
+        self.device = Some(PluggedNet { tap, net, worker });
+
+        Ok(())
+    }
+
+    /// Reset the device in the slot, if any, for the driver to set it up again, e.g. once
+    /// the guest restarted. It stays plugged.
+    pub fn reset(&self) -> Result<(), VirtioNetError> {
+        match self.device.as_ref() {
+            Some(device) => device.net.lock().unwrap().reset(),
+            None => Ok(()),
+        }
+    }
+
+    /// Pause the device in the slot, if any, or resume it, see [`VirtioNet::set_paused`].
+    pub fn set_paused(&self, paused: bool) {
+        if let Some(device) = self.device.as_ref() {
+            device.net.lock().unwrap().set_paused(paused);
+        }
+    }
+
+    /// Stop the device in the slot, and drop it along with its interface. The driver
+    /// should no longer use it.
+    pub fn unplug(&mut self) {
+        if let Some(device) = self.device.take() {
+            if device.worker.stop().is_err() {
+                println!("virtio-net worker of {} panicked", self.name);
+            }
+        }
+    }
+}
+
+impl<M, I> MutDeviceMmio for NetSlot<M, I>
+where
+    M: GuestAddressSpace + Clone + Send + 'static,
+    I: Interface + 'static,
+{
+    fn mmio_read(&mut self, base: MmioAddress, offset: MmioAddressOffset, data: &mut [u8]) {
+        if let Some(device) = self.device.as_ref() {
+            device.net.lock().unwrap().mmio_read(base, offset, data);
+            return;
+        }
+
+        let value = match offset {
+            VIRTIO_MMIO_MAGIC_VALUE => VIRTIO_MMIO_MAGIC,
+            VIRTIO_MMIO_VERSION => VIRTIO_MMIO_MODERN,
+            _ => 0,
+        }
+        .to_le_bytes();
+        let len = data.len().min(value.len());
+        data[..len].copy_from_slice(&value[..len]);
+    }
+
+    fn mmio_write(&mut self, base: MmioAddress, offset: MmioAddressOffset, data: &[u8]) {
+        // An empty slot ignores the writes.
+        if let Some(device) = self.device.as_ref() {
+            device.net.lock().unwrap().mmio_write(base, offset, data);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::{driver_init, guest_memory, new_net, MockInterface};
+    use super::super::VIRTIO_FEATURES;
+    use super::*;
+
+    use vm_memory::GuestMemoryMmap;
+
+    fn read_register(slot: &mut NetSlot<Arc<GuestMemoryMmap>, MockInterface>, offset: u64) -> u32 {
+        let mut data = [0u8; 4];
+        slot.mmio_read(MmioAddress(0), offset, &mut data);
+        u32::from_le_bytes(data)
+    }
+
+    #[test]
+    fn plug() {
+        let mem = guest_memory(0x20000);
+        let irq_fd = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        let mut slot = NetSlot::new("virtio-mmio.1".to_string(), irq_fd);
+
+        // The guest driver finds a placeholder.
+        assert_eq!(read_register(&mut slot, 0x0), VIRTIO_MMIO_MAGIC);
+        assert_eq!(read_register(&mut slot, 0x4), 2);
+        assert_eq!(read_register(&mut slot, 0x8), 0);
+        slot.mmio_write(MmioAddress(0), 0x70, &1u32.to_le_bytes());
+        assert_eq!(read_register(&mut slot, 0x70), 0);
+        assert!(!slot.driver_attached());
+
+        slot.plug("tap3".to_string(), new_net(&mem)).unwrap();
+        assert_eq!(slot.tap(), Some("tap3"));
+        // Then the virtio-net device, once probed again.
+        assert_eq!(read_register(&mut slot, 0x8), 1);
+        let net = slot.device.as_ref().unwrap().net.clone();
+        driver_init(&mut net.lock().unwrap(), &mem, VIRTIO_FEATURES);
+        assert!(slot.driver_attached());
+
+        // The driver unbinds, and resets the device.
+        slot.mmio_write(MmioAddress(0), 0x70, &0u32.to_le_bytes());
+        assert!(!slot.driver_attached());
+        let weak = Arc::downgrade(&net);
+        drop(net);
+        slot.unplug();
+        assert!(slot.is_empty());
+        // The worker released it too.
+        assert!(weak.upgrade().is_none());
+        assert_eq!(read_register(&mut slot, 0x8), 0);
+    }
+}
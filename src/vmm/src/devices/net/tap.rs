@@ -10,16 +10,22 @@
 
 use std::fs::File;
 use std::io::{Error as IoError, Read, Result as IoResult, Write};
-use std::os::raw::{c_char, c_uint, c_ulong};
-use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
-
-use virtio_bindings::bindings::virtio_net::{VIRTIO_NET_F_CSUM, VIRTIO_NET_F_HOST_UFO};
+use std::os::raw::{c_char, c_int, c_uint, c_ulong};
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use virtio_bindings::bindings::virtio_net::{
+    VIRTIO_NET_F_GUEST_CSUM, VIRTIO_NET_F_GUEST_TSO4, VIRTIO_NET_F_GUEST_TSO6,
+    VIRTIO_NET_F_GUEST_UFO,
+};
 use vmm_sys_util::ioctl::{ioctl_with_mut_ref, ioctl_with_ref, ioctl_with_val};
 use vmm_sys_util::{ioctl_ioc_nr, ioctl_iow_nr};
 
 use super::bindings::{ifreq, TUN_F_CSUM, TUN_F_TSO4, TUN_F_TSO6, TUN_F_UFO};
 use super::interface::Interface;
-use super::VirtioNetError;
+use super::{VirtioNetError, OFFLOAD_FEATURES};
+use crate::config::{NetBackend, NetConfig};
 
 // As defined in the Linux UAPI:
 // https://elixir.bootlin.com/linux/v4.17/source/include/uapi/linux/if.h#L33
@@ -36,6 +42,10 @@ ioctl_iow_nr!(TUNSETIFF, TUNTAP, 202, ::std::os::raw::c_int);
 ioctl_iow_nr!(TUNSETOFFLOAD, TUNTAP, 208, ::std::os::raw::c_uint);
 ioctl_iow_nr!(TUNSETVNETHDRSZ, TUNTAP, 216, ::std::os::raw::c_int);
 
+// First wait before attaching to a busy tap again, doubled after each attempt.
+const ATTACH_RETRY_MIN: Duration = Duration::from_millis(10);
+const ATTACH_RETRY_MAX: Duration = Duration::from_millis(200);
+
 /// Handle for a network tap interface.
 ///
 /// For now, this simply wraps the file descriptor for the tap device so methods
@@ -47,45 +57,40 @@ pub struct Tap {
 }
 
 impl Tap {
-    fn virtio_flags_to_tuntap_flags(virtio_flags: u64) -> c_uint {
-        // Check if VIRTIO_NET_F_CSUM is set and set TUN_F_CSUM if so. Do the same for UFO, TSO6 and TSO4.
-        let mut flags = 0;
-        if virtio_flags & (1 << VIRTIO_NET_F_CSUM) != 0 {
-            flags |= TUN_F_CSUM;
-        }
-        if virtio_flags & (1 << VIRTIO_NET_F_HOST_UFO) != 0 {
-            flags |= TUN_F_UFO;
+    /// Open the tap `if_name`. While another process still holds its queue, e.g. the
+    /// previous VMM, retry for up to `attach_timeout`.
+    pub fn open_named(if_name: &str, attach_timeout: Option<Duration>) -> super::Result<Self> {
+        let deadline = attach_timeout.map(|timeout| Instant::now() + timeout);
+        let mut wait = ATTACH_RETRY_MIN;
+        loop {
+            match Tap::attach(if_name) {
+                Err(error @ VirtioNetError::TapBusy(_)) => {
+                    let left = deadline.map_or(Duration::ZERO, |deadline| {
+                        deadline.saturating_duration_since(Instant::now())
+                    });
+                    if left.is_zero() {
+                        return Err(error);
+                    }
+                    log::debug!("Tap {} is busy, retrying", if_name);
+                    thread::sleep(wait.min(left));
+                    wait = (wait * 2).min(ATTACH_RETRY_MAX);
+                }
+                result => return result,
+            }
         }
-        if virtio_flags & (1 << VIRTIO_NET_F_HOST_UFO) != 0 {
-            flags |= TUN_F_TSO4;
-        }
-        if virtio_flags & (1 << VIRTIO_NET_F_HOST_UFO) != 0 {
-            flags |= TUN_F_TSO6;
-        }
-
-        flags
     }
-}
-
-impl Interface for Tap {
-    fn activate(&self, virtio_flags: u64, virtio_header_size: usize) -> super::Result<()> {
-        let flags = Tap::virtio_flags_to_tuntap_flags(virtio_flags);
 
-        let ret = unsafe { ioctl_with_val(self, TUNSETOFFLOAD(), flags as c_ulong) };
-        if ret < 0 {
-            return Err(std::io::Error::last_os_error()).map_err(VirtioNetError::IoCtlError);
+    /// A tap attached elsewhere, e.g. by a privileged parent handing its file descriptor
+    /// over. It must be non-blocking, and attached with the `IFF_TAP | IFF_NO_PI |
+    /// IFF_VNET_HDR` flags.
+    pub fn from_fd(fd: OwnedFd) -> Self {
+        Tap {
+            tap_file: File::from(fd),
         }
-
-        // Safe because we know that our file is a valid tap device and we verify the result.
-        let ret = unsafe { ioctl_with_ref(self, TUNSETVNETHDRSZ(), &virtio_header_size) };
-        if ret < 0 {
-            return Err(std::io::Error::last_os_error()).map_err(VirtioNetError::IoCtlError);
-        }
-
-        Ok(())
     }
 
-    fn open_named(if_name: &str) -> super::Result<Self> {
+    // Attach to the tap once.
+    fn attach(if_name: &str) -> super::Result<Self> {
         let terminated_if_name = build_terminated_if_name(if_name)?;
 
         let fd = unsafe {
@@ -97,7 +102,7 @@ impl Interface for Tap {
             )
         };
         if fd < 0 {
-            return Err(IoError::last_os_error()).map_err(VirtioNetError::IoError);
+            return Err(tap_error(if_name, IoError::last_os_error()));
         }
         // We just checked that the fd is valid.
         let tuntap = unsafe { File::from_raw_fd(fd) };
@@ -106,11 +111,106 @@ impl Interface for Tap {
             .if_name(&terminated_if_name)
             .flags((IFF_TAP | IFF_NO_PI | IFF_VNET_HDR) as i16)
             .execute(&tuntap, TUNSETIFF())
-            .unwrap();
+            .map_err(|e| match e {
+                VirtioNetError::IoCtlError(e) => tap_error(if_name, e),
+                e => e,
+            })?;
 
-        // Safe since only the name is accessed, and it's cloned out.
         Ok(Tap { tap_file: tuntap })
     }
+
+    fn virtio_flags_to_tuntap_flags(virtio_flags: u64) -> c_uint {
+        // The tap offloads are what it may hand over to the guest, so they follow what the
+        // driver accepts on receive. The segmentation offloads need the checksum one, the
+        // kernel rejects them alone.
+        let mut flags = 0;
+        if virtio_flags & (1 << VIRTIO_NET_F_GUEST_CSUM) == 0 {
+            return flags;
+        }
+        flags |= TUN_F_CSUM;
+        if virtio_flags & (1 << VIRTIO_NET_F_GUEST_UFO) != 0 {
+            flags |= TUN_F_UFO;
+        }
+        if virtio_flags & (1 << VIRTIO_NET_F_GUEST_TSO4) != 0 {
+            flags |= TUN_F_TSO4;
+        }
+        if virtio_flags & (1 << VIRTIO_NET_F_GUEST_TSO6) != 0 {
+            flags |= TUN_F_TSO6;
+        }
+
+        flags
+    }
+
+    /// Let the tap hand the frames over with the `TUN_F_*` offloads in `flags`, checksums
+    /// left to complete and segments to split.
+    pub(crate) fn set_offload(&self, flags: c_uint) -> IoResult<()> {
+        // Safe because we know that our file is a valid tap device and we verify the result.
+        let ret = unsafe { ioctl_with_val(self, TUNSETOFFLOAD(), flags as c_ulong) };
+        if ret < 0 {
+            return Err(IoError::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Set the size of the virtio header before each frame read from and written to the tap.
+    pub(crate) fn set_vnet_hdr_size(&self, size: c_int) -> IoResult<()> {
+        // Safe because we know that our file is a valid tap device and we verify the result.
+        let ret = unsafe { ioctl_with_ref(self, TUNSETVNETHDRSZ(), &size) };
+        if ret < 0 {
+            return Err(IoError::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+impl Interface for Tap {
+    fn activate(&self, virtio_flags: u64, virtio_header_size: usize) -> super::Result<u64> {
+        let flags = Tap::virtio_flags_to_tuntap_flags(virtio_flags);
+        let mut enabled = virtio_flags;
+
+        match self.set_offload(flags) {
+            Err(e) if flags != 0 => {
+                // Some taps refuse the offloads, e.g. inside containers. Without them, the
+                // device completes the checksums, and the frames are still right on the wire.
+                log::warn!("Tap refused offloads {:#x}: {}, going without", flags, e);
+                enabled &= !OFFLOAD_FEATURES;
+                self.set_offload(0).map_err(VirtioNetError::IoCtlError)?;
+            }
+            result => result.map_err(VirtioNetError::IoCtlError)?,
+        }
+
+        self.set_vnet_hdr_size(virtio_header_size as c_int)
+            .map_err(VirtioNetError::IoCtlError)?;
+
+        Ok(enabled)
+    }
+
+    fn open_config(config: &NetConfig) -> super::Result<Self> {
+        let if_name = match &config.backend {
+            NetBackend::Tap(if_name) => if_name,
+            NetBackend::User(_) => return Err(VirtioNetError::InvalidBackend),
+        };
+        Tap::open_named(if_name, config.attach_timeout)
+    }
+
+    fn reconnect(&self, config: &NetConfig) -> super::Result<Self> {
+        // Every second, from the device worker: it does not wait for a busy tap.
+        match &config.backend {
+            NetBackend::Tap(if_name) => Tap::open_named(if_name, None),
+            NetBackend::User(_) => Err(VirtioNetError::InvalidBackend),
+        }
+    }
+}
+
+// The error of attaching to the tap `if_name`, by cause.
+fn tap_error(if_name: &str, error: IoError) -> VirtioNetError {
+    let if_name = if_name.to_string();
+    match error.raw_os_error() {
+        Some(libc::EBUSY) => VirtioNetError::TapBusy(if_name),
+        Some(libc::EPERM | libc::EACCES) => VirtioNetError::TapPermissionDenied(if_name),
+        Some(libc::ENODEV | libc::ENOENT) => VirtioNetError::TapUnavailable(if_name),
+        _ => VirtioNetError::IoCtlError(error),
+    }
 }
 
 // Returns a byte vector representing the contents of a null terminated C string which
@@ -186,3 +286,112 @@ impl AsRawFd for Tap {
         self.tap_file.as_raw_fd()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use virtio_bindings::bindings::virtio_net::{VIRTIO_NET_F_HOST_TSO4, VIRTIO_NET_F_HOST_UFO};
+    use vmm_sys_util::ioctl_ior_nr;
+
+    use crate::devices::net::bindings::VIRTIO_HDR_LEN;
+
+    ioctl_ior_nr!(TUNGETVNETHDRSZ, TUNTAP, 215, ::std::os::raw::c_int);
+
+    // The size of the virtio header the tap expects.
+    fn vnet_hdr_size(tap: &Tap) -> c_int {
+        let mut size: c_int = 0;
+        // Safe because the tap is valid, and we check the result.
+        let ret = unsafe { ioctl_with_mut_ref(tap, TUNGETVNETHDRSZ(), &mut size) };
+        assert_eq!(ret, 0, "TUNGETVNETHDRSZ: {}", IoError::last_os_error());
+        size
+    }
+
+    #[test]
+    fn tuntap_flags() {
+        assert_eq!(Tap::virtio_flags_to_tuntap_flags(0), 0);
+        // What the device takes from the guest does not matter to the tap.
+        assert_eq!(
+            Tap::virtio_flags_to_tuntap_flags(
+                (1 << VIRTIO_NET_F_HOST_TSO4) | (1 << VIRTIO_NET_F_HOST_UFO)
+            ),
+            0
+        );
+        // Nor do the segmentation offloads without the checksum one.
+        assert_eq!(
+            Tap::virtio_flags_to_tuntap_flags(1 << VIRTIO_NET_F_GUEST_TSO4),
+            0
+        );
+
+        assert_eq!(
+            Tap::virtio_flags_to_tuntap_flags(
+                (1 << VIRTIO_NET_F_GUEST_CSUM) | (1 << VIRTIO_NET_F_GUEST_TSO4)
+            ),
+            TUN_F_CSUM | TUN_F_TSO4
+        );
+        assert_eq!(
+            Tap::virtio_flags_to_tuntap_flags(
+                (1 << VIRTIO_NET_F_GUEST_CSUM)
+                    | (1 << VIRTIO_NET_F_GUEST_TSO6)
+                    | (1 << VIRTIO_NET_F_GUEST_UFO)
+            ),
+            TUN_F_CSUM | TUN_F_TSO6 | TUN_F_UFO
+        );
+    }
+    #[test]
+    fn attach_errors() {
+        let error = |errno| tap_error("tap0", IoError::from_raw_os_error(errno));
+        assert!(matches!(error(libc::EBUSY), VirtioNetError::TapBusy(name) if name == "tap0"));
+        assert!(matches!(
+            error(libc::EPERM),
+            VirtioNetError::TapPermissionDenied(_)
+        ));
+        assert!(matches!(
+            error(libc::ENODEV),
+            VirtioNetError::TapUnavailable(_)
+        ));
+        assert!(matches!(error(libc::EINVAL), VirtioNetError::IoCtlError(_)));
+    }
+
+    #[test]
+    #[ignore = "needs root"]
+    fn offloads() {
+        let tap = Tap::open_named("lumper-offl0", None).unwrap();
+
+        // The kernel rejects the segmentation offloads without the checksum one.
+        assert!(tap.set_offload(TUN_F_TSO4).is_err());
+        tap.set_offload(TUN_F_CSUM | TUN_F_TSO4).unwrap();
+        tap.set_vnet_hdr_size(10).unwrap();
+        assert_eq!(vnet_hdr_size(&tap), 10);
+
+        // Activating sets both up.
+        let features = (1 << VIRTIO_NET_F_GUEST_CSUM) | (1 << VIRTIO_NET_F_GUEST_TSO6);
+        assert_eq!(tap.activate(features, VIRTIO_HDR_LEN).unwrap(), features);
+        assert_eq!(vnet_hdr_size(&tap), VIRTIO_HDR_LEN as c_int);
+        assert_eq!(tap.activate(0, VIRTIO_HDR_LEN).unwrap(), 0);
+    }
+
+    #[test]
+    #[ignore = "needs root"]
+    fn busy_tap() {
+        let tap = Tap::open_named("lumper-busy0", None).unwrap();
+        // The queue is taken, until the first one goes.
+        assert!(matches!(
+            Tap::open_named("lumper-busy0", None),
+            Err(VirtioNetError::TapBusy(_))
+        ));
+        let start = Instant::now();
+        assert!(matches!(
+            Tap::open_named("lumper-busy0", Some(Duration::from_millis(100))),
+            Err(VirtioNetError::TapBusy(_))
+        ));
+        assert!(start.elapsed() >= Duration::from_millis(100));
+
+        let release = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            drop(tap);
+        });
+        Tap::open_named("lumper-busy0", Some(Duration::from_secs(2))).unwrap();
+        release.join().unwrap();
+    }
+}
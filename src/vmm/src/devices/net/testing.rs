@@ -0,0 +1,398 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Test harness for the virtio-net device: an interface over in-memory frames, and the
+//! driver side of the queues, laid out in guest memory.
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::os::fd::{AsRawFd, RawFd};
+use std::sync::{Arc, Mutex};
+
+use virtio_bindings::bindings::virtio_config::{
+    VIRTIO_CONFIG_S_ACKNOWLEDGE, VIRTIO_CONFIG_S_DRIVER, VIRTIO_CONFIG_S_DRIVER_OK,
+    VIRTIO_CONFIG_S_FEATURES_OK,
+};
+use virtio_bindings::bindings::virtio_ring::{
+    VRING_DESC_F_INDIRECT, VRING_DESC_F_NEXT, VRING_DESC_F_WRITE,
+};
+use virtio_device::VirtioMmioDevice;
+use vm_device::bus::MmioAddress;
+use vm_device::MutDeviceMmio;
+use vm_memory::{Address, Bytes, GuestAddress, GuestMemoryMmap};
+use vmm_sys_util::eventfd::EventFd;
+
+use super::interface::Interface;
+use super::{bindings, Result, VirtioNet, VirtioNetError, OFFLOAD_FEATURES};
+use crate::config::NetConfig;
+use crate::rate_limiter::RateLimiter;
+
+/// Size the driver sets for both queues.
+pub const QUEUE_SIZE: u16 = 16;
+
+// Where the frames the driver sends go, one segment every 4 KiB.
+const TX_BUFFERS: u64 = 0x10000;
+
+/// An interface which records the frames sent, and receives the queued ones.
+pub struct MockInterface {
+    fd: EventFd,
+    pub sent: Vec<Vec<u8>>,
+    pub received: VecDeque<Vec<u8>>,
+    /// The virtio features the offloads were last set from.
+    pub offloads: Mutex<Option<u64>>,
+    /// The virtio header size the interface was last set up with.
+    pub vnet_hdr_size: Mutex<Option<usize>>,
+    /// Whether the interface refuses the offloads, as some taps do.
+    pub refuse_offloads: bool,
+    /// The error the reads and writes fail with, if any.
+    pub error: Option<i32>,
+    /// Whether the interface can be opened again once it went away.
+    pub reconnectable: bool,
+}
+
+impl Read for MockInterface {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(error) = self.error {
+            return Err(io::Error::from_raw_os_error(error));
+        }
+
+        match self.received.pop_front() {
+            Some(frame) => {
+                // Readable until the last frame is read.
+                if self.received.is_empty() {
+                    let _ = self.fd.read();
+                }
+                buf[..frame.len()].copy_from_slice(&frame);
+                Ok(frame.len())
+            }
+            None => Err(io::Error::from_raw_os_error(libc::EAGAIN)),
+        }
+    }
+}
+
+impl Write for MockInterface {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(error) = self.error {
+            return Err(io::Error::from_raw_os_error(error));
+        }
+
+        self.sent.push(buf.to_vec());
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl MockInterface {
+    /// Queue a frame for the device to read, making the interface readable.
+    pub fn receive(&mut self, frame: Vec<u8>) {
+        self.received.push_back(frame);
+        self.fd.write(1).unwrap();
+    }
+}
+
+impl AsRawFd for MockInterface {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl Interface for MockInterface {
+    fn activate(&self, virtio_flags: u64, virtio_header_size: usize) -> Result<u64> {
+        let enabled = match self.refuse_offloads {
+            true => virtio_flags & !OFFLOAD_FEATURES,
+            false => virtio_flags,
+        };
+        *self.offloads.lock().unwrap() = Some(enabled);
+        *self.vnet_hdr_size.lock().unwrap() = Some(virtio_header_size);
+        Ok(enabled)
+    }
+
+    fn open_config(_config: &NetConfig) -> Result<Self> {
+        Ok(MockInterface {
+            fd: EventFd::new(libc::EFD_NONBLOCK).map_err(VirtioNetError::IoError)?,
+            sent: Vec::new(),
+            received: VecDeque::new(),
+            offloads: Mutex::new(None),
+            vnet_hdr_size: Mutex::new(None),
+            refuse_offloads: false,
+            error: None,
+            reconnectable: false,
+        })
+    }
+
+    fn reconnect(&self, config: &NetConfig) -> Result<Self> {
+        if !self.reconnectable {
+            return Err(VirtioNetError::IoError(io::Error::from_raw_os_error(
+                libc::ENODEV,
+            )));
+        }
+
+        MockInterface::open_config(config)
+    }
+}
+
+pub type TestNet = VirtioNet<Arc<GuestMemoryMmap>, MockInterface>;
+
+/// Guest memory of `size` bytes, from address 0.
+pub fn guest_memory(size: usize) -> Arc<GuestMemoryMmap> {
+    Arc::new(GuestMemoryMmap::from_ranges(&[(GuestAddress(0), size)]).unwrap())
+}
+
+/// A device without rate limiters nor vhost.
+pub fn new_net(mem: &Arc<GuestMemoryMmap>) -> TestNet {
+    TestNet::new(
+        mem.clone(),
+        EventFd::new(libc::EFD_NONBLOCK).unwrap(),
+        &NetConfig::default(),
+        RateLimiter::new(None, None).unwrap(),
+        RateLimiter::new(None, None).unwrap(),
+        None,
+    )
+    .unwrap()
+}
+
+pub fn read_register(net: &TestNet, offset: u64) -> u32 {
+    let mut data = [0u8; 4];
+    net.read(offset, &mut data);
+    u32::from_le_bytes(data)
+}
+
+pub fn write_register(net: &mut TestNet, offset: u64, value: u32) {
+    net.mmio_write(MmioAddress(0), offset, &value.to_le_bytes());
+}
+
+/// Ack the features, through DriverFeaturesSel and DriverFeatures, and set FEATURES_OK.
+/// Returns the device status read back.
+pub fn ack_features(net: &mut TestNet, features: u64) -> u32 {
+    let status = VIRTIO_CONFIG_S_ACKNOWLEDGE | VIRTIO_CONFIG_S_DRIVER;
+    write_register(net, 0x70, status);
+    for half in 0..2 {
+        write_register(net, 0x24, half);
+        write_register(net, 0x20, (features >> (32 * half)) as u32);
+    }
+    write_register(net, 0x70, status | VIRTIO_CONFIG_S_FEATURES_OK);
+
+    read_register(net, 0x70)
+}
+
+/// Go through the driver initialization, and return the RX and TX queues it set up.
+pub fn driver_init(
+    net: &mut TestNet,
+    mem: &GuestMemoryMmap,
+    features: u64,
+) -> (DriverQueue, DriverQueue) {
+    let queues = [DriverQueue::rx(mem), DriverQueue::tx(mem)];
+    driver_init_queues(net, mem, features, queues)
+}
+
+/// Go through the driver initialization, setting the RX and TX `queues` up.
+pub fn driver_init_queues(
+    net: &mut TestNet,
+    mem: &GuestMemoryMmap,
+    features: u64,
+    queues: [DriverQueue; 2],
+) -> (DriverQueue, DriverQueue) {
+    let status = ack_features(net, features);
+    assert_ne!(status & VIRTIO_CONFIG_S_FEATURES_OK, 0);
+
+    for (index, queue) in queues.iter().enumerate() {
+        write_register(net, 0x30, index as u32);
+        write_register(net, 0x38, u32::from(queue.size));
+        write_register(net, 0x80, queue.desc as u32);
+        write_register(net, 0x90, queue.avail as u32);
+        write_register(net, 0xa0, queue.used as u32);
+        write_register(net, 0x44, 1);
+    }
+
+    write_register(net, 0x70, status | VIRTIO_CONFIG_S_DRIVER_OK);
+    assert!(net.device_config.device_activated);
+
+    let [rx, tx] = queues;
+    (rx, tx)
+}
+
+/// A frame the interface receives, with its virtio header.
+pub fn rx_frame(payload_len: usize) -> Vec<u8> {
+    vec![0xab; bindings::VIRTIO_HDR_LEN + payload_len]
+}
+
+/// The driver side of a queue, with its rings at fixed guest addresses.
+pub struct DriverQueue {
+    size: u16,
+    desc: u64,
+    avail: u64,
+    used: u64,
+    // The next free descriptor, wrapping around the table.
+    next_desc: u16,
+    next_avail: u16,
+}
+
+impl DriverQueue {
+    /// A queue of `size` entries, from `desc`, each ring starting on its own page.
+    pub fn with_size(mem: &GuestMemoryMmap, desc: u64, size: u16) -> Self {
+        let page_align = |len: u64| len.next_multiple_of(0x1000);
+        let avail = desc + page_align(u64::from(size) * 16);
+        let queue = DriverQueue {
+            size,
+            desc,
+            avail,
+            used: avail + page_align(6 + u64::from(size) * 2),
+            next_desc: 0,
+            next_avail: 0,
+        };
+        // A new driver starts from empty rings.
+        for ring in [queue.avail, queue.used] {
+            mem.write_obj(0u32, GuestAddress(ring)).unwrap();
+        }
+
+        queue
+    }
+
+    /// The receive queue, in 0x4000..0x7000.
+    pub fn rx(mem: &GuestMemoryMmap) -> Self {
+        DriverQueue::with_size(mem, 0x4000, QUEUE_SIZE)
+    }
+
+    /// The transmit queue, in 0x1000..0x4000.
+    pub fn tx(mem: &GuestMemoryMmap) -> Self {
+        DriverQueue::with_size(mem, 0x1000, QUEUE_SIZE)
+    }
+
+    /// Make a chain of the buffers, given as address and length, available to the device.
+    /// Returns its head index.
+    pub fn add_chain(&mut self, mem: &GuestMemoryMmap, buffers: &[(u64, u32)], flags: u16) -> u16 {
+        let head = self.next_desc;
+        for (index, (addr, len)) in buffers.iter().enumerate() {
+            let slot = self.next_desc;
+            self.next_desc = (self.next_desc + 1) % self.size;
+            let (flags, next) = if index + 1 < buffers.len() {
+                (flags | VRING_DESC_F_NEXT as u16, self.next_desc)
+            } else {
+                (flags, 0)
+            };
+
+            write_descriptor(mem, self.desc, slot, (*addr, *len, flags, next));
+        }
+
+        self.make_available(mem, head)
+    }
+
+    /// Make a chain of a single descriptor, pointing at an indirect table at `table` of
+    /// the buffers, available to the device. Returns its head index.
+    pub fn add_indirect_chain(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        table: u64,
+        buffers: &[(u64, u32)],
+        flags: u16,
+    ) -> u16 {
+        for (index, (addr, len)) in buffers.iter().enumerate() {
+            let (flags, next) = if index + 1 < buffers.len() {
+                (flags | VRING_DESC_F_NEXT as u16, index as u16 + 1)
+            } else {
+                (flags, 0)
+            };
+            write_descriptor(mem, table, index as u16, (*addr, *len, flags, next));
+        }
+
+        let head = self.next_desc;
+        self.next_desc = (self.next_desc + 1) % self.size;
+        let table_len = buffers.len() as u32 * 16;
+        let indirect = (table, table_len, VRING_DESC_F_INDIRECT as u16, 0);
+        write_descriptor(mem, self.desc, head, indirect);
+
+        self.make_available(mem, head)
+    }
+
+    /// Chain the descriptor `slot` to the descriptor `next`, e.g. to make a chain loop.
+    pub fn link(&self, mem: &GuestMemoryMmap, slot: u16, next: u16) {
+        let desc = GuestAddress(self.desc + u64::from(slot) * 16);
+        let flags: u16 = mem.read_obj(desc.unchecked_add(12)).unwrap();
+        mem.write_obj(flags | VRING_DESC_F_NEXT as u16, desc.unchecked_add(12))
+            .unwrap();
+        mem.write_obj(next, desc.unchecked_add(14)).unwrap();
+    }
+
+    // Put the chain at `head` in the available ring.
+    fn make_available(&mut self, mem: &GuestMemoryMmap, head: u16) -> u16 {
+        let entry = u64::from(self.next_avail % self.size);
+        mem.write_obj(head, GuestAddress(self.avail + 4 + entry * 2))
+            .unwrap();
+        self.next_avail = self.next_avail.wrapping_add(1);
+        mem.write_obj(self.next_avail, GuestAddress(self.avail + 2))
+            .unwrap();
+
+        head
+    }
+
+    /// Set the index of the available ring, e.g. past the chains it holds.
+    pub fn set_avail_index(&self, mem: &GuestMemoryMmap, index: u16) {
+        mem.write_obj(index, GuestAddress(self.avail + 2)).unwrap();
+    }
+
+    /// Give the device a receive buffer of `len` bytes at `addr`.
+    pub fn post_buffer(&mut self, mem: &GuestMemoryMmap, addr: u64, len: u32) -> u16 {
+        self.add_chain(mem, &[(addr, len)], VRING_DESC_F_WRITE as u16)
+    }
+
+    /// Send a frame made of the segments, each in its own descriptor, through the queue.
+    pub fn send(&mut self, net: &mut TestNet, mem: &GuestMemoryMmap, segments: &[&[u8]]) {
+        let buffers: Vec<_> = segments
+            .iter()
+            .enumerate()
+            .map(|(index, segment)| {
+                let addr = TX_BUFFERS + index as u64 * 0x1000;
+                mem.write_slice(segment, GuestAddress(addr)).unwrap();
+                (addr, segment.len() as u32)
+            })
+            .collect();
+        self.add_chain(mem, &buffers, 0);
+
+        write_register(net, 0x50, 1);
+    }
+
+    /// Send a payload, after a virtio header, in a single descriptor.
+    pub fn send_frame(&mut self, net: &mut TestNet, mem: &GuestMemoryMmap, payload: &[u8]) {
+        let mut frame = vec![0u8; bindings::VIRTIO_HDR_LEN];
+        frame.extend_from_slice(payload);
+        self.send(net, mem, &[&frame]);
+    }
+
+    /// The index of the used ring.
+    pub fn used_index(&self, mem: &GuestMemoryMmap) -> u16 {
+        mem.read_obj(GuestAddress(self.used + 2)).unwrap()
+    }
+
+    /// The flags of the used ring, which the device sets to suppress the notifications.
+    pub fn used_flags(&self, mem: &GuestMemoryMmap) -> u16 {
+        mem.read_obj(GuestAddress(self.used)).unwrap()
+    }
+
+    /// The chains in the used ring, from `from`, as the address of their first buffer and
+    /// the length the device wrote.
+    pub fn used(&self, mem: &GuestMemoryMmap, from: u16) -> Vec<(u64, usize)> {
+        (from..self.used_index(mem))
+            .map(|index| {
+                let elem = GuestAddress(self.used + 4 + u64::from(index % self.size) * 8);
+                let head: u32 = mem.read_obj(elem).unwrap();
+                let len: u32 = mem.read_obj(elem.unchecked_add(4)).unwrap();
+                let addr = mem
+                    .read_obj(GuestAddress(self.desc + u64::from(head) * 16))
+                    .unwrap();
+                (addr, len as usize)
+            })
+            .collect()
+    }
+}
+
+// Write the descriptor `slot` of the table at `table`, as address, length, flags and next.
+fn write_descriptor(mem: &GuestMemoryMmap, table: u64, slot: u16, desc: (u64, u32, u16, u16)) {
+    let (addr, len, flags, next) = desc;
+    let desc = GuestAddress(table + u64::from(slot) * 16);
+    mem.write_obj(addr, desc).unwrap();
+    mem.write_obj(len, desc.unchecked_add(8)).unwrap();
+    mem.write_obj(flags, desc.unchecked_add(12)).unwrap();
+    mem.write_obj(next, desc.unchecked_add(14)).unwrap();
+}
@@ -0,0 +1,1252 @@
+// SPDX-License-Identifier: Apache-2.0
+
+// Userspace networking, for the hosts where we cannot create a tap. The guest frames go to
+// an embedded TCP/IP stack (smoltcp), on a private network laid out as QEMU's slirp one:
+// the VMM is the gateway at 10.0.2.2 and the DNS server at 10.0.2.3, and hands 10.0.2.15
+// out to the guest through DHCP.
+//
+// The guest TCP connections end in the stack, which relays them to host sockets. The UDP
+// datagrams skip the stack, and are sent from a host socket per guest port. The gateway
+// address stands for the host loopback, and the DNS one for the host nameserver.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::{Ipv4Addr, Shutdown, SocketAddr, SocketAddrV4, TcpListener, TcpStream, UdpSocket};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::sync::{Arc, Mutex};
+use std::time::{self, Duration, SystemTime};
+
+use smoltcp::iface::{self, SocketHandle, SocketSet};
+use smoltcp::phy::{self, Checksum, ChecksumCapabilities, DeviceCapabilities, Medium};
+use smoltcp::socket::tcp;
+use smoltcp::time::Instant;
+use smoltcp::wire::{
+    EthernetAddress, EthernetFrame, EthernetProtocol, EthernetRepr, HardwareAddress, IpAddress,
+    IpCidr, IpEndpoint, IpProtocol, Ipv4Address, Ipv4Packet, Ipv4Repr, TcpPacket, UdpPacket,
+    UdpRepr, DHCP_CLIENT_PORT, DHCP_SERVER_PORT, ETHERNET_HEADER_LEN, IPV4_HEADER_LEN,
+    UDP_HEADER_LEN,
+};
+use vmm_sys_util::eventfd::EventFd;
+use vmm_sys_util::timerfd::TimerFd;
+
+use super::bindings::VIRTIO_HDR_LEN;
+use super::dhcp::DhcpServer;
+use super::interface::Interface;
+use super::{Result, VirtioNetError};
+use crate::config::{DhcpServerConfig, NetBackend, NetConfig, UserNetConfig};
+use crate::epoll_context::{EpollContext, EventHandler, EventOps, Events, Interest, Token, Wakeup};
+
+const GATEWAY: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 2);
+const NAMESERVER: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 3);
+const GUEST: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 15);
+const PREFIX_LEN: u8 = 24;
+const GATEWAY_MAC: EthernetAddress = EthernetAddress([0x52, 0x55, 0x0a, 0x00, 0x02, 0x02]);
+
+const MTU: usize = 1514;
+// Frames waiting for the guest, beyond which they are dropped, as a full tap queue does.
+const MAX_QUEUED_FRAMES: usize = 1024;
+// Buffers of each direction of a TCP connection.
+const TCP_BUFFER_SIZE: usize = 64 * 1024;
+// Idle time after which the host socket of a guest UDP port is closed.
+const UDP_TIMEOUT: Duration = Duration::from_secs(60);
+// Delay before writing again to a host socket which could not take more.
+const RETRY_DELAY: Duration = Duration::from_millis(10);
+// First port of the connections forwarded from the host, as the guest sees them.
+const FIRST_EPHEMERAL_PORT: u16 = 49152;
+
+// Tokens of the stack file descriptors. The host sockets come after them.
+const FRAMES: Token = Token(0);
+const TIMER: Token = Token(1);
+const FIRST_HOST_SOCKET: u64 = 2;
+
+/// Userspace network stack, relaying the guest traffic to host sockets.
+///
+/// Its file descriptor is an epoll one, readable when there are frames for the guest, when
+/// a host socket is, or when the stack timers fire.
+pub struct UserNet {
+    stack: Mutex<Stack>,
+    epoll_fd: RawFd,
+}
+
+impl UserNet {
+    fn open(config: &UserNetConfig) -> io::Result<Self> {
+        let stack = Stack::new(config)?;
+        let epoll_fd = stack.epoll.as_raw_fd();
+
+        Ok(UserNet {
+            stack: Mutex::new(stack),
+            epoll_fd,
+        })
+    }
+}
+
+impl Interface for UserNet {
+    fn activate(&self, virtio_flags: u64, virtio_header_size: usize) -> Result<u64> {
+        // The stack does not verify the checksums, which the guest may leave to us.
+        self.stack.lock().unwrap().virtio_header_size = virtio_header_size;
+        Ok(virtio_flags)
+    }
+
+    fn open_config(config: &NetConfig) -> Result<Self> {
+        match &config.backend {
+            NetBackend::User(user) => UserNet::open(user).map_err(VirtioNetError::IoError),
+            NetBackend::Tap(_) => Err(VirtioNetError::InvalidBackend),
+        }
+    }
+}
+
+impl Read for UserNet {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let stack = self.stack.get_mut().unwrap();
+        if stack.frames.tx.is_empty() {
+            stack.service();
+        }
+
+        let frame = stack.frames.tx.pop_front();
+        stack.update_frames_fd();
+        let frame = frame.ok_or_else(|| io::Error::from(io::ErrorKind::WouldBlock))?;
+
+        // The frames we build are complete, the virtio header has no flags.
+        let header_size = stack.virtio_header_size;
+        let len = std::cmp::min(header_size + frame.len(), buf.len());
+        buf[..header_size].fill(0);
+        buf[header_size..len].copy_from_slice(&frame[..len - header_size]);
+
+        Ok(len)
+    }
+}
+
+impl Write for UserNet {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let stack = self.stack.get_mut().unwrap();
+        stack.guest_frame(buf);
+        stack.service();
+        stack.update_frames_fd();
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsRawFd for UserNet {
+    fn as_raw_fd(&self) -> RawFd {
+        self.epoll_fd
+    }
+}
+
+// Frames between the guest and the stack.
+#[derive(Default)]
+struct Frames {
+    // From the guest, to the stack.
+    rx: VecDeque<Vec<u8>>,
+    // To the guest, from the stack or the UDP relays.
+    tx: VecDeque<Vec<u8>>,
+}
+
+impl Frames {
+    fn send(&mut self, frame: Vec<u8>) {
+        if self.tx.len() < MAX_QUEUED_FRAMES {
+            self.tx.push_back(frame);
+        }
+    }
+}
+
+struct FrameRx(Vec<u8>);
+
+impl phy::RxToken for FrameRx {
+    fn consume<R, F>(mut self, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        f(&mut self.0)
+    }
+}
+
+struct FrameTx<'a>(&'a mut VecDeque<Vec<u8>>);
+
+impl phy::TxToken for FrameTx<'_> {
+    fn consume<R, F>(self, len: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let mut frame = vec![0; len];
+        let result = f(&mut frame);
+        self.0.push_back(frame);
+        result
+    }
+}
+
+impl phy::Device for Frames {
+    type RxToken<'a> = FrameRx;
+    type TxToken<'a> = FrameTx<'a>;
+
+    fn receive(&mut self, _timestamp: Instant) -> Option<(FrameRx, FrameTx<'_>)> {
+        let frame = self.rx.pop_front()?;
+        Some((FrameRx(frame), FrameTx(&mut self.tx)))
+    }
+
+    fn transmit(&mut self, _timestamp: Instant) -> Option<FrameTx<'_>> {
+        // The stack tries again later.
+        (self.tx.len() < MAX_QUEUED_FRAMES).then_some(FrameTx(&mut self.tx))
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut capabilities = DeviceCapabilities::default();
+        capabilities.medium = Medium::Ethernet;
+        capabilities.max_transmission_unit = MTU;
+        // With VIRTIO_NET_F_CSUM, the guest frames only have partial checksums.
+        capabilities.checksum.ipv4 = Checksum::Tx;
+        capabilities.checksum.tcp = Checksum::Tx;
+        capabilities.checksum.udp = Checksum::Tx;
+        capabilities.checksum.icmpv4 = Checksum::Tx;
+        capabilities
+    }
+}
+
+// Records the host sockets found ready, for the stack to handle once the event loop
+// returns.
+struct Ready(Arc<Mutex<Vec<(Token, Events)>>>);
+
+impl EventHandler for Ready {
+    fn process(&mut self, events: Events, ops: &mut EventOps) -> crate::Result<()> {
+        self.0.lock().unwrap().push((ops.token(), events));
+        Ok(())
+    }
+}
+
+// A guest TCP connection, and the host one it is relayed to.
+struct TcpRelay {
+    stream: TcpStream,
+    handle: SocketHandle,
+    // Guest and destination endpoints, for the connections the guest opened.
+    flow: Option<(SocketAddrV4, SocketAddrV4)>,
+    // The host closed its side.
+    host_closed: bool,
+    // The guest closed its side, and the host was told.
+    guest_closed: bool,
+}
+
+// The datagrams of a guest UDP port, sent from a host socket.
+struct UdpRelay {
+    socket: UdpSocket,
+    guest: SocketAddrV4,
+    // The destinations as the guest sees them, by host address.
+    peers: HashMap<SocketAddrV4, SocketAddrV4>,
+    last_used: time::Instant,
+}
+
+enum HostSocket {
+    // Accepts the connections of a port forward.
+    Forward {
+        listener: TcpListener,
+        guest: SocketAddrV4,
+    },
+    Tcp(TcpRelay),
+    Udp(UdpRelay),
+}
+
+impl AsRawFd for HostSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            HostSocket::Forward { listener, .. } => listener.as_raw_fd(),
+            HostSocket::Tcp(relay) => relay.stream.as_raw_fd(),
+            HostSocket::Udp(relay) => relay.socket.as_raw_fd(),
+        }
+    }
+}
+
+struct Stack {
+    iface: iface::Interface,
+    frames: Frames,
+    sockets: SocketSet<'static>,
+    epoll: EpollContext,
+    ready: Arc<Mutex<Vec<(Token, Events)>>>,
+    // Readable while there are frames for the guest.
+    frames_fd: EventFd,
+    timer: TimerFd,
+    host_sockets: BTreeMap<Token, HostSocket>,
+    // The TCP connections the guest opened, by guest and destination endpoints.
+    tcp_flows: HashMap<(SocketAddrV4, SocketAddrV4), Token>,
+    // The UDP relays, by guest port.
+    udp_flows: HashMap<u16, Token>,
+    // Learnt from the guest frames.
+    guest_mac: Option<EthernetAddress>,
+    dhcp: DhcpServer,
+    virtio_header_size: usize,
+    nameserver: Option<Ipv4Addr>,
+    next_token: u64,
+    next_port: u16,
+    next_ident: u16,
+}
+
+impl Stack {
+    fn new(config: &UserNetConfig) -> io::Result<Self> {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+
+        let mut frames = Frames::default();
+        let mut iface_config = iface::Config::new(HardwareAddress::Ethernet(GATEWAY_MAC));
+        iface_config.random_seed = now.as_nanos() as u64;
+        let mut iface = iface::Interface::new(iface_config, &mut frames, Instant::now());
+        iface.update_ip_addrs(|addrs| {
+            for address in [GATEWAY, NAMESERVER] {
+                // There is room for two addresses.
+                addrs
+                    .push(IpCidr::new(IpAddress::from(address), PREFIX_LEN))
+                    .unwrap();
+            }
+        });
+        // Accept the connections to any address, routed through the gateway.
+        iface
+            .routes_mut()
+            .add_default_ipv4_route(Ipv4Address::from(GATEWAY))
+            .unwrap();
+        iface.set_any_ip(true);
+
+        let mut epoll = EpollContext::new()?;
+        let ready = Arc::new(Mutex::new(Vec::new()));
+        let frames_fd = EventFd::new(libc::EFD_NONBLOCK)?;
+        let timer = TimerFd::new()?;
+        epoll.add(
+            frames_fd.as_raw_fd(),
+            FRAMES,
+            Interest::Read,
+            Box::new(Wakeup),
+        )?;
+        epoll.add(
+            timer.as_raw_fd(),
+            TIMER,
+            Interest::Read,
+            Box::new(Ready(ready.clone())),
+        )?;
+
+        let mut stack = Stack {
+            iface,
+            frames,
+            sockets: SocketSet::new(Vec::new()),
+            epoll,
+            ready,
+            frames_fd,
+            timer,
+            host_sockets: BTreeMap::new(),
+            tcp_flows: HashMap::new(),
+            udp_flows: HashMap::new(),
+            guest_mac: None,
+            // The guest always gets the same address.
+            dhcp: DhcpServer::new(DhcpServerConfig {
+                address: GATEWAY,
+                prefix_len: PREFIX_LEN,
+                range: (GUEST, GUEST),
+                dns: Some(NAMESERVER),
+            }),
+            virtio_header_size: VIRTIO_HDR_LEN,
+            nameserver: host_nameserver(),
+            next_token: FIRST_HOST_SOCKET,
+            next_port: FIRST_EPHEMERAL_PORT,
+            next_ident: 0,
+        };
+
+        for forward in config.hostfwd.iter() {
+            let listener = TcpListener::bind(forward.host)?;
+            listener.set_nonblocking(true)?;
+            let guest = SocketAddrV4::new(forward.guest_addr.unwrap_or(GUEST), forward.guest_port);
+            stack.register(HostSocket::Forward { listener, guest })?;
+        }
+
+        Ok(stack)
+    }
+
+    // Poll a host socket.
+    fn register(&mut self, socket: HostSocket) -> io::Result<Token> {
+        let token = Token(self.next_token);
+        self.next_token += 1;
+
+        self.epoll.add(
+            socket.as_raw_fd(),
+            token,
+            Interest::Read,
+            Box::new(Ready(self.ready.clone())),
+        )?;
+        self.host_sockets.insert(token, socket);
+
+        Ok(token)
+    }
+
+    fn unregister(&mut self, token: Token) {
+        // Before the socket is closed, and its file descriptor reused.
+        if let Err(e) = self.epoll.remove(token) {
+            log::warn!("Failed to unregister a user network socket: {}", e);
+        }
+
+        match self.host_sockets.remove(&token) {
+            Some(HostSocket::Tcp(relay)) => {
+                self.sockets.remove(relay.handle);
+                if let Some(flow) = relay.flow {
+                    self.tcp_flows.remove(&flow);
+                }
+            }
+            Some(HostSocket::Udp(relay)) => {
+                self.udp_flows.remove(&relay.guest.port());
+            }
+            _ => {}
+        }
+    }
+
+    // Keep the frames file descriptor readable while there are frames for the guest.
+    fn update_frames_fd(&self) {
+        let _ = if self.frames.tx.is_empty() {
+            self.frames_fd.read().map(drop)
+        } else {
+            self.frames_fd.write(1)
+        };
+    }
+
+    // Where a destination of the guest is on the host.
+    fn host_address(&self, address: Ipv4Addr) -> Option<Ipv4Addr> {
+        match address {
+            GATEWAY => Some(Ipv4Addr::LOCALHOST),
+            NAMESERVER => self.nameserver,
+            // Nothing else lives on the guest network.
+            address if address.octets()[..3] == GATEWAY.octets()[..3] => None,
+            address if address.is_broadcast() || address.is_multicast() => None,
+            address if address.is_unspecified() => None,
+            address => Some(address),
+        }
+    }
+
+    // Handle a frame the guest sent.
+    fn guest_frame(&mut self, buf: &[u8]) {
+        let frame = match buf.get(self.virtio_header_size..) {
+            Some(frame) => frame,
+            None => return,
+        };
+        let ethernet = match EthernetFrame::new_checked(frame) {
+            Ok(ethernet) => ethernet,
+            Err(_) => return,
+        };
+        self.guest_mac = Some(ethernet.src_addr());
+
+        if ethernet.ethertype() == EthernetProtocol::Ipv4 {
+            if let Ok(packet) = Ipv4Packet::new_checked(ethernet.payload()) {
+                match packet.next_header() {
+                    IpProtocol::Udp => {
+                        self.guest_udp(&packet);
+                        return;
+                    }
+                    IpProtocol::Tcp => self.guest_tcp(&packet),
+                    _ => (),
+                }
+            }
+        }
+
+        self.frames.rx.push_back(frame.to_vec());
+    }
+
+    // Relay a new guest connection to the host, through a socket listening on its
+    // destination.
+    fn guest_tcp(&mut self, packet: &Ipv4Packet<&[u8]>) {
+        let segment = match TcpPacket::new_checked(packet.payload()) {
+            Ok(segment) if segment.syn() && !segment.ack() => segment,
+            _ => return,
+        };
+
+        let guest = SocketAddrV4::new(packet.src_addr().into(), segment.src_port());
+        let destination = SocketAddrV4::new(packet.dst_addr().into(), segment.dst_port());
+        if self.tcp_flows.contains_key(&(guest, destination)) {
+            return;
+        }
+        let host = match self.host_address(*destination.ip()) {
+            Some(address) => SocketAddrV4::new(address, destination.port()),
+            None => return,
+        };
+
+        // Without a socket listening, the stack resets the connection.
+        let stream = match connect(host) {
+            Ok(stream) => stream,
+            Err(e) => {
+                log::debug!("Failed to connect to {}: {}", host, e);
+                return;
+            }
+        };
+        let mut socket = tcp_socket();
+        if socket.listen(IpEndpoint::from(destination)).is_err() {
+            return;
+        }
+
+        let handle = self.sockets.add(socket);
+        let relay = TcpRelay {
+            stream,
+            handle,
+            flow: Some((guest, destination)),
+            host_closed: false,
+            guest_closed: false,
+        };
+        match self.register(HostSocket::Tcp(relay)) {
+            Ok(token) => {
+                self.tcp_flows.insert((guest, destination), token);
+            }
+            Err(e) => {
+                log::warn!("Failed to relay a guest TCP connection: {}", e);
+                self.sockets.remove(handle);
+            }
+        }
+    }
+
+    // Send a guest datagram from the host socket of its port, or answer it when it is for
+    // the DHCP server.
+    fn guest_udp(&mut self, packet: &Ipv4Packet<&[u8]>) {
+        if packet.more_frags() || packet.frag_offset() != 0 {
+            return;
+        }
+        let datagram = match UdpPacket::new_checked(packet.payload()) {
+            Ok(datagram) => datagram,
+            Err(_) => return,
+        };
+        if datagram.dst_port() == DHCP_SERVER_PORT {
+            self.dhcp(datagram.payload());
+            return;
+        }
+
+        let guest = SocketAddrV4::new(packet.src_addr().into(), datagram.src_port());
+        let destination = SocketAddrV4::new(packet.dst_addr().into(), datagram.dst_port());
+        let host = match self.host_address(*destination.ip()) {
+            Some(address) => SocketAddrV4::new(address, destination.port()),
+            None => return,
+        };
+
+        let token = match self.udp_flows.get(&guest.port()) {
+            Some(token) => *token,
+            None => match self.udp_relay(guest) {
+                Ok(token) => token,
+                Err(e) => {
+                    log::warn!("Failed to relay a guest UDP port: {}", e);
+                    return;
+                }
+            },
+        };
+        if let Some(HostSocket::Udp(relay)) = self.host_sockets.get_mut(&token) {
+            relay.peers.insert(host, destination);
+            relay.last_used = time::Instant::now();
+            if let Err(e) = relay.socket.send_to(datagram.payload(), host) {
+                log::debug!("Failed to send a datagram to {}: {}", host, e);
+            }
+        }
+    }
+
+    fn udp_relay(&mut self, guest: SocketAddrV4) -> io::Result<Token> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+        socket.set_nonblocking(true)?;
+
+        let token = self.register(HostSocket::Udp(UdpRelay {
+            socket,
+            guest,
+            peers: HashMap::new(),
+            last_used: time::Instant::now(),
+        }))?;
+        self.udp_flows.insert(guest.port(), token);
+
+        Ok(token)
+    }
+
+    fn dhcp(&mut self, payload: &[u8]) {
+        let (mac, reply) = match self.dhcp.reply(payload) {
+            Some(reply) => reply,
+            None => return,
+        };
+
+        // The guest has no address yet, the reply is broadcast.
+        self.send_udp(
+            mac,
+            SocketAddrV4::new(GATEWAY, DHCP_SERVER_PORT),
+            SocketAddrV4::new(Ipv4Addr::BROADCAST, DHCP_CLIENT_PORT),
+            &reply,
+        );
+    }
+
+    fn send_udp(
+        &mut self,
+        mac: EthernetAddress,
+        source: SocketAddrV4,
+        destination: SocketAddrV4,
+        data: &[u8],
+    ) {
+        let ident = self.next_ident;
+        self.next_ident = self.next_ident.wrapping_add(1);
+
+        for frame in udp_frames(ident, GATEWAY_MAC, mac, source, destination, data) {
+            self.frames.send(frame);
+        }
+    }
+
+    // Handle the host sockets found ready, run the stack and relay the TCP connections.
+    fn service(&mut self) {
+        if let Err(e) = self.epoll.run_once(Some(Duration::ZERO)) {
+            log::warn!("Failed to poll the user network sockets: {:?}", e);
+        }
+
+        let ready = mem::take(&mut *self.ready.lock().unwrap());
+        for (token, events) in ready {
+            match self.host_sockets.get(&token) {
+                Some(HostSocket::Forward { .. }) => self.accept(token),
+                Some(HostSocket::Tcp(_)) => self.tcp_readable(token, events),
+                Some(HostSocket::Udp(_)) => self.udp_readable(token),
+                None if token == TIMER => {
+                    // The timer is not periodic, there is nothing to do if it was already
+                    // read.
+                    let _ = self.timer.wait();
+                }
+                None => (),
+            }
+        }
+
+        self.iface
+            .poll(Instant::now(), &mut self.frames, &mut self.sockets);
+        let blocked = self.relay();
+        // Send what relaying changed: acknowledgements, window updates and closures.
+        self.iface
+            .poll(Instant::now(), &mut self.frames, &mut self.sockets);
+
+        self.cleanup();
+        self.arm_timer(blocked);
+    }
+
+    fn accept(&mut self, token: Token) {
+        loop {
+            let (stream, guest) = match self.host_sockets.get(&token) {
+                Some(HostSocket::Forward { listener, guest }) => match listener.accept() {
+                    Ok((stream, _)) => (stream, *guest),
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                    Err(e) => {
+                        log::debug!("Failed to accept a forwarded connection: {}", e);
+                        return;
+                    }
+                },
+                _ => return,
+            };
+
+            if let Err(e) = self.forward(stream, guest) {
+                log::warn!("Failed to forward a connection to {}: {}", guest, e);
+            }
+        }
+    }
+
+    // Connect to the guest, from the gateway, for a forwarded connection.
+    fn forward(&mut self, stream: TcpStream, guest: SocketAddrV4) -> io::Result<()> {
+        stream.set_nonblocking(true)?;
+
+        let port = self.next_port;
+        self.next_port = port.checked_add(1).unwrap_or(FIRST_EPHEMERAL_PORT);
+        let mut socket = tcp_socket();
+        socket
+            .connect(
+                self.iface.context(),
+                IpEndpoint::from(guest),
+                IpEndpoint::new(IpAddress::from(GATEWAY), port),
+            )
+            .map_err(io::Error::other)?;
+
+        let handle = self.sockets.add(socket);
+        let relay = TcpRelay {
+            stream,
+            handle,
+            flow: None,
+            host_closed: false,
+            guest_closed: false,
+        };
+        if let Err(e) = self.register(HostSocket::Tcp(relay)) {
+            self.sockets.remove(handle);
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    // Move what the host sent to the stack, which has room for it.
+    fn tcp_readable(&mut self, token: Token, events: Events) {
+        let relay = match self.host_sockets.get_mut(&token) {
+            Some(HostSocket::Tcp(relay)) => relay,
+            _ => return,
+        };
+        let socket = self.sockets.get_mut::<tcp::Socket>(relay.handle);
+
+        if events.hangup() {
+            if let Ok(Some(e)) = relay.stream.take_error() {
+                log::debug!("Host connection failed: {}", e);
+                socket.abort();
+                return;
+            }
+        }
+
+        while socket.can_send() {
+            let read = socket.send(|buffer| match relay.stream.read(buffer) {
+                Ok(len) => (len, Ok(len)),
+                Err(e) => (0, Err(e)),
+            });
+            match read {
+                Ok(Ok(0)) => {
+                    relay.host_closed = true;
+                    socket.close();
+                }
+                Ok(Ok(_)) => continue,
+                Ok(Err(e)) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Ok(Err(e)) if e.kind() == io::ErrorKind::WouldBlock => (),
+                Ok(Err(e)) => {
+                    log::debug!("Host connection failed: {}", e);
+                    socket.abort();
+                }
+                Err(_) => (),
+            }
+            break;
+        }
+    }
+
+    // Move what the guest sent to the host sockets, and only poll the host sockets the
+    // stack has room for. Returns whether a host socket could not take everything.
+    fn relay(&mut self) -> bool {
+        let mut blocked = false;
+
+        for (token, host_socket) in self.host_sockets.iter_mut() {
+            let relay = match host_socket {
+                HostSocket::Tcp(relay) => relay,
+                _ => continue,
+            };
+            let socket = self.sockets.get_mut::<tcp::Socket>(relay.handle);
+
+            while socket.can_recv() {
+                // Until the host connection is established, the writes would block.
+                let written = socket.recv(|data| match relay.stream.write(data) {
+                    Ok(len) => (len, Ok(())),
+                    Err(e) => (0, Err(e)),
+                });
+                match written {
+                    Ok(Ok(())) => continue,
+                    Ok(Err(e)) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Ok(Err(e)) if e.kind() == io::ErrorKind::WouldBlock => blocked = true,
+                    Ok(Err(e)) => {
+                        log::debug!("Host connection failed: {}", e);
+                        socket.abort();
+                    }
+                    Err(_) => (),
+                }
+                break;
+            }
+
+            let guest_closed =
+                matches!(socket.state(), tcp::State::CloseWait | tcp::State::LastAck);
+            if guest_closed && !relay.guest_closed && !socket.can_recv() {
+                let _ = relay.stream.shutdown(Shutdown::Write);
+                relay.guest_closed = true;
+            }
+
+            let readable = !relay.host_closed && socket.can_send();
+            let result = match (readable, self.epoll.is_paused(*token)) {
+                (true, true) => self.epoll.resume(*token),
+                (false, false) => self.epoll.pause(*token),
+                _ => Ok(()),
+            };
+            if let Err(e) = result {
+                log::warn!("Failed to update a user network socket: {}", e);
+            }
+        }
+
+        blocked
+    }
+
+    fn udp_readable(&mut self, token: Token) {
+        let mut buffer = vec![0; u16::MAX as usize];
+
+        loop {
+            let (len, source, guest) = match self.host_sockets.get_mut(&token) {
+                Some(HostSocket::Udp(relay)) => match relay.socket.recv_from(&mut buffer) {
+                    Ok((len, SocketAddr::V4(from))) => {
+                        relay.last_used = time::Instant::now();
+                        // Replies come from where the guest sent its datagrams.
+                        let source = relay.peers.get(&from).copied().unwrap_or(from);
+                        (len, source, relay.guest)
+                    }
+                    Ok(_) => continue,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                    Err(e) => {
+                        log::debug!("Failed to receive a datagram: {}", e);
+                        return;
+                    }
+                },
+                _ => return,
+            };
+
+            if let Some(mac) = self.guest_mac {
+                self.send_udp(mac, source, guest, &buffer[..len]);
+            }
+        }
+    }
+
+    // Drop the closed TCP connections, and the idle UDP relays.
+    fn cleanup(&mut self) {
+        let now = time::Instant::now();
+        let closed: Vec<Token> = self
+            .host_sockets
+            .iter()
+            .filter(|(_, host_socket)| match host_socket {
+                HostSocket::Tcp(relay) => matches!(
+                    self.sockets.get::<tcp::Socket>(relay.handle).state(),
+                    tcp::State::Closed | tcp::State::TimeWait
+                ),
+                HostSocket::Udp(relay) => now.duration_since(relay.last_used) >= UDP_TIMEOUT,
+                HostSocket::Forward { .. } => false,
+            })
+            .map(|(token, _)| *token)
+            .collect();
+
+        for token in closed {
+            self.unregister(token);
+        }
+    }
+
+    // Wake the stack up for its next timer, the UDP relays expiry, or to write again to the
+    // blocked host sockets.
+    fn arm_timer(&mut self, blocked: bool) {
+        let mut delay = self
+            .iface
+            .poll_delay(Instant::now(), &self.sockets)
+            .map(Duration::from);
+        if blocked {
+            delay = Some(delay.map_or(RETRY_DELAY, |delay| delay.min(RETRY_DELAY)));
+        }
+        if !self.udp_flows.is_empty() {
+            delay = Some(delay.map_or(UDP_TIMEOUT, |delay| delay.min(UDP_TIMEOUT)));
+        }
+
+        // A zero delay would disarm the timer.
+        let delay = delay.map_or(Duration::ZERO, |delay| delay.max(Duration::from_millis(1)));
+        if let Err(e) = self.timer.reset(delay, None) {
+            log::warn!("Failed to arm the user network timer: {}", e);
+        }
+    }
+}
+
+// Build the frames of a UDP datagram, in fragments when it does not fit in one.
+fn udp_frames(
+    ident: u16,
+    source_mac: EthernetAddress,
+    destination_mac: EthernetAddress,
+    source: SocketAddrV4,
+    destination: SocketAddrV4,
+    data: &[u8],
+) -> Vec<Vec<u8>> {
+    let mut datagram = vec![0; UDP_HEADER_LEN + data.len()];
+    let repr = UdpRepr {
+        src_port: source.port(),
+        dst_port: destination.port(),
+    };
+    repr.emit(
+        &mut UdpPacket::new_unchecked(&mut datagram[..]),
+        &IpAddress::from(*source.ip()),
+        &IpAddress::from(*destination.ip()),
+        data.len(),
+        |payload| payload.copy_from_slice(data),
+        &ChecksumCapabilities::default(),
+    );
+
+    let max_fragment = (MTU - ETHERNET_HEADER_LEN - IPV4_HEADER_LEN) & !7;
+    let mut frames = Vec::new();
+    for (index, fragment) in datagram.chunks(max_fragment).enumerate() {
+        let offset = index * max_fragment;
+        let mut frame = vec![0; ETHERNET_HEADER_LEN + IPV4_HEADER_LEN + fragment.len()];
+
+        let mut ethernet = EthernetFrame::new_unchecked(&mut frame[..]);
+        EthernetRepr {
+            src_addr: source_mac,
+            dst_addr: destination_mac,
+            ethertype: EthernetProtocol::Ipv4,
+        }
+        .emit(&mut ethernet);
+
+        let mut packet = Ipv4Packet::new_unchecked(ethernet.payload_mut());
+        Ipv4Repr {
+            src_addr: (*source.ip()).into(),
+            dst_addr: (*destination.ip()).into(),
+            next_header: IpProtocol::Udp,
+            payload_len: fragment.len(),
+            hop_limit: 64,
+        }
+        .emit(&mut packet, &ChecksumCapabilities::ignored());
+        packet.set_ident(ident);
+        packet.set_dont_frag(false);
+        packet.set_more_frags(offset + fragment.len() < datagram.len());
+        packet.set_frag_offset(offset as u16);
+        packet.fill_checksum();
+        packet.payload_mut().copy_from_slice(fragment);
+
+        frames.push(frame);
+    }
+
+    frames
+}
+
+fn tcp_socket() -> tcp::Socket<'static> {
+    tcp::Socket::new(
+        tcp::SocketBuffer::new(vec![0; TCP_BUFFER_SIZE]),
+        tcp::SocketBuffer::new(vec![0; TCP_BUFFER_SIZE]),
+    )
+}
+
+// Start connecting to a host address, without waiting for the connection.
+fn connect(address: SocketAddrV4) -> io::Result<TcpStream> {
+    // Safe because we check the result.
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_INET,
+            libc::SOCK_STREAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+            0,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Safe because we just created the socket, and nothing else owns it.
+    let stream = unsafe { TcpStream::from_raw_fd(fd) };
+
+    let sockaddr = libc::sockaddr_in {
+        sin_family: libc::AF_INET as libc::sa_family_t,
+        sin_port: address.port().to_be(),
+        sin_addr: libc::in_addr {
+            s_addr: u32::from(*address.ip()).to_be(),
+        },
+        sin_zero: [0; 8],
+    };
+    // Safe because the address is valid for the length we give, and we check the result.
+    let ret = unsafe {
+        libc::connect(
+            fd,
+            &sockaddr as *const libc::sockaddr_in as *const libc::sockaddr,
+            mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        let error = io::Error::last_os_error();
+        if error.raw_os_error() != Some(libc::EINPROGRESS) {
+            return Err(error);
+        }
+    }
+
+    Ok(stream)
+}
+
+// The first IPv4 nameserver of the host, which the guest DNS queries go to.
+pub(super) fn host_nameserver() -> Option<Ipv4Addr> {
+    let resolv_conf = fs::read_to_string("/etc/resolv.conf").ok()?;
+    resolv_conf.lines().find_map(|line| {
+        let mut fields = line.split_whitespace();
+        match (fields.next(), fields.next()) {
+            (Some("nameserver"), Some(address)) => address.parse().ok(),
+            _ => None,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    use smoltcp::wire::{DhcpMessageType, DhcpPacket, DhcpRepr};
+
+    use crate::config::HostForward;
+
+    const GUEST_MAC: EthernetAddress = EthernetAddress([0x52, 0x54, 0x00, 0x12, 0x34, 0x56]);
+
+    fn user_net(hostfwd: Vec<HostForward>) -> UserNet {
+        let config = NetConfig {
+            backend: NetBackend::User(UserNetConfig { hostfwd }),
+            ..Default::default()
+        };
+        UserNet::open_config(&config).unwrap()
+    }
+
+    fn send(net: &mut UserNet, frame: &[u8]) {
+        let buf = [&[0; VIRTIO_HDR_LEN][..], frame].concat();
+        assert_eq!(net.write(&buf).unwrap(), buf.len());
+    }
+
+    fn receive(net: &mut UserNet) -> Option<Vec<u8>> {
+        let mut buf = vec![0; u16::MAX as usize];
+        match net.read(&mut buf) {
+            Ok(len) => Some(buf[VIRTIO_HDR_LEN..len].to_vec()),
+            Err(e) => {
+                assert_eq!(e.kind(), io::ErrorKind::WouldBlock);
+                None
+            }
+        }
+    }
+
+    // Wait for the host sockets to have something for the guest.
+    fn receive_timeout(net: &mut UserNet) -> Vec<u8> {
+        for _ in 0..1000 {
+            if let Some(frame) = receive(net) {
+                return frame;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        panic!("no frame for the guest");
+    }
+
+    // A guest network stack, behind the user network.
+    struct Guest {
+        iface: iface::Interface,
+        frames: Frames,
+        sockets: SocketSet<'static>,
+    }
+
+    impl Guest {
+        fn new() -> Self {
+            let mut frames = Frames::default();
+            let config = iface::Config::new(HardwareAddress::Ethernet(GUEST_MAC));
+            let mut iface = iface::Interface::new(config, &mut frames, Instant::now());
+            iface.update_ip_addrs(|addrs| {
+                addrs
+                    .push(IpCidr::new(IpAddress::from(GUEST), PREFIX_LEN))
+                    .unwrap();
+            });
+            iface
+                .routes_mut()
+                .add_default_ipv4_route(GATEWAY.into())
+                .unwrap();
+
+            Guest {
+                iface,
+                frames,
+                sockets: SocketSet::new(Vec::new()),
+            }
+        }
+
+        // Move the frames between the guest and the user network until `done`.
+        fn run(&mut self, net: &mut UserNet, mut done: impl FnMut(&mut Guest) -> bool) {
+            for _ in 0..1000 {
+                self.iface
+                    .poll(Instant::now(), &mut self.frames, &mut self.sockets);
+                while let Some(frame) = self.frames.tx.pop_front() {
+                    send(net, &frame);
+                }
+                while let Some(frame) = receive(net) {
+                    self.frames.rx.push_back(frame);
+                }
+                if done(self) {
+                    return;
+                }
+                thread::sleep(Duration::from_millis(5));
+            }
+            panic!("the guest is stuck");
+        }
+
+        fn socket(&mut self, handle: SocketHandle) -> &mut tcp::Socket<'static> {
+            self.sockets.get_mut::<tcp::Socket>(handle)
+        }
+    }
+
+    #[test]
+    fn dhcp() {
+        let mut net = user_net(Vec::new());
+
+        let discover = DhcpRepr {
+            message_type: DhcpMessageType::Discover,
+            transaction_id: 0x1234,
+            secs: 0,
+            client_hardware_address: GUEST_MAC,
+            client_ip: Ipv4Address::UNSPECIFIED,
+            your_ip: Ipv4Address::UNSPECIFIED,
+            server_ip: Ipv4Address::UNSPECIFIED,
+            router: None,
+            subnet_mask: None,
+            relay_agent_ip: Ipv4Address::UNSPECIFIED,
+            broadcast: false,
+            requested_ip: None,
+            client_identifier: Some(GUEST_MAC),
+            server_identifier: None,
+            parameter_request_list: Some(&[1, 3, 6]),
+            dns_servers: None,
+            max_size: None,
+            lease_duration: None,
+            renew_duration: None,
+            rebind_duration: None,
+            additional_options: &[],
+        };
+        let mut payload = vec![0; discover.buffer_len()];
+        discover
+            .emit(&mut DhcpPacket::new_unchecked(&mut payload[..]))
+            .unwrap();
+        for frame in udp_frames(
+            0,
+            GUEST_MAC,
+            EthernetAddress::BROADCAST,
+            SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, DHCP_CLIENT_PORT),
+            SocketAddrV4::new(Ipv4Addr::BROADCAST, DHCP_SERVER_PORT),
+            &payload,
+        ) {
+            send(&mut net, &frame);
+        }
+
+        let frame = receive(&mut net).unwrap();
+        let ethernet = EthernetFrame::new_checked(&frame[..]).unwrap();
+        assert_eq!(ethernet.dst_addr(), GUEST_MAC);
+        let packet = Ipv4Packet::new_checked(ethernet.payload()).unwrap();
+        assert_eq!(packet.dst_addr(), Ipv4Address::BROADCAST);
+        let datagram = UdpPacket::new_checked(packet.payload()).unwrap();
+        assert_eq!(datagram.dst_port(), DHCP_CLIENT_PORT);
+        let offer = DhcpPacket::new_checked(datagram.payload()).unwrap();
+        let offer = DhcpRepr::parse(&offer).unwrap();
+
+        assert_eq!(offer.message_type, DhcpMessageType::Offer);
+        assert_eq!(offer.transaction_id, 0x1234);
+        assert_eq!(offer.your_ip, GUEST.into());
+        assert_eq!(offer.router, Some(GATEWAY.into()));
+        assert_eq!(offer.subnet_mask, Some(Ipv4Address::new(255, 255, 255, 0)));
+        assert_eq!(
+            offer.dns_servers.unwrap().as_slice(),
+            [Ipv4Address::from(NAMESERVER)]
+        );
+        assert_eq!(receive(&mut net), None);
+    }
+
+    #[test]
+    fn udp_relay() {
+        let mut net = user_net(Vec::new());
+        let host = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        host.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let port = host.local_addr().unwrap().port();
+
+        // The gateway stands for the host loopback.
+        let guest = SocketAddrV4::new(GUEST, 5353);
+        let gateway = SocketAddrV4::new(GATEWAY, port);
+        for frame in udp_frames(0, GUEST_MAC, GATEWAY_MAC, guest, gateway, b"ping") {
+            send(&mut net, &frame);
+        }
+        let mut buf = [0; 16];
+        let (len, relay) = host.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"ping");
+
+        // The reply comes from where the guest sent its datagram, in fragments when it is
+        // larger than the MTU.
+        let reply: Vec<u8> = (0..3000).map(|i| i as u8).collect();
+        host.send_to(&reply, relay).unwrap();
+        let mut fragments = vec![receive_timeout(&mut net)];
+        while let Some(frame) = receive(&mut net) {
+            fragments.push(frame);
+        }
+        assert_eq!(fragments.len(), 3);
+
+        let mut datagram = Vec::new();
+        for (index, frame) in fragments.iter().enumerate() {
+            let ethernet = EthernetFrame::new_checked(&frame[..]).unwrap();
+            assert_eq!(ethernet.dst_addr(), GUEST_MAC);
+            let packet = Ipv4Packet::new_checked(ethernet.payload()).unwrap();
+            assert!(packet.verify_checksum());
+            assert_eq!(packet.src_addr(), GATEWAY.into());
+            assert_eq!(packet.dst_addr(), GUEST.into());
+            assert_eq!(packet.frag_offset() as usize, datagram.len());
+            assert_eq!(packet.more_frags(), index < 2);
+            datagram.extend_from_slice(packet.payload());
+        }
+        let datagram = UdpPacket::new_checked(&datagram[..]).unwrap();
+        assert!(datagram.verify_checksum(&GATEWAY.into(), &GUEST.into()));
+        assert_eq!(datagram.src_port(), port);
+        assert_eq!(datagram.dst_port(), guest.port());
+        assert_eq!(datagram.payload(), reply);
+    }
+
+    #[test]
+    fn tcp_relay() {
+        let mut net = user_net(Vec::new());
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let host = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0; 4];
+            stream.read_exact(&mut request).unwrap();
+            assert_eq!(&request, b"ping");
+            stream.write_all(b"pong").unwrap();
+        });
+
+        let mut guest = Guest::new();
+        let handle = guest.sockets.add(tcp_socket());
+        let context = guest.iface.context();
+        guest
+            .sockets
+            .get_mut::<tcp::Socket>(handle)
+            .connect(context, (IpAddress::from(GATEWAY), port), 49152)
+            .unwrap();
+
+        guest.run(&mut net, |guest| guest.socket(handle).may_send());
+        guest.socket(handle).send_slice(b"ping").unwrap();
+
+        // The host closes its side once it replied.
+        let mut reply = Vec::new();
+        guest.run(&mut net, |guest| {
+            let socket = guest.socket(handle);
+            while let Ok(data) = socket.recv(|data| (data.len(), data.to_vec())) {
+                if data.is_empty() {
+                    break;
+                }
+                reply.extend(data);
+            }
+            !socket.may_recv()
+        });
+        assert_eq!(reply, b"pong");
+        host.join().unwrap();
+
+        guest.socket(handle).close();
+        guest.run(&mut net, |guest| !guest.socket(handle).is_open());
+        let stack = net.stack.get_mut().unwrap();
+        assert!(stack.tcp_flows.is_empty());
+        assert!(stack.host_sockets.is_empty());
+    }
+
+    #[test]
+    fn host_forward() {
+        // Find a free port.
+        let port = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let mut net = user_net(vec![HostForward {
+            host: SocketAddrV4::new(Ipv4Addr::LOCALHOST, port),
+            guest_addr: None,
+            guest_port: 80,
+        }]);
+
+        let mut guest = Guest::new();
+        let handle = guest.sockets.add(tcp_socket());
+        guest.socket(handle).listen(80).unwrap();
+
+        let host = thread::spawn(move || {
+            let mut stream = TcpStream::connect((Ipv4Addr::LOCALHOST, port)).unwrap();
+            stream.write_all(b"ping").unwrap();
+            let mut reply = Vec::new();
+            stream.read_to_end(&mut reply).unwrap();
+            reply
+        });
+
+        let mut request = [0; 4];
+        guest.run(&mut net, |guest| guest.socket(handle).recv_queue() == 4);
+        guest.socket(handle).recv_slice(&mut request).unwrap();
+        assert_eq!(&request, b"ping");
+        // The connection comes from the gateway.
+        assert_eq!(
+            guest.socket(handle).remote_endpoint().unwrap().addr,
+            IpAddress::from(GATEWAY)
+        );
+
+        guest.socket(handle).send_slice(b"pong").unwrap();
+        guest.socket(handle).close();
+        guest.run(&mut net, |_| host.is_finished());
+        assert_eq!(host.join().unwrap(), b"pong");
+    }
+}
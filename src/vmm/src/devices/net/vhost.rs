@@ -0,0 +1,377 @@
+// SPDX-License-Identifier: Apache-2.0
+
+// vhost-net lets the host kernel move the frames between the tap and the virtqueues,
+// without copying them through the VMM. The ioctls and structures come from
+// include/uapi/linux/vhost.h and vhost_types.h.
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use virtio_queue::{Queue, QueueT};
+use vm_memory::{
+    Address, ByteValued, GuestAddress, GuestMemory, GuestMemoryRegion, MemoryRegionAddress,
+};
+use vmm_sys_util::eventfd::EventFd;
+use vmm_sys_util::ioctl::{ioctl, ioctl_with_mut_ref, ioctl_with_ptr, ioctl_with_ref};
+use vmm_sys_util::{ioctl_io_nr, ioctl_ioc_nr, ioctl_ior_nr, ioctl_iow_nr};
+
+use super::{Result, VirtioNetError};
+
+const VHOST: ::std::os::raw::c_uint = 0xaf;
+ioctl_ior_nr!(VHOST_GET_FEATURES, VHOST, 0x00, u64);
+ioctl_iow_nr!(VHOST_SET_FEATURES, VHOST, 0x00, u64);
+ioctl_io_nr!(VHOST_SET_OWNER, VHOST, 0x01);
+ioctl_iow_nr!(VHOST_SET_MEM_TABLE, VHOST, 0x03, VhostMemory);
+ioctl_iow_nr!(VHOST_SET_VRING_NUM, VHOST, 0x10, VhostVringState);
+ioctl_iow_nr!(VHOST_SET_VRING_ADDR, VHOST, 0x11, VhostVringAddr);
+ioctl_iow_nr!(VHOST_SET_VRING_BASE, VHOST, 0x12, VhostVringState);
+ioctl_iow_nr!(VHOST_SET_VRING_KICK, VHOST, 0x20, VhostVringFile);
+ioctl_iow_nr!(VHOST_SET_VRING_CALL, VHOST, 0x21, VhostVringFile);
+ioctl_iow_nr!(VHOST_NET_SET_BACKEND, VHOST, 0x30, VhostVringFile);
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct VhostVringState {
+    index: u32,
+    num: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct VhostVringFile {
+    index: u32,
+    fd: i32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct VhostVringAddr {
+    index: u32,
+    flags: u32,
+    desc_user_addr: u64,
+    used_user_addr: u64,
+    avail_user_addr: u64,
+    log_guest_addr: u64,
+}
+
+// Header of the memory table, followed by the regions.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct VhostMemory {
+    nregions: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct VhostMemoryRegion {
+    guest_phys_addr: u64,
+    memory_size: u64,
+    userspace_addr: u64,
+    flags_padding: u64,
+}
+
+// Safe because both structures only hold plain integers, without implicit padding.
+unsafe impl ByteValued for VhostMemory {}
+unsafe impl ByteValued for VhostMemoryRegion {}
+
+/// Number of virtqueues handed over to vhost-net: RX and TX.
+pub(crate) const VHOST_QUEUES: usize = 2;
+
+/// A vhost-net instance, running the datapath of one virtio-net device in the host kernel.
+///
+/// The guest notifications reach vhost through the `kick` eventfds, which the VMM registers
+/// as ioeventfds. vhost signals the used buffers on the `call` eventfd.
+pub struct VhostNet {
+    vhost_file: File,
+    features: u64,
+    kick: [EventFd; VHOST_QUEUES],
+    call: EventFd,
+}
+
+impl VhostNet {
+    /// Open `/dev/vhost-net`, and take ownership of the instance.
+    pub fn new() -> Result<Self> {
+        let vhost_file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/vhost-net")
+            .map_err(VirtioNetError::IoError)?;
+        let eventfd = || EventFd::new(libc::EFD_NONBLOCK).map_err(VirtioNetError::IoError);
+
+        let mut vhost = VhostNet {
+            vhost_file,
+            features: 0,
+            kick: [eventfd()?, eventfd()?],
+            call: eventfd()?,
+        };
+
+        // Safe because we know that our file is a vhost device and we verify the result.
+        let ret = unsafe { ioctl(&vhost, VHOST_SET_OWNER()) };
+        check(ret)?;
+
+        let mut features = 0u64;
+        // Safe because the kernel only writes a u64, and we verify the result.
+        let ret = unsafe { ioctl_with_mut_ref(&vhost, VHOST_GET_FEATURES(), &mut features) };
+        check(ret)?;
+        vhost.features = features;
+
+        Ok(vhost)
+    }
+
+    /// Virtio features vhost-net supports.
+    pub fn features(&self) -> u64 {
+        self.features
+    }
+
+    /// Eventfd the guest kicks the given queue through.
+    pub fn kick(&self, queue_index: usize) -> &EventFd {
+        &self.kick[queue_index]
+    }
+
+    /// Eventfd vhost signals the used buffers on, for all the queues.
+    pub fn call(&self) -> &EventFd {
+        &self.call
+    }
+
+    /// Start the datapath, once the driver set up the queues.
+    ///
+    /// vhost-net then reads and writes the frames from the `backend` tap directly.
+    pub fn activate<M: GuestMemory>(
+        &self,
+        mem: &M,
+        acked_features: u64,
+        queues: &[Queue],
+        backend: RawFd,
+    ) -> Result<()> {
+        // Safe because the kernel only reads a u64, and we verify the result.
+        let ret = unsafe { ioctl_with_ref(self, VHOST_SET_FEATURES(), &acked_features) };
+        check(ret)?;
+
+        self.set_mem_table(mem)?;
+
+        for (index, queue) in queues.iter().enumerate() {
+            let host_address = |address: u64| {
+                mem.get_host_address(GuestAddress(address))
+                    .map(|address| address as u64)
+                    .map_err(VirtioNetError::MemoryError)
+            };
+            let index = index as u32;
+
+            let num = VhostVringState {
+                index,
+                num: u32::from(queue.size()),
+            };
+            let base = VhostVringState {
+                index,
+                num: u32::from(queue.next_avail()),
+            };
+            let addr = VhostVringAddr {
+                index,
+                desc_user_addr: host_address(queue.desc_table())?,
+                used_user_addr: host_address(queue.used_ring())?,
+                avail_user_addr: host_address(queue.avail_ring())?,
+                ..Default::default()
+            };
+            let kick = VhostVringFile {
+                index,
+                fd: self.kick[index as usize].as_raw_fd(),
+            };
+            let call = VhostVringFile {
+                index,
+                fd: self.call.as_raw_fd(),
+            };
+
+            // Safe because the kernel only reads the structures we pass, and we verify the
+            // results.
+            let ret = unsafe { ioctl_with_ref(self, VHOST_SET_VRING_NUM(), &num) };
+            check(ret)?;
+            let ret = unsafe { ioctl_with_ref(self, VHOST_SET_VRING_BASE(), &base) };
+            check(ret)?;
+            let ret = unsafe { ioctl_with_ref(self, VHOST_SET_VRING_ADDR(), &addr) };
+            check(ret)?;
+            let ret = unsafe { ioctl_with_ref(self, VHOST_SET_VRING_KICK(), &kick) };
+            check(ret)?;
+            let ret = unsafe { ioctl_with_ref(self, VHOST_SET_VRING_CALL(), &call) };
+            check(ret)?;
+        }
+
+        for index in 0..queues.len() as u32 {
+            let backend = VhostVringFile { index, fd: backend };
+            // Safe because the kernel only reads the structure we pass, and we verify the result.
+            let ret = unsafe { ioctl_with_ref(self, VHOST_NET_SET_BACKEND(), &backend) };
+            check(ret)?;
+        }
+
+        Ok(())
+    }
+
+    /// Stop the datapath, until the next activation.
+    pub fn deactivate(&self) -> Result<()> {
+        for index in 0..VHOST_QUEUES as u32 {
+            let backend = VhostVringFile { index, fd: -1 };
+            // Safe because the kernel only reads the structure we pass, and we verify the result.
+            let ret = unsafe { ioctl_with_ref(self, VHOST_NET_SET_BACKEND(), &backend) };
+            check(ret)?;
+        }
+
+        Ok(())
+    }
+
+    // Tell vhost where the guest memory lives in the VMM address space.
+    fn set_mem_table<M: GuestMemory>(&self, mem: &M) -> Result<()> {
+        let mut regions = Vec::new();
+        for region in mem.iter() {
+            let host_address = region
+                .get_host_address(MemoryRegionAddress(0))
+                .map_err(VirtioNetError::MemoryError)?;
+            regions.push(VhostMemoryRegion {
+                guest_phys_addr: region.start_addr().raw_value(),
+                memory_size: region.len(),
+                userspace_addr: host_address as u64,
+                flags_padding: 0,
+            });
+        }
+
+        // The kernel expects the regions right after the header.
+        let header = VhostMemory {
+            nregions: regions.len() as u32,
+            padding: 0,
+        };
+        let mut table = header.as_slice().to_vec();
+        for region in regions.iter() {
+            table.extend_from_slice(region.as_slice());
+        }
+
+        // Safe because the table holds the number of regions the header announces, and we
+        // verify the result.
+        let ret = unsafe { ioctl_with_ptr(self, VHOST_SET_MEM_TABLE(), table.as_ptr()) };
+        check(ret)
+    }
+}
+
+fn check(ret: i32) -> Result<()> {
+    if ret < 0 {
+        return Err(VirtioNetError::IoCtlError(io::Error::last_os_error()));
+    }
+
+    Ok(())
+}
+
+impl AsRawFd for VhostNet {
+    fn as_raw_fd(&self) -> RawFd {
+        self.vhost_file.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::sync::Arc;
+
+    use virtio_bindings::bindings::virtio_config::{
+        VIRTIO_CONFIG_S_ACKNOWLEDGE, VIRTIO_CONFIG_S_DRIVER, VIRTIO_CONFIG_S_FEATURES_OK,
+    };
+    use virtio_device::VirtioMmioDevice;
+    use vm_memory::GuestMemoryMmap;
+
+    use super::super::bindings::VIRTIO_F_VERSION_1;
+    use super::super::interface::Interface;
+    use super::super::VirtioNet;
+    use crate::config::NetConfig;
+    use crate::rate_limiter::RateLimiter;
+
+    // An interface which never has anything to read.
+    struct IdleInterface {
+        fd: EventFd,
+    }
+
+    impl Read for IdleInterface {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::from_raw_os_error(libc::EAGAIN))
+        }
+    }
+
+    impl Write for IdleInterface {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl AsRawFd for IdleInterface {
+        fn as_raw_fd(&self) -> RawFd {
+            self.fd.as_raw_fd()
+        }
+    }
+
+    impl Interface for IdleInterface {
+        fn activate(&self, virtio_flags: u64, _virtio_header_size: usize) -> Result<u64> {
+            Ok(virtio_flags)
+        }
+
+        fn open_config(_config: &NetConfig) -> Result<Self> {
+            let fd = EventFd::new(libc::EFD_NONBLOCK).map_err(VirtioNetError::IoError)?;
+            Ok(IdleInterface { fd })
+        }
+    }
+
+    fn read_register<D: VirtioMmioDevice>(device: &D, offset: u64) -> u32 {
+        let mut data = [0u8; 4];
+        device.read(offset, &mut data);
+        u32::from_le_bytes(data)
+    }
+
+    fn write_register<D: VirtioMmioDevice>(device: &mut D, offset: u64, value: u32) {
+        device.write(offset, &value.to_le_bytes());
+    }
+
+    #[test]
+    fn negotiation() {
+        let vhost = match VhostNet::new() {
+            Ok(vhost) => vhost,
+            Err(e) => {
+                println!("Skipping, vhost-net is unavailable: {:?}", e);
+                return;
+            }
+        };
+        let vhost_features = vhost.features();
+
+        let mem = Arc::new(GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap());
+        let mut net = VirtioNet::<_, IdleInterface>::new(
+            mem,
+            EventFd::new(libc::EFD_NONBLOCK).unwrap(),
+            &NetConfig::default(),
+            RateLimiter::new(None, None).unwrap(),
+            RateLimiter::new(None, None).unwrap(),
+            Some(vhost),
+        )
+        .unwrap();
+
+        // DeviceFeaturesSel selects which half DeviceFeatures shows.
+        let mut device_features = 0u64;
+        for half in 0..2 {
+            write_register(&mut net, 0x14, half);
+            device_features |= u64::from(read_register(&net, 0x10)) << (32 * half);
+        }
+        assert_eq!(device_features & !vhost_features, 0);
+        assert_ne!(device_features & (1 << VIRTIO_F_VERSION_1), 0);
+
+        // The driver takes everything, through DriverFeaturesSel and DriverFeatures.
+        let status = VIRTIO_CONFIG_S_ACKNOWLEDGE | VIRTIO_CONFIG_S_DRIVER;
+        write_register(&mut net, 0x70, status);
+        for half in 0..2 {
+            write_register(&mut net, 0x24, half);
+            write_register(&mut net, 0x20, (device_features >> (32 * half)) as u32);
+        }
+        write_register(&mut net, 0x70, status | VIRTIO_CONFIG_S_FEATURES_OK);
+
+        assert_eq!(net.device_config.driver_features, device_features);
+        assert_ne!(read_register(&net, 0x70) & VIRTIO_CONFIG_S_FEATURES_OK, 0);
+    }
+}
@@ -0,0 +1,529 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use vm_memory::GuestAddressSpace;
+use vmm_sys_util::eventfd::EventFd;
+
+use super::interface::Interface;
+use super::{VirtioNet, VirtioNetError};
+use crate::epoll_context::{EpollContext, EventHandler, EventOps, Events, Interest, Token};
+use crate::Error;
+
+// Event tokens. The RX kicks come before the interface, so that a flood does not delay
+// them.
+const VHOST_CALL: Token = Token(0);
+const TX_LIMITER: Token = Token(1);
+const RX_LIMITER: Token = Token(2);
+const RX_KICK: Token = Token(3);
+const INTERFACE: Token = Token(4);
+const RECONNECT: Token = Token(5);
+const STOP: Token = Token(6);
+const PAUSE: Token = Token(7);
+// The tokens polled for the device, until it is paused.
+const DEVICE_TOKENS: [Token; 6] = [
+    VHOST_CALL, TX_LIMITER, RX_LIMITER, RX_KICK, INTERFACE, RECONNECT,
+];
+
+// Moves the frames between a virtio-net device and its interface, off the VMM event loop.
+struct Worker<M: GuestAddressSpace + Clone + Send, I: Interface> {
+    net: Arc<Mutex<VirtioNet<M, I>>>,
+    epoll: EpollContext,
+    stopped: Arc<AtomicBool>,
+}
+
+// Stops the worker once its handle asks it to.
+struct StopHandler {
+    stop: EventFd,
+    stopped: Arc<AtomicBool>,
+}
+
+/// A running virtio-net worker thread.
+pub(crate) struct WorkerHandle {
+    stop: EventFd,
+    thread: JoinHandle<()>,
+}
+
+impl WorkerHandle {
+    /// Stop polling the device, and wait for the thread to exit. The device keeps its
+    /// state, and may be dropped afterwards.
+    pub fn stop(self) -> thread::Result<()> {
+        self.stop.write(1).unwrap_or_else(|e| {
+            println!("Failed to stop the virtio-net worker: {:?}", e);
+        });
+        self.thread.join()
+    }
+}
+
+// Handles the events of one of the device file descriptors, told apart by their token.
+struct NetHandler<M: GuestAddressSpace + Clone + Send, I: Interface> {
+    net: Arc<Mutex<VirtioNet<M, I>>>,
+}
+
+/// Run the virtio-net device I/O on its own thread.
+///
+/// The thread polls the interface, the RX queue kicks and the rate limiter timers. When
+/// the interface goes away, e.g. its tap is deleted, the thread tries to open it again
+/// until it is back, and polls the new one. When the device fails, the thread marks it as
+/// needing a reset, and leaves it alone until the driver resets it. The rest of the VM
+/// keeps running.
+///
+/// With vhost-net, the host kernel moves the frames, and the thread only relays its
+/// notifications to the driver.
+///
+/// Dropping the returned handle leaves the thread running.
+pub(crate) fn spawn_worker<M, I>(net: Arc<Mutex<VirtioNet<M, I>>>) -> io::Result<WorkerHandle>
+where
+    M: GuestAddressSpace + Clone + Send + 'static,
+    I: Interface + 'static,
+{
+    let stop = EventFd::new(libc::EFD_NONBLOCK)?;
+    let worker = Worker::new(net, stop.try_clone()?)?;
+
+    let thread = thread::Builder::new()
+        .name("virtio-net".to_string())
+        .spawn(move || worker.run())?;
+
+    Ok(WorkerHandle { stop, thread })
+}
+
+impl<M, I> Worker<M, I>
+where
+    M: GuestAddressSpace + Clone + Send + 'static,
+    I: Interface + 'static,
+{
+    fn new(net: Arc<Mutex<VirtioNet<M, I>>>, stop: EventFd) -> io::Result<Self> {
+        let mut epoll = EpollContext::new()?;
+        let stopped = Arc::new(AtomicBool::new(false));
+        let handler = StopHandler {
+            stop,
+            stopped: stopped.clone(),
+        };
+        epoll.add(
+            handler.stop.as_raw_fd(),
+            STOP,
+            Interest::Read,
+            Box::new(handler),
+        )?;
+        {
+            let device = net.lock().unwrap();
+            let mut add = |fd, token| {
+                let handler = NetHandler { net: net.clone() };
+                epoll.add(fd, token, Interest::Read, Box::new(handler))
+            };
+
+            add(device.pause_kick.as_raw_fd(), PAUSE)?;
+            match device.vhost() {
+                Some(vhost) => add(vhost.call().as_raw_fd(), VHOST_CALL)?,
+                None => {
+                    add(device.interface.as_raw_fd(), INTERFACE)?;
+                    add(device.rx_kick.as_raw_fd(), RX_KICK)?;
+                    add(device.reconnect_timer.as_raw_fd(), RECONNECT)?;
+                    if device.rx_limiter.is_limited() {
+                        add(device.rx_limiter.as_raw_fd(), RX_LIMITER)?;
+                    }
+                    if device.tx_limiter.is_limited() {
+                        add(device.tx_limiter.as_raw_fd(), TX_LIMITER)?;
+                    }
+                }
+            }
+        }
+
+        Ok(Worker {
+            net,
+            epoll,
+            stopped,
+        })
+    }
+
+    fn run(mut self) {
+        while !self.stopped.load(Ordering::SeqCst) {
+            let error = match self.epoll.run_once(None) {
+                Ok(_) => continue,
+                Err(Error::VirtioNet(e)) => e,
+                Err(Error::EpollError(e)) => VirtioNetError::IoError(e),
+                Err(e) => VirtioNetError::IoError(io::Error::other(e.to_string())),
+            };
+            // Which kicks us, to stop polling the device until it is reset.
+            self.net.lock().unwrap().fail(error);
+        }
+    }
+}
+
+impl EventHandler for StopHandler {
+    fn process(&mut self, _events: Events, _ops: &mut EventOps) -> crate::Result<()> {
+        // The counter only wakes us up, its value does not matter.
+        let _ = self.stop.read();
+        self.stopped.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+impl<M, I> EventHandler for NetHandler<M, I>
+where
+    M: GuestAddressSpace + Clone + Send + 'static,
+    I: Interface + 'static,
+{
+    fn process(&mut self, _events: Events, ops: &mut EventOps) -> crate::Result<()> {
+        let mut net = self.net.lock().unwrap();
+
+        // Nothing moves while paused, or failed until the driver resets the device: the
+        // frames for the guest wait in the host queue.
+        if ops.token() == PAUSE {
+            // The counter only wakes us up, its value does not matter.
+            let _ = net.pause_kick.read();
+        }
+        if net.paused || net.failed() {
+            for token in DEVICE_TOKENS {
+                ops.pause(token);
+            }
+            return Ok(());
+        }
+
+        match ops.token() {
+            PAUSE => {
+                for token in DEVICE_TOKENS {
+                    ops.resume(token);
+                }
+            }
+            VHOST_CALL => {
+                net.vhost_call_event();
+                return Ok(());
+            }
+            TX_LIMITER => {
+                net.tx_limiter_event();
+                return Ok(());
+            }
+            RX_LIMITER => net.rx_limiter_event()?,
+            RX_KICK => net.rx_kick_event()?,
+            RECONNECT => {
+                if net.reconnect_event() {
+                    ops.set_fd(INTERFACE, net.interface.as_raw_fd());
+                }
+            }
+            _ => {
+                net.irq_trace.processing(ops.wakeup());
+                net.process_tap()?
+            }
+        }
+
+        // The interface stays readable while the rate limiter holds a frame back, and
+        // reports errors once it went away.
+        if net.rx_limiter.is_blocked() || !net.link_up() {
+            ops.pause(INTERFACE);
+        } else {
+            ops.resume(INTERFACE);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::os::unix::io::RawFd;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::MutexGuard;
+    use std::time::{Duration, Instant};
+
+    use virtio_bindings::bindings::virtio_config::VIRTIO_CONFIG_S_NEEDS_RESET;
+    use vm_device::bus::MmioAddress;
+    use vm_device::MutDeviceMmio;
+    use vm_memory::{GuestAddress, GuestMemoryMmap};
+    use vmm_sys_util::eventfd::EventFd;
+
+    use super::super::testing::{
+        driver_init, guest_memory, rx_frame, write_register, MockInterface,
+    };
+    use super::super::{Result, VIRTIO_FEATURES};
+    use crate::config::NetConfig;
+    use crate::devices::virtio::VIRTIO_MMIO_INT_CONFIG;
+    use crate::rate_limiter::RateLimiter;
+
+    // An interface which is always readable, and fails all the reads.
+    struct BrokenInterface {
+        readable: EventFd,
+    }
+
+    impl Read for BrokenInterface {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::from_raw_os_error(libc::EIO))
+        }
+    }
+
+    impl Write for BrokenInterface {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl AsRawFd for BrokenInterface {
+        fn as_raw_fd(&self) -> RawFd {
+            self.readable.as_raw_fd()
+        }
+    }
+
+    impl Interface for BrokenInterface {
+        fn activate(&self, virtio_flags: u64, _virtio_header_size: usize) -> Result<u64> {
+            Ok(virtio_flags)
+        }
+
+        fn open_config(_config: &NetConfig) -> Result<Self> {
+            let readable = EventFd::new(libc::EFD_NONBLOCK).map_err(VirtioNetError::IoError)?;
+            readable.write(1).map_err(VirtioNetError::IoError)?;
+            Ok(BrokenInterface { readable })
+        }
+    }
+
+    // An interface which always has a frame to read, until stopped.
+    struct FloodInterface {
+        readable: EventFd,
+        stop: AtomicBool,
+    }
+
+    impl Read for FloodInterface {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.stop.load(Ordering::SeqCst) {
+                return Err(io::Error::from_raw_os_error(libc::EIO));
+            }
+
+            buf[..64].fill(0xff);
+            Ok(64)
+        }
+    }
+
+    impl Write for FloodInterface {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl AsRawFd for FloodInterface {
+        fn as_raw_fd(&self) -> RawFd {
+            self.readable.as_raw_fd()
+        }
+    }
+
+    impl Interface for FloodInterface {
+        fn activate(&self, virtio_flags: u64, _virtio_header_size: usize) -> Result<u64> {
+            Ok(virtio_flags)
+        }
+
+        fn open_config(_config: &NetConfig) -> Result<Self> {
+            let readable = EventFd::new(libc::EFD_NONBLOCK).map_err(VirtioNetError::IoError)?;
+            readable.write(1).map_err(VirtioNetError::IoError)?;
+            Ok(FloodInterface {
+                readable,
+                stop: AtomicBool::new(false),
+            })
+        }
+    }
+
+    fn new_net<I: Interface>(
+        mem: &Arc<GuestMemoryMmap>,
+    ) -> Arc<Mutex<VirtioNet<Arc<GuestMemoryMmap>, I>>> {
+        let net = VirtioNet::new(
+            mem.clone(),
+            EventFd::new(libc::EFD_NONBLOCK).unwrap(),
+            &NetConfig::default(),
+            RateLimiter::new(None, None).unwrap(),
+            RateLimiter::new(None, None).unwrap(),
+            None,
+        )
+        .unwrap();
+
+        Arc::new(Mutex::new(net))
+    }
+
+    // Lock the device as a vCPU would, failing if the worker keeps it for too long.
+    fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<T> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            if let Ok(guard) = mutex.try_lock() {
+                return guard;
+            }
+            assert!(
+                Instant::now() < deadline,
+                "the worker keeps the device locked"
+            );
+            thread::yield_now();
+        }
+    }
+
+    fn wait_for(mut condition: impl FnMut() -> bool, message: &str) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !condition() {
+            assert!(Instant::now() < deadline, "{}", message);
+            thread::yield_now();
+        }
+    }
+
+    fn readable(fd: RawFd) -> bool {
+        let mut pollfd = libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // Safe because we pass a single valid pollfd, and check the result.
+        let ret = unsafe { libc::poll(&mut pollfd, 1, 0) };
+        assert!(ret >= 0);
+        pollfd.revents & libc::POLLIN != 0
+    }
+
+    #[test]
+    fn read_errors() {
+        const DEVICES: usize = 16;
+        let mem = Arc::new(GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap());
+
+        let devices: Vec<_> = (0..DEVICES)
+            .map(|_| new_net::<BrokenInterface>(&mem))
+            .collect();
+        let workers: Vec<_> = devices
+            .iter()
+            .map(|net| spawn_worker(net.clone()).unwrap())
+            .collect();
+
+        wait_for(
+            || devices.iter().all(|net| lock(net).failed()),
+            "the devices did not fail",
+        );
+
+        // The vCPUs still get to the failed devices, the workers do not keep them locked.
+        for _ in 0..100 {
+            for net in devices.iter() {
+                let start = Instant::now();
+                let mut status = [0u8; 4];
+                lock(net).mmio_read(MmioAddress(0), 0x70, &mut status);
+                assert_ne!(u32::from_le_bytes(status) & VIRTIO_CONFIG_S_NEEDS_RESET, 0);
+                assert!(start.elapsed() < Duration::from_millis(100));
+            }
+        }
+
+        for worker in workers {
+            worker.stop().unwrap();
+        }
+
+        for net in devices {
+            let net = net.lock().unwrap();
+            assert_ne!(
+                net.device_config.interrupt_status.load(Ordering::SeqCst) & VIRTIO_MMIO_INT_CONFIG,
+                0
+            );
+            // The driver heard about it.
+            assert_eq!(net.guest_irq_fd.read().unwrap(), 1);
+        }
+    }
+
+    #[test]
+    fn rx_flood() {
+        let mem = Arc::new(GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap());
+        let net = new_net::<FloodInterface>(&mem);
+        let stats = net.lock().unwrap().stats();
+        let worker = spawn_worker(net.clone()).unwrap();
+
+        // The driver is not ready, the worker keeps dropping frames. It still gets to the RX
+        // kicks, and releases the device in between.
+        for _ in 0..100 {
+            let rx_kick = {
+                let net = lock(&net);
+                net.rx_kick.write(1).unwrap();
+                net.rx_kick.as_raw_fd()
+            };
+
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while readable(rx_kick) {
+                assert!(Instant::now() < deadline, "the RX kick was not handled");
+                thread::yield_now();
+            }
+        }
+        assert!(stats.rx_budget_exhausted.load(Ordering::Relaxed) > 0);
+
+        lock(&net).interface.stop.store(true, Ordering::SeqCst);
+        wait_for(|| lock(&net).failed(), "the device did not fail");
+        worker.stop().unwrap();
+    }
+
+    #[test]
+    fn failure_reset() {
+        let mem = guest_memory(0x20000);
+        let net = new_net::<MockInterface>(&mem);
+        let stats = net.lock().unwrap().stats();
+        driver_init(&mut net.lock().unwrap(), &mem, VIRTIO_FEATURES);
+        let worker = spawn_worker(net.clone()).unwrap();
+
+        // A read fails, and the device with it.
+        lock(&net).interface.error = Some(libc::EIO);
+        lock(&net).interface.receive(rx_frame(64));
+        wait_for(|| lock(&net).failed(), "the device did not fail");
+
+        // The driver resets the device, and probes it again.
+        lock(&net).interface.error = None;
+        write_register(&mut lock(&net), 0x70, 0);
+        assert!(!lock(&net).failed());
+        let (mut rx, mut tx) = driver_init(&mut lock(&net), &mem, VIRTIO_FEATURES);
+
+        // The frames move again, both ways.
+        rx.post_buffer(&mem, 0x8000, 2048);
+        lock(&net).interface.receive(rx_frame(64));
+        wait_for(|| rx.used_index(&mem) > 0, "the frame was not delivered");
+        assert!(stats.rx_packets.load(Ordering::Relaxed) > 0);
+        tx.send_frame(&mut lock(&net), &mem, &[0xcd; 64]);
+        assert_eq!(lock(&net).interface.sent.len(), 1);
+
+        worker.stop().unwrap();
+    }
+
+    #[test]
+    fn pause() {
+        let mem = Arc::new(GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap());
+        let net = new_net::<MockInterface>(&mem);
+        let stats = net.lock().unwrap().stats();
+        let (mut rx, _) = driver_init(&mut net.lock().unwrap(), &mem, VIRTIO_FEATURES);
+        rx.post_buffer(&mem, 0x8000, 2048);
+        let worker = spawn_worker(net.clone()).unwrap();
+
+        // The frame the host sends while paused waits in its queue.
+        lock(&net).set_paused(true);
+        lock(&net).interface.receive(rx_frame(64));
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(rx.used_index(&mem), 0);
+        assert_eq!(lock(&net).interface.received.len(), 1);
+
+        // And reaches the guest once resumed.
+        lock(&net).set_paused(false);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while rx.used_index(&mem) == 0 {
+            assert!(Instant::now() < deadline, "the frame was not delivered");
+            thread::yield_now();
+        }
+        assert_eq!(stats.rx_packets.load(Ordering::Relaxed), 1);
+
+        worker.stop().unwrap();
+    }
+
+    #[test]
+    fn stop() {
+        let mem = Arc::new(GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap());
+        let net = new_net::<MockInterface>(&mem);
+        let worker = spawn_worker(net.clone()).unwrap();
+
+        worker.stop().unwrap();
+        // The worker released the device, as it was.
+        assert_eq!(Arc::strong_count(&net), 1);
+        assert!(!net.lock().unwrap().failed());
+    }
+}
@@ -0,0 +1,263 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! virtio-9p device, sharing a host directory with the guest, which mounts it by its tag:
+//!
+//! ```text
+//! mount -t 9p -o trans=virtio,version=9p2000.L <tag> /mnt
+//! ```
+
+mod protocol;
+mod server;
+
+use std::borrow::{Borrow, BorrowMut};
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use virtio_device::{VirtioConfig, VirtioDeviceActions, VirtioDeviceType, VirtioMmioDevice};
+use virtio_queue::{Queue, QueueOwnedT, QueueT};
+use vm_device::{
+    bus::{MmioAddress, MmioAddressOffset},
+    MutDeviceMmio,
+};
+use vm_memory::{Bytes, GuestAddressSpace};
+use vmm_sys_util::eventfd::EventFd;
+
+use crate::devices::irq_trace::IrqTrace;
+use crate::devices::virtio::{self, VIRTIO_MMIO_INTERRUPT_ACK, VIRTIO_MMIO_INT_VRING};
+use server::{Server, MAX_MESSAGE_SIZE};
+
+/// virtio-9p device ID.
+/// See https://docs.oasis-open.org/virtio/virtio/v1.2/virtio-v1.2.html#x1-2270009
+pub(crate) const VIRTIO_9P_DEVICE_ID: u32 = 9;
+const VIRTIO_F_VERSION_1: u64 = 32;
+// The configuration space holds the mount tag.
+const VIRTIO_9P_MOUNT_TAG: u64 = 0;
+const VIRTIO_FEATURES: u64 = (1 << VIRTIO_F_VERSION_1) | (1 << VIRTIO_9P_MOUNT_TAG);
+
+const QUEUE_SIZE: u16 = 128;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// Failed to open the shared directory.
+    #[error("failed to open the shared directory")]
+    Open(#[source] io::Error),
+    /// Failed to create the eventfd waking the worker up.
+    #[error("failed to create the worker eventfd")]
+    EventFd(#[source] io::Error),
+    /// Failed to create the request queue.
+    #[error("virtio queue error: {0:?}")]
+    Queue(virtio_queue::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Serialize the virtio-9p configuration space: the length of the tag, then the tag.
+fn config_vec(tag: &str) -> Vec<u8> {
+    let mut config_vec = Vec::new();
+    config_vec.extend_from_slice(&(tag.len() as u16).to_le_bytes());
+    config_vec.extend_from_slice(tag.as_bytes());
+    config_vec
+}
+
+/// virtio-9p device, serving a host directory with the 9P2000.L protocol.
+pub struct Virtio9p<M: GuestAddressSpace + Clone + Send> {
+    pub device_config: VirtioConfig<Queue>,
+    pub guest_irq_fd: EventFd,
+    pub address_space: M,
+    server: Server,
+    irq_trace: Arc<IrqTrace>,
+    // Written when the driver sends requests, to wake the worker up.
+    kick: EventFd,
+}
+
+impl<M: GuestAddressSpace + Clone + Send> Virtio9p<M> {
+    pub fn new(
+        memory: M,
+        irq_fd: EventFd,
+        tag: &str,
+        path: &Path,
+        read_only: bool,
+    ) -> Result<Self> {
+        Ok(Virtio9p {
+            device_config: VirtioConfig::new(
+                VIRTIO_FEATURES,
+                vec![Queue::new(QUEUE_SIZE).map_err(Error::Queue)?],
+                config_vec(tag),
+            ),
+            guest_irq_fd: irq_fd,
+            address_space: memory,
+            server: Server::new(path, read_only).map_err(Error::Open)?,
+            irq_trace: Arc::new(IrqTrace::new("shared-dir")),
+            kick: EventFd::new(0).map_err(Error::EventFd)?,
+        })
+    }
+
+    /// The interrupt latency of the device.
+    pub(crate) fn irq_trace(&self) -> Arc<IrqTrace> {
+        self.irq_trace.clone()
+    }
+
+    // Answer the pending requests, in order.
+    fn process_queue(&mut self) {
+        let mem = self.address_space.memory().clone();
+
+        loop {
+            if let Err(e) = self.device_config.queues[0].disable_notification(&*mem) {
+                log::error!("Failed to disable notification: {:?}", e);
+                break;
+            }
+
+            // Never fails since we know the memory is valid.
+            while let Some(chain) = self.device_config.queues[0].iter(&*mem).unwrap().next() {
+                // The request is in the readable buffers, the response goes to the writable
+                // ones. A request larger than a message is cut, and rejected.
+                let mut request = Vec::new();
+                let mut buffers = Vec::new();
+                for desc in chain.clone() {
+                    let len = desc.len() as usize;
+                    if desc.is_write_only() {
+                        buffers.push((desc.addr(), len));
+                    } else if request.len() + len <= MAX_MESSAGE_SIZE as usize {
+                        let start = request.len();
+                        request.resize(start + len, 0);
+                        if let Err(e) = mem.read_slice(&mut request[start..], desc.addr()) {
+                            log::warn!("Failed to read 9p request: {:?}", e);
+                        }
+                    }
+                }
+
+                let response = self.server.handle(&request);
+                let mut used_len = 0;
+                for (addr, len) in buffers {
+                    let end = response.len().min(used_len + len);
+                    if let Err(e) = mem.write_slice(&response[used_len..end], addr) {
+                        log::warn!("Failed to write 9p response: {:?}", e);
+                        break;
+                    }
+                    used_len = end;
+                }
+                if used_len < response.len() {
+                    log::warn!("9p response larger than its buffers");
+                }
+
+                let queue = &mut self.device_config.queues[0];
+                // Try continuing even if we failed to add the used buffer.
+                queue
+                    .add_used(&*mem, chain.head_index(), used_len as u32)
+                    .unwrap_or_else(|e| {
+                        log::error!("Failed to add used buffer: {:?}", e);
+                    });
+
+                if queue.needs_notification(&*mem).unwrap_or_default() {
+                    virtio::signal(
+                        &self.device_config.interrupt_status,
+                        &self.guest_irq_fd,
+                        &self.irq_trace,
+                        VIRTIO_MMIO_INT_VRING,
+                    );
+                }
+            }
+
+            if !self.device_config.queues[0]
+                .enable_notification(&*mem)
+                .unwrap_or_default()
+            {
+                break;
+            }
+        }
+    }
+}
+
+/// Serve the requests on their own thread, so that the file I/O does not hold the vCPU
+/// sending them.
+///
+/// The thread runs for as long as the VMM.
+pub(crate) fn spawn_worker<M>(p9: Arc<Mutex<Virtio9p<M>>>) -> io::Result<()>
+where
+    M: GuestAddressSpace + Clone + Send + 'static,
+{
+    let kick = p9.lock().unwrap().kick.try_clone()?;
+
+    thread::Builder::new()
+        .name("virtio-9p".to_string())
+        .spawn(move || {
+            // The counter only wakes us up, its value does not matter.
+            while kick.read().is_ok() {
+                p9.lock().unwrap().process_queue();
+            }
+        })?;
+
+    Ok(())
+}
+
+impl<M: GuestAddressSpace + Clone + Send> VirtioDeviceType for Virtio9p<M> {
+    fn device_type(&self) -> u32 {
+        VIRTIO_9P_DEVICE_ID
+    }
+}
+
+impl<M: GuestAddressSpace + Clone + Send> VirtioMmioDevice for Virtio9p<M> {
+    fn queue_notify(&mut self, val: u32) {
+        if val != 0 {
+            return;
+        }
+
+        // The worker thread owns the request processing.
+        self.kick.write(1).unwrap_or_else(|e| {
+            log::error!("Failed to kick the 9p queue: {:?}", e);
+        });
+    }
+}
+
+impl<M: GuestAddressSpace + Clone + Send> Borrow<VirtioConfig<Queue>> for Virtio9p<M> {
+    fn borrow(&self) -> &VirtioConfig<Queue> {
+        &self.device_config
+    }
+}
+
+impl<M: GuestAddressSpace + Clone + Send> BorrowMut<VirtioConfig<Queue>> for Virtio9p<M> {
+    fn borrow_mut(&mut self) -> &mut VirtioConfig<Queue> {
+        &mut self.device_config
+    }
+}
+
+impl<M: GuestAddressSpace + Clone + Send> VirtioDeviceActions for Virtio9p<M> {
+    type E = Error;
+
+    fn activate(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        // The fids go along with the next session.
+        virtio::reset_config(&mut self.device_config);
+        Ok(())
+    }
+}
+
+impl<M: GuestAddressSpace + Clone + Send> MutDeviceMmio for Virtio9p<M> {
+    fn mmio_read(&mut self, _base: MmioAddress, offset: MmioAddressOffset, data: &mut [u8]) {
+        self.read(offset, data);
+    }
+
+    fn mmio_write(&mut self, _base: MmioAddress, offset: MmioAddressOffset, data: &[u8]) {
+        if offset == VIRTIO_MMIO_INTERRUPT_ACK {
+            virtio::ack(&self.device_config.interrupt_status, &self.irq_trace, data);
+            return;
+        }
+
+        self.write(offset, data);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mount_tag() {
+        assert_eq!(config_vec("src"), [3, 0, b's', b'r', b'c']);
+    }
+}
@@ -0,0 +1,171 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! 9P2000.L wire format: little-endian integers, strings prefixed with their 16 bits
+//! length, and messages starting with their size, type and tag.
+//!
+//! See https://github.com/chaos/diod/blob/master/protocol.md
+
+use std::io;
+
+// Message types, each response being its request plus one.
+pub(crate) const RLERROR: u8 = 7;
+pub(crate) const TSTATFS: u8 = 8;
+pub(crate) const TLOPEN: u8 = 12;
+pub(crate) const TLCREATE: u8 = 14;
+pub(crate) const TREADLINK: u8 = 22;
+pub(crate) const TGETATTR: u8 = 24;
+pub(crate) const TSETATTR: u8 = 26;
+pub(crate) const TREADDIR: u8 = 40;
+pub(crate) const TFSYNC: u8 = 50;
+pub(crate) const TMKDIR: u8 = 72;
+pub(crate) const TUNLINKAT: u8 = 76;
+pub(crate) const TVERSION: u8 = 100;
+pub(crate) const TATTACH: u8 = 104;
+pub(crate) const TFLUSH: u8 = 108;
+pub(crate) const TWALK: u8 = 110;
+pub(crate) const TREAD: u8 = 116;
+pub(crate) const TWRITE: u8 = 118;
+pub(crate) const TCLUNK: u8 = 120;
+
+/// Size of the message header: size[4] type[1] tag[2].
+pub(crate) const HEADER_SIZE: usize = 7;
+/// Size of a qid: type[1] version[4] path[8].
+pub(crate) const QID_SIZE: usize = 13;
+
+// Qid types.
+pub(crate) const QTDIR: u8 = 0x80;
+pub(crate) const QTSYMLINK: u8 = 0x02;
+pub(crate) const QTFILE: u8 = 0x00;
+
+/// The only protocol version we speak.
+pub(crate) const VERSION_9P2000_L: &str = "9P2000.L";
+
+/// Unique identifier of a file on the server.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Qid {
+    pub kind: u8,
+    pub version: u32,
+    pub path: u64,
+}
+
+fn invalid() -> io::Error {
+    io::Error::from_raw_os_error(libc::EINVAL)
+}
+
+/// Decodes the fields of a request, after its header.
+pub(crate) struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Reader { buf }
+    }
+
+    /// Take the next `len` bytes. A truncated request is invalid.
+    pub fn bytes(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if len > self.buf.len() {
+            return Err(invalid());
+        }
+        let (bytes, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> io::Result<u16> {
+        // The slice is 2 bytes long.
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    pub fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    /// A string, which the file names are: any bytes but NUL.
+    pub fn string(&mut self) -> io::Result<&'a [u8]> {
+        let len = self.u16()?;
+        let string = self.bytes(len.into())?;
+        if string.contains(&0) {
+            return Err(invalid());
+        }
+        Ok(string)
+    }
+}
+
+/// Encodes a message, or some of its fields.
+#[derive(Default)]
+pub(crate) struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    /// Start the message of type `kind`, tagged `tag`.
+    pub fn new(kind: u8, tag: u16) -> Self {
+        let mut writer = Writer::default();
+        // The size is only known once the message is complete.
+        writer.u32(0);
+        writer.u8(kind);
+        writer.u16(tag);
+        writer
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.buf.push(value);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    /// A string, truncated to the 64 KiB strings can hold.
+    pub fn string(&mut self, string: &[u8]) {
+        let len = string.len().min(u16::MAX.into());
+        self.u16(len as u16);
+        self.bytes(&string[..len]);
+    }
+
+    pub fn qid(&mut self, qid: &Qid) {
+        self.u8(qid.kind);
+        self.u32(qid.version);
+        self.u64(qid.path);
+    }
+
+    /// The fields written so far, without any header.
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+
+    /// The complete message, with its size.
+    pub fn finish(mut self) -> Vec<u8> {
+        let size = self.buf.len() as u32;
+        self.buf[..4].copy_from_slice(&size.to_le_bytes());
+        self.buf
+    }
+}
+
+/// The error response to the request tagged `tag`.
+pub(crate) fn error(tag: u16, errno: u32) -> Vec<u8> {
+    let mut writer = Writer::new(RLERROR, tag);
+    writer.u32(errno);
+    writer.finish()
+}
@@ -0,0 +1,1026 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! 9P2000.L file server, sharing a host directory with the guest.
+//!
+//! A fid names a file by its path from the root of the share. The path is resolved anew
+//! for every operation, one name at a time from the root, without following symlinks:
+//! whatever a symlink points to on the host, the guest cannot leave the share through it.
+//! The guest still sees the symlinks, and resolves them itself, in its own tree. `..`
+//! stops at the root.
+//!
+//! Only regular files and directories can be opened. The files keep the ownership and
+//! permissions they have on the host, the ones the guest creates belong to the VMM user.
+
+use std::collections::HashMap;
+use std::ffi::{CStr, CString, OsStr, OsString};
+use std::fs::File;
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::path::Path;
+use std::ptr::NonNull;
+
+use super::protocol::{
+    self, Qid, Reader, Writer, HEADER_SIZE, QID_SIZE, QTDIR, QTFILE, QTSYMLINK, TATTACH, TCLUNK,
+    TFLUSH, TFSYNC, TGETATTR, TLCREATE, TLOPEN, TMKDIR, TREAD, TREADDIR, TREADLINK, TSETATTR,
+    TSTATFS, TUNLINKAT, TVERSION, TWALK, TWRITE, VERSION_9P2000_L,
+};
+
+/// Largest message we exchange, the guest may ask for smaller ones.
+pub(crate) const MAX_MESSAGE_SIZE: u32 = 128 << 10;
+
+// Size of the Rread and Rreaddir fields before their data: the header, then count[4].
+const IO_HEADER_SIZE: u32 = HEADER_SIZE as u32 + 4;
+
+// Tag of the messages outside of any transaction.
+const NOTAG: u16 = 0xffff;
+
+// Open flags of 9P2000.L, besides the access mode. The others are ignored.
+const P9_ACCMODE: u32 = 0o3;
+const P9_CREATE: u32 = 0o100;
+const P9_EXCL: u32 = 0o200;
+const P9_TRUNC: u32 = 0o1000;
+const P9_APPEND: u32 = 0o2000;
+const P9_DSYNC: u32 = 0o10000;
+const P9_DIRECTORY: u32 = 0o200000;
+const P9_SYNC: u32 = 0o4000000;
+
+// Fields of Rgetattr we fill: the mode up to the blocks.
+const GETATTR_BASIC: u64 = 0x7ff;
+
+// Fields Tsetattr changes.
+const SETATTR_MODE: u32 = 0x1;
+const SETATTR_UID: u32 = 0x2;
+const SETATTR_GID: u32 = 0x4;
+const SETATTR_SIZE: u32 = 0x8;
+const SETATTR_ATIME: u32 = 0x10;
+const SETATTR_MTIME: u32 = 0x20;
+const SETATTR_ATIME_SET: u32 = 0x80;
+const SETATTR_MTIME_SET: u32 = 0x100;
+
+fn errno(errno: i32) -> io::Error {
+    io::Error::from_raw_os_error(errno)
+}
+
+// A file the guest opened.
+enum Open {
+    File(File),
+    Dir(Dir),
+}
+
+// A file the guest walked to.
+struct Fid {
+    // Names from the root of the share to the file, none for the root.
+    path: Vec<OsString>,
+    open: Option<Open>,
+}
+
+/// Serves the 9P2000.L requests of the guest on a host directory.
+pub(crate) struct Server {
+    // The shared directory, opened with O_PATH: the paths resolve from there, even once
+    // the VMM is in a chroot.
+    root: File,
+    read_only: bool,
+    msize: u32,
+    fids: HashMap<u32, Fid>,
+}
+
+impl Server {
+    pub fn new(path: &Path, read_only: bool) -> io::Result<Self> {
+        let path = cstring(path.as_os_str())?;
+        // Safe because the path is a valid C string, and we check the result.
+        let fd = unsafe {
+            libc::open(
+                path.as_ptr(),
+                libc::O_PATH | libc::O_DIRECTORY | libc::O_CLOEXEC,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Server {
+            // Safe because the descriptor is ours.
+            root: unsafe { File::from_raw_fd(fd) },
+            read_only,
+            msize: MAX_MESSAGE_SIZE,
+            fids: HashMap::new(),
+        })
+    }
+
+    /// Handle a request, and return its response.
+    pub fn handle(&mut self, request: &[u8]) -> Vec<u8> {
+        let mut header = Reader::new(request);
+        let (size, kind, tag) = match (header.u32(), header.u8(), header.u16()) {
+            (Ok(size), Ok(kind), Ok(tag)) => (size as usize, kind, tag),
+            _ => return protocol::error(NOTAG, libc::EINVAL as u32),
+        };
+        // The buffers of the guest may be larger than the request.
+        let body = match request.get(HEADER_SIZE..size) {
+            Some(body) => body,
+            None => return protocol::error(tag, libc::EINVAL as u32),
+        };
+
+        let mut response = Writer::new(kind.wrapping_add(1), tag);
+        match self.dispatch(kind, &mut Reader::new(body), &mut response) {
+            Ok(()) => response.finish(),
+            Err(e) => protocol::error(tag, e.raw_os_error().unwrap_or(libc::EIO) as u32),
+        }
+    }
+
+    fn dispatch(&mut self, kind: u8, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        match kind {
+            TVERSION => self.version(r, w),
+            TATTACH => self.attach(r, w),
+            TWALK => self.walk(r, w),
+            TLOPEN => self.lopen(r, w),
+            TLCREATE => self.lcreate(r, w),
+            TREAD => self.read(r, w),
+            TWRITE => self.write(r, w),
+            TREADDIR => self.readdir(r, w),
+            TGETATTR => self.getattr(r, w),
+            TSETATTR => self.setattr(r),
+            TREADLINK => self.readlink(r, w),
+            TSTATFS => self.statfs(r, w),
+            TFSYNC => self.fsync(r),
+            TMKDIR => self.mkdir(r, w),
+            TUNLINKAT => self.unlinkat(r),
+            // The requests are handled in order, there is nothing left to cancel.
+            TFLUSH => r.u16().map(|_| ()),
+            TCLUNK => {
+                let fid = r.u32()?;
+                self.fids
+                    .remove(&fid)
+                    .map(|_| ())
+                    .ok_or_else(|| errno(libc::EBADF))
+            }
+            _ => Err(errno(libc::EOPNOTSUPP)),
+        }
+    }
+
+    fn fid(&mut self, fid: u32) -> io::Result<&mut Fid> {
+        self.fids.get_mut(&fid).ok_or_else(|| errno(libc::EBADF))
+    }
+
+    fn path(&mut self, fid: u32) -> io::Result<Vec<OsString>> {
+        Ok(self.fid(fid)?.path.clone())
+    }
+
+    fn check_writable(&self) -> io::Result<()> {
+        if self.read_only {
+            return Err(errno(libc::EROFS));
+        }
+        Ok(())
+    }
+
+    // Open `path` with O_PATH, one name at a time from the root, without following any
+    // symlink. The last name may be a symlink, unless it must be a directory.
+    fn resolve(&self, path: &[OsString], directory: bool) -> io::Result<File> {
+        let mut file = openat(
+            &self.root,
+            c_str(b".\0"),
+            libc::O_PATH | libc::O_DIRECTORY,
+            0,
+        )?;
+        for (index, name) in path.iter().enumerate() {
+            let mut flags = libc::O_PATH | libc::O_NOFOLLOW;
+            if directory || index + 1 < path.len() {
+                flags |= libc::O_DIRECTORY;
+            }
+            file = openat(&file, &cstring(name)?, flags, 0)?;
+        }
+
+        Ok(file)
+    }
+
+    // The directory holding `path`, and the name of `path` in it: `.` for the root.
+    fn at(&self, path: &[OsString]) -> io::Result<(File, CString)> {
+        match path.split_last() {
+            Some((name, parent)) => Ok((self.resolve(parent, true)?, cstring(name)?)),
+            None => Ok((self.resolve(&[], true)?, c_str(b".\0").to_owned())),
+        }
+    }
+
+    fn stat(&mut self, fid: u32) -> io::Result<libc::stat> {
+        let fid = self.fid(fid)?;
+        match fid.open.as_ref() {
+            Some(Open::File(file)) => fstat(file.as_raw_fd()),
+            Some(Open::Dir(dir)) => fstat(dir.fd()),
+            None => {
+                let path = fid.path.clone();
+                fstat(self.resolve(&path, false)?.as_raw_fd())
+            }
+        }
+    }
+
+    fn version(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let msize = r.u32()?;
+        let version = r.string()?;
+
+        // A new session starts.
+        self.fids.clear();
+        self.msize = msize.min(MAX_MESSAGE_SIZE);
+        w.u32(self.msize);
+        if version == VERSION_9P2000_L.as_bytes() {
+            w.string(version);
+        } else {
+            w.string(b"unknown");
+        }
+
+        Ok(())
+    }
+
+    fn attach(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let fid = r.u32()?;
+        // There is no authentication, nor any user mapping.
+        let _afid = r.u32()?;
+        let _uname = r.string()?;
+        let _aname = r.string()?;
+        let _n_uname = r.u32()?;
+        if self.fids.contains_key(&fid) {
+            return Err(errno(libc::EBADF));
+        }
+
+        let stat = fstat(self.resolve(&[], true)?.as_raw_fd())?;
+        self.fids.insert(
+            fid,
+            Fid {
+                path: Vec::new(),
+                open: None,
+            },
+        );
+        w.qid(&qid(&stat));
+
+        Ok(())
+    }
+
+    fn walk(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let fid = r.u32()?;
+        let newfid = r.u32()?;
+        let names = (0..r.u16()?)
+            .map(|_| r.string())
+            .collect::<io::Result<Vec<_>>>()?;
+        let mut path = self.path(fid)?;
+        if newfid != fid && self.fids.contains_key(&newfid) {
+            return Err(errno(libc::EBADF));
+        }
+
+        let mut qids = Vec::new();
+        for name in names.iter() {
+            let next = match *name {
+                b".." => path[..path.len().saturating_sub(1)].to_vec(),
+                b"." => path.clone(),
+                name => {
+                    let mut next = path.clone();
+                    next.push(check_name(name)?.to_os_string());
+                    next
+                }
+            };
+            match self
+                .resolve(&next, false)
+                .and_then(|file| fstat(file.as_raw_fd()))
+            {
+                Ok(stat) => {
+                    qids.push(qid(&stat));
+                    path = next;
+                }
+                Err(e) if qids.is_empty() => return Err(e),
+                // The guest learns how far the walk went, newfid is not set.
+                Err(_) => break,
+            }
+        }
+
+        if qids.len() == names.len() {
+            self.fids.insert(newfid, Fid { path, open: None });
+        }
+        w.u16(qids.len() as u16);
+        for qid in qids.iter() {
+            w.qid(qid);
+        }
+
+        Ok(())
+    }
+
+    fn lopen(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let fid = r.u32()?;
+        let flags = self.open_flags(r.u32()?)?;
+        let path = self.path(fid)?;
+
+        let stat = fstat(self.resolve(&path, false)?.as_raw_fd())?;
+        let (dir, name) = self.at(&path)?;
+        let open = match stat.st_mode & libc::S_IFMT {
+            libc::S_IFDIR => Open::Dir(Dir::new(openat(
+                &dir,
+                &name,
+                libc::O_RDONLY | libc::O_DIRECTORY | libc::O_NOFOLLOW,
+                0,
+            )?)?),
+            libc::S_IFREG => Open::File(openat(&dir, &name, flags, 0)?),
+            libc::S_IFLNK => return Err(errno(libc::ELOOP)),
+            _ => return Err(errno(libc::EOPNOTSUPP)),
+        };
+
+        self.fid(fid)?.open = Some(open);
+        w.qid(&qid(&stat));
+        // The guest reads and writes up to the message size.
+        w.u32(0);
+
+        Ok(())
+    }
+
+    fn lcreate(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let fid = r.u32()?;
+        let name = check_name(r.string()?)?.to_os_string();
+        let flags = self.open_flags(r.u32()? | P9_CREATE)?;
+        let mode = r.u32()? & 0o7777;
+        let _gid = r.u32()?;
+        let path = self.path(fid)?;
+
+        let file = openat(&self.resolve(&path, true)?, &cstring(&name)?, flags, mode)?;
+        let stat = fstat(file.as_raw_fd())?;
+        // The fid now stands for the new file.
+        let fid = self.fid(fid)?;
+        fid.path.push(name);
+        fid.open = Some(Open::File(file));
+        w.qid(&qid(&stat));
+        w.u32(0);
+
+        Ok(())
+    }
+
+    // The host open flags for the 9P2000.L ones.
+    fn open_flags(&self, flags: u32) -> io::Result<libc::c_int> {
+        let mut open = match flags & P9_ACCMODE {
+            0 => libc::O_RDONLY,
+            1 => libc::O_WRONLY,
+            2 => libc::O_RDWR,
+            _ => return Err(errno(libc::EINVAL)),
+        };
+        if open != libc::O_RDONLY || flags & (P9_CREATE | P9_TRUNC | P9_APPEND) != 0 {
+            self.check_writable()?;
+        }
+
+        for (p9, flag) in [
+            (P9_CREATE, libc::O_CREAT),
+            (P9_EXCL, libc::O_EXCL),
+            (P9_TRUNC, libc::O_TRUNC),
+            (P9_APPEND, libc::O_APPEND),
+            (P9_DSYNC, libc::O_DSYNC),
+            (P9_DIRECTORY, libc::O_DIRECTORY),
+            (P9_SYNC, libc::O_SYNC),
+        ] {
+            if flags & p9 != 0 {
+                open |= flag;
+            }
+        }
+
+        Ok(open | libc::O_NOFOLLOW)
+    }
+
+    fn read(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let fid = r.u32()?;
+        let offset = r.u64()?;
+        let count = r.u32()?.min(self.msize.saturating_sub(IO_HEADER_SIZE));
+
+        let file = match self.fid(fid)?.open.as_ref() {
+            Some(Open::File(file)) => file,
+            Some(Open::Dir(_)) => return Err(errno(libc::EISDIR)),
+            None => return Err(errno(libc::EBADF)),
+        };
+        let mut data = vec![0; count as usize];
+        let len = file.read_at(&mut data, offset)?;
+        w.u32(len as u32);
+        w.bytes(&data[..len]);
+
+        Ok(())
+    }
+
+    fn write(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let fid = r.u32()?;
+        let offset = r.u64()?;
+        let count = r.u32()?;
+        let data = r.bytes(count as usize)?;
+
+        let file = match self.fid(fid)?.open.as_ref() {
+            Some(Open::File(file)) => file,
+            Some(Open::Dir(_)) => return Err(errno(libc::EISDIR)),
+            None => return Err(errno(libc::EBADF)),
+        };
+        let len = file.write_at(data, offset)?;
+        w.u32(len as u32);
+
+        Ok(())
+    }
+
+    fn readdir(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let fid = r.u32()?;
+        let offset = r.u64()?;
+        let count = r.u32()?.min(self.msize.saturating_sub(IO_HEADER_SIZE)) as usize;
+
+        let dir = match self.fid(fid)?.open.as_mut() {
+            Some(Open::Dir(dir)) => dir,
+            Some(Open::File(_)) => return Err(errno(libc::ENOTDIR)),
+            None => return Err(errno(libc::EBADF)),
+        };
+        // The guest asks for the entries after the last one it got, by its offset.
+        dir.seek(offset);
+        let mut entries = Writer::default();
+        let mut len = 0;
+        while let Some(entry) = dir.read_entry() {
+            let size = QID_SIZE + 8 + 1 + 2 + entry.name.len();
+            if len + size > count {
+                break;
+            }
+            len += size;
+
+            let kind = match entry.kind {
+                libc::DT_DIR => QTDIR,
+                libc::DT_LNK => QTSYMLINK,
+                _ => QTFILE,
+            };
+            entries.qid(&Qid {
+                kind,
+                version: 0,
+                path: entry.ino,
+            });
+            entries.u64(entry.next);
+            entries.u8(entry.kind);
+            entries.string(&entry.name);
+        }
+
+        w.u32(len as u32);
+        w.bytes(&entries.into_bytes());
+
+        Ok(())
+    }
+
+    fn getattr(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let fid = r.u32()?;
+        // The basic fields are always there.
+        let _request_mask = r.u64()?;
+
+        let stat = self.stat(fid)?;
+        // nlink_t is only 32 bits on aarch64.
+        #[allow(clippy::unnecessary_cast)]
+        let nlink = stat.st_nlink as u64;
+        w.u64(GETATTR_BASIC);
+        w.qid(&qid(&stat));
+        w.u32(stat.st_mode);
+        w.u32(stat.st_uid);
+        w.u32(stat.st_gid);
+        w.u64(nlink);
+        w.u64(stat.st_rdev);
+        w.u64(stat.st_size as u64);
+        w.u64(stat.st_blksize as u64);
+        w.u64(stat.st_blocks as u64);
+        for (sec, nsec) in [
+            (stat.st_atime, stat.st_atime_nsec),
+            (stat.st_mtime, stat.st_mtime_nsec),
+            (stat.st_ctime, stat.st_ctime_nsec),
+            // The birth time is not part of the basic fields.
+            (0, 0),
+        ] {
+            w.u64(sec as u64);
+            w.u64(nsec as u64);
+        }
+        // The generation and data version are reserved.
+        w.u64(0);
+        w.u64(0);
+
+        Ok(())
+    }
+
+    fn setattr(&mut self, r: &mut Reader) -> io::Result<()> {
+        let fid = r.u32()?;
+        let valid = r.u32()?;
+        let mode = r.u32()?;
+        let uid = r.u32()?;
+        let gid = r.u32()?;
+        let size = r.u64()?;
+        let atime = (r.u64()?, r.u64()?);
+        let mtime = (r.u64()?, r.u64()?);
+        self.check_writable()?;
+        let path = self.path(fid)?;
+
+        // fchmodat() follows symlinks, the guest cannot create any.
+        let stat = fstat(self.resolve(&path, false)?.as_raw_fd())?;
+        if stat.st_mode & libc::S_IFMT == libc::S_IFLNK
+            && valid & (SETATTR_MODE | SETATTR_SIZE) != 0
+        {
+            return Err(errno(libc::ELOOP));
+        }
+        let (dir, name) = self.at(&path)?;
+
+        if valid & SETATTR_MODE != 0 {
+            // Safe because the name is a valid C string, and we check the result.
+            check(unsafe { libc::fchmodat(dir.as_raw_fd(), name.as_ptr(), mode & 0o7777, 0) })?;
+        }
+        if valid & (SETATTR_UID | SETATTR_GID) != 0 {
+            let uid = if valid & SETATTR_UID != 0 {
+                uid
+            } else {
+                u32::MAX
+            };
+            let gid = if valid & SETATTR_GID != 0 {
+                gid
+            } else {
+                u32::MAX
+            };
+            // Safe because the name is a valid C string, and we check the result.
+            check(unsafe {
+                libc::fchownat(
+                    dir.as_raw_fd(),
+                    name.as_ptr(),
+                    uid,
+                    gid,
+                    libc::AT_SYMLINK_NOFOLLOW,
+                )
+            })?;
+        }
+        if valid & SETATTR_SIZE != 0 {
+            openat(&dir, &name, libc::O_WRONLY | libc::O_NOFOLLOW, 0)?.set_len(size)?;
+        }
+        if valid & (SETATTR_ATIME | SETATTR_MTIME) != 0 {
+            let time = |field, set, (sec, nsec): (u64, u64)| libc::timespec {
+                tv_sec: sec as libc::time_t,
+                tv_nsec: match (valid & field != 0, valid & set != 0) {
+                    (false, _) => libc::UTIME_OMIT,
+                    (true, false) => libc::UTIME_NOW,
+                    (true, true) => nsec as libc::c_long,
+                },
+            };
+            let times = [
+                time(SETATTR_ATIME, SETATTR_ATIME_SET, atime),
+                time(SETATTR_MTIME, SETATTR_MTIME_SET, mtime),
+            ];
+            // Safe because the name is a valid C string, the times an array of two, and we
+            // check the result.
+            check(unsafe {
+                libc::utimensat(
+                    dir.as_raw_fd(),
+                    name.as_ptr(),
+                    times.as_ptr(),
+                    libc::AT_SYMLINK_NOFOLLOW,
+                )
+            })?;
+        }
+
+        Ok(())
+    }
+
+    fn readlink(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let fid = r.u32()?;
+        let path = self.path(fid)?;
+        if path.is_empty() {
+            return Err(errno(libc::EINVAL));
+        }
+
+        let (dir, name) = self.at(&path)?;
+        let mut target = vec![0u8; libc::PATH_MAX as usize];
+        // Safe because the name is a valid C string, the buffer is as long as we tell, and
+        // we check the result.
+        let len = unsafe {
+            libc::readlinkat(
+                dir.as_raw_fd(),
+                name.as_ptr(),
+                target.as_mut_ptr() as *mut libc::c_char,
+                target.len(),
+            )
+        };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        w.string(&target[..len as usize]);
+
+        Ok(())
+    }
+
+    fn statfs(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        // All the files of the share are on the file system of its root, as far as the
+        // guest is concerned.
+        let _fid = r.u32()?;
+
+        // Safe because the structure is plain data, and we check the result.
+        let mut stat: libc::statfs = unsafe { mem::zeroed() };
+        check(unsafe { libc::fstatfs(self.root.as_raw_fd(), &mut stat) })?;
+        w.u32(stat.f_type as u32);
+        w.u32(stat.f_bsize as u32);
+        w.u64(stat.f_blocks);
+        w.u64(stat.f_bfree);
+        w.u64(stat.f_bavail);
+        w.u64(stat.f_files);
+        w.u64(stat.f_ffree);
+        w.u64(0);
+        w.u32(stat.f_namelen as u32);
+
+        Ok(())
+    }
+
+    fn fsync(&mut self, r: &mut Reader) -> io::Result<()> {
+        let fid = r.u32()?;
+        let datasync = r.u32()? != 0;
+
+        match self.fid(fid)?.open.as_ref() {
+            Some(Open::File(file)) if datasync => file.sync_data(),
+            Some(Open::File(file)) => file.sync_all(),
+            Some(Open::Dir(_)) => Ok(()),
+            None => Err(errno(libc::EBADF)),
+        }
+    }
+
+    fn mkdir(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let fid = r.u32()?;
+        let name = cstring(check_name(r.string()?)?)?;
+        let mode = r.u32()? & 0o7777;
+        let _gid = r.u32()?;
+        self.check_writable()?;
+        let path = self.path(fid)?;
+
+        let dir = self.resolve(&path, true)?;
+        // Safe because the name is a valid C string, and we check the result.
+        check(unsafe { libc::mkdirat(dir.as_raw_fd(), name.as_ptr(), mode) })?;
+        let created = openat(&dir, &name, libc::O_PATH | libc::O_NOFOLLOW, 0)?;
+        w.qid(&qid(&fstat(created.as_raw_fd())?));
+
+        Ok(())
+    }
+
+    fn unlinkat(&mut self, r: &mut Reader) -> io::Result<()> {
+        let fid = r.u32()?;
+        let name = cstring(check_name(r.string()?)?)?;
+        let flags = r.u32()? as libc::c_int & libc::AT_REMOVEDIR;
+        self.check_writable()?;
+        let path = self.path(fid)?;
+
+        let dir = self.resolve(&path, true)?;
+        // Safe because the name is a valid C string, and we check the result.
+        check(unsafe { libc::unlinkat(dir.as_raw_fd(), name.as_ptr(), flags) })
+    }
+}
+
+// A name in a directory, not a path.
+fn check_name(name: &[u8]) -> io::Result<&OsStr> {
+    if name.is_empty() || name == b"." || name == b".." || name.contains(&b'/') {
+        return Err(errno(libc::EINVAL));
+    }
+    Ok(OsStr::from_bytes(name))
+}
+
+fn cstring(name: &OsStr) -> io::Result<CString> {
+    CString::new(name.as_bytes()).map_err(|_| errno(libc::EINVAL))
+}
+
+fn c_str(bytes: &'static [u8]) -> &'static CStr {
+    // The callers give NUL terminated strings.
+    CStr::from_bytes_with_nul(bytes).unwrap()
+}
+
+fn check(ret: libc::c_int) -> io::Result<()> {
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn openat(dir: &File, name: &CStr, flags: libc::c_int, mode: u32) -> io::Result<File> {
+    // Safe because the name is a valid C string, and we check the result.
+    let fd = unsafe {
+        libc::openat(
+            dir.as_raw_fd(),
+            name.as_ptr(),
+            flags | libc::O_CLOEXEC,
+            mode as libc::c_uint,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // Safe because the descriptor is ours.
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+// fstat() works on the O_PATH descriptors as well.
+fn fstat(fd: RawFd) -> io::Result<libc::stat> {
+    // Safe because the structure is plain data, and we check the result.
+    let mut stat: libc::stat = unsafe { mem::zeroed() };
+    check(unsafe { libc::fstat(fd, &mut stat) })?;
+    Ok(stat)
+}
+
+fn qid(stat: &libc::stat) -> Qid {
+    let kind = match stat.st_mode & libc::S_IFMT {
+        libc::S_IFDIR => QTDIR,
+        libc::S_IFLNK => QTSYMLINK,
+        _ => QTFILE,
+    };
+
+    Qid {
+        kind,
+        version: 0,
+        path: stat.st_ino,
+    }
+}
+
+// An entry of a directory, and the offset of the next one.
+struct Entry {
+    ino: u64,
+    kind: u8,
+    name: Vec<u8>,
+    next: u64,
+}
+
+// A directory stream.
+struct Dir(NonNull<libc::DIR>);
+
+// Safe because the stream is only used through its owner.
+unsafe impl Send for Dir {}
+
+impl Dir {
+    fn new(file: File) -> io::Result<Self> {
+        let fd = file.into_raw_fd();
+        // Safe because we own the descriptor, which the stream owns once created.
+        match NonNull::new(unsafe { libc::fdopendir(fd) }) {
+            Some(dir) => Ok(Dir(dir)),
+            None => {
+                let e = io::Error::last_os_error();
+                // Safe because the descriptor is still ours.
+                unsafe { libc::close(fd) };
+                Err(e)
+            }
+        }
+    }
+
+    fn fd(&self) -> RawFd {
+        // Safe because the stream is open.
+        unsafe { libc::dirfd(self.0.as_ptr()) }
+    }
+
+    // Go back to the entry at `offset`, as telldir() returned it, or to the first one.
+    fn seek(&mut self, offset: u64) {
+        // Safe because the stream is open. The kernel checks the offset.
+        unsafe {
+            if offset == 0 {
+                libc::rewinddir(self.0.as_ptr());
+            } else {
+                libc::seekdir(self.0.as_ptr(), offset as libc::c_long);
+            }
+        }
+    }
+
+    fn read_entry(&mut self) -> Option<Entry> {
+        // Safe because the stream is open, and the entry is valid until the next call.
+        let entry = unsafe { libc::readdir(self.0.as_ptr()).as_ref()? };
+        let name = unsafe { CStr::from_ptr(entry.d_name.as_ptr()) };
+
+        Some(Entry {
+            ino: entry.d_ino,
+            kind: entry.d_type,
+            name: name.to_bytes().to_vec(),
+            // Safe because the stream is open.
+            next: unsafe { libc::telldir(self.0.as_ptr()) } as u64,
+        })
+    }
+}
+
+impl Drop for Dir {
+    fn drop(&mut self) {
+        // Safe because we own the stream, and its descriptor.
+        unsafe { libc::closedir(self.0.as_ptr()) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+    use std::os::unix::fs::symlink;
+
+    use vmm_sys_util::tempdir::TempDir;
+
+    use crate::devices::p9::protocol::RLERROR;
+
+    // Send a request, and return the type of the response and its fields.
+    fn call(server: &mut Server, kind: u8, fields: impl FnOnce(&mut Writer)) -> (u8, Vec<u8>) {
+        let mut request = Writer::new(kind, 1);
+        fields(&mut request);
+        let response = server.handle(&request.finish());
+
+        let mut reader = Reader::new(&response);
+        assert_eq!(reader.u32().unwrap() as usize, response.len());
+        let kind = reader.u8().unwrap();
+        assert_eq!(reader.u16().unwrap(), 1);
+        (kind, response[HEADER_SIZE..].to_vec())
+    }
+
+    // The errno of an Rlerror response.
+    fn error(response: (u8, Vec<u8>)) -> i32 {
+        assert_eq!(response.0, RLERROR);
+        Reader::new(&response.1).u32().unwrap() as i32
+    }
+
+    fn attach(server: &mut Server, fid: u32) -> Qid {
+        let (kind, _) = call(server, TVERSION, |w| {
+            w.u32(MAX_MESSAGE_SIZE);
+            w.string(VERSION_9P2000_L.as_bytes());
+        });
+        assert_eq!(kind, TVERSION + 1);
+
+        let (kind, fields) = call(server, TATTACH, |w| {
+            w.u32(fid);
+            w.u32(u32::MAX);
+            w.string(b"root");
+            w.string(b"");
+            w.u32(0);
+        });
+        assert_eq!(kind, TATTACH + 1);
+        read_qid(&mut Reader::new(&fields))
+    }
+
+    fn read_qid(reader: &mut Reader) -> Qid {
+        Qid {
+            kind: reader.u8().unwrap(),
+            version: reader.u32().unwrap(),
+            path: reader.u64().unwrap(),
+        }
+    }
+
+    fn walk(server: &mut Server, fid: u32, newfid: u32, names: &[&str]) -> (u8, Vec<u8>) {
+        call(server, TWALK, |w| {
+            w.u32(fid);
+            w.u32(newfid);
+            w.u16(names.len() as u16);
+            for name in names {
+                w.string(name.as_bytes());
+            }
+        })
+    }
+
+    fn walked_qids(response: (u8, Vec<u8>)) -> Vec<Qid> {
+        assert_eq!(response.0, TWALK + 1);
+        let mut reader = Reader::new(&response.1);
+        (0..reader.u16().unwrap())
+            .map(|_| read_qid(&mut reader))
+            .collect()
+    }
+
+    fn lopen(server: &mut Server, fid: u32, flags: u32) -> (u8, Vec<u8>) {
+        call(server, TLOPEN, |w| {
+            w.u32(fid);
+            w.u32(flags);
+        })
+    }
+
+    #[test]
+    fn walk_and_read() {
+        let share = TempDir::new().unwrap();
+        fs::write(share.as_path().join("hello.txt"), "hello").unwrap();
+        fs::create_dir(share.as_path().join("sub")).unwrap();
+        let mut server = Server::new(share.as_path(), false).unwrap();
+        let root = attach(&mut server, 0);
+        assert_eq!(root.kind, QTDIR);
+
+        let qids = walked_qids(walk(&mut server, 0, 1, &["sub", "..", "hello.txt"]));
+        assert_eq!(qids.len(), 3);
+        assert_eq!(qids[1], root);
+        assert_eq!(lopen(&mut server, 1, 0).0, TLOPEN + 1);
+        let (kind, fields) = call(&mut server, TREAD, |w| {
+            w.u32(1);
+            w.u64(1);
+            w.u32(100);
+        });
+        assert_eq!(kind, TREAD + 1);
+        assert_eq!(&fields[4..], b"ello");
+
+        // The guest creates files, and writes them.
+        walked_qids(walk(&mut server, 0, 2, &["sub"]));
+        let (kind, _) = call(&mut server, TLCREATE, |w| {
+            w.u32(2);
+            w.string(b"new.txt");
+            w.u32(1);
+            w.u32(0o644);
+            w.u32(0);
+        });
+        assert_eq!(kind, TLCREATE + 1);
+        let (kind, _) = call(&mut server, TWRITE, |w| {
+            w.u32(2);
+            w.u64(0);
+            w.u32(3);
+            w.bytes(b"new");
+        });
+        assert_eq!(kind, TWRITE + 1);
+        assert_eq!(
+            fs::read_to_string(share.as_path().join("sub/new.txt")).unwrap(),
+            "new"
+        );
+
+        // The entries come in as many calls as they need.
+        walked_qids(walk(&mut server, 0, 3, &[]));
+        assert_eq!(lopen(&mut server, 3, 0).0, TLOPEN + 1);
+        let mut names = Vec::new();
+        let mut offset = 0;
+        loop {
+            let (kind, fields) = call(&mut server, TREADDIR, |w| {
+                w.u32(3);
+                w.u64(offset);
+                w.u32(40);
+            });
+            assert_eq!(kind, TREADDIR + 1);
+            let mut reader = Reader::new(&fields[4..]);
+            let mut entries = 0;
+            while let Ok(kind) = reader.u8() {
+                reader.bytes(QID_SIZE - 1).unwrap();
+                offset = reader.u64().unwrap();
+                assert_eq!(reader.u8().unwrap() == libc::DT_DIR, kind == QTDIR);
+                names.push(String::from_utf8(reader.string().unwrap().to_vec()).unwrap());
+                entries += 1;
+            }
+            if entries == 0 {
+                break;
+            }
+        }
+        names.sort();
+        assert_eq!(names, [".", "..", "hello.txt", "sub"]);
+    }
+
+    #[test]
+    fn share_escapes() {
+        let outside = TempDir::new().unwrap();
+        fs::write(outside.as_path().join("secret"), "secret").unwrap();
+        let share = TempDir::new().unwrap();
+        symlink(outside.as_path(), share.as_path().join("escape")).unwrap();
+        symlink("/etc/passwd", share.as_path().join("passwd")).unwrap();
+        let mut server = Server::new(share.as_path(), false).unwrap();
+        let root = attach(&mut server, 0);
+
+        // `..` stops at the root.
+        assert_eq!(
+            walked_qids(walk(&mut server, 0, 1, &["..", ".."])),
+            [root, root]
+        );
+
+        // The symlinks are not followed, the walk stops at them.
+        let qids = walked_qids(walk(&mut server, 0, 2, &["escape", "secret"]));
+        assert_eq!(qids.len(), 1);
+        assert_eq!(qids[0].kind, QTSYMLINK);
+        assert_eq!(error(lopen(&mut server, 2, 0)), libc::EBADF);
+
+        // The guest resolves them itself.
+        walked_qids(walk(&mut server, 0, 2, &["passwd"]));
+        let (kind, fields) = call(&mut server, TREADLINK, |w| w.u32(2));
+        assert_eq!(kind, TREADLINK + 1);
+        assert_eq!(Reader::new(&fields).string().unwrap(), b"/etc/passwd");
+        assert_eq!(error(lopen(&mut server, 2, 0)), libc::ELOOP);
+        assert_eq!(
+            error(call(&mut server, TSETATTR, |w| {
+                w.u32(2);
+                w.u32(SETATTR_MODE);
+                w.u32(0o777);
+                w.bytes(&[0; 8 + 8 + 4 * 8]);
+            })),
+            libc::ELOOP
+        );
+
+        // The names are single components.
+        assert_eq!(
+            error(walk(&mut server, 0, 3, &["escape/secret"])),
+            libc::EINVAL
+        );
+        assert_eq!(
+            error(call(&mut server, TUNLINKAT, |w| {
+                w.u32(0);
+                w.string(b"../share");
+                w.u32(0);
+            })),
+            libc::EINVAL
+        );
+    }
+
+    #[test]
+    fn read_only() {
+        let share = TempDir::new().unwrap();
+        fs::write(share.as_path().join("file"), "data").unwrap();
+        let mut server = Server::new(share.as_path(), true).unwrap();
+        attach(&mut server, 0);
+
+        walked_qids(walk(&mut server, 0, 1, &["file"]));
+        assert_eq!(error(lopen(&mut server, 1, 2)), libc::EROFS);
+        assert_eq!(error(lopen(&mut server, 1, P9_TRUNC)), libc::EROFS);
+        assert_eq!(lopen(&mut server, 1, 0).0, TLOPEN + 1);
+        assert_eq!(
+            error(call(&mut server, TUNLINKAT, |w| {
+                w.u32(0);
+                w.string(b"file");
+                w.u32(0);
+            })),
+            libc::EROFS
+        );
+        assert_eq!(
+            fs::read_to_string(share.as_path().join("file")).unwrap(),
+            "data"
+        );
+    }
+}
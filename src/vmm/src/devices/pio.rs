@@ -0,0 +1,72 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+/// Ports the guest writes to as an I/O delay, or for POST codes. Accesses are ignored.
+const DELAY_PORTS: [u16; 2] = [0x80, 0xed];
+
+/// Accounts for guest accesses to ports no device claims.
+///
+/// Those accesses are ignored, reads return all ones as on a floating bus. They are only
+/// logged once per port, as guests keep probing some of them (e.g. the ACPI PM timer).
+#[derive(Default)]
+pub(crate) struct UnknownPorts {
+    // Number of accesses to each port.
+    counts: Mutex<BTreeMap<u16, u64>>,
+}
+
+impl UnknownPorts {
+    pub fn new() -> Self {
+        UnknownPorts::default()
+    }
+
+    /// Record an access to `addr`, `access` being "read" or "write".
+    pub fn access(&self, addr: u16, access: &str) {
+        if DELAY_PORTS.contains(&addr) {
+            return;
+        }
+
+        let mut counts = self.counts.lock().unwrap();
+        let count = counts.entry(addr).or_insert(0);
+        if *count == 0 {
+            log::debug!(
+                "Unsupported device {} at {:#x}, ignoring further accesses",
+                access,
+                addr
+            );
+        }
+        *count += 1;
+    }
+
+    /// Number of accesses to each port.
+    pub fn counts(&self) -> Vec<(u16, u64)> {
+        self.counts
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(port, count)| (*port, *count))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unknown_ports() {
+        let ports = UnknownPorts::new();
+
+        // Delay ports are not even counted.
+        for _ in 0..100 {
+            ports.access(0x80, "write");
+        }
+
+        ports.access(0x608, "read");
+        ports.access(0x608, "read");
+        ports.access(0x61, "write");
+
+        assert_eq!(ports.counts(), vec![(0x61, 1), (0x608, 2)]);
+    }
+}
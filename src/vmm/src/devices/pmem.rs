@@ -0,0 +1,385 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use std::borrow::{Borrow, BorrowMut};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::ptr;
+use std::sync::Arc;
+
+use virtio_device::{VirtioConfig, VirtioDeviceActions, VirtioDeviceType, VirtioMmioDevice};
+use virtio_queue::{Queue, QueueOwnedT, QueueT};
+use vm_device::{
+    bus::{MmioAddress, MmioAddressOffset},
+    MutDeviceMmio,
+};
+use vm_memory::{Bytes, GuestAddress, GuestAddressSpace};
+use vmm_sys_util::eventfd::EventFd;
+
+use crate::devices::irq_trace::IrqTrace;
+use crate::devices::virtio::{self, VIRTIO_MMIO_INTERRUPT_ACK, VIRTIO_MMIO_INT_VRING};
+
+/// virtio-pmem device ID.
+/// See https://docs.oasis-open.org/virtio/virtio/v1.2/virtio-v1.2.html#x1-68900019
+pub(crate) const VIRTIO_PMEM_DEVICE_ID: u32 = 27;
+const VIRTIO_F_VERSION_1: u64 = 32;
+const VIRTIO_FEATURES: u64 = 1 << VIRTIO_F_VERSION_1;
+
+// Request and response types of the flush queue.
+const VIRTIO_PMEM_REQ_TYPE_FLUSH: u32 = 0;
+const VIRTIO_PMEM_RESP_TYPE_OK: u32 = 0;
+const VIRTIO_PMEM_RESP_TYPE_EIO: u32 = 1;
+
+const QUEUE_SIZE: u16 = 256;
+
+/// Alignment of the guest physical range backing a pmem device. The guest maps it with
+/// huge pages, so the range is also padded to a multiple of this.
+pub(crate) const PMEM_ALIGNMENT: u64 = 2 << 20;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// Failed to open the image.
+    #[error("failed to open the image")]
+    Open(#[source] io::Error),
+    /// The image is empty.
+    #[error("the image is empty")]
+    EmptyImage,
+    /// Failed to mmap the image.
+    #[error("failed to map the image")]
+    Mmap(#[source] io::Error),
+    /// Failed to create the flush queue.
+    #[error("virtio queue error: {0:?}")]
+    Queue(virtio_queue::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+// Host mapping of the image, padded with anonymous memory up to the device size.
+struct Mapping {
+    addr: *mut libc::c_void,
+    // Size of the image.
+    len: usize,
+    // Size of the whole mapping.
+    size: usize,
+}
+
+// Safe because the mapping is owned by the device, the guest is the only one accessing
+// the memory behind it.
+unsafe impl Send for Mapping {}
+
+impl Mapping {
+    fn new(file: &File, len: usize, size: usize, read_only: bool) -> io::Result<Self> {
+        let prot = if read_only {
+            libc::PROT_READ
+        } else {
+            libc::PROT_READ | libc::PROT_WRITE
+        };
+
+        // Reserve the whole range first, the image is then mapped at its beginning.
+        // Safe because we do not map over any existing memory, and check the result.
+        let addr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                size,
+                prot,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE,
+                -1,
+                0,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        let mapping = Mapping { addr, len, size };
+
+        // Safe because the range belongs to the reservation we just made.
+        let ret = unsafe {
+            libc::mmap(
+                addr,
+                len,
+                prot,
+                libc::MAP_SHARED | libc::MAP_FIXED | libc::MAP_NORESERVE,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ret == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(mapping)
+    }
+
+    // Write the guest changes back to the image.
+    fn sync(&self) -> io::Result<()> {
+        // Safe because the range is mapped.
+        if unsafe { libc::msync(self.addr, self.len, libc::MS_SYNC) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        // Safe because we own the mapping.
+        unsafe { libc::munmap(self.addr, self.size) };
+    }
+}
+
+/// Serialize the virtio-pmem configuration space: the guest physical range of the device.
+fn config_vec(start: GuestAddress, size: u64) -> Vec<u8> {
+    let mut config_vec = Vec::new();
+    config_vec.extend_from_slice(&start.0.to_le_bytes());
+    config_vec.extend_from_slice(&size.to_le_bytes());
+    config_vec
+}
+
+/// virtio-pmem device, exposing a file mapped into the guest physical memory.
+///
+/// The guest accesses the image directly through the mapping, with DAX. The only
+/// request it sends is a flush, asking for its writes to reach the image.
+pub struct VirtioPmem<M: GuestAddressSpace + Clone + Send> {
+    pub device_config: VirtioConfig<Queue>,
+    pub guest_irq_fd: EventFd,
+    pub address_space: M,
+    mapping: Mapping,
+    read_only: bool,
+    guest_address: GuestAddress,
+    irq_trace: Arc<IrqTrace>,
+    // Keeps the image open for as long as it is mapped.
+    _file: File,
+}
+
+impl<M: GuestAddressSpace + Clone + Send> VirtioPmem<M> {
+    pub fn new(memory: M, irq_fd: EventFd, path: &Path, read_only: bool) -> Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(!read_only)
+            .open(path)
+            .map_err(Error::Open)?;
+
+        let len = file.metadata().map_err(Error::Open)?.len();
+        if len == 0 {
+            return Err(Error::EmptyImage);
+        }
+        let size = (len + PMEM_ALIGNMENT - 1) & !(PMEM_ALIGNMENT - 1);
+
+        let mapping =
+            Mapping::new(&file, len as usize, size as usize, read_only).map_err(Error::Mmap)?;
+
+        Ok(VirtioPmem {
+            device_config: VirtioConfig::new(
+                VIRTIO_FEATURES,
+                vec![Queue::new(QUEUE_SIZE).map_err(Error::Queue)?],
+                // The guest address is only known once the device memory is allocated.
+                config_vec(GuestAddress(0), size),
+            ),
+            guest_irq_fd: irq_fd,
+            address_space: memory,
+            mapping,
+            read_only,
+            guest_address: GuestAddress(0),
+            irq_trace: Arc::new(IrqTrace::new("pmem")),
+            _file: file,
+        })
+    }
+
+    /// Size of the guest physical range backing the device.
+    pub fn size(&self) -> u64 {
+        self.mapping.size as u64
+    }
+
+    /// Host address of the mapping, to be registered as a KVM memory slot.
+    pub fn host_address(&self) -> u64 {
+        self.mapping.addr as u64
+    }
+
+    pub fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    pub fn guest_address(&self) -> GuestAddress {
+        self.guest_address
+    }
+
+    /// The interrupt latency of the device.
+    pub(crate) fn irq_trace(&self) -> Arc<IrqTrace> {
+        self.irq_trace.clone()
+    }
+
+    /// Tell the guest where the device memory lives.
+    pub fn set_guest_address(&mut self, address: GuestAddress) {
+        self.guest_address = address;
+        self.device_config.config_space = config_vec(address, self.size());
+    }
+
+    fn flush(&self) -> u32 {
+        // Nothing can be dirty in a read-only mapping.
+        if self.read_only {
+            return VIRTIO_PMEM_RESP_TYPE_OK;
+        }
+
+        match self.mapping.sync() {
+            Ok(()) => VIRTIO_PMEM_RESP_TYPE_OK,
+            Err(e) => {
+                println!("Failed to sync pmem image: {:?}", e);
+                VIRTIO_PMEM_RESP_TYPE_EIO
+            }
+        }
+    }
+}
+
+impl<M: GuestAddressSpace + Clone + Send> VirtioDeviceType for VirtioPmem<M> {
+    fn device_type(&self) -> u32 {
+        VIRTIO_PMEM_DEVICE_ID
+    }
+}
+
+impl<M: GuestAddressSpace + Clone + Send> VirtioMmioDevice for VirtioPmem<M> {
+    fn queue_notify(&mut self, val: u32) {
+        if val != 0 {
+            return;
+        }
+
+        let mem = self.address_space.memory().clone();
+
+        loop {
+            if let Err(e) = self.device_config.queues[0].disable_notification(&*mem) {
+                println!("Failed to disable notification: {:?}", e);
+                break;
+            }
+
+            // Never fails since we know the memory is valid.
+            while let Some(chain) = self.device_config.queues[0].iter(&*mem).unwrap().next() {
+                // The request is a readable 32 bits type, the response a writable 32 bits status.
+                let mut request = None;
+                let mut response = None;
+                for desc in chain.clone() {
+                    if desc.len() < 4 {
+                        continue;
+                    }
+                    if desc.is_write_only() {
+                        response = response.or(Some(desc.addr()));
+                    } else {
+                        request = request.or(Some(desc.addr()));
+                    }
+                }
+
+                let status = match request.map(|addr| mem.read_obj::<u32>(addr)) {
+                    Some(Ok(req)) if u32::from_le(req) == VIRTIO_PMEM_REQ_TYPE_FLUSH => {
+                        self.flush()
+                    }
+                    _ => {
+                        println!("invalid pmem request");
+                        VIRTIO_PMEM_RESP_TYPE_EIO
+                    }
+                };
+
+                let used_len = match response {
+                    Some(addr) => match mem.write_obj(status.to_le(), addr) {
+                        Ok(()) => 4,
+                        Err(e) => {
+                            println!("Failed to write pmem response: {:?}", e);
+                            0
+                        }
+                    },
+                    None => 0,
+                };
+
+                let queue = &mut self.device_config.queues[0];
+                // Try continuing even if we failed to add the used buffer.
+                queue
+                    .add_used(&*mem, chain.head_index(), used_len)
+                    .unwrap_or_else(|e| {
+                        println!("Failed to add used buffer: {:?}", e);
+                    });
+
+                if queue.needs_notification(&*mem).unwrap_or_default() {
+                    virtio::signal(
+                        &self.device_config.interrupt_status,
+                        &self.guest_irq_fd,
+                        &self.irq_trace,
+                        VIRTIO_MMIO_INT_VRING,
+                    );
+                }
+            }
+
+            if !self.device_config.queues[0]
+                .enable_notification(&*mem)
+                .unwrap_or_default()
+            {
+                break;
+            }
+        }
+    }
+}
+
+impl<M: GuestAddressSpace + Clone + Send> Borrow<VirtioConfig<Queue>> for VirtioPmem<M> {
+    fn borrow(&self) -> &VirtioConfig<Queue> {
+        &self.device_config
+    }
+}
+
+impl<M: GuestAddressSpace + Clone + Send> BorrowMut<VirtioConfig<Queue>> for VirtioPmem<M> {
+    fn borrow_mut(&mut self) -> &mut VirtioConfig<Queue> {
+        &mut self.device_config
+    }
+}
+
+impl<M: GuestAddressSpace + Clone + Send> VirtioDeviceActions for VirtioPmem<M> {
+    type E = Error;
+
+    fn activate(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        virtio::reset_config(&mut self.device_config);
+        Ok(())
+    }
+}
+
+impl<M: GuestAddressSpace + Clone + Send> MutDeviceMmio for VirtioPmem<M> {
+    fn mmio_read(&mut self, _base: MmioAddress, offset: MmioAddressOffset, data: &mut [u8]) {
+        self.read(offset, data);
+    }
+
+    fn mmio_write(&mut self, _base: MmioAddress, offset: MmioAddressOffset, data: &[u8]) {
+        if offset == VIRTIO_MMIO_INTERRUPT_ACK {
+            virtio::ack(&self.device_config.interrupt_status, &self.irq_trace, data);
+            return;
+        }
+
+        self.write(offset, data);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    use vmm_sys_util::tempfile::TempFile;
+
+    #[test]
+    fn image_mapping() {
+        let image = TempFile::new().unwrap();
+        image.as_file().write_all(b"rootfs").unwrap();
+
+        let file = image.as_file().try_clone().unwrap();
+        let mapping = Mapping::new(&file, 6, PMEM_ALIGNMENT as usize, false).unwrap();
+
+        // Safe because the mapping is at least 6 bytes long, and writable.
+        let data = unsafe { std::slice::from_raw_parts_mut(mapping.addr as *mut u8, 6) };
+        assert_eq!(data, b"rootfs");
+        data.copy_from_slice(b"ROOTFS");
+        mapping.sync().unwrap();
+
+        assert_eq!(std::fs::read(image.as_path()).unwrap(), b"ROOTFS");
+
+        assert_eq!(config_vec(GuestAddress(0x1_0000_0000), 2 << 20).len(), 16);
+    }
+}
@@ -0,0 +1,101 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use std::sync::Arc;
+
+use vm_device::bus::{PioAddress, PioAddressOffset};
+use vm_device::MutDevicePio;
+
+use crate::{ExitNotifier, ExitReason, PanicReport, PvpanicEvent};
+
+/// I/O port of the pvpanic device, the one QEMU uses.
+pub const PVPANIC_PORT: u16 = 0x505;
+/// Number of pvpanic ports.
+pub const PVPANIC_PORT_SIZE: u16 = 1;
+
+// Events, as bits of the port value.
+const PVPANIC_PANICKED: u8 = 1 << 0;
+const PVPANIC_CRASH_LOADED: u8 = 1 << 1;
+
+/// pvpanic device, through which the guest kernel reports its panics.
+///
+/// Reading the port gives the events the device supports, the guest writes the event it
+/// hit. Either stops the VMM with [`ExitReason::GuestPanic`], including a crash kernel
+/// being loaded: the VMM does not wait for the crash dump.
+pub(crate) struct Pvpanic {
+    exit: Arc<ExitNotifier>,
+}
+
+impl Pvpanic {
+    pub fn new(exit: Arc<ExitNotifier>) -> Self {
+        Pvpanic { exit }
+    }
+
+    fn event(&self, value: u8) {
+        let event = if value & PVPANIC_PANICKED != 0 {
+            PvpanicEvent::Panicked
+        } else if value & PVPANIC_CRASH_LOADED != 0 {
+            PvpanicEvent::CrashLoaded
+        } else {
+            log::debug!("Ignoring pvpanic event {:#x}", value);
+            return;
+        };
+
+        self.exit
+            .notify(ExitReason::GuestPanic(PanicReport::Pvpanic(event)));
+    }
+}
+
+impl MutDevicePio for Pvpanic {
+    fn pio_read(&mut self, _base: PioAddress, offset: PioAddressOffset, data: &mut [u8]) {
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte = match offset + i as PioAddressOffset {
+                0 => PVPANIC_PANICKED | PVPANIC_CRASH_LOADED,
+                _ => 0xff,
+            };
+        }
+    }
+
+    fn pio_write(&mut self, _base: PioAddress, offset: PioAddressOffset, data: &[u8]) {
+        if offset == 0 {
+            if let Some(value) = data.first() {
+                self.event(*value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn events() {
+        let exit = Arc::new(ExitNotifier::new().unwrap());
+        let mut pvpanic = Pvpanic::new(exit.clone());
+
+        // The guest only sends the events the device supports.
+        let mut data = [0u8];
+        pvpanic.pio_read(PioAddress(PVPANIC_PORT), 0, &mut data);
+        assert_eq!(data[0], PVPANIC_PANICKED | PVPANIC_CRASH_LOADED);
+
+        // Unknown events are ignored.
+        pvpanic.pio_write(PioAddress(PVPANIC_PORT), 0, &[1 << 4]);
+        assert_eq!(exit.take(), None);
+
+        pvpanic.pio_write(PioAddress(PVPANIC_PORT), 0, &[PVPANIC_CRASH_LOADED]);
+        assert_eq!(
+            exit.take(),
+            Some(ExitReason::GuestPanic(PanicReport::Pvpanic(
+                PvpanicEvent::CrashLoaded
+            )))
+        );
+
+        pvpanic.pio_write(PioAddress(PVPANIC_PORT), 0, &[PVPANIC_PANICKED]);
+        assert_eq!(
+            exit.take(),
+            Some(ExitReason::GuestPanic(PanicReport::Pvpanic(
+                PvpanicEvent::Panicked
+            )))
+        );
+    }
+}
@@ -0,0 +1,253 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use vm_device::bus::{PioAddress, PioAddressOffset};
+use vm_device::MutDevicePio;
+
+use crate::devices::clock::DateTime;
+
+/// CMOS index port, followed by the data port.
+pub const RTC_PORT: u16 = 0x70;
+/// Number of RTC ports.
+pub const RTC_PORT_SIZE: u16 = 2;
+
+// Offsets of the index and data ports.
+const INDEX: PioAddressOffset = 0;
+const DATA: PioAddressOffset = 1;
+// The index port bit 7 masks the NMI, it is not part of the index.
+const INDEX_MASK: u8 = 0x7f;
+
+// CMOS registers.
+const SECONDS: u8 = 0x00;
+const MINUTES: u8 = 0x02;
+const HOURS: u8 = 0x04;
+const WEEKDAY: u8 = 0x06;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+const REG_A: u8 = 0x0a;
+const REG_B: u8 = 0x0b;
+const REG_C: u8 = 0x0c;
+const REG_D: u8 = 0x0d;
+/// CMOS register of the century, as found in the FADT.
+pub const CENTURY: u8 = 0x32;
+
+// Register A: update in progress, always clear as the time is read at once.
+const REG_A_UIP: u8 = 1 << 7;
+// Register A power-on value: 32.768 kHz time base, 1024 Hz periodic rate.
+const REG_A_DEFAULT: u8 = 0x26;
+// Register B: binary instead of BCD values, and 24 instead of 12 hours mode.
+const REG_B_DM_BINARY: u8 = 1 << 2;
+const REG_B_24H: u8 = 1 << 1;
+// Register D: the CMOS battery is fine.
+const REG_D_VRT: u8 = 1 << 7;
+// PM flag of the hours, in 12 hours mode.
+const HOURS_PM: u8 = 1 << 7;
+
+/// MC146818 compatible real-time clock, giving the host UTC time to the guest.
+///
+/// The time is read from the host clock on each access. The guest cannot set it, and
+/// neither the alarm nor the periodic and update interrupts are implemented. The other
+/// CMOS registers read as zero.
+pub(crate) struct Rtc {
+    index: u8,
+    reg_a: u8,
+    reg_b: u8,
+    clock: fn() -> DateTime,
+}
+
+impl Rtc {
+    pub fn new() -> Self {
+        Rtc {
+            index: 0,
+            reg_a: REG_A_DEFAULT,
+            reg_b: REG_B_24H,
+            clock: DateTime::now,
+        }
+    }
+
+    // Encode a value of the date, in the format set in register B.
+    fn encode(&self, value: u64) -> u8 {
+        // All the fields are below 100.
+        let value = (value % 100) as u8;
+        if self.reg_b & REG_B_DM_BINARY != 0 {
+            value
+        } else {
+            ((value / 10) << 4) | (value % 10)
+        }
+    }
+
+    fn encode_hours(&self, hours: u64) -> u8 {
+        if self.reg_b & REG_B_24H != 0 {
+            return self.encode(hours);
+        }
+
+        // From 12 AM to 11 PM.
+        let value = self.encode(match hours % 12 {
+            0 => 12,
+            hours => hours,
+        });
+        if hours >= 12 {
+            value | HOURS_PM
+        } else {
+            value
+        }
+    }
+
+    fn read_register(&self, index: u8) -> u8 {
+        let now = || (self.clock)();
+
+        match index {
+            SECONDS => self.encode(now().second),
+            MINUTES => self.encode(now().minute),
+            HOURS => self.encode_hours(now().hour),
+            // From 1 (Sunday) to 7.
+            WEEKDAY => self.encode(now().weekday + 1),
+            DAY => self.encode(now().day),
+            MONTH => self.encode(now().month),
+            YEAR => self.encode(now().year),
+            CENTURY => self.encode(now().year / 100),
+            REG_A => self.reg_a & !REG_A_UIP,
+            REG_B => self.reg_b,
+            // No interrupt is ever pending.
+            REG_C => 0,
+            REG_D => REG_D_VRT,
+            _ => 0,
+        }
+    }
+
+    fn write_register(&mut self, index: u8, value: u8) {
+        match index {
+            REG_A => self.reg_a = value,
+            REG_B => self.reg_b = value,
+            // The clock follows the host, the guest cannot set it.
+            _ => log::debug!("Ignoring RTC register {:#x} write {:#x}", index, value),
+        }
+    }
+}
+
+impl MutDevicePio for Rtc {
+    fn pio_read(&mut self, _base: PioAddress, offset: PioAddressOffset, data: &mut [u8]) {
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte = match offset + i as PioAddressOffset {
+                INDEX => self.index,
+                DATA => self.read_register(self.index),
+                _ => 0xff,
+            };
+        }
+    }
+
+    fn pio_write(&mut self, _base: PioAddress, offset: PioAddressOffset, data: &[u8]) {
+        for (i, byte) in data.iter().enumerate() {
+            match offset + i as PioAddressOffset {
+                INDEX => self.index = byte & INDEX_MASK,
+                DATA => self.write_register(self.index, *byte),
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(rtc: &mut Rtc, index: u8) -> u8 {
+        // Linux masks the NMI while it accesses the CMOS.
+        rtc.pio_write(PioAddress(RTC_PORT), INDEX, &[index | 0x80]);
+        let mut data = [0];
+        rtc.pio_read(PioAddress(RTC_PORT), DATA, &mut data);
+        data[0]
+    }
+
+    fn write(rtc: &mut Rtc, index: u8, value: u8) {
+        rtc.pio_write(PioAddress(RTC_PORT), INDEX, &[index]);
+        rtc.pio_write(PioAddress(RTC_PORT), DATA, &[value]);
+    }
+
+    // Read the date as the guest does, decoding the values in the format of register B.
+    fn read_date(rtc: &mut Rtc) -> DateTime {
+        assert_eq!(read(rtc, REG_A) & REG_A_UIP, 0);
+        let reg_b = read(rtc, REG_B);
+        let decode = |value: u8| {
+            u64::from(if reg_b & REG_B_DM_BINARY != 0 {
+                value
+            } else {
+                (value >> 4) * 10 + (value & 0xf)
+            })
+        };
+
+        let hours = read(rtc, HOURS);
+        let hour = if reg_b & REG_B_24H != 0 {
+            decode(hours)
+        } else {
+            decode(hours & !HOURS_PM) % 12 + if hours & HOURS_PM != 0 { 12 } else { 0 }
+        };
+
+        DateTime {
+            year: decode(read(rtc, CENTURY)) * 100 + decode(read(rtc, YEAR)),
+            month: decode(read(rtc, MONTH)),
+            day: decode(read(rtc, DAY)),
+            weekday: decode(read(rtc, WEEKDAY)) - 1,
+            hour,
+            minute: decode(read(rtc, MINUTES)),
+            second: decode(read(rtc, SECONDS)),
+        }
+    }
+
+    #[test]
+    fn date() {
+        // 2026-10-17T23:04:59, a Saturday.
+        let mut rtc = Rtc {
+            clock: || DateTime::from_unix(1_792_278_299),
+            ..Rtc::new()
+        };
+        let expected = DateTime::from_unix(1_792_278_299);
+
+        // BCD and 24 hours mode by default.
+        assert_eq!(read(&mut rtc, REG_B), REG_B_24H);
+        assert_eq!(read(&mut rtc, SECONDS), 0x59);
+        assert_eq!(read(&mut rtc, HOURS), 0x23);
+        assert_eq!(read(&mut rtc, YEAR), 0x26);
+        assert_eq!(read(&mut rtc, CENTURY), 0x20);
+        assert_eq!(read_date(&mut rtc), expected);
+
+        write(&mut rtc, REG_B, REG_B_24H | REG_B_DM_BINARY);
+        assert_eq!(read(&mut rtc, SECONDS), 59);
+        assert_eq!(read(&mut rtc, HOURS), 23);
+        assert_eq!(read(&mut rtc, WEEKDAY), 7);
+        assert_eq!(read_date(&mut rtc), expected);
+
+        // 12 hours mode.
+        write(&mut rtc, REG_B, 0);
+        assert_eq!(read(&mut rtc, HOURS), HOURS_PM | 0x11);
+        assert_eq!(read_date(&mut rtc), expected);
+        write(&mut rtc, REG_B, REG_B_DM_BINARY);
+        assert_eq!(read(&mut rtc, HOURS), HOURS_PM | 11);
+        assert_eq!(read_date(&mut rtc), expected);
+
+        // Midnight is 12 AM.
+        rtc.clock = || DateTime::from_unix(1_792_195_200);
+        assert_eq!(read(&mut rtc, HOURS), 12);
+
+        // The guest cannot set the time.
+        write(&mut rtc, YEAR, 0);
+        assert_eq!(read(&mut rtc, YEAR), 26);
+    }
+
+    #[test]
+    fn status() {
+        let mut rtc = Rtc::new();
+
+        assert_eq!(read(&mut rtc, REG_C), 0);
+        assert_eq!(read(&mut rtc, REG_D), REG_D_VRT);
+        // Unimplemented registers, e.g. the alarm and the CMOS memory.
+        for index in [0x01, 0x03, 0x05, 0x0e, 0x0f, 0x7f] {
+            assert_eq!(read(&mut rtc, index), 0);
+        }
+
+        // The index port reads back the index, without the NMI mask.
+        let mut data = [0];
+        rtc.pio_read(PioAddress(RTC_PORT), INDEX, &mut data);
+        assert_eq!(data[0], 0x7f);
+    }
+}
@@ -1,14 +1,141 @@
 // SPDX-License-Identifier: Apache-2.0
 
-use std::io::{Error, Result, Write};
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind, Result, Write};
 use std::ops::Deref;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use vm_superio::serial::NoEvents;
+#[cfg(target_arch = "aarch64")]
+use vm_device::bus::{MmioAddress, MmioAddressOffset};
+use vm_device::bus::{PioAddress, PioAddressOffset};
+use vm_device::device_manager::{self, IoManager};
+use vm_device::resources::Resource;
+#[cfg(target_arch = "aarch64")]
+use vm_device::MutDeviceMmio;
+use vm_device::MutDevicePio;
+use vm_superio::serial::{self, NoEvents};
 use vm_superio::{Serial, Trigger};
 use vmm_sys_util::eventfd::EventFd;
 
-pub const SERIAL_PORT_BASE: u16 = 0x3f8;
-pub const SERIAL_PORT_LAST_REGISTER: u16 = SERIAL_PORT_BASE + 0x8;
+use super::console_session::SessionRecorder;
+use crate::api::SerialCounters;
+#[cfg(target_arch = "aarch64")]
+use crate::layout::{SERIAL_MMIO_SIZE, SERIAL_MMIO_START};
+
+/// Number of registers (and thus I/O ports) of a 16550 UART.
+pub const SERIAL_PORT_SIZE: u16 = 0x8;
+
+/// Legacy PC serial port location: I/O base and IRQ line.
+#[cfg(target_arch = "x86_64")]
+#[derive(Clone, Copy, Debug)]
+pub struct SerialPort {
+    pub base: u16,
+    pub irq: u32,
+}
+
+/// Serial port location: MMIO base and SPI.
+#[cfg(target_arch = "aarch64")]
+#[derive(Clone, Copy, Debug)]
+pub struct SerialPort {
+    pub base: u64,
+    pub irq: u32,
+}
+
+/// COM1, exposed to the guest as ttyS0. This is the console.
+#[cfg(target_arch = "x86_64")]
+pub const COM1: SerialPort = SerialPort {
+    base: 0x3f8,
+    irq: 4,
+};
+
+/// COM2, exposed to the guest as ttyS1. This is the agent channel.
+#[cfg(target_arch = "x86_64")]
+pub const COM2: SerialPort = SerialPort {
+    base: 0x2f8,
+    irq: 3,
+};
+
+/// First serial port, exposed to the guest as ttyS0. This is the console.
+#[cfg(target_arch = "aarch64")]
+pub const COM1: SerialPort = SerialPort {
+    base: SERIAL_MMIO_START,
+    irq: 1,
+};
+
+/// Second serial port, exposed to the guest as ttyS1. This is the agent channel.
+#[cfg(target_arch = "aarch64")]
+pub const COM2: SerialPort = SerialPort {
+    base: SERIAL_MMIO_START + SERIAL_MMIO_SIZE,
+    irq: 2,
+};
+
+impl SerialPort {
+    /// Map the registers of the port to `serial`.
+    #[cfg(target_arch = "x86_64")]
+    pub(crate) fn register(
+        &self,
+        io_manager: &mut IoManager,
+        serial: Arc<Mutex<LumperSerial>>,
+    ) -> std::result::Result<(), device_manager::Error> {
+        io_manager.register_pio_resources(
+            serial,
+            &[Resource::PioAddressRange {
+                base: self.base,
+                size: SERIAL_PORT_SIZE,
+            }],
+        )
+    }
+
+    /// Map the registers of the port to `serial`.
+    #[cfg(target_arch = "aarch64")]
+    pub(crate) fn register(
+        &self,
+        io_manager: &mut IoManager,
+        serial: Arc<Mutex<LumperSerial>>,
+    ) -> std::result::Result<(), device_manager::Error> {
+        io_manager.register_mmio_resources(
+            serial,
+            &[Resource::MmioAddressRange {
+                base: self.base,
+                size: SERIAL_PORT_SIZE.into(),
+            }],
+        )
+    }
+}
+
+/// Pending input size above which the VMM stops reading from the input source.
+pub(crate) const INPUT_BACKLOG_HIGH: usize = 16 * 1024;
+/// Pending input size below which the VMM reads from the input source again.
+pub(crate) const INPUT_BACKLOG_LOW: usize = 4 * 1024;
+
+// Data register: transmit holding register on writes, receive buffer register on reads.
+const DATA_OFFSET: u8 = 0;
+// Interrupt enable register, and its received data available and transmit holding
+// register empty bits.
+const IER_OFFSET: u8 = 1;
+const IER_RDA: u8 = 0x01;
+const IER_THR_EMPTY: u8 = 0x02;
+// Interrupt identification register, and the interrupts it reports. The top bits tell
+// that the FIFOs are enabled.
+const IIR_OFFSET: u8 = 2;
+const IIR_NONE: u8 = 0x01;
+const IIR_THR_EMPTY: u8 = 0x02;
+const IIR_RDA: u8 = 0x04;
+const IIR_FIFO_BITS: u8 = 0xc0;
+// Line control register, and its divisor latch access bit.
+const LCR_OFFSET: u8 = 3;
+const LCR_DLAB: u8 = 0x80;
+// Modem control register, and its loopback bit.
+const MCR_OFFSET: u8 = 4;
+const MCR_LOOP: u8 = 0x10;
+// Line status register, and its data ready bit.
+const LSR_OFFSET: u8 = 5;
+const LSR_DATA_READY: u8 = 0x01;
+
+// The output sink failures are logged at most this often.
+const OUTPUT_ERROR_LOG_INTERVAL: Duration = Duration::from_secs(10);
 
 pub struct EventFdTrigger(EventFd);
 
@@ -36,25 +163,720 @@ impl EventFdTrigger {
     }
 }
 
+/// The device model only raises an interrupt when it queues one, and forgets it once the
+/// guest read IIR. [`LumperSerial`] raises them instead, from the register state.
+pub struct NoTrigger;
+
+impl Trigger for NoTrigger {
+    type E = Error;
+
+    fn trigger(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Serial port counters, read by the API while the vCPUs update them.
+#[derive(Default)]
+pub(crate) struct SerialStats {
+    rx_bytes: AtomicU64,
+    tx_bytes: AtomicU64,
+    tx_errors: AtomicU64,
+    // When a sink failure was last logged.
+    last_error_log: Mutex<Option<Instant>>,
+}
+
+impl SerialStats {
+    pub fn counters(&self) -> SerialCounters {
+        SerialCounters {
+            rx_bytes: self.rx_bytes.load(Ordering::Relaxed),
+            tx_bytes: self.tx_bytes.load(Ordering::Relaxed),
+            tx_errors: self.tx_errors.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Count a failed write to the output sink. A disk filling up fails every write, the
+    /// failures are only logged every few seconds.
+    pub fn output_error(&self, error: &Error) {
+        let errors = self.tx_errors.fetch_add(1, Ordering::Relaxed) + 1;
+
+        let mut last_log = self.last_error_log.lock().unwrap();
+        if last_log.is_none_or(|last| last.elapsed() >= OUTPUT_ERROR_LOG_INTERVAL) {
+            log::warn!(
+                "Failed to write the serial output ({} failed writes so far): {}",
+                errors,
+                error
+            );
+            *last_log = Some(Instant::now());
+        }
+    }
+}
+
 pub(crate) struct LumperSerial {
     // evenfd allows for the device to send interrupts to the guest.
     eventfd: EventFdTrigger,
 
     // serial is the actual serial device.
-    pub serial: Serial<EventFdTrigger, NoEvents, Box<dyn Write + Send>>,
+    pub serial: Serial<NoTrigger, NoEvents, Box<dyn Write + Send>>,
+
+    // Input waiting for room in the device receive FIFO.
+    pending_input: VecDeque<u8>,
+
+    // A transmit holding register empty interrupt is pending, until the guest reads it
+    // from IIR. The transmit register empties as soon as it is written.
+    thr_empty: bool,
+
+    // Whether the interrupt line is up. The eventfd fires when it goes up.
+    irq_line: bool,
+
+    stats: Arc<SerialStats>,
+
+    // Records the input sent to the guest.
+    recorder: Option<SessionRecorder>,
 }
 
 impl LumperSerial {
     pub fn new(output: Box<dyn Write + Send>) -> Result<Self> {
+        Self::with_stats(output, Arc::default())
+    }
+
+    /// A port counting in `stats`, which the output sink shares to count its failures.
+    pub fn with_stats(output: Box<dyn Write + Send>, stats: Arc<SerialStats>) -> Result<Self> {
         let eventfd = EventFdTrigger::new(libc::EFD_NONBLOCK).unwrap();
 
         Ok(LumperSerial {
-            eventfd: eventfd.try_clone()?,
-            serial: Serial::new(eventfd.try_clone()?, Box::new(output)),
+            eventfd,
+            serial: Serial::new(NoTrigger, Box::new(output)),
+            pending_input: VecDeque::new(),
+            thr_empty: false,
+            irq_line: false,
+            stats,
+            recorder: None,
+        })
+    }
+
+    /// A copy of the port, registers and pending input included, writing to `output`. The
+    /// copy raises its interrupt through an eventfd of its own, and counts in `stats`.
+    pub fn clone_with_output(
+        &self,
+        output: Box<dyn Write + Send>,
+        stats: Arc<SerialStats>,
+    ) -> Result<Self> {
+        let serial = Serial::from_state(&self.serial.state(), NoTrigger, NoEvents, output)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, format!("{:?}", e)))?;
+
+        Ok(LumperSerial {
+            eventfd: EventFdTrigger::new(libc::EFD_NONBLOCK)?,
+            serial,
+            pending_input: self.pending_input.clone(),
+            thr_empty: self.thr_empty,
+            irq_line: self.irq_line,
+            stats,
+            recorder: None,
         })
     }
 
     pub fn eventfd(&self) -> Result<EventFd> {
         Ok(self.eventfd.try_clone()?.0)
     }
+
+    /// Put the registers back in their power-on state, and empty the receive FIFO, when
+    /// the guest restarts. The port keeps its output, its counters, and the input waiting
+    /// for room in the FIFO.
+    pub fn reset(&mut self) {
+        let serial = std::mem::replace(
+            &mut self.serial,
+            Serial::new(NoTrigger, Box::new(std::io::sink())),
+        );
+        self.serial = Serial::new(NoTrigger, serial.into_writer());
+        self.thr_empty = false;
+        self.irq_line = false;
+    }
+
+    /// Send `data` to the guest.
+    ///
+    /// The receive FIFO is only 64 bytes deep, what does not fit is kept until the
+    /// guest reads. So is the input sent while the port is in loopback mode.
+    pub fn enqueue_input(&mut self, data: &[u8]) -> std::result::Result<(), serial::Error<Error>> {
+        if let Some(recorder) = self.recorder.as_ref() {
+            recorder.record_input(data);
+        }
+        self.pending_input.extend(data);
+        self.flush_input()
+    }
+
+    /// Record the input sent to the guest with `recorder`, from now on.
+    pub fn set_recorder(&mut self, recorder: SessionRecorder) {
+        self.recorder = Some(recorder);
+    }
+
+    pub fn stats(&self) -> Arc<SerialStats> {
+        self.stats.clone()
+    }
+
+    /// Number of input bytes waiting for room in the receive FIFO.
+    pub fn pending_input(&self) -> usize {
+        self.pending_input.len()
+    }
+
+    // Move the pending input to the receive FIFO, as much as it takes, and update the
+    // interrupt. What the device refuses stays pending, for the next call.
+    fn flush_input(&mut self) -> std::result::Result<(), serial::Error<Error>> {
+        let count = std::cmp::min(self.serial.fifo_capacity(), self.pending_input.len());
+        let enqueued = match count {
+            0 => Ok(0),
+            _ => {
+                let input: Vec<u8> = self.pending_input.iter().take(count).copied().collect();
+                self.serial.enqueue_raw_bytes(&input)
+            }
+        };
+        if let Ok(written) = enqueued {
+            self.pending_input.drain(..written);
+            self.stats
+                .rx_bytes
+                .fetch_add(written as u64, Ordering::Relaxed);
+        }
+
+        // Even with no input, a register access may have changed the interrupt state.
+        self.update_interrupt().map_err(serial::Error::Trigger)?;
+        enqueued.map(|_| ())
+    }
+
+    // The interrupt enable register, unless hidden by the divisor latch.
+    fn interrupt_enable(&mut self) -> Option<u8> {
+        match self.serial.read(LCR_OFFSET) & LCR_DLAB {
+            0 => Some(self.serial.read(IER_OFFSET)),
+            _ => None,
+        }
+    }
+
+    // The interrupt to report in IIR, by priority, if any. Received data is reported
+    // for as long as there is some, not only when it arrives.
+    fn pending_interrupt(&mut self, ier: u8) -> Option<u8> {
+        if ier & IER_RDA != 0 && self.serial.read(LSR_OFFSET) & LSR_DATA_READY != 0 {
+            Some(IIR_RDA)
+        } else if ier & IER_THR_EMPTY != 0 && self.thr_empty {
+            Some(IIR_THR_EMPTY)
+        } else {
+            None
+        }
+    }
+
+    // Raise the interrupt when one gets pending. The interrupt enable register is
+    // unknown while the divisor latch is selected, the line stays as it is then.
+    fn update_interrupt(&mut self) -> Result<()> {
+        let Some(ier) = self.interrupt_enable() else {
+            return Ok(());
+        };
+
+        let line = self.pending_interrupt(ier).is_some();
+        let rising = line && !self.irq_line;
+        self.irq_line = line;
+        if rising {
+            self.eventfd.trigger()?;
+        }
+
+        Ok(())
+    }
+
+    fn read_iir(&mut self) -> u8 {
+        // Only keep the FIFO bits of the device model, it forgets the interrupts it
+        // reported once.
+        let fifo = self.serial.read(IIR_OFFSET) & IIR_FIFO_BITS;
+        let ier = self.interrupt_enable().unwrap_or(0);
+
+        match self.pending_interrupt(ier) {
+            Some(IIR_THR_EMPTY) => {
+                // Reading it acknowledges the transmit holding register empty interrupt.
+                self.thr_empty = false;
+                fifo | IIR_THR_EMPTY
+            }
+            Some(iir) => fifo | iir,
+            None => fifo | IIR_NONE,
+        }
+    }
+
+    /// Whether the guest enabled the receive interrupt.
+    ///
+    /// Linux only does so once the port is opened, input sent before is lost.
+    pub fn rx_enabled(&mut self) -> bool {
+        // The interrupt enable register is hidden while the divisor latch is selected.
+        // Neither read has side effects.
+        self.serial.read(LCR_OFFSET) & LCR_DLAB == 0 && self.serial.read(IER_OFFSET) & IER_RDA != 0
+    }
+
+    /// Handle a guest read of the register at `offset`.
+    pub fn read(&mut self, offset: u8) -> u8 {
+        let value = match offset {
+            IIR_OFFSET => self.read_iir(),
+            _ => self.serial.read(offset),
+        };
+
+        // The guest may have made room in the receive FIFO.
+        if let Err(e) = self.flush_input() {
+            eprintln!("Failed to refill the serial receive FIFO: {:?}", e);
+        }
+
+        value
+    }
+
+    /// Handle a guest write of `value` to the register at `offset`.
+    pub fn write(&mut self, offset: u8, value: u8) {
+        // The transmit and interrupt enable registers are hidden while the divisor latch
+        // is selected.
+        if let Some(ier) = self.interrupt_enable() {
+            match offset {
+                DATA_OFFSET => {
+                    // In loopback mode, the byte goes to the receive FIFO instead.
+                    if self.serial.read(MCR_OFFSET) & MCR_LOOP == 0 {
+                        self.stats.tx_bytes.fetch_add(1, Ordering::Relaxed);
+                    }
+                    self.thr_empty = true;
+                }
+                // Enabling the interrupt with an empty transmit register raises it.
+                IER_OFFSET if value & IER_THR_EMPTY != 0 && ier & IER_THR_EMPTY == 0 => {
+                    self.thr_empty = true;
+                }
+                _ => (),
+            }
+        }
+        // The guest does not know about the sink, a failed write only loses the output.
+        match self.serial.write(offset, value) {
+            Ok(()) => {}
+            Err(serial::Error::IOError(e)) => self.stats.output_error(&e),
+            Err(e) => eprintln!("Failed to write to the serial port: {:?}", e),
+        }
+
+        // Leaving the loopback mode lets the pending input in, and the new settings may
+        // raise the interrupt.
+        if let Err(e) = self.flush_input() {
+            eprintln!("Failed to refill the serial receive FIFO: {:?}", e);
+        }
+    }
+}
+
+impl MutDevicePio for LumperSerial {
+    fn pio_read(&mut self, _base: PioAddress, offset: PioAddressOffset, data: &mut [u8]) {
+        // The offset is lower than SERIAL_PORT_SIZE, it always fits in a u8.
+        // String reads (insb) read the register once per byte.
+        for byte in data.iter_mut() {
+            *byte = self.read(offset as u8);
+        }
+    }
+
+    fn pio_write(&mut self, _base: PioAddress, offset: PioAddressOffset, data: &[u8]) {
+        let offset = offset as u8;
+
+        // The registers are 8 bits wide. Only string writes (outsb) of characters to the
+        // transmit register carry several bytes.
+        if data.len() > 1 && offset != DATA_OFFSET {
+            log::debug!(
+                "Ignoring {} bytes write to serial register {}",
+                data.len(),
+                offset
+            );
+            return;
+        }
+
+        for byte in data {
+            self.write(offset, *byte);
+        }
+    }
+}
+
+// On aarch64, the same registers are memory mapped, one byte apart.
+#[cfg(target_arch = "aarch64")]
+impl MutDeviceMmio for LumperSerial {
+    fn mmio_read(&mut self, _base: MmioAddress, offset: MmioAddressOffset, data: &mut [u8]) {
+        // The registers are 8 bits wide, wider reads get zeroes past the first byte.
+        data.fill(0);
+        if let Some(byte) = data.first_mut() {
+            *byte = self.read(offset as u8);
+        }
+    }
+
+    fn mmio_write(&mut self, _base: MmioAddress, offset: MmioAddressOffset, data: &[u8]) {
+        if let Some(byte) = data.first() {
+            self.write(offset as u8, *byte);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(target_arch = "x86_64")]
+    use vm_device::device_manager::PioManager;
+
+    #[test]
+    fn paste() {
+        let mut serial = LumperSerial::new(Box::new(std::io::sink())).unwrap();
+        let pasted: Vec<u8> = (0..4096).map(|i| (i % 251) as u8).collect();
+
+        // Stdin is read in 64 bytes chunks, while the guest lags behind.
+        let mut received = Vec::new();
+        for chunk in pasted.chunks(64) {
+            serial.enqueue_input(chunk).unwrap();
+            for _ in 0..16 {
+                if serial.read(LSR_OFFSET) & LSR_DATA_READY != 0 {
+                    received.push(serial.read(0));
+                }
+            }
+        }
+        assert!(serial.pending_input() > 0);
+
+        while serial.read(LSR_OFFSET) & LSR_DATA_READY != 0 {
+            received.push(serial.read(0));
+        }
+
+        assert_eq!(serial.pending_input(), 0);
+        assert_eq!(received, pasted);
+    }
+
+    #[test]
+    fn rx_enabled() {
+        let mut serial = LumperSerial::new(Box::new(std::io::sink())).unwrap();
+        assert!(!serial.rx_enabled());
+
+        // The divisor latch high byte shares the interrupt enable register offset.
+        serial.serial.write(LCR_OFFSET, LCR_DLAB).unwrap();
+        serial.serial.write(IER_OFFSET, IER_RDA).unwrap();
+        assert!(!serial.rx_enabled());
+
+        serial.serial.write(LCR_OFFSET, 0x03).unwrap();
+        assert!(!serial.rx_enabled());
+        serial.serial.write(IER_OFFSET, IER_RDA).unwrap();
+        assert!(serial.rx_enabled());
+    }
+
+    // Whether the interrupt fired since the last call.
+    fn fired(eventfd: &EventFd) -> bool {
+        eventfd.read().is_ok()
+    }
+
+    #[test]
+    fn isr_loop() {
+        let mut serial = LumperSerial::new(Box::new(std::io::sink())).unwrap();
+        let eventfd = serial.eventfd().unwrap();
+        let input: Vec<u8> = (0..4096).map(|i| (i % 251) as u8).collect();
+        serial.write(LCR_OFFSET, 0x03);
+        serial.write(IER_OFFSET, IER_RDA);
+
+        // The input comes in chunks of various sizes, some while the guest handles the
+        // interrupt. The guest only looks at the port when interrupted, and reads one
+        // byte per IIR read.
+        let mut chunks = input.chunks(97).enumerate().peekable();
+        let mut received = Vec::new();
+        for round in 0.. {
+            assert!(round < 1000, "input stranded");
+
+            if let Some((_, chunk)) = chunks.next_if(|(index, _)| index % 3 != 2) {
+                serial.enqueue_input(chunk).unwrap();
+            }
+            if !fired(&eventfd) {
+                if chunks.peek().is_none() {
+                    break;
+                }
+                continue;
+            }
+
+            loop {
+                let iir = serial.read(IIR_OFFSET);
+                if iir & IIR_NONE != 0 {
+                    break;
+                }
+                assert_eq!(iir & 0x0f, IIR_RDA);
+                if let Some((_, chunk)) = chunks.next_if(|(index, _)| index % 3 == 2) {
+                    serial.enqueue_input(chunk).unwrap();
+                }
+                received.push(serial.read(DATA_OFFSET));
+            }
+        }
+
+        assert_eq!(received, input);
+        assert_eq!(serial.pending_input(), 0);
+        assert_eq!(serial.read(LSR_OFFSET) & LSR_DATA_READY, 0);
+    }
+
+    #[test]
+    fn rx_interrupt_on_enable() {
+        let mut serial = LumperSerial::new(Box::new(std::io::sink())).unwrap();
+        let eventfd = serial.eventfd().unwrap();
+        serial.enqueue_input(b"early").unwrap();
+        assert!(!fired(&eventfd));
+        assert_eq!(serial.read(IIR_OFFSET) & 0x0f, IIR_NONE);
+
+        // The input received before shows up as soon as the interrupt is enabled.
+        serial.write(IER_OFFSET, IER_RDA);
+        assert!(fired(&eventfd));
+        assert_eq!(serial.read(IIR_OFFSET) & 0x0f, IIR_RDA);
+        assert_eq!(serial.read(IIR_OFFSET) & 0x0f, IIR_RDA);
+    }
+
+    #[test]
+    fn clone_with_output() {
+        let mut serial = LumperSerial::new(Box::new(std::io::sink())).unwrap();
+        serial.write(LCR_OFFSET, 0x03);
+        serial.write(IER_OFFSET, IER_RDA);
+        let input: Vec<u8> = (0..100).collect();
+        serial.enqueue_input(&input).unwrap();
+        assert_eq!(serial.read(DATA_OFFSET), 0);
+
+        let output = SharedBuffer::default();
+        let mut clone = serial
+            .clone_with_output(Box::new(output.clone()), Arc::default())
+            .unwrap();
+        let eventfd = clone.eventfd().unwrap();
+        // The clone starts where the original was, the rest of the input included.
+        assert!(clone.rx_enabled());
+        assert_eq!(clone.read(IIR_OFFSET) & 0x0f, IIR_RDA);
+        let mut received = Vec::new();
+        while clone.read(LSR_OFFSET) & LSR_DATA_READY != 0 {
+            received.push(clone.read(DATA_OFFSET));
+        }
+        assert_eq!(received, &input[1..]);
+        clone.write(DATA_OFFSET, b'c');
+        assert_eq!(*output.0.lock().unwrap(), b"c");
+
+        // Neither affects the other.
+        assert_eq!(serial.read(DATA_OFFSET), 1);
+        assert_eq!(serial.pending_input(), 34);
+        clone.enqueue_input(b"x").unwrap();
+        assert!(fired(&eventfd));
+        assert_eq!(serial.stats().counters().rx_bytes, 66);
+        assert_eq!(clone.stats().counters().rx_bytes, 36);
+    }
+
+    #[test]
+    fn thr_empty_interrupt() {
+        let output = SharedBuffer::default();
+        let mut serial = LumperSerial::new(Box::new(output.clone())).unwrap();
+        let eventfd = serial.eventfd().unwrap();
+
+        // Enabling it with an empty transmit register raises it, reading IIR clears it.
+        serial.write(IER_OFFSET, IER_THR_EMPTY);
+        assert!(fired(&eventfd));
+        assert_eq!(serial.read(IIR_OFFSET) & 0x0f, IIR_THR_EMPTY);
+        assert_eq!(serial.read(IIR_OFFSET) & 0x0f, IIR_NONE);
+
+        // The transmit register empties again after each write.
+        serial.write(DATA_OFFSET, b'a');
+        assert!(fired(&eventfd));
+        assert_eq!(serial.read(IIR_OFFSET) & 0x0f, IIR_THR_EMPTY);
+
+        // Received data goes first.
+        serial.write(IER_OFFSET, IER_RDA | IER_THR_EMPTY);
+        serial.write(DATA_OFFSET, b'b');
+        serial.enqueue_input(b"x").unwrap();
+        assert_eq!(serial.read(IIR_OFFSET) & 0x0f, IIR_RDA);
+        assert_eq!(serial.read(DATA_OFFSET), b'x');
+        assert_eq!(serial.read(IIR_OFFSET) & 0x0f, IIR_THR_EMPTY);
+        assert_eq!(serial.read(IIR_OFFSET) & 0x0f, IIR_NONE);
+        assert_eq!(*output.0.lock().unwrap(), b"ab");
+    }
+
+    #[test]
+    fn loopback() {
+        let output = SharedBuffer::default();
+        let mut serial = LumperSerial::new(Box::new(output.clone())).unwrap();
+        let eventfd = serial.eventfd().unwrap();
+        serial.write(IER_OFFSET, IER_RDA);
+        serial.write(MCR_OFFSET, MCR_LOOP);
+
+        // The input waits for the end of the loopback mode, the output comes back.
+        serial.enqueue_input(b"input").unwrap();
+        assert_eq!(serial.pending_input(), 5);
+        serial.write(DATA_OFFSET, b'!');
+        assert!(fired(&eventfd));
+        assert_eq!(serial.read(IIR_OFFSET) & 0x0f, IIR_RDA);
+        assert_eq!(serial.read(DATA_OFFSET), b'!');
+        assert_eq!(serial.read(IIR_OFFSET) & 0x0f, IIR_NONE);
+        assert!(output.0.lock().unwrap().is_empty());
+
+        serial.write(MCR_OFFSET, 0);
+        assert!(fired(&eventfd));
+        let mut received = Vec::new();
+        while serial.read(LSR_OFFSET) & LSR_DATA_READY != 0 {
+            received.push(serial.read(DATA_OFFSET));
+        }
+        assert_eq!(received, b"input");
+
+        let counters = serial.stats().counters();
+        assert_eq!(counters.rx_bytes, 5);
+        assert_eq!(counters.tx_bytes, 0);
+    }
+
+    #[test]
+    fn reset() {
+        let output = SharedBuffer::default();
+        let mut serial = LumperSerial::new(Box::new(output.clone())).unwrap();
+        serial.write(IER_OFFSET, IER_RDA);
+        serial.enqueue_input(b"before").unwrap();
+
+        serial.reset();
+        assert!(!serial.rx_enabled());
+        assert_eq!(serial.read(LSR_OFFSET) & LSR_DATA_READY, 0);
+        assert_eq!(serial.read(IIR_OFFSET) & 0x0f, IIR_NONE);
+
+        // The output goes on to the same sink.
+        serial.write(DATA_OFFSET, b'!');
+        assert_eq!(*output.0.lock().unwrap(), b"!");
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn pio_device(output: &SharedBuffer) -> (Arc<Mutex<LumperSerial>>, IoManager) {
+        let serial = Arc::new(Mutex::new(
+            LumperSerial::new(Box::new(output.clone())).unwrap(),
+        ));
+        let mut io_manager = IoManager::new();
+        COM1.register(&mut io_manager, serial.clone()).unwrap();
+
+        (serial, io_manager)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn access_sizes() {
+        let output = SharedBuffer::default();
+        let (serial, mut io_manager) = pio_device(&output);
+        let thr = PioAddress(COM1.base + u16::from(DATA_OFFSET));
+        let lcr = PioAddress(COM1.base + u16::from(LCR_OFFSET));
+
+        // KVM hands over all the bytes of an exit at once: 1, 2 or 4 bytes for a single
+        // access, the whole string for rep outsb.
+        io_manager.pio_write(thr, b"a").unwrap();
+        io_manager.pio_write(thr, b"bc").unwrap();
+        io_manager.pio_write(thr, b"defg").unwrap();
+        let early_boot = b"Decompressing Linux... Parsing ELF... done.\n";
+        io_manager.pio_write(thr, early_boot).unwrap();
+
+        let mut expected = b"abcdefg".to_vec();
+        expected.extend_from_slice(early_boot);
+        assert_eq!(*output.0.lock().unwrap(), expected);
+        let tx_bytes = expected.len() as u64;
+        assert_eq!(serial.lock().unwrap().stats().counters().tx_bytes, tx_bytes);
+
+        // Other registers are 8 bits wide, wider writes are ignored.
+        io_manager.pio_write(lcr, &[0x03]).unwrap();
+        io_manager.pio_write(lcr, &[LCR_DLAB, 0]).unwrap();
+        io_manager.pio_write(lcr, &[LCR_DLAB, 0, 0, 0]).unwrap();
+        let mut data = [0u8];
+        io_manager.pio_read(lcr, &mut data).unwrap();
+        assert_eq!(data[0], 0x03);
+
+        // Reads fill every byte: a rep insb drains the receive FIFO.
+        serial.lock().unwrap().enqueue_input(b"wxyz").unwrap();
+        let mut data = [0u8; 2];
+        io_manager.pio_read(thr, &mut data).unwrap();
+        assert_eq!(&data, b"wx");
+        let mut data = [0u8; 4];
+        io_manager.pio_read(lcr, &mut data).unwrap();
+        assert_eq!(data, [0x03; 4]);
+        io_manager.pio_read(thr, &mut data[..2]).unwrap();
+        assert_eq!(&data[..2], b"yz");
+
+        // Divisor latch writes are not output.
+        io_manager.pio_write(lcr, &[LCR_DLAB]).unwrap();
+        io_manager.pio_write(thr, &[0x01]).unwrap();
+        let counters = serial.lock().unwrap().stats().counters();
+        assert_eq!(counters.tx_bytes, tx_bytes);
+        assert_eq!(counters.rx_bytes, 4);
+    }
+
+    // Serial output, shared with the test.
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    // Serial output to a full disk.
+    struct FullDisk;
+
+    impl Write for FullDisk {
+        fn write(&mut self, _buf: &[u8]) -> Result<usize> {
+            Err(Error::from_raw_os_error(libc::ENOSPC))
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn output_errors() {
+        let mut serial = LumperSerial::new(Box::new(FullDisk)).unwrap();
+        serial.write(LCR_OFFSET, 0x03);
+
+        // The guest keeps writing, the output is lost.
+        for byte in b"hello" {
+            serial.write(DATA_OFFSET, *byte);
+        }
+        let counters = serial.stats().counters();
+        assert_eq!(counters.tx_bytes, 5);
+        assert_eq!(counters.tx_errors, 5);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn pio_registers() {
+        // The same accesses go to a serial through the I/O manager, and to a reference
+        // serial directly.
+        let output = SharedBuffer::default();
+        let (serial, mut io_manager) = pio_device(&output);
+
+        let expected_output = SharedBuffer::default();
+        let mut expected = LumperSerial::new(Box::new(expected_output.clone())).unwrap();
+
+        let write = |io_manager: &mut IoManager, expected: &mut LumperSerial, offset, value| {
+            io_manager
+                .pio_write(PioAddress(COM1.base + offset), &[value])
+                .unwrap();
+            expected.write(offset as u8, value);
+        };
+
+        // Set the divisor latch, then the line, modem, FIFO and interrupt settings.
+        write(&mut io_manager, &mut expected, 3, 0x80);
+        write(&mut io_manager, &mut expected, 0, 0x01);
+        write(&mut io_manager, &mut expected, 1, 0x00);
+        write(&mut io_manager, &mut expected, 3, 0x03);
+        write(&mut io_manager, &mut expected, 4, 0x0b);
+        write(&mut io_manager, &mut expected, 2, 0x07);
+        write(&mut io_manager, &mut expected, 1, 0x0f);
+        write(&mut io_manager, &mut expected, 7, 0x5a);
+        for byte in b"hello" {
+            write(&mut io_manager, &mut expected, 0, *byte);
+        }
+
+        serial.lock().unwrap().enqueue_input(b"ok").unwrap();
+        expected.enqueue_input(b"ok").unwrap();
+
+        // Read every register, twice so that the receive FIFO drains.
+        for _ in 0..2 {
+            for offset in 0..SERIAL_PORT_SIZE {
+                let mut data = [0u8];
+                io_manager
+                    .pio_read(PioAddress(COM1.base + offset), &mut data)
+                    .unwrap();
+                assert_eq!(data[0], expected.read(offset as u8), "offset {}", offset);
+            }
+        }
+
+        assert_eq!(*output.0.lock().unwrap(), b"hello");
+        assert_eq!(
+            *output.0.lock().unwrap(),
+            *expected_output.0.lock().unwrap()
+        );
+
+        // Ports past the serial are not claimed.
+        assert!(io_manager
+            .pio_read(PioAddress(COM1.base + SERIAL_PORT_SIZE), &mut [0u8])
+            .is_err());
+    }
 }
@@ -0,0 +1,88 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use std::io::{Result, Write};
+
+/// Output copied to several sinks, e.g. the console file and a library consumer.
+///
+/// Every sink gets all the bytes: one failing does not cut the others off. The sinks are
+/// expected not to block, as [`AsyncWriter`](super::async_writer::AsyncWriter)s.
+pub(crate) struct TeeWriter {
+    sinks: Vec<Box<dyn Write + Send>>,
+}
+
+impl TeeWriter {
+    pub fn new(sinks: Vec<Box<dyn Write + Send>>) -> Self {
+        TeeWriter { sinks }
+    }
+}
+
+impl Write for TeeWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let mut result = Ok(buf.len());
+        for sink in self.sinks.iter_mut() {
+            if let Err(e) = sink.write_all(buf) {
+                result = Err(e);
+            }
+        }
+
+        result
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        let mut result = Ok(());
+        for sink in self.sinks.iter_mut() {
+            if let Err(e) = sink.flush() {
+                result = Err(e);
+            }
+        }
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    struct BrokenSink;
+
+    impl Write for BrokenSink {
+        fn write(&mut self, _buf: &[u8]) -> Result<usize> {
+            Err(io::Error::from_raw_os_error(libc::EPIPE))
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn all_sinks() {
+        let first = SharedBuffer::default();
+        let second = SharedBuffer::default();
+        let mut tee = TeeWriter::new(vec![
+            Box::new(first.clone()),
+            Box::new(BrokenSink),
+            Box::new(second.clone()),
+        ]);
+
+        assert!(tee.write(b"login: ").is_err());
+        assert_eq!(*first.0.lock().unwrap(), b"login: ");
+        assert_eq!(*second.0.lock().unwrap(), b"login: ");
+    }
+}
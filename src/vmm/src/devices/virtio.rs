@@ -0,0 +1,63 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Interrupts and reset of the virtio-mmio devices.
+//!
+//! The driver interrupt handler reads the InterruptStatus register, writes what it read
+//! back to InterruptACK, then handles the configuration change and the used rings. An
+//! event coming in meanwhile has its used ring entry written before its status bit, so
+//! the handler either sees it in the rings, or finds its bit on the next interrupt.
+
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use virtio_device::VirtioConfig;
+use virtio_queue::{Queue, QueueT};
+use vm_device::bus::MmioAddressOffset;
+use vmm_sys_util::eventfd::EventFd;
+
+use super::irq_trace::IrqTrace;
+
+/// Offset of the InterruptACK register.
+pub(crate) const VIRTIO_MMIO_INTERRUPT_ACK: MmioAddressOffset = 0x64;
+
+/// Interrupt status bit telling the driver the queues were used.
+pub(crate) const VIRTIO_MMIO_INT_VRING: u8 = 0x1;
+/// Interrupt status bit telling the driver the device configuration changed.
+pub(crate) const VIRTIO_MMIO_INT_CONFIG: u8 = 0x2;
+
+/// Set `bits` in the interrupt status, then interrupt the guest.
+///
+/// Each event writes the irqfd, whether the bits were already set or not: the driver may
+/// have read the status before they were.
+pub(crate) fn signal(status: &AtomicU8, irq_fd: &EventFd, trace: &IrqTrace, bits: u8) {
+    status.fetch_or(bits, Ordering::SeqCst);
+    trace.signal();
+
+    // Error should be recoverable as is, so we just log it.
+    irq_fd.write(1).unwrap_or_else(|e| {
+        println!("Failed to signal irq: {:?}", e);
+    });
+}
+
+/// Clear the bits the driver wrote to InterruptACK. The others stay set, for the next
+/// interrupt.
+pub(crate) fn ack(status: &AtomicU8, trace: &IrqTrace, data: &[u8]) {
+    trace.ack();
+    if let Ok(value) = data.try_into() {
+        status.fetch_and(!(u32::from_le_bytes(value) as u8), Ordering::SeqCst);
+    }
+}
+
+/// Put `config` back in the state of a new device: the driver negotiates the features,
+/// and sets the queues up, again.
+pub(crate) fn reset_config(config: &mut VirtioConfig<Queue>) {
+    for queue in config.queues.iter_mut() {
+        queue.reset();
+    }
+    config.driver_features = 0;
+    config.device_features_select = 0;
+    config.driver_features_select = 0;
+    config.queue_select = 0;
+    config.device_status = 0;
+    config.interrupt_status.store(0, Ordering::SeqCst);
+    config.device_activated = false;
+}
@@ -0,0 +1,231 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use vm_device::bus::{MmioAddress, MmioAddressOffset};
+use vm_device::MutDeviceMmio;
+use vmm_sys_util::errno;
+use vmm_sys_util::timerfd::TimerFd;
+
+use crate::config::WatchdogAction;
+use crate::epoll_context::{EventHandler, EventOps, Events};
+use crate::{ExitReason, Result};
+
+/// Size of the watchdog register window.
+pub(crate) const WATCHDOG_MMIO_SIZE: u64 = 0x1000;
+
+// Register offsets. All the registers are 32 bits wide.
+// Writing 1 enables the watchdog, and starts the countdown. Writing 0 disables it.
+const REG_ENABLE: MmioAddressOffset = 0x0;
+// Timeout, in seconds. Writing it restarts the countdown.
+const REG_TIMEOUT: MmioAddressOffset = 0x4;
+// Writing any value restarts the countdown.
+const REG_PET: MmioAddressOffset = 0x8;
+
+/// A minimal watchdog, backed by a host timer.
+///
+/// Once the guest enabled it, it must pet it more often than the timeout. Otherwise the
+/// timer fd becomes readable, and the VMM takes the configured action.
+pub(crate) struct Watchdog {
+    timer: TimerFd,
+    timeout: Duration,
+    // The timeout the guest starts with, it may change it.
+    configured_timeout: Duration,
+    enabled: bool,
+}
+
+impl Watchdog {
+    pub fn new(timeout: Duration) -> errno::Result<Self> {
+        Ok(Watchdog {
+            timer: TimerFd::new()?,
+            timeout,
+            configured_timeout: timeout,
+            enabled: false,
+        })
+    }
+
+    // Restart the countdown, if the watchdog is enabled.
+    fn pet(&mut self) {
+        if !self.enabled {
+            return;
+        }
+
+        if let Err(e) = self.timer.reset(self.timeout, None) {
+            log::error!("Failed to arm the watchdog timer: {:?}", e);
+        }
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if enabled {
+            self.pet();
+        } else if let Err(e) = self.timer.clear() {
+            log::error!("Failed to disarm the watchdog timer: {:?}", e);
+        }
+    }
+
+    /// Disable the watchdog, and restore the configured timeout, as at boot.
+    pub fn reset(&mut self) {
+        self.set_enabled(false);
+        self.timeout = self.configured_timeout;
+    }
+
+    /// Handle the timer fd readiness. Returns whether the watchdog expired.
+    pub fn expired(&self) -> bool {
+        // The guest may have petted or disabled the watchdog since the timer fired, which
+        // rearmed or disarmed it.
+        self.enabled && !self.timer.is_armed().unwrap_or(true)
+    }
+
+    fn read(&self, offset: MmioAddressOffset) -> u32 {
+        match offset {
+            REG_ENABLE => u32::from(self.enabled),
+            REG_TIMEOUT => self.timeout.as_secs() as u32,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, offset: MmioAddressOffset, value: u32) {
+        match offset {
+            REG_ENABLE => self.set_enabled(value != 0),
+            REG_TIMEOUT if value != 0 => {
+                self.timeout = Duration::from_secs(u64::from(value));
+                self.pet();
+            }
+            REG_PET => self.pet(),
+            _ => {}
+        }
+    }
+}
+
+impl AsRawFd for Watchdog {
+    fn as_raw_fd(&self) -> RawFd {
+        self.timer.as_raw_fd()
+    }
+}
+
+/// Stops the VMM with the configured action when the watchdog expires.
+pub(crate) struct WatchdogHandler {
+    watchdog: Arc<Mutex<Watchdog>>,
+    action: WatchdogAction,
+}
+
+impl WatchdogHandler {
+    pub fn new(watchdog: Arc<Mutex<Watchdog>>, action: WatchdogAction) -> Self {
+        WatchdogHandler { watchdog, action }
+    }
+}
+
+impl EventHandler for WatchdogHandler {
+    fn process(&mut self, _events: Events, ops: &mut EventOps) -> Result<()> {
+        if self.watchdog.lock().unwrap().expired() {
+            ops.exit(ExitReason::WatchdogExpired(self.action));
+        }
+
+        Ok(())
+    }
+}
+
+impl MutDeviceMmio for Watchdog {
+    fn mmio_read(&mut self, _base: MmioAddress, offset: MmioAddressOffset, data: &mut [u8]) {
+        let value = self.read(offset).to_le_bytes();
+        let len = data.len().min(value.len());
+        data[..len].copy_from_slice(&value[..len]);
+    }
+
+    fn mmio_write(&mut self, _base: MmioAddress, offset: MmioAddressOffset, data: &[u8]) {
+        let mut value = [0u8; 4];
+        let len = data.len().min(value.len());
+        value[..len].copy_from_slice(&data[..len]);
+        self.write(offset, u32::from_le_bytes(value));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Instant;
+
+    const TIMEOUT: Duration = Duration::from_millis(200);
+
+    fn write(watchdog: &mut Watchdog, offset: MmioAddressOffset, value: u32) {
+        watchdog.mmio_write(MmioAddress(0), offset, &value.to_le_bytes());
+    }
+
+    // Wait for the timer fd to be readable, as the VMM event loop does.
+    fn wait_readable(watchdog: &Watchdog, timeout: Duration) -> bool {
+        let mut pollfd = libc::pollfd {
+            fd: watchdog.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // Safe because pollfd is valid, and only read by the kernel for one entry.
+        let ret = unsafe { libc::poll(&mut pollfd, 1, timeout.as_millis() as i32) };
+        ret == 1
+    }
+
+    #[test]
+    fn hung_guest() {
+        let mut watchdog = Watchdog::new(TIMEOUT).unwrap();
+
+        // Disabled, it never fires.
+        assert!(!wait_readable(&watchdog, TIMEOUT * 2));
+
+        // A healthy guest pets the watchdog more often than the timeout.
+        write(&mut watchdog, REG_ENABLE, 1);
+        for _ in 0..5 {
+            thread::sleep(TIMEOUT / 2);
+            assert!(!watchdog.expired());
+            write(&mut watchdog, REG_PET, 0);
+        }
+        assert!(!wait_readable(&watchdog, Duration::ZERO));
+
+        // Then it hangs, and the watchdog fires within the window.
+        let hung = Instant::now();
+        assert!(wait_readable(&watchdog, TIMEOUT * 5));
+        assert!(hung.elapsed() >= TIMEOUT - Duration::from_millis(10));
+        assert!(watchdog.expired());
+
+        // A late pet restarts the countdown.
+        write(&mut watchdog, REG_PET, 0);
+        assert!(!watchdog.expired());
+
+        // Disabling stops it.
+        write(&mut watchdog, REG_ENABLE, 0);
+        assert!(!wait_readable(&watchdog, TIMEOUT * 2));
+        assert!(!watchdog.expired());
+    }
+
+    #[test]
+    fn registers() {
+        let mut watchdog = Watchdog::new(Duration::from_secs(30)).unwrap();
+        let mut data = [0u8; 4];
+
+        watchdog.mmio_read(MmioAddress(0), REG_TIMEOUT, &mut data);
+        assert_eq!(u32::from_le_bytes(data), 30);
+
+        write(&mut watchdog, REG_TIMEOUT, 10);
+        write(&mut watchdog, REG_ENABLE, 1);
+        watchdog.mmio_read(MmioAddress(0), REG_TIMEOUT, &mut data);
+        assert_eq!(u32::from_le_bytes(data), 10);
+        watchdog.mmio_read(MmioAddress(0), REG_ENABLE, &mut data);
+        assert_eq!(u32::from_le_bytes(data), 1);
+        assert!(watchdog.timer.is_armed().unwrap());
+
+        // A zero timeout is ignored.
+        write(&mut watchdog, REG_TIMEOUT, 0);
+        watchdog.mmio_read(MmioAddress(0), REG_TIMEOUT, &mut data);
+        assert_eq!(u32::from_le_bytes(data), 10);
+
+        // Back to the boot state.
+        watchdog.reset();
+        watchdog.mmio_read(MmioAddress(0), REG_TIMEOUT, &mut data);
+        assert_eq!(u32::from_le_bytes(data), 30);
+        watchdog.mmio_read(MmioAddress(0), REG_ENABLE, &mut data);
+        assert_eq!(u32::from_le_bytes(data), 0);
+        assert!(!watchdog.timer.is_armed().unwrap());
+    }
+}
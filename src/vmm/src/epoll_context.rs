@@ -2,42 +2,368 @@
 
 extern crate epoll;
 
+use std::collections::BTreeMap;
 use std::io;
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::result;
+use std::time::Duration;
+
+use crate::devices::irq_trace;
+use crate::{Error, ExitReason, Result};
 
 pub(crate) const EPOLL_EVENTS_LEN: usize = 10;
 
-pub struct EpollContext {
+/// Identifies a registered file descriptor. The events ready at once are dispatched in the
+/// order of their tokens.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct Token(pub u64);
+
+/// What to wait for on a file descriptor.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Interest {
+    Read,
+}
+
+impl Interest {
+    fn events(self) -> epoll::Events {
+        match self {
+            Interest::Read => epoll::Events::EPOLLIN,
+        }
+    }
+}
+
+/// Readiness of a file descriptor.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Events(epoll::Events);
+
+impl Events {
+    pub fn readable(&self) -> bool {
+        self.0.contains(epoll::Events::EPOLLIN)
+    }
+
+    /// The peer closed its end, or the file descriptor failed.
+    pub fn hangup(&self) -> bool {
+        self.0
+            .intersects(epoll::Events::EPOLLHUP | epoll::Events::EPOLLERR)
+    }
+}
+
+/// Changes a handler asks for, applied once it returns.
+pub(crate) struct EventOps {
+    token: Token,
+    // When the loop woke up for the event, with `--trace-irq`, 0 otherwise.
+    wakeup: u64,
+    requests: Vec<Request>,
+    exit: Option<ExitReason>,
+}
+
+enum Request {
+    Pause(Token),
+    Resume(Token),
+    Remove(Token),
+    SetFd(Token, RawFd),
+}
+
+impl EventOps {
+    fn new(token: Token, wakeup: u64) -> Self {
+        EventOps {
+            token,
+            wakeup,
+            requests: Vec::new(),
+            exit: None,
+        }
+    }
+
+    /// Token of the event being processed.
+    pub fn token(&self) -> Token {
+        self.token
+    }
+
+    /// When the loop woke up for the event, as an [`irq_trace::now`] timestamp, 0 unless
+    /// tracing the interrupts.
+    pub fn wakeup(&self) -> u64 {
+        self.wakeup
+    }
+
+    /// Stop polling a file descriptor, keeping its handler.
+    pub fn pause(&mut self, token: Token) {
+        self.requests.push(Request::Pause(token));
+    }
+
+    /// Poll a paused file descriptor again.
+    pub fn resume(&mut self, token: Token) {
+        self.requests.push(Request::Resume(token));
+    }
+
+    /// Deregister a file descriptor, and drop its handler.
+    pub fn remove(&mut self, token: Token) {
+        self.requests.push(Request::Remove(token));
+    }
+
+    /// Poll another file descriptor for a token, e.g. once its owner reopened it.
+    pub fn set_fd(&mut self, token: Token, fd: RawFd) {
+        self.requests.push(Request::SetFd(token, fd));
+    }
+
+    /// Stop the event loop. The events left are not dispatched.
+    pub fn exit(&mut self, reason: ExitReason) {
+        self.exit = Some(reason);
+    }
+}
+
+/// Processes the events of a registered file descriptor.
+pub(crate) trait EventHandler: Send {
+    fn process(&mut self, events: Events, ops: &mut EventOps) -> Result<()>;
+}
+
+/// Only wakes the event loop up, which then checks what it needs.
+pub(crate) struct Wakeup;
+
+impl EventHandler for Wakeup {
+    fn process(&mut self, _events: Events, _ops: &mut EventOps) -> Result<()> {
+        Ok(())
+    }
+}
+
+struct Registration {
+    fd: RawFd,
+    interest: Interest,
+    paused: bool,
+    handler: Box<dyn EventHandler>,
+}
+
+/// Dispatches the events of the registered file descriptors to their handlers.
+pub(crate) struct EpollContext {
     raw_fd: RawFd,
+    handlers: BTreeMap<Token, Registration>,
+    events: Vec<epoll::Event>,
 }
 
 impl EpollContext {
     pub fn new() -> result::Result<EpollContext, io::Error> {
         let raw_fd = epoll::create(true)?;
-        Ok(EpollContext { raw_fd })
+        Ok(EpollContext {
+            raw_fd,
+            handlers: BTreeMap::new(),
+            events: vec![epoll::Event::new(epoll::Events::empty(), 0); EPOLL_EVENTS_LEN],
+        })
+    }
+
+    fn ctl(
+        &self,
+        op: epoll::ControlOptions,
+        fd: RawFd,
+        events: epoll::Events,
+        token: Token,
+    ) -> io::Result<()> {
+        epoll::ctl(self.raw_fd, op, fd, epoll::Event::new(events, token.0))
     }
 
-    pub fn add_stdin(&self) -> result::Result<(), io::Error> {
-        epoll::ctl(
-            self.raw_fd,
+    /// Register `fd`, dispatching its events to `handler`.
+    pub fn add(
+        &mut self,
+        fd: RawFd,
+        token: Token,
+        interest: Interest,
+        handler: Box<dyn EventHandler>,
+    ) -> result::Result<(), io::Error> {
+        if self.handlers.contains_key(&token) {
+            return Err(io::Error::from(io::ErrorKind::AlreadyExists));
+        }
+
+        self.ctl(
             epoll::ControlOptions::EPOLL_CTL_ADD,
-            libc::STDIN_FILENO,
-            epoll::Event::new(epoll::Events::EPOLLIN, libc::STDIN_FILENO as u64),
+            fd,
+            interest.events(),
+            token,
         )?;
+        self.handlers.insert(
+            token,
+            Registration {
+                fd,
+                interest,
+                paused: false,
+                handler,
+            },
+        );
 
         Ok(())
     }
-    pub fn add_fd(&self, fd: RawFd) -> result::Result<(), io::Error> {
-        epoll::ctl(
-            self.raw_fd,
-            epoll::ControlOptions::EPOLL_CTL_ADD,
-            fd,
-            epoll::Event::new(epoll::Events::EPOLLIN, fd as u64),
-        )?;
+
+    /// Deregister a file descriptor, and drop its handler. Unknown tokens are ignored.
+    pub fn remove(&mut self, token: Token) -> result::Result<(), io::Error> {
+        if let Some(registration) = self.handlers.remove(&token) {
+            if !registration.paused {
+                self.ctl(
+                    epoll::ControlOptions::EPOLL_CTL_DEL,
+                    registration.fd,
+                    epoll::Events::empty(),
+                    token,
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Stop polling a file descriptor, until it is resumed.
+    pub fn pause(&mut self, token: Token) -> result::Result<(), io::Error> {
+        match self.handlers.get(&token) {
+            Some(registration) if !registration.paused => {
+                self.ctl(
+                    epoll::ControlOptions::EPOLL_CTL_DEL,
+                    registration.fd,
+                    epoll::Events::empty(),
+                    token,
+                )?;
+            }
+            _ => return Ok(()),
+        }
+        // Safe to unwrap, the token was just found.
+        self.handlers.get_mut(&token).unwrap().paused = true;
+
+        Ok(())
+    }
+
+    /// Poll a paused file descriptor again.
+    pub fn resume(&mut self, token: Token) -> result::Result<(), io::Error> {
+        match self.handlers.get(&token) {
+            Some(registration) if registration.paused => {
+                self.ctl(
+                    epoll::ControlOptions::EPOLL_CTL_ADD,
+                    registration.fd,
+                    registration.interest.events(),
+                    token,
+                )?;
+            }
+            _ => return Ok(()),
+        }
+        // Safe to unwrap, the token was just found.
+        self.handlers.get_mut(&token).unwrap().paused = false;
+
+        Ok(())
+    }
+
+    /// Poll another file descriptor for `token`, keeping its handler, and whether it is
+    /// paused. Unknown tokens are ignored.
+    pub fn set_fd(&mut self, token: Token, fd: RawFd) -> result::Result<(), io::Error> {
+        let registration = match self.handlers.get(&token) {
+            Some(registration) => registration,
+            None => return Ok(()),
+        };
+        if !registration.paused {
+            // Closing the previous file descriptor may have deregistered it already.
+            let _ = self.ctl(
+                epoll::ControlOptions::EPOLL_CTL_DEL,
+                registration.fd,
+                epoll::Events::empty(),
+                token,
+            );
+            self.ctl(
+                epoll::ControlOptions::EPOLL_CTL_ADD,
+                fd,
+                registration.interest.events(),
+                token,
+            )?;
+        }
+        // Safe to unwrap, the token was just found.
+        self.handlers.get_mut(&token).unwrap().fd = fd;
 
         Ok(())
     }
+
+    pub fn is_paused(&self, token: Token) -> bool {
+        self.handlers
+            .get(&token)
+            .is_some_and(|registration| registration.paused)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.handlers.is_empty()
+    }
+
+    /// Deregister all the file descriptors, drop their handlers, and close the epoll file
+    /// descriptor. Returns the first deregistration failure, once all of them are gone.
+    ///
+    /// Nothing is polled afterwards. Closing again does nothing.
+    pub fn close(&mut self) -> result::Result<(), io::Error> {
+        if self.raw_fd < 0 {
+            return Ok(());
+        }
+
+        let tokens: Vec<Token> = self.handlers.keys().copied().collect();
+        let mut result = Ok(());
+        for token in tokens {
+            // The handler is dropped even when its file descriptor was closed first.
+            let removed = self.remove(token);
+            if result.is_ok() {
+                result = removed;
+            }
+        }
+
+        // Safe because we own the file descriptor, which is not used past this point.
+        unsafe { libc::close(self.raw_fd) };
+        self.raw_fd = -1;
+
+        result
+    }
+
+    /// Wait for events, for up to `timeout` when there is one, and dispatch them. Returns
+    /// the reason a handler asked the event loop to stop for.
+    pub fn run_once(&mut self, timeout: Option<Duration>) -> Result<Option<ExitReason>> {
+        // Round up, so that we do not spin during the last millisecond.
+        let timeout = timeout.map_or(-1, |timeout| {
+            i32::try_from(timeout.as_micros().div_ceil(1000)).unwrap_or(i32::MAX)
+        });
+
+        let count = match epoll::wait(self.raw_fd, timeout, &mut self.events[..]) {
+            Ok(count) => count,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => return Ok(None),
+            Err(e) => return Err(Error::EpollError(e)),
+        };
+        let wakeup = if irq_trace::enabled() {
+            irq_trace::now()
+        } else {
+            0
+        };
+
+        let mut ready: Vec<(Token, Events)> = self.events[..count]
+            .iter()
+            .map(|event| {
+                (
+                    Token(event.data),
+                    Events(epoll::Events::from_bits_truncate(event.events)),
+                )
+            })
+            .collect();
+        ready.sort_by_key(|(token, _)| *token);
+
+        for (token, events) in ready {
+            // Skip the handlers paused or removed by the previous ones.
+            let registration = match self.handlers.get_mut(&token) {
+                Some(registration) if !registration.paused => registration,
+                _ => continue,
+            };
+
+            let mut ops = EventOps::new(token, wakeup);
+            registration.handler.process(events, &mut ops)?;
+
+            for request in ops.requests {
+                match request {
+                    Request::Pause(token) => self.pause(token),
+                    Request::Resume(token) => self.resume(token),
+                    Request::Remove(token) => self.remove(token),
+                    Request::SetFd(token, fd) => self.set_fd(token, fd),
+                }
+                .map_err(Error::EpollError)?;
+            }
+            if ops.exit.is_some() {
+                return Ok(ops.exit);
+            }
+        }
+
+        Ok(None)
+    }
 }
 
 impl AsRawFd for EpollContext {
@@ -45,3 +371,251 @@ impl AsRawFd for EpollContext {
         self.raw_fd
     }
 }
+
+impl Drop for EpollContext {
+    fn drop(&mut self) {
+        if let Err(e) = self.close() {
+            log::debug!("Failed to deregister a file descriptor: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::{Arc, Mutex};
+
+    use vmm_sys_util::eventfd::EventFd;
+
+    // Records the order it is called in, and does what it was told to.
+    struct Recorder {
+        eventfd: Arc<EventFd>,
+        log: Arc<Mutex<Vec<Token>>>,
+        action: fn(&mut EventOps),
+    }
+
+    impl EventHandler for Recorder {
+        fn process(&mut self, events: Events, ops: &mut EventOps) -> Result<()> {
+            assert!(events.readable());
+            self.eventfd.read().unwrap();
+            self.log.lock().unwrap().push(ops.token());
+            (self.action)(ops);
+            Ok(())
+        }
+    }
+
+    struct Setup {
+        epoll: EpollContext,
+        eventfds: Vec<Arc<EventFd>>,
+        log: Arc<Mutex<Vec<Token>>>,
+    }
+
+    impl Setup {
+        // Register eventfds, with their actions, in the reverse order of their tokens.
+        fn new(actions: &[fn(&mut EventOps)]) -> Self {
+            let mut epoll = EpollContext::new().unwrap();
+            let log = Arc::new(Mutex::new(Vec::new()));
+            let mut eventfds = Vec::new();
+            for (index, action) in actions.iter().enumerate().rev() {
+                let eventfd = Arc::new(EventFd::new(libc::EFD_NONBLOCK).unwrap());
+                let handler = Recorder {
+                    eventfd: eventfd.clone(),
+                    log: log.clone(),
+                    action: *action,
+                };
+                epoll
+                    .add(
+                        eventfd.as_raw_fd(),
+                        Token(index as u64),
+                        Interest::Read,
+                        Box::new(handler),
+                    )
+                    .unwrap();
+                eventfds.insert(0, eventfd);
+            }
+
+            Setup {
+                epoll,
+                eventfds,
+                log,
+            }
+        }
+
+        fn signal_all(&self) {
+            for eventfd in self.eventfds.iter() {
+                eventfd.write(1).unwrap();
+            }
+        }
+
+        fn dispatched(&self) -> Vec<Token> {
+            self.log.lock().unwrap().drain(..).collect()
+        }
+    }
+
+    const TIMEOUT: Option<Duration> = Some(Duration::from_millis(100));
+
+    #[test]
+    fn dispatch_order() {
+        let mut setup = Setup::new(&[|_| {}, |_| {}, |_| {}]);
+
+        // Nothing is ready.
+        assert_eq!(setup.epoll.run_once(Some(Duration::ZERO)).unwrap(), None);
+        assert!(setup.dispatched().is_empty());
+
+        // By token, whatever the registration and readiness order.
+        for eventfd in setup.eventfds.iter().rev() {
+            eventfd.write(1).unwrap();
+        }
+        assert_eq!(setup.epoll.run_once(TIMEOUT).unwrap(), None);
+        assert_eq!(setup.dispatched(), [Token(0), Token(1), Token(2)]);
+
+        // A token is only registered once.
+        let eventfd = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        assert_eq!(
+            setup
+                .epoll
+                .add(
+                    eventfd.as_raw_fd(),
+                    Token(1),
+                    Interest::Read,
+                    Box::new(Wakeup)
+                )
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::AlreadyExists
+        );
+    }
+
+    #[test]
+    fn remove() {
+        let mut setup = Setup::new(&[
+            |ops| ops.remove(Token(2)),
+            |ops| ops.remove(ops.token()),
+            |_| {},
+        ]);
+
+        // The events of a handler removed by a previous one are dropped.
+        setup.signal_all();
+        assert_eq!(setup.epoll.run_once(TIMEOUT).unwrap(), None);
+        assert_eq!(setup.dispatched(), [Token(0), Token(1)]);
+
+        setup.signal_all();
+        assert_eq!(setup.epoll.run_once(TIMEOUT).unwrap(), None);
+        assert_eq!(setup.dispatched(), [Token(0)]);
+
+        setup.epoll.remove(Token(0)).unwrap();
+        assert!(setup.epoll.is_empty());
+        // Unknown tokens are ignored.
+        setup.epoll.remove(Token(0)).unwrap();
+    }
+
+    #[test]
+    fn close() {
+        let mut setup = Setup::new(&[|_| {}, |_| {}]);
+        setup.epoll.pause(Token(1)).unwrap();
+        let log = Arc::downgrade(&setup.log);
+        drop(setup.log);
+
+        // The handlers, and the log they hold, are dropped.
+        setup.epoll.close().unwrap();
+        assert!(setup.epoll.is_empty());
+        assert!(log.upgrade().is_none());
+        setup.epoll.close().unwrap();
+        assert!(setup.epoll.run_once(Some(Duration::ZERO)).is_err());
+    }
+
+    #[test]
+    fn pause() {
+        let mut setup = Setup::new(&[|ops| ops.pause(Token(1)), |_| {}]);
+
+        setup.signal_all();
+        assert_eq!(setup.epoll.run_once(TIMEOUT).unwrap(), None);
+        assert_eq!(setup.dispatched(), [Token(0)]);
+        assert!(setup.epoll.is_paused(Token(1)));
+
+        // The event waits for the file descriptor to be polled again.
+        assert_eq!(setup.epoll.run_once(Some(Duration::ZERO)).unwrap(), None);
+        assert!(setup.dispatched().is_empty());
+        setup.epoll.resume(Token(1)).unwrap();
+        assert!(!setup.epoll.is_paused(Token(1)));
+        assert_eq!(setup.epoll.run_once(TIMEOUT).unwrap(), None);
+        assert_eq!(setup.dispatched(), [Token(1)]);
+
+        // A paused file descriptor can be removed.
+        setup.epoll.pause(Token(1)).unwrap();
+        setup.epoll.remove(Token(1)).unwrap();
+        assert!(!setup.epoll.is_paused(Token(1)));
+    }
+
+    #[test]
+    fn exit() {
+        let mut setup = Setup::new(&[|_| {}, |ops| ops.exit(ExitReason::GuestShutdown), |_| {}]);
+
+        // The events after the exit request wait.
+        setup.signal_all();
+        assert_eq!(
+            setup.epoll.run_once(TIMEOUT).unwrap(),
+            Some(ExitReason::GuestShutdown)
+        );
+        assert_eq!(setup.dispatched(), [Token(0), Token(1)]);
+    }
+
+    #[test]
+    fn handler_error() {
+        struct Failing;
+
+        impl EventHandler for Failing {
+            fn process(&mut self, _events: Events, _ops: &mut EventOps) -> Result<()> {
+                Err(Error::DirtyTrackingDisabled)
+            }
+        }
+
+        let mut epoll = EpollContext::new().unwrap();
+        let eventfd = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        epoll
+            .add(
+                eventfd.as_raw_fd(),
+                Token(0),
+                Interest::Read,
+                Box::new(Failing),
+            )
+            .unwrap();
+        eventfd.write(1).unwrap();
+        assert!(matches!(
+            epoll.run_once(TIMEOUT),
+            Err(Error::DirtyTrackingDisabled)
+        ));
+    }
+
+    #[test]
+    fn set_fd() {
+        let mut setup = Setup::new(&[|_| {}]);
+        let new = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        setup.epoll.set_fd(Token(0), new.as_raw_fd()).unwrap();
+
+        // Only the new file descriptor is polled.
+        setup.signal_all();
+        assert_eq!(setup.epoll.run_once(Some(Duration::ZERO)).unwrap(), None);
+        assert!(setup.dispatched().is_empty());
+        new.write(1).unwrap();
+        assert_eq!(setup.epoll.run_once(TIMEOUT).unwrap(), None);
+        assert_eq!(setup.dispatched(), [Token(0)]);
+        new.read().unwrap();
+
+        // A paused one is polled once resumed.
+        let newer = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        setup.epoll.pause(Token(0)).unwrap();
+        setup.epoll.set_fd(Token(0), newer.as_raw_fd()).unwrap();
+        assert!(setup.epoll.is_paused(Token(0)));
+        setup.epoll.resume(Token(0)).unwrap();
+
+        setup.signal_all();
+        new.write(1).unwrap();
+        assert_eq!(setup.epoll.run_once(Some(Duration::ZERO)).unwrap(), None);
+        assert!(setup.dispatched().is_empty());
+        newer.write(1).unwrap();
+        assert_eq!(setup.epoll.run_once(TIMEOUT).unwrap(), None);
+        assert_eq!(setup.dispatched(), [Token(0)]);
+    }
+}
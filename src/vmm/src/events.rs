@@ -0,0 +1,308 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Lifecycle events, for orchestrators to follow the VM without parsing its logs.
+//!
+//! Each event is a JSON object on its own line, with the `CLOCK_MONOTONIC` time it
+//! happened at, in microseconds, and its name:
+//!
+//! ```text
+//! {"timestamp_us":1234567,"event":"configured","configure_us":9120,"memory_configure_us":412,"kernel_load_us":6530,"vcpu_configure_us":1830,"memory_advice":["mergeable"]}
+//! {"timestamp_us":1234890,"event":"vcpus-started","vcpus":2}
+//! {"timestamp_us":1236001,"event":"guest-console-active"}
+//! {"timestamp_us":1402117,"event":"agent-ready"}
+//! {"timestamp_us":1405880,"event":"reboot","reboots":1}
+//! {"timestamp_us":1408356,"event":"shutdown","reason":"signal","detail":"15"}
+//! ```
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use serde::Serialize;
+
+use crate::config::WatchdogAction;
+use crate::{ExitReason, PanicReport, PvpanicEvent, Result};
+
+/// A lifecycle transition of the VM.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum Event {
+    /// The VM is configured, ready to run, in `configure_us`. Mapping its memory took
+    /// `memory_configure_us` of it, loading the kernel and the initramfs `kernel_load_us`,
+    /// and creating and setting its vCPUs up `vcpu_configure_us`, while the kernel loaded
+    /// on x86_64. The host took the `memory_advice` hints about its memory.
+    Configured {
+        configure_us: u64,
+        memory_configure_us: u64,
+        kernel_load_us: u64,
+        vcpu_configure_us: u64,
+        memory_advice: Vec<&'static str>,
+    },
+    /// The vCPUs started running the guest.
+    VcpusStarted { vcpus: usize },
+    /// The guest wrote to its console for the first time.
+    GuestConsoleActive,
+    /// The guest agent sent its first heartbeat.
+    AgentReady,
+    /// The guest rebooted, and restarts in place, for the `reboots`th time. The vCPUs
+    /// start again next.
+    Reboot { reboots: u32 },
+    /// The VM stopped running, see [`ExitReason`]. The detail is the panic line, the
+    /// signal number, the watchdog action or the error message, if any.
+    Shutdown {
+        reason: &'static str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        detail: Option<String>,
+    },
+}
+
+impl Event {
+    /// The event for the result of [`VMM::run`](crate::VMM::run).
+    pub fn shutdown(result: &Result<ExitReason>) -> Self {
+        let (reason, detail) = match result {
+            Ok(ExitReason::GuestShutdown) => ("guest-shutdown", None),
+            Ok(ExitReason::GuestReset) => ("guest-reset", None),
+            Ok(ExitReason::GuestPanic(report)) => {
+                let detail = match report {
+                    PanicReport::Console(line) => line.clone(),
+                    PanicReport::Pvpanic(PvpanicEvent::Panicked) => "pvpanic".to_string(),
+                    PanicReport::Pvpanic(PvpanicEvent::CrashLoaded) => {
+                        "pvpanic, crash kernel loaded".to_string()
+                    }
+                    PanicReport::SystemEvent => "crash system event".to_string(),
+                };
+                ("guest-panic", Some(detail))
+            }
+            Ok(ExitReason::Timeout) => ("timeout", None),
+            Ok(ExitReason::Signal(signal)) => ("signal", Some(signal.to_string())),
+            Ok(ExitReason::WatchdogExpired(action)) => {
+                let action = match action {
+                    WatchdogAction::Poweroff => "poweroff",
+                    WatchdogAction::Reset => "reset",
+                };
+                ("watchdog", Some(action.to_string()))
+            }
+            Ok(ExitReason::VcpuError(message)) => ("vcpu-error", Some(message.clone())),
+            Ok(ExitReason::ConsoleError(message)) => ("console-error", Some(message.clone())),
+            Ok(ExitReason::Paused) => ("paused", None),
+            Err(e) => ("error", Some(e.to_string())),
+        };
+
+        Event::Shutdown { reason, detail }
+    }
+}
+
+// An event, as written.
+#[derive(Serialize)]
+struct Record<'a> {
+    timestamp_us: u64,
+    #[serde(flatten)]
+    event: &'a Event,
+}
+
+impl Record<'_> {
+    fn line(&self) -> Vec<u8> {
+        // Serializing plain structs cannot fail.
+        let mut line = serde_json::to_vec(self).unwrap();
+        line.push(b'\n');
+        line
+    }
+}
+
+// CLOCK_MONOTONIC, in microseconds: the readers can compare it with their own.
+fn monotonic_us() -> u64 {
+    let mut now = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // Safe because `now` is a valid timespec, and the clock always exists.
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut now) };
+
+    now.tv_sec as u64 * 1_000_000 + now.tv_nsec as u64 / 1_000
+}
+
+/// Writes the events to a FIFO, or any file, without ever blocking.
+///
+/// A FIFO is opened for reading as well, so that the writes never fail for want of a
+/// reader: the events wait in the pipe for the next one. What does not fit in the pipe
+/// is dropped, a stuck reader cannot hold the VMM back.
+#[derive(Clone)]
+pub(crate) struct EventSink(Arc<EventFile>);
+
+struct EventFile {
+    file: File,
+    dropped: AtomicU64,
+}
+
+impl EventSink {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(path)?;
+
+        Ok(EventSink(Arc::new(EventFile {
+            file,
+            dropped: AtomicU64::new(0),
+        })))
+    }
+
+    pub fn emit(&self, event: Event) {
+        let line = Record {
+            timestamp_us: monotonic_us(),
+            event: &event,
+        }
+        .line();
+
+        // The lines are shorter than PIPE_BUF, they go to the pipe whole or not at all.
+        match (&self.0.file).write(&line) {
+            Ok(written) if written == line.len() => (),
+            _ => {
+                self.0.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Number of events that could not be written.
+    pub fn dropped(&self) -> u64 {
+        self.0.dropped.load(Ordering::Relaxed)
+    }
+}
+
+/// Output sink emitting [`Event::GuestConsoleActive`] on the first write of the guest.
+pub(crate) struct ActivityWriter {
+    inner: Box<dyn Write + Send>,
+    events: Option<EventSink>,
+}
+
+impl ActivityWriter {
+    pub fn new(inner: Box<dyn Write + Send>, events: EventSink) -> Self {
+        ActivityWriter {
+            inner,
+            events: Some(events),
+        }
+    }
+}
+
+impl Write for ActivityWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !buf.is_empty() {
+            if let Some(events) = self.events.take() {
+                events.emit(Event::GuestConsoleActive);
+            }
+        }
+
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::ffi::CString;
+    use std::io::Read;
+    use std::os::unix::ffi::OsStrExt;
+
+    use vmm_sys_util::tempdir::TempDir;
+
+    use crate::Error;
+
+    #[test]
+    fn golden() {
+        let events = [
+            Event::Configured {
+                configure_us: 9120,
+                memory_configure_us: 412,
+                kernel_load_us: 6530,
+                vcpu_configure_us: 1830,
+                memory_advice: vec!["mergeable"],
+            },
+            Event::VcpusStarted { vcpus: 2 },
+            Event::GuestConsoleActive,
+            Event::AgentReady,
+            Event::Reboot { reboots: 1 },
+            Event::shutdown(&Ok(ExitReason::GuestShutdown)),
+            Event::shutdown(&Ok(ExitReason::GuestPanic(PanicReport::Console(
+                "Kernel panic - not syncing: \"init\" died".to_string(),
+            )))),
+            Event::shutdown(&Ok(ExitReason::Signal(15))),
+            Event::shutdown(&Ok(ExitReason::WatchdogExpired(WatchdogAction::Reset))),
+            Event::shutdown(&Err(Error::NotClonable("it is not paused"))),
+        ];
+
+        let lines: Vec<u8> = events
+            .iter()
+            .enumerate()
+            .flat_map(|(index, event)| {
+                Record {
+                    timestamp_us: 1_000_000 + index as u64,
+                    event,
+                }
+                .line()
+            })
+            .collect();
+        assert_eq!(
+            String::from_utf8(lines).unwrap(),
+            include_str!("fixtures/events.jsonl")
+        );
+    }
+
+    #[test]
+    fn fifo() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.as_path().join("events");
+        let c_path = CString::new(path.as_os_str().as_bytes()).unwrap();
+        // Safe because the path is a valid C string, and we check the result.
+        assert_eq!(unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) }, 0);
+
+        // Nobody reads yet.
+        let sink = EventSink::open(&path).unwrap();
+        let start = monotonic_us();
+        sink.emit(Event::Configured {
+            configure_us: 0,
+            memory_configure_us: 0,
+            kernel_load_us: 0,
+            vcpu_configure_us: 0,
+            memory_advice: Vec::new(),
+        });
+        let mut reader = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(&path)
+            .unwrap();
+        sink.emit(Event::VcpusStarted { vcpus: 1 });
+
+        let mut output = String::new();
+        let _ = reader.read_to_string(&mut output);
+        let lines: Vec<serde_json::Value> = output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["event"], "configured");
+        assert_eq!(lines[1]["event"], "vcpus-started");
+        assert_eq!(lines[1]["vcpus"], 1);
+        let timestamp = lines[0]["timestamp_us"].as_u64().unwrap();
+        assert!(start <= timestamp && timestamp <= monotonic_us());
+        assert_eq!(sink.dropped(), 0);
+
+        // Once the pipe is full, the events are dropped rather than waited for.
+        drop(reader);
+        for _ in 0..1_000_000 {
+            sink.emit(Event::AgentReady);
+            if sink.dropped() > 0 {
+                break;
+            }
+        }
+        assert_eq!(sink.dropped(), 1);
+    }
+}
@@ -0,0 +1,112 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Firmware blob, e.g. a verification stub for measured boot experiments, mapped read-only
+//! into the MMIO gap before the kernel starts.
+//!
+//! The blob gets its own KVM memory slot, reserved in the E820 map. With an entry, the boot
+//! vCPU starts in the blob, see `Vcpu::configure_firmware_entry`.
+
+use std::fs;
+use std::io;
+use std::ptr;
+
+use vm_memory::GuestAddress;
+
+use crate::config::FirmwareConfig;
+use crate::layout;
+
+// KVM maps whole pages.
+const PAGE_SIZE: u64 = 0x1000;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// Failed to read the blob.
+    #[error("failed to read the firmware")]
+    Read(#[source] io::Error),
+    /// The blob is empty.
+    #[error("the firmware is empty")]
+    Empty,
+    /// The blob would overlap the RAM or the device registers.
+    #[error(
+        "the firmware at {address:#x} ({size} bytes) must fit in the MMIO gap, out of the RAM and the device registers"
+    )]
+    Placement { address: u64, size: u64 },
+    /// Failed to map the memory holding the blob.
+    #[error("failed to map the firmware")]
+    Mmap(#[source] io::Error),
+}
+
+/// A firmware blob, copied to host memory the guest maps read-only.
+pub(crate) struct Firmware {
+    addr: *mut libc::c_void,
+    size: usize,
+    address: u64,
+    entry: bool,
+}
+
+// Safe because the mapping is owned by the firmware, the guest is the only one accessing
+// the memory behind it.
+unsafe impl Send for Firmware {}
+
+impl Firmware {
+    pub fn load(config: &FirmwareConfig) -> Result<Self, Error> {
+        let blob = fs::read(&config.path).map_err(Error::Read)?;
+        if blob.is_empty() {
+            return Err(Error::Empty);
+        }
+        let size = (blob.len() as u64 + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+        if !layout::firmware_fits(config.address, size) {
+            return Err(Error::Placement {
+                address: config.address,
+                size,
+            });
+        }
+
+        // Safe because we do not map over any existing memory, and check the result.
+        let addr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                size as usize,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(Error::Mmap(io::Error::last_os_error()));
+        }
+        // Safe because the mapping is at least as large as the blob. The rest of the last
+        // page stays zeroed.
+        unsafe { ptr::copy_nonoverlapping(blob.as_ptr(), addr as *mut u8, blob.len()) };
+
+        Ok(Firmware {
+            addr,
+            size: size as usize,
+            address: config.address,
+            entry: config.entry,
+        })
+    }
+
+    /// Guest physical range of the blob, as (address, size), padded to whole pages.
+    pub fn range(&self) -> (u64, u64) {
+        (self.address, self.size as u64)
+    }
+
+    /// Host address of the mapping, to be registered as a KVM memory slot.
+    pub fn host_address(&self) -> u64 {
+        self.addr as u64
+    }
+
+    /// Where the boot vCPU starts, if in the blob.
+    pub fn entry(&self) -> Option<GuestAddress> {
+        self.entry.then_some(GuestAddress(self.address))
+    }
+}
+
+impl Drop for Firmware {
+    fn drop(&mut self) {
+        // Safe because we own the mapping.
+        unsafe { libc::munmap(self.addr, self.size) };
+    }
+}
@@ -0,0 +1,280 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Cloning a paused VM, to serve requests from a guest booted ahead of time.
+
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::FromRawFd;
+use std::sync::Arc;
+
+use kvm_bindings::{
+    kvm_irqchip, KVM_IRQCHIP_IOAPIC, KVM_IRQCHIP_PIC_MASTER, KVM_IRQCHIP_PIC_SLAVE,
+};
+use vm_memory::{Bytes, GuestMemory, GuestMemoryMmap, GuestMemoryRegion, MemoryRegionAddress};
+
+use crate::config::{ConsoleMode, IrqchipMode, VMMConfig};
+use crate::cpu::Vcpu;
+use crate::devices::serial::{SerialStats, COM1};
+use crate::{map_private, Error, Result, VmmState, VMM};
+
+// The copy of the RAM leaves holes for the pages of zeroes.
+const PAGE_SIZE: u64 = 4096;
+
+impl VMM {
+    /// Start a new VM from where this one was paused, with
+    /// [`ExitReason::Paused`](crate::ExitReason::Paused), or from where it was configured
+    /// if it did not run yet.
+    ///
+    /// A template VM boots up to a checkpoint, e.g. its agent telling it is ready, and a
+    /// [`PauseTrigger`](crate::PauseTrigger) stops it there. The clones then skip the boot:
+    ///
+    /// - the first clone copies the RAM of the template to a memfd, which all the clones
+    ///   map privately: they share the pages until they write to them,
+    /// - the vCPUs, the interrupt controllers and the KVM clock start from the state of the
+    ///   template,
+    /// - so does the console, pending input included.
+    ///
+    /// The template stays paused, and can be cloned again. Running it again drops the copy
+    /// of its RAM.
+    ///
+    /// Only part of the VM state is copied, cloning fails with [`Error::NotClonable`]
+    /// unless the template:
+    ///
+    /// - has no virtio-net, virtio-pmem, virtio-9p or watchdog device, no firmware, no second
+    ///   serial port and no console input file. The network devices are plugged into the
+    ///   slots of each clone instead, which must all be empty in the template. Without a MAC
+    ///   address, the guest driver picks a random one.
+    /// - has no console file, which the clones would truncate. A Unix socket console gets a
+    ///   new connection per clone.
+    ///
+    /// The clones have neither the API socket, nor the console sink or the event FIFO of
+    /// the template. The RTC and ACPI power management registers start over from their
+    /// power-on values. They stop when their guest reboots, even if the template restarts.
+    pub fn clone_from_paused(&self) -> Result<VMM> {
+        let config = self.check_clonable()?;
+        let memory = self.memory_for_clones()?;
+
+        let mut clone = VMM::new()?;
+        clone.config = Some(config.clone());
+        if !self.stdin_attached {
+            clone.detach_stdin()?;
+        }
+        clone.set_timeout(config.timeout);
+
+        let stats = Arc::new(SerialStats::default());
+        let output = clone.console_output(
+            &config.console,
+            config.panic_detect,
+            config.console_error_policy,
+            stats.clone(),
+        )?;
+        let serial = self
+            .serial
+            .lock()
+            .unwrap()
+            .clone_with_output(output, stats)
+            .map_err(Error::SerialCreation)?;
+        clone
+            .irqfds
+            .insert(COM1.irq, serial.eventfd().map_err(Error::IrqRegister)?);
+        *clone.serial.lock().unwrap() = serial;
+
+        let regions: Vec<_> = self
+            .guest_memory
+            .iter()
+            .map(|region| (region.start_addr(), region.len() as usize))
+            .collect();
+        clone.set_dirty_tracking(self.dirty_tracking);
+        clone.register_memory(map_private(&regions, memory)?)?;
+
+        // Set up in the same order, the devices get the same addresses and interrupts.
+        clone.configure_net_slots(config.net_slots)?;
+        clone.configure_io(IrqchipMode::Full)?;
+        clone.register_acpi_devices()?;
+        for chip_id in [
+            KVM_IRQCHIP_PIC_MASTER,
+            KVM_IRQCHIP_PIC_SLAVE,
+            KVM_IRQCHIP_IOAPIC,
+        ] {
+            let mut irqchip = kvm_irqchip {
+                chip_id,
+                ..Default::default()
+            };
+            self.vm_fd
+                .get_irqchip(&mut irqchip)
+                .map_err(Error::KvmIoctl)?;
+            clone.vm_fd.set_irqchip(&irqchip).map_err(Error::KvmIoctl)?;
+        }
+        if let Some(mut clock) = self.paused_clock {
+            // From the time of the pause, not from the current one.
+            clock.flags = 0;
+            clone.vm_fd.set_clock(&clock).map_err(Error::KvmIoctl)?;
+        }
+
+        let msr_list = self.kvm.get_msr_index_list().map_err(Error::KvmIoctl)?;
+        let mut states = Vec::with_capacity(self.vcpus.len());
+        for vcpu in self.vcpus.iter() {
+            states.push(vcpu.save_state(msr_list.as_slice()).map_err(Error::Vcpu)?);
+            let vcpu = Vcpu::new(
+                &clone.vm_fd,
+                vcpu.index,
+                config.topology.apic_id(vcpu.index as u8).into(),
+                clone.io_manager.clone(),
+                clone.unknown_ports.clone(),
+            )
+            .map_err(Error::Vcpu)?;
+            clone.vcpus.push(vcpu);
+        }
+        clone.state = VmmState::Ready;
+        // The TSC itself is restored with the MSRs.
+        clone.configure_tsc(config.tsc_khz)?;
+        for (vcpu, state) in clone.vcpus.iter().zip(states.iter()) {
+            vcpu.restore_state(state).map_err(Error::Vcpu)?;
+        }
+
+        Ok(clone)
+    }
+
+    // The configuration of the template, unless it has state the clones would not get.
+    fn check_clonable(&self) -> Result<&VMMConfig> {
+        let config = self
+            .config
+            .as_ref()
+            .ok_or(Error::NotClonable("it was not set up from a configuration"))?;
+
+        let reason = if self.vcpus.is_empty() {
+            "it is not paused"
+        } else if config.net.is_some() {
+            "virtio-net devices cannot be cloned, plug one into each clone instead"
+        } else if config.pmem.is_some() {
+            "virtio-pmem devices cannot be cloned"
+        } else if config.firmware.is_some() {
+            "the firmware cannot be cloned"
+        } else if config.shared_dir.is_some() {
+            "shared directories cannot be cloned"
+        } else if config.watchdog.is_some() {
+            "the watchdog cannot be cloned"
+        } else if config.irqchip == IrqchipMode::Split {
+            "the split irqchip cannot be cloned"
+        } else if config.max_cpus > config.cpus {
+            "the hot-pluggable vCPUs cannot be cloned"
+        } else if config.gdb.is_some() {
+            "the GDB stub cannot be shared with the clones"
+        } else if config.serial2.is_some() {
+            "the second serial port cannot be cloned"
+        } else if config.console_input.is_some() {
+            "the console input cannot be shared with the clones"
+        } else if config.console_record.is_some() || config.console_replay.is_some() {
+            "the console session cannot be shared with the clones"
+        } else if matches!(config.console, ConsoleMode::File(_)) {
+            "the console file cannot be shared with the clones"
+        } else if self
+            .net_slots
+            .iter()
+            .any(|slot| !slot.lock().unwrap().is_empty())
+        {
+            "virtio-net devices cannot be cloned, unplug them first"
+        } else {
+            return Ok(config);
+        };
+
+        Err(Error::NotClonable(reason))
+    }
+
+    // The copy of the RAM the clones map, made for the first one.
+    fn memory_for_clones(&self) -> Result<Arc<File>> {
+        let mut memory = self.clone_memory.lock().unwrap();
+        if let Some(file) = memory.as_ref() {
+            return Ok(file.clone());
+        }
+
+        let file = Arc::new(copy_memory(&self.guest_memory).map_err(Error::CloneMemory)?);
+        *memory = Some(file.clone());
+        Ok(file)
+    }
+}
+
+// Copy the guest RAM to a memfd, one region after the other. The pages of zeroes are left
+// as holes, which take no memory.
+fn copy_memory(guest_memory: &GuestMemoryMmap) -> io::Result<File> {
+    // Safe because the name is a valid C string, and we check the result.
+    let fd = unsafe { libc::memfd_create(c"lumper-template".as_ptr(), libc::MFD_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Safe because we own the new descriptor.
+    let file = unsafe { File::from_raw_fd(fd) };
+    file.set_len(guest_memory.iter().map(|region| region.len()).sum())?;
+
+    let mut page = [0u8; PAGE_SIZE as usize];
+    let mut offset = 0;
+    for region in guest_memory.iter() {
+        for page_offset in (0..region.len()).step_by(PAGE_SIZE as usize) {
+            region
+                .read_slice(&mut page, MemoryRegionAddress(page_offset))
+                .map_err(io::Error::other)?;
+            if page.iter().any(|byte| *byte != 0) {
+                file.write_all_at(&page, offset + page_offset)?;
+            }
+        }
+        offset += region.len();
+    }
+
+    Ok(file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::fs::MetadataExt;
+
+    use vm_memory::GuestAddress;
+
+    #[test]
+    fn clone_memory() {
+        let regions = [(GuestAddress(0), 0x10000), (GuestAddress(0x100000), 0x8000)];
+        let template = GuestMemoryMmap::from_ranges(&regions).unwrap();
+        template
+            .write_slice(b"template", GuestAddress(0x1000))
+            .unwrap();
+        template
+            .write_slice(b"end", GuestAddress(0x108000 - 3))
+            .unwrap();
+
+        let memory = Arc::new(copy_memory(&template).unwrap());
+        let metadata = memory.metadata().unwrap();
+        assert_eq!(metadata.len(), 0x18000);
+        // Only the two pages written to take memory, in 512 bytes blocks.
+        assert_eq!(metadata.blocks(), 2 * PAGE_SIZE / 512);
+
+        let clones: Vec<_> = (0..2)
+            .map(|_| map_private(&regions, memory.clone()).unwrap())
+            .collect();
+        for (index, clone) in clones.iter().enumerate() {
+            let mut data = [0u8; 8];
+            clone.read_slice(&mut data, GuestAddress(0x1000)).unwrap();
+            assert_eq!(&data, b"template");
+            let mut end = [0u8; 3];
+            clone
+                .read_slice(&mut end, GuestAddress(0x108000 - 3))
+                .unwrap();
+            assert_eq!(&end, b"end");
+
+            clone
+                .write_slice(format!("vm{}", index).as_bytes(), GuestAddress(0x1000))
+                .unwrap();
+        }
+
+        // The clones write to their own copies of the pages.
+        for (index, clone) in clones.iter().enumerate() {
+            let mut data = [0u8; 8];
+            clone.read_slice(&mut data, GuestAddress(0x1000)).unwrap();
+            assert_eq!(&data, format!("vm{}plate", index).as_bytes());
+        }
+        let mut data = [0u8; 8];
+        memory.read_exact_at(&mut data, 0x1000).unwrap();
+        assert_eq!(&data, b"template");
+    }
+}
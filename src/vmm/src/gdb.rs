@@ -0,0 +1,565 @@
+// SPDX-License-Identifier: Apache-2.0
+
+#![cfg(target_arch = "x86_64")]
+
+//! GDB remote serial protocol stub, debugging the guest kernel through the KVM guest debug.
+//!
+//! The stub serves a single client, which the boot vCPU waits for before running the guest.
+//! The vCPUs then stop on the software breakpoints the client sets, and after each single
+//! step: the vCPU stopping serves the client from its own thread, while the others are
+//! paused. The client cannot interrupt the running guest.
+//!
+//! The client sees the general purpose registers, and the memory at the virtual addresses
+//! the stopped vCPU maps. Before the guest kernel maps itself, the addresses of its text
+//! mapping are translated as it maps them, from the address it is loaded at: the kernel
+//! must not be relocated, e.g. by KASLR, nor decompress itself over the breakpoints.
+
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddrV4, TcpListener, TcpStream};
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+
+use kvm_bindings::{
+    kvm_debug_exit_arch, kvm_guest_debug, kvm_regs, kvm_sregs, KVM_GUESTDBG_ENABLE,
+    KVM_GUESTDBG_SINGLESTEP, KVM_GUESTDBG_USE_SW_BP,
+};
+use kvm_ioctls::VcpuFd;
+use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
+
+use crate::cpu::{VcpuHandle, VcpuRunState};
+use crate::ExitReason;
+
+// The exception of the debug exits on the software breakpoints.
+const BP_VECTOR: u32 = 3;
+const INT3: u8 = 0xcc;
+// The signal the stop replies report, for the breakpoints and the single steps.
+const SIGTRAP: u8 = 5;
+// Where the kernel maps its text, at the physical address it is loaded at.
+const KERNEL_TEXT_MAP: u64 = 0xffff_ffff_8000_0000;
+const PAGE_SIZE: u64 = 0x1000;
+// The largest packet the client sends, in bytes.
+const PACKET_SIZE: usize = 0x4000;
+// The reply to the requests failing, e.g. on memory the vCPU does not map.
+const ERROR: &str = "E01";
+
+// The 64-bit registers at the start of the amd64 `g` packet, in order.
+fn registers(regs: &mut kvm_regs) -> [&mut u64; 17] {
+    [
+        &mut regs.rax,
+        &mut regs.rbx,
+        &mut regs.rcx,
+        &mut regs.rdx,
+        &mut regs.rsi,
+        &mut regs.rdi,
+        &mut regs.rbp,
+        &mut regs.rsp,
+        &mut regs.r8,
+        &mut regs.r9,
+        &mut regs.r10,
+        &mut regs.r11,
+        &mut regs.r12,
+        &mut regs.r13,
+        &mut regs.r14,
+        &mut regs.r15,
+        &mut regs.rip,
+    ]
+}
+
+// The registers of a `g` reply: the 64-bit ones, eflags and the segment selectors. The
+// client takes the floating point and vector registers after them as unavailable.
+fn encode_registers(regs: &kvm_regs, sregs: &kvm_sregs) -> String {
+    let mut regs = *regs;
+    let mut bytes = Vec::new();
+    for reg in registers(&mut regs) {
+        bytes.extend_from_slice(&reg.to_le_bytes());
+    }
+    bytes.extend_from_slice(&(regs.rflags as u32).to_le_bytes());
+    for segment in [sregs.cs, sregs.ss, sregs.ds, sregs.es, sregs.fs, sregs.gs] {
+        bytes.extend_from_slice(&u32::from(segment.selector).to_le_bytes());
+    }
+    to_hex(&bytes)
+}
+
+// Set the 64-bit registers and eflags from a `G` request, which has all the registers of
+// the client. The segment selectors are left alone. Returns None when it is too short.
+fn decode_registers(hex: &str, regs: &mut kvm_regs) -> Option<()> {
+    let bytes = from_hex(hex.get(..2 * (17 * 8 + 4))?)?;
+    let (values, rflags) = bytes.split_at(17 * 8);
+    for (reg, value) in registers(regs).into_iter().zip(values.chunks_exact(8)) {
+        *reg = u64::from_le_bytes(value.try_into().unwrap());
+    }
+    regs.rflags = u32::from_le_bytes(rflags.try_into().unwrap()).into();
+    Some(())
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    let pairs = hex.as_bytes().chunks_exact(2);
+    if !pairs.remainder().is_empty() || !hex.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return None;
+    }
+    pairs
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+        .collect()
+}
+
+// The address and the length of `<address>,<length>`, in hexadecimal.
+fn parse_range(range: &str) -> Option<(u64, usize)> {
+    let (addr, len) = range.split_once(',')?;
+    let addr = u64::from_str_radix(addr, 16).ok()?;
+    let len = usize::from_str_radix(len, 16).ok()?;
+    Some((addr, len))
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+}
+
+// Frame `data` as a `$<data>#<checksum>` packet.
+fn packet(data: &str) -> Vec<u8> {
+    format!("${}#{:02x}", data, checksum(data.as_bytes())).into_bytes()
+}
+
+// Read the next packet, skipping the acknowledgements and the interrupts before it.
+// Returns None when its checksum does not match.
+fn read_packet(reader: &mut impl BufRead) -> io::Result<Option<Vec<u8>>> {
+    let mut byte = [0u8];
+    while byte[0] != b'$' {
+        reader.read_exact(&mut byte)?;
+    }
+
+    let mut data = Vec::new();
+    reader.read_until(b'#', &mut data)?;
+    if data.pop() != Some(b'#') {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    let mut sum = [0u8; 2];
+    reader.read_exact(&mut sum)?;
+
+    let valid = from_hex(&String::from_utf8_lossy(&sum)) == Some(vec![checksum(&data)]);
+    Ok(valid.then_some(data))
+}
+
+// The connection to the client.
+struct Client {
+    reader: BufReader<TcpStream>,
+}
+
+impl Client {
+    // Read the next request, asking the client to send it again while it is corrupted.
+    fn receive(&mut self) -> io::Result<String> {
+        loop {
+            let packet = read_packet(&mut self.reader)?;
+            let ack: &[u8] = if packet.is_some() { b"+" } else { b"-" };
+            self.reader.get_mut().write_all(ack)?;
+            if let Some(data) = packet {
+                return Ok(String::from_utf8_lossy(&data).into_owned());
+            }
+        }
+    }
+
+    // Send a reply. TCP does not corrupt it, its acknowledgement is skipped along with the
+    // next request.
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        self.reader.get_mut().write_all(&packet(data))
+    }
+}
+
+// How a stopped vCPU gets back to the guest, as the client asks.
+#[derive(Debug, PartialEq, Eq)]
+enum Resume {
+    Continue,
+    Step,
+    // Stop the VM.
+    Kill,
+}
+
+// What becomes of a request.
+enum Action {
+    Reply(String),
+    Resume(Resume),
+    // The client leaves, the VM runs on without it.
+    Detach,
+}
+
+// Shared by the vCPUs, which stop one at a time.
+#[derive(Default)]
+struct Session {
+    client: Option<Client>,
+    // Whether the boot vCPU waited for the client.
+    attached: bool,
+    // The guest physical address and the original byte of the software breakpoints, by
+    // guest virtual address.
+    breakpoints: BTreeMap<u64, (u64, u8)>,
+    // The run states of the vCPUs, by index, paused while one of them is stopped.
+    vcpus: Vec<Arc<VcpuHandle>>,
+}
+
+/// Serves a GDB client, see the [module documentation](self).
+pub(crate) struct GdbStub {
+    listener: TcpListener,
+    guest_memory: GuestMemoryMmap,
+    session: Mutex<Session>,
+}
+
+impl GdbStub {
+    /// Listen for a client on `addr`.
+    pub fn new(addr: SocketAddrV4, guest_memory: GuestMemoryMmap) -> io::Result<Self> {
+        Ok(GdbStub {
+            listener: TcpListener::bind(addr)?,
+            guest_memory,
+            session: Mutex::new(Session::default()),
+        })
+    }
+
+    /// Set the handles of the vCPUs, by index, before they run.
+    pub fn set_vcpus(&self, vcpus: Vec<Arc<VcpuHandle>>) {
+        self.session.lock().unwrap().vcpus = vcpus;
+    }
+
+    /// Wait for the client before the boot vCPU first runs, and stop it there for the
+    /// client. Returns why the VM must stop, if the client killed it.
+    pub fn attach(&self, index: u64, vcpu: &VcpuFd) -> Option<ExitReason> {
+        let mut session = self.session.lock().unwrap();
+        if index != 0 || session.attached {
+            return None;
+        }
+        session.attached = true;
+
+        if let Ok(addr) = self.listener.local_addr() {
+            println!("Waiting for GDB on {}", addr);
+        }
+        match self.listener.accept() {
+            Ok((stream, _)) => {
+                session.client = Some(Client {
+                    reader: BufReader::new(stream),
+                })
+            }
+            Err(e) => {
+                eprintln!("Failed to accept the GDB client: {}", e);
+                return None;
+            }
+        }
+        // The client asks why the vCPU stopped.
+        self.stop(&mut session, index, vcpu, false)
+    }
+
+    /// Handle a debug exit of the vCPU `index`: stop it for the client on its breakpoints
+    /// and single steps, and give the guest its own breakpoints. Returns why the VM must
+    /// stop, if the client killed it.
+    pub fn debug_exit(
+        &self,
+        index: u64,
+        vcpu: &VcpuFd,
+        debug: kvm_debug_exit_arch,
+    ) -> Option<ExitReason> {
+        let mut session = self.session.lock().unwrap();
+        if debug.exception == BP_VECTOR && !session.breakpoints.contains_key(&debug.pc) {
+            // The int3 of the guest, e.g. patching its text, or a breakpoint the client
+            // removed while the vCPU waited for the session, which it then runs again.
+            if self.read_memory(vcpu, debug.pc, 1) == Some(vec![INT3]) {
+                if let Err(e) = inject_breakpoint(vcpu) {
+                    eprintln!("vCPU {} failed to inject a breakpoint: {}", index, e);
+                }
+            }
+            return None;
+        }
+        if session.client.is_none() {
+            return None;
+        }
+
+        self.stop(&mut session, index, vcpu, true)
+    }
+
+    // Pause the other vCPUs, and serve the client until it resumes the vCPU `index`.
+    fn stop(
+        &self,
+        session: &mut Session,
+        index: u64,
+        vcpu: &VcpuFd,
+        report: bool,
+    ) -> Option<ExitReason> {
+        let others: Vec<_> = session
+            .vcpus
+            .iter()
+            .enumerate()
+            .filter(|(other, _)| *other as u64 != index)
+            .map(|(_, handle)| handle.clone())
+            .collect();
+        for handle in others.iter() {
+            handle.set_state(VcpuRunState::Paused);
+        }
+
+        let resume = self.serve(session, vcpu, report).unwrap_or_else(|e| {
+            eprintln!("Lost the GDB client: {}", e);
+            self.detach(session);
+            Resume::Continue
+        });
+        // The VMM stops the paused vCPUs.
+        if resume == Resume::Kill {
+            return Some(ExitReason::GuestShutdown);
+        }
+        if let Err(e) = set_guest_debug(vcpu, resume == Resume::Step) {
+            return Some(ExitReason::VcpuError(format!(
+                "vCPU {} failed to set up the guest debug: {}",
+                index, e
+            )));
+        }
+
+        for handle in others.iter() {
+            handle.set_state(VcpuRunState::Running);
+        }
+        None
+    }
+
+    // Answer the client until it resumes the vCPU, telling it why it stopped with `report`.
+    fn serve(&self, session: &mut Session, vcpu: &VcpuFd, report: bool) -> io::Result<Resume> {
+        let client = match session.client.as_mut() {
+            Some(client) => client,
+            None => return Ok(Resume::Continue),
+        };
+        if report {
+            client.send(&format!("S{:02x}", SIGTRAP))?;
+        }
+
+        loop {
+            let request = client.receive()?;
+            match self.request(&request, vcpu, &mut session.breakpoints) {
+                Action::Reply(reply) => client.send(&reply)?,
+                Action::Resume(resume) => return Ok(resume),
+                Action::Detach => {
+                    client.send("OK")?;
+                    self.detach(session);
+                    return Ok(Resume::Continue);
+                }
+            }
+        }
+    }
+
+    // Answer a request of the client, with the vCPU stopped.
+    fn request(
+        &self,
+        request: &str,
+        vcpu: &VcpuFd,
+        breakpoints: &mut BTreeMap<u64, (u64, u8)>,
+    ) -> Action {
+        let reply = match request.as_bytes().first() {
+            Some(b'?') => format!("S{:02x}", SIGTRAP),
+            Some(b'g') => match (vcpu.get_regs(), vcpu.get_sregs()) {
+                (Ok(regs), Ok(sregs)) => encode_registers(&regs, &sregs),
+                _ => ERROR.to_string(),
+            },
+            Some(b'G') => {
+                let set = vcpu.get_regs().ok().and_then(|mut regs| {
+                    decode_registers(&request[1..], &mut regs)?;
+                    vcpu.set_regs(&regs).ok()
+                });
+                status(set)
+            }
+            Some(b'm') => parse_range(&request[1..])
+                .filter(|(_, len)| *len <= PACKET_SIZE / 2)
+                .and_then(|(addr, len)| self.read_memory(vcpu, addr, len))
+                .map_or(ERROR.to_string(), |data| to_hex(&data)),
+            Some(b'M') => {
+                let written = request[1..].split_once(':').and_then(|(range, data)| {
+                    let (addr, len) = parse_range(range)?;
+                    let data = from_hex(data).filter(|data| data.len() == len)?;
+                    self.write_memory(vcpu, addr, &data)
+                });
+                status(written)
+            }
+            // Software breakpoints, the client emulates the other kinds when it can.
+            Some(b'Z') | Some(b'z') if request[1..].starts_with("0,") => {
+                let set = parse_range(&request[3..]).and_then(|(addr, _)| {
+                    self.set_breakpoint(vcpu, breakpoints, addr, request.starts_with('Z'))
+                });
+                status(set)
+            }
+            // The signals the client resumes with are not delivered to the guest.
+            Some(b'c') | Some(b'C') => return Action::Resume(Resume::Continue),
+            Some(b's') | Some(b'S') => return Action::Resume(Resume::Step),
+            Some(b'k') => return Action::Resume(Resume::Kill),
+            Some(b'D') => return Action::Detach,
+            // The vCPUs are not threads to the client, it only sees the stopped one.
+            Some(b'H') => "OK".to_string(),
+            _ if request.starts_with("qSupported") => format!("PacketSize={:x}", PACKET_SIZE),
+            _ if request == "qAttached" => "1".to_string(),
+            // Unsupported, the client falls back to the requests above.
+            _ => String::new(),
+        };
+        Action::Reply(reply)
+    }
+
+    // Read `len` bytes at the guest virtual address `addr`.
+    fn read_memory(&self, vcpu: &VcpuFd, addr: u64, len: usize) -> Option<Vec<u8>> {
+        let mut data = vec![0u8; len];
+        for (gpa, range) in translate_range(vcpu, addr, len)? {
+            self.guest_memory
+                .read_slice(&mut data[range], GuestAddress(gpa))
+                .ok()?;
+        }
+        Some(data)
+    }
+
+    // Write `data` at the guest virtual address `addr`.
+    fn write_memory(&self, vcpu: &VcpuFd, addr: u64, data: &[u8]) -> Option<()> {
+        for (gpa, range) in translate_range(vcpu, addr, data.len())? {
+            self.guest_memory
+                .write_slice(&data[range], GuestAddress(gpa))
+                .ok()?;
+        }
+        Some(())
+    }
+
+    // Insert, or remove, the software breakpoint at the guest virtual address `addr`.
+    fn set_breakpoint(
+        &self,
+        vcpu: &VcpuFd,
+        breakpoints: &mut BTreeMap<u64, (u64, u8)>,
+        addr: u64,
+        insert: bool,
+    ) -> Option<()> {
+        if insert && !breakpoints.contains_key(&addr) {
+            let gpa = GuestAddress(translate(vcpu, addr)?);
+            let original: u8 = self.guest_memory.read_obj(gpa).ok()?;
+            self.guest_memory.write_obj(INT3, gpa).ok()?;
+            breakpoints.insert(addr, (gpa.0, original));
+        } else if !insert {
+            if let Some((gpa, original)) = breakpoints.remove(&addr) {
+                self.guest_memory
+                    .write_obj(original, GuestAddress(gpa))
+                    .ok()?;
+            }
+        }
+        Some(())
+    }
+
+    // Forget the client, and restore the bytes its breakpoints replaced.
+    fn detach(&self, session: &mut Session) {
+        session.client = None;
+        for (gpa, original) in std::mem::take(&mut session.breakpoints).into_values() {
+            if let Err(e) = self.guest_memory.write_obj(original, GuestAddress(gpa)) {
+                eprintln!("Failed to remove a GDB breakpoint at {:#x}: {}", gpa, e);
+            }
+        }
+    }
+}
+
+// The reply to a request which sets something.
+fn status(done: Option<()>) -> String {
+    done.map_or(ERROR, |_| "OK").to_string()
+}
+
+// The guest physical address of `gva`, in the page tables of `vcpu`.
+fn translate(vcpu: &VcpuFd, gva: u64) -> Option<u64> {
+    match vcpu.translate_gva(gva) {
+        Ok(translation) if translation.valid != 0 => Some(translation.physical_address),
+        // The kernel text, before the kernel maps it.
+        _ if gva >= KERNEL_TEXT_MAP => Some(gva - KERNEL_TEXT_MAP),
+        _ => None,
+    }
+}
+
+// The guest physical addresses of the `len` bytes at `addr`, with the range of the bytes
+// in each page.
+fn translate_range(vcpu: &VcpuFd, addr: u64, len: usize) -> Option<Vec<(u64, Range<usize>)>> {
+    let mut pages = Vec::new();
+    let mut offset = 0;
+    while offset < len {
+        let gva = addr.checked_add(offset as u64)?;
+        let size = (PAGE_SIZE - gva % PAGE_SIZE).min((len - offset) as u64) as usize;
+        pages.push((translate(vcpu, gva)?, offset..offset + size));
+        offset += size;
+    }
+    Some(pages)
+}
+
+// Deliver the breakpoint exception of an int3 of the guest to the guest.
+fn inject_breakpoint(vcpu: &VcpuFd) -> kvm_ioctls::Result<()> {
+    let mut events = vcpu.get_vcpu_events()?;
+    events.exception.injected = 1;
+    events.exception.nr = BP_VECTOR as u8;
+    events.exception.has_error_code = 0;
+    vcpu.set_vcpu_events(&events)
+}
+
+/// Get the software breakpoints of `vcpu` to exit to the VMM, and with `step`, each of its
+/// instructions.
+pub(crate) fn set_guest_debug(vcpu: &VcpuFd, step: bool) -> kvm_ioctls::Result<()> {
+    let mut control = KVM_GUESTDBG_ENABLE | KVM_GUESTDBG_USE_SW_BP;
+    if step {
+        control |= KVM_GUESTDBG_SINGLESTEP;
+    }
+    vcpu.set_guest_debug(&kvm_guest_debug {
+        control,
+        ..Default::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packets() {
+        assert_eq!(packet("OK"), b"$OK#9a");
+        assert_eq!(packet(""), b"$#00");
+
+        let mut input: &[u8] = b"+\x03$g#67$m0,4#fd$m0,4#00";
+        assert_eq!(read_packet(&mut input).unwrap(), Some(b"g".to_vec()));
+        assert_eq!(read_packet(&mut input).unwrap(), Some(b"m0,4".to_vec()));
+        // A corrupted packet.
+        assert_eq!(read_packet(&mut input).unwrap(), None);
+        assert!(read_packet(&mut input).is_err());
+        assert!(read_packet(&mut &b"$g#6"[..]).is_err());
+    }
+
+    #[test]
+    fn registers_round_trip() {
+        let mut regs = kvm_regs {
+            rax: 1,
+            r15: 0xffff_ffff_8100_0000,
+            rip: 0xffff_ffff_8200_0000,
+            rflags: 0x246,
+            ..Default::default()
+        };
+        let mut sregs = kvm_sregs::default();
+        sregs.cs.selector = 0x10;
+
+        let hex = encode_registers(&regs, &sregs);
+        assert_eq!(hex.len(), 2 * (17 * 8 + 4 + 6 * 4));
+        assert!(hex.starts_with("0100000000000000"));
+        assert_eq!(&hex[2 * 17 * 8..2 * (17 * 8 + 4 + 4)], "4602000010000000");
+
+        // The client sends all its registers, and those it does not have as x's.
+        let decoded = hex.clone() + &"x".repeat(64);
+        let mut decoded_regs = kvm_regs::default();
+        decode_registers(&decoded, &mut decoded_regs).unwrap();
+        assert_eq!(
+            [decoded_regs.rax, decoded_regs.r15, decoded_regs.rip],
+            [regs.rax, regs.r15, regs.rip]
+        );
+        assert_eq!(decoded_regs.rflags, 0x246);
+
+        regs.rip = 0;
+        assert!(decode_registers(&hex[..100], &mut regs).is_none());
+        assert_eq!(regs.rip, 0);
+    }
+
+    #[test]
+    fn hex() {
+        assert_eq!(to_hex(&[0x00, 0xab, 0x12]), "00ab12");
+        assert_eq!(from_hex("00AB12"), Some(vec![0x00, 0xab, 0x12]));
+        assert_eq!(from_hex("0"), None);
+        assert_eq!(from_hex("+1"), None);
+        assert_eq!(
+            parse_range("ffffffff81000000,40"),
+            Some((0xffff_ffff_8100_0000, 0x40))
+        );
+        assert_eq!(parse_range("10"), None);
+    }
+}
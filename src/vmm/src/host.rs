@@ -0,0 +1,116 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Host resources the guest competes for.
+
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+/// A limit on the memory the VMM can use.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MemoryLimit {
+    /// Memory the host has available, MemAvailable in /proc/meminfo.
+    Available(u64),
+    /// memory.max of the cgroup of the VMM, with cgroup v2.
+    Cgroup(u64),
+}
+
+impl MemoryLimit {
+    /// The limit, in bytes.
+    pub fn bytes(self) -> u64 {
+        match self {
+            MemoryLimit::Available(bytes) | MemoryLimit::Cgroup(bytes) => bytes,
+        }
+    }
+}
+
+impl fmt::Display for MemoryLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MemoryLimit::Available(bytes) => {
+                write!(f, "the {} MiB available on the host", bytes >> 20)
+            }
+            MemoryLimit::Cgroup(bytes) => {
+                write!(f, "the {} MiB memory limit of the VMM cgroup", bytes >> 20)
+            }
+        }
+    }
+}
+
+/// The lowest limit on the memory of the VMM, if the host tells any.
+///
+/// The guest memory is only allocated as the guest touches it, going past the limit
+/// may still work for a while.
+pub fn memory_limit() -> Option<MemoryLimit> {
+    let available = fs::read_to_string("/proc/meminfo")
+        .ok()
+        .and_then(|meminfo| mem_available(&meminfo))
+        .map(MemoryLimit::Available);
+    let cgroup = cgroup_memory_max().map(MemoryLimit::Cgroup);
+
+    [available, cgroup]
+        .into_iter()
+        .flatten()
+        .min_by_key(|limit| limit.bytes())
+}
+
+fn cgroup_memory_max() -> Option<u64> {
+    let cgroups = fs::read_to_string("/proc/self/cgroup").ok()?;
+    let path = Path::new("/sys/fs/cgroup")
+        .join(unified_cgroup(&cgroups)?.trim_start_matches('/'))
+        .join("memory.max");
+
+    memory_max(&fs::read_to_string(path).ok()?)
+}
+
+// MemAvailable, in bytes, from the content of /proc/meminfo.
+fn mem_available(meminfo: &str) -> Option<u64> {
+    let kib = meminfo
+        .lines()
+        .find_map(|line| line.strip_prefix("MemAvailable:"))?
+        .trim()
+        .strip_suffix("kB")?
+        .trim_end()
+        .parse::<u64>()
+        .ok()?;
+
+    kib.checked_mul(1024)
+}
+
+// The cgroup v2 path of the process, from the content of /proc/self/cgroup.
+fn unified_cgroup(cgroups: &str) -> Option<&str> {
+    cgroups.lines().find_map(|line| line.strip_prefix("0::"))
+}
+
+// The limit in the content of a memory.max file, which is `max` without one.
+fn memory_max(content: &str) -> Option<u64> {
+    content.trim().parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn host_files() {
+        let meminfo = "MemTotal:       16303424 kB\nMemFree:         1183420 kB\nMemAvailable:    9467548 kB\n";
+        assert_eq!(mem_available(meminfo), Some(9_467_548 * 1024));
+        assert_eq!(mem_available("MemTotal:       16303424 kB\n"), None);
+
+        let cgroups =
+            "1:name=systemd:/user.slice\n0::/user.slice/user-1000.slice/session-2.scope\n";
+        assert_eq!(
+            unified_cgroup(cgroups),
+            Some("/user.slice/user-1000.slice/session-2.scope")
+        );
+        assert_eq!(unified_cgroup("1:memory:/docker/abc\n"), None);
+
+        assert_eq!(memory_max("1073741824\n"), Some(1 << 30));
+        assert_eq!(memory_max("max\n"), None);
+
+        assert_eq!(
+            MemoryLimit::Cgroup(1 << 30).to_string(),
+            "the 1024 MiB memory limit of the VMM cgroup"
+        );
+    }
+}
@@ -0,0 +1,304 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use std::io::{self, Read, Seek, Write};
+
+use flate2::read::MultiGzDecoder;
+use serde::Serialize;
+use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
+
+use crate::config::ImageFile;
+use crate::{Error, Result};
+
+// First bytes of a gzip member.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Sequential writes to the guest memory, from an address up to a limit.
+struct GuestMemoryWriter<'a> {
+    guest_memory: &'a GuestMemoryMmap,
+    address: u64,
+    limit: u64,
+    // Set once a write hit the limit.
+    full: bool,
+}
+
+impl<'a> GuestMemoryWriter<'a> {
+    fn new(guest_memory: &'a GuestMemoryMmap, address: u64, limit: u64) -> Self {
+        GuestMemoryWriter {
+            guest_memory,
+            address,
+            limit,
+            full: false,
+        }
+    }
+}
+
+impl Write for GuestMemoryWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = std::cmp::min(buf.len() as u64, self.limit.saturating_sub(self.address)) as usize;
+        if len == 0 && !buf.is_empty() {
+            self.full = true;
+            return Err(io::Error::new(
+                io::ErrorKind::WriteZero,
+                "guest memory exhausted",
+            ));
+        }
+
+        self.guest_memory
+            .write_slice(&buf[..len], GuestAddress(self.address))
+            .map_err(io::Error::other)?;
+        self.address += len as u64;
+
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Where the initramfs goes in the guest memory.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct InitramfsImage {
+    pub address: u64,
+    /// Size in the guest memory, once inflated if `decompressed`.
+    pub size: u64,
+    /// Whether the VMM inflates it on the host.
+    pub decompressed: bool,
+}
+
+// Tell whether the initramfs is to be inflated on the host, and rewind it.
+fn is_gzip(file: &mut ImageFile, decompress: bool) -> Result<bool> {
+    let image = file.source().clone();
+    let open_error = |source: io::Error| Error::InitramfsOpen {
+        image: image.clone(),
+        source,
+    };
+    file.rewind().map_err(open_error)?;
+
+    let mut magic = [0u8; 2];
+    let gzip = decompress && file.read_exact(&mut magic).is_ok() && magic == GZIP_MAGIC;
+    file.rewind().map_err(open_error)?;
+
+    Ok(gzip)
+}
+
+/// Check that the initramfs fits at `address`, below `limit`, without loading it.
+///
+/// A gzip initramfs to decompress is inflated, and thrown away, to find its size.
+pub(crate) fn inspect(
+    file: &mut ImageFile,
+    address: u64,
+    limit: u64,
+    decompress: bool,
+) -> Result<InitramfsImage> {
+    let image = file.source().clone();
+    let gzip = is_gzip(file, decompress)?;
+
+    let size = if gzip {
+        io::copy(&mut MultiGzDecoder::new(&mut *file), &mut io::sink()).map_err(|source| {
+            Error::InitramfsDecompress {
+                image: image.clone(),
+                source,
+            }
+        })?
+    } else {
+        file.size().map_err(|source| Error::InitramfsOpen {
+            image: image.clone(),
+            source,
+        })?
+    };
+
+    if address.saturating_add(size) > limit {
+        return Err(if gzip {
+            Error::InitramfsTooLarge { image, limit }
+        } else {
+            Error::ImagesTooLarge
+        });
+    }
+
+    Ok(InitramfsImage {
+        address,
+        size,
+        decompressed: gzip,
+    })
+}
+
+/// Load the initramfs at `address`, below `limit`, and return its size in guest memory.
+///
+/// With `decompress`, a gzip initramfs is inflated on the host, streaming into the guest
+/// memory, so that the kernel finds a plain cpio archive and skips its own inflation.
+/// Other initramfs are loaded as they are.
+pub(crate) fn load(
+    guest_memory: &GuestMemoryMmap,
+    file: &mut ImageFile,
+    address: u64,
+    limit: u64,
+    decompress: bool,
+) -> Result<u64> {
+    let image = file.source().clone();
+    let gzip = is_gzip(file, decompress)?;
+
+    if gzip {
+        let mut writer = GuestMemoryWriter::new(guest_memory, address, limit);
+        // The kernel accepts concatenated archives, e.g. with early microcode updates.
+        return match io::copy(&mut MultiGzDecoder::new(&mut *file), &mut writer) {
+            Ok(size) => Ok(size),
+            Err(_) if writer.full => Err(Error::InitramfsTooLarge { image, limit }),
+            Err(source) => Err(Error::InitramfsDecompress { image, source }),
+        };
+    }
+
+    let size = file.size().map_err(|source| Error::InitramfsOpen {
+        image: image.clone(),
+        source,
+    })?;
+    if address + size > limit {
+        return Err(Error::ImagesTooLarge);
+    }
+    guest_memory
+        .read_from(GuestAddress(address), file, size as usize)
+        .map_err(|source| Error::InitramfsLoad { image, source })?;
+
+    Ok(size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use vmm_sys_util::tempfile::TempFile;
+
+    use crate::config::ImageSource;
+
+    const SIZE: u64 = 0x10_0000;
+
+    // A cpio-looking payload, compressible but not uniform.
+    fn payload(len: usize) -> Vec<u8> {
+        b"070701".iter().copied().cycle().take(len).collect()
+    }
+
+    fn guest_memory() -> GuestMemoryMmap {
+        GuestMemoryMmap::from_ranges(&[(GuestAddress(0), SIZE as usize)]).unwrap()
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn initramfs(content: &[u8]) -> TempFile {
+        let file = TempFile::new().unwrap();
+        file.as_file().write_all(content).unwrap();
+        file
+    }
+
+    fn open(file: &TempFile) -> ImageFile {
+        ImageSource::Path(file.as_path().into()).open().unwrap()
+    }
+
+    fn read(guest_memory: &GuestMemoryMmap, address: u64, len: usize) -> Vec<u8> {
+        let mut data = vec![0; len];
+        guest_memory
+            .read_slice(&mut data, GuestAddress(address))
+            .unwrap();
+        data
+    }
+
+    #[test]
+    fn gzip_initramfs() {
+        let guest_memory = guest_memory();
+        let data = payload(0x8_0000);
+        let compressed = gzip(&data);
+        let file = initramfs(&compressed);
+
+        // Decompressed, with its decompressed size.
+        let size = load(&guest_memory, &mut open(&file), 0x1000, SIZE, true).unwrap();
+        assert_eq!(size, data.len() as u64);
+        assert_eq!(read(&guest_memory, 0x1000, data.len()), data);
+
+        // Concatenated members make a single archive.
+        let file = initramfs(&[compressed.clone(), gzip(b"TRAILER!!!")].concat());
+        let size = load(&guest_memory, &mut open(&file), 0x1000, SIZE, true).unwrap();
+        assert_eq!(size, data.len() as u64 + 10);
+        assert_eq!(read(&guest_memory, 0x1000 + size - 10, 10), b"TRAILER!!!");
+
+        // Loaded as it is without decompression.
+        let file = initramfs(&compressed);
+        let size = load(&guest_memory, &mut open(&file), 0x1000, SIZE, false).unwrap();
+        assert_eq!(size, compressed.len() as u64);
+        assert_eq!(read(&guest_memory, 0x1000, compressed.len()), compressed);
+    }
+
+    #[test]
+    fn plain_initramfs() {
+        let guest_memory = guest_memory();
+        let data = payload(0x1000);
+        let mut file = open(&initramfs(&data));
+
+        let size = load(&guest_memory, &mut file, 0x1000, SIZE, true).unwrap();
+        assert_eq!(size, data.len() as u64);
+        assert_eq!(read(&guest_memory, 0x1000, data.len()), data);
+
+        // The same file loads again, from its start.
+        let size = load(&guest_memory, &mut file, 0x4000, SIZE, true).unwrap();
+        assert_eq!(read(&guest_memory, 0x4000, size as usize), data);
+
+        assert!(matches!(
+            load(&guest_memory, &mut file, SIZE - 0x800, SIZE, true),
+            Err(Error::ImagesTooLarge)
+        ));
+    }
+
+    #[test]
+    fn decompression_errors() {
+        let guest_memory = guest_memory();
+
+        // Larger than the memory left once inflated.
+        let file = initramfs(&gzip(&payload(SIZE as usize)));
+        assert!(matches!(
+            load(&guest_memory, &mut open(&file), 0x1000, SIZE, true),
+            Err(Error::InitramfsTooLarge { limit: SIZE, .. })
+        ));
+
+        let mut truncated = gzip(&payload(0x1000));
+        truncated.truncate(truncated.len() / 2);
+        let file = initramfs(&truncated);
+        assert!(matches!(
+            load(&guest_memory, &mut open(&file), 0x1000, SIZE, true),
+            Err(Error::InitramfsDecompress { .. })
+        ));
+    }
+
+    #[test]
+    fn inspect_initramfs() {
+        let data = payload(0x8_0000);
+        let compressed = gzip(&data);
+        let file = initramfs(&compressed);
+
+        // Sized as it would be loaded, nothing goes to the guest memory.
+        assert_eq!(
+            inspect(&mut open(&file), 0x1000, SIZE, true).unwrap(),
+            InitramfsImage {
+                address: 0x1000,
+                size: data.len() as u64,
+                decompressed: true,
+            }
+        );
+        assert_eq!(
+            inspect(&mut open(&file), 0x1000, SIZE, false).unwrap().size,
+            compressed.len() as u64
+        );
+
+        assert!(matches!(
+            inspect(&mut open(&file), 0x1000, 0x1010, true),
+            Err(Error::InitramfsTooLarge { limit: 0x1010, .. })
+        ));
+        assert!(matches!(
+            inspect(&mut open(&file), 0x1000, 0x1010, false),
+            Err(Error::ImagesTooLarge)
+        ));
+    }
+}
@@ -0,0 +1,219 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Reader of the newc cpio archives the initramfs is made of.
+//!
+//! Each entry is a header of ASCII hexadecimal fields, followed by its NUL terminated name
+//! and its data, each padded to 4 bytes. The `TRAILER!!!` entry ends the archive. An
+//! initramfs may be several archives in a row, e.g. the early microcode updates and then
+//! the compressed root file system.
+
+use std::io::{self, Read};
+
+// Magic numbers of the newc header, without and with a checksum.
+const NEWC_MAGIC: &[u8; 6] = b"070701";
+const NEWC_CRC_MAGIC: &[u8; 6] = b"070702";
+const HEADER_SIZE: usize = 110;
+// Name of the entry ending the archive.
+const TRAILER: &[u8] = b"TRAILER!!!";
+// Longest name, with its NUL, as the kernel takes it.
+const PATH_MAX: usize = 4096;
+
+// File type bits of the mode.
+const S_IFMT: u32 = 0o170000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+
+/// An entry of an archive.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Entry {
+    /// Path of the entry, as in the archive.
+    pub name: String,
+    /// File type and permissions.
+    pub mode: u32,
+}
+
+impl Entry {
+    /// Whether the entry is a regular file.
+    pub fn is_file(&self) -> bool {
+        self.mode & S_IFMT == S_IFREG
+    }
+
+    /// Whether the entry is a symbolic link, its data being the target.
+    pub fn is_symlink(&self) -> bool {
+        self.mode & S_IFMT == S_IFLNK
+    }
+}
+
+/// Reads the entries of an archive, and the data of the current one. Once past the trailer,
+/// the reader is left right after it.
+pub struct CpioReader<R> {
+    reader: R,
+    // Bytes read since the archive start, which the padding is relative to.
+    offset: u64,
+    // Data of the current entry left to read.
+    left: u64,
+    // Whether the trailer was read.
+    done: bool,
+}
+
+impl<R: Read> CpioReader<R> {
+    /// Read the archive starting at the current position of `reader`.
+    pub fn new(reader: R) -> Self {
+        CpioReader {
+            reader,
+            offset: 0,
+            left: 0,
+            done: false,
+        }
+    }
+
+    /// The next entry, skipping the data left of the current one. None past the trailer.
+    pub fn next_entry(&mut self) -> io::Result<Option<Entry>> {
+        if self.done {
+            return Ok(None);
+        }
+        let end = self.offset + self.left;
+        self.skip(self.left + padding(end))?;
+        self.left = 0;
+
+        let mut header = [0u8; HEADER_SIZE];
+        self.read_exact_counted(&mut header)?;
+        if &header[..6] != NEWC_MAGIC && &header[..6] != NEWC_CRC_MAGIC {
+            return Err(invalid("not a newc cpio archive"));
+        }
+        let mode = field(&header, 1)?;
+        let size = field(&header, 6)?;
+        let name_size = field(&header, 11)? as usize;
+        if name_size == 0 || name_size > PATH_MAX {
+            return Err(invalid("invalid name size"));
+        }
+
+        let mut name = vec![0u8; name_size];
+        self.read_exact_counted(&mut name)?;
+        if name.pop() != Some(0) {
+            return Err(invalid("name without its NUL"));
+        }
+        self.skip(padding(self.offset))?;
+
+        if name == TRAILER {
+            self.done = true;
+            return Ok(None);
+        }
+        self.left = u64::from(size);
+
+        Ok(Some(Entry {
+            name: String::from_utf8_lossy(&name).into_owned(),
+            mode,
+        }))
+    }
+
+    fn read_exact_counted(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        self.reader.read_exact(buf)?;
+        self.offset += buf.len() as u64;
+        Ok(())
+    }
+
+    fn skip(&mut self, len: u64) -> io::Result<()> {
+        let skipped = io::copy(&mut (&mut self.reader).take(len), &mut io::sink())?;
+        self.offset += skipped;
+        if skipped < len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(())
+    }
+}
+
+/// Reads the data of the current entry.
+impl<R: Read> Read for CpioReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf.len().min(self.left as usize);
+        let len = self.reader.read(&mut buf[..len])?;
+        self.offset += len as u64;
+        self.left -= len as u64;
+        Ok(len)
+    }
+}
+
+// The field at `index` of the header, after the magic.
+fn field(header: &[u8; HEADER_SIZE], index: usize) -> io::Result<u32> {
+    let start = NEWC_MAGIC.len() + index * 8;
+    std::str::from_utf8(&header[start..start + 8])
+        .ok()
+        .and_then(|field| u32::from_str_radix(field, 16).ok())
+        .ok_or_else(|| invalid("invalid header field"))
+}
+
+// Bytes from `offset` to the next multiple of 4.
+fn padding(offset: u64) -> u64 {
+    offset.wrapping_neg() % 4
+}
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BUSYBOX: &[u8] = include_bytes!("fixtures/busybox.cpio");
+
+    // The entries of the archive, with their data.
+    fn entries(archive: &[u8]) -> io::Result<Vec<(Entry, Vec<u8>)>> {
+        let mut reader = CpioReader::new(archive);
+        let mut entries = Vec::new();
+        while let Some(entry) = reader.next_entry()? {
+            let mut data = Vec::new();
+            reader.read_to_end(&mut data)?;
+            entries.push((entry, data));
+        }
+        Ok(entries)
+    }
+
+    #[test]
+    fn newc() {
+        let entries = entries(BUSYBOX).unwrap();
+        let names: Vec<_> = entries
+            .iter()
+            .map(|(entry, _)| entry.name.as_str())
+            .collect();
+        assert_eq!(names, [".", "bin", "bin/busybox", "bin/sh", "init"]);
+
+        let (sh, target) = &entries[3];
+        assert!(sh.is_symlink());
+        assert_eq!(target, b"busybox");
+        let (init, script) = &entries[4];
+        assert!(init.is_file());
+        assert_eq!(init.mode, 0o100755);
+        assert!(script.starts_with(b"#!/bin/sh\n"));
+    }
+
+    #[test]
+    fn skipped_data() {
+        // Entries whose data is not read are skipped, with their padding.
+        let mut rest = BUSYBOX;
+        let mut reader = CpioReader::new(&mut rest);
+        let mut names = Vec::new();
+        while let Some(entry) = reader.next_entry().unwrap() {
+            names.push(entry.name);
+        }
+        assert_eq!(names.len(), 5);
+        // The trailer ends the archive, the reader is left past it.
+        assert_eq!(reader.next_entry().unwrap(), None);
+        assert!(rest.iter().all(|byte| *byte == 0));
+    }
+
+    #[test]
+    fn malformed() {
+        let error = entries(&BUSYBOX[..BUSYBOX.len() / 2]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+
+        let error = entries(include_bytes!("fixtures/bzImage")).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        let mut archive = BUSYBOX.to_vec();
+        archive[14] = b'x';
+        let error = entries(&archive).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
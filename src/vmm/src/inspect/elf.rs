@@ -0,0 +1,130 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! ELF header parser, for the architecture of the kernel and of the init binaries.
+
+use std::fmt;
+
+// Identification and header fields. See elf(5).
+const ELF_MAGIC: [u8; 4] = *b"\x7fELF";
+const EI_CLASS: usize = 4;
+const EI_DATA: usize = 5;
+const ELFCLASS32: u8 = 1;
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const ELFDATA2MSB: u8 = 2;
+const E_MACHINE: usize = 18;
+
+// Machines.
+pub(super) const EM_386: u16 = 3;
+const EM_PPC64: u16 = 21;
+const EM_S390: u16 = 22;
+const EM_ARM: u16 = 40;
+pub(super) const EM_X86_64: u16 = 62;
+pub(super) const EM_AARCH64: u16 = 183;
+const EM_RISCV: u16 = 243;
+
+/// What an ELF file runs on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ElfHeader {
+    /// Whether it is a 64-bit file.
+    pub class64: bool,
+    /// The machine it is built for, as `e_machine`.
+    pub machine: u16,
+}
+
+impl ElfHeader {
+    /// Parse the ELF identification and machine at the start of `bytes`. None when they
+    /// are not the start of an ELF file.
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < E_MACHINE + 2 || bytes[..4] != ELF_MAGIC {
+            return None;
+        }
+        let class64 = match bytes[EI_CLASS] {
+            ELFCLASS32 => false,
+            ELFCLASS64 => true,
+            _ => return None,
+        };
+        let machine = [bytes[E_MACHINE], bytes[E_MACHINE + 1]];
+        let machine = match bytes[EI_DATA] {
+            ELFDATA2LSB => u16::from_le_bytes(machine),
+            ELFDATA2MSB => u16::from_be_bytes(machine),
+            _ => return None,
+        };
+
+        Some(ElfHeader { class64, machine })
+    }
+
+    /// The header of the binaries the host runs natively.
+    pub fn host() -> Self {
+        let machine = match std::env::consts::ARCH {
+            "aarch64" => EM_AARCH64,
+            _ => EM_X86_64,
+        };
+        ElfHeader {
+            class64: true,
+            machine,
+        }
+    }
+
+    /// Whether a binary with this header runs on `host`, which may run the 32-bit binaries
+    /// of its architecture too.
+    pub fn runs_on(&self, host: &ElfHeader) -> bool {
+        match (self.machine, host.machine) {
+            (EM_386, EM_X86_64) | (EM_ARM, EM_AARCH64) => true,
+            (machine, host_machine) => machine == host_machine && self.class64 == host.class64,
+        }
+    }
+}
+
+impl fmt::Display for ElfHeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.machine, self.class64) {
+            (EM_386, _) => write!(f, "x86"),
+            (EM_X86_64, true) => write!(f, "x86_64"),
+            (EM_X86_64, false) => write!(f, "x32"),
+            (EM_ARM, _) => write!(f, "arm"),
+            (EM_AARCH64, true) => write!(f, "aarch64"),
+            (EM_AARCH64, false) => write!(f, "aarch64 ILP32"),
+            (EM_PPC64, _) => write!(f, "ppc64"),
+            (EM_S390, _) => write!(f, "s390"),
+            (EM_RISCV, true) => write!(f, "riscv64"),
+            (EM_RISCV, false) => write!(f, "riscv32"),
+            (machine, _) => write!(f, "ELF machine {}", machine),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn machines() {
+        let vmlinux = ElfHeader::parse(include_bytes!("fixtures/vmlinux")).unwrap();
+        assert_eq!(
+            vmlinux,
+            ElfHeader {
+                class64: true,
+                machine: EM_X86_64
+            }
+        );
+        assert_eq!(vmlinux.to_string(), "x86_64");
+        let arm64 = ElfHeader::parse(include_bytes!("fixtures/vmlinux-arm64")).unwrap();
+        assert_eq!(arm64.to_string(), "aarch64");
+        assert!(vmlinux.runs_on(&vmlinux));
+        assert!(!arm64.runs_on(&vmlinux));
+
+        // A big-endian 32-bit header.
+        let mut header = include_bytes!("fixtures/vmlinux")[..20].to_vec();
+        header[EI_CLASS] = ELFCLASS32;
+        header[EI_DATA] = ELFDATA2MSB;
+        header[E_MACHINE..E_MACHINE + 2].copy_from_slice(&EM_S390.to_be_bytes());
+        assert_eq!(ElfHeader::parse(&header).unwrap().to_string(), "s390");
+
+        // Not ELF, or too short for the machine.
+        assert_eq!(ElfHeader::parse(include_bytes!("fixtures/bzImage")), None);
+        assert_eq!(ElfHeader::parse(&header[..18]), None);
+        header[EI_CLASS] = 3;
+        assert_eq!(ElfHeader::parse(&header), None);
+    }
+}
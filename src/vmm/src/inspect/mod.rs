@@ -0,0 +1,605 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! What the boot images are, and whether they go together, before booting them.
+//!
+//! The kernel is scanned for its version banner and its 8250 serial driver, the initramfs
+//! is unpacked in memory to follow its init down to the binary that runs. What is found
+//! wrong is only a [`Problem`]: the guest may boot anyway, e.g. with a driver built as a
+//! module, and the user decides whether it should stop the VMM.
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read};
+
+use flate2::bufread::GzDecoder;
+use serde::{Serialize, Serializer};
+
+use crate::config::CmdlineBuilder;
+
+mod cpio;
+mod elf;
+
+use cpio::CpioReader;
+use elf::{ElfHeader, EM_386, EM_AARCH64, EM_X86_64};
+
+// The kernel is scanned in chunks, which overlap by more than the strings looked for.
+const SCAN_CHUNK: usize = 1 << 20;
+const SCAN_OVERLAP: usize = 256;
+// The banner of `init/version.c`, followed by the release.
+const VERSION_BANNER: &[u8] = b"Linux version ";
+// Longest release reported, longer ones are not a release.
+const VERSION_MAX_LEN: usize = 64;
+// The name of the 8250 driver, which the serial consoles of the VM need.
+const SERIAL_DRIVER: &[u8] = b"serial8250";
+
+// bzImage setup header fields. See Documentation/arch/x86/boot.rst.
+const BZIMAGE_SETUP_SIZE: usize = 0x240;
+const BZIMAGE_HDR_OFFSET: usize = 0x202;
+const BZIMAGE_VERSION_OFFSET: usize = 0x206;
+const BZIMAGE_KERNEL_VERSION_OFFSET: usize = 0x20e;
+const BZIMAGE_XLOADFLAGS_OFFSET: usize = 0x236;
+// The boot protocol version which added `xloadflags`, and its 64-bit kernel flag.
+const BZIMAGE_XLOADFLAGS_VERSION: u16 = 0x020c;
+const XLF_KERNEL_64: u8 = 1;
+// Magic number of the arm64 Image header.
+const ARM64_IMAGE_MAGIC_OFFSET: usize = 0x38;
+const ARM64_IMAGE_MAGIC: &[u8; 4] = b"ARM\x64";
+
+// How much of the files is kept: enough for an ELF header or a `#!` line.
+const FILE_HEAD_SIZE: u64 = 256;
+// Longest symbolic link target.
+const PATH_MAX: u64 = 4096;
+// Symbolic links followed in a path before giving up, as the kernel does.
+const MAX_SYMLINKS: usize = 40;
+// Interpreters followed from the init script down to a binary, as the kernel does.
+const MAX_INTERPRETERS: usize = 4;
+// What the kernel runs from the initramfs, unless `rdinit=` tells otherwise.
+const DEFAULT_INIT: &str = "/init";
+
+/// What the boot images are, and what may keep the guest from booting.
+#[derive(Debug, Default, Serialize, PartialEq, Eq)]
+pub struct BootCheck {
+    /// The kernel release, e.g. `6.6.8`, from its header or its version banner.
+    pub kernel_version: Option<String>,
+    /// What the kernel is built for.
+    pub kernel_arch: Option<String>,
+    /// What the init binary of the initramfs is built for.
+    pub init_arch: Option<String>,
+    pub problems: Vec<Problem>,
+}
+
+/// Something that may keep the guest from booting.
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum Problem {
+    #[error("the kernel is built for {kernel}, not for the {host} host")]
+    KernelArch { kernel: String, host: String },
+    #[error("console={0}, but the kernel has no 8250 serial driver")]
+    NoSerialDriver(String),
+    #[error("console={console} is not a console of the VM, which has {devices}")]
+    UnknownConsole { console: String, devices: String },
+    #[error("the initramfs has no {0}, and the command line no root= to boot from instead")]
+    NoInit(String),
+    #[error("the init {init} needs {missing}, which the initramfs does not have")]
+    BrokenInit { init: String, missing: String },
+    #[error("the init {path} is built for {arch}, not for the {host} host")]
+    InitArch {
+        path: String,
+        arch: String,
+        host: String,
+    },
+    #[error("the init {0} is not an executable file")]
+    InitNotExecutable(String),
+    #[error("the initramfs does not read as cpio archives: {0}")]
+    InvalidInitramfs(String),
+}
+
+/// Problems are reported as their messages.
+impl Serialize for Problem {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl fmt::Display for BootCheck {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let unknown = |value: &Option<String>| value.clone().unwrap_or_else(|| "unknown".into());
+        write!(
+            f,
+            "kernel {} ({}), init {}",
+            unknown(&self.kernel_version),
+            unknown(&self.kernel_arch),
+            unknown(&self.init_arch)
+        )
+    }
+}
+
+/// Check that `kernel`, `initramfs` and `cmdline` go together on this host. `serial2` is
+/// whether the VM has the second serial port.
+///
+/// Only reading the kernel fails, the initramfs being checked too.
+pub(crate) fn check<K: Read, I: Read>(
+    kernel: K,
+    initramfs: Option<I>,
+    cmdline: &CmdlineBuilder,
+    serial2: bool,
+) -> io::Result<BootCheck> {
+    check_on(ElfHeader::host(), kernel, initramfs, cmdline, serial2)
+}
+
+fn check_on<K: Read, I: Read>(
+    host: ElfHeader,
+    kernel: K,
+    initramfs: Option<I>,
+    cmdline: &CmdlineBuilder,
+    serial2: bool,
+) -> io::Result<BootCheck> {
+    let kernel = KernelInfo::scan(kernel)?;
+    let mut check = BootCheck {
+        kernel_version: kernel.version,
+        kernel_arch: kernel.arch.map(|arch| arch.to_string()),
+        ..Default::default()
+    };
+    if let Some(arch) = kernel.arch.filter(|arch| *arch != host) {
+        check.problems.push(Problem::KernelArch {
+            kernel: arch.to_string(),
+            host: host.to_string(),
+        });
+    }
+
+    // The kernel takes the name up to the options, e.g. `ttyS0,115200n8`.
+    if let Some(console) = cmdline.value("console") {
+        let console = console.split(',').next().unwrap_or_default();
+        match console {
+            "ttyS0" => {}
+            "ttyS1" if serial2 => {}
+            _ => check.problems.push(Problem::UnknownConsole {
+                console: console.to_string(),
+                devices: if serial2 { "ttyS0 and ttyS1" } else { "ttyS0" }.to_string(),
+            }),
+        }
+        if console.starts_with("ttyS") && kernel.serial8250 == Some(false) {
+            check
+                .problems
+                .push(Problem::NoSerialDriver(console.to_string()));
+        }
+    }
+
+    if let Some(initramfs) = initramfs {
+        let mut files = HashMap::new();
+        match read_archives(&mut BufReader::new(initramfs), &mut files) {
+            Ok(true) => check_init(&files, cmdline, host, &mut check),
+            // Compressed in a way the VMM does not read.
+            Ok(false) => log::debug!("Not checking the init of the initramfs"),
+            Err(e) => check
+                .problems
+                .push(Problem::InvalidInitramfs(e.to_string())),
+        }
+    }
+
+    Ok(check)
+}
+
+// What the kernel scan found.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct KernelInfo {
+    version: Option<String>,
+    arch: Option<ElfHeader>,
+    // Unknown in a compressed kernel.
+    serial8250: Option<bool>,
+}
+
+impl KernelInfo {
+    fn scan<R: Read>(mut kernel: R) -> io::Result<Self> {
+        let mut info = KernelInfo::default();
+        let mut serial8250 = false;
+        let mut chunk = Vec::with_capacity(SCAN_CHUNK + SCAN_OVERLAP);
+        let mut first = true;
+        loop {
+            let kept = chunk.len();
+            (&mut kernel)
+                .take(SCAN_CHUNK as u64)
+                .read_to_end(&mut chunk)?;
+            let end = chunk.len() - kept < SCAN_CHUNK;
+
+            if first {
+                first = false;
+                if let Some(bzimage) = KernelInfo::bzimage(&chunk) {
+                    return Ok(bzimage);
+                }
+                info.arch = ElfHeader::parse(&chunk);
+                if chunk.get(ARM64_IMAGE_MAGIC_OFFSET..ARM64_IMAGE_MAGIC_OFFSET + 4)
+                    == Some(ARM64_IMAGE_MAGIC)
+                {
+                    info.arch = Some(ElfHeader {
+                        class64: true,
+                        machine: EM_AARCH64,
+                    });
+                }
+            }
+            if info.version.is_none() {
+                info.version = find_version(&chunk);
+            }
+            serial8250 = serial8250 || find(&chunk, SERIAL_DRIVER).is_some();
+            if end || (info.version.is_some() && serial8250) {
+                break;
+            }
+            chunk.drain(..chunk.len() - SCAN_OVERLAP);
+        }
+        info.serial8250 = Some(serial8250);
+
+        Ok(info)
+    }
+
+    // The version and the architecture in the setup header of a bzImage, whose kernel is
+    // compressed.
+    fn bzimage(header: &[u8]) -> Option<Self> {
+        if header.len() < BZIMAGE_SETUP_SIZE
+            || header[BZIMAGE_HDR_OFFSET..BZIMAGE_HDR_OFFSET + 4] != *b"HdrS"
+        {
+            return None;
+        }
+        let le_u16 = |offset: usize| u16::from_le_bytes([header[offset], header[offset + 1]]);
+
+        // The string starts with the release, and is relative to the setup header.
+        let version = match le_u16(BZIMAGE_KERNEL_VERSION_OFFSET) {
+            0 => None,
+            offset => header.get(usize::from(offset) + 0x200..).and_then(release),
+        };
+        let arch = (le_u16(BZIMAGE_VERSION_OFFSET) >= BZIMAGE_XLOADFLAGS_VERSION).then(|| {
+            match header[BZIMAGE_XLOADFLAGS_OFFSET] & XLF_KERNEL_64 {
+                0 => ElfHeader {
+                    class64: false,
+                    machine: EM_386,
+                },
+                _ => ElfHeader {
+                    class64: true,
+                    machine: EM_X86_64,
+                },
+            }
+        });
+
+        Some(KernelInfo {
+            version,
+            arch,
+            serial8250: None,
+        })
+    }
+}
+
+// The release of the first version banner, skipping the format strings the kernel has too.
+fn find_version(mut bytes: &[u8]) -> Option<String> {
+    while let Some(start) = find(bytes, VERSION_BANNER) {
+        bytes = &bytes[start + VERSION_BANNER.len()..];
+        if let Some(release) = release(bytes) {
+            return Some(release);
+        }
+    }
+    None
+}
+
+// The release at the start of `bytes`: a digit, up to the next blank or NUL.
+fn release(bytes: &[u8]) -> Option<String> {
+    if !bytes.first()?.is_ascii_digit() {
+        return None;
+    }
+    let len = bytes
+        .iter()
+        .take(VERSION_MAX_LEN)
+        .position(|byte| matches!(byte, b' ' | b'\n' | 0))?;
+    Some(String::from_utf8_lossy(&bytes[..len]).into_owned())
+}
+
+fn find(bytes: &[u8], needle: &[u8]) -> Option<usize> {
+    bytes
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+// A file of the initramfs, as much as the checks need.
+#[derive(Debug)]
+enum Node {
+    File { mode: u32, head: Vec<u8> },
+    Symlink(String),
+    Other,
+}
+
+// Read the archives the initramfs is made of into `files`, by path without the leading
+// `/`, the later entries replacing the earlier ones as they do in the guest. Returns
+// whether all were read: the kernel takes more compression formats than the VMM does.
+fn read_archives(reader: &mut dyn BufRead, files: &mut HashMap<String, Node>) -> io::Result<bool> {
+    loop {
+        // The archives may be padded with zeroes.
+        let buf = reader.fill_buf()?;
+        let zeroes = buf.iter().take_while(|byte| **byte == 0).count();
+        let (empty, data) = (buf.is_empty(), zeroes < buf.len());
+        reader.consume(zeroes);
+        if empty {
+            return Ok(true);
+        }
+        if !data {
+            continue;
+        }
+
+        let buf = reader.fill_buf()?;
+        if buf.starts_with(&[0x1f, 0x8b]) {
+            // Read to its end, the gzip trailer included, before what follows it.
+            let mut decoder = BufReader::new(GzDecoder::new(&mut *reader));
+            if !read_archives(&mut decoder, files)? {
+                return Ok(false);
+            }
+        } else if buf.starts_with(b"0707") {
+            let mut archive = CpioReader::new(&mut *reader);
+            while let Some(entry) = archive.next_entry()? {
+                let node = if entry.is_file() {
+                    let mut head = Vec::new();
+                    (&mut archive).take(FILE_HEAD_SIZE).read_to_end(&mut head)?;
+                    Node::File {
+                        mode: entry.mode,
+                        head,
+                    }
+                } else if entry.is_symlink() {
+                    let mut target = Vec::new();
+                    (&mut archive).take(PATH_MAX).read_to_end(&mut target)?;
+                    Node::Symlink(String::from_utf8_lossy(&target).into_owned())
+                } else {
+                    Node::Other
+                };
+                let name = entry.name.strip_prefix("./").unwrap_or(&entry.name);
+                let name = name.trim_start_matches('/').trim_end_matches('/');
+                if !name.is_empty() && name != "." {
+                    files.insert(name.to_string(), node);
+                }
+            }
+        } else {
+            return Ok(false);
+        }
+    }
+}
+
+// The file at `path` and its path, following the symbolic links.
+fn lookup<'a>(files: &'a HashMap<String, Node>, path: &str) -> Option<(String, &'a Node)> {
+    let mut pending: VecDeque<String> = components(path).collect();
+    let mut resolved: Vec<String> = Vec::new();
+    let mut links = 0;
+    let mut node = None;
+    while let Some(component) = pending.pop_front() {
+        if component == ".." {
+            resolved.pop();
+            continue;
+        }
+        resolved.push(component);
+        node = files.get(&resolved.join("/"));
+        match node {
+            Some(Node::Symlink(target)) => {
+                links += 1;
+                if links > MAX_SYMLINKS {
+                    return None;
+                }
+                resolved.pop();
+                if target.starts_with('/') {
+                    resolved.clear();
+                }
+                for component in components(target).collect::<Vec<_>>().into_iter().rev() {
+                    pending.push_front(component);
+                }
+            }
+            // The archives may leave the directories out.
+            None if !pending.is_empty() => {}
+            None => return None,
+            Some(_) => {}
+        }
+    }
+    Some((format!("/{}", resolved.join("/")), node?))
+}
+
+fn components(path: &str) -> impl Iterator<Item = String> + '_ {
+    path.split('/')
+        .filter(|component| !component.is_empty() && *component != ".")
+        .map(str::to_string)
+}
+
+// Check the init of the initramfs, the binary that runs it included.
+fn check_init(
+    files: &HashMap<String, Node>,
+    cmdline: &CmdlineBuilder,
+    host: ElfHeader,
+    check: &mut BootCheck,
+) {
+    match find_init(files, cmdline) {
+        Ok(Some((path, elf))) => {
+            check.init_arch = Some(elf.to_string());
+            if !elf.runs_on(&host) {
+                check.problems.push(Problem::InitArch {
+                    path,
+                    arch: elf.to_string(),
+                    host: host.to_string(),
+                });
+            }
+        }
+        Ok(None) => {}
+        Err(problem) => check.problems.push(problem),
+    }
+}
+
+// Follow the init through its interpreters, down to the ELF binary that runs. None when
+// the kernel boots from the root file system instead.
+fn find_init(
+    files: &HashMap<String, Node>,
+    cmdline: &CmdlineBuilder,
+) -> Result<Option<(String, ElfHeader)>, Problem> {
+    let init = cmdline.value("rdinit").unwrap_or(DEFAULT_INIT);
+    let mut next = init.to_string();
+    for _ in 0..=MAX_INTERPRETERS {
+        let (path, mode, head) = match lookup(files, &next) {
+            Some((path, Node::File { mode, head })) => (path, *mode, head),
+            Some((path, _)) => return Err(Problem::InitNotExecutable(path)),
+            None if next != init => {
+                return Err(Problem::BrokenInit {
+                    init: init.to_string(),
+                    missing: next,
+                })
+            }
+            None if cmdline.value("root").is_some() => return Ok(None),
+            None => return Err(Problem::NoInit(next)),
+        };
+        if mode & 0o111 == 0 {
+            return Err(Problem::InitNotExecutable(path));
+        }
+
+        if let Some(elf) = ElfHeader::parse(head) {
+            return Ok(Some((path, elf)));
+        }
+        let interpreter = head
+            .strip_prefix(b"#!")
+            .and_then(|line| line.split(|byte| *byte == b'\n').next())
+            .and_then(|line| {
+                let line = String::from_utf8_lossy(line);
+                line.split_whitespace().next().map(str::to_string)
+            });
+        match interpreter {
+            Some(interpreter) => next = interpreter,
+            None => return Err(Problem::InitNotExecutable(path)),
+        }
+    }
+
+    // Too many interpreters for the kernel.
+    Err(Problem::InitNotExecutable(next))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const X86_64: ElfHeader = ElfHeader {
+        class64: true,
+        machine: EM_X86_64,
+    };
+    const VMLINUX: &[u8] = include_bytes!("fixtures/vmlinux");
+    const VMLINUX_ARM64: &[u8] = include_bytes!("fixtures/vmlinux-arm64");
+    const BZIMAGE: &[u8] = include_bytes!("fixtures/bzImage");
+    const BUSYBOX: &[u8] = include_bytes!("fixtures/busybox.cpio");
+
+    fn check(kernel: &[u8], initramfs: Option<&[u8]>, cmdline: &str, serial2: bool) -> BootCheck {
+        let mut builder = CmdlineBuilder::default();
+        builder.append(cmdline).unwrap();
+        check_on(X86_64, kernel, initramfs, &builder, serial2).unwrap()
+    }
+
+    #[test]
+    fn kernels() {
+        let vmlinux = KernelInfo::scan(VMLINUX).unwrap();
+        // Past the format string with the same banner.
+        assert_eq!(vmlinux.version.as_deref(), Some("6.6.8"));
+        assert_eq!(vmlinux.arch, Some(X86_64));
+        assert_eq!(vmlinux.serial8250, Some(true));
+
+        let arm64 = KernelInfo::scan(VMLINUX_ARM64).unwrap();
+        assert_eq!(arm64.version.as_deref(), Some("6.1.0-arm64"));
+        assert_eq!(arm64.arch.unwrap().to_string(), "aarch64");
+        assert_eq!(arm64.serial8250, Some(false));
+
+        // The kernel of a bzImage is compressed, only its setup header tells.
+        let bzimage = KernelInfo::scan(BZIMAGE).unwrap();
+        assert_eq!(
+            bzimage,
+            KernelInfo {
+                version: Some("6.5.0-lumper".into()),
+                arch: Some(X86_64),
+                serial8250: None,
+            }
+        );
+
+        // The banner across two chunks.
+        let mut image = vec![0u8; SCAN_CHUNK - 20];
+        image.extend_from_slice(b"Linux version 6.12.1-rc3 (gcc) serial8250");
+        let info = KernelInfo::scan(&image[..]).unwrap();
+        assert_eq!(info.version.as_deref(), Some("6.12.1-rc3"));
+        assert_eq!(info.arch, None);
+        assert_eq!(info.serial8250, Some(true));
+    }
+
+    #[test]
+    fn init() {
+        // The script runs with busybox, through the /bin/sh link.
+        let busybox = check(VMLINUX, Some(BUSYBOX), "", false);
+        assert_eq!(
+            busybox,
+            BootCheck {
+                kernel_version: Some("6.6.8".into()),
+                kernel_arch: Some("x86_64".into()),
+                init_arch: Some("x86_64".into()),
+                problems: Vec::new(),
+            }
+        );
+
+        // After the early microcode archive, a compressed one with a link to an arm64 init.
+        let arm64_init: &[u8] = include_bytes!("fixtures/arm64.img");
+        let arm64 = check(VMLINUX, Some(arm64_init), "", false);
+        assert_eq!(arm64.init_arch.as_deref(), Some("aarch64"));
+        assert_eq!(
+            arm64.problems,
+            [Problem::InitArch {
+                path: "/sbin/init".into(),
+                arch: "aarch64".into(),
+                host: "x86_64".into(),
+            }]
+        );
+
+        // Without init, the kernel boots from root= if there is one.
+        let no_init: &[u8] = include_bytes!("fixtures/no-init.cpio.gz");
+        assert_eq!(
+            check(VMLINUX, Some(no_init), "", false).problems,
+            [Problem::NoInit("/init".into())]
+        );
+        assert!(check(VMLINUX, Some(no_init), "root=/dev/vda", false)
+            .problems
+            .is_empty());
+        assert_eq!(
+            check(VMLINUX, Some(no_init), "rdinit=/sbin/init", false).problems,
+            [Problem::NoInit("/sbin/init".into())]
+        );
+
+        // What the VMM does not read is not checked.
+        let other = check(VMLINUX, Some(BZIMAGE), "", false);
+        assert!(other.problems.is_empty());
+        let mut truncated = BUSYBOX.to_vec();
+        truncated.truncate(200);
+        assert!(matches!(
+            check(VMLINUX, Some(&truncated[..]), "", false).problems[..],
+            [Problem::InvalidInitramfs(_)]
+        ));
+    }
+
+    #[test]
+    fn consoles() {
+        assert!(check(VMLINUX, None, "console=ttyS0,115200n8", false)
+            .problems
+            .is_empty());
+        assert_eq!(
+            check(VMLINUX, None, "console=hvc0", false).problems,
+            [Problem::UnknownConsole {
+                console: "hvc0".into(),
+                devices: "ttyS0".into(),
+            }]
+        );
+        assert!(check(VMLINUX, None, "console=ttyS1", true)
+            .problems
+            .is_empty());
+        assert!(!check(VMLINUX, None, "console=ttyS1", false)
+            .problems
+            .is_empty());
+
+        let arm64 = check(VMLINUX_ARM64, None, "console=ttyS0", false);
+        assert_eq!(
+            arm64
+                .problems
+                .iter()
+                .map(|problem| problem.to_string())
+                .collect::<Vec<_>>(),
+            [
+                "the kernel is built for aarch64, not for the x86_64 host",
+                "console=ttyS0, but the kernel has no 8250 serial driver",
+            ]
+        );
+    }
+}
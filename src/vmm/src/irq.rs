@@ -0,0 +1,261 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use std::collections::BTreeMap;
+use std::io;
+use std::result;
+use std::sync::{Arc, Mutex};
+
+use kvm_bindings::{
+    __IncompleteArrayField, kvm_irq_routing, kvm_irq_routing_entry, kvm_msi, KVM_IRQCHIP_IOAPIC,
+    KVM_IRQCHIP_PIC_MASTER, KVM_IRQCHIP_PIC_SLAVE, KVM_IRQ_ROUTING_IRQCHIP, KVM_IRQ_ROUTING_MSI,
+};
+use kvm_ioctls::VmFd;
+use vm_allocator::IdAllocator;
+use vmm_sys_util::eventfd::EventFd;
+
+use crate::devices::ioapic::{self, Ioapic, Msi, MsiSink};
+
+/// Number of legacy IRQs, wired to both the PICs and the IOAPIC.
+const PIC_IRQS: u32 = 16;
+/// Number of IOAPIC input pins.
+const IOAPIC_PINS: u32 = ioapic::IOAPIC_PINS as u32;
+/// GSIs handed out to devices, past the ones KVM routes to the irqchip pins by default.
+pub(crate) const DEVICE_GSI_BASE: u32 = IOAPIC_PINS;
+pub(crate) const DEVICE_GSI_MAX: u32 = 31;
+// Legacy routes, then one route per device GSI.
+const MAX_ROUTES: usize =
+    (PIC_IRQS + IOAPIC_PINS) as usize + (DEVICE_GSI_MAX - DEVICE_GSI_BASE + 1) as usize;
+
+/// Where the guest receives the interrupts signaled on a GSI.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum IrqRoute {
+    /// An IOAPIC input pin, which is also the guest IRQ number.
+    IoapicPin(u32),
+}
+
+/// Allocates GSIs to devices, and routes them to the guest.
+///
+/// Devices signal their interrupts on their own GSI, with an irqfd. The routing table
+/// also keeps the KVM default routes of the legacy IRQs.
+///
+/// The GSIs are distinct, but not what the guest sees: each one is routed back onto the
+/// device IOAPIC pin, from 5 up. The in-kernel IOAPIC only has 24 pins, so the pins of
+/// the devices are still legacy IRQs the guest may share with ISA devices, only the irqfds
+/// are told apart.
+pub(crate) struct GsiAllocator {
+    gsis: IdAllocator,
+    routes: BTreeMap<u32, IrqRoute>,
+}
+
+// kvm_irq_routing ends with a flexible array, this gives it room for all the routes.
+#[repr(C)]
+struct RoutingTable {
+    header: kvm_irq_routing,
+    entries: [kvm_irq_routing_entry; MAX_ROUTES],
+}
+
+fn irqchip_route(gsi: u32, irqchip: u32, pin: u32) -> kvm_irq_routing_entry {
+    let mut entry = kvm_irq_routing_entry {
+        gsi,
+        type_: KVM_IRQ_ROUTING_IRQCHIP,
+        ..Default::default()
+    };
+    entry.u.irqchip.irqchip = irqchip;
+    entry.u.irqchip.pin = pin;
+    entry
+}
+
+fn msi_route(gsi: u32, msi: Msi) -> kvm_irq_routing_entry {
+    let mut entry = kvm_irq_routing_entry {
+        gsi,
+        type_: KVM_IRQ_ROUTING_MSI,
+        ..Default::default()
+    };
+    entry.u.msi.address_lo = msi.address;
+    entry.u.msi.data = msi.data;
+    entry
+}
+
+// Replace the VM GSI routing table.
+fn set_routing(vm_fd: &VmFd, entries: &[kvm_irq_routing_entry]) -> kvm_ioctls::Result<()> {
+    let mut table = RoutingTable {
+        header: kvm_irq_routing {
+            nr: entries.len() as u32,
+            flags: 0,
+            entries: __IncompleteArrayField::new(),
+        },
+        entries: [kvm_irq_routing_entry::default(); MAX_ROUTES],
+    };
+    table.entries[..entries.len()].copy_from_slice(entries);
+
+    vm_fd.set_gsi_routing(&table.header)
+}
+
+impl GsiAllocator {
+    pub fn new() -> result::Result<Self, vm_allocator::Error> {
+        Ok(GsiAllocator {
+            gsis: IdAllocator::new(DEVICE_GSI_BASE, DEVICE_GSI_MAX)?,
+            routes: BTreeMap::new(),
+        })
+    }
+
+    /// Allocate a GSI, delivered to the guest through `route`.
+    pub fn allocate(&mut self, route: IrqRoute) -> result::Result<u32, vm_allocator::Error> {
+        let gsi = self.gsis.allocate_id()?;
+        self.routes.insert(gsi, route);
+        Ok(gsi)
+    }
+
+    // The legacy routes set up by KVM_CREATE_IRQCHIP, then the device ones.
+    fn entries(&self) -> Vec<kvm_irq_routing_entry> {
+        let mut entries = Vec::with_capacity(MAX_ROUTES);
+
+        for irq in 0..PIC_IRQS {
+            let (pic, pin) = if irq < 8 {
+                (KVM_IRQCHIP_PIC_MASTER, irq)
+            } else {
+                (KVM_IRQCHIP_PIC_SLAVE, irq - 8)
+            };
+            entries.push(irqchip_route(irq, pic, pin));
+        }
+        for pin in 0..IOAPIC_PINS {
+            entries.push(irqchip_route(pin, KVM_IRQCHIP_IOAPIC, pin));
+        }
+
+        for (gsi, route) in self.routes.iter() {
+            match route {
+                IrqRoute::IoapicPin(pin) => {
+                    entries.push(irqchip_route(*gsi, KVM_IRQCHIP_IOAPIC, *pin))
+                }
+            }
+        }
+
+        entries
+    }
+
+    /// Replace the VM GSI routing table. The full irqchip must exist.
+    pub fn set_routing(&self, vm_fd: &VmFd) -> kvm_ioctls::Result<()> {
+        set_routing(vm_fd, &self.entries())
+    }
+
+    /// The IOAPIC pin a GSI signals.
+    pub fn pin(&self, gsi: u32) -> Option<u32> {
+        if gsi < IOAPIC_PINS {
+            return Some(gsi);
+        }
+        self.routes.get(&gsi).map(|route| match route {
+            IrqRoute::IoapicPin(pin) => *pin,
+        })
+    }
+}
+
+/// Delivers the interrupts the devices signal on their eventfds, whichever the irqchip.
+pub(crate) enum IrqTrigger {
+    /// KVM reads the eventfds as irqfds, and injects through its IOAPIC and PICs.
+    Kernel,
+    /// The IOAPIC worker reads them, and the userspace IOAPIC injects MSIs.
+    Userspace {
+        ioapic: Arc<Mutex<Ioapic>>,
+        eventfds: Vec<(usize, EventFd)>,
+    },
+}
+
+impl IrqTrigger {
+    /// Deliver the interrupts signaled on `eventfd` to the IOAPIC `pin`, with its `gsi`.
+    pub fn connect(
+        &mut self,
+        vm_fd: &VmFd,
+        eventfd: &EventFd,
+        gsi: u32,
+        pin: u32,
+    ) -> io::Result<()> {
+        match self {
+            IrqTrigger::Kernel => vm_fd
+                .register_irqfd(eventfd, gsi)
+                .map_err(|e| io::Error::from_raw_os_error(e.errno())),
+            IrqTrigger::Userspace { eventfds, .. } => {
+                eventfds.push((pin as usize, eventfd.try_clone()?));
+                Ok(())
+            }
+        }
+    }
+
+    /// Start reading the eventfds, when KVM does not.
+    pub fn start(&mut self) -> io::Result<()> {
+        match self {
+            IrqTrigger::Kernel => Ok(()),
+            IrqTrigger::Userspace { ioapic, eventfds } => {
+                ioapic::spawn_worker(ioapic.clone(), std::mem::take(eventfds))
+            }
+        }
+    }
+
+    /// Reset the userspace IOAPIC. KVM resets its own with the irqchip state.
+    pub fn reset(&self) {
+        if let IrqTrigger::Userspace { ioapic, .. } = self {
+            ioapic.lock().unwrap().reset();
+        }
+    }
+}
+
+// With the split irqchip, the IOAPIC pins are routed as MSIs, to the local APICs. The
+// routing table only holds those.
+impl MsiSink for Arc<VmFd> {
+    fn send(&self, msi: Msi) -> io::Result<()> {
+        let msi = kvm_msi {
+            address_lo: msi.address,
+            data: msi.data,
+            ..Default::default()
+        };
+        self.signal_msi(msi)
+            .map(|_| ())
+            .map_err(|e| io::Error::from_raw_os_error(e.errno()))
+    }
+
+    fn set_routes(&self, routes: &[(u32, Msi)]) -> io::Result<()> {
+        let entries: Vec<_> = routes
+            .iter()
+            .map(|(pin, msi)| msi_route(*pin, *msi))
+            .collect();
+        set_routing(self, &entries).map_err(|e| io::Error::from_raw_os_error(e.errno()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn routes() {
+        let mut allocator = GsiAllocator::new().unwrap();
+
+        let gsis: Vec<u32> = (5..=12)
+            .map(|pin| allocator.allocate(IrqRoute::IoapicPin(pin)).unwrap())
+            .collect();
+        assert_eq!(gsis, (DEVICE_GSI_BASE..=DEVICE_GSI_MAX).collect::<Vec<_>>());
+        assert!(allocator.allocate(IrqRoute::IoapicPin(13)).is_err());
+
+        let entries = allocator.entries();
+        assert_eq!(entries.len(), MAX_ROUTES);
+
+        // Safe because all the entries are irqchip routes.
+        let routes: Vec<(u32, u32, u32)> = entries
+            .iter()
+            .map(|e| unsafe { (e.gsi, e.u.irqchip.irqchip, e.u.irqchip.pin) })
+            .collect();
+
+        // Legacy IRQs still reach both the PICs and the IOAPIC.
+        assert!(routes.contains(&(4, KVM_IRQCHIP_PIC_MASTER, 4)));
+        assert!(routes.contains(&(4, KVM_IRQCHIP_IOAPIC, 4)));
+        assert!(routes.contains(&(9, KVM_IRQCHIP_PIC_SLAVE, 1)));
+        assert!(routes.contains(&(23, KVM_IRQCHIP_IOAPIC, 23)));
+
+        // Each device GSI has its own route.
+        for (gsi, pin) in gsis.iter().zip(5..) {
+            let device: Vec<_> = routes.iter().filter(|r| r.0 == *gsi).collect();
+            assert_eq!(device, vec![&(*gsi, KVM_IRQCHIP_IOAPIC, pin)]);
+            assert_eq!(allocator.pin(*gsi), Some(pin));
+        }
+        assert_eq!(allocator.pin(4), Some(4));
+    }
+}
@@ -0,0 +1,240 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Dropping the privileges the VMM set up with, before it runs guest code.
+//!
+//! The VMM may need root to configure the VM: creating a TAP, mapping hugepages. Once
+//! [`VMM::from_config`](crate::VMM::from_config) returned, every file the VM uses is open,
+//! and [`enter`] confines the process:
+//!
+//! 1. optionally, new mount, UTS and IPC namespaces, entered by `configure`,
+//! 2. a chroot to an empty directory,
+//! 3. no supplementary groups, then the jail gid and uid,
+//! 4. no way to regain privileges through exec (`PR_SET_NO_NEW_PRIVS`).
+//!
+//! Any failure is fatal: the VM must not run partially de-privileged.
+//!
+//! The VMM no longer reaches the host filesystem afterwards. The paths it would use at
+//! runtime are unavailable: console files cannot be rotated, a tap deleted while the guest
+//! runs cannot be opened again, no tap can be hot-plugged, and the API socket and pidfile
+//! are left behind on exit.
+//!
+//! The threads started by `configure`, which write the serial outputs, drop the
+//! privileges along with the process. A new mount namespace only applies to the calling
+//! thread, and to the threads it creates afterwards: with `unshare`, `configure` enters
+//! the namespaces before starting any thread, and [`enter`] must be called from the same
+//! thread, so that they all share the root it changes. The vCPUs and the device threads
+//! are started by [`VMM::run`](crate::VMM::run), and inherit the jail.
+//!
+//! There is no seccomp filter. One installed before the jail would have to allow
+//! `unshare`, `chroot`, `setgroups`, `setgid`, `setuid` and `prctl`. Installed after, it
+//! needs no privilege, as `PR_SET_NO_NEW_PRIVS` is already set.
+
+use std::cell::Cell;
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+
+use crate::config::JailConfig;
+
+/// Errors entering the jail.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("failed to unshare the mount, UTS and IPC namespaces")]
+    Unshare(#[source] io::Error),
+    #[error("failed to chroot to {path:?}")]
+    Chroot {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    #[error("failed to drop the supplementary groups")]
+    SetGroups(#[source] io::Error),
+    #[error("failed to switch to gid {gid}")]
+    SetGid {
+        gid: u32,
+        #[source]
+        source: io::Error,
+    },
+    #[error("failed to switch to uid {uid}")]
+    SetUid {
+        uid: u32,
+        #[source]
+        source: io::Error,
+    },
+    #[error("failed to set no_new_privs")]
+    NoNewPrivs(#[source] io::Error),
+    #[error("the VMM could regain root after dropping it")]
+    StillPrivileged,
+    #[error("the jail must be entered from the thread which configured the VMM")]
+    NotUnshared,
+}
+
+thread_local! {
+    // Whether this thread entered the namespaces of the jail.
+    static UNSHARED: Cell<bool> = const { Cell::new(false) };
+}
+
+fn check(ret: libc::c_int) -> io::Result<()> {
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// Move the calling thread to new mount, UTS and IPC namespaces. The threads it creates
+/// afterwards share them, along with its root directory.
+pub(crate) fn unshare() -> Result<(), Error> {
+    // Safe because it only takes flags, and we check the result.
+    check(unsafe { libc::unshare(libc::CLONE_NEWNS | libc::CLONE_NEWUTS | libc::CLONE_NEWIPC) })
+        .map_err(Error::Unshare)?;
+    UNSHARED.with(|unshared| unshared.set(true));
+
+    Ok(())
+}
+
+/// Confine the VMM as `config` describes.
+///
+/// This must be called after [`VMM::from_config`](crate::VMM::from_config), from the same
+/// thread, and before [`VMM::run`](crate::VMM::run). On error, the process must exit
+/// without running the VM.
+pub fn enter(config: &JailConfig) -> Result<(), Error> {
+    // Another thread would leave the threads of the VMM out of the chroot.
+    if config.unshare && !UNSHARED.with(Cell::get) {
+        return Err(Error::NotUnshared);
+    }
+
+    let chroot_error = |source| Error::Chroot {
+        path: config.chroot.clone(),
+        source,
+    };
+    let path =
+        CString::new(config.chroot.as_os_str().as_bytes()).map_err(|e| chroot_error(e.into()))?;
+    // Safe because the paths are valid C strings, and we check the results. Moving to the
+    // new root leaves no working directory outside of it.
+    unsafe {
+        check(libc::chdir(path.as_ptr())).map_err(chroot_error)?;
+        check(libc::chroot(c".".as_ptr())).map_err(chroot_error)?;
+        check(libc::chdir(c"/".as_ptr())).map_err(chroot_error)?;
+    }
+
+    // The groups go first, changing the uid drops the right to change them. The libc
+    // wrappers apply the changes to all the threads.
+    // Safe because the calls only take integers, and we check the results.
+    unsafe {
+        check(libc::setgroups(0, std::ptr::null())).map_err(Error::SetGroups)?;
+        check(libc::setgid(config.gid)).map_err(|source| Error::SetGid {
+            gid: config.gid,
+            source,
+        })?;
+        check(libc::setuid(config.uid)).map_err(|source| Error::SetUid {
+            uid: config.uid,
+            source,
+        })?;
+        check(libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0)).map_err(Error::NoNewPrivs)?;
+    }
+
+    // setuid only drops the saved uid when called by root: a VMM started setuid, or with
+    // capabilities, could get its privileges back.
+    // Safe because the calls only take integers.
+    let regained = unsafe {
+        libc::setuid(0) == 0
+            || libc::geteuid() != config.uid
+            || libc::getegid() != config.gid
+            || libc::getuid() != config.uid
+            || libc::getgid() != config.gid
+    };
+    if regained {
+        return Err(Error::StillPrivileged);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Read;
+    use std::process::Command;
+    use std::sync::mpsc;
+    use std::thread;
+
+    const CHILD_ENV: &str = "LUMPER_JAIL_TEST_DIR";
+    const NOBODY: u32 = 65534;
+
+    // A status line, e.g. `Uid:` and its real, effective, saved and filesystem ids.
+    fn status_field<'a>(status: &'a str, name: &str) -> Vec<&'a str> {
+        status
+            .lines()
+            .find_map(|line| line.strip_prefix(name))
+            .unwrap()
+            .split_whitespace()
+            .collect()
+    }
+
+    // The jail applies to the whole process, the test enters it from a child process.
+    #[test]
+    #[ignore = "needs root"]
+    fn enter_jail() {
+        let dir = match env::var_os(CHILD_ENV) {
+            Some(dir) => PathBuf::from(dir),
+            None => {
+                let dir = env::temp_dir().join(format!("lumper-jail-{}", std::process::id()));
+                fs::create_dir_all(dir.join("root")).unwrap();
+                fs::write(dir.join("vmlinux"), b"kernel").unwrap();
+
+                let status = Command::new(env::current_exe().unwrap())
+                    .args(["--exact", "jail::tests::enter_jail", "--ignored"])
+                    .env(CHILD_ENV, &dir)
+                    .status()
+                    .unwrap();
+                fs::remove_dir_all(&dir).unwrap();
+                assert!(status.success());
+                return;
+            }
+        };
+
+        // As the VMM does, the files are open and the namespaces unshared before starting
+        // the threads, then the jail is entered from the same thread.
+        let config = JailConfig {
+            uid: NOBODY,
+            gid: NOBODY,
+            chroot: dir.join("root"),
+            unshare: true,
+        };
+        let mut kernel = File::open(dir.join("vmlinux")).unwrap();
+        let mut proc_status = File::open("/proc/self/status").unwrap();
+        unshare().unwrap();
+        let (jailed, wait) = mpsc::channel();
+        let writer = thread::spawn(move || {
+            wait.recv().unwrap();
+            fs::read_dir("/").unwrap().next().is_none()
+        });
+        let other = config.clone();
+        assert!(matches!(
+            thread::spawn(move || enter(&other)).join().unwrap(),
+            Err(Error::NotUnshared)
+        ));
+        enter(&config).unwrap();
+        jailed.send(()).unwrap();
+        // The thread started in between is jailed too.
+        assert!(writer.join().unwrap());
+
+        let mut status = String::new();
+        proc_status.read_to_string(&mut status).unwrap();
+        let nobody = NOBODY.to_string();
+        assert_eq!(status_field(&status, "Uid:"), [nobody.as_str(); 4]);
+        assert_eq!(status_field(&status, "Gid:"), [nobody.as_str(); 4]);
+        assert!(status_field(&status, "Groups:").is_empty());
+
+        let mut content = Vec::new();
+        kernel.read_to_end(&mut content).unwrap();
+        assert_eq!(content, b"kernel");
+        // The paths now resolve in the jail.
+        assert!(File::open(dir.join("vmlinux")).is_err());
+        assert!(fs::read_dir("/").unwrap().next().is_none());
+    }
+}
@@ -2,17 +2,20 @@
 
 #![cfg(target_arch = "x86_64")]
 
-use std::fs::File;
-use std::path::PathBuf;
+use std::io::{self, Read, Seek, SeekFrom};
 use std::result;
 
 use linux_loader::bootparam::boot_params;
 use linux_loader::cmdline::Cmdline;
 use linux_loader::configurator::{linux::LinuxBootConfigurator, BootConfigurator, BootParams};
 use linux_loader::loader::{elf::Elf, load_cmdline, KernelLoader, KernelLoaderResult};
-use vm_memory::{Address, Bytes, GuestAddress, GuestMemory, GuestMemoryMmap};
+use serde::Serialize;
+use vm_memory::{Address, GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
 
-use crate::{Error, Result};
+use crate::config::ImageFile;
+use crate::layout::{self, CMDLINE_START, HIMEM_START, ZEROPG_START};
+use crate::resources::{MemoryMap, RangeKind};
+use crate::{initramfs, Error, Result};
 
 // x86_64 boot constants. See https://www.kernel.org/doc/Documentation/x86/boot.txt for the full
 // documentation.
@@ -27,25 +30,165 @@ const KERNEL_LOADER_OTHER: u8 = 0xff;
 // Header field: `kernel_alignment`. Alignment unit required by a relocatable kernel.
 const KERNEL_MIN_ALIGNMENT_BYTES: u32 = 0x0100_0000;
 
-// Start address for the EBDA (Extended Bios Data Area). Older computers (like the one this VMM
-// emulates) typically use 1 KiB for the EBDA, starting at 0x9fc00.
-// See https://wiki.osdev.org/Memory_Map_(x86) for more information.
-const EBDA_START: u64 = 0x0009_fc00;
+// Header field offsets, in a bzImage.
+const KERNEL_BOOT_FLAG_OFFSET: usize = 0x1fe;
+const KERNEL_HDR_OFFSET: usize = 0x202;
+
+// ELF64 identification and header fields. See elf(5).
+const ELF_MAGIC: [u8; 4] = *b"\x7fELF";
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const ELF64_EHDR_SIZE: u64 = 64;
+const ELF64_PHDR_SIZE: u16 = 56;
+// Program header type of the segments to load.
+const PT_LOAD: u32 = 1;
+
 // RAM memory type.
 // TODO: this should be bindgen'ed and exported by linux-loader.
 // See https://github.com/rust-vmm/linux-loader/issues/51
 const E820_RAM: u32 = 1;
+// Reserved memory type, for the guest physical ranges backed by devices.
+const E820_RESERVED: u32 = 2;
 
-/// Address of the zeropage, where Linux kernel boot parameters are written.
-pub(crate) const ZEROPG_START: u64 = 0x7000;
-
-const HIMEM_START: u64 = 0x0010_0000; // 1 MB
-
-/// Address where the kernel command line is written.
-const CMDLINE_START: u64 = 0x0002_0000;
 // Default command line
 pub const DEFAULT_CMDLINE: &str = "console=ttyS0 i8042.nokbd reboot=k panic=1 pci=off";
 
+/// The kernel image, as read from its headers.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct KernelImage {
+    pub format: &'static str,
+    /// Entry point address.
+    pub entry: u64,
+    /// Guest physical range the segments load to.
+    pub start: u64,
+    pub end: u64,
+}
+
+fn le_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+fn le_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn le_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+/// Read the ELF headers of the kernel, and check that it loads in the `ram` regions, as
+/// (address, size), without loading it.
+///
+/// The kernel must be an uncompressed vmlinux.
+pub fn inspect(ram: &[(GuestAddress, usize)], file: &mut ImageFile) -> Result<KernelImage> {
+    let image = file.source().clone();
+    let open_error = |source: io::Error| Error::KernelOpen {
+        image: image.clone(),
+        source,
+    };
+    let invalid = |reason| Error::KernelHeader {
+        image: image.clone(),
+        reason,
+    };
+
+    // Large enough for the ELF header and the bzImage setup header.
+    let mut header = [0u8; 0x208];
+    let len = file
+        .rewind()
+        .and_then(|_| file.read(&mut header))
+        .map_err(open_error)?;
+    let header = &header[..len];
+    if is_bzimage(header) {
+        return Err(Error::KernelBzImage(image));
+    }
+    if len < ELF64_EHDR_SIZE as usize || header[..4] != ELF_MAGIC {
+        return Err(Error::KernelNotElf(image));
+    }
+    if header[4] != ELFCLASS64 || header[5] != ELFDATA2LSB {
+        return Err(invalid("not a 64-bit little-endian ELF"));
+    }
+
+    let entry = le_u64(header, 0x18);
+    let phoff = le_u64(header, 0x20);
+    let phentsize = le_u16(header, 0x36);
+    let phnum = le_u16(header, 0x38);
+    if phentsize != ELF64_PHDR_SIZE {
+        return Err(invalid("invalid program header size"));
+    }
+    if phoff < ELF64_EHDR_SIZE {
+        return Err(invalid("invalid program header offset"));
+    }
+
+    let mut phdrs = vec![0u8; usize::from(phnum) * usize::from(ELF64_PHDR_SIZE)];
+    file.seek(SeekFrom::Start(phoff))
+        .and_then(|_| file.read_exact(&mut phdrs))
+        .map_err(|_| invalid("truncated program headers"))?;
+
+    // The loader skips the segments without file content.
+    let mut range: Option<(u64, u64)> = None;
+    for phdr in phdrs.chunks_exact(usize::from(ELF64_PHDR_SIZE)) {
+        if le_u32(phdr, 0) != PT_LOAD || le_u64(phdr, 0x20) == 0 {
+            continue;
+        }
+        let paddr = le_u64(phdr, 0x18);
+        if paddr < HIMEM_START {
+            return Err(invalid("segment below the high memory"));
+        }
+        let end = paddr
+            .checked_add(le_u64(phdr, 0x28))
+            .ok_or_else(|| invalid("segment past the address space end"))?;
+        range = Some(match range {
+            Some((start, last)) => (start.min(paddr), last.max(end)),
+            None => (paddr, end),
+        });
+    }
+    let (start, end) = range.ok_or_else(|| invalid("no loadable segment"))?;
+
+    // The kernel goes in the RAM below the MMIO gap, with the boot structures.
+    let ram_end = ram
+        .iter()
+        .find(|(addr, _)| addr.raw_value() == 0)
+        .map_or(0, |(_, size)| *size as u64);
+    if end > ram_end {
+        return Err(Error::KernelPastRam {
+            image,
+            end,
+            ram_end,
+        });
+    }
+
+    Ok(KernelImage {
+        format: "elf",
+        entry,
+        start,
+        end,
+    })
+}
+
+// Whether the header is a bzImage one, which the ELF loader cannot load.
+fn is_bzimage(header: &[u8]) -> bool {
+    header.len() >= KERNEL_HDR_OFFSET + 4
+        && le_u16(header, KERNEL_BOOT_FLAG_OFFSET) == KERNEL_BOOT_FLAG_MAGIC
+        && le_u32(header, KERNEL_HDR_OFFSET) == KERNEL_HDR_MAGIC
+}
+
+/// Where the initramfs goes, past the kernel ending at `kernel_end`, as an address and the
+/// limit it must stay below.
+///
+/// It must stay in the same RAM region, below 4 GiB as its address is 32 bits wide.
+pub fn initramfs_range(ram: &[(GuestAddress, usize)], kernel_end: u64) -> Result<(u64, u64)> {
+    let address = kernel_end + 1;
+    let (start, size) = ram
+        .iter()
+        .find(|(start, size)| {
+            (start.raw_value()..start.raw_value() + *size as u64).contains(&address)
+        })
+        .ok_or(Error::ImagesTooLarge)?;
+    let limit = (start.raw_value() + *size as u64).min(layout::first_addr_past_32bits());
+
+    Ok((address, limit))
+}
+
 fn add_e820_entry(
     params: &mut boot_params,
     addr: u64,
@@ -70,11 +213,11 @@ fn add_e820_entry(
 ///
 /// * `guest_memory` - guest memory
 /// * `himem_start` - address where high memory starts.
-/// * `mmio_gap_start` - address where the MMIO gap starts.
-/// * `mmio_gap_end` - address where the MMIO gap ends.
+/// * `memory_map` - the guest physical ranges, RAM or reserved.
 pub fn build_bootparams(
     guest_memory: &GuestMemoryMmap,
     himem_start: GuestAddress,
+    memory_map: &MemoryMap,
 ) -> std::result::Result<boot_params, Error> {
     let mut params = boot_params::default();
 
@@ -83,19 +226,18 @@ pub fn build_bootparams(
     params.hdr.kernel_alignment = KERNEL_MIN_ALIGNMENT_BYTES;
     params.hdr.type_of_loader = KERNEL_LOADER_OTHER;
 
-    // Add an entry for EBDA itself.
-    add_e820_entry(&mut params, 0, EBDA_START, E820_RAM)?;
-
-    // Add entries for the usable RAM regions.
-    let last_addr = guest_memory.last_addr();
-    add_e820_entry(
-        &mut params,
-        himem_start.raw_value() as u64,
-        last_addr
-            .checked_offset_from(himem_start)
-            .ok_or(Error::HimemStartPastMemEnd)?,
-        E820_RAM,
-    )?;
+    if guest_memory.last_addr() < himem_start {
+        return Err(Error::HimemStartPastMemEnd);
+    }
+    // The RAM goes around the BIOS area, the KVM pages and the MMIO gap. The guest must not
+    // use the other ranges as RAM.
+    for (addr, size, kind) in memory_map.iter() {
+        let mem_type = match kind {
+            RangeKind::Ram => E820_RAM,
+            RangeKind::Reserved => E820_RESERVED,
+        };
+        add_e820_entry(&mut params, addr, size, mem_type)?;
+    }
 
     Ok(params)
 }
@@ -104,66 +246,60 @@ pub fn build_bootparams(
 ///
 /// # Arguments
 ///
-/// * `kernel_cfg` - [`KernelConfig`](struct.KernelConfig.html) struct containing kernel
-///                  configurations.
+/// * `guest_memory` - guest memory.
+/// * `kernel` - the open kernel image.
+/// * `initramfs` - the open initramfs image, if any.
+/// * `decompress_initramfs` - inflate a gzip initramfs on the host.
+/// * `memory_map` - the guest physical ranges, for the E820 map.
 pub fn kernel_setup(
     guest_memory: &GuestMemoryMmap,
-    kernel_path: PathBuf,
-    initramfs_path: Option<String>,
-    cmdline: &Cmdline,
+    kernel: &mut ImageFile,
+    initramfs: Option<&mut ImageFile>,
+    decompress_initramfs: bool,
+    cmdline: &str,
+    memory_map: &MemoryMap,
 ) -> Result<KernelLoaderResult> {
-    let mut kernel_image = File::open(kernel_path).map_err(Error::IO)?;
+    let ram: Vec<_> = guest_memory
+        .iter()
+        .map(|region| (region.start_addr(), region.len() as usize))
+        .collect();
+    // Check the headers first, for a clearer error than the loader's.
+    inspect(&ram, kernel)?;
+
     let zero_page_addr = GuestAddress(ZEROPG_START);
 
     // Load the kernel into guest memory.
-    let kernel_load = Elf::load(
-        guest_memory,
-        None,
-        &mut kernel_image,
-        Some(GuestAddress(HIMEM_START)),
-    )
-    .map_err(Error::KernelLoad)?;
+    let kernel_load = Elf::load(guest_memory, None, kernel, Some(GuestAddress(HIMEM_START)))
+        .map_err(Error::KernelLoad)?;
 
     // Generate boot parameters.
-    let mut bootparams = build_bootparams(guest_memory, GuestAddress(HIMEM_START))?;
-
-    let cmdline_str = cmdline
-        .as_cstring()
-        .map_err(Error::Cmdline)?
-        .into_string()
-        .map_err(Error::IntoStringError)?;
+    let mut bootparams = build_bootparams(guest_memory, GuestAddress(HIMEM_START), memory_map)?;
 
-    let cmdline_size = cmdline_str.len() as u32;
+    let cmdline_size = cmdline.len() as u32;
 
     // Add the kernel command line to the boot parameters.
     bootparams.hdr.cmd_line_ptr = CMDLINE_START as u32;
     bootparams.hdr.cmdline_size = cmdline_size + 1;
 
     // Shrink the command line to the actual size.
-    let mut shrinked_cmdline =
-        linux_loader::cmdline::Cmdline::new(cmdline_size as usize + 1).map_err(Error::Cmdline)?;
+    let mut shrinked_cmdline = Cmdline::new(cmdline_size as usize + 1).map_err(Error::Cmdline)?;
     shrinked_cmdline
-        .insert_str(&cmdline_str)
+        .insert_str(cmdline)
         .map_err(Error::Cmdline)?;
 
     // Add the initramfs to the boot parameters if one was provided.
-    if let Some(initramfs_path) = initramfs_path {
-        // Open the initramfs file
-        let mut initramfs_file = File::open(initramfs_path).map_err(Error::IO)?;
-        let initramfs_size = initramfs_file.metadata().unwrap().len() as usize;
-
-        // Find the address where the initramfs should be loaded.
+    if let Some(initramfs) = initramfs {
         // The initramfs is loaded right after the kernel.
-        let initramfs_address = kernel_load.kernel_end + 1;
+        let (initramfs_address, limit) = initramfs_range(&ram, kernel_load.kernel_end)?;
 
         // Load the initramfs into guest memory.
-        guest_memory
-            .read_from(
-                GuestAddress(initramfs_address),
-                &mut initramfs_file,
-                initramfs_size,
-            )
-            .map_err(|_| Error::InitramfsLoad)?;
+        let initramfs_size = initramfs::load(
+            guest_memory,
+            initramfs,
+            initramfs_address,
+            limit,
+            decompress_initramfs,
+        )?;
 
         // Set the initramfs address and size in the boot parameters.
         bootparams.hdr.ramdisk_image = initramfs_address as u32;
@@ -188,3 +324,309 @@ pub fn kernel_setup(
 
     Ok(kernel_load)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::os::fd::{AsRawFd, FromRawFd};
+
+    use vm_memory::Bytes;
+    use vmm_sys_util::tempfile::TempFile;
+
+    use crate::config::ImageSource;
+    use crate::layout::EBDA_START;
+    use crate::resources::GuestResources;
+
+    // The guest physical ranges, with the `ram` regions.
+    fn resources(ram: &[(GuestAddress, usize)]) -> GuestResources {
+        let mut resources = GuestResources::new().unwrap();
+        for (start, size) in ram {
+            resources.add_ram(start.raw_value(), *size as u64).unwrap();
+        }
+        resources
+    }
+
+    fn e820_entries(params: &boot_params) -> Vec<(u64, u64, u32)> {
+        params.e820_table[..params.e820_entries as usize]
+            .iter()
+            .map(|entry| (entry.addr, entry.size, entry.type_))
+            .collect()
+    }
+
+    // An ELF64 kernel with a single segment, at `paddr` and of `size` bytes in memory.
+    fn elf_kernel(paddr: u64, size: u64) -> TempFile {
+        let mut image = vec![0u8; 0x100];
+        image[..4].copy_from_slice(&ELF_MAGIC);
+        image[4] = ELFCLASS64;
+        image[5] = ELFDATA2LSB;
+        image[0x18..0x20].copy_from_slice(&paddr.to_le_bytes());
+        image[0x20..0x28].copy_from_slice(&ELF64_EHDR_SIZE.to_le_bytes());
+        image[0x36..0x38].copy_from_slice(&ELF64_PHDR_SIZE.to_le_bytes());
+        image[0x38..0x3a].copy_from_slice(&1u16.to_le_bytes());
+
+        let phdr = &mut image[ELF64_EHDR_SIZE as usize..];
+        phdr[..4].copy_from_slice(&PT_LOAD.to_le_bytes());
+        phdr[0x18..0x20].copy_from_slice(&paddr.to_le_bytes());
+        phdr[0x20..0x28].copy_from_slice(&0x10u64.to_le_bytes());
+        phdr[0x28..0x30].copy_from_slice(&size.to_le_bytes());
+
+        let file = TempFile::new().unwrap();
+        file.as_file().write_all(&image).unwrap();
+        file
+    }
+
+    fn open(file: &TempFile) -> ImageFile {
+        ImageSource::Path(file.as_path().into()).open().unwrap()
+    }
+
+    // A sealed memfd with the content of `file`, as quark passes the images.
+    fn sealed_memfd(file: &TempFile) -> File {
+        // Safe because the name is a valid C string, and we check the result.
+        let fd = unsafe {
+            libc::memfd_create(
+                c"image".as_ptr(),
+                libc::MFD_CLOEXEC | libc::MFD_ALLOW_SEALING,
+            )
+        };
+        assert!(fd >= 0);
+        // Safe because the descriptor is ours.
+        let mut memfd = unsafe { File::from_raw_fd(fd) };
+        memfd.write_all(&fs::read(file.as_path()).unwrap()).unwrap();
+
+        let seals =
+            libc::F_SEAL_SEAL | libc::F_SEAL_SHRINK | libc::F_SEAL_GROW | libc::F_SEAL_WRITE;
+        // Safe because it only takes integers, and we check the result.
+        assert_eq!(unsafe { libc::fcntl(fd, libc::F_ADD_SEALS, seals) }, 0);
+        memfd
+    }
+
+    #[test]
+    fn inspect_kernel() {
+        let ram = layout::ram_regions(512 << 20);
+        let file = elf_kernel(0x100_0000, 0x80_0000);
+        assert_eq!(
+            inspect(&ram, &mut open(&file)).unwrap(),
+            KernelImage {
+                format: "elf",
+                entry: 0x100_0000,
+                start: 0x100_0000,
+                end: 0x180_0000,
+            }
+        );
+
+        // Past the end of the RAM.
+        let ram = layout::ram_regions(16 << 20);
+        assert!(matches!(
+            inspect(&ram, &mut open(&file)),
+            Err(Error::KernelPastRam {
+                end: 0x180_0000,
+                ram_end: 0x100_0000,
+                ..
+            })
+        ));
+
+        // Over the boot structures.
+        let file = elf_kernel(0x1000, 0x1000);
+        assert!(matches!(
+            inspect(&ram, &mut open(&file)),
+            Err(Error::KernelHeader { .. })
+        ));
+    }
+
+    #[test]
+    fn not_elf_kernel() {
+        let ram = layout::ram_regions(512 << 20);
+
+        let mut image = vec![0u8; 0x1000];
+        image[KERNEL_BOOT_FLAG_OFFSET..KERNEL_BOOT_FLAG_OFFSET + 2]
+            .copy_from_slice(&KERNEL_BOOT_FLAG_MAGIC.to_le_bytes());
+        image[KERNEL_HDR_OFFSET..KERNEL_HDR_OFFSET + 4]
+            .copy_from_slice(&KERNEL_HDR_MAGIC.to_le_bytes());
+        let file = TempFile::new().unwrap();
+        file.as_file().write_all(&image).unwrap();
+        assert!(matches!(
+            inspect(&ram, &mut open(&file)),
+            Err(Error::KernelBzImage(_))
+        ));
+
+        let file = TempFile::new().unwrap();
+        file.as_file().write_all(b"kernel").unwrap();
+        assert!(matches!(
+            inspect(&ram, &mut open(&file)),
+            Err(Error::KernelNotElf(_))
+        ));
+    }
+
+    #[test]
+    fn memfd_images() {
+        let kernel = elf_kernel(0x100_0000, 0x1000);
+        let initramfs = TempFile::new().unwrap();
+        initramfs.as_file().write_all(b"070701").unwrap();
+        let kernel_memfd = sealed_memfd(&kernel);
+        let initramfs_memfd = sealed_memfd(&initramfs);
+
+        // The boot parameters, the kernel and the initramfs, as loaded in the guest memory.
+        let boot = |kernel: ImageSource, initramfs: ImageSource| {
+            let ram = layout::ram_regions(64 << 20);
+            let guest_memory = GuestMemoryMmap::from_ranges(&ram).unwrap();
+            let load = kernel_setup(
+                &guest_memory,
+                &mut kernel.open().unwrap(),
+                Some(&mut initramfs.open().unwrap()),
+                false,
+                DEFAULT_CMDLINE,
+                resources(&ram).memory_map(),
+            )
+            .unwrap();
+
+            let mut loaded = vec![0u8; 0x3000];
+            guest_memory
+                .read_slice(&mut loaded[..0x1000], GuestAddress(ZEROPG_START))
+                .unwrap();
+            guest_memory
+                .read_slice(&mut loaded[0x1000..], GuestAddress(0x100_0000))
+                .unwrap();
+            (load.kernel_end, loaded)
+        };
+
+        let from_paths = boot(
+            ImageSource::Path(kernel.as_path().into()),
+            ImageSource::Path(initramfs.as_path().into()),
+        );
+        let from_fds = boot(
+            format!("fd:{}", kernel_memfd.as_raw_fd()).parse().unwrap(),
+            format!("/proc/self/fd/{}", initramfs_memfd.as_raw_fd())
+                .parse()
+                .unwrap(),
+        );
+        assert_eq!(from_paths.0, 0x100_1000);
+        assert_eq!(&from_paths.1[0x2001..0x2007], b"070701");
+        assert!(from_paths == from_fds);
+
+        // Again, from the same descriptors.
+        let from_files = boot(kernel_memfd.into(), initramfs_memfd.into());
+        assert!(from_paths == from_files);
+    }
+
+    #[test]
+    fn initramfs_past_the_kernel() {
+        let ram = layout::ram_regions(8 << 30);
+        assert_eq!(
+            initramfs_range(&ram, 0x180_0000).unwrap(),
+            (0x180_0001, layout::IDENTITY_MAP_START)
+        );
+        assert!(matches!(
+            initramfs_range(&ram, layout::IDENTITY_MAP_START),
+            Err(Error::ImagesTooLarge)
+        ));
+    }
+
+    #[test]
+    fn e820_around_the_kvm_pages() {
+        // 3328 MiB of RAM used to run into the KVM pages right below the MMIO gap.
+        let ram = layout::ram_regions(3328 << 20);
+        let memory = GuestMemoryMmap::from_ranges(&ram).unwrap();
+        let params = build_bootparams(
+            &memory,
+            GuestAddress(HIMEM_START),
+            resources(&ram).memory_map(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            e820_entries(&params),
+            [
+                (0, EBDA_START, E820_RAM),
+                (EBDA_START, HIMEM_START - EBDA_START, E820_RESERVED),
+                (
+                    HIMEM_START,
+                    layout::IDENTITY_MAP_START - HIMEM_START,
+                    E820_RAM
+                ),
+                (layout::IDENTITY_MAP_START, 0x4000, E820_RESERVED),
+                (layout::first_addr_past_32bits(), 0x4000, E820_RAM),
+            ]
+        );
+    }
+
+    #[test]
+    fn e820_tiles_the_ram() {
+        let (kvm_pages, kvm_pages_size) = layout::kvm_pages();
+        for size in [64 << 20, 3328 << 20, layout::IDENTITY_MAP_START, 6 << 30] {
+            for (mmio_windows, pmem, firmware) in [
+                (0, false, false),
+                (3, false, false),
+                (1, true, false),
+                (2, true, true),
+            ] {
+                let ram = layout::ram_regions(size);
+                let mut resources = resources(&ram);
+                let mut reserved = vec![
+                    (EBDA_START, HIMEM_START - EBDA_START),
+                    (kvm_pages, kvm_pages_size),
+                ];
+                for window in 0..mmio_windows {
+                    reserved.push((layout::DEVICE_MMIO_START + window * 0x1000, 0x1000));
+                }
+                if pmem {
+                    let ram_end = ram.last().map(|(start, size)| start.0 + *size as u64);
+                    let start = layout::device_memory_start(ram_end.unwrap());
+                    reserved.push(((start + 0x1f_ffff) & !0x1f_ffff, 0x40_0000));
+                }
+                if firmware {
+                    reserved.push((layout::first_addr_past_32bits() - 0x10_0000, 0x10_0000));
+                }
+                // The BIOS area and the KVM pages come reserved.
+                for (start, size) in reserved.iter().skip(2) {
+                    resources.reserve(*start, *size).unwrap();
+                }
+                reserved.sort();
+
+                let memory = GuestMemoryMmap::from_ranges(&ram).unwrap();
+                let params =
+                    build_bootparams(&memory, GuestAddress(HIMEM_START), resources.memory_map())
+                        .unwrap();
+                let entries = e820_entries(&params);
+
+                // Sorted, without overlaps.
+                for pair in entries.windows(2) {
+                    assert!(pair[0].0 + pair[0].1 <= pair[1].0, "{:x?}", pair);
+                }
+                // Every reservation, and nothing else.
+                let reserved_entries: Vec<_> = entries
+                    .iter()
+                    .filter(|entry| entry.2 == E820_RESERVED)
+                    .map(|(addr, size, _)| (*addr, *size))
+                    .collect();
+                assert_eq!(reserved_entries, reserved);
+                // The RAM, but for the reservations.
+                let mut expected = Vec::new();
+                for (start, size) in ram.iter() {
+                    let mut addr = start.0;
+                    let end = start.0 + *size as u64;
+                    for (reserved_start, reserved_size) in reserved.iter() {
+                        let reserved_end = reserved_start + reserved_size;
+                        if *reserved_start < end && addr < reserved_end {
+                            if addr < *reserved_start {
+                                expected.push((addr, reserved_start - addr));
+                            }
+                            addr = reserved_end.min(end);
+                        }
+                    }
+                    if addr < end {
+                        expected.push((addr, end - addr));
+                    }
+                }
+                let ram_entries: Vec<_> = entries
+                    .iter()
+                    .filter(|entry| entry.2 == E820_RAM)
+                    .map(|(addr, size, _)| (*addr, *size))
+                    .collect();
+                assert_eq!(ram_entries, expected, "{} bytes of RAM", size);
+            }
+        }
+    }
+}
@@ -0,0 +1,265 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! x86_64 guest physical memory map.
+//!
+//! ```text
+//!   0x0000_0500  boot GDT, then IDT
+//!   0x0000_7000  zero page, holding the Linux boot parameters
+//!   0x0000_8000  boot stack, growing down from 0x8ff0
+//!   0x0000_9000  boot page tables: PML4, PDPTE, PDE, then the PDE mapping the firmware
+//!   0x0002_0000  kernel command line
+//!   0x0009_fc00  EBDA, holding the MP table
+//!   0x000e_0000  BIOS read-only area, holding the ACPI tables
+//!   0x000f_0000  SMBIOS tables
+//!   0x0010_0000  high memory: the kernel, the initramfs, then RAM up to the KVM pages
+//!   0xcfff_c000  KVM identity map page, then TSS
+//!   0xd000_0000  MMIO gap: device registers, then the IOAPIC and the local APICs, and
+//!                the firmware, if any, around them
+//! 0x1_0000_0000  RAM past the MMIO gap, then device memory
+//! ```
+
+use std::ops::Range;
+
+use vm_memory::GuestAddress;
+
+/// Boot GDT.
+pub(crate) const BOOT_GDT_START: u64 = 0x500;
+/// Size of the boot GDT: 4 entries.
+pub(crate) const BOOT_GDT_SIZE: u64 = 0x20;
+/// Boot IDT, with a single empty entry.
+pub(crate) const BOOT_IDT_START: u64 = BOOT_GDT_START + BOOT_GDT_SIZE;
+const BOOT_IDT_SIZE: u64 = 0x8;
+
+/// Address of the zero page, where the Linux kernel boot parameters are written.
+pub(crate) const ZEROPG_START: u64 = 0x7000;
+const ZEROPG_SIZE: u64 = 0x1000;
+
+/// Initial stack pointer of the boot CPU. The stack grows down to the zero page.
+pub(crate) const BOOT_STACK_POINTER: u64 = 0x8ff0;
+const BOOT_STACK_START: u64 = ZEROPG_START + ZEROPG_SIZE;
+
+/// Boot page tables, identity mapping the first GiB.
+pub(crate) const PML4_START: u64 = 0x9000;
+pub(crate) const PDPTE_START: u64 = 0xa000;
+pub(crate) const PDE_START: u64 = 0xb000;
+/// Page directory identity mapping the GiB the firmware starts in, only filled with a
+/// firmware entry.
+pub(crate) const FIRMWARE_PDE_START: u64 = 0xc000;
+const PAGE_TABLE_SIZE: u64 = 0x1000;
+
+/// Address where the kernel command line is written.
+pub(crate) const CMDLINE_START: u64 = 0x0002_0000;
+/// Maximum size of the kernel command line, including the terminating NUL.
+pub(crate) const CMDLINE_MAX_SIZE: usize = 4096;
+
+/// Start of the EBDA (Extended BIOS Data Area). Older computers, like the one we emulate,
+/// typically use 1 KiB for it, right below 640 KiB. RAM stops there until the high memory.
+/// See https://wiki.osdev.org/Memory_Map_(x86) for more information.
+pub(crate) const EBDA_START: u64 = 0x0009_fc00;
+/// The MP table lives in the EBDA.
+pub(crate) const MPTABLE_START: u64 = EBDA_START;
+
+/// Start of the BIOS read-only area, where the guest looks for the RSDP.
+pub(crate) const ACPI_TABLES_START: u64 = 0x000e_0000;
+/// End of the ACPI tables.
+pub(crate) const ACPI_TABLES_END: u64 = SMBIOS_START;
+
+/// The guest looks for the SMBIOS entry point in the last 64 KiB of the BIOS area.
+pub(crate) const SMBIOS_START: u64 = 0x000f_0000;
+/// End of the BIOS read-only area.
+pub(crate) const SMBIOS_END: u64 = HIMEM_START;
+
+/// Start of the high memory, where the kernel is loaded.
+pub(crate) const HIMEM_START: u64 = 0x0010_0000;
+
+/// Identity mapped page table KVM runs real mode guest code with, on hosts without
+/// unrestricted guest support. The RAM below 4 GiB stops there.
+pub(crate) const IDENTITY_MAP_START: u64 = TSS_START - IDENTITY_MAP_SIZE;
+const IDENTITY_MAP_SIZE: u64 = 0x1000;
+
+/// Three pages KVM keeps the TSS of those guests in. They must not be RAM either.
+pub(crate) const TSS_START: u64 = MMIO_GAP_START - TSS_SIZE;
+const TSS_SIZE: u64 = 0x3000;
+
+/// Start of the hole below 4 GiB that is not RAM, for the device registers.
+pub(crate) const MMIO_GAP_START: u64 = 0xd000_0000;
+
+/// Range where the device registers, e.g. the virtio-mmio ones, are allocated.
+pub(crate) const DEVICE_MMIO_START: u64 = MMIO_GAP_START;
+pub(crate) const DEVICE_MMIO_SIZE: u64 = 0x1000_0000;
+
+/// IOAPIC registers. Source: linux/arch/x86/include/asm/apicdef.h.
+pub(crate) const IOAPIC_START: u64 = 0xfec0_0000;
+const IOAPIC_SIZE: u64 = 0x1000;
+/// Local APIC registers. Source: linux/arch/x86/include/asm/apicdef.h.
+pub(crate) const APIC_START: u64 = 0xfee0_0000;
+const APIC_SIZE: u64 = 0x1000;
+
+/// Size of the range where device memory (pmem) is allocated, past the RAM.
+pub(crate) const DEVICE_MEMORY_SIZE: u64 = 1 << 40;
+
+/// Guest physical range that is not RAM below 4 GiB.
+pub(crate) fn mmio_gap() -> Range<u64> {
+    MMIO_GAP_START..first_addr_past_32bits()
+}
+
+/// The pages of KVM right below the MMIO gap, as (start, size), to reserve in the E820
+/// map.
+pub(crate) fn kvm_pages() -> (u64, u64) {
+    (IDENTITY_MAP_START, MMIO_GAP_START - IDENTITY_MAP_START)
+}
+
+/// Whether `size` bytes of firmware fit at `start`: in the MMIO gap, out of the device
+/// registers.
+pub(crate) fn firmware_fits(start: u64, size: u64) -> bool {
+    let end = match start.checked_add(size) {
+        Some(end) if size > 0 => end,
+        _ => return false,
+    };
+    let devices = [
+        (DEVICE_MMIO_START, DEVICE_MMIO_SIZE),
+        (IOAPIC_START, IOAPIC_SIZE),
+        (APIC_START, APIC_SIZE),
+    ];
+
+    let gap = mmio_gap();
+    gap.start <= start
+        && end <= gap.end
+        && !devices
+            .iter()
+            .any(|(device, device_size)| start < device + device_size && *device < end)
+}
+
+/// First address that does not fit in 32 bits.
+pub(crate) const fn first_addr_past_32bits() -> u64 {
+    1 << 32
+}
+
+/// Where device memory can start, given the end of the RAM: past the MMIO gap.
+pub(crate) fn device_memory_start(ram_end: u64) -> u64 {
+    std::cmp::max(ram_end, first_addr_past_32bits())
+}
+
+/// RAM regions for `size` bytes of guest memory: from 0 up to the KVM pages below the
+/// MMIO gap, and the rest past 4 GiB.
+pub(crate) fn ram_regions(size: u64) -> Vec<(GuestAddress, usize)> {
+    let low_size = size.min(IDENTITY_MAP_START);
+    let mut regions = vec![(GuestAddress(0), low_size as usize)];
+
+    if size > low_size {
+        regions.push((
+            GuestAddress(first_addr_past_32bits()),
+            (size - low_size) as usize,
+        ));
+    }
+
+    regions
+}
+
+// Fixed guest physical ranges, as (start, size), sorted by address.
+const REGIONS: [(u64, u64); 18] = [
+    (BOOT_GDT_START, BOOT_GDT_SIZE),
+    (BOOT_IDT_START, BOOT_IDT_SIZE),
+    (ZEROPG_START, ZEROPG_SIZE),
+    (BOOT_STACK_START, BOOT_STACK_POINTER - BOOT_STACK_START),
+    (PML4_START, PAGE_TABLE_SIZE),
+    (PDPTE_START, PAGE_TABLE_SIZE),
+    (PDE_START, PAGE_TABLE_SIZE),
+    (FIRMWARE_PDE_START, PAGE_TABLE_SIZE),
+    (CMDLINE_START, CMDLINE_MAX_SIZE as u64),
+    (EBDA_START, ACPI_TABLES_START - EBDA_START),
+    (ACPI_TABLES_START, ACPI_TABLES_END - ACPI_TABLES_START),
+    (SMBIOS_START, SMBIOS_END - SMBIOS_START),
+    (HIMEM_START, IDENTITY_MAP_START - HIMEM_START),
+    (IDENTITY_MAP_START, IDENTITY_MAP_SIZE),
+    (TSS_START, TSS_SIZE),
+    (DEVICE_MMIO_START, DEVICE_MMIO_SIZE),
+    (IOAPIC_START, IOAPIC_SIZE),
+    (APIC_START, APIC_SIZE),
+];
+
+const fn disjoint(regions: &[(u64, u64)]) -> bool {
+    let mut i = 1;
+    while i < regions.len() {
+        if regions[i - 1].0 + regions[i - 1].1 > regions[i].0 {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+// Moving a boot structure onto another one would corrupt it silently.
+const _: () = assert!(disjoint(&REGIONS), "guest memory regions overlap");
+const _: () = assert!(APIC_START + APIC_SIZE <= first_addr_past_32bits());
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn regions() {
+        for pair in REGIONS.windows(2) {
+            let ((start, size), (next, _)) = (pair[0], pair[1]);
+            assert!(size > 0);
+            assert!(start + size <= next, "{:#x} overlaps {:#x}", start, next);
+        }
+
+        // The RAM stops at the KVM pages, then the MMIO gap holds all the device registers.
+        assert_eq!(kvm_pages().0, HIMEM_START + REGIONS[11].1);
+        let gap = mmio_gap();
+        assert_eq!(gap.start, kvm_pages().0 + kvm_pages().1);
+        for (start, size) in &REGIONS[14..] {
+            assert!(gap.start <= *start && start + size <= gap.end);
+        }
+    }
+
+    #[test]
+    fn firmware_placement() {
+        assert!(firmware_fits(0xffff_0000, 0x1000));
+        assert!(firmware_fits(0xe000_0000, 0x1000));
+        // Up to 4 GiB, not past it.
+        assert!(firmware_fits(0xffff_f000, 0x1000));
+        assert!(!firmware_fits(0xffff_f000, 0x1001));
+        // The RAM and the device registers.
+        assert!(!firmware_fits(HIMEM_START, 0x1000));
+        assert!(!firmware_fits(kvm_pages().0, 0x1000));
+        assert!(!firmware_fits(DEVICE_MMIO_START, 0x1000));
+        assert!(!firmware_fits(IOAPIC_START - 0x1000, 0x2000));
+        assert!(!firmware_fits(APIC_START, 0x1000));
+        assert!(!firmware_fits(first_addr_past_32bits(), 0x1000));
+        assert!(!firmware_fits(0xffff_0000, 0));
+    }
+
+    #[test]
+    fn ram_around_the_gap() {
+        assert_eq!(ram_regions(512 << 20), vec![(GuestAddress(0), 512 << 20)]);
+        assert_eq!(
+            ram_regions(IDENTITY_MAP_START),
+            vec![(GuestAddress(0), IDENTITY_MAP_START as usize)]
+        );
+        // Up to the MMIO gap, 3328 MiB used to cover the KVM pages: the last 16 KiB go
+        // past 4 GiB now.
+        assert_eq!(
+            ram_regions(3328 << 20),
+            vec![
+                (GuestAddress(0), IDENTITY_MAP_START as usize),
+                (GuestAddress(first_addr_past_32bits()), 0x4000),
+            ]
+        );
+
+        let regions = ram_regions(8 << 30);
+        assert_eq!(
+            regions,
+            vec![
+                (GuestAddress(0), IDENTITY_MAP_START as usize),
+                (
+                    GuestAddress(first_addr_past_32bits()),
+                    (8 << 30) - IDENTITY_MAP_START as usize
+                ),
+            ]
+        );
+        let total: usize = regions.iter().map(|(_, size)| size).sum();
+        assert_eq!(total, 8 << 30);
+    }
+}
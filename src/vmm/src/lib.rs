@@ -1,6 +1,37 @@
 // SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
 
-#![cfg(target_arch = "x86_64")]
+//! A minimal VMM running Linux guests on KVM.
+//!
+//! A [`VMMConfig`](config::VMMConfig) describes the VM, [`VMM::from_config`] sets it up,
+//! and [`VMM::run`] runs it until it stops, telling why with an [`ExitReason`]:
+//!
+//! ```no_run
+//! use std::path::PathBuf;
+//!
+//! use vmm::config::VMMConfigBuilder;
+//! use vmm::{ExitReason, VMM};
+//!
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let config = VMMConfigBuilder::default()
+//!     .kernel(PathBuf::from("vmlinux"))
+//!     .memory(256 << 20)
+//!     .build()?;
+//!
+//! let mut vmm = VMM::from_config(&config)?;
+//! match vmm.run()? {
+//!     ExitReason::GuestShutdown => println!("the guest stopped"),
+//!     reason => println!("the guest did not stop by itself: {:?}", reason),
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The configuration, the constructors and the run loop are the stable API, along with
+//! the types they take and return. The [`agent`], [`api`] and [`events`] modules follow
+//! the protocols they document. How the VM is put together is private, and may change in
+//! any release.
+
+#![cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
 
 extern crate libc;
 
@@ -8,154 +39,1786 @@ extern crate linux_loader;
 extern crate vm_memory;
 extern crate vm_superio;
 
+use std::collections::BTreeMap;
 use std::fs::File;
-use std::io::stdout;
-use std::os::unix::io::AsRawFd;
-use std::os::unix::prelude::RawFd;
+use std::io;
+use std::io::{stdout, IsTerminal, Read, Write};
+#[cfg(target_arch = "x86_64")]
+use std::net::SocketAddrV4;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
 use std::sync::{Arc, Mutex};
-use std::thread;
-use std::{io, path::PathBuf};
-
-use devices::net::tap::Tap;
-use devices::net::VirtioNet;
-use kvm_bindings::{kvm_userspace_memory_region, KVM_MAX_CPUID_ENTRIES};
-use kvm_ioctls::{Kvm, VmFd};
-use linux_loader::loader::{self, KernelLoaderResult};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use devices::net::bridge::TapBridge;
+use devices::net::dhcp::TapDhcpServer;
+pub use devices::net::interface::NetInterface;
+use devices::net::slot::NetSlot;
+pub use devices::net::tap::Tap;
+use devices::net::vhost::{VhostNet, VHOST_QUEUES};
+use devices::net::{NetStats, VirtioNet, WorkerHandle};
+#[cfg(target_arch = "x86_64")]
+use kvm_bindings::{
+    kvm_clock_data, kvm_enable_cap, KVM_CAP_SPLIT_IRQCHIP, KVM_CAP_X2APIC_API,
+    KVM_MAX_CPUID_ENTRIES, KVM_X2APIC_API_DISABLE_BROADCAST_QUIRK, KVM_X2APIC_API_USE_32BIT_IDS,
+};
+use kvm_bindings::{kvm_userspace_memory_region, KVM_MEM_LOG_DIRTY_PAGES, KVM_MEM_READONLY};
+use kvm_ioctls::{Cap, IoEventAddress, Kvm, VmFd};
+use linux_loader::loader;
+use serde::Serialize;
 use vm_device::device_manager::IoManager;
 use vm_device::resources::Resource;
-use vm_memory::{Address, GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
+use vm_memory::{
+    Address, Bytes, FileOffset, GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion,
+    GuestRegionMmap, MmapRegion,
+};
 use vmm_sys_util::eventfd::EventFd;
 use vmm_sys_util::terminal::Terminal;
+mod aarch64;
+mod acpi;
+#[cfg(target_arch = "aarch64")]
+use aarch64::fdt::MmioDevice;
+#[cfg(target_arch = "aarch64")]
+use aarch64::{kernel, layout};
+pub mod agent;
+use agent::{Agent, AgentChannel, AgentWriter};
+pub mod api;
+use api::{ApiRequest, ApiResponse, ApiSocket, IrqLatencyReport, MemoryFd, Stats, VcpuCounters};
+pub mod config;
+use config::{
+    CmdlineBuilder, ConsoleErrorPolicy, ConsoleMode, CpuTopology, ImageFile, ImageSource, KsmMode,
+    MacAddress, MemoryBackend, MemoryShare, NetBackend, NetConfig, PmemConfig, ReplayConfig,
+    SharedDirConfig, ThpMode, VMMConfig, WatchdogAction, WatchdogConfig,
+};
+#[cfg(target_arch = "x86_64")]
+use config::{CpuTemplate, FirmwareConfig, GdbConfig, IrqchipMode, PvFeatures, SmbiosConfig, Uuid};
+pub mod capabilities;
+pub mod cgroup;
 mod cpu;
-use cpu::{cpuid, mptable, Vcpu};
+#[cfg(target_arch = "x86_64")]
+use cpu::hotplug::VcpuHotplug;
+#[cfg(target_arch = "x86_64")]
+use cpu::{cpuid, mptable, msrs, templates};
+use cpu::{Vcpu, VcpuHandle, VcpuRunState, VcpuStats};
 mod devices;
-use devices::serial::LumperSerial;
-use vm_allocator::IdAllocator;
+#[cfg(target_arch = "x86_64")]
+use devices::acpi_pm::{AcpiPm, ACPI_PM_PORT_SIZE, PM1A_EVT_BLK};
+use devices::async_writer::{AsyncWriter, FlushHandle, OnSinkError, OUTPUT_QUEUE_SIZE};
+use devices::console_input::ConsoleInput;
+pub use devices::console_scanner::MarkerWatch;
+use devices::console_scanner::ScanningWriter;
+use devices::console_session::{SessionRecorder, SessionReplay};
+#[cfg(target_arch = "x86_64")]
+use devices::cpu_hotplug::{CpuHotplug, CPU_HOTPLUG_PORT, CPU_HOTPLUG_PORT_SIZE};
+use devices::hand_back::{HandBackWriter, SinkSlot};
+#[cfg(target_arch = "x86_64")]
+use devices::i8042::{I8042, I8042_COMMAND_PORT, I8042_COMMAND_PORT_SIZE};
+#[cfg(target_arch = "x86_64")]
+use devices::ioapic::{Ioapic, IOAPIC_MMIO_SIZE, IOAPIC_PINS};
+use devices::irq_trace::{self, IrqTrace};
+use devices::log_file::LogFile;
+use devices::p9::Virtio9p;
+use devices::pio::UnknownPorts;
+use devices::pmem::{VirtioPmem, PMEM_ALIGNMENT};
+#[cfg(target_arch = "x86_64")]
+use devices::pvpanic::{Pvpanic, PVPANIC_PORT, PVPANIC_PORT_SIZE};
+#[cfg(target_arch = "x86_64")]
+use devices::rtc::{Rtc, RTC_PORT, RTC_PORT_SIZE};
+use devices::serial::{self, LumperSerial, SerialStats, COM1, COM2};
+use devices::tee::TeeWriter;
+use devices::watchdog::{Watchdog, WatchdogHandler, WATCHDOG_MMIO_SIZE};
+use vm_allocator::{AddressAllocator, AllocPolicy, IdAllocator};
 
 mod epoll_context;
-use epoll_context::{EpollContext, EPOLL_EVENTS_LEN};
+use epoll_context::{EpollContext, EventHandler, EventOps, Events, Interest, Token};
+pub mod events;
+use events::{ActivityWriter, Event, EventSink};
+#[cfg(target_arch = "x86_64")]
+mod firmware;
+#[cfg(target_arch = "x86_64")]
+use firmware::Firmware;
+#[cfg(target_arch = "x86_64")]
+mod fork;
+mod gdb;
+#[cfg(target_arch = "x86_64")]
+use gdb::GdbStub;
+pub mod host;
+mod initramfs;
+pub use initramfs::InitramfsImage;
+pub mod inspect;
+use inspect::BootCheck;
+#[cfg(target_arch = "x86_64")]
+mod irq;
+pub mod jail;
+#[cfg(target_arch = "x86_64")]
+use irq::{GsiAllocator, IrqRoute, IrqTrigger};
+#[cfg(target_arch = "x86_64")]
 mod kernel;
-
-const CMDLINE_MAX_SIZE: usize = 4096;
-
-#[derive(Debug)]
+pub use kernel::KernelImage;
+#[cfg(target_arch = "x86_64")]
+mod layout;
+use layout::{CMDLINE_MAX_SIZE, DEVICE_MEMORY_SIZE, DEVICE_MMIO_SIZE, DEVICE_MMIO_START};
+pub mod logging;
+mod memory_hints;
+use memory_hints::Advice;
+mod memory_share;
+use memory_share::SharedMemory;
+mod pause;
+use pause::VmPause;
+mod signals;
+use signals::SignalFd;
+mod rate_limiter;
+use rate_limiter::RateLimiter;
+pub mod quardle;
+#[cfg(target_arch = "x86_64")]
+mod reboot;
+#[cfg(target_arch = "x86_64")]
+use reboot::BootState;
+mod resource_lock;
+use resource_lock::TapLock;
+#[cfg(target_arch = "x86_64")]
+mod resources;
+#[cfg(target_arch = "x86_64")]
+use resources::GuestResources;
+pub mod slip;
+mod smbios;
 
 /// VMM errors.
+#[derive(Debug, thiserror::Error)]
 pub enum Error {
     /// Failed to write boot parameters to guest memory.
-    BootConfigure(linux_loader::configurator::Error),
+    #[error("failed to write the boot parameters")]
+    BootConfigure(#[from] linux_loader::configurator::Error),
     /// Error configuring the kernel command line.
-    Cmdline(linux_loader::cmdline::Error),
+    #[error("invalid kernel command line")]
+    Cmdline(#[from] linux_loader::cmdline::Error),
+    /// The kernel command line has conflicting parameters, or does not fit.
+    #[error("failed to compose the kernel command line")]
+    CmdlineCompose(#[source] config::Error),
+    /// Failed to open the kernel image.
+    #[error("failed to open kernel {image}")]
+    KernelOpen {
+        image: ImageSource,
+        #[source]
+        source: io::Error,
+    },
+    /// The kernel image is not an ELF file.
+    #[error(
+        "invalid ELF magic in kernel {0}, is this a bzImage? An uncompressed vmlinux is needed"
+    )]
+    KernelNotElf(ImageSource),
+    /// The kernel image is a bzImage, which is not supported.
+    #[error("kernel {0} is a bzImage, an uncompressed vmlinux is needed")]
+    KernelBzImage(ImageSource),
+    /// The kernel ELF headers are not valid.
+    #[error("invalid kernel {image}: {reason}")]
+    KernelHeader {
+        image: ImageSource,
+        reason: &'static str,
+    },
+    /// The kernel loads past the end of the guest RAM.
+    #[error("kernel {image} loads up to {end:#x}, past the guest RAM end {ram_end:#x}")]
+    KernelPastRam {
+        image: ImageSource,
+        end: u64,
+        ram_end: u64,
+    },
     /// Failed to load kernel.
-    KernelLoad(loader::Error),
+    #[error("failed to load kernel")]
+    KernelLoad(#[from] loader::Error),
+    /// Failed to open the initramfs.
+    #[error("failed to open initramfs {image}")]
+    InitramfsOpen {
+        image: ImageSource,
+        #[source]
+        source: io::Error,
+    },
     /// Failed to load initrd.
-    InitramfsLoad,
+    #[error("failed to load initramfs {image}")]
+    InitramfsLoad {
+        image: ImageSource,
+        #[source]
+        source: vm_memory::GuestMemoryError,
+    },
+    /// Failed to decompress the initramfs.
+    #[error("failed to decompress initramfs {image}")]
+    InitramfsDecompress {
+        image: ImageSource,
+        #[source]
+        source: io::Error,
+    },
+    /// The decompressed initramfs does not fit in the guest memory.
+    #[error(
+        "the decompressed initramfs {image} does not fit in the guest memory below {limit:#x}"
+    )]
+    InitramfsTooLarge { image: ImageSource, limit: u64 },
     /// Invalid E820 configuration.
+    #[error("invalid E820 configuration")]
     E820Configuration,
     /// Highmem start address is past the guest memory end.
+    #[error("the high memory start address is past the guest memory end")]
     HimemStartPastMemEnd,
+    /// The kernel and the initramfs do not fit in the guest memory.
+    #[error("the kernel and the initramfs do not fit in the guest memory")]
+    ImagesTooLarge,
     /// I/O error.
-    IO(io::Error),
+    #[error("I/O error")]
+    IO(#[source] io::Error),
     /// Error issuing an ioctl to KVM.
-    KvmIoctl(kvm_ioctls::Error),
+    #[error("KVM ioctl failed")]
+    KvmIoctl(#[source] kvm_ioctls::Error),
+    /// /dev/kvm does not exist, KVM is not available on this host.
+    #[error("/dev/kvm does not exist: is KVM enabled and its module loaded?")]
+    KvmNotFound,
+    /// The current user is not allowed to open /dev/kvm.
+    #[error(
+        "permission denied opening /dev/kvm: add your user to the kvm group \
+         (`sudo usermod -aG kvm $USER`) and log in again"
+    )]
+    KvmPermissionDenied,
+    /// Unsupported KVM API version.
+    #[error("unsupported KVM API version {0} (expected 12)")]
+    KvmApiVersion(i32),
+    /// A TSC frequency was requested, but the host cannot scale the TSC.
+    #[error("the host KVM cannot set the guest TSC frequency (no KVM_CAP_TSC_CONTROL)")]
+    TscControlUnsupported,
+    /// The host KVM lacks capabilities the VMM relies on.
+    #[error("the host KVM is missing required capabilities: {}", .0.join(", "))]
+    MissingKvmCapability(Vec<&'static str>),
+    /// The host KVM does not route the interrupts with x2APIC IDs.
+    #[cfg(target_arch = "x86_64")]
+    #[error("failed to enable the KVM x2APIC API")]
+    X2ApicApi(#[source] kvm_ioctls::Error),
+    /// The host KVM cannot leave the IOAPIC to the VMM.
+    #[cfg(target_arch = "x86_64")]
+    #[error("the host KVM cannot split the irqchip (no KVM_CAP_SPLIT_IRQCHIP)")]
+    SplitIrqchip(#[source] kvm_ioctls::Error),
+    /// Failed to start the userspace IOAPIC worker thread.
+    #[cfg(target_arch = "x86_64")]
+    #[error("failed to start the IOAPIC worker")]
+    IoapicWorker(#[source] io::Error),
+    /// The host KVM supports fewer vCPUs than requested.
+    #[error("the host KVM supports at most {max} vCPUs, {requested} were requested")]
+    TooManyVcpus { requested: u32, max: usize },
     /// vCPU errors.
-    Vcpu(cpu::Error),
+    #[error("failed to configure vCPU")]
+    Vcpu(#[from] cpu::Error),
+    /// Failed to write the ACPI tables.
+    #[cfg(target_arch = "x86_64")]
+    #[error("failed to set up ACPI")]
+    Acpi(#[from] acpi::Error),
+    /// Failed to set up the SMBIOS tables.
+    #[cfg(target_arch = "x86_64")]
+    #[error("failed to set up SMBIOS")]
+    Smbios(#[from] smbios::Error),
+    /// Failed to generate the UUID of the VM.
+    #[cfg(target_arch = "x86_64")]
+    #[error("failed to generate the VM UUID")]
+    Uuid(#[source] io::Error),
+    /// Failed to build the device tree.
+    #[cfg(target_arch = "aarch64")]
+    #[error("failed to build the device tree")]
+    Fdt(#[from] aarch64::fdt::Error),
     /// Memory error.
-    Memory(vm_memory::Error),
+    #[error("failed to allocate guest memory")]
+    Memory(#[from] vm_memory::Error),
     /// Serial creation error
-    SerialCreation(io::Error),
+    #[error("failed to create serial port")]
+    SerialCreation(#[source] io::Error),
     /// IRQ registration error
-    IrqRegister(io::Error),
+    #[error("failed to register IRQ")]
+    IrqRegister(#[source] io::Error),
     /// epoll creation error
-    EpollError(io::Error),
+    #[error("epoll error")]
+    EpollError(#[source] io::Error),
+    /// Signal handling error
+    #[error("failed to handle signals")]
+    Signal(#[source] io::Error),
     /// STDIN read error
-    StdinRead(kvm_ioctls::Error),
+    #[error("failed to read from stdin")]
+    StdinRead(#[source] kvm_ioctls::Error),
+    /// Console input read error
+    #[error("failed to read the console input")]
+    ConsoleInputRead(#[source] io::Error),
+    /// Second serial port input read error
+    #[error("failed to read the second serial port input")]
+    Serial2Read(#[source] io::Error),
     /// STDIN write error
+    #[error("failed to write to the serial port: {0:?}")]
     StdinWrite(vm_superio::serial::Error<io::Error>),
     /// Terminal configuration error
-    TerminalConfigure(kvm_ioctls::Error),
+    #[error("failed to configure the terminal")]
+    TerminalConfigure(#[source] kvm_ioctls::Error),
     /// Console configuration error
-    ConsoleError(io::Error),
+    #[error("failed to open console {path:?}")]
+    ConsoleError {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    /// Another lumper instance uses the console file or the tap, see `--no-lock`.
+    #[error(
+        "{resource} is in use by another lumper instance{}, see --no-lock",
+        holder(.holder_pid)
+    )]
+    ResourceBusy {
+        resource: String,
+        holder_pid: Option<u32>,
+    },
+    /// Failed to lock the console file or the tap.
+    #[error("failed to lock {resource}")]
+    ResourceLock {
+        resource: String,
+        #[source]
+        source: io::Error,
+    },
+    /// Console session error
+    #[error("failed to open console session {path:?}")]
+    ConsoleSession {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    /// The console output diverged from the replayed session
+    #[error("the console output diverged from the replayed session: {0}")]
+    ReplayDiverged(String),
+    /// The agent channel was requested for the console
+    #[error("the agent channel can only be used on the second serial port")]
+    AgentConsole,
     /// Allocator error
-    Allocator(vm_allocator::Error),
+    #[error("resource allocator error")]
+    Allocator(#[from] vm_allocator::Error),
     /// IntoString error
-    IntoStringError(std::ffi::IntoStringError),
+    #[error("invalid kernel command line string")]
+    IntoStringError(#[from] std::ffi::IntoStringError),
     /// Error writing to the guest memory.
-    GuestMemory(vm_memory::guest_memory::Error),
+    #[error("failed to write to guest memory")]
+    GuestMemory(#[from] vm_memory::guest_memory::Error),
+    /// Failed to create a rate limiter.
+    #[error("failed to create rate limiter")]
+    RateLimiter(#[source] vmm_sys_util::errno::Error),
+    /// Failed to create a virtio-pmem device.
+    #[error("failed to set up pmem device {path}")]
+    VirtioPmem {
+        path: PathBuf,
+        #[source]
+        source: devices::pmem::Error,
+    },
+    /// Failed to load the firmware blob.
+    #[cfg(target_arch = "x86_64")]
+    #[error("failed to load firmware {path}")]
+    Firmware {
+        path: PathBuf,
+        #[source]
+        source: firmware::Error,
+    },
+    /// Failed to listen for the GDB client.
+    #[cfg(target_arch = "x86_64")]
+    #[error("failed to listen for GDB on {addr}")]
+    GdbListen {
+        addr: SocketAddrV4,
+        #[source]
+        source: io::Error,
+    },
+    /// Failed to share a host directory.
+    #[error("failed to share directory {path}")]
+    SharedDir {
+        path: PathBuf,
+        #[source]
+        source: devices::p9::Error,
+    },
+    /// Failed to start the virtio-9p worker thread.
+    #[error("failed to start the virtio-9p worker")]
+    SharedDirWorker(#[source] io::Error),
+    /// Failed to create the watchdog device.
+    #[error("failed to create the watchdog")]
+    Watchdog(#[source] vmm_sys_util::errno::Error),
+    /// Read-only memory slots are not supported.
+    #[error("KVM_CAP_READONLY_MEM is required for read-only pmem devices and firmware")]
+    ReadonlyMemUnsupported,
     /// Error related to the virtio-net device.
-    VirtioNet(devices::net::VirtioNetError),
+    #[error("virtio-net error")]
+    VirtioNet(#[from] devices::net::VirtioNetError),
+    /// Failed to start the virtio-net worker thread.
+    #[error("failed to start the virtio-net worker")]
+    NetWorker(#[source] io::Error),
+    /// Failed to start the DHCP server on the TAP interface.
+    #[error("failed to start the DHCP server on {if_name}")]
+    DhcpServer {
+        if_name: String,
+        #[source]
+        source: io::Error,
+    },
+    /// The bridge to attach the tap to does not exist.
+    #[error("bridge {0} does not exist, create-bridge creates it")]
+    BridgeMissing(String),
+    /// Attaching the tap to a bridge needs CAP_NET_ADMIN.
+    #[error("no permission to attach to bridge {0}, which needs CAP_NET_ADMIN")]
+    BridgePermission(String),
+    /// Failed to attach the tap to its bridge.
+    #[error("failed to attach tap {if_name} to bridge {bridge}")]
+    Bridge {
+        if_name: String,
+        bridge: String,
+        #[source]
+        source: io::Error,
+    },
+    /// Failed to create a hot-plugged virtio-net device.
+    #[error("failed to create a network device on {tap}")]
+    NetHotplug {
+        tap: String,
+        #[source]
+        source: devices::net::VirtioNetError,
+    },
+    /// Every network slot holds a device.
+    #[error("no free network slot")]
+    NetSlotsFull,
+    /// A hot-plugged network device already uses the tap.
+    #[error("a network device already uses {0}")]
+    NetDeviceExists(String),
+    /// No hot-plugged network device uses the tap.
+    #[error("no hot-plugged network device uses {0}")]
+    UnknownNetDevice(String),
+    /// The guest driver did not release the network device, named by its slot.
+    #[error("the guest driver still uses {0}, unbind it first")]
+    NetDeviceInUse(String),
+    /// The MAC address could not be parsed.
+    #[error("invalid MAC address `{0}`")]
+    InvalidMac(String),
+    /// vCPUs cannot be plugged into the paused VM.
+    #[error("the VM is paused")]
+    VmPaused,
+    /// More vCPUs were requested than the guest may have, see `--max-cpus`.
+    #[error("the guest has at most {max} vCPUs, {requested} were requested")]
+    VcpuLimit { requested: u32, max: u32 },
     /// Error related to IOManager.
-    IoManager(vm_device::device_manager::Error),
+    #[error("device manager error")]
+    IoManager(#[from] vm_device::device_manager::Error),
+    /// Failed to open the event FIFO.
+    #[error("failed to open the event FIFO {path:?}")]
+    EventFifo {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    /// Failed to create the API socket.
+    #[error("failed to create the API socket {path:?}")]
+    ApiSocket {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    /// Failed to accept an API connection.
+    #[error("failed to accept an API connection")]
+    ApiAccept(#[source] io::Error),
+    /// The dirty pages are only known with dirty page tracking.
+    #[error("dirty page tracking is not enabled")]
+    DirtyTrackingDisabled,
+    /// Failed to share the guest memory.
+    #[error("failed to share the guest memory")]
+    MemoryShare(#[source] io::Error),
+    /// The guest memory descriptor is only available with a shared guest memory.
+    #[error("the guest memory is not shared")]
+    MemoryNotShared,
+    /// The API asked for a log level which does not exist.
+    #[error("invalid log level {0}, expected off, error, warn, info, debug or trace")]
+    LogLevel(String),
+    /// The guest memory range is not all RAM.
+    #[error("guest memory range {addr:#x}+{len:#x} is not all RAM")]
+    GuestRange { addr: u64, len: usize },
+    /// Failed to open the memory backend file.
+    #[error("failed to open the memory backend {path:?}")]
+    MemoryBackend {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    /// The host did not take a guest memory hint.
+    #[error("failed to advise the host about the guest memory: {advice}")]
+    MemoryAdvice {
+        advice: &'static str,
+        #[source]
+        source: io::Error,
+    },
+    /// The memory backend file is not the size of the guest memory.
+    #[error(
+        "memory backend {path:?} holds {size} bytes, but the guest memory is {expected} bytes"
+    )]
+    MemoryBackendSize {
+        path: PathBuf,
+        size: u64,
+        expected: u64,
+    },
+    /// The VM cannot be cloned, see [`VMM::clone_from_paused`].
+    #[error("cannot clone the VM: {0}")]
+    NotClonable(&'static str),
+    /// Failed to copy the guest RAM for the clones.
+    #[error("failed to copy the guest memory of the template")]
+    CloneMemory(#[source] io::Error),
+    /// Failed to zero the guest RAM, to restart the guest.
+    #[error("failed to clear the guest memory for the reboot")]
+    MemoryReset(#[source] io::Error),
+    /// The VM is not in a state the step applies to, e.g. its devices are configured
+    /// before its memory.
+    #[error("cannot {attempted} a VM in the {from} state")]
+    InvalidStateTransition {
+        from: VmmState,
+        attempted: &'static str,
+    },
+    /// The virtio-net device is configured already.
+    #[error("a virtio-net device is configured already, plug the others in network slots")]
+    NetConfigured,
+    /// The namespaces of the jail could not be entered.
+    #[error("failed to prepare the jail")]
+    Jail(#[source] jail::Error),
+}
+
+// The holder of a busy resource, for its error message.
+fn holder(pid: &Option<u32>) -> String {
+    pid.map(|pid| format!(" (PID {})", pid)).unwrap_or_default()
 }
 
 /// Dedicated [`Result`](https://doc.rust-lang.org/std/result/) type.
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// Why the VMM stopped running the guest.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ExitReason {
+    /// The guest stopped.
+    GuestShutdown,
+    /// The guest asked for a reset.
+    GuestReset,
+    /// The guest kernel panicked.
+    GuestPanic(PanicReport),
+    /// The guest ran for longer than the configured timeout.
+    Timeout,
+    /// The VMM received a termination signal.
+    Signal(i32),
+    /// The guest stopped petting the watchdog. Holds the configured action.
+    WatchdogExpired(WatchdogAction),
+    /// A vCPU failed.
+    VcpuError(String),
+    /// Writing the console output failed, with the stop console error policy.
+    ConsoleError(String),
+    /// The VM was paused through a [`PauseTrigger`]. Its vCPUs are stopped, it can run
+    /// again, or be cloned.
+    Paused,
+}
+
+impl ExitReason {
+    /// The exit code of the lumper binary when the VM stops for this reason, so that
+    /// callers of either the binary or the library tell the reasons apart the same way.
+    ///
+    /// * 0: the guest shut down
+    /// * 1: the guest asked for a reset, or the watchdog expired with action=reset, and
+    ///   the guest did not restart in place
+    /// * 2: a vCPU or the console failed, or the VM paused
+    /// * 3: guest kernel panic
+    /// * 4: the guest ran for longer than the timeout
+    /// * 5: the watchdog expired
+    /// * 128 + n: the VMM received signal n
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ExitReason::GuestShutdown => 0,
+            ExitReason::GuestReset => 1,
+            ExitReason::VcpuError(_) | ExitReason::ConsoleError(_) | ExitReason::Paused => 2,
+            ExitReason::GuestPanic(_) => 3,
+            ExitReason::Timeout => 4,
+            ExitReason::WatchdogExpired(WatchdogAction::Poweroff) => 5,
+            ExitReason::WatchdogExpired(WatchdogAction::Reset) => 1,
+            ExitReason::Signal(signal) => 128 + signal,
+        }
+    }
+}
+
+/// Where the VM is in its setup and its life, see [`VMM::state`]. The VM goes through the
+/// states in order, but for the pauses, back to [`Ready`](VmmState::Ready).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum VmmState {
+    /// The VM exists, with neither memory nor devices.
+    Created,
+    /// The guest RAM is mapped.
+    MemoryConfigured,
+    /// The devices are set up.
+    DevicesConfigured,
+    /// The kernel and the initramfs are in the guest memory. On x86_64 the vCPUs are set up
+    /// while they load, the VM goes straight to [`Ready`](VmmState::Ready) once both are.
+    KernelLoaded,
+    /// The vCPUs are set up, the VM can run.
+    Ready,
+    /// The vCPUs run the guest.
+    Running,
+    /// The VM stopped for good.
+    Stopped,
+}
+
+impl std::fmt::Display for VmmState {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            VmmState::Created => "created",
+            VmmState::MemoryConfigured => "memory-configured",
+            VmmState::DevicesConfigured => "devices-configured",
+            VmmState::KernelLoaded => "kernel-loaded",
+            VmmState::Ready => "ready",
+            VmmState::Running => "running",
+            VmmState::Stopped => "stopped",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// The states the serial ports and their sinks are set up in, before the irqchip.
+const SERIAL_STATES: [VmmState; 3] = [
+    VmmState::Created,
+    VmmState::MemoryConfigured,
+    VmmState::DevicesConfigured,
+];
+// The states the devices are set up in, once the memory is.
+const DEVICE_STATES: [VmmState; 2] = [VmmState::MemoryConfigured, VmmState::DevicesConfigured];
+// The states the tables describing the VM are written in, once the memory is.
+#[cfg(target_arch = "x86_64")]
+const TABLE_STATES: [VmmState; 3] = [
+    VmmState::MemoryConfigured,
+    VmmState::DevicesConfigured,
+    VmmState::KernelLoaded,
+];
+// The states before the VM runs.
+const SETUP_STATES: [VmmState; 5] = [
+    VmmState::Created,
+    VmmState::MemoryConfigured,
+    VmmState::DevicesConfigured,
+    VmmState::KernelLoaded,
+    VmmState::Ready,
+];
+
+/// How the guest reported a kernel panic.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PanicReport {
+    /// The console line reporting it.
+    Console(String),
+    /// The event written to the pvpanic device.
+    Pvpanic(PvpanicEvent),
+    /// The crash system event of a vCPU.
+    SystemEvent,
+}
+
+/// Events of the pvpanic device.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PvpanicEvent {
+    /// The guest kernel panicked.
+    Panicked,
+    /// The guest kernel panicked, and is about to run its crash kernel.
+    CrashLoaded,
+}
+
+/// Where the kernel and the initramfs go in the guest memory.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct BootImages {
+    pub kernel: KernelImage,
+    pub initramfs: Option<InitramfsImage>,
+}
+
+/// Check that the kernel and the initramfs of `config` fit in its guest memory, from
+/// their headers and sizes, without creating a VM nor mapping any memory.
+///
+/// [`VMM::from_config`] runs the same checks before loading them.
+pub fn inspect_images(config: &VMMConfig) -> Result<BootImages> {
+    let ram = layout::ram_regions(config.memory);
+    let (mut kernel_file, initramfs_file) = open_images(config)?;
+    let kernel = kernel::inspect(&ram, &mut kernel_file)?;
+
+    let initramfs = match initramfs_file {
+        Some(mut file) => {
+            let (address, limit) = kernel::initramfs_range(&ram, kernel.end)?;
+            Some(initramfs::inspect(
+                &mut file,
+                address,
+                limit,
+                config.initrd_in_memory,
+            )?)
+        }
+        None => None,
+    };
+
+    Ok(BootImages { kernel, initramfs })
+}
+
+/// Check that the kernel, the initramfs and the command line of `config` go together on
+/// this host: the architectures, the console driver, the init of the initramfs. Only
+/// reading the kernel fails, what may keep the guest from booting is in the problems.
+pub fn check_images(config: &VMMConfig) -> Result<BootCheck> {
+    let mut cmdline = CmdlineBuilder::default();
+    for param in default_cmdline(&config.console) {
+        cmdline.append(param).map_err(Error::CmdlineCompose)?;
+    }
+    if let Some(extra) = config.cmdline.as_deref() {
+        cmdline.append(extra).map_err(Error::CmdlineCompose)?;
+    }
+
+    let (kernel, initramfs) = open_images(config)?;
+    inspect::check(kernel, initramfs, &cmdline, config.serial2.is_some()).map_err(|source| {
+        Error::KernelOpen {
+            image: config.kernel.clone(),
+            source,
+        }
+    })
+}
+
+// The parameters the VMM boots the kernel with, before those of the configuration. Without
+// a console, the kernel does not print to the serial port.
+fn default_cmdline(console: &ConsoleMode) -> impl Iterator<Item = &'static str> + '_ {
+    kernel::DEFAULT_CMDLINE
+        .split(' ')
+        .filter(move |param| *console != ConsoleMode::None || !param.starts_with("console="))
+}
+
+// Open the kernel and the initramfs of `config`.
+fn open_images(config: &VMMConfig) -> Result<(ImageFile, Option<ImageFile>)> {
+    let kernel = config.kernel.open().map_err(|source| Error::KernelOpen {
+        image: config.kernel.clone(),
+        source,
+    })?;
+    let initramfs = config
+        .initramfs
+        .as_ref()
+        .map(|image| {
+            image.open().map_err(|source| Error::InitramfsOpen {
+                image: image.clone(),
+                source,
+            })
+        })
+        .transpose()?;
+
+    Ok((kernel, initramfs))
+}
+
+/// Lets vCPUs and devices ask the VMM event loop to stop.
+pub(crate) struct ExitNotifier {
+    reason: Mutex<Option<ExitReason>>,
+    eventfd: EventFd,
+}
+
+impl ExitNotifier {
+    fn new() -> io::Result<Self> {
+        Ok(ExitNotifier {
+            reason: Mutex::new(None),
+            eventfd: EventFd::new(libc::EFD_NONBLOCK)?,
+        })
+    }
+
+    /// Request the VMM to stop. Only the first reason is kept.
+    pub fn notify(&self, reason: ExitReason) {
+        let mut current = self.reason.lock().unwrap();
+        if current.is_none() {
+            *current = Some(reason);
+            if let Err(e) = self.eventfd.write(1) {
+                eprintln!("Failed to signal the VMM exit: {}", e);
+            }
+        }
+    }
+
+    fn take(&self) -> Option<ExitReason> {
+        self.reason.lock().unwrap().take()
+    }
+}
+
+/// How [`VMM::run_until`] returned.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RunUntilOutcome {
+    /// The console printed the marker. Holds the console output up to its end. The VM is
+    /// paused, [`VMM::resume_run`] runs it on.
+    MarkerSeen(Vec<u8>),
+    /// The console did not print the marker in time. Holds the console output so far. The
+    /// VM is paused too.
+    TimedOut(Vec<u8>),
+    /// The VM stopped for another reason first, as [`VMM::run`] returned it.
+    GuestExited(ExitReason),
+}
+
+/// Pauses a running VM from any thread, e.g. one watching the console output for a
+/// checkpoint. See [`VMM::pause_trigger`].
+#[derive(Clone)]
+pub struct PauseTrigger {
+    exit: Arc<ExitNotifier>,
+}
+
+impl PauseTrigger {
+    /// Stop the vCPUs, and get [`VMM::run`] to return [`ExitReason::Paused`]. This does
+    /// nothing if the VM already stops for another reason.
+    pub fn pause(&self) {
+        self.exit.notify(ExitReason::Paused);
+    }
+}
+
+// Stop the VMM for the reason a vCPU or a device gave.
+impl EventHandler for Arc<ExitNotifier> {
+    fn process(&mut self, _events: Events, ops: &mut EventOps) -> Result<()> {
+        // The VM may run again, e.g. once paused: the next requests must wake it up anew.
+        let _ = self.eventfd.read();
+        if let Some(reason) = self.take() {
+            ops.exit(reason);
+        }
+
+        Ok(())
+    }
+}
+
+// Forwards the VMM stdin to the console.
+struct StdinInput {
+    serial: Arc<Mutex<LumperSerial>>,
+}
+
+impl EventHandler for StdinInput {
+    fn process(&mut self, events: Events, ops: &mut EventOps) -> Result<()> {
+        // The terminal or the pipe went away, nothing more will come.
+        if events.hangup() && !events.readable() {
+            ops.remove(ops.token());
+            return Ok(());
+        }
+
+        let mut out = [0u8; 64];
+        let count = io::stdin()
+            .lock()
+            .read_raw(&mut out)
+            .map_err(Error::StdinRead)?;
+
+        let mut console = self.serial.lock().unwrap();
+        console
+            .enqueue_input(&out[..count])
+            .map_err(Error::StdinWrite)?;
+
+        // Stop reading stdin until the guest catches up, the event loop resumes it.
+        if console.pending_input() > serial::INPUT_BACKLOG_HIGH {
+            ops.pause(ops.token());
+        }
+
+        Ok(())
+    }
+}
+
+// Forwards the bytes received on the second serial port socket to the guest.
+struct Serial2Input {
+    input: UnixStream,
+    serial2: Arc<Mutex<LumperSerial>>,
+}
+
+impl EventHandler for Serial2Input {
+    fn process(&mut self, _events: Events, ops: &mut EventOps) -> Result<()> {
+        let mut out = [0u8; 64];
+
+        loop {
+            let count = match self.input.read(&mut out) {
+                Ok(0) => {
+                    // The peer closed the socket, stop polling it.
+                    ops.remove(ops.token());
+                    return Ok(());
+                }
+                Ok(count) => count,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(Error::Serial2Read(e)),
+            };
+
+            self.serial2
+                .lock()
+                .unwrap()
+                .enqueue_input(&out[..count])
+                .map_err(Error::StdinWrite)?;
+        }
+    }
+}
+
+// Serves the API requests.
+struct ApiHandler {
+    socket: ApiSocket,
+    vm_fd: Arc<VmFd>,
+    guest_memory: GuestMemoryMmap,
+    dirty_tracking: bool,
+    net: Option<Arc<NetStats>>,
+    serial: Arc<SerialStats>,
+    serial2: Option<Arc<SerialStats>>,
+    vcpus: Vec<Arc<VcpuStats>>,
+    irq_traces: Vec<Arc<IrqTrace>>,
+    net_slots: Vec<Arc<Mutex<NetSlot<Arc<GuestMemoryMmap>, NetInterface>>>>,
+    // Whether the taps plugged into the slots are locked, and their locks.
+    lock_taps: bool,
+    tap_locks: Mutex<BTreeMap<String, TapLock>>,
+    #[cfg(target_arch = "x86_64")]
+    vcpu_hotplug: Option<Arc<Mutex<VcpuHotplug>>>,
+    shared_memory: Option<Arc<SharedMemory>>,
+    pause: Arc<VmPause>,
+}
+
+impl ApiHandler {
+    fn request(&self, request: ApiRequest) -> ApiResponse {
+        match request {
+            ApiRequest::DirtyStats => {
+                match read_dirty_log(&self.vm_fd, &self.guest_memory, self.dirty_tracking) {
+                    Ok(bitmap) => ApiResponse::DirtyStats {
+                        dirty_pages: bitmap.dirty_pages(),
+                        page_size: DirtyBitmap::PAGE_SIZE,
+                    },
+                    Err(e) => ApiResponse::Error {
+                        error: e.to_string(),
+                    },
+                }
+            }
+            ApiRequest::Stats => ApiResponse::Stats(Stats {
+                net: self.net.as_ref().map(|stats| stats.counters()),
+                serial: self.serial.counters(),
+                serial2: self.serial2.as_ref().map(|stats| stats.counters()),
+                vcpus: self.vcpus.iter().map(|stats| stats.counters()).collect(),
+            }),
+            ApiRequest::AddNet { tap, mac } => match self.add_net(tap, mac) {
+                Ok(device) => ApiResponse::NetDevice { device },
+                Err(e) => ApiResponse::Error {
+                    error: e.to_string(),
+                },
+            },
+            ApiRequest::RemoveNet { tap } => match self.remove_net(&tap) {
+                Ok(device) => ApiResponse::NetDevice { device },
+                Err(e) => ApiResponse::Error {
+                    error: e.to_string(),
+                },
+            },
+            ApiRequest::IrqLatency => {
+                let traces: Vec<_> = self.irq_traces.iter().map(Arc::as_ref).collect();
+                ApiResponse::IrqLatency(IrqLatencyReport {
+                    enabled: irq_trace::enabled(),
+                    devices: irq_trace::report(&traces),
+                })
+            }
+            ApiRequest::AddVcpu { count } => match self.add_vcpus(count) {
+                Ok(cpus) => ApiResponse::VcpuCount { cpus },
+                Err(e) => ApiResponse::Error {
+                    error: e.to_string(),
+                },
+            },
+            ApiRequest::GetMemoryFd => match self.shared_memory.as_ref() {
+                Some(shared) => ApiResponse::MemoryFd(MemoryFd {
+                    regions: shared.regions(),
+                    fd: shared.read_only_fd(),
+                }),
+                None => ApiResponse::Error {
+                    error: Error::MemoryNotShared.to_string(),
+                },
+            },
+            ApiRequest::SetLogLevel { level, trace_exits } => {
+                match set_log_level(level.as_deref(), trace_exits) {
+                    Ok(()) => log_level(),
+                    Err(e) => ApiResponse::Error {
+                        error: e.to_string(),
+                    },
+                }
+            }
+            ApiRequest::GetLogLevel => log_level(),
+            ApiRequest::Pause => {
+                self.pause.pause();
+                self.info()
+            }
+            ApiRequest::Resume => {
+                self.pause.resume();
+                self.info()
+            }
+            ApiRequest::Info => self.info(),
+        }
+    }
+
+    // The info response.
+    fn info(&self) -> ApiResponse {
+        let state = match self.pause.paused() {
+            true => "paused",
+            false => "running",
+        };
+        ApiResponse::Info {
+            state: state.to_string(),
+        }
+    }
+
+    // Plug `count` vCPUs into the guest. Returns its vCPU count.
+    fn add_vcpus(&self, count: u8) -> Result<u8> {
+        // They would start running.
+        if self.pause.paused() {
+            return Err(Error::VmPaused);
+        }
+        #[cfg(target_arch = "x86_64")]
+        if let Some(hotplug) = self.vcpu_hotplug.as_ref() {
+            let mut hotplug = hotplug.lock().unwrap();
+            let requested = u32::from(hotplug.cpus()) + u32::from(count);
+            if requested > hotplug.max_cpus() {
+                return Err(Error::VcpuLimit {
+                    requested,
+                    max: hotplug.max_cpus(),
+                });
+            }
+            let cpus = hotplug.add(count).map_err(Error::Vcpu)?;
+
+            log::info!("{} vCPUs plugged, the guest has {}", count, cpus);
+            return Ok(cpus);
+        }
+
+        // Without hotplug, the guest has all its vCPUs.
+        let cpus = self.vcpus.len() as u32;
+        Err(Error::VcpuLimit {
+            requested: cpus + u32::from(count),
+            max: cpus,
+        })
+    }
+
+    // Plug a device on `tap` into a free slot. Returns the guest platform device of the
+    // slot.
+    fn add_net(&self, tap: String, mac: Option<String>) -> Result<String> {
+        let mac = mac
+            .map(|mac| {
+                mac.parse::<MacAddress>()
+                    .map_err(|_| Error::InvalidMac(mac))
+            })
+            .transpose()?;
+        if self
+            .net_slots
+            .iter()
+            .any(|slot| slot.lock().unwrap().tap() == Some(tap.as_str()))
+        {
+            return Err(Error::NetDeviceExists(tap));
+        }
+        let mut slot = self
+            .net_slots
+            .iter()
+            .map(|slot| slot.lock().unwrap())
+            .find(|slot| slot.is_empty())
+            .ok_or(Error::NetSlotsFull)?;
+
+        let lock = self.lock_taps.then(|| TapLock::acquire(&tap)).transpose()?;
+
+        let config = NetConfig {
+            backend: NetBackend::Tap(tap.clone()),
+            mac,
+            ..Default::default()
+        };
+        let net = VirtioNet::new(
+            Arc::new(self.guest_memory.clone()),
+            slot.irq_fd().map_err(Error::IrqRegister)?,
+            &config,
+            RateLimiter::new(None, None).map_err(Error::RateLimiter)?,
+            RateLimiter::new(None, None).map_err(Error::RateLimiter)?,
+            None,
+        )
+        .map_err(|source| Error::NetHotplug {
+            tap: tap.clone(),
+            source,
+        })?;
+        slot.plug(tap.clone(), net).map_err(Error::NetWorker)?;
+        if let Some(lock) = lock {
+            self.tap_locks.lock().unwrap().insert(tap.clone(), lock);
+        }
+
+        log::info!("virtio-net device on {} plugged into {}", tap, slot.name());
+        Ok(slot.name().to_string())
+    }
+
+    // Unplug the device on `tap`, once the guest driver released it. Returns the guest
+    // platform device of the slot.
+    fn remove_net(&self, tap: &str) -> Result<String> {
+        let mut slot = self
+            .net_slots
+            .iter()
+            .map(|slot| slot.lock().unwrap())
+            .find(|slot| slot.tap() == Some(tap))
+            .ok_or_else(|| Error::UnknownNetDevice(tap.to_string()))?;
+        // The vCPUs cannot reach the device while we hold the slot.
+        if slot.driver_attached() {
+            return Err(Error::NetDeviceInUse(slot.name().to_string()));
+        }
+        slot.unplug();
+        self.tap_locks.lock().unwrap().remove(tap);
+
+        log::info!(
+            "virtio-net device on {} unplugged from {}",
+            tap,
+            slot.name()
+        );
+        Ok(slot.name().to_string())
+    }
+}
+
+impl EventHandler for ApiHandler {
+    fn process(&mut self, _events: Events, _ops: &mut EventOps) -> Result<()> {
+        self.socket
+            .handle_connections(|request| self.request(request))
+            .map_err(Error::ApiAccept)
+    }
+}
+
 /// Maximum usable IRQ https://www.kernel.org/doc/html/latest/virt/kvm/api.html#kvm-create-irqchip
+#[cfg(target_arch = "x86_64")]
 const IOAPIC_MAX_IRQ: u32 = 23;
-/// IRQ for the serial port
-const SERIAL1_IRQ: u32 = 4;
-/// minimal IRQ for the virtio devices
-const X86_IRQ_BASE: u32 = SERIAL1_IRQ + 1;
+/// How often the console input backlog is checked while stdin polling is paused.
+const INPUT_BACKLOG_POLL: Duration = Duration::from_millis(10);
+/// How long to wait for the serial output to be written when the VMM stops.
+const OUTPUT_FLUSH_TIMEOUT: Duration = Duration::from_secs(1);
+/// Size of the register window of a virtio-mmio device.
+const VIRTIO_MMIO_SIZE: u64 = 0x1000;
+/// Offset of the QueueNotify register, in the virtio-mmio device registers.
+const VIRTIO_MMIO_QUEUE_NOTIFY: u64 = 0x50;
+/// minimal IRQ (IOAPIC pin) for the virtio devices
+#[cfg(target_arch = "x86_64")]
+const X86_IRQ_BASE: u32 = COM1.irq + 1;
+/// SPIs for the virtio devices, past the serial ports.
+#[cfg(target_arch = "aarch64")]
+const AARCH64_IRQ_BASE: u32 = COM2.irq + 1;
+#[cfg(target_arch = "aarch64")]
+const AARCH64_MAX_IRQ: u32 = aarch64::gic::GIC_SPI_COUNT - 1;
+
+// Event loop tokens, the events ready at once are handled in this order.
+const EXIT_TOKEN: Token = Token(0);
+const SIGNALS_TOKEN: Token = Token(1);
+const WATCHDOG_TOKEN: Token = Token(2);
+const API_TOKEN: Token = Token(3);
+const STDIN_TOKEN: Token = Token(4);
+const CONSOLE_INPUT_TOKEN: Token = Token(5);
+const SERIAL2_TOKEN: Token = Token(6);
+const DHCP_TOKEN: Token = Token(7);
+
+// Map the RAM regions from consecutive parts of a template file, privately: the guest
+// writes go to copies of the pages, never to the file.
+fn template_memory(regions: &[(GuestAddress, usize)], path: &Path) -> Result<GuestMemoryMmap> {
+    let backend_error = |source| Error::MemoryBackend {
+        path: path.into(),
+        source,
+    };
+
+    let file = File::open(path).map_err(backend_error)?;
+    let size = file.metadata().map_err(backend_error)?.len();
+    let expected: u64 = regions.iter().map(|(_, size)| *size as u64).sum();
+    // Accessing the guest memory past the end of the file would raise a SIGBUS.
+    if size != expected {
+        return Err(Error::MemoryBackendSize {
+            path: path.into(),
+            size,
+            expected,
+        });
+    }
+
+    map_private(regions, Arc::new(file))
+}
+
+// Map the RAM regions from consecutive parts of `file`, privately.
+fn map_private(regions: &[(GuestAddress, usize)], file: Arc<File>) -> Result<GuestMemoryMmap> {
+    map_file(regions, file, libc::MAP_PRIVATE)
+}
+
+// Map the RAM regions from consecutive parts of `file`, with the mmap(2) `flags`.
+fn map_file(
+    regions: &[(GuestAddress, usize)],
+    file: Arc<File>,
+    flags: libc::c_int,
+) -> Result<GuestMemoryMmap> {
+    let mut offset = 0;
+    let mut guest_regions = Vec::new();
+    for (address, size) in regions {
+        let mapping = MmapRegion::build(
+            Some(FileOffset::from_arc(file.clone(), offset)),
+            *size,
+            libc::PROT_READ | libc::PROT_WRITE,
+            flags | libc::MAP_NORESERVE,
+        )
+        .map_err(vm_memory::Error::MmapRegion)?;
+        guest_regions.push(GuestRegionMmap::new(mapping, *address).map_err(Error::Memory)?);
+        offset += *size as u64;
+    }
+
+    GuestMemoryMmap::from_regions(guest_regions).map_err(Error::Memory)
+}
+
+/// Guest pages written since the previous [`VMM::dirty_bitmap`] call, by RAM region.
+pub struct DirtyBitmap {
+    // Guest address and size of the regions, with one bit per page.
+    regions: Vec<(u64, u64, Vec<u64>)>,
+}
+
+impl DirtyBitmap {
+    /// Size of the pages the bitmap tracks.
+    pub const PAGE_SIZE: u64 = 4096;
+
+    /// Number of dirty pages.
+    pub fn dirty_pages(&self) -> u64 {
+        self.regions
+            .iter()
+            .flat_map(|(_, _, bitmap)| bitmap.iter())
+            .map(|word| u64::from(word.count_ones()))
+            .sum()
+    }
+
+    /// Whether the page holding the guest physical `address` is dirty.
+    pub fn is_dirty(&self, address: u64) -> bool {
+        self.regions
+            .iter()
+            .find(|(start, size, _)| (*start..*start + *size).contains(&address))
+            .is_some_and(|(start, _, bitmap)| {
+                let page = (address - start) / Self::PAGE_SIZE;
+                bitmap[(page / 64) as usize] & (1 << (page % 64)) != 0
+            })
+    }
+}
+
+// Get the pages the guest wrote to, from the dirty log of the RAM slots, and start over.
+fn read_dirty_log(
+    vm_fd: &VmFd,
+    guest_memory: &GuestMemoryMmap,
+    dirty_tracking: bool,
+) -> Result<DirtyBitmap> {
+    if !dirty_tracking {
+        return Err(Error::DirtyTrackingDisabled);
+    }
+
+    // The RAM regions use the first slots, in order.
+    let mut regions = Vec::new();
+    for (slot, region) in guest_memory.iter().enumerate() {
+        let bitmap = vm_fd
+            .get_dirty_log(slot as u32, region.len() as usize)
+            .map_err(Error::KvmIoctl)?;
+        regions.push((region.start_addr().raw_value(), region.len(), bitmap));
+    }
+
+    Ok(DirtyBitmap { regions })
+}
+
+// Change the log level, and turn the vCPU exit trace on or off, as the API asks.
+fn set_log_level(level: Option<&str>, trace_exits: Option<bool>) -> Result<()> {
+    if let Some(level) = level {
+        let level: log::LevelFilter = level
+            .parse()
+            .map_err(|_| Error::LogLevel(level.to_string()))?;
+        logging::set_level(level);
+        log::warn!("Log level set to {}", level);
+    }
+    if let Some(trace_exits) = trace_exits {
+        logging::set_trace_exits(trace_exits);
+    }
+
+    Ok(())
+}
+
+// The log level response.
+fn log_level() -> ApiResponse {
+    ApiResponse::LogLevel {
+        level: logging::level().to_string().to_lowercase(),
+        trace_exits: logging::trace_exits(),
+    }
+}
+
+// Sum up the exits of the vCPU `index` once it stopped.
+fn log_vcpu_counters(index: u64, counters: &VcpuCounters) {
+    let kinds = &counters.exit_kinds;
+    log::info!(
+        "vCPU {}: {} exits (io_in {}, io_out {}, mmio_read {}, mmio_write {}, hlt {}, \
+         other {}), {:?} handling them, last {}",
+        index,
+        counters.exits,
+        kinds.io_in,
+        kinds.io_out,
+        kinds.mmio_read,
+        kinds.mmio_write,
+        kinds.hlt,
+        kinds.other,
+        Duration::from_nanos(counters.handling_ns),
+        counters.last_exit.unwrap_or("none"),
+    );
+}
+
+/// What a VMM may take from the previous one in the same process, and hand back to the
+/// next one, see [`VMM::from_config_with_parts`].
+#[derive(Default)]
+pub struct VmmParts {
+    /// Network interface, already open, e.g. a [`Tap`] handed over by a privileged parent.
+    pub net: Option<NetInterface>,
+    /// Also gets the console output, as the sink of [`VMM::from_config_with_sink`]. A file
+    /// descriptor goes in as a `File`.
+    pub console: Option<Box<dyn Write + Send>>,
+}
 
 pub struct VMM {
-    vm_fd: VmFd,
+    // Which of the configure_* steps apply, and whether it runs.
+    state: VmmState,
+    // Shared with the API handler.
+    vm_fd: Arc<VmFd>,
     kvm: Kvm,
     guest_memory: GuestMemoryMmap,
+    // Log the guest writes to the RAM, see dirty_bitmap().
+    dirty_tracking: bool,
+    memory_backend: Option<MemoryBackend>,
+    // Share the RAM in a memfd, also at a given descriptor, see memory_share.
+    memory_share: Option<MemoryShare>,
+    memory_share_fd: Option<RawFd>,
+    shared_memory: Option<Arc<SharedMemory>>,
+    // The madvise(2) hints for the guest memory, then those the host took.
+    memory_advice: Vec<Advice>,
+    // Fail when the host does not take one of them.
+    strict: bool,
     vcpus: Vec<Vcpu>,
+    // How long mapping the memory, loading the kernel and the initramfs, and creating and
+    // setting the vCPUs up took.
+    memory_configure_time: Duration,
+    kernel_load_time: Duration,
+    vcpu_configure_time: Duration,
+    // Kick the vCPUs, and change their run state, once their threads own them.
+    vcpu_handles: Vec<Arc<VcpuHandle>>,
+    // The configuration the VM was set up from, when it was at once, for the clones.
+    config: Option<VMMConfig>,
+    // The KVM clock when the VM was paused, for the clones to start from.
+    #[cfg(target_arch = "x86_64")]
+    paused_clock: Option<kvm_clock_data>,
+    // A copy of the RAM of the paused VM, which the clones map privately.
+    #[cfg(target_arch = "x86_64")]
+    clone_memory: Mutex<Option<Arc<File>>>,
+    // What the guest boots from, when it restarts on reboot.
+    #[cfg(target_arch = "x86_64")]
+    boot_state: Option<BootState>,
 
     serial: Arc<Mutex<LumperSerial>>,
-    virtio_manager: Arc<Mutex<IoManager>>,
-    virtio_net: Option<Arc<Mutex<VirtioNet<Arc<GuestMemoryMmap>, Tap>>>>,
+    serial2: Option<Arc<Mutex<LumperSerial>>>,
+    // Frames sent by the guest agent, until an AgentChannel takes them.
+    agent_frames: Option<Receiver<Vec<u8>>>,
+    // Accesses to ports no device claims.
+    unknown_ports: Arc<UnknownPorts>,
+    // Serial output queues, flushed before returning from run(), and closed once the VM
+    // stops.
+    output_flushers: Vec<FlushHandle>,
+    // Also gets the console output, once configured.
+    console_sink: Option<Box<dyn Write + Send>>,
+    // Whether the network interface and the console sink came from VmmParts, and go back
+    // to them once the VM stopped, rather than being closed.
+    keep_parts: bool,
+    // Where the console sink goes back once the VM stopped, when kept.
+    console_slot: SinkSlot,
+    // The interface configure_net takes instead of opening one, then the one it gets back
+    // once the VM stopped, when kept.
+    net_interface: Option<NetInterface>,
+    // Whether the console files and the taps are locked, see resource_lock.
+    lock_resources: bool,
+    // The tap of the virtio-net device, locked.
+    net_lock: Option<TapLock>,
+    // The bridge the tap of the virtio-net device is attached to.
+    net_bridge: Option<TapBridge>,
+    // Lifecycle events, see configure_events().
+    events: Option<EventSink>,
+    // Port I/O and MMIO devices.
+    io_manager: Arc<Mutex<IoManager>>,
+    virtio_net: Option<Arc<Mutex<VirtioNet<Arc<GuestMemoryMmap>, NetInterface>>>>,
+    // The thread running the virtio-net I/O, once started.
+    net_worker: Option<WorkerHandle>,
+    // Pauses the running VM for the API, once it is served.
+    vm_pause: Option<Arc<VmPause>>,
+    virtio_pmem: Option<Arc<Mutex<VirtioPmem<Arc<GuestMemoryMmap>>>>>,
+    virtio_9p: Option<Arc<Mutex<Virtio9p<Arc<GuestMemoryMmap>>>>>,
+    // Disabled again when the guest restarts.
+    #[cfg(target_arch = "x86_64")]
+    watchdog: Option<Arc<Mutex<Watchdog>>>,
+    #[cfg(target_arch = "x86_64")]
+    firmware: Option<Firmware>,
+    // Stops the vCPUs for the GDB client, once configured.
+    #[cfg(target_arch = "x86_64")]
+    gdb: Option<Arc<GdbStub>>,
+    // Plugs the vCPUs past the boot ones, when the guest may have more.
+    #[cfg(target_arch = "x86_64")]
+    vcpu_hotplug: Option<Arc<Mutex<VcpuHotplug>>>,
+    // Reserved virtio-mmio windows, which the API plugs virtio-net devices into.
+    net_slots: Vec<Arc<Mutex<NetSlot<Arc<GuestMemoryMmap>, NetInterface>>>>,
 
+    // Dispatches the events of the stdin, the exit requests, the signals, and the devices
+    // the VMM polls.
     epoll: EpollContext,
+    exit: Arc<ExitNotifier>,
+    // Whether the console input comes from the VMM stdin.
+    stdin_attached: bool,
+    // Console input read from a file or a pipe, instead of stdin.
+    console_input: Option<ConsoleInput>,
+    // Records the console session, and replays a recorded one instead of stdin.
+    console_recorder: Option<SessionRecorder>,
+    console_replay: Option<SessionReplay>,
+    // The console marker run_until() waits for.
+    console_watch: Arc<Mutex<Option<MarkerWatch>>>,
+    // How long the guest may run.
+    timeout: Option<Duration>,
+    // Whether the device threads run, from the first run() on.
+    devices_started: bool,
 
-    cmdline: linux_loader::cmdline::Cmdline,
+    cmdline: CmdlineBuilder,
     irq_allocator: IdAllocator,
+    #[cfg(target_arch = "x86_64")]
+    gsi_allocator: GsiAllocator,
+    // Device interrupt eventfds, by GSI, connected to the irqchip by configure_io().
+    irqfds: BTreeMap<u32, EventFd>,
+    // Delivers the interrupts signaled on the eventfds.
+    #[cfg(target_arch = "x86_64")]
+    irq_trigger: IrqTrigger,
+    mmio_allocator: AddressAllocator,
+    // Allocates device memory ranges, once the RAM size is known.
+    device_memory_allocator: Option<AddressAllocator>,
+    // Every guest physical range, RAM or not, which the E820 map comes from.
+    #[cfg(target_arch = "x86_64")]
+    resources: GuestResources,
+    // Guest TSC frequency, in kHz, when known.
+    #[cfg(target_arch = "x86_64")]
+    tsc_khz: Option<u32>,
+    // The virtio-mmio devices, described to the guest in the device tree.
+    #[cfg(target_arch = "aarch64")]
+    virtio_devices: Vec<MmioDevice>,
+    // The virtio-mmio devices on the kernel command line, which the guest numbers in order.
+    #[cfg(target_arch = "x86_64")]
+    cmdline_virtio_devices: u32,
 }
 
 impl VMM {
-    /// Create a new VMM.
-    pub fn new() -> Result<Self> {
+    /// Create a VM from `config`, ready to [`run`](VMM::run).
+    ///
+    /// The kernel and the initramfs are loaded, and every file the VM uses is open.
+    pub fn from_config(config: &VMMConfig) -> Result<Self> {
+        let mut vmm = VMM::new()?;
+        vmm.configure(config)?;
+
+        Ok(vmm)
+    }
+
+    /// Create a VM from `config`, like [`VMM::from_config`], which also sends the console
+    /// output to `sink`, e.g. to relay it in-process.
+    ///
+    /// The sink is written from its own thread: when it is too slow, the output it misses
+    /// is dropped rather than stalling the guest.
+    pub fn from_config_with_sink(config: &VMMConfig, sink: Box<dyn Write + Send>) -> Result<Self> {
+        let mut vmm = VMM::new()?;
+        vmm.console_sink = Some(sink);
+        vmm.configure(config)?;
+
+        Ok(vmm)
+    }
+
+    /// Create a VM from `config`, like [`VMM::from_config`], over what the previous VMM
+    /// handed back with [`VMM::into_parts`], e.g. to run jobs in a row without opening the
+    /// tap again.
+    ///
+    /// The network interface replaces the one the backend of `config` names, which the
+    /// DHCP server and the reconnections still use. When the configuration fails, the parts
+    /// are closed along with the VMM.
+    pub fn from_config_with_parts(config: &VMMConfig, parts: VmmParts) -> Result<Self> {
+        let mut vmm = VMM::new()?;
+        vmm.keep_parts = true;
+        vmm.net_interface = parts.net;
+        vmm.console_sink = parts.console;
+        vmm.configure(config)?;
+
+        Ok(vmm)
+    }
+
+    /// Stop the VM if it still runs, and hand back the network interface and the console
+    /// sink it was created with, for the next VMM to take.
+    ///
+    /// A console sink still stuck in a write once the output timeout expired is not handed
+    /// back.
+    pub fn into_parts(mut self) -> VmmParts {
+        self.teardown(Vec::new());
+
+        let console = self
+            .console_sink
+            .take()
+            .or_else(|| self.console_slot.lock().unwrap().take());
+        VmmParts {
+            net: self.net_interface.take(),
+            console,
+        }
+    }
+
+    // Create a VM without any configuration.
+    pub(crate) fn new() -> Result<Self> {
+        // Handle the termination signals, and the log level one, in the event loop. This
+        // blocks them in all the threads we create later, until the VMM is dropped.
+        let mut handled = signals::EXIT_SIGNALS.to_vec();
+        handled.push(signals::LOG_LEVEL_SIGNAL);
+        let signals = SignalFd::new(&handled).map_err(Error::Signal)?;
+
         // Open /dev/kvm and get a file descriptor to it.
-        let kvm = Kvm::new().map_err(Error::KvmIoctl)?;
+        let kvm = capabilities::open_kvm()?;
+
+        // Fail early on hosts that cannot run our guests, rather than with some
+        // EINVAL from a later ioctl.
+        capabilities::check_kvm_capabilities(&kvm)?;
+
+        // The vCPU threads inherit it.
+        cpu::register_kick_handler()?;
 
         // Create a KVM VM object.
         // KVM returns a file descriptor to the VM object.
-        let vm_fd = kvm.create_vm().map_err(Error::KvmIoctl)?;
+        let vm_fd = Arc::new(kvm.create_vm().map_err(Error::KvmIoctl)?);
+
+        // Give KVM the pages it runs real mode guest code with on hosts without unrestricted
+        // guest support, out of the RAM. This must happen before the vCPUs are created.
+        #[cfg(target_arch = "x86_64")]
+        {
+            vm_fd
+                .set_identity_map_address(layout::IDENTITY_MAP_START)
+                .map_err(Error::KvmIoctl)?;
+            vm_fd
+                .set_tss_address(layout::TSS_START as usize)
+                .map_err(Error::KvmIoctl)?;
+        }
+
+        let serial = Arc::new(Mutex::new(
+            LumperSerial::new(Box::new(stdout())).map_err(Error::SerialCreation)?,
+        ));
+
+        let mut epoll = EpollContext::new().map_err(Error::EpollError)?;
+        let stdin = StdinInput {
+            serial: serial.clone(),
+        };
+        // A regular file or /dev/null, e.g. under systemd, cannot be polled: the console then
+        // has no input.
+        let stdin_attached = match epoll.add(
+            libc::STDIN_FILENO,
+            STDIN_TOKEN,
+            Interest::Read,
+            Box::new(stdin),
+        ) {
+            Ok(()) => true,
+            Err(e) if e.raw_os_error() == Some(libc::EPERM) => {
+                log::info!("stdin cannot be polled, the console has no input");
+                false
+            }
+            Err(e) => return Err(Error::EpollError(e)),
+        };
+
+        let exit = Arc::new(ExitNotifier::new().map_err(Error::EpollError)?);
+        epoll
+            .add(
+                exit.eventfd.as_raw_fd(),
+                EXIT_TOKEN,
+                Interest::Read,
+                Box::new(exit.clone()),
+            )
+            .map_err(Error::EpollError)?;
+        epoll
+            .add(
+                signals.as_raw_fd(),
+                SIGNALS_TOKEN,
+                Interest::Read,
+                Box::new(signals),
+            )
+            .map_err(Error::EpollError)?;
+        let mut io_manager = IoManager::new();
+        COM1.register(&mut io_manager, serial.clone())?;
+        #[cfg(target_arch = "x86_64")]
+        {
+            io_manager.register_pio_resources(
+                Arc::new(Mutex::new(Rtc::new())),
+                &[Resource::PioAddressRange {
+                    base: RTC_PORT,
+                    size: RTC_PORT_SIZE,
+                }],
+            )?;
+            // The reset line reboot=k pulses.
+            io_manager.register_pio_resources(
+                Arc::new(Mutex::new(I8042::new(exit.clone()))),
+                &[Resource::PioAddressRange {
+                    base: I8042_COMMAND_PORT,
+                    size: I8042_COMMAND_PORT_SIZE,
+                }],
+            )?;
+        }
 
-        let epoll = EpollContext::new().map_err(Error::EpollError)?;
-        epoll.add_stdin().map_err(Error::EpollError)?;
+        let mut irqfds = BTreeMap::new();
+        irqfds.insert(
+            COM1.irq,
+            serial
+                .lock()
+                .unwrap()
+                .eventfd()
+                .map_err(Error::IrqRegister)?,
+        );
 
         let vmm = VMM {
+            state: VmmState::Created,
             vm_fd,
             kvm,
             guest_memory: GuestMemoryMmap::default(),
+            dirty_tracking: false,
+            memory_backend: None,
+            memory_share: None,
+            memory_share_fd: None,
+            shared_memory: None,
+            memory_advice: Vec::new(),
+            strict: false,
             vcpus: vec![],
-            serial: Arc::new(Mutex::new(
-                LumperSerial::new(Box::new(stdout())).map_err(Error::SerialCreation)?,
-            )),
+            memory_configure_time: Duration::ZERO,
+            kernel_load_time: Duration::ZERO,
+            vcpu_configure_time: Duration::ZERO,
+            vcpu_handles: Vec::new(),
+            config: None,
+            #[cfg(target_arch = "x86_64")]
+            paused_clock: None,
+            #[cfg(target_arch = "x86_64")]
+            clone_memory: Mutex::new(None),
+            #[cfg(target_arch = "x86_64")]
+            boot_state: None,
+            serial,
+            serial2: None,
+            agent_frames: None,
+            unknown_ports: Arc::new(UnknownPorts::new()),
+            output_flushers: Vec::new(),
+            console_sink: None,
+            keep_parts: false,
+            console_slot: SinkSlot::default(),
+            net_interface: None,
+            lock_resources: true,
+            net_lock: None,
+            net_bridge: None,
+            events: None,
             virtio_net: None,
-            virtio_manager: Arc::new(Mutex::new(IoManager::new())),
+            net_worker: None,
+            vm_pause: None,
+            virtio_pmem: None,
+            virtio_9p: None,
+            #[cfg(target_arch = "x86_64")]
+            watchdog: None,
+            #[cfg(target_arch = "x86_64")]
+            firmware: None,
+            #[cfg(target_arch = "x86_64")]
+            gdb: None,
+            #[cfg(target_arch = "x86_64")]
+            vcpu_hotplug: None,
+            net_slots: Vec::new(),
+            io_manager: Arc::new(Mutex::new(io_manager)),
             epoll,
+            exit,
+            stdin_attached,
+            console_input: None,
+            console_recorder: None,
+            console_replay: None,
+            console_watch: Arc::new(Mutex::new(None)),
+            timeout: None,
+            devices_started: false,
+            #[cfg(target_arch = "x86_64")]
             irq_allocator: IdAllocator::new(X86_IRQ_BASE, IOAPIC_MAX_IRQ)
                 .map_err(Error::Allocator)?,
-            cmdline: linux_loader::cmdline::Cmdline::new(CMDLINE_MAX_SIZE)
-                .map_err(Error::Cmdline)?,
+            #[cfg(target_arch = "aarch64")]
+            irq_allocator: IdAllocator::new(AARCH64_IRQ_BASE, AARCH64_MAX_IRQ)
+                .map_err(Error::Allocator)?,
+            #[cfg(target_arch = "x86_64")]
+            gsi_allocator: GsiAllocator::new().map_err(Error::Allocator)?,
+            irqfds,
+            #[cfg(target_arch = "x86_64")]
+            irq_trigger: IrqTrigger::Kernel,
+            mmio_allocator: AddressAllocator::new(DEVICE_MMIO_START, DEVICE_MMIO_SIZE)
+                .map_err(Error::Allocator)?,
+            device_memory_allocator: None,
+            #[cfg(target_arch = "x86_64")]
+            resources: GuestResources::new().map_err(Error::Allocator)?,
+            cmdline: CmdlineBuilder::new(CMDLINE_MAX_SIZE),
+            #[cfg(target_arch = "x86_64")]
+            tsc_khz: None,
+            #[cfg(target_arch = "aarch64")]
+            virtio_devices: Vec::new(),
+            #[cfg(target_arch = "x86_64")]
+            cmdline_virtio_devices: 0,
         };
 
         Ok(vmm)
     }
 
-    pub fn configure_memory(&mut self, mem_size_mb: u32) -> Result<()> {
-        // Convert memory size from MBytes to bytes.
-        let mem_size = ((mem_size_mb as u64) << 20) as usize;
+    /// Log the pages the guest writes to, for [`VMM::dirty_bitmap`].
+    ///
+    /// This must be called before [`VMM::configure_memory`].
+    pub(crate) fn set_dirty_tracking(&mut self, enabled: bool) {
+        self.dirty_tracking = enabled;
+    }
+
+    /// Lock the console files and the taps, unless disabled, see [`resource_lock`].
+    ///
+    /// This must be called before the console and the network are configured.
+    pub(crate) fn set_resource_locking(&mut self, enabled: bool) {
+        self.lock_resources = enabled;
+    }
+
+    /// Map the guest RAM from `backend`, instead of anonymous memory.
+    ///
+    /// This must be called before [`VMM::configure_memory`].
+    pub(crate) fn set_memory_backend(&mut self, backend: Option<MemoryBackend>) {
+        self.memory_backend = backend;
+    }
+
+    /// Share the guest RAM with other processes, through the API and at the descriptor
+    /// `fd`, see [`api`].
+    ///
+    /// This must be called before [`VMM::configure_memory`].
+    pub(crate) fn set_memory_share(&mut self, share: Option<MemoryShare>, fd: Option<RawFd>) {
+        self.memory_share = share;
+        self.memory_share_fd = fd;
+    }
+
+    /// Advise the host kernel about KSM and transparent huge pages for the guest RAM. It
+    /// only warns about the hints it does not take, unless `strict`.
+    ///
+    /// This must be called before [`VMM::configure_memory`].
+    pub(crate) fn set_memory_hints(&mut self, ksm: Option<KsmMode>, thp: ThpMode, strict: bool) {
+        self.memory_advice = memory_hints::advice(ksm, thp);
+        self.strict = strict;
+    }
+
+    /// Configure `mem_size` bytes of guest RAM.
+    pub(crate) fn configure_memory(&mut self, mem_size: u64) -> Result<()> {
+        self.check_state(&[VmmState::Created], "configure the memory of")?;
+        let start = Instant::now();
 
-        // Create one single memory region, from zero to mem_size.
-        let mem_regions = vec![(GuestAddress(0), mem_size)];
+        // The RAM goes around the MMIO gap.
+        let mem_regions = layout::ram_regions(mem_size);
 
         // Allocate the guest memory from the memory region.
-        let guest_memory = GuestMemoryMmap::from_ranges(&mem_regions).map_err(Error::Memory)?;
+        let guest_memory = match (self.memory_backend.as_ref(), self.memory_share) {
+            (Some(MemoryBackend::File(path)), _) => template_memory(&mem_regions, path)?,
+            (None, Some(MemoryShare::Memfd)) => self.shared_memory(&mem_regions)?,
+            (None, None) => GuestMemoryMmap::from_ranges(&mem_regions).map_err(Error::Memory)?,
+        };
+
+        let mut applied = Vec::new();
+        for advice in self.memory_advice.drain(..) {
+            match memory_hints::advise(&guest_memory, advice) {
+                Ok(()) => applied.push(advice),
+                Err(source) if self.strict => {
+                    return Err(Error::MemoryAdvice {
+                        advice: advice.name,
+                        source,
+                    })
+                }
+                Err(e) => log::warn!(
+                    "the host did not take the {} memory hint: {}",
+                    advice.name,
+                    e
+                ),
+            }
+        }
+        self.memory_advice = applied;
+
+        self.register_memory(guest_memory)?;
+        self.memory_configure_time = start.elapsed();
+        Ok(())
+    }
+
+    // Map the RAM regions from a memfd, which the other processes map too.
+    fn shared_memory(&mut self, regions: &[(GuestAddress, usize)]) -> Result<GuestMemoryMmap> {
+        let shared = SharedMemory::create(regions).map_err(Error::MemoryShare)?;
+        if let Some(fd) = self.memory_share_fd {
+            shared.expose_at(fd).map_err(Error::MemoryShare)?;
+        }
+        let guest_memory = map_file(regions, shared.file(), libc::MAP_SHARED)?;
+        self.shared_memory = Some(Arc::new(shared));
+
+        Ok(guest_memory)
+    }
+
+    // Register the RAM with KVM, and lay the device memory out after it.
+    fn register_memory(&mut self, guest_memory: GuestMemoryMmap) -> Result<()> {
+        self.check_state(&[VmmState::Created], "configure the memory of")?;
 
         // For each memory region in guest_memory:
         // 1. Create a KVM memory region mapping the memory region guest physical address to the host virtual address.
@@ -167,250 +1830,2401 @@ impl VMM {
                 memory_size: region.len() as u64,
                 // It's safe to unwrap because the guest address is valid.
                 userspace_addr: guest_memory.get_host_address(region.start_addr()).unwrap() as u64,
-                flags: 0,
+                flags: if self.dirty_tracking {
+                    KVM_MEM_LOG_DIRTY_PAGES
+                } else {
+                    0
+                },
             };
 
             // Register the KVM memory region with KVM.
             unsafe { self.vm_fd.set_user_memory_region(kvm_memory_region) }
                 .map_err(Error::KvmIoctl)?;
+            #[cfg(target_arch = "x86_64")]
+            self.resources
+                .add_ram(region.start_addr().raw_value(), region.len())
+                .map_err(Error::Allocator)?;
         }
 
+        // Device memory goes after the RAM.
+        let device_memory_start =
+            layout::device_memory_start(guest_memory.last_addr().raw_value() + 1);
+        let device_memory_start =
+            (device_memory_start + PMEM_ALIGNMENT - 1) & !(PMEM_ALIGNMENT - 1);
+        self.device_memory_allocator = Some(
+            AddressAllocator::new(device_memory_start, DEVICE_MEMORY_SIZE)
+                .map_err(Error::Allocator)?,
+        );
+
         self.guest_memory = guest_memory;
+        self.state = VmmState::MemoryConfigured;
 
         Ok(())
     }
 
-    pub fn load_default_cmdline(&mut self) -> Result<()> {
-        self.cmdline
-            .insert_str(kernel::DEFAULT_CMDLINE)
-            .map_err(Error::Cmdline)
+    /// Where the VM is in its setup and its life.
+    pub fn state(&self) -> VmmState {
+        self.state
     }
-    // configure the virtio-net device
-    pub fn configure_net(&mut self, interface: Option<String>) -> Result<()> {
-        let if_name = match interface {
-            Some(if_name) => if_name,
-            None => return Ok(()),
-        };
 
-        // Temporary hardcoded address, see allocator PR
-        let virtio_address = GuestAddress(0xd0000000);
+    // Fail unless the VM is in one of the `allowed` states to `attempted` it.
+    fn check_state(&self, allowed: &[VmmState], attempted: &'static str) -> Result<()> {
+        if allowed.contains(&self.state) {
+            Ok(())
+        } else {
+            Err(Error::InvalidStateTransition {
+                from: self.state,
+                attempted,
+            })
+        }
+    }
 
-        let irq_fd = EventFd::new(libc::EFD_NONBLOCK).map_err(Error::IrqRegister)?;
+    // Set a device up: the VM must have its memory, and has its devices configured after.
+    fn configure_device(&mut self, attempted: &'static str) -> Result<()> {
+        self.check_state(&DEVICE_STATES, attempted)?;
+        self.state = VmmState::DevicesConfigured;
+        Ok(())
+    }
 
-        let virtio_net = VirtioNet::new(
-            Arc::new(self.guest_memory.clone()),
-            irq_fd,
-            if_name.as_str(),
-        )
-        .map_err(Error::VirtioNet)?;
+    /// Get the pages the guest wrote to since the previous call, or since the RAM was
+    /// configured, and start over.
+    ///
+    /// Only the guest writes are logged. The VMM writes to the guest memory, e.g. from the
+    /// virtio devices, are not.
+    pub fn dirty_bitmap(&self) -> Result<DirtyBitmap> {
+        read_dirty_log(&self.vm_fd, &self.guest_memory, self.dirty_tracking)
+    }
 
-        self.epoll
-            .add_fd(virtio_net.as_raw_fd())
-            .map_err(Error::EpollError)?;
-        let mut io_manager = self.virtio_manager.lock().unwrap();
+    /// Read `len` bytes of the guest RAM at the guest physical address `addr`, which may
+    /// span several RAM regions, but no hole.
+    ///
+    /// The guest keeps running: the read races with the vCPUs and the devices, it may see
+    /// the structures they are updating half written, and their writes in another order
+    /// than they made them. Pause the VM for a consistent view.
+    pub fn read_guest(&self, addr: u64, len: usize) -> Result<Vec<u8>> {
+        let address = GuestAddress(addr);
+        let out_of_range = || Error::GuestRange { addr, len };
+        if !self.guest_memory.check_range(address, len) {
+            return Err(out_of_range());
+        }
 
-        self.virtio_net = Some(Arc::new(Mutex::new(virtio_net)));
+        let mut data = vec![0; len];
+        self.guest_memory
+            .read_slice(&mut data, address)
+            .map_err(|_| out_of_range())?;
+        Ok(data)
+    }
 
-        io_manager
-            .register_mmio_resources(
+    // Start the guest command line with the default parameters. A guest without a console
+    // does not write its own to the serial port, unless the command line of the user says
+    // otherwise.
+    pub(crate) fn load_default_cmdline(&mut self, console: &ConsoleMode) -> Result<()> {
+        for param in default_cmdline(console) {
+            self.cmdline.append(param).map_err(Error::CmdlineCompose)?;
+        }
+
+        Ok(())
+    }
+    // configure the virtio-net device
+    pub(crate) fn configure_net(&mut self, net: Option<&NetConfig>) -> Result<()> {
+        self.configure_device("configure the network of")?;
+        let net = match net {
+            Some(net) => net,
+            None => return Ok(()),
+        };
+        // The previous device would keep its interrupt and its worker.
+        if self.virtio_net.is_some() {
+            return Err(Error::NetConfigured);
+        }
+
+        // Bandwidth buckets allow bursts of one second of traffic unless told otherwise,
+        // operations buckets always do.
+        let burst = |rate: u64| net.burst.unwrap_or(rate);
+        let rx_limiter = RateLimiter::new(
+            net.rx_rate.map(|rate| (rate, burst(rate))),
+            net.rx_ops.map(|ops| (ops, ops)),
+        )
+        .map_err(Error::RateLimiter)?;
+        let tx_limiter = RateLimiter::new(
+            net.tx_rate.map(|rate| (rate, burst(rate))),
+            net.tx_ops.map(|ops| (ops, ops)),
+        )
+        .map_err(Error::RateLimiter)?;
+
+        // Before opening the tap, whose frames another instance would get.
+        if let (true, NetBackend::Tap(if_name)) = (self.lock_resources, &net.backend) {
+            self.net_lock = Some(TapLock::acquire(if_name)?);
+        }
+        if let Some(bridge) = net.bridge.as_ref() {
+            TapBridge::check_permission(bridge)?;
+        }
+
+        let virtio_address = self.allocate_mmio(VIRTIO_MMIO_SIZE)?;
+        let (irq, gsi) = self.allocate_device_irq()?;
+
+        let irq_fd = EventFd::new(libc::EFD_NONBLOCK).map_err(Error::IrqRegister)?;
+        self.irqfds
+            .insert(gsi, irq_fd.try_clone().map_err(Error::IrqRegister)?);
+
+        let vhost = if net.vhost {
+            VhostNet::new()
+                .map_err(|e| {
+                    log::warn!(
+                        "vhost-net is unavailable, falling back to the userspace datapath: {:?}",
+                        e
+                    );
+                })
+                .ok()
+        } else {
+            None
+        };
+
+        let memory = Arc::new(self.guest_memory.clone());
+        let virtio_net = match self.net_interface.take() {
+            Some(interface) => VirtioNet::with_interface(
+                memory, irq_fd, net, interface, rx_limiter, tx_limiter, vhost,
+            ),
+            None => VirtioNet::new(memory, irq_fd, net, rx_limiter, tx_limiter, vhost),
+        }
+        .map_err(Error::VirtioNet)?;
+
+        // The queue notifications go straight to vhost-net, without leaving KVM.
+        if let Some(vhost) = virtio_net.vhost() {
+            let notify = IoEventAddress::Mmio(virtio_address + VIRTIO_MMIO_QUEUE_NOTIFY);
+            for queue_index in 0..VHOST_QUEUES {
+                self.vm_fd
+                    .register_ioevent(vhost.kick(queue_index), &notify, queue_index as u32)
+                    .map_err(Error::KvmIoctl)?;
+            }
+        }
+
+        let mut io_manager = self.io_manager.lock().unwrap();
+
+        self.virtio_net = Some(Arc::new(Mutex::new(virtio_net)));
+
+        io_manager
+            .register_mmio_resources(
                 // It's safe to unwrap because the virtio-net was just assigned
                 self.virtio_net.as_ref().unwrap().clone(),
                 &[
                     Resource::MmioAddressRange {
-                        base: virtio_address.raw_value(),
-                        size: 0x1000,
+                        base: virtio_address,
+                        size: VIRTIO_MMIO_SIZE,
+                    },
+                    Resource::LegacyIrq(irq),
+                ],
+            )
+            .map_err(Error::IoManager)?;
+
+        self.add_virtio_device(virtio_address, irq)?;
+
+        // The tap exists from now on, it can join its bridge, and the DHCP server can listen
+        // on it.
+        if let (Some(bridge), NetBackend::Tap(if_name)) = (net.bridge.as_ref(), &net.backend) {
+            self.net_bridge = Some(TapBridge::attach(if_name, bridge)?);
+        }
+        if let (Some(dhcp), NetBackend::Tap(if_name)) = (net.dhcp.as_ref(), &net.backend) {
+            let server =
+                TapDhcpServer::bind(if_name, dhcp).map_err(|source| Error::DhcpServer {
+                    if_name: if_name.clone(),
+                    source,
+                })?;
+            self.epoll
+                .add(
+                    server.as_raw_fd(),
+                    DHCP_TOKEN,
+                    Interest::Read,
+                    Box::new(server),
+                )
+                .map_err(Error::EpollError)?;
+        }
+
+        Ok(())
+    }
+
+    /// Reserve `count` slots for the virtio-net devices plugged in through the API, see
+    /// [`api`].
+    ///
+    /// Each slot has its own MMIO window and IRQ, and is announced to the guest as a
+    /// virtio-mmio device it leaves unbound until a device is plugged in.
+    pub(crate) fn configure_net_slots(&mut self, count: u8) -> Result<()> {
+        self.configure_device("configure the network slots of")?;
+        for _ in 0..count {
+            let address = self.allocate_mmio(VIRTIO_MMIO_SIZE)?;
+            let (irq, gsi) = self.allocate_device_irq()?;
+
+            let irq_fd = EventFd::new(libc::EFD_NONBLOCK).map_err(Error::IrqRegister)?;
+            self.irqfds
+                .insert(gsi, irq_fd.try_clone().map_err(Error::IrqRegister)?);
+
+            let name = self.add_virtio_device(address, irq)?;
+            let slot = Arc::new(Mutex::new(NetSlot::new(name, irq_fd)));
+            self.io_manager
+                .lock()
+                .unwrap()
+                .register_mmio_resources(
+                    slot.clone(),
+                    &[
+                        Resource::MmioAddressRange {
+                            base: address,
+                            size: VIRTIO_MMIO_SIZE,
+                        },
+                        Resource::LegacyIrq(irq),
+                    ],
+                )
+                .map_err(Error::IoManager)?;
+            self.net_slots.push(slot);
+        }
+
+        Ok(())
+    }
+
+    // configure the virtio-pmem device
+    pub(crate) fn configure_pmem(&mut self, pmem: Option<&PmemConfig>) -> Result<()> {
+        self.configure_device("configure the pmem device of")?;
+        let pmem = match pmem {
+            Some(pmem) => pmem,
+            None => return Ok(()),
+        };
+        let pmem_error = |source| Error::VirtioPmem {
+            path: pmem.path.clone(),
+            source,
+        };
+
+        if pmem.read_only && !self.kvm.check_extension(Cap::ReadonlyMem) {
+            return Err(Error::ReadonlyMemUnsupported);
+        }
+
+        let virtio_address = self.allocate_mmio(VIRTIO_MMIO_SIZE)?;
+        let (irq, gsi) = self.allocate_device_irq()?;
+
+        let irq_fd = EventFd::new(libc::EFD_NONBLOCK).map_err(Error::IrqRegister)?;
+        self.irqfds
+            .insert(gsi, irq_fd.try_clone().map_err(Error::IrqRegister)?);
+
+        let mut virtio_pmem = VirtioPmem::new(
+            Arc::new(self.guest_memory.clone()),
+            irq_fd,
+            &pmem.path,
+            pmem.read_only,
+        )
+        .map_err(pmem_error)?;
+
+        // Map the image into the guest physical memory, after the RAM.
+        let guest_address = self
+            .device_memory_allocator
+            .as_mut()
+            // Safe because the memory is configured before the devices.
+            .unwrap()
+            .allocate(virtio_pmem.size(), PMEM_ALIGNMENT, AllocPolicy::FirstMatch)
+            .map_err(Error::Allocator)?
+            .start();
+        #[cfg(target_arch = "x86_64")]
+        self.resources
+            .reserve(guest_address, virtio_pmem.size())
+            .map_err(Error::Allocator)?;
+        virtio_pmem.set_guest_address(GuestAddress(guest_address));
+
+        let kvm_memory_region = kvm_userspace_memory_region {
+            // The RAM regions use the first slots.
+            slot: self.guest_memory.num_regions() as u32,
+            guest_phys_addr: guest_address,
+            memory_size: virtio_pmem.size(),
+            userspace_addr: virtio_pmem.host_address(),
+            flags: if virtio_pmem.read_only() {
+                KVM_MEM_READONLY
+            } else {
+                0
+            },
+        };
+        // Safe because the mapping lives as long as the device, which lives as long as the VM.
+        unsafe { self.vm_fd.set_user_memory_region(kvm_memory_region) }.map_err(Error::KvmIoctl)?;
+
+        let virtio_pmem = Arc::new(Mutex::new(virtio_pmem));
+        self.io_manager
+            .lock()
+            .unwrap()
+            .register_mmio_resources(
+                virtio_pmem.clone(),
+                &[
+                    Resource::MmioAddressRange {
+                        base: virtio_address,
+                        size: VIRTIO_MMIO_SIZE,
+                    },
+                    Resource::LegacyIrq(irq),
+                ],
+            )
+            .map_err(Error::IoManager)?;
+        self.virtio_pmem = Some(virtio_pmem);
+
+        self.add_virtio_device(virtio_address, irq)?;
+
+        Ok(())
+    }
+
+    // Map the firmware blob read-only into the guest.
+    #[cfg(target_arch = "x86_64")]
+    pub(crate) fn configure_firmware(&mut self, firmware: Option<&FirmwareConfig>) -> Result<()> {
+        self.configure_device("load the firmware of")?;
+        let config = match firmware {
+            Some(config) => config,
+            None => return Ok(()),
+        };
+
+        if !self.kvm.check_extension(Cap::ReadonlyMem) {
+            return Err(Error::ReadonlyMemUnsupported);
+        }
+
+        let firmware = Firmware::load(config).map_err(|source| Error::Firmware {
+            path: config.path.clone(),
+            source,
+        })?;
+        let (guest_address, size) = firmware.range();
+        self.resources
+            .reserve(guest_address, size)
+            .map_err(Error::Allocator)?;
+        let kvm_memory_region = kvm_userspace_memory_region {
+            // After the RAM slots, and the pmem one.
+            slot: self.guest_memory.num_regions() as u32 + 1,
+            guest_phys_addr: guest_address,
+            memory_size: size,
+            userspace_addr: firmware.host_address(),
+            flags: KVM_MEM_READONLY,
+        };
+        // Safe because the mapping lives as long as the firmware, which lives as long as the
+        // VM.
+        unsafe { self.vm_fd.set_user_memory_region(kvm_memory_region) }.map_err(Error::KvmIoctl)?;
+        self.firmware = Some(firmware);
+
+        Ok(())
+    }
+
+    // configure the virtio-9p device sharing a host directory
+    pub(crate) fn configure_shared_dir(
+        &mut self,
+        shared_dir: Option<&SharedDirConfig>,
+    ) -> Result<()> {
+        self.configure_device("configure the shared directory of")?;
+        let shared_dir = match shared_dir {
+            Some(shared_dir) => shared_dir,
+            None => return Ok(()),
+        };
+
+        let virtio_address = self.allocate_mmio(VIRTIO_MMIO_SIZE)?;
+        let (irq, gsi) = self.allocate_device_irq()?;
+
+        let irq_fd = EventFd::new(libc::EFD_NONBLOCK).map_err(Error::IrqRegister)?;
+        self.irqfds
+            .insert(gsi, irq_fd.try_clone().map_err(Error::IrqRegister)?);
+
+        let virtio_9p = Virtio9p::new(
+            Arc::new(self.guest_memory.clone()),
+            irq_fd,
+            &shared_dir.tag,
+            &shared_dir.path,
+            shared_dir.read_only,
+        )
+        .map_err(|source| Error::SharedDir {
+            path: shared_dir.path.clone(),
+            source,
+        })?;
+
+        let virtio_9p = Arc::new(Mutex::new(virtio_9p));
+        self.io_manager
+            .lock()
+            .unwrap()
+            .register_mmio_resources(
+                virtio_9p.clone(),
+                &[
+                    Resource::MmioAddressRange {
+                        base: virtio_address,
+                        size: VIRTIO_MMIO_SIZE,
                     },
-                    Resource::LegacyIrq(5),
+                    Resource::LegacyIrq(irq),
                 ],
             )
             .map_err(Error::IoManager)?;
+        self.virtio_9p = Some(virtio_9p);
+
+        self.add_virtio_device(virtio_address, irq)?;
+
+        Ok(())
+    }
+
+    /// Add a watchdog device, stopping the VM when the guest enabled it and then stopped
+    /// petting it.
+    ///
+    /// The guest finds its registers at the address given by the `lumper.watchdog`
+    /// command line parameter.
+    pub(crate) fn configure_watchdog(&mut self, watchdog: Option<&WatchdogConfig>) -> Result<()> {
+        self.configure_device("configure the watchdog of")?;
+        let config = match watchdog {
+            Some(config) => config,
+            None => return Ok(()),
+        };
+
+        let address = self.allocate_mmio(WATCHDOG_MMIO_SIZE)?;
+
+        let watchdog = Watchdog::new(config.timeout).map_err(Error::Watchdog)?;
+        let fd = watchdog.as_raw_fd();
+        let watchdog = Arc::new(Mutex::new(watchdog));
+        let handler = WatchdogHandler::new(watchdog.clone(), config.action);
+        self.epoll
+            .add(fd, WATCHDOG_TOKEN, Interest::Read, Box::new(handler))
+            .map_err(Error::EpollError)?;
+
+        self.io_manager
+            .lock()
+            .unwrap()
+            .register_mmio_resources(
+                watchdog.clone(),
+                &[Resource::MmioAddressRange {
+                    base: address,
+                    size: WATCHDOG_MMIO_SIZE,
+                }],
+            )
+            .map_err(Error::IoManager)?;
 
-        // Add the virtio-net device to the cmdline.
         self.cmdline
-            .add_virtio_mmio_device(0x1000, virtio_address, 5, None)
-            .map_err(Error::Cmdline)?;
+            .insert("lumper.watchdog", &format!("{:#x}", address))
+            .map_err(Error::CmdlineCompose)?;
+        #[cfg(target_arch = "x86_64")]
+        {
+            self.watchdog = Some(watchdog);
+        }
+
+        Ok(())
+    }
+
+    /// Let the API plug the vCPUs of `topology` past the `cpus` boot ones into the running
+    /// guest, see [`cpu::hotplug`].
+    ///
+    /// The ACPI tables describe the hot-pluggable vCPUs, and the register and interrupt
+    /// telling the guest about them.
+    #[cfg(target_arch = "x86_64")]
+    pub(crate) fn configure_vcpu_hotplug(
+        &mut self,
+        topology: &CpuTopology,
+        cpus: u8,
+    ) -> Result<()> {
+        self.configure_device("configure the vCPU hotplug of")?;
+        if topology.vcpu_count() == u32::from(cpus) {
+            return Ok(());
+        }
+
+        let (irq, gsi) = self.allocate_device_irq()?;
+        let hotplug = VcpuHotplug::new(
+            self.vm_fd.clone(),
+            *topology,
+            cpus,
+            irq,
+            self.io_manager.clone(),
+            self.unknown_ports.clone(),
+            self.exit.clone(),
+        )
+        .map_err(Error::Vcpu)?;
+        self.irqfds
+            .insert(gsi, hotplug.ged_fd().map_err(Error::IrqRegister)?);
+        self.io_manager.lock().unwrap().register_pio_resources(
+            Arc::new(Mutex::new(CpuHotplug::new(hotplug.present()))),
+            &[Resource::PioAddressRange {
+                base: CPU_HOTPLUG_PORT,
+                size: CPU_HOTPLUG_PORT_SIZE,
+            }],
+        )?;
+        self.vcpu_hotplug = Some(Arc::new(Mutex::new(hotplug)));
 
         Ok(())
     }
 
-    pub fn configure_io(&mut self) -> Result<()> {
+    // Allocate a window of `size` bytes for device registers, aligned to its size.
+    fn allocate_mmio(&mut self, size: u64) -> Result<u64> {
+        let address = self
+            .mmio_allocator
+            .allocate(size, size, AllocPolicy::FirstMatch)
+            .map_err(Error::Allocator)?
+            .start();
+        #[cfg(target_arch = "x86_64")]
+        self.resources
+            .reserve(address, size)
+            .map_err(Error::Allocator)?;
+
+        Ok(address)
+    }
+
+    // Allocate a guest IRQ, and the GSI the device signals it on.
+    fn allocate_device_irq(&mut self) -> Result<(u32, u32)> {
+        let irq = self.irq_allocator.allocate_id().map_err(Error::Allocator)?;
+        #[cfg(target_arch = "x86_64")]
+        let gsi = self
+            .gsi_allocator
+            .allocate(IrqRoute::IoapicPin(irq))
+            .map_err(Error::Allocator)?;
+        // With the default routing, GSI n signals SPI n.
+        #[cfg(target_arch = "aarch64")]
+        let gsi = irq;
+
+        Ok((irq, gsi))
+    }
+
+    // Tell the guest where a virtio-mmio device is: on the kernel command line on x86_64,
+    // in the device tree on aarch64. Returns the name of the guest platform device.
+    fn add_virtio_device(&mut self, address: u64, irq: u32) -> Result<String> {
+        #[cfg(target_arch = "x86_64")]
+        {
+            let device = format!("{}K@{:#x}:{}", VIRTIO_MMIO_SIZE >> 10, address, irq);
+            self.cmdline
+                .insert("virtio_mmio.device", &device)
+                .map_err(Error::CmdlineCompose)?;
+            self.cmdline_virtio_devices += 1;
+            Ok(format!("virtio-mmio.{}", self.cmdline_virtio_devices - 1))
+        }
+        #[cfg(target_arch = "aarch64")]
+        {
+            self.virtio_devices.push(MmioDevice {
+                base: address,
+                size: VIRTIO_MMIO_SIZE,
+                irq,
+            });
+            Ok(format!("{:x}.virtio_mmio", address))
+        }
+    }
+
+    /// Create the irqchip, and wire the device interrupts to it.
+    ///
+    /// With the split irqchip, KVM only emulates the local APICs: the VMM emulates the
+    /// IOAPIC, see [`devices::ioapic`], and there are no PICs.
+    ///
+    /// This must be called once all the devices are configured, and before the vCPUs are.
+    #[cfg(target_arch = "x86_64")]
+    pub(crate) fn configure_io(&mut self, irqchip: IrqchipMode) -> Result<()> {
+        self.configure_device("configure the irqchip of")?;
         // First, create the irqchip.
         // On `x86_64`, this _must_ be created _before_ the vCPUs.
         // It sets up the virtual IOAPIC, virtual PIC, and sets up the future vCPUs for local APIC.
         // When in doubt, look in the kernel for `KVM_CREATE_IRQCHIP`.
         // https://elixir.bootlin.com/linux/latest/source/arch/x86/kvm/x86.c
-        self.vm_fd.create_irq_chip().map_err(Error::KvmIoctl)?;
+        match irqchip {
+            IrqchipMode::Full => self.vm_fd.create_irq_chip().map_err(Error::KvmIoctl)?,
+            IrqchipMode::Split => {
+                // Only the local APICs, with routes reserved for the IOAPIC pins.
+                let split_irqchip = kvm_enable_cap {
+                    cap: KVM_CAP_SPLIT_IRQCHIP,
+                    args: [IOAPIC_PINS as u64, 0, 0, 0],
+                    ..Default::default()
+                };
+                self.vm_fd
+                    .enable_cap(&split_irqchip)
+                    .map_err(Error::SplitIrqchip)?;
+            }
+        }
 
+        // Let the guest use the x2APIC: the interrupts are routed with 32 bits APIC IDs,
+        // and 0xff is no longer a broadcast once it switched to x2APIC mode.
+        let x2apic_api = kvm_enable_cap {
+            cap: KVM_CAP_X2APIC_API,
+            args: [
+                u64::from(KVM_X2APIC_API_USE_32BIT_IDS | KVM_X2APIC_API_DISABLE_BROADCAST_QUIRK),
+                0,
+                0,
+                0,
+            ],
+            ..Default::default()
+        };
         self.vm_fd
-            .register_irqfd(
-                &self
-                    .serial
+            .enable_cap(&x2apic_api)
+            .map_err(Error::X2ApicApi)?;
+
+        match irqchip {
+            // Then route the device GSIs, on top of the default legacy IRQ routes.
+            IrqchipMode::Full => {
+                self.gsi_allocator
+                    .set_routing(&self.vm_fd)
+                    .map_err(Error::KvmIoctl)?;
+                self.irq_trigger = IrqTrigger::Kernel;
+            }
+            // The IOAPIC routes its pins as the guest sets them up.
+            IrqchipMode::Split => {
+                let ioapic = Arc::new(Mutex::new(Ioapic::new(Box::new(self.vm_fd.clone()))));
+                self.io_manager
                     .lock()
                     .unwrap()
-                    .eventfd()
-                    .map_err(Error::IrqRegister)?,
-                SERIAL1_IRQ,
-            )
-            .map_err(Error::KvmIoctl)?;
+                    .register_mmio_resources(
+                        ioapic.clone(),
+                        &[Resource::MmioAddressRange {
+                            base: layout::IOAPIC_START,
+                            size: IOAPIC_MMIO_SIZE,
+                        }],
+                    )
+                    .map_err(Error::IoManager)?;
+                self.irq_trigger = IrqTrigger::Userspace {
+                    ioapic,
+                    eventfds: Vec::new(),
+                };
+            }
+        }
 
-        if let Some(virtio_net) = self.virtio_net.as_ref() {
-            self.vm_fd
-                .register_irqfd(&virtio_net.lock().unwrap().guest_irq_fd, 5)
-                .map_err(Error::KvmIoctl)?;
+        for (gsi, irqfd) in self.irqfds.iter() {
+            let pin = self.gsi_allocator.pin(*gsi).unwrap_or(*gsi);
+            self.irq_trigger
+                .connect(&self.vm_fd, irqfd, *gsi, pin)
+                .map_err(Error::IrqRegister)?;
         }
+
         Ok(())
     }
 
-    pub fn configure_console(&mut self, console_path: Option<String>) -> Result<()> {
-        if let Some(console_path) = console_path {
-            // We create the file if it does not exist, else we open
-            let file = File::create(&console_path).map_err(Error::ConsoleError)?;
-
-            let mut serial = self.serial.lock().unwrap();
-            *serial = LumperSerial::new(Box::new(file)).map_err(Error::SerialCreation)?;
+    // Open the output sink for a serial port, along with its input side if it has one. A
+    // file is locked with `lock`.
+    fn open_serial_sink(
+        mode: &ConsoleMode,
+        lock: bool,
+    ) -> Result<(Box<dyn Write + Send>, Option<UnixStream>)> {
+        match mode {
+            ConsoleMode::Stdout => Ok((Box::new(stdout()), None)),
+            // The file is truncated if it exists.
+            ConsoleMode::File(file) if lock => {
+                Ok((Box::new(LogFile::create_locked(file.clone())?), None))
+            }
+            ConsoleMode::File(file) => {
+                let file = LogFile::create(file.clone()).map_err(|source| Error::ConsoleError {
+                    path: file.path.clone(),
+                    source,
+                })?;
+                Ok((Box::new(file), None))
+            }
+            ConsoleMode::Unix(path) => {
+                let console_error = |source: io::Error| Error::ConsoleError {
+                    path: path.clone(),
+                    source,
+                };
+                let stream = UnixStream::connect(path).map_err(console_error)?;
+                let input = stream.try_clone().map_err(console_error)?;
+                input.set_nonblocking(true).map_err(console_error)?;
+                Ok((Box::new(stream), Some(input)))
+            }
+            ConsoleMode::Agent => Err(Error::AgentConsole),
+            ConsoleMode::None => Ok((Box::new(io::sink()), None)),
         }
+    }
 
-        Ok(())
+    // Move the writes to a serial output sink off the vCPU threads. Its failures are
+    // counted in `stats`.
+    fn async_output(
+        &mut self,
+        output: Box<dyn Write + Send>,
+        on_error: OnSinkError,
+        stats: Arc<SerialStats>,
+    ) -> Result<Box<dyn Write + Send>> {
+        let writer = AsyncWriter::new(output, OUTPUT_QUEUE_SIZE, on_error, stats)
+            .map_err(Error::SerialCreation)?;
+        self.output_flushers.push(writer.flush_handle());
+
+        Ok(Box::new(writer))
     }
 
-    pub fn configure_vcpus(
+    pub(crate) fn configure_console(
         &mut self,
-        num_vcpus: u8,
-        kernel_load: KernelLoaderResult,
+        console: &ConsoleMode,
+        panic_detect: bool,
+        error_policy: ConsoleErrorPolicy,
     ) -> Result<()> {
-        mptable::setup_mptable(&self.guest_memory, num_vcpus)
-            .map_err(|e| Error::Vcpu(cpu::Error::Mptable(e)))?;
+        self.check_state(&SERIAL_STATES, "configure the console of")?;
+        // Without a console, stdin and the terminal are left alone.
+        if *console == ConsoleMode::None {
+            self.detach_stdin()?;
+        }
+        let stats = Arc::new(SerialStats::default());
+        let output = self.console_output(console, panic_detect, error_policy, stats.clone())?;
 
-        let base_cpuid = self
-            .kvm
-            .get_supported_cpuid(KVM_MAX_CPUID_ENTRIES)
-            .map_err(Error::KvmIoctl)?;
+        let mut serial = self.serial.lock().unwrap();
+        *serial = LumperSerial::with_stats(output, stats).map_err(Error::SerialCreation)?;
+        if let Some(recorder) = self.console_recorder.as_ref() {
+            serial.set_recorder(recorder.clone());
+        }
+        self.irqfds
+            .insert(COM1.irq, serial.eventfd().map_err(Error::IrqRegister)?);
 
-        for index in 0..num_vcpus {
-            let vcpu = Vcpu::new(
-                &self.vm_fd,
-                index.into(),
-                Arc::clone(&self.serial),
-                self.virtio_manager.clone(),
-            )
-            .map_err(Error::Vcpu)?;
+        Ok(())
+    }
 
-            // Set CPUID.
-            let mut vcpu_cpuid = base_cpuid.clone();
-            cpuid::filter_cpuid(
-                &self.kvm,
-                index as usize,
-                num_vcpus as usize,
-                &mut vcpu_cpuid,
-            );
-            vcpu.configure_cpuid(&vcpu_cpuid).map_err(Error::Vcpu)?;
+    // Open the console output, along with the sink and the panic detection. The sink
+    // failures are counted in `stats`.
+    fn console_output(
+        &mut self,
+        console: &ConsoleMode,
+        panic_detect: bool,
+        error_policy: ConsoleErrorPolicy,
+        stats: Arc<SerialStats>,
+    ) -> Result<Box<dyn Write + Send>> {
+        let on_error = match error_policy {
+            ConsoleErrorPolicy::Discard => OnSinkError::Discard,
+            ConsoleErrorPolicy::Stop => OnSinkError::Stop(self.exit.clone()),
+            // There is nothing to fall back to from stdout.
+            ConsoleErrorPolicy::StdoutFallback if *console == ConsoleMode::Stdout => {
+                OnSinkError::Discard
+            }
+            ConsoleErrorPolicy::StdoutFallback => OnSinkError::Fallback(Box::new(stdout())),
+        };
 
-            // Configure MSRs (model specific registers).
-            vcpu.configure_msrs().map_err(Error::Vcpu)?;
+        let mut output = match console {
+            // There is nothing to queue the writes for.
+            ConsoleMode::None => Box::new(io::sink()),
+            console => {
+                // Only a Unix socket console could provide input, and the console input is
+                // stdin.
+                let (output, _) = Self::open_serial_sink(console, self.lock_resources)?;
+                self.async_output(output, on_error, stats.clone())?
+            }
+        };
+        // Each sink has its own queue, a slow one does not hold the other back.
+        if let Some(mut sink) = self.console_sink.take() {
+            if self.keep_parts {
+                sink = Box::new(HandBackWriter::new(sink, self.console_slot.clone()));
+            }
+            let sink = self.async_output(sink, OnSinkError::Discard, stats)?;
+            output = Box::new(TeeWriter::new(vec![output, sink]));
+        }
 
-            // Configure regs, sregs and fpu.
-            vcpu.configure_regs(kernel_load.kernel_load)
-                .map_err(Error::Vcpu)?;
-            vcpu.configure_sregs(&self.guest_memory)
-                .map_err(Error::Vcpu)?;
-            vcpu.configure_fpu().map_err(Error::Vcpu)?;
+        output = Box::new(ScanningWriter::new(
+            output,
+            panic_detect,
+            self.console_watch.clone(),
+            self.exit.clone(),
+        ));
+        if let Some(events) = self.events.as_ref() {
+            output = Box::new(ActivityWriter::new(output, events.clone()));
+        }
+        if let Some(recorder) = self.console_recorder.as_ref() {
+            output = Box::new(recorder.output_writer(output));
+        }
+        if let Some(replay) = self.console_replay.as_ref() {
+            output = Box::new(replay.output_writer(output));
+        }
 
-            // Configure LAPICs.
-            vcpu.configure_lapic().map_err(Error::Vcpu)?;
+        Ok(output)
+    }
 
-            self.vcpus.push(vcpu);
+    // The interrupt latency of the devices configured at boot. The hot-plugged ones are not
+    // traced.
+    fn irq_traces(&self) -> Vec<Arc<IrqTrace>> {
+        let mut traces = Vec::new();
+        if let Some(net) = self.virtio_net.as_ref() {
+            traces.push(net.lock().unwrap().irq_trace());
+        }
+        if let Some(pmem) = self.virtio_pmem.as_ref() {
+            traces.push(pmem.lock().unwrap().irq_trace());
+        }
+        if let Some(p9) = self.virtio_9p.as_ref() {
+            traces.push(p9.lock().unwrap().irq_trace());
         }
+        traces
+    }
+
+    /// Serve the API requests on a Unix socket at `path`, see [`api`].
+    ///
+    /// This must be called after [`VMM::configure_memory`], and after the devices and the
+    /// vCPUs are configured for their counters to be served.
+    pub(crate) fn configure_api(&mut self, path: Option<&Path>) -> Result<()> {
+        self.check_state(&SETUP_STATES, "configure the API socket of")?;
+        let path = match path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        let socket = ApiSocket::bind(path).map_err(|source| Error::ApiSocket {
+            path: path.into(),
+            source,
+        })?;
+        let fd = socket.as_raw_fd();
+        let pause = Arc::new(VmPause::new(
+            self.virtio_net.as_ref(),
+            self.net_slots.clone(),
+            self.output_flushers.clone(),
+            #[cfg(target_arch = "x86_64")]
+            self.vcpu_hotplug.clone(),
+        ));
+        let handler = ApiHandler {
+            socket,
+            vm_fd: self.vm_fd.clone(),
+            guest_memory: self.guest_memory.clone(),
+            dirty_tracking: self.dirty_tracking,
+            net: self
+                .virtio_net
+                .as_ref()
+                .map(|net| net.lock().unwrap().stats()),
+            serial: self.serial.lock().unwrap().stats(),
+            serial2: self
+                .serial2
+                .as_ref()
+                .map(|serial| serial.lock().unwrap().stats()),
+            vcpus: self.vcpus.iter().map(|vcpu| vcpu.stats()).collect(),
+            irq_traces: self.irq_traces(),
+            net_slots: self.net_slots.clone(),
+            lock_taps: self.lock_resources,
+            tap_locks: Mutex::new(BTreeMap::new()),
+            shared_memory: self.shared_memory.clone(),
+            #[cfg(target_arch = "x86_64")]
+            vcpu_hotplug: self.vcpu_hotplug.clone(),
+            pause: pause.clone(),
+        };
+        self.vm_pause = Some(pause);
+        self.epoll
+            .add(fd, API_TOKEN, Interest::Read, Box::new(handler))
+            .map_err(Error::EpollError)?;
 
         Ok(())
     }
 
-    // Run all virtual CPUs.
-    pub fn run(&mut self) -> Result<()> {
-        for mut vcpu in self.vcpus.drain(..) {
-            println!("Starting vCPU {:?}", vcpu.index);
-            let _ = thread::Builder::new().spawn(move || loop {
-                vcpu.run();
-            });
-        }
+    /// Write the lifecycle events of the VM to `path`, usually a FIFO, see [`events`].
+    ///
+    /// This must be called first, the events of the devices configured earlier are not
+    /// written.
+    pub(crate) fn configure_events(&mut self, path: Option<&Path>) -> Result<()> {
+        self.check_state(&SETUP_STATES, "configure the event FIFO of")?;
+        let path = match path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
 
-        let stdin = io::stdin();
-        let stdin_lock = stdin.lock();
-        stdin_lock
-            .set_raw_mode()
-            .map_err(Error::TerminalConfigure)?;
-        let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); EPOLL_EVENTS_LEN];
-        let epoll_fd = self.epoll.as_raw_fd();
-        let interface_fd = match self.virtio_net.as_ref() {
-            Some(virtio_net) => Some(virtio_net.lock().unwrap().interface.as_raw_fd()),
-            None => None,
-        };
-        // Let's start the STDIN/Network interface polling thread.
-        loop {
-            let num_events = match epoll::wait(epoll_fd, -1, &mut events[..]) {
-                Ok(num_events) => num_events,
-                Err(e) => {
-                    if e.kind() == io::ErrorKind::Interrupted {
-                        continue;
-                    } else {
-                        return Err(Error::EpollError(e));
-                    }
-                }
-            };
+        let events = EventSink::open(path).map_err(|source| Error::EventFifo {
+            path: path.into(),
+            source,
+        })?;
+        self.events = Some(events);
 
-            for event in events.iter().take(num_events) {
-                let event_data = event.data as RawFd;
+        Ok(())
+    }
 
-                if let libc::STDIN_FILENO = event_data {
-                    let mut out = [0u8; 64];
+    /// Feed the console with a file or a pipe, instead of stdin.
+    ///
+    /// The input is sent once the guest opened the console, as fast as it reads it. The
+    /// end of a regular file only stops the input, a FIFO is read for as long as the VM
+    /// runs.
+    pub(crate) fn configure_console_input(&mut self, path: Option<&Path>) -> Result<()> {
+        self.check_state(&SETUP_STATES, "configure the console input of")?;
+        let path = match path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
 
-                    let count = stdin_lock.read_raw(&mut out).map_err(Error::StdinRead)?;
+        let input = ConsoleInput::open(path).map_err(|source| Error::ConsoleError {
+            path: path.into(),
+            source,
+        })?;
+        self.detach_stdin()?;
+        self.console_input = Some(input);
 
-                    self.serial
-                        .lock()
-                        .unwrap()
-                        .serial
-                        .enqueue_raw_bytes(&out[..count])
-                        .map_err(Error::StdinWrite)?;
-                }
+        Ok(())
+    }
 
-                if interface_fd == Some(event_data) {
-                    self.virtio_net
-                        .as_ref()
-                        // Safe because we checked that the virtio_net is Some before the loop.
-                        .unwrap()
-                        .lock()
-                        .unwrap()
-                        .process_tap()
-                        .map_err(Error::VirtioNet)?;
-                }
+    /// Record the console session to `record`, and replay the `replay` one instead of
+    /// stdin, see [`devices::console_session`].
+    ///
+    /// This must be called before [`VMM::configure_console`], whose output is recorded
+    /// and compared with the replayed one. The session is timed from the first run.
+    pub(crate) fn configure_console_session(
+        &mut self,
+        record: Option<&Path>,
+        replay: Option<&ReplayConfig>,
+    ) -> Result<()> {
+        self.check_state(&SERIAL_STATES, "configure the console session of")?;
+        if let Some(path) = record {
+            let recorder =
+                SessionRecorder::create(path).map_err(|source| Error::ConsoleSession {
+                    path: path.into(),
+                    source,
+                })?;
+            self.console_recorder = Some(recorder);
+        }
+        if let Some(replay) = replay {
+            let session = SessionReplay::open(&replay.path, replay.fast, replay.tolerance)
+                .map_err(|source| Error::ConsoleSession {
+                    path: replay.path.clone(),
+                    source,
+                })?;
+            self.detach_stdin()?;
+            self.console_replay = Some(session);
+        }
+
+        Ok(())
+    }
+
+    // Configure the second serial port (COM2/ttyS1), used by the agent.
+    pub(crate) fn configure_serial2(&mut self, serial2: Option<&ConsoleMode>) -> Result<()> {
+        self.check_state(&SERIAL_STATES, "configure the second serial port of")?;
+        let mode = match serial2 {
+            Some(mode) => mode,
+            None => return Ok(()),
+        };
+
+        let stats = Arc::new(SerialStats::default());
+        let (output, input): (Box<dyn Write + Send>, _) = match mode {
+            ConsoleMode::Agent => {
+                let (sender, receiver) = mpsc::channel();
+                self.agent_frames = Some(receiver);
+                (
+                    Box::new(AgentWriter::new(sender, self.events.clone())),
+                    None,
+                )
+            }
+            ConsoleMode::None => (Box::new(io::sink()), None),
+            mode => {
+                let (output, input) = Self::open_serial_sink(mode, self.lock_resources)?;
+                let output = self.async_output(output, OnSinkError::Discard, stats.clone())?;
+                (output, input)
             }
+        };
+        let serial = Arc::new(Mutex::new(
+            LumperSerial::with_stats(output, stats).map_err(Error::SerialCreation)?,
+        ));
+        COM2.register(&mut self.io_manager.lock().unwrap(), serial.clone())?;
+
+        if let Some(input) = input {
+            let fd = input.as_raw_fd();
+            let handler = Serial2Input {
+                input,
+                serial2: serial.clone(),
+            };
+            self.epoll
+                .add(fd, SERIAL2_TOKEN, Interest::Read, Box::new(handler))
+                .map_err(Error::EpollError)?;
+        }
+
+        self.irqfds.insert(
+            COM2.irq,
+            serial
+                .lock()
+                .unwrap()
+                .eventfd()
+                .map_err(Error::IrqRegister)?,
+        );
+        self.serial2 = Some(serial);
+
+        Ok(())
+    }
+
+    /// Describe the vCPUs and the power management registers to the guest with ACPI tables,
+    /// and with the legacy MP table if `mptable` is set. The guest boots with the first
+    /// `cpus` vCPUs of `topology`.
+    ///
+    /// This lets the guest power off: entering the S5 sleep state stops the VMM with
+    /// [`ExitReason::GuestShutdown`]. The tables also describe a pvpanic device, the guest
+    /// kernel panics then stop the VMM with [`ExitReason::GuestPanic`].
+    #[cfg(target_arch = "x86_64")]
+    pub(crate) fn configure_acpi(
+        &mut self,
+        topology: &CpuTopology,
+        cpus: u8,
+        mptable: bool,
+    ) -> Result<()> {
+        self.check_state(&TABLE_STATES, "configure the ACPI tables of")?;
+        let ged_irq = self
+            .vcpu_hotplug
+            .as_ref()
+            .map(|hotplug| hotplug.lock().unwrap().ged_irq());
+        acpi::setup_acpi(&self.guest_memory, topology, cpus, ged_irq)?;
+        if mptable {
+            mptable::setup_mptable(&self.guest_memory, topology, cpus)
+                .map_err(|e| Error::Vcpu(cpu::Error::Mptable(e)))?;
         }
+
+        self.register_acpi_devices()
     }
 
-    pub fn configure(
+    /// Describe the machine to the guest in the SMBIOS tables, with a random UUID unless
+    /// given one.
+    #[cfg(target_arch = "x86_64")]
+    pub(crate) fn configure_smbios(
         &mut self,
-        num_vcpus: u8,
-        mem_size_mb: u32,
-        kernel_path: &str,
-        console: Option<String>,
-        initramfs_path: Option<String>,
-        if_name: Option<String>,
+        uuid: Option<Uuid>,
+        smbios: &SmbiosConfig,
     ) -> Result<()> {
-        self.configure_console(console)?;
-        self.configure_memory(mem_size_mb)?;
-        self.load_default_cmdline()?;
+        self.check_state(&TABLE_STATES, "configure the SMBIOS tables of")?;
+        let uuid = match uuid {
+            Some(uuid) => uuid,
+            None => Uuid::random().map_err(Error::Uuid)?,
+        };
+        log::debug!("VM UUID {}", uuid);
+
+        smbios::setup_smbios(&self.guest_memory, &uuid, smbios)?;
+        Ok(())
+    }
 
-        self.configure_net(if_name)?;
+    // Register the power management and pvpanic devices the ACPI tables describe.
+    #[cfg(target_arch = "x86_64")]
+    fn register_acpi_devices(&mut self) -> Result<()> {
+        let pm = Arc::new(Mutex::new(AcpiPm::new(self.exit.clone())));
+        self.io_manager.lock().unwrap().register_pio_resources(
+            pm,
+            &[Resource::PioAddressRange {
+                base: PM1A_EVT_BLK,
+                size: ACPI_PM_PORT_SIZE,
+            }],
+        )?;
 
-        let kernel_load = kernel::kernel_setup(
-            &self.guest_memory,
-            PathBuf::from(kernel_path),
-            initramfs_path,
-            &self.cmdline,
+        let pvpanic = Arc::new(Mutex::new(Pvpanic::new(self.exit.clone())));
+        self.io_manager.lock().unwrap().register_pio_resources(
+            pvpanic,
+            &[Resource::PioAddressRange {
+                base: PVPANIC_PORT,
+                size: PVPANIC_PORT_SIZE,
+            }],
         )?;
-        self.configure_io()?;
-        self.configure_vcpus(num_vcpus, kernel_load)?;
 
         Ok(())
     }
+
+    // Check that the host KVM can run all the vCPUs.
+    fn check_vcpu_count(&self, topology: &CpuTopology) -> Result<()> {
+        let max = self.kvm.get_max_vcpus();
+        if topology.vcpu_count() as usize > max {
+            return Err(Error::TooManyVcpus {
+                requested: topology.vcpu_count(),
+                max,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Create the first `cpus` vCPUs of `topology`, starting at the kernel `entry`.
+    ///
+    /// The kernel may still be loading: the VM is only ready to run once it is in the guest
+    /// memory, see [`VMM::configure`].
+    #[cfg(target_arch = "x86_64")]
+    pub(crate) fn configure_vcpus(
+        &mut self,
+        topology: &CpuTopology,
+        cpus: u8,
+        cpu_template: CpuTemplate,
+        pv_features: PvFeatures,
+        entry: GuestAddress,
+    ) -> Result<()> {
+        self.check_state(&DEVICE_STATES, "configure the vCPUs of")?;
+        self.check_vcpu_count(topology)?;
+        let start = Instant::now();
+
+        // Only the APIC ID differs from a vCPU to another, the rest of the CPUID and the MSRs
+        // are set up once for all.
+        let mut base_cpuid = self
+            .kvm
+            .get_supported_cpuid(KVM_MAX_CPUID_ENTRIES)
+            .map_err(Error::KvmIoctl)?;
+        cpuid::filter_cpuid(
+            self.kvm.check_extension(Cap::TscDeadlineTimer),
+            topology,
+            &mut base_cpuid,
+        );
+        cpuid::filter_pv_features(pv_features, &mut base_cpuid);
+        templates::apply_template(cpu_template, &mut base_cpuid)
+            .map_err(|e| Error::Vcpu(cpu::Error::CpuTemplate(e)))?;
+        let boot_msrs = msrs::create_boot_msr_entries(
+            &msrs::pv_msrs(&base_cpuid),
+            templates::msr_allowlist(cpu_template),
+        )
+        .map_err(|e| Error::Vcpu(cpu::Error::CreateMsr(e)))?;
+
+        let vm_fd = &self.vm_fd;
+        let guest_memory = &self.guest_memory;
+        let firmware_entry = self.firmware.as_ref().and_then(Firmware::entry);
+        let configure = |index: u8| -> cpu::Result<Vcpu> {
+            let apic_id = topology.apic_id(index);
+            let vcpu = Vcpu::new(
+                vm_fd,
+                index.into(),
+                apic_id.into(),
+                self.io_manager.clone(),
+                self.unknown_ports.clone(),
+            )?;
+
+            let mut vcpu_cpuid = base_cpuid.clone();
+            cpuid::set_apic_id(apic_id, &mut vcpu_cpuid);
+            vcpu.configure_cpuid(&vcpu_cpuid)?;
+            vcpu.configure_msrs(&boot_msrs)?;
+
+            // Configure regs, sregs and fpu.
+            vcpu.configure_regs(entry)?;
+            vcpu.configure_sregs(guest_memory)?;
+            // Only the boot vCPU, the others start where the guest tells them to.
+            if let (Some(entry), 0) = (firmware_entry, index) {
+                vcpu.configure_firmware_entry(guest_memory, entry)?;
+            }
+            vcpu.configure_fpu()?;
+
+            // Configure LAPICs.
+            vcpu.configure_lapic()?;
+            Ok(vcpu)
+        };
+
+        // The vCPUs are independent, they are set up in parallel. The ACPI setup checked
+        // that their count fits.
+        let vcpus = thread::scope(|scope| {
+            let threads: Vec<_> = (0..cpus)
+                .map(|index| scope.spawn(move || configure(index)))
+                .collect();
+            threads
+                .into_iter()
+                .map(|thread| {
+                    thread
+                        .join()
+                        .unwrap_or_else(|e| std::panic::resume_unwind(e))
+                })
+                .collect::<cpu::Result<Vec<Vcpu>>>()
+        })
+        .map_err(Error::Vcpu)?;
+        self.vcpus.extend(vcpus);
+        // The plugged vCPUs are set up the same way.
+        if let Some(hotplug) = self.vcpu_hotplug.as_ref() {
+            hotplug
+                .lock()
+                .unwrap()
+                .set_vcpu_setup(base_cpuid, boot_msrs);
+        }
+
+        self.vcpu_configure_time = start.elapsed();
+        Ok(())
+    }
+
+    /// Set the TSC frequency of all the vCPUs.
+    ///
+    /// Without an explicit `tsc_khz`, all the vCPUs are pinned to the host frequency. Failing
+    /// to do so is only a warning, the guest then just runs with the host TSC.
+    #[cfg(target_arch = "x86_64")]
+    pub(crate) fn configure_tsc(&mut self, tsc_khz: Option<u32>) -> Result<()> {
+        self.check_state(&[VmmState::Ready], "set the TSC frequency of")?;
+        let tsc_control = self.kvm.check_extension(Cap::TscControl);
+        if tsc_khz.is_some() && !tsc_control {
+            return Err(Error::TscControlUnsupported);
+        }
+
+        let first_vcpu = match self.vcpus.first() {
+            Some(vcpu) => vcpu,
+            None => return Ok(()),
+        };
+        let freq = match tsc_khz {
+            Some(freq) => freq,
+            None => match first_vcpu.tsc_khz() {
+                Ok(freq) => freq,
+                Err(e) => {
+                    log::warn!("Failed to read the host TSC frequency: {}", e);
+                    return Ok(());
+                }
+            },
+        };
+        // Keep the frequency around, so that a restored guest can check it still runs
+        // with the same TSC.
+        self.tsc_khz = Some(freq);
+
+        if !tsc_control {
+            return Ok(());
+        }
+
+        for vcpu in self.vcpus.iter() {
+            if let Err(e) = vcpu.configure_tsc_khz(freq) {
+                if tsc_khz.is_some() {
+                    return Err(Error::Vcpu(e));
+                }
+                log::warn!("Failed to set the TSC frequency to {} kHz: {}", freq, e);
+                return Ok(());
+            }
+        }
+        if let Some(hotplug) = self.vcpu_hotplug.as_ref() {
+            hotplug.lock().unwrap().set_tsc_khz(freq);
+        }
+
+        Ok(())
+    }
+
+    /// Listen for a GDB client, which the boot vCPU waits for before running the guest. The
+    /// vCPUs then stop for it on the breakpoints it sets, see [`gdb`].
+    #[cfg(target_arch = "x86_64")]
+    pub(crate) fn configure_gdb(&mut self, gdb: Option<&GdbConfig>) -> Result<()> {
+        self.check_state(&[VmmState::Ready], "attach GDB to")?;
+        let addr = match gdb {
+            Some(gdb) => gdb.addr,
+            None => return Ok(()),
+        };
+
+        let stub = GdbStub::new(addr, self.guest_memory.clone())
+            .map_err(|source| Error::GdbListen { addr, source })?;
+        let stub = Arc::new(stub);
+        for vcpu in self.vcpus.iter_mut() {
+            vcpu.set_gdb(stub.clone()).map_err(Error::Vcpu)?;
+        }
+        self.gdb = Some(stub);
+        Ok(())
+    }
+
+    /// Guest TSC frequency, in kHz, when known.
+    #[cfg(target_arch = "x86_64")]
+    pub(crate) fn tsc_khz(&self) -> Option<u32> {
+        self.tsc_khz
+    }
+
+    /// Stop reading the console input from stdin, and leave the terminal alone.
+    ///
+    /// This is required when stdin is not a terminal, e.g. once a daemon closed it.
+    pub fn detach_stdin(&mut self) -> Result<()> {
+        if self.stdin_attached {
+            self.epoll.remove(STDIN_TOKEN).map_err(Error::EpollError)?;
+            self.stdin_attached = false;
+        }
+
+        Ok(())
+    }
+
+    /// Stop the guest with [`ExitReason::Timeout`] once it ran for `timeout`.
+    pub(crate) fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    /// A trigger pausing the VM while it runs, from any thread.
+    pub fn pause_trigger(&self) -> PauseTrigger {
+        PauseTrigger {
+            exit: self.exit.clone(),
+        }
+    }
+
+    /// Run all the vCPUs, until the VM stops.
+    ///
+    /// A VM stopped with [`ExitReason::Paused`] keeps its vCPUs, and runs again on the next
+    /// call. Only the vCPUs pause: the devices threads keep running, and the guest time
+    /// goes on.
+    ///
+    /// A guest restarting on reboot starts over without returning, until it rebooted the
+    /// maximum number of times. The timeout covers all its boots.
+    ///
+    /// Otherwise, including on errors, the vCPUs and the devices are stopped, and the whole
+    /// console output is written and synced to its file before this returns.
+    pub fn run(&mut self) -> Result<ExitReason> {
+        self.check_state(&[VmmState::Ready], "run")?;
+        self.state = VmmState::Running;
+
+        // The guest moves on, the clones would no longer start from where it paused.
+        #[cfg(target_arch = "x86_64")]
+        {
+            self.paused_clock = None;
+            *self.clone_memory.get_mut().unwrap() = None;
+        }
+
+        let mut vcpu_threads = self.start_vcpus();
+        let deadline = self.timeout.map(|timeout| Instant::now() + timeout);
+
+        let stdin = io::stdin();
+        let stdin_lock = stdin.lock();
+        let mut raw_mode = false;
+        // The vCPUs run already, a failure stops them like any exit.
+        let mut result = self.start_devices().and_then(|()| {
+            // A pipe feeds the console as is, there is no terminal to set up.
+            if self.stdin_attached && stdin_lock.is_terminal() {
+                stdin_lock
+                    .set_raw_mode()
+                    .map_err(Error::TerminalConfigure)?;
+                raw_mode = true;
+            } else if self.stdin_attached {
+                log::info!("stdin is not a terminal, leaving it as is");
+            }
+            let reason = self.event_loop(deadline)?;
+            #[cfg(target_arch = "x86_64")]
+            let reason = self.restart_on_reboot(reason, deadline, &mut vcpu_threads)?;
+            Ok(reason)
+        });
+        // A paused VM keeps its vCPUs, to run again.
+        self.state = match result {
+            Ok(ExitReason::Paused) => VmmState::Ready,
+            _ => VmmState::Stopped,
+        };
+
+        // Along with the vCPUs plugged while the VM ran.
+        #[cfg(target_arch = "x86_64")]
+        if let Some(hotplug) = self.vcpu_hotplug.as_ref() {
+            for (handle, vcpu_thread) in hotplug.lock().unwrap().take_started() {
+                self.vcpu_handles.push(handle);
+                vcpu_threads.push(vcpu_thread);
+            }
+        }
+
+        for (port, count) in self.unknown_ports.counts() {
+            log::debug!("{} accesses to unsupported port {:#x}", count, port);
+        }
+
+        if matches!(result, Ok(ExitReason::Paused)) {
+            self.stop_vcpus(vcpu_threads);
+            // Let the output of the guest so far reach the serial sinks.
+            for flusher in self.output_flushers.iter() {
+                flusher.flush(OUTPUT_FLUSH_TIMEOUT);
+            }
+            #[cfg(target_arch = "x86_64")]
+            {
+                self.paused_clock = Some(self.vm_fd.get_clock().map_err(Error::KvmIoctl)?);
+            }
+        } else {
+            self.teardown(vcpu_threads);
+            // The vCPUs are gone, the whole output is in.
+            if let (Ok(_), Some(replay)) = (&result, self.console_replay.take()) {
+                if let Err(divergence) = replay.check() {
+                    result = Err(Error::ReplayDiverged(divergence.to_string()));
+                }
+            }
+        }
+
+        if raw_mode {
+            stdin_lock
+                .set_canon_mode()
+                .map_err(Error::TerminalConfigure)?;
+        }
+
+        if let Some(events) = self.events.as_ref() {
+            events.emit(Event::shutdown(&result));
+            if events.dropped() > 0 {
+                log::warn!("{} events did not fit in the event FIFO", events.dropped());
+            }
+        }
+
+        result
+    }
+
+    /// Run the VM, like [`run`](VMM::run), until the console prints `marker` or `timeout`
+    /// elapsed. Either way, the VM pauses, and [`resume_run`](VMM::resume_run) runs it
+    /// for the rest of its session.
+    ///
+    /// The console output goes to its sink as usual, the outcome only holds a copy.
+    ///
+    /// ```no_run
+    /// use std::path::PathBuf;
+    /// use std::time::Duration;
+    ///
+    /// use vmm::config::VMMConfigBuilder;
+    /// use vmm::{RunUntilOutcome, VMM};
+    ///
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let config = VMMConfigBuilder::default()
+    ///     .kernel(PathBuf::from("vmlinux"))
+    ///     .build()?;
+    /// let mut vmm = VMM::from_config(&config)?;
+    ///
+    /// match vmm.run_until(b"Run /init as init process", Duration::from_secs(10))? {
+    ///     RunUntilOutcome::MarkerSeen(_) => {
+    ///         // The guest runs on from there.
+    ///         println!("booted, then stopped with {:?}", vmm.resume_run()?)
+    ///     }
+    ///     RunUntilOutcome::TimedOut(output) => {
+    ///         println!("no init after:\n{}", String::from_utf8_lossy(&output))
+    ///     }
+    ///     RunUntilOutcome::GuestExited(reason) => println!("stopped with {:?}", reason),
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn run_until(&mut self, marker: &[u8], timeout: Duration) -> Result<RunUntilOutcome> {
+        self.check_state(&[VmmState::Ready], "run")?;
+        // Seen before anything runs.
+        if marker.is_empty() {
+            return Ok(RunUntilOutcome::MarkerSeen(Vec::new()));
+        }
+
+        *self.console_watch.lock().unwrap() = Some(MarkerWatch::new(marker));
+        let (cancel, cancelled) = mpsc::channel::<()>();
+        let pause = self.pause_trigger();
+        let timer = thread::spawn(move || {
+            let expired = cancelled.recv_timeout(timeout) == Err(RecvTimeoutError::Timeout);
+            if expired {
+                pause.pause();
+            }
+            expired
+        });
+
+        let result = self.run();
+        drop(cancel);
+        let expired = timer.join().unwrap_or(false);
+        let watch = self.console_watch.lock().unwrap().take();
+        let reason = result?;
+        if reason == ExitReason::Paused {
+            // The timer may have paused the VM once it stopped for the marker: the next run
+            // would stop at once.
+            let _ = self.exit.eventfd.read();
+            match self.exit.take() {
+                Some(ExitReason::Paused) | None => {}
+                Some(reason) => self.exit.notify(reason),
+            }
+        }
+
+        Ok(match (reason, watch) {
+            (ExitReason::Paused, Some(watch)) if watch.seen() => {
+                RunUntilOutcome::MarkerSeen(watch.into_output())
+            }
+            (ExitReason::Paused, Some(watch)) if expired => {
+                RunUntilOutcome::TimedOut(watch.into_output())
+            }
+            (reason, _) => RunUntilOutcome::GuestExited(reason),
+        })
+    }
+
+    /// Run a VM paused by [`run_until`](VMM::run_until) or through a [`PauseTrigger`] for
+    /// the rest of its session, like [`run`](VMM::run).
+    pub fn resume_run(&mut self) -> Result<ExitReason> {
+        // It ran already.
+        if !self.devices_started {
+            return Err(Error::InvalidStateTransition {
+                from: self.state,
+                attempted: "resume",
+            });
+        }
+
+        self.run()
+    }
+
+    // Run each vCPU on its own thread, until it stops the VM or is told to exit.
+    fn start_vcpus(&mut self) -> Vec<JoinHandle<Vcpu>> {
+        #[cfg(target_arch = "x86_64")]
+        if let Some(gdb) = self.gdb.as_ref() {
+            gdb.set_vcpus(self.vcpus.iter().map(Vcpu::handle).collect());
+        }
+        // Before they start, for them to stay out of the guest when it is paused.
+        if let Some(pause) = self.vm_pause.as_ref() {
+            pause.set_vcpus(self.vcpus.iter().map(Vcpu::handle).collect());
+        }
+
+        let mut vcpu_threads = Vec::new();
+        for mut vcpu in self.vcpus.drain(..) {
+            println!("Starting vCPU {:?}", vcpu.index);
+            self.vcpu_handles.push(vcpu.handle());
+            let exit = self.exit.clone();
+            let spawned = thread::Builder::new().spawn(move || {
+                if let Some(reason) = vcpu.run_until_exit() {
+                    exit.notify(reason);
+                }
+                vcpu
+            });
+            if let Ok(vcpu_thread) = spawned {
+                vcpu_threads.push(vcpu_thread);
+            }
+        }
+        if let Some(events) = self.events.as_ref() {
+            events.emit(Event::VcpusStarted {
+                vcpus: vcpu_threads.len(),
+            });
+        }
+
+        vcpu_threads
+    }
+
+    // Get the vCPUs of `vcpu_threads` out of the guest, and back, to run them again.
+    fn stop_vcpus(&mut self, vcpu_threads: Vec<JoinHandle<Vcpu>>) {
+        for handle in self.vcpu_handles.drain(..) {
+            handle.set_state(VcpuRunState::Exiting);
+        }
+        for vcpu_thread in vcpu_threads {
+            // The vCPU loop catches the panics.
+            let mut vcpu = vcpu_thread.join().expect("vCPU thread panicked");
+            vcpu.reset_handle();
+            self.vcpus.push(vcpu);
+        }
+    }
+
+    // Start the device threads, on the first run. The device I/O runs on its own thread, so
+    // that a failing device does not stop the VM.
+    fn start_devices(&mut self) -> Result<()> {
+        if self.devices_started {
+            return Ok(());
+        }
+
+        // Along with the guest.
+        if let Some(recorder) = self.console_recorder.as_ref() {
+            recorder.start();
+        }
+        if let Some(replay) = self.console_replay.as_mut() {
+            replay.start();
+        }
+
+        if let Some(virtio_net) = self.virtio_net.as_ref() {
+            self.net_worker =
+                Some(devices::net::spawn_worker(virtio_net.clone()).map_err(Error::NetWorker)?);
+        }
+        if let Some(virtio_9p) = self.virtio_9p.as_ref() {
+            devices::p9::spawn_worker(virtio_9p.clone()).map_err(Error::SharedDirWorker)?;
+        }
+        #[cfg(target_arch = "x86_64")]
+        self.irq_trigger.start().map_err(Error::IoapicWorker)?;
+        self.devices_started = true;
+
+        Ok(())
+    }
+
+    // Release what the stopped VM holds, in order: the vCPUs, joining `vcpu_threads`, then
+    // the serial output, which reaches the console file in full, then the network
+    // interfaces and the event loop. The KVM file descriptors go last, along with the VMM.
+    //
+    // This can be called again, e.g. when the VMM is dropped, and does nothing more.
+    fn teardown(&mut self, vcpu_threads: Vec<JoinHandle<Vcpu>>) {
+        for handle in self.vcpu_handles.drain(..) {
+            handle.set_state(VcpuRunState::Exiting);
+        }
+        for vcpu_thread in vcpu_threads {
+            // The vCPU loop catches the panics.
+            match vcpu_thread.join() {
+                Ok(vcpu) => log_vcpu_counters(vcpu.index, &vcpu.stats().counters()),
+                Err(_) => log::warn!("vCPU thread panicked"),
+            }
+        }
+        // Their file descriptors are closed along with them.
+        self.vcpus.clear();
+
+        for flusher in self.output_flushers.drain(..) {
+            if !flusher.close(OUTPUT_FLUSH_TIMEOUT) {
+                log::warn!(
+                    "The serial output did not get out within {:?}",
+                    OUTPUT_FLUSH_TIMEOUT
+                );
+            }
+        }
+        if let Some(recorder) = self.console_recorder.take() {
+            if let Err(e) = recorder.finish() {
+                log::warn!("Failed to record the console session: {}", e);
+            }
+        }
+
+        if let Some(worker) = self.net_worker.take() {
+            if worker.stop().is_err() {
+                println!("virtio-net worker panicked");
+            }
+        }
+        for slot in self.net_slots.iter() {
+            slot.lock().unwrap().unplug();
+        }
+        // The bus holds the devices too, the tap is closed once both let go of it, unless it
+        // goes back to the parts.
+        let virtio_net = self.virtio_net.take();
+        #[cfg(target_arch = "x86_64")]
+        {
+            self.vcpu_hotplug = None;
+        }
+        self.io_manager = Arc::new(Mutex::new(IoManager::new()));
+        if let Some(net) = virtio_net.filter(|_| self.keep_parts) {
+            match Arc::try_unwrap(net) {
+                Ok(net) => {
+                    self.net_interface = Some(net.into_inner().unwrap().into_interface());
+                }
+                Err(_) => log::warn!("The network interface is still in use, it is closed"),
+            }
+        }
+
+        // Along with the handlers, e.g. the API one.
+        if let Err(e) = self.epoll.close() {
+            log::debug!("Failed to deregister a file descriptor: {}", e);
+        }
+    }
+
+    // Dispatch the events until the VM stops, or `deadline`.
+    fn event_loop(&mut self, deadline: Option<Instant>) -> Result<ExitReason> {
+        loop {
+            let console_input_waiting = self.process_console_input()?;
+            let replay_waiting = self.process_console_replay()?;
+
+            // Nothing tells us when the guest reads, poll the backlog while an input waits.
+            // So is the replayed input, until it is due.
+            let mut timeout =
+                if self.epoll.is_paused(STDIN_TOKEN) || console_input_waiting || replay_waiting {
+                    Some(INPUT_BACKLOG_POLL)
+                } else {
+                    None
+                };
+            if let Some(deadline) = deadline {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    return Ok(ExitReason::Timeout);
+                }
+                timeout = Some(timeout.map_or(remaining, |timeout| timeout.min(remaining)));
+            }
+
+            if let Some(reason) = self.epoll.run_once(timeout)? {
+                return Ok(reason);
+            }
+
+            if self.epoll.is_paused(STDIN_TOKEN)
+                && self.serial.lock().unwrap().pending_input() < serial::INPUT_BACKLOG_LOW
+            {
+                self.epoll.resume(STDIN_TOKEN).map_err(Error::EpollError)?;
+            }
+        }
+    }
+
+    /// Take the channel to the guest agent.
+    ///
+    /// This is only available once, and when the second serial port is configured
+    /// in agent mode.
+    pub fn agent_channel(&mut self) -> Option<AgentChannel> {
+        let frames = self.agent_frames.take()?;
+        // The agent frames are only set along with the second serial port.
+        let serial = self.serial2.as_ref()?.clone();
+
+        Some(AgentChannel::new(serial, frames))
+    }
+
+    /// Take the client running commands through the guest agent, see
+    /// [`agent_channel`](VMM::agent_channel): it uses the same channel.
+    pub fn agent(&mut self) -> Option<Agent> {
+        self.agent_channel().map(Agent::new)
+    }
+
+    // Forward the console input file to the guest, as fast as it reads it. Returns whether
+    // the input waits for the guest.
+    fn process_console_input(&mut self) -> Result<bool> {
+        let input = match self.console_input.as_mut() {
+            Some(input) => input,
+            None => return Ok(false),
+        };
+        let mut console = self.serial.lock().unwrap();
+
+        let ready = console.rx_enabled() && console.pending_input() < serial::INPUT_BACKLOG_LOW;
+        input
+            .set_polled(&mut self.epoll, CONSOLE_INPUT_TOKEN, ready)
+            .map_err(Error::EpollError)?;
+        if !ready {
+            return Ok(true);
+        }
+
+        let mut out = [0u8; 64];
+        while console.pending_input() <= serial::INPUT_BACKLOG_HIGH {
+            match input.read(&mut out).map_err(Error::ConsoleInputRead)? {
+                Some(0) => {
+                    // The input ended, the guest keeps running.
+                    input
+                        .set_polled(&mut self.epoll, CONSOLE_INPUT_TOKEN, false)
+                        .map_err(Error::EpollError)?;
+                    self.console_input = None;
+                    return Ok(false);
+                }
+                Some(count) => console
+                    .enqueue_input(&out[..count])
+                    .map_err(Error::StdinWrite)?,
+                // The input is polled until it has more.
+                None => return Ok(false),
+            }
+        }
+
+        // The backlog is full, until the guest reads.
+        Ok(true)
+    }
+
+    // Send the replayed input to the guest, once it is due. Returns whether some input is
+    // still to be sent.
+    fn process_console_replay(&mut self) -> Result<bool> {
+        let replay = match self.console_replay.as_mut() {
+            Some(replay) => replay,
+            None => return Ok(false),
+        };
+        let mut console = self.serial.lock().unwrap();
+
+        while console.pending_input() < serial::INPUT_BACKLOG_LOW {
+            match replay.next_input() {
+                Some(data) => console.enqueue_input(&data).map_err(Error::StdinWrite)?,
+                None => break,
+            }
+        }
+
+        Ok(!replay.is_done())
+    }
+
+    pub(crate) fn configure(&mut self, config: &VMMConfig) -> Result<()> {
+        let start = Instant::now();
+        self.config = Some(config.clone());
+        // Before starting any thread, so that they all share the namespaces, and the root
+        // the jail changes.
+        if config.jail.as_ref().is_some_and(|jail| jail.unshare) {
+            jail::unshare().map_err(Error::Jail)?;
+        }
+        // Process-wide, a VM cloned from this one traces its interrupts too.
+        if config.trace_irq {
+            irq_trace::enable();
+        }
+        self.configure_events(config.event_fifo.as_deref())?;
+        self.set_resource_locking(config.lock_resources);
+        self.configure_console_session(
+            config.console_record.as_deref(),
+            config.console_replay.as_ref(),
+        )?;
+        self.configure_console(
+            &config.console,
+            config.panic_detect,
+            config.console_error_policy,
+        )?;
+        self.configure_console_input(config.console_input.as_deref())?;
+        self.configure_serial2(config.serial2.as_ref())?;
+        self.set_dirty_tracking(config.dirty_tracking);
+        self.set_memory_backend(config.memory_backend.clone());
+        self.set_memory_share(config.memory_share, config.memory_share_fd);
+        self.set_memory_hints(config.memory_ksm, config.memory_thp, config.strict);
+        self.configure_memory(config.memory)?;
+        self.load_default_cmdline(&config.console)?;
+
+        self.configure_net(config.net.as_ref())?;
+        self.configure_net_slots(config.net_slots)?;
+        self.configure_pmem(config.pmem.as_ref())?;
+        self.configure_shared_dir(config.shared_dir.as_ref())?;
+        self.configure_watchdog(config.watchdog.as_ref())?;
+        // Last, replacing the console, root, init and panic parameters of the VMM.
+        if let Some(cmdline) = config.cmdline.as_deref() {
+            self.cmdline
+                .append(cmdline)
+                .map_err(Error::CmdlineCompose)?;
+        }
+
+        #[cfg(target_arch = "x86_64")]
+        {
+            self.configure_vcpu_hotplug(&config.topology, config.cpus)?;
+            // Once all the devices have their interrupts.
+            self.configure_io(config.irqchip)?;
+            // Before the kernel, which gets its range reserved in the E820 map.
+            self.configure_firmware(config.firmware.as_ref())?;
+
+            self.check_state(&DEVICE_STATES, "load the kernel of")?;
+            // Checked to fit before loading anything.
+            let cmdline = self.cmdline.build().map_err(Error::CmdlineCompose)?;
+            let (mut kernel, mut initramfs) = open_images(config)?;
+            let entry = kernel::inspect(&layout::ram_regions(config.memory), &mut kernel)?.entry;
+            let guest_memory = self.guest_memory.clone();
+            let memory_map = self.resources.memory_map().clone();
+            // The vCPUs only need the entry point of the kernel, from its headers: they are
+            // set up along with the tables, none of them in the memory the kernel and the
+            // initramfs load to, while a thread copies the images. The scope joins it on
+            // errors too, nothing writes to the guest memory past configure().
+            thread::scope(|scope| {
+                let loading = scope.spawn(|| {
+                    let load_start = Instant::now();
+                    kernel::kernel_setup(
+                        &guest_memory,
+                        &mut kernel,
+                        initramfs.as_mut(),
+                        config.initrd_in_memory,
+                        &cmdline,
+                        &memory_map,
+                    )
+                    .map(|kernel_load| (kernel_load, load_start.elapsed()))
+                });
+                let configured = self
+                    .configure_acpi(&config.topology, config.cpus, config.mptable)
+                    .and_then(|()| self.configure_smbios(config.uuid, &config.smbios))
+                    .and_then(|()| {
+                        self.configure_vcpus(
+                            &config.topology,
+                            config.cpus,
+                            config.cpu_template,
+                            config.pv_features,
+                            GuestAddress(entry),
+                        )
+                    });
+
+                // The vCPUs cannot run before the kernel is in the guest memory.
+                let (kernel_load, load_time) = loading
+                    .join()
+                    .unwrap_or_else(|e| std::panic::resume_unwind(e))?;
+                debug_assert_eq!(kernel_load.kernel_load, GuestAddress(entry));
+                configured?;
+                self.kernel_load_time = load_time;
+                self.state = VmmState::Ready;
+                Ok::<(), Error>(())
+            })?;
+            self.configure_tsc(config.tsc_khz)?;
+            self.configure_gdb(config.gdb.as_ref())?;
+            if config.restart_on_reboot {
+                self.save_boot_state(
+                    kernel,
+                    initramfs,
+                    config.initrd_in_memory,
+                    cmdline,
+                    config.max_reboots,
+                )?;
+            }
+        }
+
+        // The CPU template, the MP table and the TSC frequency only apply to x86_64.
+        #[cfg(target_arch = "aarch64")]
+        {
+            self.check_state(&DEVICE_STATES, "load the kernel of")?;
+            let (mut kernel, mut initramfs) = open_images(config)?;
+            let load_start = Instant::now();
+            let images = kernel::kernel_setup(
+                &self.guest_memory,
+                &mut kernel,
+                initramfs.as_mut(),
+                config.initrd_in_memory,
+            )?;
+            self.kernel_load_time = load_start.elapsed();
+            self.state = VmmState::KernelLoaded;
+            self.configure_vcpus(&config.topology, &images)?;
+            // Once all the devices have their interrupts, and the vCPUs exist.
+            self.configure_io()?;
+            self.configure_fdt(&config.topology, &images)?;
+        }
+
+        self.configure_api(config.api_socket.as_deref())?;
+        self.set_timeout(config.timeout);
+        if let Some(events) = self.events.as_ref() {
+            events.emit(Event::Configured {
+                configure_us: start.elapsed().as_micros() as u64,
+                memory_configure_us: self.memory_configure_time.as_micros() as u64,
+                kernel_load_us: self.kernel_load_time.as_micros() as u64,
+                vcpu_configure_us: self.vcpu_configure_time.as_micros() as u64,
+                memory_advice: self
+                    .memory_advice
+                    .iter()
+                    .map(|advice| advice.name)
+                    .collect(),
+            });
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for VMM {
+    fn drop(&mut self) {
+        // Once run() stopped the VM, there is nothing left to do.
+        self.teardown(Vec::new());
+    }
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use super::*;
+
+    use std::fs;
+    use std::os::unix::fs::FileExt;
+
+    use devices::net::interface::Interface;
+    use kvm_ioctls::VcpuExit;
+    use vm_device::bus::{MmioAddress, MmioAddressOffset, MmioRange};
+    use vm_device::MutDeviceMmio;
+    use vm_memory::Bytes;
+    use vmm_sys_util::tempfile::TempFile;
+
+    // Pages of the mapping at `address` written to since they were mapped, in KiB.
+    fn private_dirty_kib(address: u64) -> u64 {
+        let smaps = fs::read_to_string("/proc/self/smaps").unwrap();
+        let mut in_mapping = false;
+        for line in smaps.lines() {
+            let first = line.split_whitespace().next().unwrap_or_default();
+            match first.split_once('-') {
+                // Mappings start with their address range, then come their fields.
+                Some((start, end)) if !first.ends_with(':') => {
+                    let start = u64::from_str_radix(start, 16).unwrap();
+                    let end = u64::from_str_radix(end, 16).unwrap();
+                    in_mapping = (start..end).contains(&address);
+                }
+                _ if in_mapping && first == "Private_Dirty:" => {
+                    return line[first.len()..]
+                        .trim()
+                        .trim_end_matches(" kB")
+                        .parse()
+                        .unwrap();
+                }
+                _ => {}
+            }
+        }
+        panic!("no mapping at {:#x}", address);
+    }
+
+    #[test]
+    fn memory_template() {
+        let size = config::MIN_MEMORY;
+        let template = TempFile::new().unwrap();
+        template.as_file().set_len(size).unwrap();
+        template
+            .as_file()
+            .write_all_at(b"template", 0x1000)
+            .unwrap();
+        let regions = layout::ram_regions(size);
+
+        let vms: Vec<_> = (0..2)
+            .map(|_| template_memory(&regions, template.as_path()).unwrap())
+            .collect();
+        for (index, memory) in vms.iter().enumerate() {
+            let mut data = [0u8; 8];
+            memory.read_slice(&mut data, GuestAddress(0x1000)).unwrap();
+            assert_eq!(&data, b"template");
+            memory
+                .write_slice(format!("vm{}", index).as_bytes(), GuestAddress(0x1000))
+                .unwrap();
+        }
+
+        // Each VM has its own copy of the page it wrote to, and only of this one.
+        for (index, memory) in vms.iter().enumerate() {
+            let mut data = [0u8; 8];
+            memory.read_slice(&mut data, GuestAddress(0x1000)).unwrap();
+            assert_eq!(&data, format!("vm{}plate", index).as_bytes());
+
+            let mut end = [0u8; 8];
+            memory.read_slice(&mut end, GuestAddress(size - 8)).unwrap();
+            assert_eq!(end, [0; 8]);
+
+            let host_address = memory.get_host_address(GuestAddress(0)).unwrap() as u64;
+            assert_eq!(private_dirty_kib(host_address), 4);
+        }
+
+        let mut data = [0u8; 8];
+        template.as_file().read_exact_at(&mut data, 0x1000).unwrap();
+        assert_eq!(&data, b"template");
+
+        // The file must hold exactly the RAM.
+        assert!(matches!(
+            template_memory(&layout::ram_regions(size * 2), template.as_path()),
+            Err(Error::MemoryBackendSize { .. })
+        ));
+    }
+
+    #[test]
+    #[ignore = "needs KVM"]
+    fn read_guest() {
+        let mut vmm = VMM::new().unwrap();
+        vmm.set_memory_share(Some(MemoryShare::Memfd), None);
+        vmm.configure_memory(config::MIN_MEMORY).unwrap();
+        vmm.guest_memory
+            .write_slice(b"Linux version", GuestAddress(0x1000))
+            .unwrap();
+
+        assert_eq!(vmm.read_guest(0x1000, 13).unwrap(), b"Linux version");
+        // The memfd holds the same RAM.
+        let shared = vmm.shared_memory.as_ref().unwrap();
+        let mut data = [0u8; 13];
+        shared.file().read_exact_at(&mut data, 0x1000).unwrap();
+        assert_eq!(&data, b"Linux version");
+
+        assert!(matches!(
+            vmm.read_guest(config::MIN_MEMORY - 4, 8),
+            Err(Error::GuestRange { len: 8, .. })
+        ));
+        assert!(vmm.read_guest(u64::MAX, 2).is_err());
+    }
+
+    #[test]
+    #[ignore = "needs KVM"]
+    fn dirty_bitmap() {
+        let mut vmm = VMM::new().unwrap();
+        vmm.set_dirty_tracking(true);
+        vmm.configure_memory(config::MIN_MEMORY).unwrap();
+
+        // Real mode code writing to three pages, then halting.
+        let code = [
+            0xc6, 0x06, 0x00, 0x30, 0x55, // mov byte [0x3000], 0x55
+            0xc6, 0x06, 0x00, 0x50, 0x55, // mov byte [0x5000], 0x55
+            0xc6, 0x06, 0xff, 0x9f, 0x55, // mov byte [0x9fff], 0x55
+            0xf4, // hlt
+        ];
+        vmm.guest_memory
+            .write_slice(&code, GuestAddress(0x1000))
+            .unwrap();
+        // The VMM writes are not logged.
+        assert_eq!(vmm.dirty_bitmap().unwrap().dirty_pages(), 0);
+
+        let vcpu = vmm.vm_fd.create_vcpu(0).unwrap();
+        let mut sregs = vcpu.get_sregs().unwrap();
+        sregs.cs.base = 0;
+        sregs.cs.selector = 0;
+        vcpu.set_sregs(&sregs).unwrap();
+        let mut regs = vcpu.get_regs().unwrap();
+        regs.rip = 0x1000;
+        regs.rflags = 2;
+        vcpu.set_regs(&regs).unwrap();
+        match vcpu.run().unwrap() {
+            VcpuExit::Hlt => {}
+            exit => panic!("unexpected exit {:?}", exit),
+        }
+
+        let bitmap = vmm.dirty_bitmap().unwrap();
+        assert_eq!(bitmap.dirty_pages(), 3);
+        for address in [0x3000, 0x5000, 0x9000, 0x9fff] {
+            assert!(bitmap.is_dirty(address), "{:#x} is not dirty", address);
+        }
+        for address in [0x1000, 0x4000, 0xa000] {
+            assert!(!bitmap.is_dirty(address), "{:#x} is dirty", address);
+        }
+
+        // The log starts over.
+        assert_eq!(vmm.dirty_bitmap().unwrap().dirty_pages(), 0);
+    }
+
+    // A vCPU starting in real mode, at the code written to 0x1000, with a flat 4 GiB data
+    // segment to reach the MMIO gap.
+    fn real_mode_vcpu(vmm: &VMM, code: &[u8]) -> Vcpu {
+        vmm.guest_memory
+            .write_slice(code, GuestAddress(0x1000))
+            .unwrap();
+
+        let vcpu = Vcpu::new(
+            &vmm.vm_fd,
+            0,
+            0,
+            vmm.io_manager.clone(),
+            vmm.unknown_ports.clone(),
+        )
+        .unwrap();
+        let mut sregs = vcpu.vcpu_fd.get_sregs().unwrap();
+        sregs.cs.base = 0;
+        sregs.cs.selector = 0;
+        sregs.ds.limit = 0xffff_ffff;
+        sregs.ds.g = 1;
+        vcpu.vcpu_fd.set_sregs(&sregs).unwrap();
+        let mut regs = vcpu.vcpu_fd.get_regs().unwrap();
+        regs.rip = 0x1000;
+        regs.rflags = 2;
+        vcpu.vcpu_fd.set_regs(&regs).unwrap();
+
+        vcpu
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    #[ignore = "needs KVM"]
+    fn pvpanic() {
+        let mut vmm = VMM::new().unwrap();
+        vmm.configure_memory(config::MIN_MEMORY).unwrap();
+        vmm.configure_acpi(&CpuTopology::flat(1), 1, false).unwrap();
+
+        // Real mode code reporting a panic to the pvpanic device, then halting.
+        let code = [
+            0xb0, 0x01, // mov al, 1
+            0xba, 0x05, 0x05, // mov dx, 0x505
+            0xee, // out dx, al
+            0xf4, // hlt
+        ];
+        let mut vcpu = real_mode_vcpu(&vmm, &code);
+        while vcpu.run().is_none() {}
+
+        // The device asked the VMM to stop before the guest halted.
+        assert_eq!(
+            vmm.exit.take(),
+            Some(ExitReason::GuestPanic(PanicReport::Pvpanic(
+                PvpanicEvent::Panicked
+            )))
+        );
+    }
+
+    #[test]
+    #[ignore = "needs KVM"]
+    fn unclaimed_mmio() {
+        let mut vmm = VMM::new().unwrap();
+        vmm.configure_memory(config::MIN_MEMORY).unwrap();
+
+        // Real mode code writing to and reading from an address no device claims, then
+        // halting.
+        let code = [
+            0x67, 0xc6, 0x05, 0xef, 0xbe, 0xad, 0xde, 0x55, // mov byte [0xdeadbeef], 0x55
+            0x67, 0xa0, 0xef, 0xbe, 0xad, 0xde, // mov al, [0xdeadbeef]
+            0xa2, 0x00, 0x30, // mov [0x3000], al
+            0xf4, // hlt
+        ];
+        let mut vcpu = real_mode_vcpu(&vmm, &code);
+        let stats = vcpu.stats();
+        assert_eq!(vcpu.run_until_exit(), Some(ExitReason::GuestShutdown));
+
+        // The write went nowhere, the read returned all ones, both were counted.
+        let value: u8 = vmm.guest_memory.read_obj(GuestAddress(0x3000)).unwrap();
+        assert_eq!(value, 0xff);
+        assert_eq!(stats.counters().stray_mmio, 2);
+    }
+
+    struct FailingDevice;
+
+    impl MutDeviceMmio for FailingDevice {
+        fn mmio_read(&mut self, _base: MmioAddress, _offset: MmioAddressOffset, data: &mut [u8]) {
+            data.fill(0);
+        }
+
+        fn mmio_write(&mut self, _base: MmioAddress, _offset: MmioAddressOffset, _data: &[u8]) {
+            panic!("device failure");
+        }
+    }
+
+    #[test]
+    #[ignore = "needs KVM"]
+    fn vcpu_panic() {
+        let mut vmm = VMM::new().unwrap();
+        vmm.configure_memory(config::MIN_MEMORY).unwrap();
+        vmm.io_manager
+            .lock()
+            .unwrap()
+            .register_mmio(
+                MmioRange::new(MmioAddress(0xdead_b000), 0x1000).unwrap(),
+                Arc::new(Mutex::new(FailingDevice)),
+            )
+            .unwrap();
+
+        // Real mode code writing to the device, which panics.
+        let code = [
+            0x67, 0xc6, 0x05, 0xef, 0xbe, 0xad, 0xde, 0x55, // mov byte [0xdeadbeef], 0x55
+            0xf4, // hlt
+        ];
+        let mut vcpu = real_mode_vcpu(&vmm, &code);
+        assert_eq!(
+            vcpu.run_until_exit(),
+            Some(ExitReason::VcpuError(
+                "vCPU 0 panicked: device failure".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    #[ignore = "needs KVM"]
+    fn vcpu_kick() {
+        let mut vmm = VMM::new().unwrap();
+        vmm.configure_memory(config::MIN_MEMORY).unwrap();
+
+        // Real mode code looping forever, without leaving guest mode.
+        let code = [
+            0xeb, 0xfe, // jmp $
+        ];
+        let mut vcpu = real_mode_vcpu(&vmm, &code);
+        let handle = vcpu.handle();
+        let stats = vcpu.stats();
+        let thread = thread::spawn(move || vcpu.run_until_exit());
+
+        // A kick gets the vCPU back to the guest.
+        thread::sleep(Duration::from_millis(50));
+        handle.kick();
+        thread::sleep(Duration::from_millis(50));
+        assert!(!thread.is_finished());
+
+        // A pause keeps it out of the guest.
+        handle.set_state(VcpuRunState::Paused);
+        assert!(handle.wait_parked(Duration::from_secs(1)));
+        let exits = stats.counters().exits;
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(stats.counters().exits, exits);
+        assert!(!thread.is_finished());
+
+        // And the vCPU stops without a reason of its own, paused or not.
+        handle.set_state(VcpuRunState::Running);
+        handle.set_state(VcpuRunState::Exiting);
+        assert_eq!(thread.join().unwrap(), None);
+        assert_eq!(handle.state(), VcpuRunState::Exiting);
+        // Nothing is left to kick.
+        handle.kick();
+    }
+
+    #[test]
+    #[ignore = "needs KVM"]
+    fn vcpu_exit_counters() {
+        let mut vmm = VMM::new().unwrap();
+        vmm.configure_memory(config::MIN_MEMORY).unwrap();
+
+        // Real mode code writing to the delay port forever.
+        let code = [
+            0xba, 0x80, 0x00, // mov dx, 0x80
+            0xee, // out dx, al
+            0xeb, 0xfd, // jmp -3
+        ];
+        let mut vcpu = real_mode_vcpu(&vmm, &code);
+        let handle = vcpu.handle();
+        let stats = vcpu.stats();
+        let thread = thread::spawn(move || vcpu.run_until_exit());
+        thread::sleep(Duration::from_millis(100));
+        handle.set_state(VcpuRunState::Exiting);
+        assert_eq!(thread.join().unwrap(), None);
+
+        // The port writes are about all the exits, and took some time to handle.
+        let counters = stats.counters();
+        assert!(counters.exits > 100);
+        assert!(counters.exit_kinds.io_out * 10 > counters.exits * 9);
+        assert_eq!(counters.last_exit, Some("io_out"));
+        #[cfg(feature = "exit-timing")]
+        assert!(counters.handling_ns > 0);
+        #[cfg(not(feature = "exit-timing"))]
+        assert_eq!(counters.handling_ns, 0);
+    }
+
+    // The CPU time a thread used so far.
+    fn thread_cpu_time(thread: &thread::JoinHandle<Option<ExitReason>>) -> Duration {
+        use std::os::unix::thread::JoinHandleExt;
+
+        let mut clock: libc::clockid_t = 0;
+        let mut time = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        // Safe because the thread is running until joined, and we check the results.
+        unsafe {
+            assert_eq!(
+                libc::pthread_getcpuclockid(thread.as_pthread_t(), &mut clock),
+                0
+            );
+            assert_eq!(libc::clock_gettime(clock, &mut time), 0);
+        }
+        Duration::new(time.tv_sec as u64, time.tv_nsec as u32)
+    }
+
+    #[test]
+    #[ignore = "needs KVM"]
+    fn vm_pause() {
+        let mut vmm = VMM::new().unwrap();
+        vmm.configure_memory(config::MIN_MEMORY).unwrap();
+
+        let code = [
+            0xeb, 0xfe, // jmp $
+        ];
+        let mut vcpu = real_mode_vcpu(&vmm, &code);
+        let pause = VmPause::new(
+            None,
+            Vec::new(),
+            Vec::new(),
+            #[cfg(target_arch = "x86_64")]
+            None,
+        );
+        let handle = vcpu.handle();
+        pause.set_vcpus(vec![handle.clone()]);
+        let thread = thread::spawn(move || vcpu.run_until_exit());
+
+        // The paused vCPU thread no longer uses the CPU.
+        pause.pause();
+        pause.pause();
+        assert!(pause.paused());
+        let used = thread_cpu_time(&thread);
+        thread::sleep(Duration::from_millis(200));
+        assert!(thread_cpu_time(&thread) - used < Duration::from_millis(5));
+
+        // Until resumed.
+        pause.resume();
+        assert!(!pause.paused());
+        let used = thread_cpu_time(&thread);
+        thread::sleep(Duration::from_millis(200));
+        assert!(thread_cpu_time(&thread) - used > Duration::from_millis(50));
+
+        handle.set_state(VcpuRunState::Exiting);
+        assert_eq!(thread.join().unwrap(), None);
+    }
+
+    #[test]
+    #[ignore = "needs KVM"]
+    fn state_machine() {
+        let topology = CpuTopology::flat(1);
+        let net: NetConfig = "user".parse().unwrap();
+        let invalid = |result: Result<()>, state: VmmState| matches!(result, Err(Error::InvalidStateTransition { from, .. }) if from == state);
+
+        // Only the serial ports and their sinks come before the memory.
+        let mut vmm = VMM::new().unwrap();
+        assert_eq!(vmm.state(), VmmState::Created);
+        assert!(invalid(vmm.configure_net(None), VmmState::Created));
+        assert!(invalid(vmm.configure_net_slots(0), VmmState::Created));
+        assert!(invalid(vmm.configure_pmem(None), VmmState::Created));
+        assert!(invalid(vmm.configure_firmware(None), VmmState::Created));
+        assert!(invalid(vmm.configure_shared_dir(None), VmmState::Created));
+        assert!(invalid(vmm.configure_watchdog(None), VmmState::Created));
+        assert!(invalid(
+            vmm.configure_vcpu_hotplug(&topology, 1),
+            VmmState::Created
+        ));
+        assert!(invalid(
+            vmm.configure_io(IrqchipMode::Full),
+            VmmState::Created
+        ));
+        assert!(invalid(
+            vmm.configure_acpi(&topology, 1, false),
+            VmmState::Created
+        ));
+        assert!(invalid(
+            vmm.configure_vcpus(
+                &topology,
+                1,
+                CpuTemplate::default(),
+                PvFeatures::all(),
+                GuestAddress(0),
+            ),
+            VmmState::Created
+        ));
+        assert!(invalid(vmm.configure_tsc(None), VmmState::Created));
+        assert!(matches!(
+            vmm.run(),
+            Err(Error::InvalidStateTransition {
+                from: VmmState::Created,
+                attempted: "run",
+            })
+        ));
+        vmm.configure_serial2(None).unwrap();
+
+        // The memory is only configured once, before the vCPUs.
+        vmm.configure_memory(config::MIN_MEMORY).unwrap();
+        assert_eq!(vmm.state(), VmmState::MemoryConfigured);
+        assert!(invalid(
+            vmm.configure_memory(config::MIN_MEMORY),
+            VmmState::MemoryConfigured
+        ));
+        assert!(invalid(vmm.configure_tsc(None), VmmState::MemoryConfigured));
+
+        // A second virtio-net device would leak the first one.
+        vmm.configure_net(Some(&net)).unwrap();
+        assert_eq!(vmm.state(), VmmState::DevicesConfigured);
+        assert!(matches!(
+            vmm.configure_net(Some(&net)),
+            Err(Error::NetConfigured)
+        ));
+        vmm.configure_io(IrqchipMode::Full).unwrap();
+
+        // The vCPUs are set up while the kernel loads, the VM only runs once it is loaded.
+        vmm.configure_vcpus(
+            &topology,
+            1,
+            CpuTemplate::default(),
+            PvFeatures::all(),
+            GuestAddress(layout::HIMEM_START),
+        )
+        .unwrap();
+        assert_eq!(vmm.state(), VmmState::DevicesConfigured);
+        assert!(invalid(
+            vmm.configure_tsc(None),
+            VmmState::DevicesConfigured
+        ));
+        assert!(matches!(
+            vmm.run(),
+            Err(Error::InvalidStateTransition {
+                from: VmmState::DevicesConfigured,
+                attempted: "run",
+            })
+        ));
+
+        // Nothing changes once the VM stopped.
+        vmm.state = VmmState::Stopped;
+        assert!(matches!(
+            vmm.run(),
+            Err(Error::InvalidStateTransition {
+                from: VmmState::Stopped,
+                ..
+            })
+        ));
+        assert!(invalid(vmm.configure_serial2(None), VmmState::Stopped));
+        assert!(invalid(vmm.configure_events(None), VmmState::Stopped));
+        assert!(invalid(vmm.configure_api(None), VmmState::Stopped));
+        assert!(invalid(
+            vmm.configure_console_input(None),
+            VmmState::Stopped
+        ));
+        assert!(invalid(
+            vmm.configure_console_session(None, None),
+            VmmState::Stopped
+        ));
+    }
+
+    #[test]
+    #[ignore = "needs KVM"]
+    fn reused_parts() {
+        let net: NetConfig = "user".parse().unwrap();
+        let mut parts = VmmParts {
+            net: Some(NetInterface::open_config(&net).unwrap()),
+            console: None,
+        };
+        let fd = parts.net.as_ref().unwrap().as_raw_fd();
+
+        // Two VMs in a row, over the same interface.
+        for _ in 0..2 {
+            let mut vmm = VMM::new().unwrap();
+            vmm.keep_parts = true;
+            vmm.net_interface = parts.net.take();
+            vmm.configure_memory(config::MIN_MEMORY).unwrap();
+            vmm.configure_net(Some(&net)).unwrap();
+            assert!(vmm.net_interface.is_none());
+
+            parts = vmm.into_parts();
+            assert_eq!(parts.net.as_ref().unwrap().as_raw_fd(), fd);
+        }
+    }
 }
@@ -0,0 +1,94 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Log level of the VMM, which changes while the VM runs.
+//!
+//! The logger of the binary filters the records with [`enabled`], the API socket and
+//! SIGUSR1 change the level, so that a misbehaving VM can be debugged without restarting
+//! it. The level is also the `log` crate maximum, which the macros check first.
+//!
+//! The vCPU exit trace logs every exit at the debug level. It is separate, as it slows the
+//! guest down: `-vv` turns it on at startup, the API socket at runtime.
+//!
+//! Both are process-wide, and a relaxed atomic load to check.
+
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+use log::{Level, LevelFilter};
+
+// The levels, by their order in `LevelFilter`.
+const LEVELS: [LevelFilter; 6] = [
+    LevelFilter::Off,
+    LevelFilter::Error,
+    LevelFilter::Warn,
+    LevelFilter::Info,
+    LevelFilter::Debug,
+    LevelFilter::Trace,
+];
+
+static LEVEL: AtomicU8 = AtomicU8::new(LevelFilter::Warn as u8);
+static TRACE_EXITS: AtomicBool = AtomicBool::new(false);
+
+/// Log the records up to `level`.
+pub fn set_level(level: LevelFilter) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+    log::set_max_level(level);
+}
+
+pub fn level() -> LevelFilter {
+    LEVELS[usize::from(LEVEL.load(Ordering::Relaxed))]
+}
+
+/// Whether the records of `level` are logged.
+pub fn enabled(level: Level) -> bool {
+    level <= self::level()
+}
+
+/// Log one level more, from warn up to trace and back to warn. Returns the new level.
+pub fn cycle_level() -> LevelFilter {
+    let level = match level() {
+        LevelFilter::Trace | LevelFilter::Off | LevelFilter::Error => LevelFilter::Warn,
+        level => LEVELS[level as usize + 1],
+    };
+    set_level(level);
+    level
+}
+
+/// Log every vCPU exit, at the debug level.
+pub fn set_trace_exits(enabled: bool) {
+    TRACE_EXITS.store(enabled, Ordering::Relaxed);
+}
+
+pub fn trace_exits() -> bool {
+    TRACE_EXITS.load(Ordering::Relaxed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The only test changing the process-wide level.
+    #[test]
+    fn levels() {
+        set_level(LevelFilter::Info);
+        assert_eq!(level(), LevelFilter::Info);
+        assert_eq!(log::max_level(), LevelFilter::Info);
+        assert!(enabled(Level::Warn));
+        assert!(enabled(Level::Info));
+        assert!(!enabled(Level::Debug));
+
+        // SIGUSR1 goes up to trace, then back to the default.
+        assert_eq!(cycle_level(), LevelFilter::Debug);
+        assert!(enabled(Level::Debug));
+        assert!(!enabled(Level::Trace));
+        assert_eq!(cycle_level(), LevelFilter::Trace);
+        assert!(enabled(Level::Trace));
+        assert_eq!(cycle_level(), LevelFilter::Warn);
+        assert!(!enabled(Level::Info));
+
+        // Quieter than the default, the signal gets back to it.
+        set_level(LevelFilter::Off);
+        assert!(!enabled(Level::Error));
+        assert_eq!(cycle_level(), LevelFilter::Warn);
+        assert!(enabled(Level::Error));
+    }
+}
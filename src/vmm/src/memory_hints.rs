@@ -0,0 +1,131 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Hints to the host kernel about the guest memory, given with madvise(2): whether KSM may
+//! merge its pages, and whether they go in transparent huge pages.
+
+use std::io;
+
+use vm_memory::{GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
+
+use crate::config::{KsmMode, ThpMode};
+
+/// An madvise(2) advice.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Advice {
+    /// Its name, without the MADV_ prefix, in lower case.
+    pub name: &'static str,
+    value: libc::c_int,
+}
+
+const MERGEABLE: Advice = Advice {
+    name: "mergeable",
+    value: libc::MADV_MERGEABLE,
+};
+const UNMERGEABLE: Advice = Advice {
+    name: "unmergeable",
+    value: libc::MADV_UNMERGEABLE,
+};
+const HUGEPAGE: Advice = Advice {
+    name: "hugepage",
+    value: libc::MADV_HUGEPAGE,
+};
+const NOHUGEPAGE: Advice = Advice {
+    name: "nohugepage",
+    value: libc::MADV_NOHUGEPAGE,
+};
+
+/// The advice for the modes, nothing for those left to the host.
+pub(crate) fn advice(ksm: Option<KsmMode>, thp: ThpMode) -> Vec<Advice> {
+    let ksm = ksm.map(|ksm| match ksm {
+        KsmMode::On => MERGEABLE,
+        KsmMode::Off => UNMERGEABLE,
+    });
+    let thp = match thp {
+        ThpMode::Always => Some(HUGEPAGE),
+        ThpMode::Never => Some(NOHUGEPAGE),
+        ThpMode::Default => None,
+    };
+    ksm.into_iter().chain(thp).collect()
+}
+
+/// Give `advice` about all the guest memory regions.
+///
+/// This fails with EINVAL when the host kernel lacks the feature, e.g. KSM.
+pub(crate) fn advise(memory: &GuestMemoryMmap, advice: Advice) -> io::Result<()> {
+    for region in memory.iter() {
+        let addr = memory
+            .get_host_address(region.start_addr())
+            .map_err(|_| io::Error::from_raw_os_error(libc::EFAULT))?;
+        // Safe because the region is mapped for its whole length, and the advice does not
+        // change its content.
+        let ret = unsafe { libc::madvise(addr.cast(), region.len() as usize, advice.value) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    use vm_memory::GuestAddress;
+
+    // The VmFlags of the mapping at `addr`, from /proc/self/smaps.
+    fn vm_flags(addr: usize) -> Vec<String> {
+        let smaps = fs::read_to_string("/proc/self/smaps").unwrap();
+        let mut inside = false;
+        for line in smaps.lines() {
+            if let Some((start, end)) = line
+                .split_whitespace()
+                .next()
+                .and_then(|range| range.split_once('-'))
+            {
+                if let (Ok(start), Ok(end)) = (
+                    usize::from_str_radix(start, 16),
+                    usize::from_str_radix(end, 16),
+                ) {
+                    inside = (start..end).contains(&addr);
+                    continue;
+                }
+            }
+            if let Some(flags) = line.strip_prefix("VmFlags:").filter(|_| inside) {
+                return flags.split_whitespace().map(str::to_string).collect();
+            }
+        }
+        panic!("no mapping at {:#x}", addr);
+    }
+
+    #[test]
+    fn modes() {
+        assert!(advice(None, ThpMode::Default).is_empty());
+        assert_eq!(
+            advice(Some(KsmMode::On), ThpMode::Never),
+            [MERGEABLE, NOHUGEPAGE]
+        );
+        assert_eq!(advice(Some(KsmMode::Off), ThpMode::Default), [UNMERGEABLE]);
+        assert_eq!(advice(None, ThpMode::Always), [HUGEPAGE]);
+    }
+
+    #[test]
+    fn smaps_flags() {
+        let memory = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let addr = memory.get_host_address(GuestAddress(0)).unwrap() as usize;
+
+        // Each advice shows in the flags of the mapping, unless the host lacks the feature.
+        for (advice, flag) in [(MERGEABLE, "mg"), (HUGEPAGE, "hg"), (NOHUGEPAGE, "nh")] {
+            match advise(&memory, advice) {
+                Ok(()) => assert!(vm_flags(addr).contains(&flag.to_string())),
+                Err(e) => assert_eq!(e.raw_os_error(), Some(libc::EINVAL)),
+            }
+        }
+        advise(&memory, UNMERGEABLE).unwrap();
+        assert!(!vm_flags(addr).contains(&"mg".to_string()));
+        // The huge page advice replace each other.
+        assert!(!vm_flags(addr).contains(&"hg".to_string()));
+    }
+}
@@ -0,0 +1,204 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Guest RAM in a memfd, which other processes map read-only to look into the guest while
+//! it runs, e.g. to read the kernel log buffer.
+//!
+//! The RAM regions are consecutive parts of the memfd, the descriptor the other processes
+//! get only opens it for reading. Nothing stops the guest meanwhile: their reads race with
+//! the vCPUs, they may see the structures the guest is updating half written, and its
+//! writes in another order than it made them.
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::sync::Arc;
+
+use vm_memory::GuestAddress;
+
+use crate::api::MemoryRegion;
+
+/// The guest RAM, shared.
+pub(crate) struct SharedMemory {
+    memfd: Arc<File>,
+    // The memfd again, read-only, for the other processes.
+    read_only: File,
+    regions: Vec<MemoryRegion>,
+}
+
+impl SharedMemory {
+    /// A memfd the size of the RAM `regions`.
+    pub fn create(regions: &[(GuestAddress, usize)]) -> io::Result<Self> {
+        // Safe because the name is a valid C string, and we check the result.
+        let fd = unsafe {
+            libc::memfd_create(
+                c"lumper-guest-ram".as_ptr(),
+                libc::MFD_CLOEXEC | libc::MFD_ALLOW_SEALING,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Safe because we own the new descriptor.
+        let memfd = unsafe { File::from_raw_fd(fd) };
+
+        let mut shared = Vec::new();
+        let mut offset = 0;
+        for (address, size) in regions {
+            shared.push(MemoryRegion {
+                guest_addr: address.0,
+                size: *size as u64,
+                offset,
+            });
+            offset += *size as u64;
+        }
+        memfd.set_len(offset)?;
+        // Mapped past its end, the memory would raise a SIGBUS.
+        let seals = libc::F_SEAL_SHRINK | libc::F_SEAL_GROW | libc::F_SEAL_SEAL;
+        // Safe because the descriptor is valid, and we check the result.
+        if unsafe { libc::fcntl(memfd.as_raw_fd(), libc::F_ADD_SEALS, seals) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // A new open file description, the memfd descriptor would share its write access.
+        let read_only = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_CLOEXEC)
+            .open(format!("/proc/self/fd/{}", memfd.as_raw_fd()))?;
+
+        Ok(SharedMemory {
+            memfd: Arc::new(memfd),
+            read_only,
+            regions: shared,
+        })
+    }
+
+    /// The memfd, to map the guest RAM from.
+    pub fn file(&self) -> Arc<File> {
+        self.memfd.clone()
+    }
+
+    /// Where the RAM regions are in the memfd.
+    pub fn regions(&self) -> Vec<MemoryRegion> {
+        self.regions.clone()
+    }
+
+    /// The memfd, read-only, to hand out to the other processes.
+    pub fn read_only_fd(&self) -> RawFd {
+        self.read_only.as_raw_fd()
+    }
+
+    /// Zero the whole RAM, giving its pages back to the host. The mappings stay.
+    pub fn clear(&self) -> io::Result<()> {
+        let size = self.memfd.metadata()?.len();
+        let mode = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+        // Safe because the descriptor is valid, and we check the result. The seals allow
+        // punching holes, the size does not change.
+        if unsafe { libc::fallocate(self.memfd.as_raw_fd(), mode, 0, size as libc::off_t) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    /// Make the read-only memfd descriptor `fd` too, inherited by the child processes and
+    /// reachable through `/proc/<pid>/fd/<fd>`.
+    ///
+    /// This fails with EBUSY when `fd` is open, e.g. a descriptor of the VMM.
+    pub fn expose_at(&self, fd: RawFd) -> io::Result<()> {
+        // Safe because it only checks the descriptor.
+        if unsafe { libc::fcntl(fd, libc::F_GETFD) } >= 0 {
+            return Err(io::Error::from_raw_os_error(libc::EBUSY));
+        }
+        // Safe because both are descriptors, and we check the result. The copy does not
+        // have FD_CLOEXEC.
+        if unsafe { libc::dup2(self.read_only.as_raw_fd(), fd) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vm_memory::Bytes;
+
+    use crate::map_file;
+
+    #[test]
+    fn shared() {
+        let regions = [(GuestAddress(0), 0x2000), (GuestAddress(0x10_0000), 0x1000)];
+        let shared = SharedMemory::create(&regions).unwrap();
+        assert_eq!(
+            shared.regions(),
+            [
+                MemoryRegion {
+                    guest_addr: 0,
+                    size: 0x2000,
+                    offset: 0
+                },
+                MemoryRegion {
+                    guest_addr: 0x10_0000,
+                    size: 0x1000,
+                    offset: 0x2000
+                }
+            ]
+        );
+        // Sealed to the size of the RAM.
+        assert!(shared.file().set_len(0x1000).is_err());
+
+        // Inherited, without replacing an open descriptor.
+        let error = shared.expose_at(shared.read_only_fd()).unwrap_err();
+        assert_eq!(error.raw_os_error(), Some(libc::EBUSY));
+        shared.expose_at(1000).unwrap();
+        // Safe because the descriptor is ours to close.
+        unsafe {
+            assert_eq!(libc::fcntl(1000, libc::F_GETFD), 0);
+            libc::close(1000);
+        }
+
+        let fd = shared.read_only_fd();
+        let map = |prot| {
+            // Safe because we check the result, and the mapping is unmapped or goes away
+            // with the process.
+            unsafe { libc::mmap(std::ptr::null_mut(), 0x3000, prot, libc::MAP_SHARED, fd, 0) }
+        };
+        // Not for writing.
+        assert_eq!(map(libc::PROT_READ | libc::PROT_WRITE), libc::MAP_FAILED);
+
+        let guest_memory = map_file(&regions, shared.file(), libc::MAP_SHARED).unwrap();
+        guest_memory
+            .write_slice(b"Linux version", GuestAddress(0x10_0010))
+            .unwrap();
+
+        // Another process sees the write, at the offset of the region.
+        // Safe because the child only makes system calls before exiting.
+        let pid = unsafe { libc::fork() };
+        assert!(pid >= 0);
+        if pid == 0 {
+            let mapping = map(libc::PROT_READ);
+            let found = mapping != libc::MAP_FAILED && {
+                // Safe because the mapping is 0x3000 bytes long.
+                let data =
+                    unsafe { std::slice::from_raw_parts((mapping as *const u8).add(0x2010), 13) };
+                data == b"Linux version"
+            };
+            // Safe because the child exits without running anything else.
+            unsafe { libc::_exit(if found { 0 } else { 1 }) };
+        }
+        let mut status = 0;
+        // Safe because the status is a valid pointer.
+        assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+        assert!(libc::WIFEXITED(status));
+        assert_eq!(libc::WEXITSTATUS(status), 0);
+
+        shared.clear().unwrap();
+        let mut data = [0u8; 13];
+        guest_memory
+            .read_slice(&mut data, GuestAddress(0x10_0010))
+            .unwrap();
+        assert_eq!(data, [0u8; 13]);
+    }
+}
@@ -0,0 +1,146 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Pause of the running VM, from the API socket.
+//!
+//! Unlike a [`PauseTrigger`](crate::PauseTrigger), which gets [`VMM::run`](crate::VMM::run)
+//! to return, the VMM keeps running its event loop while the VM is paused, e.g. to serve
+//! the API. The vCPUs wait out of guest mode, the virtio-net workers leave their interface
+//! alone, so that the frames for the guest wait in the host queue, and the serial output
+//! so far is written. The guest time goes on.
+
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use vm_memory::GuestMemoryMmap;
+
+#[cfg(target_arch = "x86_64")]
+use crate::cpu::hotplug::VcpuHotplug;
+use crate::cpu::{VcpuHandle, VcpuRunState};
+use crate::devices::async_writer::FlushHandle;
+use crate::devices::net::interface::NetInterface;
+use crate::devices::net::slot::NetSlot;
+use crate::devices::net::VirtioNet;
+use crate::OUTPUT_FLUSH_TIMEOUT;
+
+// How long a vCPU may take to leave the guest, e.g. while a device handles its exit.
+const PARK_TIMEOUT: Duration = Duration::from_secs(1);
+
+type Net = VirtioNet<Arc<GuestMemoryMmap>, NetInterface>;
+
+/// Pauses and resumes the VM, shared by the VMM and the API handler.
+pub(crate) struct VmPause {
+    state: Mutex<State>,
+    // Weak, the VMM hands the interface back once the VM stopped.
+    net: Option<Weak<Mutex<Net>>>,
+    net_slots: Vec<Arc<Mutex<NetSlot<Arc<GuestMemoryMmap>, NetInterface>>>>,
+    output_flushers: Vec<FlushHandle>,
+    #[cfg(target_arch = "x86_64")]
+    vcpu_hotplug: Option<Arc<Mutex<VcpuHotplug>>>,
+}
+
+#[derive(Default)]
+struct State {
+    paused: bool,
+    // The boot vCPUs, once started.
+    vcpus: Vec<Arc<VcpuHandle>>,
+}
+
+impl VmPause {
+    pub fn new(
+        net: Option<&Arc<Mutex<Net>>>,
+        net_slots: Vec<Arc<Mutex<NetSlot<Arc<GuestMemoryMmap>, NetInterface>>>>,
+        output_flushers: Vec<FlushHandle>,
+        #[cfg(target_arch = "x86_64")] vcpu_hotplug: Option<Arc<Mutex<VcpuHotplug>>>,
+    ) -> Self {
+        VmPause {
+            state: Mutex::new(State::default()),
+            net: net.map(Arc::downgrade),
+            net_slots,
+            output_flushers,
+            #[cfg(target_arch = "x86_64")]
+            vcpu_hotplug,
+        }
+    }
+
+    /// Control the vCPUs about to start, which wait for the resume if the VM is paused.
+    pub fn set_vcpus(&self, vcpus: Vec<Arc<VcpuHandle>>) {
+        let mut state = self.state.lock().unwrap();
+        if state.paused {
+            for handle in vcpus.iter() {
+                handle.set_state(VcpuRunState::Paused);
+            }
+        }
+        state.vcpus = vcpus;
+    }
+
+    pub fn paused(&self) -> bool {
+        self.state.lock().unwrap().paused
+    }
+
+    /// Get the vCPUs out of the guest, then stop the devices and write the serial output.
+    /// Pausing a paused VM does nothing.
+    pub fn pause(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.paused {
+            return;
+        }
+        state.paused = true;
+
+        let vcpus = self.vcpus(&state);
+        for handle in vcpus.iter() {
+            handle.set_state(VcpuRunState::Paused);
+        }
+        for handle in vcpus.iter() {
+            if !handle.wait_parked(PARK_TIMEOUT) {
+                log::warn!("A vCPU did not leave the guest within {:?}", PARK_TIMEOUT);
+            }
+        }
+
+        // Once the vCPUs no longer reach them.
+        self.set_devices_paused(true);
+        for flusher in self.output_flushers.iter() {
+            if !flusher.flush(OUTPUT_FLUSH_TIMEOUT) {
+                log::warn!(
+                    "The serial output did not get out within {:?}",
+                    OUTPUT_FLUSH_TIMEOUT
+                );
+            }
+        }
+        log::info!("VM paused");
+    }
+
+    /// Start the devices again, then the vCPUs. Resuming a running VM does nothing.
+    pub fn resume(&self) {
+        let mut state = self.state.lock().unwrap();
+        if !state.paused {
+            return;
+        }
+        state.paused = false;
+
+        self.set_devices_paused(false);
+        for handle in self.vcpus(&state) {
+            handle.set_state(VcpuRunState::Running);
+        }
+        log::info!("VM resumed");
+    }
+
+    // The boot vCPUs, and the plugged ones.
+    fn vcpus(&self, state: &State) -> Vec<Arc<VcpuHandle>> {
+        let vcpus = state.vcpus.iter().cloned();
+        #[cfg(target_arch = "x86_64")]
+        if let Some(hotplug) = self.vcpu_hotplug.as_ref() {
+            let plugged = hotplug.lock().unwrap().started_handles();
+            return vcpus.chain(plugged).collect();
+        }
+        vcpus.collect()
+    }
+
+    fn set_devices_paused(&self, paused: bool) {
+        if let Some(net) = self.net.as_ref().and_then(Weak::upgrade) {
+            net.lock().unwrap().set_paused(paused);
+        }
+        for slot in self.net_slots.iter() {
+            slot.lock().unwrap().set_paused(paused);
+        }
+    }
+}
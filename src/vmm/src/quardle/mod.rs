@@ -0,0 +1,318 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! quark bundles ("quardles").
+//!
+//! A quardle is a gzipped tarball, or the directory it extracts to, holding a kernel, an
+//! optional initramfs, and a `quark.json` manifest describing how to boot them:
+//!
+//! ```text
+//! {
+//!     "version": 1,
+//!     "kernel": "vmlinux",
+//!     "initramfs": "initramfs.img",
+//!     "cmdline": "quiet",
+//!     "memory": 256,
+//!     "cpus": 2,
+//!     "net": "tap0"
+//! }
+//! ```
+//!
+//! The paths are relative to the bundle. Only `version` and `kernel` are required, the
+//! other fields are hints the command line overrides.
+
+use std::fs::{self, File};
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+use flate2::read::GzDecoder;
+use serde::Deserialize;
+use vmm_sys_util::tempdir::TempDir;
+
+use crate::config::NetConfig;
+
+/// Name of the manifest, at the root of the bundle.
+pub const MANIFEST: &str = "quark.json";
+/// Manifest version this VMM understands.
+pub const VERSION: u64 = 1;
+
+/// Bundle errors.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// The bundle could not be extracted.
+    #[error("failed to extract the quardle {path:?}")]
+    Extract {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    /// The manifest could not be read.
+    #[error("failed to read {path:?}")]
+    ReadManifest {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    /// The manifest is not valid JSON, or does not match its schema.
+    #[error("invalid manifest {path:?}")]
+    InvalidManifest {
+        path: PathBuf,
+        #[source]
+        source: serde_json::Error,
+    },
+    /// The manifest has no version, or one this VMM does not understand.
+    #[error("unsupported manifest version {0:?} (expected {})", VERSION)]
+    UnsupportedVersion(Option<u64>),
+    /// A file the manifest refers to is missing from the bundle.
+    #[error("file {0:?} referenced by the manifest does not exist")]
+    MissingFile(PathBuf),
+    /// A file the manifest refers to is absolute, goes up with `..`, or is a symlink out of
+    /// the bundle.
+    #[error("file {0:?} referenced by the manifest is outside the bundle")]
+    OutsideBundle(PathBuf),
+    /// The network hint could not be parsed.
+    #[error("invalid network hint in the manifest")]
+    InvalidNet(#[source] crate::config::Error),
+}
+
+/// Dedicated [`Result`](https://doc.rust-lang.org/std/result/) type.
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Manifest {
+    #[allow(dead_code)]
+    version: u64,
+    kernel: PathBuf,
+    initramfs: Option<PathBuf>,
+    cmdline: Option<String>,
+    memory: Option<u32>,
+    cpus: Option<u8>,
+    net: Option<String>,
+}
+
+/// An opened quardle. An extracted tarball is removed when dropped, the VMM must be
+/// configured before.
+#[derive(Debug)]
+pub struct Quardle {
+    /// Kernel path.
+    pub kernel: PathBuf,
+    /// Optional initramfs path.
+    pub initramfs: Option<PathBuf>,
+    /// Extra kernel command line parameters.
+    pub cmdline: Option<String>,
+    /// Guest memory size hint, in MiB.
+    pub memory: Option<u32>,
+    /// Number of vCPUs hint.
+    pub cpus: Option<u8>,
+    /// TAP interface hint.
+    pub net: Option<NetConfig>,
+    // Where the tarball was extracted.
+    _dir: Option<TempDir>,
+}
+
+impl Quardle {
+    /// Open a bundle: a gzipped tarball, extracted to a temporary directory, or a directory.
+    pub fn open(path: &Path) -> Result<Self> {
+        if path.is_dir() {
+            return Self::load(path, None);
+        }
+
+        let extract = |source: io::Error| Error::Extract {
+            path: path.into(),
+            source,
+        };
+        let dir = TempDir::new_with_prefix(std::env::temp_dir().join("lumper-quardle-"))
+            .map_err(|e| extract(e.into()))?;
+        let file = File::open(path).map_err(extract)?;
+        // Entries escaping the directory, e.g. with `..`, are skipped.
+        tar::Archive::new(GzDecoder::new(file))
+            .unpack(dir.as_path())
+            .map_err(extract)?;
+
+        let root = dir.as_path().to_path_buf();
+        Self::load(&root, Some(dir))
+    }
+
+    fn load(root: &Path, dir: Option<TempDir>) -> Result<Self> {
+        let path = root.join(MANIFEST);
+        let content = fs::read(&path).map_err(|source| Error::ReadManifest {
+            path: path.clone(),
+            source,
+        })?;
+        let invalid = |source| Error::InvalidManifest {
+            path: path.clone(),
+            source,
+        };
+
+        // Check the version first: the schema of other versions is unknown.
+        let value: serde_json::Value = serde_json::from_slice(&content).map_err(invalid)?;
+        let version = value.get("version").and_then(serde_json::Value::as_u64);
+        if version != Some(VERSION) {
+            return Err(Error::UnsupportedVersion(version));
+        }
+        let manifest: Manifest = serde_json::from_value(value).map_err(invalid)?;
+
+        let file = |name: PathBuf| {
+            // A bundle only refers to its own files.
+            if name.is_absolute() || name.components().any(|c| c == Component::ParentDir) {
+                return Err(Error::OutsideBundle(name));
+            }
+            let path = root.join(&name);
+            if !path.is_file() {
+                return Err(Error::MissingFile(path));
+            }
+            // The bundle may hold symlinks, to any file.
+            match (path.canonicalize(), root.canonicalize()) {
+                (Ok(target), Ok(root)) if target.starts_with(&root) => Ok(path),
+                _ => Err(Error::OutsideBundle(name)),
+            }
+        };
+
+        Ok(Quardle {
+            kernel: file(manifest.kernel)?,
+            initramfs: manifest.initramfs.map(file).transpose()?,
+            cmdline: manifest.cmdline,
+            memory: manifest.memory,
+            cpus: manifest.cpus,
+            net: manifest
+                .net
+                .map(|net| net.parse())
+                .transpose()
+                .map_err(Error::InvalidNet)?,
+            _dir: dir,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::NetBackend;
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("src/quardle/fixtures")
+            .join(name)
+    }
+
+    #[test]
+    fn tarball() {
+        let quardle = Quardle::open(&fixture("hello.qrk")).unwrap();
+        let dir = quardle.kernel.parent().unwrap().to_path_buf();
+
+        assert_eq!(fs::read(&quardle.kernel).unwrap(), b"kernel\n");
+        assert_eq!(
+            fs::read(quardle.initramfs.as_ref().unwrap()).unwrap(),
+            b"initramfs\n"
+        );
+        assert_eq!(quardle.cmdline.as_deref(), Some("quiet"));
+        assert_eq!(quardle.memory, Some(256));
+        assert_eq!(quardle.cpus, Some(2));
+        assert_eq!(
+            quardle.net.as_ref().unwrap().backend,
+            NetBackend::Tap("tap0".to_string())
+        );
+
+        // The extracted files only live as long as the bundle.
+        drop(quardle);
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn malformed() {
+        assert!(matches!(
+            Quardle::open(&fixture("malformed.qrk")),
+            Err(Error::InvalidManifest { path, .. }) if path.ends_with(MANIFEST)
+        ));
+        assert!(matches!(
+            Quardle::open(&fixture("nonexistent.qrk")),
+            Err(Error::Extract { .. })
+        ));
+    }
+
+    #[test]
+    fn symlink() {
+        // The tarball links its kernel to /etc/passwd.
+        assert!(matches!(
+            Quardle::open(&fixture("symlink.qrk")),
+            Err(Error::OutsideBundle(path)) if path == Path::new("vmlinux")
+        ));
+
+        // So does a directory, through one of its parents, while the links within the
+        // bundle are followed.
+        let dir = TempDir::new().unwrap();
+        let write = |manifest: &str| fs::write(dir.as_path().join(MANIFEST), manifest).unwrap();
+        fs::write(dir.as_path().join("vmlinux-6.1"), b"kernel").unwrap();
+        std::os::unix::fs::symlink("vmlinux-6.1", dir.as_path().join("vmlinux")).unwrap();
+        std::os::unix::fs::symlink("/etc", dir.as_path().join("boot")).unwrap();
+
+        write(r#"{"version": 1, "kernel": "vmlinux"}"#);
+        let quardle = Quardle::open(dir.as_path()).unwrap();
+        assert_eq!(fs::read(&quardle.kernel).unwrap(), b"kernel");
+
+        write(r#"{"version": 1, "kernel": "boot/passwd"}"#);
+        assert!(matches!(
+            Quardle::open(dir.as_path()),
+            Err(Error::OutsideBundle(path)) if path == Path::new("boot/passwd")
+        ));
+    }
+
+    #[test]
+    fn directory() {
+        let dir = TempDir::new().unwrap();
+        let write = |manifest: &str| fs::write(dir.as_path().join(MANIFEST), manifest).unwrap();
+        fs::write(dir.as_path().join("bzImage"), b"kernel").unwrap();
+
+        write(r#"{"version": 1, "kernel": "bzImage"}"#);
+        let quardle = Quardle::open(dir.as_path()).unwrap();
+        assert_eq!(quardle.kernel, dir.as_path().join("bzImage"));
+        assert!(quardle.initramfs.is_none());
+        assert!(quardle.cpus.is_none());
+        drop(quardle);
+        // A directory is left alone.
+        assert!(dir.as_path().join("bzImage").exists());
+
+        // Missing files fail with their path.
+        write(r#"{"version": 1, "kernel": "bzImage", "initramfs": "initrd"}"#);
+        assert!(matches!(
+            Quardle::open(dir.as_path()),
+            Err(Error::MissingFile(path)) if path == dir.as_path().join("initrd")
+        ));
+
+        // So do files outside the bundle.
+        write(r#"{"version": 1, "kernel": "/etc/passwd"}"#);
+        assert!(matches!(
+            Quardle::open(dir.as_path()),
+            Err(Error::OutsideBundle(path)) if path == Path::new("/etc/passwd")
+        ));
+        write(r#"{"version": 1, "kernel": "bzImage", "initramfs": "boot/../../initrd"}"#);
+        assert!(matches!(
+            Quardle::open(dir.as_path()),
+            Err(Error::OutsideBundle(path)) if path == Path::new("boot/../../initrd")
+        ));
+
+        write(r#"{"version": 2, "kernel": "bzImage", "disks": []}"#);
+        assert!(matches!(
+            Quardle::open(dir.as_path()),
+            Err(Error::UnsupportedVersion(Some(2)))
+        ));
+        write(r#"{"kernel": "bzImage"}"#);
+        assert!(matches!(
+            Quardle::open(dir.as_path()),
+            Err(Error::UnsupportedVersion(None))
+        ));
+
+        // Unknown fields are rejected.
+        write(r#"{"version": 1, "kernel": "bzImage", "disks": []}"#);
+        assert!(matches!(
+            Quardle::open(dir.as_path()),
+            Err(Error::InvalidManifest { .. })
+        ));
+
+        write(r#"{"version": 1, "kernel": "bzImage", "net": "tap0,rx_rate=fast"}"#);
+        assert!(matches!(
+            Quardle::open(dir.as_path()),
+            Err(Error::InvalidNet(_))
+        ));
+    }
+}
@@ -0,0 +1,272 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use std::cmp;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::{Duration, Instant};
+
+use vmm_sys_util::errno;
+use vmm_sys_util::timerfd::TimerFd;
+
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+/// Token bucket, refilled at a constant rate up to its size.
+#[derive(Clone, Debug)]
+pub(crate) struct TokenBucket {
+    // Maximum number of tokens, which is also the largest burst.
+    size: u64,
+    // Tokens added per second.
+    rate: u64,
+    budget: u64,
+    // Time the budget was last refilled at.
+    last_update: Instant,
+}
+
+impl TokenBucket {
+    /// Create a full bucket, so that a burst can go through right away.
+    pub fn new(rate: u64, size: u64, now: Instant) -> Self {
+        TokenBucket {
+            size,
+            rate,
+            budget: size,
+            last_update: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_update).as_nanos();
+        let tokens = elapsed * u128::from(self.rate) / NANOS_PER_SEC;
+
+        if tokens >= u128::from(self.size - self.budget) {
+            self.budget = self.size;
+            self.last_update = now;
+        } else {
+            self.budget += tokens as u64;
+            // Only account for the time that produced whole tokens, so that fractions of
+            // tokens are not lost between refills.
+            let used = tokens * NANOS_PER_SEC / u128::from(self.rate);
+            self.last_update += Duration::from_nanos(used as u64);
+        }
+    }
+
+    /// Time until `tokens` are available, zero if they already are.
+    pub fn wait_time(&mut self, tokens: u64, now: Instant) -> Duration {
+        self.refill(now);
+
+        // Requests larger than the bucket only wait for it to be full.
+        let tokens = cmp::min(tokens, self.size);
+        if tokens <= self.budget {
+            return Duration::ZERO;
+        }
+
+        let missing = u128::from(tokens - self.budget);
+        let nanos = (missing * NANOS_PER_SEC).div_ceil(u128::from(self.rate));
+        Duration::from_nanos(nanos as u64)
+    }
+
+    /// Take `tokens` from the bucket. They must be available, see [`TokenBucket::wait_time`].
+    pub fn consume(&mut self, tokens: u64) {
+        self.budget -= cmp::min(tokens, self.budget);
+    }
+}
+
+/// Limits the bandwidth and operations rate of a device queue.
+///
+/// When either budget runs out, the limiter is blocked and arms a timer firing once
+/// enough tokens are back. The device stops processing the queue until the timer fd
+/// is readable and [`RateLimiter::event_handler`] is called.
+pub(crate) struct RateLimiter {
+    // In bytes.
+    bandwidth: Option<TokenBucket>,
+    // In operations, usually frames.
+    ops: Option<TokenBucket>,
+    timer: TimerFd,
+    blocked: bool,
+}
+
+impl RateLimiter {
+    /// `bandwidth` and `ops` are (rate per second, burst size) pairs, `None` for no limit.
+    pub fn new(bandwidth: Option<(u64, u64)>, ops: Option<(u64, u64)>) -> errno::Result<Self> {
+        let now = Instant::now();
+        let bucket = |(rate, size): (u64, u64)| TokenBucket::new(rate, size, now);
+
+        Ok(RateLimiter {
+            bandwidth: bandwidth.map(bucket),
+            ops: ops.map(bucket),
+            timer: TimerFd::new()?,
+            blocked: false,
+        })
+    }
+
+    /// A limiter letting everything through.
+    pub fn unlimited() -> errno::Result<Self> {
+        Self::new(None, None)
+    }
+
+    /// Whether there is any limit, and the timer fd needs to be polled.
+    pub fn is_limited(&self) -> bool {
+        self.bandwidth.is_some() || self.ops.is_some()
+    }
+
+    /// Whether the limiter waits for its timer.
+    pub fn is_blocked(&self) -> bool {
+        self.blocked
+    }
+
+    /// Account for one operation of `bytes`. Returns `false` when the budget is exhausted,
+    /// the operation must then be retried once the limiter is unblocked.
+    pub fn consume(&mut self, bytes: u64) -> bool {
+        self.consume_at(bytes, Instant::now())
+    }
+
+    fn consume_at(&mut self, bytes: u64, now: Instant) -> bool {
+        if self.blocked {
+            return false;
+        }
+
+        let wait = cmp::max(
+            self.bandwidth
+                .as_mut()
+                .map_or(Duration::ZERO, |bucket| bucket.wait_time(bytes, now)),
+            self.ops
+                .as_mut()
+                .map_or(Duration::ZERO, |bucket| bucket.wait_time(1, now)),
+        );
+
+        if !wait.is_zero() {
+            match self.timer.reset(wait, None) {
+                Ok(()) => self.blocked = true,
+                // Without a timer nothing would unblock us, let the operation through.
+                Err(e) => println!("Failed to arm rate limiter timer: {:?}", e),
+            }
+            if self.blocked {
+                return false;
+            }
+        }
+
+        if let Some(bucket) = self.bandwidth.as_mut() {
+            bucket.consume(bytes);
+        }
+        if let Some(bucket) = self.ops.as_mut() {
+            bucket.consume(1);
+        }
+
+        true
+    }
+
+    /// Handle the timer fd readiness, unblocking the limiter.
+    pub fn event_handler(&mut self) {
+        // The timer is not periodic, there is nothing to do if it was already read.
+        let _ = self.timer.wait();
+        self.blocked = false;
+    }
+}
+
+impl AsRawFd for RateLimiter {
+    fn as_raw_fd(&self) -> RawFd {
+        self.timer.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FRAME_SIZE: u64 = 1500;
+
+    // Send frames as fast as the limiter allows for `duration`, on a fake clock.
+    // Returns the number of bytes sent.
+    fn saturate(limiter: &mut RateLimiter, start: Instant, duration: Duration) -> u64 {
+        let mut now = start;
+        let mut sent = 0;
+
+        while now < start + duration {
+            if limiter.consume_at(FRAME_SIZE, now) {
+                sent += FRAME_SIZE;
+                continue;
+            }
+
+            // Jump to the time the timer would fire.
+            let wait = cmp::max(
+                limiter
+                    .bandwidth
+                    .as_mut()
+                    .map_or(Duration::ZERO, |b| b.wait_time(FRAME_SIZE, now)),
+                limiter
+                    .ops
+                    .as_mut()
+                    .map_or(Duration::ZERO, |b| b.wait_time(1, now)),
+            );
+            now += wait;
+            // Do not wait for the actual timer.
+            limiter.blocked = false;
+        }
+
+        sent
+    }
+
+    #[test]
+    fn burst() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(1000, 5000, now);
+
+        // The whole burst goes through at once.
+        assert_eq!(bucket.wait_time(5000, now), Duration::ZERO);
+        bucket.consume(5000);
+        assert_eq!(bucket.wait_time(1, now), Duration::from_millis(1));
+        assert_eq!(bucket.wait_time(500, now), Duration::from_millis(500));
+
+        // Refilling never goes beyond the bucket size.
+        let later = now + Duration::from_secs(60);
+        assert_eq!(bucket.wait_time(5000, later), Duration::ZERO);
+        bucket.consume(5000);
+        assert_eq!(bucket.wait_time(1000, later), Duration::from_secs(1));
+
+        // Larger requests only wait for a full bucket.
+        assert_eq!(bucket.wait_time(10000, later), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn sustained_bandwidth() {
+        // 10 Mbit/s, with a 1 MiB burst.
+        let rate = 10_000_000 / 8;
+        let burst = 1 << 20;
+        let mut limiter = RateLimiter::new(Some((rate, burst)), None).unwrap();
+        let start = limiter.bandwidth.as_ref().unwrap().last_update;
+
+        let seconds = 20;
+        let sent = saturate(&mut limiter, start, Duration::from_secs(seconds));
+
+        // Past the initial burst, the throughput is the configured rate.
+        let sustained = (sent - burst) / seconds;
+        assert!(sustained.abs_diff(rate) < rate / 100, "{} B/s", sustained);
+    }
+
+    #[test]
+    fn sustained_ops() {
+        let mut limiter = RateLimiter::new(None, Some((100, 10))).unwrap();
+        let start = limiter.ops.as_ref().unwrap().last_update;
+
+        let frames = saturate(&mut limiter, start, Duration::from_secs(10)) / FRAME_SIZE;
+        assert!((1005..=1011).contains(&frames), "{} frames", frames);
+    }
+
+    #[test]
+    fn blocked_until_event() {
+        let mut limiter = RateLimiter::new(Some((1000, 1000)), None).unwrap();
+        let now = limiter.bandwidth.as_ref().unwrap().last_update;
+
+        assert!(limiter.consume_at(1000, now));
+        assert!(!limiter.consume_at(1, now));
+        assert!(limiter.is_blocked());
+
+        // Tokens are back, but the device has to wait for the timer.
+        let later = now + Duration::from_secs(1);
+        assert!(!limiter.consume_at(1, later));
+        limiter.event_handler();
+        assert!(limiter.consume_at(1, later));
+
+        let mut unlimited = RateLimiter::unlimited().unwrap();
+        assert!(!unlimited.is_limited());
+        assert!(unlimited.consume(u64::MAX));
+    }
+}
@@ -0,0 +1,276 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Restarting the guest in place when it reboots, without a new VMM process.
+//!
+//! The VM keeps what the host set up for it: the memory mappings, the vCPUs, the devices
+//! and their taps, console and sockets. The guest gets the state it booted with the first
+//! time: zeroed RAM with the boot tables, the kernel and the initramfs loaded again, the
+//! vCPUs and the interrupt controllers as they were configured, and the devices reset.
+
+use std::io;
+use std::mem;
+use std::thread::JoinHandle;
+use std::time::Instant;
+
+use kvm_bindings::{
+    kvm_irqchip, KVM_IRQCHIP_IOAPIC, KVM_IRQCHIP_PIC_MASTER, KVM_IRQCHIP_PIC_SLAVE,
+};
+use virtio_device::VirtioDeviceActions;
+use vm_memory::{Bytes, GuestAddress, GuestMemory, GuestMemoryRegion};
+
+use crate::config::{ImageFile, WatchdogAction};
+use crate::cpu::{Vcpu, VcpuState};
+use crate::events::Event;
+use crate::irq::IrqTrigger;
+use crate::layout::HIMEM_START;
+use crate::{kernel, Error, ExitReason, Result, VMM};
+
+// Whether the VM stopped for a reset of the guest, which it may restart from.
+fn is_reset(reason: &ExitReason) -> bool {
+    matches!(
+        reason,
+        ExitReason::GuestReset | ExitReason::WatchdogExpired(WatchdogAction::Reset)
+    )
+}
+
+/// What the guest booted from, to boot it again.
+pub(crate) struct BootState {
+    vcpus: Vec<VcpuState>,
+    // Only with the in-kernel irqchip.
+    irqchips: Vec<kvm_irqchip>,
+    // The boot tables, below the kernel.
+    low_memory: Vec<u8>,
+    // Kept open: the jail may not let the VMM open them again.
+    kernel: ImageFile,
+    initramfs: Option<ImageFile>,
+    initrd_in_memory: bool,
+    cmdline: String,
+    max_reboots: Option<u32>,
+    reboots: u32,
+}
+
+impl VMM {
+    /// Save the state the configured VM boots from, to restart the guest when it reboots.
+    /// The images are those the kernel and the initramfs were loaded from.
+    pub(crate) fn save_boot_state(
+        &mut self,
+        kernel: ImageFile,
+        initramfs: Option<ImageFile>,
+        initrd_in_memory: bool,
+        cmdline: String,
+        max_reboots: Option<u32>,
+    ) -> Result<()> {
+        let msr_list = self.kvm.get_msr_index_list().map_err(Error::KvmIoctl)?;
+        let vcpus = self
+            .vcpus
+            .iter()
+            .map(|vcpu| vcpu.save_state(msr_list.as_slice()))
+            .collect::<std::result::Result<_, _>>()
+            .map_err(Error::Vcpu)?;
+
+        let mut irqchips = Vec::new();
+        if matches!(self.irq_trigger, IrqTrigger::Kernel) {
+            for chip_id in [
+                KVM_IRQCHIP_PIC_MASTER,
+                KVM_IRQCHIP_PIC_SLAVE,
+                KVM_IRQCHIP_IOAPIC,
+            ] {
+                let mut irqchip = kvm_irqchip {
+                    chip_id,
+                    ..Default::default()
+                };
+                self.vm_fd
+                    .get_irqchip(&mut irqchip)
+                    .map_err(Error::KvmIoctl)?;
+                irqchips.push(irqchip);
+            }
+        }
+
+        let mut low_memory = vec![0u8; HIMEM_START as usize];
+        self.guest_memory
+            .read_slice(&mut low_memory, GuestAddress(0))
+            .map_err(|_| Error::GuestRange {
+                addr: 0,
+                len: low_memory.len(),
+            })?;
+
+        self.boot_state = Some(BootState {
+            vcpus,
+            irqchips,
+            low_memory,
+            kernel,
+            initramfs,
+            initrd_in_memory,
+            cmdline,
+            max_reboots,
+            reboots: 0,
+        });
+
+        Ok(())
+    }
+
+    /// Restart the guest each time it resets, or the watchdog resets it, while it may
+    /// reboot, until the VM stops for another reason. The vCPUs of `vcpu_threads` stopped
+    /// for `reason`, the new threads replace them. Returns why the VM stopped.
+    pub(crate) fn restart_on_reboot(
+        &mut self,
+        mut reason: ExitReason,
+        deadline: Option<Instant>,
+        vcpu_threads: &mut Vec<JoinHandle<Vcpu>>,
+    ) -> Result<ExitReason> {
+        while is_reset(&reason) && self.may_reboot() {
+            self.stop_vcpus(mem::take(vcpu_threads));
+            let reboots = self.reboot()?;
+            match reason {
+                ExitReason::GuestReset => log::info!("The guest rebooted, restarting it"),
+                _ => log::warn!("The guest watchdog expired, restarting the guest"),
+            }
+            if let Some(events) = self.events.as_ref() {
+                events.emit(Event::Reboot { reboots });
+            }
+            *vcpu_threads = self.start_vcpus();
+            reason = self.event_loop(deadline)?;
+        }
+
+        Ok(reason)
+    }
+
+    // Whether the guest restarts on a reset, rather than stopping the VM: it was set up to,
+    // and did not reboot the maximum number of times yet.
+    fn may_reboot(&self) -> bool {
+        self.boot_state.as_ref().is_some_and(|boot| {
+            boot.max_reboots
+                .is_none_or(|max_reboots| boot.reboots < max_reboots)
+        })
+    }
+
+    // Boot the guest again, once its vCPUs are stopped. Returns how many times it did.
+    fn reboot(&mut self) -> Result<u32> {
+        // Another vCPU may have asked for the reset too, meanwhile.
+        let _ = self.exit.eventfd.read();
+        match self.exit.take() {
+            Some(ExitReason::GuestReset) | None => {}
+            Some(reason) => self.exit.notify(reason),
+        }
+
+        // Before the memory goes: the device workers stop using the guest buffers.
+        self.reset_devices()?;
+        self.clear_memory().map_err(Error::MemoryReset)?;
+
+        let mut boot = self
+            .boot_state
+            .take()
+            .expect("rebooting without a boot state");
+        let loaded = self
+            .guest_memory
+            .write_slice(&boot.low_memory, GuestAddress(0))
+            .map_err(|_| Error::GuestRange {
+                addr: 0,
+                len: boot.low_memory.len(),
+            })
+            .and_then(|()| {
+                kernel::kernel_setup(
+                    &self.guest_memory,
+                    &mut boot.kernel,
+                    boot.initramfs.as_mut(),
+                    boot.initrd_in_memory,
+                    &boot.cmdline,
+                    self.resources.memory_map(),
+                )
+            })
+            .and_then(|_| self.restore_boot_state(&boot));
+        boot.reboots += 1;
+        let reboots = boot.reboots;
+        self.boot_state = Some(boot);
+        loaded?;
+
+        Ok(reboots)
+    }
+
+    // Put the interrupt controllers and the vCPUs back at the kernel entry point.
+    fn restore_boot_state(&self, boot: &BootState) -> Result<()> {
+        for irqchip in boot.irqchips.iter() {
+            self.vm_fd.set_irqchip(irqchip).map_err(Error::KvmIoctl)?;
+        }
+        for (vcpu, state) in self.vcpus.iter().zip(boot.vcpus.iter()) {
+            vcpu.restore_state(state).map_err(Error::Vcpu)?;
+        }
+
+        Ok(())
+    }
+
+    // Reset the devices the guest drives, as at power on. They keep their host side: the
+    // console output, the taps, the shared directory and the pmem file.
+    fn reset_devices(&self) -> Result<()> {
+        self.serial.lock().unwrap().reset();
+        if let Some(serial2) = self.serial2.as_ref() {
+            serial2.lock().unwrap().reset();
+        }
+
+        if let Some(virtio_net) = self.virtio_net.as_ref() {
+            virtio_net.lock().unwrap().reset()?;
+        }
+        for slot in self.net_slots.iter() {
+            slot.lock().unwrap().reset()?;
+        }
+        // Both only exist along with their configuration.
+        let config = self.config.as_ref();
+        if let Some(virtio_pmem) = self.virtio_pmem.as_ref() {
+            let pmem = config.and_then(|config| config.pmem.as_ref());
+            virtio_pmem
+                .lock()
+                .unwrap()
+                .reset()
+                .map_err(|source| Error::VirtioPmem {
+                    path: pmem.map(|pmem| pmem.path.clone()).unwrap_or_default(),
+                    source,
+                })?;
+        }
+        if let Some(virtio_9p) = self.virtio_9p.as_ref() {
+            let shared_dir = config.and_then(|config| config.shared_dir.as_ref());
+            virtio_9p
+                .lock()
+                .unwrap()
+                .reset()
+                .map_err(|source| Error::SharedDir {
+                    path: shared_dir.map(|dir| dir.path.clone()).unwrap_or_default(),
+                    source,
+                })?;
+        }
+
+        if let Some(watchdog) = self.watchdog.as_ref() {
+            watchdog.lock().unwrap().reset();
+        }
+        self.irq_trigger.reset();
+
+        Ok(())
+    }
+
+    // Zero the RAM. A RAM template is mapped privately: its pages come back instead.
+    fn clear_memory(&self) -> io::Result<()> {
+        if let Some(shared) = self.shared_memory.as_ref() {
+            return shared.clear();
+        }
+
+        for region in self.guest_memory.iter() {
+            let address = self
+                .guest_memory
+                .get_host_address(region.start_addr())
+                .map_err(io::Error::other)?;
+            // Safe because the range is the private mapping of the region, which nothing
+            // else uses while the vCPUs are stopped and the devices reset.
+            let ret = unsafe {
+                libc::madvise(
+                    address as *mut libc::c_void,
+                    region.len() as usize,
+                    libc::MADV_DONTNEED,
+                )
+            };
+            if ret < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,231 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Advisory locks on what two lumper instances must not share: the console files, whose
+//! output they would mix, and the taps, whose frames they would steal from each other.
+//!
+//! A console file is locked itself, with `flock`. A tap is locked through its lock file,
+//! `tap-<name>.lock` in `$XDG_RUNTIME_DIR/lumper`, or in `/run/lumper` without it, which
+//! holds the PID of the instance using the tap. The locks go away with their descriptors,
+//! however the process exits. The lock files left behind by an instance that was killed
+//! are removed the next time a tap is locked.
+
+use std::ffi::OsStr;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use crate::{Error, Result};
+
+// Where the tap lock files go, without a runtime directory.
+const LOCK_DIR: &str = "/run/lumper";
+
+/// Lock `file` for this instance, unless another one has it. Returns whether we got it.
+pub(crate) fn try_lock(file: &File) -> io::Result<bool> {
+    // Safe because the descriptor is valid, and we check the result.
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+        return Ok(true);
+    }
+    let error = io::Error::last_os_error();
+    match error.raw_os_error() {
+        Some(libc::EWOULDBLOCK) => Ok(false),
+        _ => Err(error),
+    }
+}
+
+/// Lock the console file `path`, open as `file`, failing when another instance has it.
+pub(crate) fn lock_console(file: &File, path: &Path) -> Result<()> {
+    let resource = || format!("console file {}", path.display());
+    let locked = try_lock(file).map_err(|source| Error::ResourceLock {
+        resource: resource(),
+        source,
+    })?;
+    if !locked {
+        return Err(Error::ResourceBusy {
+            resource: resource(),
+            holder_pid: flock_holder(file),
+        });
+    }
+
+    Ok(())
+}
+
+/// A tap locked for this instance. Its lock file is removed once unlocked.
+pub(crate) struct TapLock {
+    // Locked, for as long as it is open.
+    _file: File,
+    path: PathBuf,
+}
+
+impl TapLock {
+    /// Lock the tap `if_name`, failing when another instance has it.
+    pub fn acquire(if_name: &str) -> Result<Self> {
+        TapLock::acquire_in(&lock_dir(), if_name)
+    }
+
+    fn acquire_in(dir: &Path, if_name: &str) -> Result<Self> {
+        let resource = format!("tap {}", if_name);
+        let lock_error = |source| Error::ResourceLock {
+            resource: resource.clone(),
+            source,
+        };
+        fs::create_dir_all(dir).map_err(lock_error)?;
+        remove_stale(dir);
+
+        let path = dir.join(format!("tap-{}.lock", if_name));
+        loop {
+            let mut file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&path)
+                .map_err(lock_error)?;
+            if !try_lock(&file).map_err(lock_error)? {
+                return Err(Error::ResourceBusy {
+                    resource,
+                    holder_pid: read_pid(&mut file),
+                });
+            }
+            // The previous holder removed the file before we got its lock.
+            if !is_at(&file, &path) {
+                continue;
+            }
+
+            file.set_len(0)
+                .and_then(|()| writeln!(file, "{}", std::process::id()))
+                .map_err(lock_error)?;
+            return Ok(TapLock { _file: file, path });
+        }
+    }
+}
+
+impl Drop for TapLock {
+    fn drop(&mut self) {
+        // Still locked, nobody else uses it.
+        if let Err(e) = fs::remove_file(&self.path) {
+            log::warn!("Failed to remove {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+// The directory of the tap lock files.
+fn lock_dir() -> PathBuf {
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => Path::new(&dir).join("lumper"),
+        None => PathBuf::from(LOCK_DIR),
+    }
+}
+
+// Remove the lock files in `dir` nobody holds, left behind by killed instances.
+fn remove_stale(dir: &Path) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for path in entries.flatten().map(|entry| entry.path()) {
+        if path.extension() != Some(OsStr::new("lock")) {
+            continue;
+        }
+        let file = match OpenOptions::new().write(true).open(&path) {
+            Ok(file) => file,
+            Err(_) => continue,
+        };
+        if matches!(try_lock(&file), Ok(true)) && is_at(&file, &path) {
+            log::debug!("Removing the stale lock file {}", path.display());
+            let _ = fs::remove_file(&path);
+        }
+    }
+}
+
+// Whether `file` is still the one at `path`.
+fn is_at(file: &File, path: &Path) -> bool {
+    match (file.metadata(), fs::metadata(path)) {
+        (Ok(file), Ok(path)) => file.dev() == path.dev() && file.ino() == path.ino(),
+        _ => false,
+    }
+}
+
+// The PID a tap lock file holds, unless its holder did not write it yet.
+fn read_pid(file: &mut File) -> Option<u32> {
+    let mut pid = String::new();
+    file.read_to_string(&mut pid).ok()?;
+    pid.trim().parse().ok()
+}
+
+// The PID of the process holding the flock on `file`, as /proc/locks tells.
+fn flock_holder(file: &File) -> Option<u32> {
+    let metadata = file.metadata().ok()?;
+    let dev = metadata.dev();
+    // The device numbers are in hex there, e.g. `1: FLOCK ADVISORY WRITE 1234 08:02:1311 0
+    // EOF`.
+    let major = ((dev >> 8) & 0xfff) | ((dev >> 32) & !0xfff);
+    let minor = (dev & 0xff) | ((dev >> 12) & !0xff);
+    let id = format!("{:02x}:{:02x}:{}", major, minor, metadata.ino());
+
+    let locks = fs::read_to_string("/proc/locks").ok()?;
+    locks.lines().find_map(|line| {
+        let fields: Vec<_> = line.split_whitespace().collect();
+        match fields.as_slice() {
+            [_, "FLOCK", _, _, pid, lock_id, ..] if *lock_id == id => pid.parse().ok(),
+            _ => None,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use vmm_sys_util::tempdir::TempDir;
+
+    #[test]
+    fn tap() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.as_path().join("tap-tap0.lock");
+
+        // A lock file nobody holds goes away.
+        fs::write(dir.as_path().join("tap-tap1.lock"), "1\n").unwrap();
+        let lock = TapLock::acquire_in(dir.as_path(), "tap0").unwrap();
+        assert!(!dir.as_path().join("tap-tap1.lock").exists());
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            format!("{}\n", std::process::id())
+        );
+
+        // The holder is told, and its lock file kept.
+        let pid = std::process::id();
+        assert!(matches!(
+            TapLock::acquire_in(dir.as_path(), "tap0"),
+            Err(Error::ResourceBusy { holder_pid: Some(holder), .. }) if holder == pid
+        ));
+        assert!(path.exists());
+        // Other taps are free.
+        let other = TapLock::acquire_in(dir.as_path(), "tap1").unwrap();
+
+        drop(lock);
+        drop(other);
+        assert!(!path.exists());
+        TapLock::acquire_in(dir.as_path(), "tap0").unwrap();
+    }
+
+    #[test]
+    fn console() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.as_path().join("console.log");
+        let open = || File::create(&path).unwrap();
+
+        let file = open();
+        lock_console(&file, &path).unwrap();
+        let pid = std::process::id();
+        assert!(matches!(
+            lock_console(&open(), &path),
+            Err(Error::ResourceBusy { holder_pid: Some(holder), .. }) if holder == pid
+        ));
+
+        // Released along with the descriptor.
+        drop(file);
+        lock_console(&open(), &path).unwrap();
+    }
+}
@@ -0,0 +1,143 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! The guest physical address space, as allocated to the RAM and to everything else.
+//!
+//! Every range the guest sees goes through one allocator, which refuses overlaps, and the
+//! E820 map the guest boots with comes from it: the RAM is usable, the rest is reserved.
+
+use std::collections::BTreeMap;
+use std::result;
+
+use vm_allocator::{AddressAllocator, AllocPolicy};
+
+use crate::layout::{self, EBDA_START, HIMEM_START};
+
+// Guest physical addresses have at most 52 bits.
+const GUEST_PHYS_SIZE: u64 = 1 << 52;
+
+/// What a guest physical range is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum RangeKind {
+    /// RAM the guest may use.
+    Ram,
+    /// Anything else: the BIOS area, the KVM pages, the device registers and memory, the
+    /// firmware.
+    Reserved,
+}
+
+/// The allocated guest physical ranges, by address.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct MemoryMap(BTreeMap<u64, (u64, RangeKind)>);
+
+impl MemoryMap {
+    /// The ranges, as (address, size, kind), sorted by address. None overlap.
+    pub fn iter(&self) -> impl Iterator<Item = (u64, u64, RangeKind)> + '_ {
+        self.0
+            .iter()
+            .map(|(address, (size, kind))| (*address, *size, *kind))
+    }
+}
+
+/// Allocates the guest physical address space.
+pub(crate) struct GuestResources {
+    allocator: AddressAllocator,
+    map: MemoryMap,
+}
+
+impl GuestResources {
+    /// The address space, where the ranges that are never RAM are already reserved: the
+    /// BIOS area below the high memory, with the boot tables, and the KVM pages below the
+    /// MMIO gap.
+    pub fn new() -> result::Result<Self, vm_allocator::Error> {
+        let mut resources = GuestResources {
+            allocator: AddressAllocator::new(0, GUEST_PHYS_SIZE)?,
+            map: MemoryMap::default(),
+        };
+        resources.reserve(EBDA_START, HIMEM_START - EBDA_START)?;
+        let (start, size) = layout::kvm_pages();
+        resources.reserve(start, size)?;
+
+        Ok(resources)
+    }
+
+    /// Add the RAM region of `size` bytes at `start`, around the ranges reserved in it.
+    pub fn add_ram(&mut self, start: u64, size: u64) -> result::Result<(), vm_allocator::Error> {
+        let end = start + size;
+        let reserved: Vec<_> = self
+            .map
+            .iter()
+            .filter(|(address, size, kind)| {
+                *kind == RangeKind::Reserved && *address < end && start < address + size
+            })
+            .collect();
+
+        let mut address = start;
+        for (reserved_start, reserved_size, _) in reserved {
+            if address < reserved_start {
+                self.allocate(address, reserved_start - address, RangeKind::Ram)?;
+            }
+            address = address.max(reserved_start + reserved_size);
+        }
+        if address < end {
+            self.allocate(address, end - address, RangeKind::Ram)?;
+        }
+
+        Ok(())
+    }
+
+    /// Reserve the `size` bytes at `start`, which must not be allocated yet.
+    pub fn reserve(&mut self, start: u64, size: u64) -> result::Result<(), vm_allocator::Error> {
+        self.allocate(start, size, RangeKind::Reserved)
+    }
+
+    /// The ranges allocated so far.
+    pub fn memory_map(&self) -> &MemoryMap {
+        &self.map
+    }
+
+    fn allocate(
+        &mut self,
+        start: u64,
+        size: u64,
+        kind: RangeKind,
+    ) -> result::Result<(), vm_allocator::Error> {
+        self.allocator
+            .allocate(size, 1, AllocPolicy::ExactMatch(start))?;
+        self.map.0.insert(start, (size, kind));
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ram_around_the_reservations() {
+        let mut resources = GuestResources::new().unwrap();
+        resources.add_ram(0, 0x1000_0000).unwrap();
+        // Device registers do not go over the RAM, nor over each other.
+        assert!(resources.reserve(0x10_0000, 0x1000).is_err());
+        resources
+            .reserve(layout::DEVICE_MMIO_START, 0x1000)
+            .unwrap();
+        assert!(resources
+            .reserve(layout::DEVICE_MMIO_START + 0x800, 0x1000)
+            .is_err());
+        // Nor does more RAM.
+        assert!(resources.add_ram(0x800_0000, 0x1000).is_err());
+
+        let (kvm_pages, kvm_pages_size) = layout::kvm_pages();
+        assert_eq!(
+            resources.memory_map().iter().collect::<Vec<_>>(),
+            [
+                (0, EBDA_START, RangeKind::Ram),
+                (EBDA_START, HIMEM_START - EBDA_START, RangeKind::Reserved),
+                (HIMEM_START, 0x1000_0000 - HIMEM_START, RangeKind::Ram),
+                (kvm_pages, kvm_pages_size, RangeKind::Reserved),
+                (layout::DEVICE_MMIO_START, 0x1000, RangeKind::Reserved),
+            ]
+        );
+    }
+}
@@ -0,0 +1,146 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use crate::epoll_context::{EventHandler, EventOps, Events};
+use crate::{logging, Error, ExitReason, Result};
+
+/// Signals asking the VMM to stop.
+pub(crate) const EXIT_SIGNALS: [libc::c_int; 3] = [libc::SIGINT, libc::SIGTERM, libc::SIGHUP];
+/// Signal asking the VMM to log more, see [`logging::cycle_level`].
+pub(crate) const LOG_LEVEL_SIGNAL: libc::c_int = libc::SIGUSR1;
+
+/// Receives signals through a file descriptor, so that the epoll loop can handle them.
+///
+/// The signals are blocked in the creating thread, and in all the threads it creates
+/// afterwards, so this must be created before any other thread. Dropping it restores the
+/// signal mask of the dropping thread, which should be the creating one.
+pub(crate) struct SignalFd {
+    fd: RawFd,
+    // The signal mask before the signals were blocked.
+    old_mask: libc::sigset_t,
+}
+
+impl SignalFd {
+    pub fn new(signals: &[libc::c_int]) -> io::Result<Self> {
+        // Safe because the sets are initialized by sigemptyset or pthread_sigmask before
+        // being used, and all the pointers are valid.
+        unsafe {
+            let mut set: libc::sigset_t = mem::zeroed();
+            libc::sigemptyset(&mut set);
+            for signal in signals {
+                libc::sigaddset(&mut set, *signal);
+            }
+
+            let mut old_mask: libc::sigset_t = mem::zeroed();
+            let ret = libc::pthread_sigmask(libc::SIG_BLOCK, &set, &mut old_mask);
+            if ret != 0 {
+                return Err(io::Error::from_raw_os_error(ret));
+            }
+
+            let fd = libc::signalfd(-1, &set, libc::SFD_NONBLOCK | libc::SFD_CLOEXEC);
+            if fd < 0 {
+                let e = io::Error::last_os_error();
+                libc::pthread_sigmask(libc::SIG_SETMASK, &old_mask, std::ptr::null_mut());
+                return Err(e);
+            }
+
+            Ok(SignalFd { fd, old_mask })
+        }
+    }
+
+    /// Get the next pending signal, if any.
+    pub fn read(&self) -> io::Result<Option<libc::c_int>> {
+        // Safe because signalfd_siginfo is plain old data.
+        let mut info: libc::signalfd_siginfo = unsafe { mem::zeroed() };
+        let size = mem::size_of::<libc::signalfd_siginfo>();
+
+        // Safe because the buffer is valid and large enough.
+        let ret = unsafe {
+            libc::read(
+                self.fd,
+                &mut info as *mut libc::signalfd_siginfo as *mut libc::c_void,
+                size,
+            )
+        };
+        if ret < 0 {
+            let e = io::Error::last_os_error();
+            return match e.kind() {
+                io::ErrorKind::WouldBlock => Ok(None),
+                _ => Err(e),
+            };
+        }
+
+        Ok(Some(info.ssi_signo as libc::c_int))
+    }
+}
+
+impl AsRawFd for SignalFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+// Stop the VMM on the first exit signal.
+impl EventHandler for SignalFd {
+    fn process(&mut self, _events: Events, ops: &mut EventOps) -> Result<()> {
+        match self.read().map_err(Error::Signal)? {
+            Some(LOG_LEVEL_SIGNAL) => log::warn!("Log level set to {}", logging::cycle_level()),
+            Some(signal) => ops.exit(ExitReason::Signal(signal)),
+            None => {}
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for SignalFd {
+    fn drop(&mut self) {
+        // Safe because we own the file descriptor, and the mask was filled by
+        // pthread_sigmask.
+        unsafe {
+            libc::close(self.fd);
+            libc::pthread_sigmask(libc::SIG_SETMASK, &self.old_mask, std::ptr::null_mut());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pending_signal() {
+        let signal_fd = SignalFd::new(&[libc::SIGUSR2]).unwrap();
+        assert_eq!(signal_fd.read().unwrap(), None);
+
+        // Safe because SIGUSR2 is blocked, it is only queued for the signalfd.
+        unsafe { libc::pthread_kill(libc::pthread_self(), libc::SIGUSR2) };
+        assert_eq!(signal_fd.read().unwrap(), Some(libc::SIGUSR2));
+        assert_eq!(signal_fd.read().unwrap(), None);
+    }
+
+    // Whether `signal` is blocked in the calling thread.
+    fn blocked(signal: libc::c_int) -> bool {
+        // Safe because the set is filled by pthread_sigmask before being read.
+        unsafe {
+            let mut mask: libc::sigset_t = mem::zeroed();
+            assert_eq!(
+                libc::pthread_sigmask(libc::SIG_BLOCK, std::ptr::null(), &mut mask),
+                0
+            );
+            libc::sigismember(&mask, signal) == 1
+        }
+    }
+
+    #[test]
+    fn restore_mask() {
+        assert!(!blocked(libc::SIGUSR2));
+        let signal_fd = SignalFd::new(&[libc::SIGUSR2]).unwrap();
+        assert!(blocked(libc::SIGUSR2));
+        drop(signal_fd);
+        assert!(!blocked(libc::SIGUSR2));
+    }
+}
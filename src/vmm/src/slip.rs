@@ -0,0 +1,242 @@
+// SPDX-License-Identifier: Apache-2.0
+
+// SLIP (RFC 1055) framing, used by the agent protocol on the second serial port.
+
+/// Frame delimiter.
+pub const END: u8 = 0xc0;
+/// Escape byte.
+pub const ESC: u8 = 0xdb;
+/// Escaped `END` (`ESC ESC_END`).
+pub const ESC_END: u8 = 0xdc;
+/// Escaped `ESC` (`ESC ESC_ESC`).
+pub const ESC_ESC: u8 = 0xdd;
+
+/// Default maximum decoded frame size.
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 64 * 1024;
+
+/// Encode `frame` as a SLIP packet.
+///
+/// The packet starts with an `END` byte as well, so that any line noise received
+/// before it is flushed as an (invalid) frame by the peer.
+pub fn encode(frame: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(frame.len() + 2);
+
+    packet.push(END);
+    for byte in frame {
+        match *byte {
+            END => packet.extend_from_slice(&[ESC, ESC_END]),
+            ESC => packet.extend_from_slice(&[ESC, ESC_ESC]),
+            b => packet.push(b),
+        }
+    }
+    packet.push(END);
+
+    packet
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    // Accumulating frame bytes.
+    Normal,
+    // The previous byte was ESC.
+    Escape,
+    // The current frame is corrupted, drop everything until the next END.
+    Discard,
+}
+
+/// Streaming SLIP decoder.
+///
+/// Bytes can be fed in arbitrary chunks, frames split across several calls to
+/// [`Decoder::decode`] are reassembled. Frames containing an invalid escape
+/// sequence or larger than the maximum frame size are dropped, and the decoder
+/// resynchronizes on the next `END` byte.
+#[derive(Debug)]
+pub struct Decoder {
+    buffer: Vec<u8>,
+    max_frame_size: usize,
+    state: State,
+    dropped: u64,
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Decoder::new(DEFAULT_MAX_FRAME_SIZE)
+    }
+}
+
+impl Decoder {
+    pub fn new(max_frame_size: usize) -> Self {
+        Decoder {
+            buffer: Vec::new(),
+            max_frame_size,
+            state: State::Normal,
+            dropped: 0,
+        }
+    }
+
+    /// Number of corrupted or oversized frames dropped so far.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    fn drop_frame(&mut self) {
+        self.buffer.clear();
+        self.state = State::Discard;
+        self.dropped += 1;
+    }
+
+    fn push(&mut self, byte: u8) {
+        if self.buffer.len() == self.max_frame_size {
+            self.drop_frame();
+        } else {
+            self.buffer.push(byte);
+        }
+    }
+
+    /// Feed a single byte, returning a frame if `byte` completed one.
+    pub fn decode_byte(&mut self, byte: u8) -> Option<Vec<u8>> {
+        match (self.state, byte) {
+            (State::Discard, END) => self.state = State::Normal,
+            (State::Discard, _) => {}
+            // An END right after an ESC is a protocol violation, but it still ends the frame.
+            (State::Escape, END) => {
+                self.buffer.clear();
+                self.state = State::Normal;
+                self.dropped += 1;
+            }
+            (State::Escape, ESC_END) => {
+                self.state = State::Normal;
+                self.push(END);
+            }
+            (State::Escape, ESC_ESC) => {
+                self.state = State::Normal;
+                self.push(ESC);
+            }
+            (State::Escape, _) => self.drop_frame(),
+            (State::Normal, END) => {
+                // Back-to-back END bytes produce empty frames, which are skipped.
+                if !self.buffer.is_empty() {
+                    return Some(std::mem::take(&mut self.buffer));
+                }
+            }
+            (State::Normal, ESC) => self.state = State::Escape,
+            (State::Normal, b) => self.push(b),
+        }
+
+        None
+    }
+
+    /// Feed `data`, returning all the frames it completed.
+    pub fn decode(&mut self, data: &[u8]) -> Vec<Vec<u8>> {
+        data.iter()
+            .filter_map(|byte| self.decode_byte(*byte))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Minimal xorshift PRNG, enough to generate reproducible test inputs.
+    struct XorShift(u64);
+
+    impl XorShift {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, max: usize) -> usize {
+            (self.next() % max as u64) as usize
+        }
+    }
+
+    #[test]
+    fn encode_escapes() {
+        assert_eq!(
+            encode(&[1, END, 2, ESC, 3]),
+            vec![END, 1, ESC, ESC_END, 2, ESC, ESC_ESC, 3, END]
+        );
+        assert_eq!(encode(&[]), vec![END, END]);
+    }
+
+    #[test]
+    fn back_to_back_frames() {
+        let mut data = encode(b"hello");
+        data.extend(encode(b"world"));
+        data.extend(encode(&[END, ESC]));
+
+        let mut decoder = Decoder::default();
+        assert_eq!(
+            decoder.decode(&data),
+            vec![b"hello".to_vec(), b"world".to_vec(), vec![END, ESC]]
+        );
+        assert_eq!(decoder.dropped(), 0);
+    }
+
+    #[test]
+    fn escape_split_across_writes() {
+        let data = encode(&[0x42, END, ESC]);
+        let mut decoder = Decoder::default();
+
+        // Split right after each ESC byte.
+        assert!(decoder.decode(&data[..3]).is_empty());
+        assert!(decoder.decode(&data[3..5]).is_empty());
+        assert_eq!(decoder.decode(&data[5..]), vec![vec![0x42, END, ESC]]);
+    }
+
+    #[test]
+    fn resync_after_invalid_escape() {
+        let mut data = vec![END, 1, 2, ESC, 0x42, 3, END];
+        data.extend(encode(b"ok"));
+
+        let mut decoder = Decoder::default();
+        assert_eq!(decoder.decode(&data), vec![b"ok".to_vec()]);
+        assert_eq!(decoder.dropped(), 1);
+    }
+
+    #[test]
+    fn oversized_frame() {
+        let mut data = encode(&[7u8; 17]);
+        data.extend(encode(&[8u8; 16]));
+
+        let mut decoder = Decoder::new(16);
+        assert_eq!(decoder.decode(&data), vec![vec![8u8; 16]]);
+        assert_eq!(decoder.dropped(), 1);
+    }
+
+    #[test]
+    fn random_frames_random_splits() {
+        let mut rng = XorShift(0x2545_f491_4f6c_dd1d);
+
+        for _ in 0..200 {
+            let frames: Vec<Vec<u8>> = (0..rng.below(8) + 1)
+                .map(|_| {
+                    // Bias the content towards the special bytes.
+                    (0..rng.below(300) + 1)
+                        .map(|_| match rng.below(4) {
+                            0 => END,
+                            1 => ESC,
+                            _ => rng.next() as u8,
+                        })
+                        .collect()
+                })
+                .collect();
+            let data: Vec<u8> = frames.iter().flat_map(|f| encode(f)).collect();
+
+            let mut decoder = Decoder::default();
+            let mut decoded = Vec::new();
+            let mut offset = 0;
+            while offset < data.len() {
+                let len = std::cmp::min(rng.below(16) + 1, data.len() - offset);
+                decoded.extend(decoder.decode(&data[offset..offset + len]));
+                offset += len;
+            }
+
+            assert_eq!(decoded, frames);
+        }
+    }
+}
@@ -0,0 +1,307 @@
+// SPDX-License-Identifier: Apache-2.0
+
+#![cfg(target_arch = "x86_64")]
+
+//! SMBIOS tables, telling the guest what machine it runs on: the BIOS (type 0), the system
+//! with its UUID and serial number (type 1), and the chassis (type 3).
+//!
+//! Without EFI, the guest finds the 64-bit entry point by scanning the last 64 KiB of the
+//! BIOS area. It points to the structure table right after it.
+
+use std::result;
+
+use vm_memory::{Bytes, GuestAddress, GuestMemoryError, GuestMemoryMmap};
+
+use crate::config::{SmbiosConfig, Uuid};
+use crate::layout::{SMBIOS_END, SMBIOS_START};
+
+// SMBIOS 3.2, with the 64-bit entry point.
+const ENTRY_POINT_SIZE: usize = 24;
+const MAJOR_VERSION: u8 = 3;
+const MINOR_VERSION: u8 = 2;
+const ENTRY_POINT_REVISION: u8 = 1;
+
+const BIOS_INFORMATION: u8 = 0;
+const SYSTEM_INFORMATION: u8 = 1;
+const CHASSIS_INFORMATION: u8 = 3;
+const END_OF_TABLE: u8 = 127;
+
+// BIOS characteristics are not supported.
+const BIOS_CHARACTERISTICS: u64 = 1 << 3;
+// The table describes a virtual machine, in the second extension byte.
+const BIOS_CHARACTERISTICS_VM: u8 = 1 << 4;
+// The system was powered on with its power switch.
+const WAKE_UP_POWER_SWITCH: u8 = 6;
+const CHASSIS_OTHER: u8 = 1;
+// Boot-up, power supply and thermal states.
+const CHASSIS_STATE_SAFE: u8 = 3;
+const CHASSIS_SECURITY_NONE: u8 = 3;
+
+const MANUFACTURER: &str = "lumper";
+const PRODUCT: &str = "lumper";
+const BIOS_VERSION: &str = "0";
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// The tables do not fit in the BIOS area.
+    #[error("the SMBIOS tables do not fit in the BIOS area")]
+    TooLarge,
+    /// Failed to write the tables to the guest memory.
+    #[error("failed to write the SMBIOS tables")]
+    Write(#[source] GuestMemoryError),
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+// A structure being built: its formatted area, then its strings.
+struct Structure {
+    bytes: Vec<u8>,
+    strings: Vec<u8>,
+    count: u8,
+}
+
+impl Structure {
+    fn new(kind: u8, handle: u16) -> Self {
+        let mut bytes = vec![kind];
+        // Length of the formatted area, filled once it is complete.
+        bytes.push(0);
+        bytes.extend_from_slice(&handle.to_le_bytes());
+
+        Structure {
+            bytes,
+            strings: Vec::new(),
+            count: 0,
+        }
+    }
+
+    fn append(&mut self, data: &[u8]) {
+        self.bytes.extend_from_slice(data);
+    }
+
+    // Add a reference to `string`, numbered from 1 in the order they are added.
+    fn string(&mut self, string: &str) {
+        self.strings.extend_from_slice(string.as_bytes());
+        self.strings.push(0);
+        self.count += 1;
+        self.bytes.push(self.count);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        self.bytes[1] = self.bytes.len() as u8;
+        // The strings end with an empty one, there are two NULs when there is none.
+        if self.strings.is_empty() {
+            self.strings.push(0);
+        }
+        self.strings.push(0);
+        self.bytes.extend_from_slice(&self.strings);
+        self.bytes
+    }
+}
+
+// Value making the bytes of `data` sum to zero, once stored in `data`.
+fn checksum(data: &[u8]) -> u8 {
+    let sum = data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+    0u8.wrapping_sub(sum)
+}
+
+fn entry_point(table: GuestAddress, table_size: usize) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(ENTRY_POINT_SIZE);
+    bytes.extend_from_slice(b"_SM3_");
+    // Checksum, filled once the entry point is complete.
+    bytes.push(0);
+    bytes.push(ENTRY_POINT_SIZE as u8);
+    bytes.extend_from_slice(&[MAJOR_VERSION, MINOR_VERSION, 0, ENTRY_POINT_REVISION, 0]);
+    bytes.extend_from_slice(&(table_size as u32).to_le_bytes());
+    bytes.extend_from_slice(&table.0.to_le_bytes());
+
+    bytes[5] = checksum(&bytes);
+    bytes
+}
+
+fn bios_information(handle: u16) -> Vec<u8> {
+    let mut bios = Structure::new(BIOS_INFORMATION, handle);
+    bios.string(MANUFACTURER);
+    bios.string(BIOS_VERSION);
+    // No BIOS starting segment, release date nor ROM.
+    bios.append(&0u16.to_le_bytes());
+    bios.append(&[0, 0]);
+    bios.append(&BIOS_CHARACTERISTICS.to_le_bytes());
+    bios.append(&[0, BIOS_CHARACTERISTICS_VM]);
+    bios.finish()
+}
+
+fn system_information(handle: u16, uuid: &Uuid, config: &SmbiosConfig) -> Vec<u8> {
+    let uuid_string = uuid.to_string();
+    let mut system = Structure::new(SYSTEM_INFORMATION, handle);
+    system.string(config.manufacturer.as_deref().unwrap_or(MANUFACTURER));
+    system.string(config.product.as_deref().unwrap_or(PRODUCT));
+    optional_string(&mut system, config.version.as_deref());
+    system.string(config.serial.as_deref().unwrap_or(&uuid_string));
+    // Since SMBIOS 2.6, the first three fields of the UUID are little-endian.
+    let bytes = uuid.0;
+    system.append(&[bytes[3], bytes[2], bytes[1], bytes[0]]);
+    system.append(&[bytes[5], bytes[4], bytes[7], bytes[6]]);
+    system.append(&bytes[8..]);
+    system.append(&[WAKE_UP_POWER_SWITCH]);
+    optional_string(&mut system, config.sku.as_deref());
+    optional_string(&mut system, config.family.as_deref());
+    system.finish()
+}
+
+fn chassis_information(handle: u16, config: &SmbiosConfig) -> Vec<u8> {
+    let mut chassis = Structure::new(CHASSIS_INFORMATION, handle);
+    chassis.string(config.manufacturer.as_deref().unwrap_or(MANUFACTURER));
+    chassis.append(&[CHASSIS_OTHER]);
+    // No version, serial number nor asset tag.
+    chassis.append(&[0, 0, 0]);
+    chassis.append(&[CHASSIS_STATE_SAFE; 3]);
+    chassis.append(&[CHASSIS_SECURITY_NONE]);
+    // No OEM information, height, power cords nor contained elements.
+    chassis.append(&0u32.to_le_bytes());
+    chassis.append(&[0, 0, 0, 0]);
+    // No SKU number.
+    chassis.append(&[0]);
+    chassis.finish()
+}
+
+// A string, or no reference when it is unset.
+fn optional_string(structure: &mut Structure, string: Option<&str>) {
+    match string {
+        Some(string) => structure.string(string),
+        None => structure.append(&[0]),
+    }
+}
+
+/// Write the SMBIOS tables describing the VM with `uuid` to the guest memory.
+pub fn setup_smbios(mem: &GuestMemoryMmap, uuid: &Uuid, config: &SmbiosConfig) -> Result<()> {
+    let table: Vec<u8> = [
+        bios_information(0),
+        system_information(1, uuid, config),
+        chassis_information(2, config),
+        Structure::new(END_OF_TABLE, 3).finish(),
+    ]
+    .concat();
+
+    let table_addr = SMBIOS_START + ENTRY_POINT_SIZE as u64;
+    if table_addr + table.len() as u64 > SMBIOS_END {
+        return Err(Error::TooLarge);
+    }
+
+    mem.write_slice(
+        &entry_point(GuestAddress(table_addr), table.len()),
+        GuestAddress(SMBIOS_START),
+    )
+    .map_err(Error::Write)?;
+    mem.write_slice(&table, GuestAddress(table_addr))
+        .map_err(Error::Write)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The structures of the table, as (type, formatted area, strings).
+    fn structures(mut table: &[u8]) -> Vec<(u8, Vec<u8>, Vec<String>)> {
+        let mut structures = Vec::new();
+        while !table.is_empty() {
+            let length = table[1] as usize;
+            let end = table[length..]
+                .windows(2)
+                .position(|pair| pair == [0, 0])
+                .unwrap()
+                + length;
+            let strings = table[length..end]
+                .split(|byte| *byte == 0)
+                .filter(|string| !string.is_empty())
+                .map(|string| String::from_utf8(string.to_vec()).unwrap())
+                .collect();
+            structures.push((table[0], table[..length].to_vec(), strings));
+            table = &table[end + 2..];
+        }
+        structures
+    }
+
+    fn setup(uuid: &Uuid, config: &SmbiosConfig) -> Vec<(u8, Vec<u8>, Vec<String>)> {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 1 << 20)]).unwrap();
+        setup_smbios(&mem, uuid, config).unwrap();
+
+        let mut entry = [0u8; ENTRY_POINT_SIZE];
+        mem.read_slice(&mut entry, GuestAddress(SMBIOS_START))
+            .unwrap();
+        assert_eq!(&entry[..5], b"_SM3_");
+        assert_eq!(checksum(&entry), 0);
+        assert_eq!(&entry[7..9], [3, 2]);
+
+        let size = u32::from_le_bytes(entry[12..16].try_into().unwrap()) as usize;
+        let address = u64::from_le_bytes(entry[16..24].try_into().unwrap());
+        let mut table = vec![0u8; size];
+        mem.read_slice(&mut table, GuestAddress(address)).unwrap();
+        structures(&table)
+    }
+
+    #[test]
+    fn tables() {
+        let uuid: Uuid = "00112233-4455-6677-8899-aabbccddeeff".parse().unwrap();
+        let structures = setup(&uuid, &SmbiosConfig::default());
+        let kinds: Vec<u8> = structures.iter().map(|(kind, ..)| *kind).collect();
+        assert_eq!(kinds, [0, 1, 3, 127]);
+
+        let (_, bios, strings) = &structures[0];
+        assert_eq!(bios.len(), 0x14);
+        assert_eq!(strings, &["lumper", "0"]);
+        assert_eq!(bios[0x13], BIOS_CHARACTERISTICS_VM);
+
+        // The guest shows the UUID as it was given, and it is the serial number.
+        let (_, system, strings) = &structures[1];
+        assert_eq!(system.len(), 0x1b);
+        assert_eq!(
+            system[8..24],
+            [
+                0x33, 0x22, 0x11, 0x00, 0x55, 0x44, 0x77, 0x66, 0x88, 0x99, 0xaa, 0xbb, 0xcc, 0xdd,
+                0xee, 0xff
+            ]
+        );
+        assert_eq!(
+            strings,
+            &["lumper", "lumper", "00112233-4455-6677-8899-aabbccddeeff"]
+        );
+        // No version, SKU nor family.
+        assert_eq!([system[6], system[0x19], system[0x1a]], [0, 0, 0]);
+
+        let (_, chassis, strings) = &structures[2];
+        assert_eq!(chassis.len(), 0x16);
+        assert_eq!(strings, &["lumper"]);
+        assert_eq!(structures[3].1.len(), 4);
+    }
+
+    #[test]
+    fn strings() {
+        let uuid = Uuid::random().unwrap();
+        let config = SmbiosConfig {
+            manufacturer: Some("ACME".to_string()),
+            product: Some("runner".to_string()),
+            version: Some("2".to_string()),
+            serial: Some("42".to_string()),
+            sku: Some("S".to_string()),
+            family: Some("F".to_string()),
+        };
+        let structures = setup(&uuid, &config);
+
+        let (_, system, strings) = &structures[1];
+        assert_eq!(strings, &["ACME", "runner", "2", "42", "S", "F"]);
+        assert_eq!(
+            [
+                system[4],
+                system[5],
+                system[6],
+                system[7],
+                system[0x19],
+                system[0x1a]
+            ],
+            [1, 2, 3, 4, 5, 6]
+        );
+        assert_eq!(structures[2].2, ["ACME"]);
+    }
+}
@@ -0,0 +1,194 @@
+// SPDX-License-Identifier: Apache-2.0
+
+// Validates configurations with --dry-run, which needs neither KVM nor a real kernel: the
+// kernel is a bare x86_64 ELF header with a single segment, and the strings the boot checks
+// look for.
+
+use std::env;
+use std::fs::{self, File};
+use std::io;
+use std::os::fd::AsRawFd;
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+fn temp_path(name: &str) -> PathBuf {
+    env::temp_dir().join(format!("lumper-test-{}-{}", std::process::id(), name))
+}
+
+// An ELF64 kernel loading `size` bytes at 16 MiB.
+fn write_kernel(name: &str, size: u64) -> PathBuf {
+    let paddr: u64 = 0x100_0000;
+    let mut image = vec![0u8; 0x100];
+    image[..6].copy_from_slice(b"\x7fELF\x02\x01");
+    // EM_X86_64.
+    image[0x12..0x14].copy_from_slice(&62u16.to_le_bytes());
+    image[0x18..0x20].copy_from_slice(&paddr.to_le_bytes());
+    image[0x20..0x28].copy_from_slice(&64u64.to_le_bytes());
+    image[0x36..0x38].copy_from_slice(&56u16.to_le_bytes());
+    image[0x38..0x3a].copy_from_slice(&1u16.to_le_bytes());
+    // PT_LOAD, with some content in the file.
+    image[0x40..0x44].copy_from_slice(&1u32.to_le_bytes());
+    image[0x58..0x60].copy_from_slice(&paddr.to_le_bytes());
+    image[0x60..0x68].copy_from_slice(&0x10u64.to_le_bytes());
+    image[0x68..0x70].copy_from_slice(&size.to_le_bytes());
+    let banner = b"Linux version 6.6.8 (lumper@test) serial8250";
+    image[0x80..0x80 + banner.len()].copy_from_slice(banner);
+
+    let path = temp_path(name);
+    fs::write(&path, image).unwrap();
+    path
+}
+
+fn dry_run(args: &[&str]) -> (Output, serde_json::Value) {
+    dry_run_with_fds(args, &[])
+}
+
+// Runs with the files open in lumper, as descriptors of the same number.
+fn dry_run_with_fds(args: &[&str], files: &[&File]) -> (Output, serde_json::Value) {
+    let fds: Vec<_> = files.iter().map(|file| file.as_raw_fd()).collect();
+    let mut command = Command::new(env!("CARGO_BIN_EXE_lumper"));
+    command.args(args).args(["--dry-run", "--output", "json"]);
+    // Safe because clearing the close-on-exec flags is async-signal-safe.
+    unsafe {
+        command.pre_exec(move || {
+            for fd in fds.iter() {
+                if libc::fcntl(*fd, libc::F_SETFD, 0) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+
+    let output = command.output().unwrap();
+    let summary = serde_json::from_slice(&output.stdout).unwrap();
+    (output, summary)
+}
+
+#[test]
+fn valid_config() {
+    let kernel = write_kernel("dry-run-vmlinux", 0x80_0000);
+    let initramfs = temp_path("dry-run-initramfs");
+    fs::write(&initramfs, vec![0u8; 0x1000]).unwrap();
+
+    let (output, summary) = dry_run(&[
+        "--kernel",
+        kernel.to_str().unwrap(),
+        "--initramfs",
+        initramfs.to_str().unwrap(),
+        "--cpus",
+        "2",
+    ]);
+    let _ = fs::remove_file(&kernel);
+    let _ = fs::remove_file(&initramfs);
+
+    assert!(output.status.success(), "{}", summary);
+    assert_eq!(summary["valid"], true);
+    assert_eq!(summary["config"]["cpus"], 2);
+    assert_eq!(summary["images"]["kernel"]["format"], "elf");
+    assert_eq!(summary["images"]["kernel"]["end"], 0x180_0000);
+    assert_eq!(summary["images"]["initramfs"]["address"], 0x180_0001);
+    assert_eq!(summary["images"]["initramfs"]["size"], 0x1000);
+}
+
+#[test]
+fn invalid_config() {
+    // A 512 MiB kernel does not fit in the default memory.
+    let kernel = write_kernel("dry-run-large-vmlinux", 0x2000_0000);
+    let (output, summary) = dry_run(&["--kernel", kernel.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(64));
+    assert_eq!(summary["valid"], false);
+    assert!(summary["error"]
+        .as_str()
+        .unwrap()
+        .contains("past the guest RAM end"));
+
+    // Rejected before looking at the kernel.
+    let (output, summary) = dry_run(&["--kernel", kernel.to_str().unwrap(), "--memory", "16"]);
+    let _ = fs::remove_file(&kernel);
+    assert_eq!(output.status.code(), Some(64));
+    assert!(summary["error"]
+        .as_str()
+        .unwrap()
+        .starts_with("invalid configuration"));
+    assert!(summary.get("images").is_none());
+
+    let (output, summary) = dry_run(&["--kernel", "/nonexistent/vmlinux"]);
+    assert_eq!(output.status.code(), Some(64));
+    assert_eq!(summary["valid"], false);
+}
+
+#[test]
+fn fd_images() {
+    let kernel = write_kernel("dry-run-fd-vmlinux", 0x80_0000);
+    let kernel_file = File::open(&kernel).unwrap();
+    let initramfs = temp_path("dry-run-fd-initramfs");
+    fs::write(&initramfs, vec![0u8; 0x1000]).unwrap();
+    let initramfs_file = File::open(&initramfs).unwrap();
+    // Only the descriptors are left.
+    let _ = fs::remove_file(&kernel);
+    let _ = fs::remove_file(&initramfs);
+
+    let kernel_arg = format!("fd:{}", kernel_file.as_raw_fd());
+    let initramfs_arg = format!("/proc/self/fd/{}", initramfs_file.as_raw_fd());
+    let (output, summary) = dry_run_with_fds(
+        &["--kernel", &kernel_arg, "--initramfs", &initramfs_arg],
+        &[&kernel_file, &initramfs_file],
+    );
+    assert!(output.status.success(), "{}", summary);
+    assert_eq!(summary["config"]["kernel"], kernel_arg);
+    assert_eq!(summary["images"]["kernel"]["end"], 0x180_0000);
+    assert_eq!(summary["images"]["initramfs"]["size"], 0x1000);
+
+    // Not inherited.
+    let (output, summary) = dry_run(&["--kernel", "fd:999"]);
+    assert_eq!(output.status.code(), Some(64));
+    assert!(summary["error"]
+        .as_str()
+        .unwrap()
+        .contains("not a readable and seekable file"));
+}
+
+#[test]
+fn boot_check() {
+    let kernel = write_kernel("dry-run-check-vmlinux", 0x80_0000);
+    let initramfs = temp_path("dry-run-check-initramfs");
+    fs::write(
+        &initramfs,
+        include_bytes!("../src/vmm/src/inspect/fixtures/busybox.cpio"),
+    )
+    .unwrap();
+    let images = [
+        "--kernel",
+        kernel.to_str().unwrap(),
+        "--initramfs",
+        initramfs.to_str().unwrap(),
+    ];
+
+    let (output, summary) = dry_run(&images);
+    assert!(output.status.success(), "{}", summary);
+    assert_eq!(summary["check"]["kernel_version"], "6.6.8");
+    assert_eq!(summary["check"]["kernel_arch"], "x86_64");
+    assert_eq!(summary["check"]["init_arch"], "x86_64");
+    assert_eq!(summary["check"]["problems"], serde_json::json!([]));
+
+    // The guest would boot without a console, which --strict does not let go.
+    let hvc0 = [&images[..], &["--append", "console=hvc0"]].concat();
+    let (output, summary) = dry_run(&hvc0);
+    assert!(output.status.success(), "{}", summary);
+    assert!(summary["check"]["problems"][0]
+        .as_str()
+        .unwrap()
+        .starts_with("console=hvc0 is not a console of the VM"));
+    let (output, summary) = dry_run(&[&hvc0[..], &["--strict"]].concat());
+    assert_eq!(output.status.code(), Some(64));
+    assert_eq!(summary["valid"], false);
+    assert_eq!(summary["error"], "the boot images may not boot");
+
+    let (output, summary) = dry_run(&[&hvc0[..], &["--strict", "--no-validate"]].concat());
+    let _ = fs::remove_file(&kernel);
+    let _ = fs::remove_file(&initramfs);
+    assert!(output.status.success(), "{}", summary);
+    assert!(summary.get("check").is_none());
+}
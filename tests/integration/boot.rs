@@ -0,0 +1,305 @@
+// SPDX-License-Identifier: Apache-2.0
+
+// Boots with the main configurations, and stops for each reason the guest can give.
+
+use std::os::unix::io::AsRawFd;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use vmm::config::WatchdogAction;
+use vmm::{ExitReason, PanicReport};
+
+use crate::harness::{api_request, api_request_fd, temp_path, Tap, TestVm};
+
+const BOOT_TIMEOUT: Duration = Duration::from_secs(30);
+const EXIT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Boot with `cpus` vCPUs, check that the guest sees them all, then power it off.
+fn boot_cpus(cpus: u8) {
+    TestVm::builder()
+        .cpus(cpus)
+        .spawn()
+        .wait_for("Linux version", BOOT_TIMEOUT)
+        // The echo of the command has `$(nproc)`, not the count.
+        .send("echo cpus-$(nproc)x\n")
+        .wait_for(&format!("cpus-{}x", cpus), BOOT_TIMEOUT)
+        .send("poweroff -f\n")
+        .expect_exit(ExitReason::GuestShutdown, EXIT_TIMEOUT);
+}
+
+#[test]
+#[ignore = "needs KVM, LUMPER_KERNEL and LUMPER_INITRAMFS"]
+fn one_vcpu() {
+    boot_cpus(1);
+}
+
+#[test]
+#[ignore = "needs KVM, LUMPER_KERNEL and LUMPER_INITRAMFS"]
+fn two_vcpus() {
+    boot_cpus(2);
+}
+
+#[test]
+#[ignore = "needs KVM, LUMPER_KERNEL and LUMPER_INITRAMFS"]
+fn four_vcpus() {
+    boot_cpus(4);
+}
+
+#[test]
+#[ignore = "needs KVM, LUMPER_KERNEL and LUMPER_INITRAMFS"]
+fn sixteen_vcpus() {
+    boot_cpus(16);
+}
+
+// The vCPUs are set up while the kernel, the initramfs and the command line load.
+#[test]
+#[ignore = "needs KVM, LUMPER_KERNEL and LUMPER_INITRAMFS"]
+fn parallel_load() {
+    TestVm::builder()
+        .cpus(4)
+        .spawn()
+        .wait_for("Linux version", BOOT_TIMEOUT)
+        .send("echo cpus-$(nproc)-cmdline-$(grep -c i8042.nokbd /proc/cmdline)x\n")
+        .wait_for("cpus-4-cmdline-1x", BOOT_TIMEOUT)
+        .send("poweroff -f\n")
+        .expect_exit(ExitReason::GuestShutdown, EXIT_TIMEOUT);
+}
+
+#[test]
+#[ignore = "needs KVM, root, LUMPER_KERNEL and LUMPER_INITRAMFS"]
+fn net() {
+    let tap = Tap::new();
+    TestVm::builder()
+        .net(&tap)
+        .spawn()
+        .wait_for("Linux version", BOOT_TIMEOUT)
+        .send("ip link set eth0 up && echo eth0-$((1 + 1))-up\n")
+        .wait_for("eth0-2-up", BOOT_TIMEOUT)
+        .send("poweroff -f\n")
+        .expect_exit(ExitReason::GuestShutdown, EXIT_TIMEOUT);
+}
+
+#[test]
+#[ignore = "needs KVM, LUMPER_KERNEL and LUMPER_INITRAMFS"]
+fn reset() {
+    TestVm::builder()
+        .spawn()
+        .wait_for("Linux version", BOOT_TIMEOUT)
+        .send("reboot -f\n")
+        .expect_exit(ExitReason::GuestReset, EXIT_TIMEOUT);
+}
+
+#[test]
+#[ignore = "needs KVM, LUMPER_KERNEL and LUMPER_INITRAMFS"]
+fn restart_on_reboot() {
+    let mut vm = TestVm::builder()
+        .arg("--restart-on-reboot")
+        .arg("--max-reboots")
+        .arg("1")
+        .spawn();
+    vm.wait_for("Linux version", BOOT_TIMEOUT)
+        .send("touch /tmp/first-boot; reboot -f\n");
+
+    // The same VMM boots the guest again, with its memory cleared.
+    let deadline = Instant::now() + BOOT_TIMEOUT;
+    while vm.console().matches("Linux version").count() < 2 {
+        assert!(
+            Instant::now() < deadline,
+            "no second boot:\n{}",
+            vm.console()
+        );
+        thread::sleep(Duration::from_millis(100));
+    }
+    vm.send("test -e /tmp/first-boot || echo second-boot-$((6 * 7))x\n")
+        .wait_for("second-boot-42x", BOOT_TIMEOUT)
+        // Past the maximum, the reboot stops the VM.
+        .send("reboot -f\n")
+        .expect_exit(ExitReason::GuestReset, EXIT_TIMEOUT);
+}
+
+// Enables the watchdog at the address the command line gives, and never pets it.
+const ENABLE_WATCHDOG: &str =
+    "devmem $(sed 's/.*lumper.watchdog=\\([^ ]*\\).*/\\1/' /proc/cmdline) 32 1\n";
+
+#[test]
+#[ignore = "needs KVM, LUMPER_KERNEL and LUMPER_INITRAMFS"]
+fn watchdog_reset() {
+    let mut vm = TestVm::builder()
+        .arg("--watchdog")
+        .arg("timeout=1,action=reset")
+        .arg("--restart-on-reboot")
+        .arg("--max-reboots")
+        .arg("1")
+        .spawn();
+    vm.wait_for("Linux version", BOOT_TIMEOUT)
+        .send(ENABLE_WATCHDOG);
+
+    // The expired watchdog restarts the guest in place.
+    let deadline = Instant::now() + BOOT_TIMEOUT;
+    while vm.console().matches("Linux version").count() < 2 {
+        assert!(
+            Instant::now() < deadline,
+            "no second boot:\n{}",
+            vm.console()
+        );
+        thread::sleep(Duration::from_millis(100));
+    }
+    // Past the maximum, it stops the VM.
+    vm.send(ENABLE_WATCHDOG).expect_exit(
+        ExitReason::WatchdogExpired(WatchdogAction::Reset),
+        EXIT_TIMEOUT,
+    );
+}
+
+#[test]
+#[ignore = "needs KVM, LUMPER_KERNEL and LUMPER_INITRAMFS"]
+fn timeout() {
+    // The shell waits for input forever.
+    TestVm::builder()
+        .timeout(5)
+        .spawn()
+        .wait_for("Linux version", BOOT_TIMEOUT)
+        .expect_exit(ExitReason::Timeout, BOOT_TIMEOUT);
+}
+
+#[test]
+#[ignore = "needs KVM, LUMPER_KERNEL and LUMPER_INITRAMFS"]
+fn panic_output() {
+    let mut vm = TestVm::builder().spawn();
+    vm.wait_for("Linux version", BOOT_TIMEOUT)
+        .send("echo last-$((6 * 7))x; echo c > /proc/sysrq-trigger\n")
+        .expect_exit(
+            ExitReason::GuestPanic(PanicReport::Console(String::new())),
+            EXIT_TIMEOUT,
+        );
+
+    // The VM stopped as soon as the panic showed up, its last lines are in the console
+    // file all the same.
+    let console = vm.console();
+    assert!(console.contains("last-42x"), "{}", console);
+    assert!(
+        console.contains("Kernel panic - not syncing: sysrq triggered crash"),
+        "{}",
+        console
+    );
+}
+
+// The bytes the guest wrote to its console, from the stats the API socket returns.
+fn console_bytes(stats: &str) -> u64 {
+    let counters = &stats[stats.find("\"serial\":").expect(stats)..];
+    let bytes = &counters[counters.find("\"tx_bytes\":").expect(stats) + "\"tx_bytes\":".len()..];
+    let end = bytes.find(|c: char| !c.is_ascii_digit()).unwrap();
+    bytes[..end].parse().unwrap()
+}
+
+#[test]
+#[ignore = "needs KVM, LUMPER_KERNEL and LUMPER_INITRAMFS"]
+fn headless() {
+    // The guest only writes to the discarded console when told to.
+    let socket = temp_path("headless-api");
+    let mut vm = TestVm::builder()
+        .headless()
+        .timeout(20)
+        .arg("--cmdline")
+        .arg("console=ttyS0")
+        .arg("--api-socket")
+        .arg(&socket)
+        .spawn();
+
+    // Its output is still counted.
+    let deadline = Instant::now() + BOOT_TIMEOUT;
+    loop {
+        let stats = api_request(&socket, "{\"action\":\"stats\"}\n", BOOT_TIMEOUT);
+        if console_bytes(&stats) > 0 {
+            break;
+        }
+        assert!(Instant::now() < deadline, "no console output: {}", stats);
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    // And the VM runs with stdin a pipe, until it times out.
+    vm.expect_exit(ExitReason::Timeout, BOOT_TIMEOUT);
+    assert_eq!(vm.console(), "");
+    let _ = std::fs::remove_file(&socket);
+}
+
+// A recorded session replays against a fresh VM, which gives the same output.
+#[test]
+#[ignore = "needs KVM, LUMPER_KERNEL and LUMPER_INITRAMFS"]
+fn console_replay() {
+    let session = temp_path("console-session");
+    TestVm::builder()
+        .arg("--console-record")
+        .arg(&session)
+        .spawn()
+        .wait_for("Linux version", BOOT_TIMEOUT)
+        .send("echo replay-$((6 * 7))x\n")
+        .wait_for("replay-42x", BOOT_TIMEOUT)
+        .send("poweroff -f\n")
+        .expect_exit(ExitReason::GuestShutdown, EXIT_TIMEOUT);
+
+    // The input waits for the output it followed, the boot log differs in a few numbers.
+    let mut vm = TestVm::builder()
+        .replay(&session)
+        .arg("--replay-fast")
+        .arg("--replay-tolerance")
+        .arg("100")
+        .spawn();
+    vm.wait_for("replay-42x", BOOT_TIMEOUT)
+        .expect_exit(ExitReason::GuestShutdown, EXIT_TIMEOUT);
+    let _ = std::fs::remove_file(&session);
+}
+
+// Another process maps the guest memory while the VM runs, and finds what the guest wrote.
+#[test]
+#[ignore = "needs KVM, LUMPER_KERNEL and LUMPER_INITRAMFS"]
+fn memory_share() {
+    let socket = temp_path("memory-share-api");
+    let mut vm = TestVm::builder()
+        .arg("--memory-share")
+        .arg("memfd")
+        .arg("--api-socket")
+        .arg(&socket)
+        .spawn();
+    vm.wait_for("Linux version", BOOT_TIMEOUT);
+
+    let (response, memory) =
+        api_request_fd(&socket, "{\"action\":\"get-memory-fd\"}\n", BOOT_TIMEOUT);
+    let response: serde_json::Value = serde_json::from_str(&response).unwrap();
+    let size: u64 = response["regions"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|region| region["size"].as_u64().unwrap())
+        .sum();
+    let memory = memory.expect("no descriptor with the response");
+
+    // The descriptor is read-only.
+    let map = |prot| {
+        // Safe because we check the result, and unmap it.
+        unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                size as usize,
+                prot,
+                libc::MAP_SHARED,
+                memory.as_raw_fd(),
+                0,
+            )
+        }
+    };
+    assert_eq!(map(libc::PROT_READ | libc::PROT_WRITE), libc::MAP_FAILED);
+    let mapping = map(libc::PROT_READ);
+    assert_ne!(mapping, libc::MAP_FAILED);
+
+    // The kernel log buffer, or the kernel image.
+    // Safe because the mapping is `size` bytes long.
+    let ram = unsafe { std::slice::from_raw_parts(mapping as *const u8, size as usize) };
+    assert!(ram.windows(13).any(|window| window == b"Linux version"));
+    // Safe because nothing uses the mapping past this point.
+    assert_eq!(unsafe { libc::munmap(mapping, size as usize) }, 0);
+
+    vm.send("poweroff -f\n")
+        .expect_exit(ExitReason::GuestShutdown, EXIT_TIMEOUT);
+    let _ = std::fs::remove_file(&socket);
+}
@@ -0,0 +1,109 @@
+// SPDX-License-Identifier: Apache-2.0
+
+// Pauses a booted guest, and clones it: the clones go on running it from there. The VMs
+// run in process, through the vmm crate.
+
+use std::fs;
+use std::io::{BufRead, BufReader};
+use std::os::unix::net::UnixListener;
+use std::thread::{self, JoinHandle};
+
+use vmm::config::{ConsoleMode, VMMConfigBuilder};
+use vmm::{Error, ExitReason, PauseTrigger, VMM};
+
+use crate::harness::{initramfs, kernel, temp_path};
+
+// Init prints the guest uptime every second, only with shell builtins and sleep.
+const TICKER: &str =
+    "rdinit=/bin/sh -- -c \"while true; do read up idle < /proc/uptime; echo tick $up; sleep 1; done\"";
+
+// Accept the next console connection, and pause the VM once it printed `ticks` ticks.
+// Returns the console output, and the uptimes of the ticks.
+fn watch_console(
+    listener: &UnixListener,
+    pause: PauseTrigger,
+    ticks: usize,
+) -> JoinHandle<(String, Vec<f64>)> {
+    let listener = listener.try_clone().unwrap();
+    thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut output = String::new();
+        let mut uptimes = Vec::new();
+        for line in BufReader::new(stream).lines() {
+            let line = line.unwrap();
+            output.push_str(&line);
+            output.push('\n');
+            if let Some(uptime) = line.trim().strip_prefix("tick ") {
+                uptimes.push(uptime.parse().unwrap());
+                if uptimes.len() == ticks {
+                    break;
+                }
+            }
+        }
+
+        pause.pause();
+        (output, uptimes)
+    })
+}
+
+#[test]
+#[ignore = "needs KVM, LUMPER_KERNEL and LUMPER_INITRAMFS"]
+fn clone_paused_vm() {
+    let kernel = kernel();
+
+    // Each VM connects to the console socket.
+    let socket = temp_path("clone-console");
+    let listener = UnixListener::bind(&socket).unwrap();
+    let config = VMMConfigBuilder::default()
+        .kernel(kernel.clone())
+        .initramfs(Some(initramfs().into()))
+        .cmdline(Some(TICKER.to_string()))
+        .console(Some(ConsoleMode::Unix(socket.clone())))
+        .build()
+        .unwrap();
+
+    // The clones would truncate a console file.
+    let log = temp_path("clone-console.log");
+    let mut logging = VMM::from_config(
+        &VMMConfigBuilder::default()
+            .kernel(kernel)
+            .console(Some(ConsoleMode::File(log.clone().into())))
+            .build()
+            .unwrap(),
+    )
+    .unwrap();
+    logging.detach_stdin().unwrap();
+    assert!(matches!(
+        logging.clone_from_paused(),
+        Err(Error::NotClonable(_))
+    ));
+    drop(logging);
+    let _ = fs::remove_file(&log);
+
+    let mut template = VMM::from_config(&config).unwrap();
+    template.detach_stdin().unwrap();
+
+    let console = watch_console(&listener, template.pause_trigger(), 3);
+    assert_eq!(template.run().unwrap(), ExitReason::Paused);
+    let (output, uptimes) = console.join().unwrap();
+    assert!(output.contains("Linux version"), "{}", output);
+    let paused_at = uptimes[2];
+
+    // The template stays paused, each clone starts from there.
+    for _ in 0..2 {
+        let mut clone = template.clone_from_paused().unwrap();
+        let console = watch_console(&listener, clone.pause_trigger(), 2);
+        assert_eq!(clone.run().unwrap(), ExitReason::Paused);
+        let (output, uptimes) = console.join().unwrap();
+
+        assert!(!output.contains("Linux version"), "{}", output);
+        assert!(
+            uptimes[0] >= paused_at && uptimes[0] < paused_at + 3.0,
+            "paused at {}, then\n{}",
+            paused_at,
+            output
+        );
+    }
+
+    let _ = fs::remove_file(&socket);
+}
@@ -0,0 +1,57 @@
+// SPDX-License-Identifier: Apache-2.0
+
+// Sends the console to /dev/full, which fails every write with ENOSPC as a full disk
+// would, with each console error policy.
+
+use std::path::Path;
+use std::time::Duration;
+
+use vmm::ExitReason;
+
+use crate::harness::{TestVm, TestVmBuilder};
+
+const BOOT_TIMEOUT: Duration = Duration::from_secs(30);
+
+// The console output is lost, the guest powers off once its shell reads the input.
+fn full_console(policy: &str) -> TestVmBuilder {
+    TestVm::builder()
+        .console_file(Path::new("/dev/full"))
+        .arg("--console-error-policy")
+        .arg(policy)
+}
+
+#[test]
+#[ignore = "needs KVM, LUMPER_KERNEL and LUMPER_INITRAMFS"]
+fn discard() {
+    // The guest still runs up to the poweroff.
+    full_console("discard")
+        .spawn()
+        .send("poweroff -f\n")
+        .expect_exit(ExitReason::GuestShutdown, BOOT_TIMEOUT);
+}
+
+#[test]
+#[ignore = "needs KVM, LUMPER_KERNEL and LUMPER_INITRAMFS"]
+fn stop() {
+    let mut vm = full_console("stop").capture_stderr().spawn();
+    vm.send("poweroff -f\n")
+        .expect_exit(ExitReason::ConsoleError(String::new()), BOOT_TIMEOUT);
+
+    let stderr = vm.stderr();
+    assert!(
+        stderr.contains("failed to write the console output"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+#[ignore = "needs KVM, LUMPER_KERNEL and LUMPER_INITRAMFS"]
+fn stdout_fallback() {
+    let mut vm = full_console("stdout-fallback").capture_stdout().spawn();
+    vm.send("poweroff -f\n")
+        .expect_exit(ExitReason::GuestShutdown, BOOT_TIMEOUT);
+
+    let stdout = vm.stdout();
+    assert!(stdout.contains("Linux version"), "{}", stdout);
+}
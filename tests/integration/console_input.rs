@@ -0,0 +1,38 @@
+// SPDX-License-Identifier: Apache-2.0
+
+// Boots a guest without a terminal: the console input comes from a file, and the output
+// goes to another one.
+
+use std::fs;
+use std::time::Duration;
+
+use vmm::ExitReason;
+
+use crate::harness::{temp_path, TestVm};
+
+const BOOT_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[test]
+#[ignore = "needs KVM, LUMPER_KERNEL and LUMPER_INITRAMFS"]
+fn scripted_console() {
+    let input = temp_path("scripted-input");
+    fs::write(&input, "uname -r; poweroff -f\n").unwrap();
+
+    let mut vm = TestVm::builder().input_file(&input).spawn();
+    vm.expect_exit(ExitReason::GuestShutdown, BOOT_TIMEOUT);
+    let _ = fs::remove_file(&input);
+
+    // The release follows the echo of the command.
+    let console = vm.console();
+    let mut lines = console
+        .lines()
+        .map(str::trim)
+        .skip_while(|line| !line.ends_with("uname -r; poweroff -f"));
+    assert!(lines.next().is_some(), "{}", console);
+    let release = lines.next().unwrap_or_default();
+    assert!(
+        release.starts_with(|c: char| c.is_ascii_digit()) && release.contains('.'),
+        "{}",
+        console
+    );
+}
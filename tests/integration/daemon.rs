@@ -0,0 +1,42 @@
+// SPDX-License-Identifier: Apache-2.0
+
+// Runs lumper as a daemon, which keeps its console, its API socket and its signal
+// handling once detached.
+
+use std::fs;
+use std::time::Duration;
+
+use vmm::ExitReason;
+
+use crate::harness::{api_request, temp_path, TestVm};
+
+const BOOT_TIMEOUT: Duration = Duration::from_secs(30);
+const EXIT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[test]
+#[ignore = "needs KVM, LUMPER_KERNEL and LUMPER_INITRAMFS"]
+fn daemonize() {
+    let pidfile = temp_path("daemon-pid");
+    let socket = temp_path("daemon-api");
+    let mut vm = TestVm::builder()
+        .daemonize(&pidfile)
+        .arg("--api-socket")
+        .arg(&socket)
+        .spawn();
+    // The pidfile holds the daemon, not the process which started it.
+    assert!(pidfile.exists());
+
+    vm.wait_for("Linux version", BOOT_TIMEOUT);
+    let stats = api_request(&socket, "{\"action\":\"stats\"}\n", BOOT_TIMEOUT);
+    assert!(stats.contains("\"serial\":"), "{}", stats);
+
+    // Safe because the daemon is our child, still running.
+    assert_eq!(
+        unsafe { libc::kill(vm.pid() as libc::pid_t, libc::SIGTERM) },
+        0
+    );
+    vm.expect_exit(ExitReason::Signal(libc::SIGTERM), EXIT_TIMEOUT);
+    // The daemon removes its pidfile on exit.
+    assert!(!pidfile.exists());
+    let _ = fs::remove_file(&socket);
+}
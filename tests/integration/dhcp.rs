@@ -0,0 +1,35 @@
+// SPDX-License-Identifier: Apache-2.0
+
+// Hands the guest an address with the DHCP server on the host side of a tap, which the
+// guest udhcpc obtains.
+
+use std::time::Duration;
+
+use vmm::ExitReason;
+
+use crate::harness::{Tap, TestVm};
+
+const BOOT_TIMEOUT: Duration = Duration::from_secs(30);
+const EXIT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[test]
+#[ignore = "needs KVM, root, LUMPER_KERNEL and LUMPER_INITRAMFS"]
+fn udhcpc_lease() {
+    let tap = Tap::new().address("172.16.0.1/24");
+    // The lease is only printed, there is no script to configure the interface.
+    TestVm::builder()
+        .arg("--net")
+        .arg(format!(
+            "{},dhcp-server=172.16.0.1/24,range=172.16.0.10-172.16.0.20",
+            tap.0
+        ))
+        .spawn()
+        .wait_for("Linux version", BOOT_TIMEOUT)
+        .send("ip link set eth0 up; udhcpc -i eth0 -n -q -s /bin/true\n")
+        .wait_for(
+            "lease of 172.16.0.10 obtained from 172.16.0.1",
+            BOOT_TIMEOUT,
+        )
+        .send("poweroff -f\n")
+        .expect_exit(ExitReason::GuestShutdown, EXIT_TIMEOUT);
+}
@@ -0,0 +1,67 @@
+// SPDX-License-Identifier: Apache-2.0
+
+// Starts the boot vCPU in a firmware blob, which writes to the serial port then jumps to
+// the kernel.
+
+use std::fs;
+use std::time::Duration;
+
+use vmm::ExitReason;
+
+use crate::harness::{temp_path, TestVm};
+
+const BOOT_TIMEOUT: Duration = Duration::from_secs(30);
+const EXIT_TIMEOUT: Duration = Duration::from_secs(10);
+
+const COM1: u16 = 0x3f8;
+
+// Write `message` to COM1, then jump to the kernel entry, in rbx.
+fn blob(message: &str) -> Vec<u8> {
+    // mov dx, COM1
+    let mut code = vec![0x66, 0xba];
+    code.extend_from_slice(&COM1.to_le_bytes());
+    for byte in message.bytes() {
+        // mov al, byte; out dx, al
+        code.extend_from_slice(&[0xb0, byte, 0xee]);
+    }
+    // jmp rbx
+    code.extend_from_slice(&[0xff, 0xe3]);
+    code
+}
+
+#[test]
+#[ignore = "needs KVM, LUMPER_KERNEL and LUMPER_INITRAMFS"]
+fn firmware_entry() {
+    let firmware = temp_path("firmware.bin");
+    fs::write(&firmware, blob("firmware ran\n")).unwrap();
+
+    // The firmware writes first, then the kernel boots.
+    let mut vm = TestVm::builder()
+        .arg("--firmware")
+        .arg(format!("path={},addr=0xffff0000,entry", firmware.display()))
+        .spawn();
+    vm.wait_for("Linux version", BOOT_TIMEOUT)
+        .send("poweroff -f\n")
+        .expect_exit(ExitReason::GuestShutdown, EXIT_TIMEOUT);
+    let _ = fs::remove_file(&firmware);
+
+    let console = vm.console();
+    assert!(console.starts_with("firmware ran\n"), "{}", console);
+}
+
+#[test]
+#[ignore = "needs KVM, LUMPER_KERNEL and LUMPER_INITRAMFS"]
+fn firmware_over_the_ram() {
+    let firmware = temp_path("ram-firmware.bin");
+    fs::write(&firmware, blob("")).unwrap();
+
+    let stderr = TestVm::builder()
+        .arg("--firmware")
+        .arg(format!("path={},addr=0x100000", firmware.display()))
+        .capture_stderr()
+        .spawn()
+        .expect_failure(EXIT_TIMEOUT);
+    let _ = fs::remove_file(&firmware);
+
+    assert!(stderr.contains("must fit in the MMIO gap"), "{}", stderr);
+}
@@ -0,0 +1,102 @@
+#!/bin/sh
+# SPDX-License-Identifier: Apache-2.0
+#
+# Builds the kernel and the initramfs the integration tests boot, from the Linux and
+# busybox sources: an uncompressed vmlinux, with the symbols of the gdb test, and a
+# busybox initramfs starting a shell on the console.
+#
+#   tests/integration/fixtures.sh [<directory>]
+#
+# They go to target/test-fixtures by default, where the tests find them when
+# LUMPER_KERNEL and LUMPER_INITRAMFS are not set. This needs curl, cpio, and what
+# building Linux and busybox needs: a C toolchain, make, flex, bison, bc and the libelf
+# headers.
+
+set -eu
+
+LINUX_VERSION=6.6
+BUSYBOX_VERSION=1.36.1
+
+ROOT=$(cd "$(dirname "$0")/../.." && pwd)
+OUT=${1:-$ROOT/target/test-fixtures}
+mkdir -p "$OUT"
+OUT=$(cd "$OUT" && pwd)
+BUILD=$OUT/build
+JOBS=$(nproc)
+
+if [ "$(uname -m)" != x86_64 ]; then
+    echo "The fixtures are x86_64 ones" >&2
+    exit 1
+fi
+
+# Download $1 to $2, unless a previous run did.
+fetch() {
+    if [ ! -f "$2" ]; then
+        curl -fL --retry 3 -o "$2.part" "$1"
+        mv "$2.part" "$2"
+    fi
+}
+
+# Extract the tarball $1 to the directory $2, from scratch.
+extract() {
+    rm -rf "$2"
+    mkdir -p "$2"
+    tar -xf "$1" -C "$2" --strip-components=1
+}
+
+mkdir -p "$BUILD"
+cd "$BUILD"
+
+# The smallest kernel, with what lumper provides and the tests use: the serial console,
+# virtio-mmio devices from the command line, ACPI vCPU hotplug, the KVM paravirtual
+# features, /dev/mem and /dev/port, sysrq, and the debug info.
+fetch "https://cdn.kernel.org/pub/linux/kernel/v6.x/linux-$LINUX_VERSION.tar.xz" linux.tar.xz
+extract linux.tar.xz linux
+(
+    cd linux
+    make tinyconfig
+    ./scripts/config \
+        -e 64BIT -e SMP -e PRINTK -e BUG -e TTY -e SERIAL_8250 -e SERIAL_8250_CONSOLE \
+        -e BLK_DEV_INITRD -e BINFMT_ELF -e BINFMT_SCRIPT -e MULTIUSER -e FUTEX -e EPOLL \
+        -e SHMEM -e PROC_FS -e SYSFS -e TMPFS -e DEVTMPFS \
+        -e HYPERVISOR_GUEST -e PARAVIRT -e PARAVIRT_SPINLOCKS -e KVM_GUEST \
+        -e X86_X2APIC -e PCI -e ACPI -e ACPI_PROCESSOR -e HOTPLUG_CPU \
+        -e MAGIC_SYSRQ -e DEVMEM -e DEVPORT \
+        -e VIRTIO_MENU -e VIRTIO_MMIO -e VIRTIO_MMIO_CMDLINE_DEVICES \
+        -e NET -e PACKET -e UNIX -e INET -e NETDEVICES -e NET_CORE -e VIRTIO_NET \
+        -e NET_9P -e NET_9P_VIRTIO -e NETWORK_FILESYSTEMS -e 9P_FS \
+        -e DEBUG_KERNEL -e DEBUG_INFO_DWARF_TOOLCHAIN_DEFAULT
+    make olddefconfig
+    make -j"$JOBS" vmlinux
+)
+cp linux/vmlinux "$OUT/vmlinux"
+
+# A static busybox, without tc, which does not build against recent kernel headers.
+fetch "https://busybox.net/downloads/busybox-$BUSYBOX_VERSION.tar.bz2" busybox.tar.bz2
+extract busybox.tar.bz2 busybox
+(
+    cd busybox
+    make defconfig
+    sed -i -e 's/^# CONFIG_STATIC is not set$/CONFIG_STATIC=y/' \
+        -e 's/^CONFIG_TC=y$/# CONFIG_TC is not set/' .config
+    make -j"$JOBS"
+    rm -rf "$BUILD/rootfs"
+    make CONFIG_PREFIX="$BUILD/rootfs" install
+)
+
+# Init mounts the pseudo filesystems, and starts a shell on the console.
+(
+    cd rootfs
+    mkdir -p dev proc sys tmp mnt
+    cat >init <<'INIT'
+#!/bin/sh
+mount -t proc proc /proc
+mount -t sysfs sysfs /sys
+mount -t devtmpfs devtmpfs /dev
+exec setsid cttyhack sh
+INIT
+    chmod +x init
+    find . | cpio -o -H newc -R 0:0 >"$OUT/initramfs.cpio"
+)
+
+echo "Built $OUT/vmlinux and $OUT/initramfs.cpio"
@@ -0,0 +1,52 @@
+// SPDX-License-Identifier: Apache-2.0
+
+// Debugging the guest kernel with GDB, through the stub the VM waits for with --gdb.
+
+use std::net::TcpListener;
+use std::process::Command;
+use std::time::Duration;
+
+use vmm::ExitReason;
+
+use crate::harness::{kernel, TestVm};
+
+const BOOT_TIMEOUT: Duration = Duration::from_secs(30);
+const EXIT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[test]
+#[ignore = "needs KVM, gdb, LUMPER_KERNEL and LUMPER_INITRAMFS"]
+fn breakpoint() {
+    // The vmlinux the VM boots, with the symbols GDB sets the breakpoint from.
+    let vmlinux = kernel();
+    let port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let mut vm = TestVm::builder()
+        .arg("--gdb")
+        .arg(format!("tcp::{}", port))
+        .spawn();
+
+    // GDB retries connecting until lumper listens. Once detached, the guest boots on.
+    let gdb = Command::new("gdb")
+        .args(["-batch", "-nx"])
+        .arg(&vmlinux)
+        .args(["-ex", &format!("target remote 127.0.0.1:{}", port)])
+        .args(["-ex", "break start_kernel"])
+        .args(["-ex", "continue"])
+        .args(["-ex", "detach"])
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&gdb.stdout);
+    assert!(
+        stdout.contains("Breakpoint 1, ") && stdout.contains("start_kernel"),
+        "{}{}",
+        stdout,
+        String::from_utf8_lossy(&gdb.stderr)
+    );
+
+    vm.wait_for("Linux version", BOOT_TIMEOUT)
+        .send("poweroff -f\n")
+        .expect_exit(ExitReason::GuestShutdown, EXIT_TIMEOUT);
+}
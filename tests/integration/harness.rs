@@ -0,0 +1,540 @@
+// SPDX-License-Identifier: Apache-2.0
+
+// Runs the lumper binary on the test kernel and initramfs, with its console in a file the
+// tests watch, and its console input from a FIFO they write to. Or without a console, the
+// tests then go through the API socket.
+
+use std::env;
+use std::ffi::{CString, OsString};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Once;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use vmm::{ExitReason, MarkerWatch};
+
+// How often the console output and the process are checked.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+// The VM stops on its own past it, should a test not get it to.
+const DEFAULT_TIMEOUT_SECS: u64 = 60;
+
+// The VMs of the tests running in parallel get their own files.
+static NEXT_VM: AtomicUsize = AtomicUsize::new(0);
+
+pub fn temp_path(name: &str) -> PathBuf {
+    env::temp_dir().join(format!("lumper-test-{}-{}", std::process::id(), name))
+}
+
+// The fixture `var` names, or else the one fixtures.sh built.
+fn fixture(var: &str, name: &str) -> PathBuf {
+    let path = env::var_os(var).map(PathBuf::from).unwrap_or_else(|| {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("target/test-fixtures")
+            .join(name)
+    });
+    assert!(
+        path.is_file(),
+        "no {}: set {}, or run tests/integration/fixtures.sh",
+        path.display(),
+        var
+    );
+    path
+}
+
+/// The test kernel, LUMPER_KERNEL or the vmlinux of fixtures.sh. Panics unless it is an
+/// uncompressed vmlinux, which the gdb test needs the symbols of as well.
+pub fn kernel() -> PathBuf {
+    let path = fixture("LUMPER_KERNEL", "vmlinux");
+    let mut magic = [0u8; 4];
+    File::open(&path).unwrap().read_exact(&mut magic).unwrap();
+    assert_eq!(
+        &magic,
+        b"\x7fELF",
+        "{} is not an uncompressed vmlinux, a bzImage cannot boot",
+        path.display()
+    );
+    path
+}
+
+/// The test initramfs, LUMPER_INITRAMFS or the initramfs.cpio of fixtures.sh.
+pub fn initramfs() -> PathBuf {
+    fixture("LUMPER_INITRAMFS", "initramfs.cpio")
+}
+
+fn mkfifo(path: &Path) {
+    let path = CString::new(path.as_os_str().as_bytes()).unwrap();
+    // Safe because the path is a valid C string.
+    let ret = unsafe { libc::mkfifo(path.as_ptr(), 0o600) };
+    assert_eq!(ret, 0, "mkfifo: {}", std::io::Error::last_os_error());
+}
+
+// Connect to the API socket once lumper binds it, and send `request`.
+fn send_request(socket: &Path, request: &str, timeout: Duration) -> UnixStream {
+    let deadline = Instant::now() + timeout;
+    let mut stream = loop {
+        match UnixStream::connect(socket) {
+            Ok(stream) => break stream,
+            Err(e) if Instant::now() >= deadline => panic!("{}: {}", socket.display(), e),
+            Err(_) => thread::sleep(POLL_INTERVAL),
+        }
+    };
+    stream.write_all(request.as_bytes()).unwrap();
+    stream
+}
+
+/// Send one request to the API socket lumper binds while it sets the VM up, returning the
+/// response line. Panics when the socket is not there after `timeout`.
+pub fn api_request(socket: &Path, request: &str, timeout: Duration) -> String {
+    let stream = send_request(socket, request, timeout);
+
+    let mut response = String::new();
+    BufReader::new(stream).read_line(&mut response).unwrap();
+    response
+}
+
+/// Send one request to the API socket, like [`api_request`], returning the response line
+/// and the descriptor sent along with it.
+pub fn api_request_fd(socket: &Path, request: &str, timeout: Duration) -> (String, Option<File>) {
+    let stream = send_request(socket, request, timeout);
+
+    // The descriptor comes with the first bytes.
+    let mut data = vec![0u8; 4096];
+    let mut iov = libc::iovec {
+        iov_base: data.as_mut_ptr() as *mut libc::c_void,
+        iov_len: data.len(),
+    };
+    let mut control = [0u64; 8];
+    // Safe because msghdr is plain data.
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = std::mem::size_of_val(&control) as _;
+    // Safe because the buffers outlive the call, and we check the result.
+    let count = unsafe { libc::recvmsg(stream.as_raw_fd(), &mut msg, 0) };
+    assert!(count >= 0, "recvmsg: {}", std::io::Error::last_os_error());
+    data.truncate(count as usize);
+
+    // Safe because the control buffer holds what recvmsg wrote to it.
+    let file = unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        if cmsg.is_null() || (*cmsg).cmsg_type != libc::SCM_RIGHTS {
+            None
+        } else {
+            let fd = std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const RawFd);
+            Some(File::from_raw_fd(fd))
+        }
+    };
+
+    let mut response = String::from_utf8(data).unwrap();
+    BufReader::new(stream).read_line(&mut response).unwrap();
+    (response, file)
+}
+
+// Adopt the daemons, once their intermediate process exits, so that their exit status
+// can be waited for.
+fn adopt_daemons() {
+    static SUBREAPER: Once = Once::new();
+    SUBREAPER.call_once(|| {
+        // Safe because the option takes an integer.
+        let ret = unsafe { libc::prctl(libc::PR_SET_CHILD_SUBREAPER, 1) };
+        assert_eq!(ret, 0, "prctl: {}", std::io::Error::last_os_error());
+    });
+}
+
+fn ip(args: &[&str]) {
+    let status = Command::new("ip").args(args).status().unwrap();
+    assert!(status.success(), "ip {}: {}", args.join(" "), status);
+}
+
+/// A tap interface, deleted with it. Creating it needs root.
+pub struct Tap(pub String);
+
+impl Tap {
+    pub fn new() -> Self {
+        let id = NEXT_VM.fetch_add(1, Ordering::Relaxed);
+        let tap = Tap(format!("lumper{}-{}", std::process::id() % 100_000, id));
+        ip(&["tuntap", "add", "dev", &tap.0, "mode", "tap", "vnet_hdr"]);
+        ip(&["link", "set", &tap.0, "up"]);
+        tap
+    }
+
+    /// Give the host side of the tap `address`, e.g. the one of a DHCP server of lumper.
+    pub fn address(self, address: &str) -> Self {
+        ip(&["address", "add", address, "dev", &self.0]);
+        self
+    }
+}
+
+impl Drop for Tap {
+    fn drop(&mut self) {
+        let _ = Command::new("ip")
+            .args(["tuntap", "del", "dev", &self.0, "mode", "tap"])
+            .status();
+    }
+}
+
+/// Options of a [`TestVm`], on top of the kernel, the initramfs and the console.
+pub struct TestVmBuilder {
+    args: Vec<OsString>,
+    timeout: u64,
+    headless: bool,
+    replay: Option<PathBuf>,
+    input: Option<PathBuf>,
+    console: Option<PathBuf>,
+    capture_stdout: bool,
+    capture_stderr: bool,
+    pidfile: Option<PathBuf>,
+}
+
+impl TestVmBuilder {
+    pub fn cpus(self, cpus: u8) -> Self {
+        self.arg("--cpus").arg(cpus.to_string())
+    }
+
+    pub fn net(self, tap: &Tap) -> Self {
+        self.arg("--net").arg(&tap.0)
+    }
+
+    /// Seconds the VM runs for at most, see `--timeout`.
+    pub fn timeout(mut self, seconds: u64) -> Self {
+        self.timeout = seconds;
+        self
+    }
+
+    /// Run without a console, with stdin a pipe. The console output is empty, and the input
+    /// goes nowhere.
+    pub fn headless(mut self) -> Self {
+        self.headless = true;
+        self
+    }
+
+    /// Replay the console `session` instead of sending the input, see `--console-replay`.
+    pub fn replay(mut self, session: &Path) -> Self {
+        self.replay = Some(session.into());
+        self
+    }
+
+    /// Read the console input from the file `path` instead of sending it, see
+    /// `--console-input`.
+    pub fn input_file(mut self, path: &Path) -> Self {
+        self.input = Some(path.into());
+        self
+    }
+
+    /// Write the console output to `path` instead of a file of its own, e.g. the one of
+    /// another VM.
+    pub fn console_file(mut self, path: &Path) -> Self {
+        self.console = Some(path.into());
+        self
+    }
+
+    /// Keep the lumper standard output, for [`TestVm::stdout`].
+    pub fn capture_stdout(mut self) -> Self {
+        self.capture_stdout = true;
+        self
+    }
+
+    /// Keep the lumper error output, for [`TestVm::stderr`] and [`TestVm::expect_failure`].
+    pub fn capture_stderr(mut self) -> Self {
+        self.capture_stderr = true;
+        self
+    }
+
+    /// Run lumper with `--daemonize`, writing the daemon PID to `pidfile`. Starting the VM
+    /// waits for lumper to detach, the [`TestVm`] is then the daemon.
+    pub fn daemonize(mut self, pidfile: &Path) -> Self {
+        self.pidfile = Some(pidfile.into());
+        self
+    }
+
+    /// Any other lumper option.
+    pub fn arg(mut self, arg: impl Into<OsString>) -> Self {
+        self.args.push(arg.into());
+        self
+    }
+
+    /// Start the VM.
+    pub fn spawn(self) -> TestVm {
+        let id = NEXT_VM.fetch_add(1, Ordering::Relaxed);
+        let own_console = self.console.is_none();
+        let console = self
+            .console
+            .unwrap_or_else(|| temp_path(&format!("vm{}-console", id)));
+        let input_path = temp_path(&format!("vm{}-input", id));
+        mkfifo(&input_path);
+        // Opened for reading as well, this does not wait for lumper to open it.
+        let input = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&input_path)
+            .unwrap();
+
+        let mut command = Command::new(env!("CARGO_BIN_EXE_lumper"));
+        command
+            .arg("--kernel")
+            .arg(kernel())
+            .arg("--initramfs")
+            .arg(initramfs())
+            .args(["--timeout", &self.timeout.to_string()]);
+        if self.headless {
+            command.args(["--console", "none"]).stdin(Stdio::piped());
+        } else {
+            command
+                .arg("--console")
+                .arg(format!("file:{}", console.display()))
+                .stdin(Stdio::null());
+            match (self.replay.as_ref(), self.input.as_ref()) {
+                (Some(session), _) => command.arg("--console-replay").arg(session),
+                (None, Some(input)) => command.arg("--console-input").arg(input),
+                (None, None) => command.arg("--console-input").arg(&input_path),
+            };
+        }
+        // A file rather than a pipe, which the boot log would fill.
+        let stdout = self
+            .capture_stdout
+            .then(|| temp_path(&format!("vm{}-stdout", id)));
+        if let Some(stdout) = stdout.as_ref() {
+            command.stdout(File::create(stdout).unwrap());
+        }
+        if self.capture_stderr {
+            command.stderr(Stdio::piped());
+        }
+        if let Some(pidfile) = self.pidfile.as_ref() {
+            adopt_daemons();
+            command.arg("--daemonize").arg("--pidfile").arg(pidfile);
+        }
+        let mut child = command.args(&self.args).spawn().unwrap();
+
+        // lumper exits once its daemon runs the VM.
+        let daemon = self.pidfile.map(|pidfile| {
+            let status = child.wait().unwrap();
+            assert!(status.success(), "lumper failed to daemonize: {}", status);
+            fs::read_to_string(&pidfile)
+                .unwrap()
+                .trim()
+                .parse()
+                .unwrap()
+        });
+
+        TestVm {
+            child,
+            daemon,
+            console,
+            own_console,
+            input_path,
+            input,
+            stdout,
+            status: None,
+        }
+    }
+}
+
+/// A running lumper process, with its console.
+pub struct TestVm {
+    child: Child,
+    // The daemon running the VM, with --daemonize.
+    daemon: Option<libc::pid_t>,
+    console: PathBuf,
+    // Whether the console file is ours to remove, rather than another VM's.
+    own_console: bool,
+    input_path: PathBuf,
+    input: File,
+    // Where the standard output goes, with capture_stdout.
+    stdout: Option<PathBuf>,
+    status: Option<ExitStatus>,
+}
+
+impl TestVm {
+    pub fn builder() -> TestVmBuilder {
+        TestVmBuilder {
+            args: Vec::new(),
+            timeout: DEFAULT_TIMEOUT_SECS,
+            headless: false,
+            replay: None,
+            input: None,
+            console: None,
+            capture_stdout: false,
+            capture_stderr: false,
+            pidfile: None,
+        }
+    }
+
+    /// The PID of the lumper process, or of its daemon.
+    pub fn pid(&self) -> u32 {
+        match self.daemon {
+            Some(pid) => pid as u32,
+            None => self.child.id(),
+        }
+    }
+
+    /// The console file.
+    pub fn console_path(&self) -> &Path {
+        &self.console
+    }
+
+    /// Whether the VM still runs.
+    pub fn is_running(&mut self) -> bool {
+        self.try_wait().is_none()
+    }
+
+    /// The console output so far, none when it goes to a device such as /dev/full.
+    pub fn console(&self) -> String {
+        if !self.console.is_file() {
+            return String::new();
+        }
+        fs::read_to_string(&self.console).unwrap_or_default()
+    }
+
+    /// The lumper standard output so far, which [`TestVmBuilder::capture_stdout`] keeps.
+    pub fn stdout(&self) -> String {
+        let path = self
+            .stdout
+            .as_ref()
+            .expect("the standard output is not captured");
+        fs::read_to_string(path).unwrap_or_default()
+    }
+
+    /// The lumper error output, which [`TestVmBuilder::capture_stderr`] keeps, once it
+    /// exited.
+    pub fn stderr(&mut self) -> String {
+        assert!(self.try_wait().is_some(), "the VM still runs");
+        let mut stderr = String::new();
+        if let Some(mut pipe) = self.child.stderr.take() {
+            pipe.read_to_string(&mut stderr).unwrap();
+        }
+        stderr
+    }
+
+    /// Wait for `marker` to show up on the console, matched as [`VMM::run_until`] does.
+    /// Panics, with the console output, on `timeout` or when the VM stops first.
+    ///
+    /// The console echoes the input: a marker sent as a command must differ from its
+    /// output, e.g. `echo cpus-$(nproc)x` waiting for `cpus-2x`.
+    ///
+    /// [`VMM::run_until`]: vmm::VMM::run_until
+    pub fn wait_for(&mut self, marker: &str, timeout: Duration) -> &mut Self {
+        let deadline = Instant::now() + timeout;
+        let mut watch = MarkerWatch::new(marker.as_bytes());
+        let mut scanned = 0;
+        loop {
+            // Only the output since the last round, the watch keeps the rest.
+            let console = fs::read(&self.console).unwrap_or_default();
+            if watch.scan(console.get(scanned..).unwrap_or_default()) {
+                return self;
+            }
+            scanned = scanned.max(console.len());
+            if let Some(status) = self.try_wait() {
+                panic!(
+                    "the VM stopped ({}) before `{}`:\n{}",
+                    status,
+                    marker,
+                    self.console()
+                );
+            }
+            if Instant::now() >= deadline {
+                panic!("no `{}` after {:?}:\n{}", marker, timeout, self.console());
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    /// Type `input` on the console.
+    pub fn send(&mut self, input: &str) -> &mut Self {
+        self.input.write_all(input.as_bytes()).unwrap();
+        self
+    }
+
+    /// Wait for the VM to stop for `reason`, from the lumper exit code. Panics, with the
+    /// console output, on `timeout` or another exit code.
+    pub fn expect_exit(&mut self, reason: ExitReason, timeout: Duration) {
+        let deadline = Instant::now() + timeout;
+        let status = loop {
+            if let Some(status) = self.try_wait() {
+                break status;
+            }
+            if Instant::now() >= deadline {
+                panic!("the VM still runs after {:?}:\n{}", timeout, self.console());
+            }
+            thread::sleep(POLL_INTERVAL);
+        };
+
+        assert_eq!(
+            status.code(),
+            Some(reason.exit_code()),
+            "expected {:?}:\n{}",
+            reason,
+            self.console()
+        );
+    }
+
+    /// Wait for lumper to fail, e.g. to start the VM, and return its error output, which
+    /// [`TestVmBuilder::capture_stderr`] keeps. Panics on `timeout` or a success.
+    pub fn expect_failure(&mut self, timeout: Duration) -> String {
+        let deadline = Instant::now() + timeout;
+        let status = loop {
+            if let Some(status) = self.try_wait() {
+                break status;
+            }
+            if Instant::now() >= deadline {
+                panic!("the VM still runs after {:?}", timeout);
+            }
+            thread::sleep(POLL_INTERVAL);
+        };
+
+        let stderr = self.stderr();
+        assert!(!status.success(), "lumper succeeded:\n{}", stderr);
+        stderr
+    }
+
+    fn try_wait(&mut self) -> Option<ExitStatus> {
+        if self.status.is_some() {
+            return self.status;
+        }
+
+        self.status = match self.daemon {
+            Some(pid) => {
+                let mut wstatus = 0;
+                // Safe because the daemon is our child, and wstatus is valid.
+                let ret = unsafe { libc::waitpid(pid, &mut wstatus, libc::WNOHANG) };
+                assert!(ret >= 0, "waitpid: {}", std::io::Error::last_os_error());
+                (ret == pid).then(|| ExitStatus::from_raw(wstatus))
+            }
+            None => self.child.try_wait().unwrap(),
+        };
+        self.status
+    }
+}
+
+impl Drop for TestVm {
+    fn drop(&mut self) {
+        if self.try_wait().is_none() {
+            match self.daemon {
+                // Safe because the daemon is our child, which we reap.
+                Some(pid) => unsafe {
+                    libc::kill(pid, libc::SIGKILL);
+                    libc::waitpid(pid, std::ptr::null_mut(), 0);
+                },
+                None => {
+                    let _ = self.child.kill();
+                    let _ = self.child.wait();
+                }
+            }
+        }
+        if self.own_console {
+            let _ = fs::remove_file(&self.console);
+        }
+        let _ = fs::remove_file(&self.input_path);
+        if let Some(stdout) = self.stdout.as_ref() {
+            let _ = fs::remove_file(stdout);
+        }
+    }
+}
@@ -0,0 +1,42 @@
+// SPDX-License-Identifier: Apache-2.0
+
+// Plugging vCPUs into the running guest, through the add-vcpu API request.
+
+use std::time::Duration;
+
+use vmm::ExitReason;
+
+use crate::harness::{api_request, temp_path, TestVm};
+
+const BOOT_TIMEOUT: Duration = Duration::from_secs(30);
+const EXIT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[test]
+#[ignore = "needs KVM, LUMPER_KERNEL and LUMPER_INITRAMFS"]
+fn add_vcpu() {
+    let socket = temp_path("hotplug-api");
+    let mut vm = TestVm::builder()
+        .cpus(1)
+        .arg("--max-cpus")
+        .arg("2")
+        .arg("--api-socket")
+        .arg(&socket)
+        .spawn();
+    vm.wait_for("Linux version", BOOT_TIMEOUT)
+        .send("echo cpus-$(nproc)x\n")
+        .wait_for("cpus-1x", BOOT_TIMEOUT);
+
+    let response = api_request(
+        &socket,
+        "{\"action\":\"add-vcpu\",\"count\":1}\n",
+        BOOT_TIMEOUT,
+    );
+    assert_eq!(response.trim_end(), "{\"cpus\":2}");
+
+    // The guest may leave the plugged vCPU offline.
+    vm.send("echo 1 > /sys/devices/system/cpu/cpu1/online; echo cpus-$(nproc)x\n")
+        .wait_for("cpus-2x", BOOT_TIMEOUT)
+        .send("poweroff -f\n")
+        .expect_exit(ExitReason::GuestShutdown, EXIT_TIMEOUT);
+    let _ = std::fs::remove_file(&socket);
+}
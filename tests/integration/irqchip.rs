@@ -0,0 +1,38 @@
+// SPDX-License-Identifier: Apache-2.0
+
+// Boots with each irqchip mode: the console input reaches the shell through the serial
+// port interrupt, routed by the in-kernel or the userspace IOAPIC.
+
+use std::time::Duration;
+
+use vmm::ExitReason;
+
+use crate::harness::TestVm;
+
+const BOOT_TIMEOUT: Duration = Duration::from_secs(30);
+const EXIT_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn boot(irqchip: &str) {
+    let mut vm = TestVm::builder().arg("--irqchip").arg(irqchip).spawn();
+    vm.wait_for("Linux version", BOOT_TIMEOUT)
+        .send("cat /proc/interrupts; echo interrupts-$((6 * 7))x\n")
+        .wait_for("interrupts-42x", BOOT_TIMEOUT)
+        .send("poweroff -f\n")
+        .expect_exit(ExitReason::GuestShutdown, EXIT_TIMEOUT);
+
+    // The guest drives the serial port through the IOAPIC either way.
+    let console = vm.console();
+    assert!(console.contains("IO-APIC"), "{}", console);
+}
+
+#[test]
+#[ignore = "needs KVM, LUMPER_KERNEL and LUMPER_INITRAMFS"]
+fn full_irqchip() {
+    boot("full");
+}
+
+#[test]
+#[ignore = "needs KVM, LUMPER_KERNEL and LUMPER_INITRAMFS"]
+fn split_irqchip() {
+    boot("split");
+}
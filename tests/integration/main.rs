@@ -0,0 +1,35 @@
+// SPDX-License-Identifier: Apache-2.0
+
+// End-to-end tests, booting a kernel with an initramfs through the lumper binary, and
+// driving the guest shell over the console.
+//
+// This needs KVM, and an uncompressed vmlinux with its symbols, which lumper boots and
+// the gdb test breaks in, and a busybox initramfs starting a shell on the console:
+//   LUMPER_KERNEL=vmlinux LUMPER_INITRAMFS=initramfs.cpio \
+//     cargo test --test integration -- --ignored
+//
+// tests/integration/fixtures.sh builds both, with what the tests use enabled, e.g. ACPI
+// vCPU hotplug and virtio-mmio, to target/test-fixtures, which the tests default to. The
+// gdb test also needs gdb, and the tap interface ones root. The run_until and clone
+// tests run their VMs in process, through the vmm crate.
+//
+// The tests boot one VM each, with the helpers of the harness module, which new device
+// tests are meant to reuse.
+
+mod boot;
+mod clone;
+mod console_errors;
+mod console_input;
+mod daemon;
+mod dhcp;
+mod firmware;
+mod gdb;
+mod harness;
+mod hotplug;
+mod irqchip;
+mod mmio;
+mod pv_features;
+mod pvpanic;
+mod resource_lock;
+mod run_until;
+mod shared_dir;
@@ -0,0 +1,25 @@
+// SPDX-License-Identifier: Apache-2.0
+
+// Guest accesses to MMIO addresses no device claims, which behave as on real hardware.
+
+use std::time::Duration;
+
+use vmm::ExitReason;
+
+use crate::harness::TestVm;
+
+const BOOT_TIMEOUT: Duration = Duration::from_secs(30);
+const EXIT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[test]
+#[ignore = "needs KVM, LUMPER_KERNEL and LUMPER_INITRAMFS"]
+fn unclaimed() {
+    // The write goes nowhere and the read returns all ones, the guest keeps running.
+    TestVm::builder()
+        .spawn()
+        .wait_for("Linux version", BOOT_TIMEOUT)
+        .send("devmem 0xdeadbeef 8 0x55; devmem 0xdeadbeef 8\n")
+        .wait_for("0xFF", BOOT_TIMEOUT)
+        .send("poweroff -f\n")
+        .expect_exit(ExitReason::GuestShutdown, EXIT_TIMEOUT);
+}
@@ -0,0 +1,50 @@
+// SPDX-License-Identifier: Apache-2.0
+
+// Boots guests with and without the KVM paravirtual features.
+
+use std::time::Duration;
+
+use vmm::ExitReason;
+
+use crate::harness::TestVm;
+
+const BOOT_TIMEOUT: Duration = Duration::from_secs(30);
+const EXIT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// What the guest tells about its clock, and the paravirtual features it set up at boot.
+const REPORT: &str =
+    "echo clocksource=$(cat /sys/devices/system/clocksource/clocksource0/current_clocksource); \
+    dmesg | grep -i -e 'pv spinlocks' -e 'pv eoi' -e 'steal' -e 'async pf'; \
+    echo report-$((6 * 7))x\n";
+
+// Boot two vCPUs with `pv_features`, and return the console output.
+fn boot_with(pv_features: &str) -> String {
+    let mut vm = TestVm::builder()
+        .cpus(2)
+        .arg("--pv-features")
+        .arg(pv_features)
+        .spawn();
+    vm.wait_for("Linux version", BOOT_TIMEOUT)
+        .send(REPORT)
+        .wait_for("report-42x", BOOT_TIMEOUT)
+        .send("poweroff -f\n")
+        .expect_exit(ExitReason::GuestShutdown, EXIT_TIMEOUT);
+    vm.console()
+}
+
+#[test]
+#[ignore = "needs KVM, LUMPER_KERNEL and LUMPER_INITRAMFS"]
+fn pv_features() {
+    // The kvmclock is not one of the features, the guest keeps it either way.
+    let console = boot_with("all");
+    assert!(console.contains("clocksource=kvm-clock"), "{}", console);
+    assert!(console.contains("PV spinlocks enabled"), "{}", console);
+
+    let console = boot_with("none");
+    assert!(console.contains("clocksource=kvm-clock"), "{}", console);
+    assert!(!console.contains("PV spinlocks enabled"), "{}", console);
+
+    // Only the listed features are offered.
+    let console = boot_with("steal-time,pv-eoi");
+    assert!(!console.contains("PV spinlocks enabled"), "{}", console);
+}
@@ -0,0 +1,27 @@
+// SPDX-License-Identifier: Apache-2.0
+
+// Reports a guest panic through the pvpanic port, which stops lumper with the panic exit
+// code whatever the console shows.
+
+use std::time::Duration;
+
+use vmm::{ExitReason, PanicReport, PvpanicEvent};
+
+use crate::harness::TestVm;
+
+const BOOT_TIMEOUT: Duration = Duration::from_secs(30);
+const EXIT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[test]
+#[ignore = "needs KVM, LUMPER_KERNEL and LUMPER_INITRAMFS"]
+fn pvpanic_exit_code() {
+    // Write the PANICKED event to port 0x505, as the pvpanic driver does.
+    TestVm::builder()
+        .spawn()
+        .wait_for("Linux version", BOOT_TIMEOUT)
+        .send("printf '\\001' | dd of=/dev/port bs=1 seek=1285 conv=notrunc; sleep 10\n")
+        .expect_exit(
+            ExitReason::GuestPanic(PanicReport::Pvpanic(PvpanicEvent::Panicked)),
+            EXIT_TIMEOUT,
+        );
+}
@@ -0,0 +1,46 @@
+// SPDX-License-Identifier: Apache-2.0
+
+// Starts lumper twice on the same console file: the second instance fails to start,
+// telling which one has the file, unless it does not lock it.
+
+use std::thread;
+use std::time::Duration;
+
+use crate::harness::TestVm;
+
+const BOOT_TIMEOUT: Duration = Duration::from_secs(30);
+const EXIT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[test]
+#[ignore = "needs KVM, LUMPER_KERNEL and LUMPER_INITRAMFS"]
+fn console_file() {
+    // The first instance has the file once its guest writes to it.
+    let mut first = TestVm::builder().spawn();
+    first.wait_for("Linux version", BOOT_TIMEOUT);
+    let console = first.console_path().to_path_buf();
+
+    let stderr = TestVm::builder()
+        .console_file(&console)
+        .capture_stderr()
+        .spawn()
+        .expect_failure(EXIT_TIMEOUT);
+    assert!(
+        stderr.contains(&format!(
+            "console file {} is in use by another lumper instance (PID {})",
+            console.display(),
+            first.pid()
+        )),
+        "{}",
+        stderr
+    );
+    // Neither did it truncate the file.
+    assert!(first.console().contains("Linux version"));
+
+    // Without the lock, it runs along.
+    let mut third = TestVm::builder()
+        .console_file(&console)
+        .arg("--no-lock")
+        .spawn();
+    thread::sleep(Duration::from_secs(1));
+    assert!(third.is_running());
+}
@@ -0,0 +1,45 @@
+// SPDX-License-Identifier: Apache-2.0
+
+// Runs a guest in process until its console prints a marker, then on to the end.
+
+use std::time::Duration;
+
+use vmm::config::{ConsoleMode, VMMConfigBuilder};
+use vmm::{ExitReason, RunUntilOutcome, VMM};
+
+use crate::harness::{initramfs, kernel};
+
+// Init says it booted, waits a bit, then powers off.
+const INIT: &str = "rdinit=/bin/sh -- -c \"echo booted; sleep 2; poweroff -f\"";
+
+#[test]
+#[ignore = "needs KVM, LUMPER_KERNEL and LUMPER_INITRAMFS"]
+fn run_until_marker() {
+    let config = VMMConfigBuilder::default()
+        .kernel(kernel())
+        .initramfs(Some(initramfs().into()))
+        .cmdline(Some(INIT.to_string()))
+        .console(Some(ConsoleMode::None))
+        .build()
+        .unwrap();
+    let mut vmm = VMM::from_config(&config).unwrap();
+    vmm.detach_stdin().unwrap();
+
+    let output = match vmm.run_until(b"booted", Duration::from_secs(30)).unwrap() {
+        RunUntilOutcome::MarkerSeen(output) => String::from_utf8_lossy(&output).into_owned(),
+        outcome => panic!("no marker: {:?}", outcome),
+    };
+    assert!(output.contains("Linux version"), "{}", output);
+    assert!(output.ends_with("booted"), "{}", output);
+
+    // Init sleeps meanwhile.
+    match vmm
+        .run_until(b"not printed", Duration::from_millis(500))
+        .unwrap()
+    {
+        RunUntilOutcome::TimedOut(output) => assert!(!output.ends_with(b"not printed")),
+        outcome => panic!("no timeout: {:?}", outcome),
+    }
+
+    assert_eq!(vmm.resume_run().unwrap(), ExitReason::GuestShutdown);
+}
@@ -0,0 +1,46 @@
+// SPDX-License-Identifier: Apache-2.0
+
+// Shares a host directory with the guest over virtio-9p: the guest reads a host file, and
+// writes one back.
+
+use std::fs;
+use std::time::Duration;
+
+use vmm::ExitReason;
+
+use crate::harness::{temp_path, TestVm};
+
+const BOOT_TIMEOUT: Duration = Duration::from_secs(30);
+const EXIT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[test]
+#[ignore = "needs KVM, LUMPER_KERNEL and LUMPER_INITRAMFS"]
+fn shared_dir() {
+    let share = temp_path("share");
+    fs::create_dir(&share).unwrap();
+    fs::write(share.join("hello"), "hello from the host\n").unwrap();
+
+    let mut vm = TestVm::builder()
+        .arg("--shared-dir")
+        .arg(format!("tag=src,path={}", share.display()))
+        .spawn();
+    vm.wait_for("Linux version", BOOT_TIMEOUT)
+        .send(
+            "mkdir -p /mnt; mount -t 9p -o trans=virtio src /mnt; cat /mnt/hello; \
+             echo hello from the guest > /mnt/reply; umount /mnt; poweroff -f\n",
+        )
+        .expect_exit(ExitReason::GuestShutdown, EXIT_TIMEOUT);
+
+    let reply = fs::read_to_string(share.join("reply"));
+    let _ = fs::remove_dir_all(&share);
+
+    let console = vm.console();
+    assert!(
+        console
+            .lines()
+            .any(|line| line.trim() == "hello from the host"),
+        "{}",
+        console
+    );
+    assert_eq!(reply.unwrap(), "hello from the guest\n", "{}", console);
+}
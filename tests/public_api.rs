@@ -0,0 +1,93 @@
+// SPDX-License-Identifier: Apache-2.0
+
+// The public API of the vmm crate, as the lumper binary and the library users rely on it.
+//
+// This only has to build: changing one of these signatures is a breaking change of the
+// crate, which needs a new minor version while it is 0.x.
+
+use std::io::Write;
+use std::os::fd::OwnedFd;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use vmm::agent::{Agent, AgentChannel};
+use vmm::config::{self, VMMConfig, VMMConfigBuilder};
+use vmm::inspect::BootCheck;
+use vmm::{
+    BootImages, DirtyBitmap, ExitReason, MarkerWatch, NetInterface, PanicReport, PauseTrigger,
+    PvpanicEvent, RunUntilOutcome, Tap, VmmParts, VmmState, VMM,
+};
+
+#[test]
+fn public_api() {
+    let _: fn(&VMMConfig) -> vmm::Result<VMM> = VMM::from_config;
+    let _: fn(&VMMConfig, Box<dyn Write + Send>) -> vmm::Result<VMM> = VMM::from_config_with_sink;
+    let _: fn(&VMMConfig, VmmParts) -> vmm::Result<VMM> = VMM::from_config_with_parts;
+    let _: fn(VMM) -> VmmParts = VMM::into_parts;
+    let _: fn(OwnedFd) -> Tap = Tap::from_fd;
+    let _: fn(&mut VMM) -> vmm::Result<ExitReason> = VMM::run;
+    let _: fn(&mut VMM, &[u8], Duration) -> vmm::Result<RunUntilOutcome> = VMM::run_until;
+    let _: fn(&mut VMM) -> vmm::Result<ExitReason> = VMM::resume_run;
+    let _: fn(&mut VMM) -> vmm::Result<()> = VMM::detach_stdin;
+    let _: fn(&VMM) -> PauseTrigger = VMM::pause_trigger;
+    let _: fn(&mut VMM) -> Option<AgentChannel> = VMM::agent_channel;
+    let _: fn(&mut VMM) -> Option<Agent> = VMM::agent;
+    let _: fn(&VMM) -> vmm::Result<VMM> = VMM::clone_from_paused;
+    let _: fn(&VMM) -> vmm::Result<DirtyBitmap> = VMM::dirty_bitmap;
+    let _: fn(&VMM) -> VmmState = VMM::state;
+    let _: fn(&PauseTrigger) = PauseTrigger::pause;
+    let _: fn(&[u8]) -> MarkerWatch = MarkerWatch::new;
+    let _: fn(&mut MarkerWatch, &[u8]) -> bool = MarkerWatch::scan;
+    let _: fn(&MarkerWatch) -> bool = MarkerWatch::seen;
+    let _: fn(MarkerWatch) -> Vec<u8> = MarkerWatch::into_output;
+    let _: fn(&VMMConfig) -> vmm::Result<BootImages> = vmm::inspect_images;
+    let _: fn(&VMMConfig) -> vmm::Result<BootCheck> = vmm::check_images;
+    let _: fn(log::LevelFilter) = vmm::logging::set_level;
+    let _: fn(log::Level) -> bool = vmm::logging::enabled;
+    let _: fn(bool) = vmm::logging::set_trace_exits;
+    let _: fn(VMMConfigBuilder) -> config::Result<VMMConfig> = VMMConfigBuilder::build;
+    let _: fn(&ExitReason) -> i32 = ExitReason::exit_code;
+
+    // Matching without a wildcard breaks on a new reason, as it would for the users.
+    let exit_code = |reason: ExitReason| match reason {
+        ExitReason::GuestShutdown => 0,
+        ExitReason::GuestReset => 1,
+        ExitReason::GuestPanic(PanicReport::Console(_))
+        | ExitReason::GuestPanic(PanicReport::Pvpanic(PvpanicEvent::Panicked))
+        | ExitReason::GuestPanic(PanicReport::Pvpanic(PvpanicEvent::CrashLoaded))
+        | ExitReason::GuestPanic(PanicReport::SystemEvent) => 3,
+        ExitReason::Timeout => 4,
+        ExitReason::Signal(signal) => 128 + signal,
+        ExitReason::WatchdogExpired(_) => 5,
+        ExitReason::VcpuError(_) => 2,
+        ExitReason::ConsoleError(_) => 2,
+        ExitReason::Paused => 2,
+    };
+    assert_eq!(exit_code(ExitReason::Signal(15)), 143);
+    for reason in [
+        ExitReason::GuestShutdown,
+        ExitReason::GuestPanic(PanicReport::SystemEvent),
+        ExitReason::Timeout,
+        ExitReason::Signal(9),
+    ] {
+        assert_eq!(reason.exit_code(), exit_code(reason.clone()));
+    }
+
+    // The parts go from one VMM to the next one.
+    let parts = VmmParts {
+        net: None::<NetInterface>,
+        console: Some(Box::new(Vec::new())),
+    };
+    assert!(parts.console.is_some());
+
+    // A configuration needs no KVM.
+    let config = VMMConfigBuilder::default()
+        .kernel(PathBuf::from("vmlinux"))
+        .build()
+        .unwrap();
+    assert_eq!(config.cpus, 1);
+    assert!(matches!(
+        VMMConfigBuilder::default().build(),
+        Err(config::Error::MissingKernel)
+    ));
+}